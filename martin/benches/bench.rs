@@ -1,3 +1,4 @@
+use actix_web::test::TestRequest;
 use async_trait::async_trait;
 use criterion::async_executor::FuturesExecutor;
 use criterion::{criterion_group, criterion_main, Criterion};
@@ -49,7 +50,7 @@ impl Source for NullSource {
         _xyz: TileCoord,
         _url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData> {
-        Ok(b"empty".to_vec())
+        Ok(TileData::from_static(b"empty"))
     }
 
     fn get_catalog_entry(&self) -> CatalogSourceEntry {
@@ -59,7 +60,8 @@ impl Source for NullSource {
 
 async fn process_tile(sources: &TileSources) {
     let src = DynTileSource::new(sources, "null", Some(0), "", None, None, None).unwrap();
-    src.get_http_response(TileCoord { z: 0, x: 0, y: 0 })
+    let req = TestRequest::default().to_http_request();
+    src.get_http_response(TileCoord { z: 0, x: 0, y: 0 }, &req)
         .await
         .unwrap();
 }
@@ -0,0 +1,143 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use clap::{Subcommand, ValueEnum};
+
+/// Commands that use the configured sources without starting the server
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Request tiles the way map clients do, and report the throughput and latency percentiles.
+    /// Tiles are requested from a running server with --url, or from the configured sources otherwise.
+    Bench(BenchArgs),
+    /// Get a single tile from the configured sources, e.g. to check the source configuration
+    Tile(TileArgs),
+    /// List the configured tile sources with their type, format, zoom levels, and bounds
+    List(ListArgs),
+    /// Print the `TileJSON` of a source, as the server would serve it
+    #[command(name = "tilejson")]
+    TileJson(TileJsonArgs),
+    /// Print a minimal `MapLibre` style showing all layers of a source, as a starting point for styling
+    Style(StyleArgs),
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct StyleArgs {
+    /// Source ID, or comma-separated source IDs of a composite source
+    pub source: String,
+    /// Public URL of the server, used for the `TileJSON` URL of the source. The configured `base_path` is added to it.
+    #[arg(long, default_value = "http://localhost:3000")]
+    pub base_url: String,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct TileJsonArgs {
+    /// Source ID, or comma-separated source IDs of a composite source
+    pub source: String,
+    /// Public URL of the server, used for the tile URLs. The configured `base_path` is added to it.
+    #[arg(long, default_value = "http://localhost:3000")]
+    pub base_url: String,
+    /// Optional query parameter (in URL query format) added to the tile URLs
+    #[arg(long)]
+    pub url_query: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct ListArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    pub format: ListFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    Table,
+    Json,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct TileArgs {
+    /// Source ID, or comma-separated source IDs of a composite source
+    pub source: String,
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+    /// Save the tile to this file, or `-` to write it to stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Print the layers of vector tiles, with the number of features and the attribute names
+    #[arg(long)]
+    pub inspect: bool,
+    /// Accepted encodings, as if a browser sent them in the `Accept-Encoding` header.
+    /// Use `identity` to get uncompressed tiles.
+    #[arg(long, default_value = "identity")]
+    pub encoding: String,
+    /// Optional query parameter (in URL query format) for the sources that support it (e.g. Postgres functions)
+    #[arg(long)]
+    pub url_query: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+pub struct BenchArgs {
+    /// Source ID to request tiles from
+    #[arg(short, long)]
+    pub source: String,
+    /// Zoom levels to request, e.g. `0-14` or `10`. Limited to the zoom levels of the source.
+    #[arg(short, long, default_value = "0-14", value_parser = parse_zoom_range)]
+    pub zoom: RangeInclusive<u8>,
+    /// Number of concurrent requests
+    #[arg(long, default_value = "16")]
+    pub concurrency: usize,
+    /// Number of tile requests to send
+    #[arg(short = 'n', long, default_value = "10000")]
+    pub requests: u64,
+    /// Stop after this many seconds, even if not all requests were sent
+    #[arg(short, long, value_name = "SECONDS")]
+    pub duration: Option<u64>,
+    /// Base URL of a running Martin server, e.g. `http://localhost:3000`.
+    /// The config file and the connection parameters are ignored.
+    #[arg(short, long)]
+    pub url: Option<String>,
+    /// Accepted encodings, as if a browser sent them in the `Accept-Encoding` header
+    #[arg(long, default_value = "gzip")]
+    pub encoding: String,
+    /// Optional query parameter (in URL query format) for the sources that support it (e.g. Postgres functions)
+    #[arg(long)]
+    pub url_query: Option<String>,
+    /// Seed of the random tile coordinates. Runs with the same seed request the same tiles.
+    #[arg(long, default_value = "1")]
+    pub seed: u64,
+}
+
+fn parse_zoom_range(value: &str) -> Result<RangeInclusive<u8>, String> {
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|z| *z <= 30)
+            .ok_or_else(|| format!("'{v}' is not a valid zoom level"))
+    };
+    let (min, max) = match value.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    if min > max {
+        return Err(format!(
+            "The minimum zoom {min} is greater than the maximum zoom {max}"
+        ));
+    }
+    Ok(min..=max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoom_range() {
+        assert_eq!(parse_zoom_range("0-14"), Ok(0..=14));
+        assert_eq!(parse_zoom_range("5"), Ok(5..=5));
+        assert!(parse_zoom_range("5-3").is_err());
+        assert!(parse_zoom_range("0-31").is_err());
+        assert!(parse_zoom_range("a").is_err());
+    }
+}
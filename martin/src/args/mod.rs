@@ -1,3 +1,6 @@
+mod command;
+pub use command::{BenchArgs, Command, ListArgs, ListFormat, StyleArgs, TileArgs, TileJsonArgs};
+
 mod connections;
 pub use connections::{Arguments, State};
 
@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::ValueEnum;
@@ -8,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use crate::args::connections::Arguments;
 use crate::args::connections::State::{Ignore, Take};
 use crate::args::environment::Env;
-use crate::pg::{PgConfig, PgSslCerts, POOL_SIZE_DEFAULT};
+use crate::pg::{PgConfig, PgSettings, PgSslCerts, POOL_SIZE_DEFAULT};
 use crate::utils::{OptBoolObj, OptOneMany};
 
 // Must match the help string for BoundsType::Quick
@@ -27,6 +28,8 @@ pub enum BoundsCalcType {
     Calc,
     /// Skip bounds calculation. The bounds will be set to the whole world.
     Skip,
+    /// Publish sources with the whole world bounds, and compute table geometry bounds in the background.
+    Background,
 }
 
 #[derive(clap::Args, Debug, PartialEq, Default, Clone)]
@@ -37,7 +40,7 @@ pub struct PgArgs {
     pub auto_bounds: Option<BoundsCalcType>,
     /// Loads trusted root certificates from a file. The file should contain a sequence of PEM-formatted CA certificates.
     #[arg(long)]
-    pub ca_root_file: Option<std::path::PathBuf>,
+    pub ca_root_file: Option<PathBuf>,
     /// If a spatial PG table has SRID 0, then this default SRID will be used as a fallback.
     #[arg(short, long)]
     pub default_srid: Option<i32>,
@@ -46,6 +49,9 @@ pub struct PgArgs {
     /// Limit the number of features in a tile from a PG table source.
     #[arg(short, long)]
     pub max_feature_count: Option<usize>,
+    /// Do not use prepared statements or session settings, e.g. when connecting through a pooler in transaction pooling mode.
+    #[arg(long)]
+    pub transaction_pooling: bool,
 }
 
 impl PgArgs {
@@ -54,20 +60,37 @@ impl PgArgs {
         cli_strings: &mut Arguments,
         env: &impl Env<'a>,
     ) -> OptOneMany<PgConfig> {
-        let connections = Self::extract_conn_strings(cli_strings, env);
+        let mut connections: Vec<_> = Self::extract_conn_strings(cli_strings, env)
+            .into_iter()
+            .map(|s| (Some(s), None))
+            .collect();
+        if connections.is_empty() {
+            if let Some(file) = env.var_os("DATABASE_URL_FILE") {
+                info!("Using env var DATABASE_URL_FILE to connect to PostgreSQL");
+                connections.push((None, Some(PathBuf::from(file))));
+            }
+        }
         let default_srid = self.get_default_srid(env);
         let certs = self.get_certs(env);
 
         let results: Vec<_> = connections
             .into_iter()
-            .map(|s| PgConfig {
-                connection_string: Some(s),
+            .map(|(connection_string, connection_string_file)| PgConfig {
+                connection_string,
+                connection_string_file,
+                connection_string_secret: None,
+                password_file: None,
+                password_secret: None,
                 ssl_certificates: certs.clone(),
                 default_srid,
                 auto_bounds: self.auto_bounds,
                 max_feature_count: self.max_feature_count,
                 pool_size: self.pool_size,
+                transaction_pooling: self.transaction_pooling.then_some(true),
+                settings: PgSettings::default(),
+                search_path: OptOneMany::NoVals,
                 auto_publish: OptBoolObj::NoValue,
+                id_prefix: None,
                 tables: None,
                 functions: None,
             })
@@ -89,6 +112,7 @@ impl PgArgs {
             auto_bounds,
             max_feature_count,
             ca_root_file,
+            transaction_pooling,
         } = self;
 
         if let Some(value) = default_srid {
@@ -115,6 +139,12 @@ impl PgArgs {
                 c.max_feature_count = max_feature_count;
             });
         }
+        if transaction_pooling {
+            info!("Enabling transaction pooling mode on all Postgres connections because of a CLI parameter");
+            pg_config.iter_mut().for_each(|c| {
+                c.transaction_pooling = Some(true);
+            });
+        }
         if let Some(ref value) = ca_root_file {
             info!("Overriding root certificate file to {} on all Postgres connections because of a CLI parameter",
                 value.display());
@@ -126,6 +156,7 @@ impl PgArgs {
         for v in &[
             "DANGER_ACCEPT_INVALID_CERTS",
             "DATABASE_URL",
+            "DATABASE_URL_FILE",
             "DEFAULT_SRID",
             "PGSSLCERT",
             "PGSSLKEY",
@@ -189,12 +220,8 @@ impl PgArgs {
         result
     }
 
-    fn parse_env_var<'a>(
-        env: &impl Env<'a>,
-        env_var: &str,
-        info: &str,
-    ) -> Option<std::path::PathBuf> {
-        let path = env.var_os(env_var).map(std::path::PathBuf::from);
+    fn parse_env_var<'a>(env: &impl Env<'a>, env_var: &str, info: &str) -> Option<PathBuf> {
+        let path = env.var_os(env_var).map(PathBuf::from);
         if let Some(p) = &path {
             let p = p.display();
             info!("Using env {env_var}={p} to load {info}");
@@ -210,8 +237,6 @@ fn is_postgresql_string(s: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
-
     use super::*;
     use crate::test_utils::{os, some, FauxEnv};
     use crate::MartinError;
@@ -287,6 +312,44 @@ mod tests {
         assert!(args.check().is_ok());
     }
 
+    #[test]
+    fn test_merge_into_config_file() {
+        let mut args = Arguments::new(vec![]);
+        let env = FauxEnv(
+            vec![("DATABASE_URL_FILE", os("/run/secrets/db_url"))]
+                .into_iter()
+                .collect(),
+        );
+        let config = PgArgs::default().into_config(&mut args, &env);
+        assert_eq!(
+            config,
+            OptOneMany::One(PgConfig {
+                connection_string_file: Some(PathBuf::from("/run/secrets/db_url")),
+                ..Default::default()
+            })
+        );
+        assert!(args.check().is_ok());
+    }
+
+    #[test]
+    fn test_transaction_pooling() {
+        let mut args = Arguments::new(vec!["postgres://localhost:6432".to_string()]);
+        let pg_args = PgArgs {
+            transaction_pooling: true,
+            ..Default::default()
+        };
+        let config = pg_args.into_config(&mut args, &FauxEnv::default());
+        assert_eq!(
+            config,
+            OptOneMany::One(PgConfig {
+                connection_string: some("postgres://localhost:6432"),
+                transaction_pooling: Some(true),
+                ..Default::default()
+            })
+        );
+        assert!(args.check().is_ok());
+    }
+
     #[test]
     fn test_merge_into_config3() {
         let mut args = Arguments::new(vec![]);
@@ -3,23 +3,29 @@ use std::path::PathBuf;
 use clap::Parser;
 use log::warn;
 
+use crate::args::command::Command;
 use crate::args::connections::Arguments;
 use crate::args::environment::Env;
 use crate::args::srv::SrvArgs;
 use crate::config::Config;
 #[cfg(any(feature = "mbtiles", feature = "pmtiles", feature = "sprites"))]
 use crate::file_config::FileConfigEnum;
+use crate::file_config::InvalidSourcePolicy;
 use crate::MartinError::ConfigAndConnectionsError;
-use crate::{MartinResult, OptOneMany};
+use crate::{IdCollisionPolicy, MartinResult, OptOneMany};
 
 #[derive(Parser, Debug, PartialEq, Default)]
 #[command(
     about,
     version,
-    after_help = "Use RUST_LOG environment variable to control logging level, e.g. RUST_LOG=debug or RUST_LOG=martin=debug. See https://docs.rs/env_logger/latest/env_logger/index.html#enabling-logging for more information."
+    after_help = "Use RUST_LOG environment variable to control logging level, e.g. RUST_LOG=debug or RUST_LOG=martin=debug. See https://docs.rs/env_logger/latest/env_logger/index.html#enabling-logging for more information.",
+    subcommand_precedence_over_arg = true
 )]
 #[derive(Clone)]
 pub struct Args {
+    /// Run a command instead of starting the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[command(flatten)]
     pub meta: MetaArgs,
     #[command(flatten)]
@@ -37,9 +43,13 @@ pub struct Args {
 pub struct MetaArgs {
     // config may need a   conflicts_with = "SourcesArgs"
     // see https://github.com/clap-rs/clap/discussions/4562
-    /// Path to config file. If set, no tile source-related parameters are allowed.
-    #[arg(short, long)]
+    /// Path or URL of the config file. Supports local files, HTTP(S) and S3 URLs.
+    /// If set, no tile source-related parameters are allowed.
+    #[arg(short, long, global = true)]
     pub config: Option<PathBuf>,
+    /// Re-fetch the config file every SECONDS, and reload all sources if it has changed
+    #[arg(long, value_name = "SECONDS", requires = "config")]
+    pub config_refresh: Option<u64>,
     /// Save resulting config to a file or use "-" to print to stdout.
     /// By default, only print if sources are auto-detected.
     #[arg(long)]
@@ -47,6 +57,12 @@ pub struct MetaArgs {
     /// Main cache size (in MB)
     #[arg(short = 'C', long)]
     pub cache_size: Option<u64>,
+    /// What to do with a file source that cannot be opened, e.g. a corrupt file or an unreachable URL [default: abort]
+    #[arg(long, value_enum)]
+    pub on_invalid_source: Option<InvalidSourcePolicy>,
+    /// What to do with a source whose ID is already used by another source or is reserved [default: suffix]
+    #[arg(long, value_enum)]
+    pub id_collisions: Option<IdCollisionPolicy>,
     /// **Deprecated** Scan for new sources on sources list requests
     #[arg(short, long, hide = true)]
     pub watch: bool,
@@ -82,7 +98,15 @@ impl Args {
             config.cache_size_mb = self.meta.cache_size;
         }
 
-        self.srv.merge_into_config(&mut config.srv);
+        if self.meta.on_invalid_source.is_some() {
+            config.on_invalid_source = self.meta.on_invalid_source;
+        }
+
+        if self.meta.id_collisions.is_some() {
+            config.id_collisions = self.meta.id_collisions;
+        }
+
+        self.srv.merge_into_config(&mut config.srv, env);
 
         #[allow(unused_mut)]
         let mut cli_strings = Arguments::new(self.meta.connection);
@@ -182,6 +206,36 @@ mod tests {
         assert_eq!(args, expected);
     }
 
+    #[test]
+    fn cli_with_command() {
+        use crate::args::{Command, TileArgs};
+
+        let tile = Command::Tile(TileArgs {
+            source: "src".to_string(),
+            z: 1,
+            x: 0,
+            y: 1,
+            output: None,
+            inspect: false,
+            encoding: "identity".to_string(),
+            url_query: None,
+        });
+        // the config may be set before or after the command
+        for args in [
+            &["martin", "--config", "c.yaml", "tile", "src", "1", "0", "1"],
+            &["martin", "tile", "src", "1", "0", "1", "--config", "c.yaml"],
+        ] {
+            let args = Args::parse_from(args);
+            assert_eq!(args.command.as_ref(), Some(&tile));
+            assert_eq!(args.meta.config, Some(PathBuf::from("c.yaml")));
+        }
+
+        // connection strings do not consume the command name
+        let args = Args::parse_from(["martin", "a.mbtiles", "tile", "src", "1", "0", "1"]);
+        assert_eq!(args.meta.connection, vec!["a.mbtiles".to_string()]);
+        assert_eq!(args.command, Some(tile));
+    }
+
     #[cfg(feature = "postgres")]
     #[test]
     fn cli_with_config() {
@@ -218,6 +272,25 @@ mod tests {
         assert_eq!(args, (cfg, meta));
     }
 
+    #[test]
+    fn cli_config_refresh() {
+        let args = parse(&[
+            "martin",
+            "-c",
+            "s3://bucket/c.yaml",
+            "--config-refresh",
+            "60",
+        ]);
+        let meta = MetaArgs {
+            config: Some(PathBuf::from("s3://bucket/c.yaml")),
+            config_refresh: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(args.unwrap(), (Config::default(), meta));
+
+        assert!(Args::try_parse_from(["martin", "--config-refresh", "60"]).is_err());
+    }
+
     #[test]
     fn cli_encoding_arguments() {
         let config1 = parse(&["martin", "--preferred-encoding", "brotli"]);
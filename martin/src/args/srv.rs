@@ -1,6 +1,8 @@
 use clap::ValueEnum;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
+use crate::args::environment::Env;
 use crate::srv::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
 
 #[allow(clippy::doc_markdown)]
@@ -9,7 +11,7 @@ use crate::srv::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
 pub struct SrvArgs {
     #[arg(help = format!("Connection keep alive timeout. [DEFAULT: {KEEP_ALIVE_DEFAULT}]"), short, long)]
     pub keep_alive: Option<u64>,
-    #[arg(help = format!("The socket address to bind. [DEFAULT: {LISTEN_ADDRESSES_DEFAULT}]"), short, long)]
+    #[arg(help = format!("The socket address to bind. Use port 0 to let the OS pick a free port. [DEFAULT: {LISTEN_ADDRESSES_DEFAULT}, or 0.0.0.0:$PORT if the PORT env var is set]"), short, long)]
     pub listen_addresses: Option<String>,
     /// Set `TileJSON` URL path prefix, ignoring X-Rewrite-URL header. Must begin with a `/`. Examples: `/`, `/tiles`
     #[arg(long)]
@@ -17,9 +19,12 @@ pub struct SrvArgs {
     /// Number of web server workers
     #[arg(short = 'W', long)]
     pub workers: Option<usize>,
-    /// Martin server preferred tile encoding. If the client accepts multiple compression formats, and the tile source is not pre-compressed, which compression should be used. `gzip` is faster, but `brotli` is smaller, and may be faster with caching.  Defaults to gzip.
+    /// Martin server preferred tile encoding. If the client accepts multiple compression formats, and the tile source is not pre-compressed, which compression should be used. `gzip` is faster, but `brotli` and `zstd` are smaller, and may be faster with caching.  Defaults to gzip.
     #[arg(long)]
     pub preferred_encoding: Option<PreferredEncoding>,
+    /// Serve only tiles and metadata. Disables the /refresh endpoint and the admin endpoints, e.g. for public-facing replicas.
+    #[arg(long)]
+    pub read_only: bool,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
@@ -29,10 +34,11 @@ pub enum PreferredEncoding {
     #[clap(alias("br"))]
     Brotli,
     Gzip,
+    Zstd,
 }
 
 impl SrvArgs {
-    pub(crate) fn merge_into_config(self, srv_config: &mut SrvConfig) {
+    pub(crate) fn merge_into_config<'a>(self, srv_config: &mut SrvConfig, env: &impl Env<'a>) {
         // Override config values with the ones from the command line
         if self.keep_alive.is_some() {
             srv_config.keep_alive = self.keep_alive;
@@ -49,5 +55,65 @@ impl SrvArgs {
         if self.base_path.is_some() {
             srv_config.base_path = self.base_path;
         }
+        if self.read_only {
+            srv_config.read_only = Some(true);
+        }
+        // Platforms like Cloud Run and Heroku tell the server which port to use
+        if srv_config.listen_addresses.is_none() {
+            if let Some(port) = env.get_env_str("PORT") {
+                match port.trim().parse::<u16>() {
+                    Ok(port) => srv_config.listen_addresses = Some(format!("0.0.0.0:{port}")),
+                    Err(_) => {
+                        warn!("Ignoring the PORT environment variable, {port} is not a valid port");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{os, some, FauxEnv};
+
+    #[test]
+    fn port_env_var() {
+        let env = FauxEnv(vec![("PORT", os("8080"))].into_iter().collect());
+        let mut config = SrvConfig::default();
+        SrvArgs::default().merge_into_config(&mut config, &env);
+        assert_eq!(config.listen_addresses, some("0.0.0.0:8080"));
+
+        // explicitly configured addresses take precedence
+        let args = SrvArgs {
+            listen_addresses: some("127.0.0.1:3000"),
+            ..Default::default()
+        };
+        let mut config = SrvConfig::default();
+        args.merge_into_config(&mut config, &env);
+        assert_eq!(config.listen_addresses, some("127.0.0.1:3000"));
+
+        let env = FauxEnv(vec![("PORT", os("http"))].into_iter().collect());
+        let mut config = SrvConfig::default();
+        SrvArgs::default().merge_into_config(&mut config, &env);
+        assert_eq!(config.listen_addresses, None);
+    }
+
+    #[test]
+    fn read_only_arg() {
+        let env = FauxEnv::default();
+        let mut config = SrvConfig {
+            read_only: Some(false),
+            ..Default::default()
+        };
+        SrvArgs::default().merge_into_config(&mut config, &env);
+        assert_eq!(config.read_only, Some(false));
+
+        let args = SrvArgs {
+            read_only: true,
+            ..Default::default()
+        };
+        args.merge_into_config(&mut config, &env);
+        assert_eq!(config.read_only, Some(true));
     }
 }
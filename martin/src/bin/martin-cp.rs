@@ -14,8 +14,8 @@ use log::{debug, error, info, log_enabled};
 use martin::args::{Args, ExtraArgs, MetaArgs, OsEnv, SrvArgs};
 use martin::srv::{merge_tilejson, DynTileSource};
 use martin::{
-    append_rect, read_config, Config, MartinError, MartinResult, ServerState, Source, TileCoord,
-    TileData, TileRect,
+    append_rect, load_config, Config, MartinError, ServerState, Source, TileCoord, TileData,
+    TileRect,
 };
 use martin_tile_utils::{bbox_to_xyz, TileInfo};
 use mbtiles::sqlx::SqliteConnection;
@@ -29,6 +29,20 @@ use tokio::sync::mpsc::channel;
 use tokio::time::Instant;
 use tokio::try_join;
 
+// glibc malloc fragments badly with many large tiles in the cache, inflating the memory use.
+// If both allocators are enabled, e.g. with --all-features, mimalloc is used.
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(
+    feature = "jemalloc",
+    not(feature = "mimalloc"),
+    not(target_env = "msvc")
+))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const SAVE_EVERY: Duration = Duration::from_secs(60);
 const PROGRESS_REPORT_AFTER: u64 = 100;
@@ -128,13 +142,14 @@ async fn start(copy_args: CopierArgs) -> MartinCpResult<()> {
     let save_config = copy_args.meta.save_config.clone();
     let mut config = if let Some(ref cfg_filename) = copy_args.meta.config {
         info!("Using {}", cfg_filename.display());
-        read_config(cfg_filename, &env)?
+        load_config(cfg_filename, &env).await?
     } else {
         info!("Config file is not specified, auto-detecting sources");
         Config::default()
     };
 
     let args = Args {
+        command: None,
         meta: copy_args.meta,
         extras: ExtraArgs::default(),
         srv: SrvArgs::default(),
@@ -314,7 +329,7 @@ async fn run_tile_copy(args: CopyArgs, state: ServerState) -> MartinCpResult<()>
         // Note: for some reason, tests hang here without the `move` keyword
         async move {
             stream::iter(iterate_tiles(tiles))
-                .map(MartinResult::Ok)
+                .map(MartinCpResult::Ok)
                 .try_for_each_concurrent(concurrency, |xyz| {
                     let tx = tx.clone();
                     async move {
@@ -1,19 +1,48 @@
+use std::path::Path;
+
 use clap::Parser;
 use log::{error, info, log_enabled};
-use martin::args::{Args, OsEnv};
+use martin::args::{Args, Command, OsEnv};
+use martin::commands::{
+    bench_server, bench_sources, format_sources, get_style, get_tile, get_tilejson, list_sources,
+};
 use martin::srv::new_server;
-use martin::{read_config, Config, MartinResult};
+use martin::{
+    configure_logging, init_logging, load_config, Config, MartinResult, LOG_LEVEL_DEFAULT,
+};
+
+// glibc malloc fragments badly with many large tiles in the cache, inflating the memory use.
+// If both allocators are enabled, e.g. with --all-features, mimalloc is used.
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(
+    feature = "jemalloc",
+    not(feature = "mimalloc"),
+    not(target_env = "msvc")
+))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 async fn start(args: Args) -> MartinResult<()> {
+    // benchmarking a running server does not need any sources
+    if let Some(Command::Bench(bench)) = &args.command {
+        if let Some(url) = &bench.url {
+            println!("{}", bench_server(bench, url).await?);
+            return Ok(());
+        }
+    }
+
     info!("Starting Martin v{VERSION}");
 
     let env = OsEnv::default();
     let save_config = args.meta.save_config.clone();
     let mut config = if let Some(ref cfg_filename) = args.meta.config {
         info!("Using {}", cfg_filename.display());
-        read_config(cfg_filename, &env)?
+        load_config(cfg_filename, &env).await?
     } else {
         info!("Config file is not specified, auto-detecting sources");
         Config::default()
@@ -21,6 +50,9 @@ async fn start(args: Args) -> MartinResult<()> {
     let args_cloned = args.clone();
     args.merge_into_config(&mut config, &env)?;
     config.finalize()?;
+    if let Some(log) = &config.srv.log {
+        configure_logging(log)?;
+    }
     let sources = config.resolve().await?;
 
     if let Some(file_name) = save_config {
@@ -29,16 +61,58 @@ async fn start(args: Args) -> MartinResult<()> {
         info!("Use --save-config to save or print Martin configuration.");
     }
 
+    match &args_cloned.command {
+        Some(Command::Bench(bench)) => {
+            println!("{}", bench_sources(bench, &sources).await?);
+            return Ok(());
+        }
+        Some(Command::Tile(tile)) => {
+            let summary = get_tile(tile, &sources).await?;
+            // the tile itself may be written to stdout
+            if tile.output.as_deref() == Some(Path::new("-")) {
+                eprintln!("{summary}");
+            } else {
+                println!("{summary}");
+            }
+            return Ok(());
+        }
+        Some(Command::List(list)) => {
+            let entries = list_sources(&sources, &config.srv);
+            println!("{}", format_sources(&entries, list.format)?);
+            return Ok(());
+        }
+        Some(Command::Style(style)) => {
+            let style = get_style(style, &sources, &config.srv)?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&style).unwrap_or_default()
+            );
+            return Ok(());
+        }
+        Some(Command::TileJson(tilejson)) => {
+            let tilejson = get_tilejson(tilejson, &sources, &config.srv)?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&tilejson).unwrap_or_default()
+            );
+            return Ok(());
+        }
+        None => {}
+    }
+
     let (server, listen_addresses) = new_server(env, args_cloned, config.srv, sources)?;
     info!("Martin has been started on {listen_addresses}.");
     info!("Use http://{listen_addresses}/catalog to get the list of available sources.");
+    #[cfg(feature = "systemd")]
+    martin::srv::notify_ready();
     server.await
 }
 
 #[actix_web::main]
 async fn main() {
-    let env = env_logger::Env::default().default_filter_or("martin=info");
-    env_logger::Builder::from_env(env).init();
+    init_logging(LOG_LEVEL_DEFAULT);
+    #[cfg(feature = "console")]
+    console_subscriber::init();
 
     if let Err(e) = start(Args::parse()).await {
         // Ensure the message is printed, even if the logging is disabled
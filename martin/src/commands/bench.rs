@@ -0,0 +1,342 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::ACCEPT_ENCODING;
+use futures::stream::{self, StreamExt};
+use log::info;
+use martin_tile_utils::bbox_to_xyz;
+use tilejson::{Bounds, TileJSON};
+
+use crate::args::BenchArgs;
+use crate::commands::accept_encoding;
+use crate::srv::DynTileSource;
+use crate::MartinError::CommandError;
+use crate::{MartinResult, ServerState, TileCoord};
+
+/// Number of tiles a map client requests for a single view
+const VIEW_WIDTH: u32 = 4;
+const VIEW_HEIGHT: u32 = 3;
+/// Number of popular areas, most views are close to one of them
+const HOTSPOTS: u32 = 16;
+/// Percentage of the views that are close to a popular area
+const HOTSPOT_VIEWS: u64 = 80;
+
+/// Results of a benchmark run
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub elapsed: Duration,
+    /// Latency of each successful request, sorted
+    pub latencies: Vec<Duration>,
+    /// Number of successful requests that returned no data
+    pub empty: u64,
+    pub errors: u64,
+    /// Number of bytes of all tiles, as sent by the server
+    pub bytes: u64,
+}
+
+impl BenchReport {
+    /// Latency at the given percentile, e.g. `99.0`
+    #[must_use]
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+
+    #[must_use]
+    pub fn requests(&self) -> u64 {
+        self.latencies.len() as u64 + self.errors
+    }
+}
+
+impl Display for BenchReport {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        let rate = |v: f64| if secs > 0.0 { v / secs } else { 0.0 };
+        writeln!(
+            f,
+            "Requests:   {} in {:.2?} ({} empty, {} errors)",
+            self.requests(),
+            self.elapsed,
+            self.empty,
+            self.errors
+        )?;
+        writeln!(
+            f,
+            "Throughput: {:.1} requests/s, {:.1} KiB/s",
+            rate(self.requests() as f64),
+            rate(self.bytes as f64) / 1024.0
+        )?;
+        write!(
+            f,
+            "Latency:    p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default()
+        )
+    }
+}
+
+/// Small deterministic random number generator (xorshift64*), so that runs with the same seed
+/// request the same tiles
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Random number in the inclusive range
+    fn range(&mut self, min: u32, max: u32) -> u32 {
+        let span = u64::from(max - min) + 1;
+        min + u32::try_from(self.next() % span).unwrap_or_default()
+    }
+
+    /// Random value between 0 and 1
+    #[allow(clippy::cast_precision_loss)]
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+/// Generate tile requests like map clients do: each view of the map requests a block of adjacent tiles,
+/// and most views are close to a few popular areas, so some tiles are requested much more often than others.
+fn tile_requests(zoom: (u8, u8), bounds: Bounds, seed: u64) -> impl Iterator<Item = TileCoord> {
+    let mut rnd = Random::new(seed);
+    let hotspots: Vec<(f64, f64)> = (0..HOTSPOTS)
+        .map(|_| {
+            (
+                bounds.left + rnd.unit() * (bounds.right - bounds.left),
+                bounds.bottom + rnd.unit() * (bounds.top - bounds.bottom),
+            )
+        })
+        .collect();
+
+    std::iter::repeat(()).flat_map(move |()| {
+        let z = u8::try_from(rnd.range(zoom.0.into(), zoom.1.into())).unwrap_or(zoom.0);
+        let (min_x, min_y, max_x, max_y) =
+            bbox_to_xyz(bounds.left, bounds.bottom, bounds.right, bounds.top, z);
+        let (x, y) = if rnd.next() % 100 < HOTSPOT_VIEWS {
+            let (lon, lat) = hotspots[rnd.range(0, HOTSPOTS - 1) as usize];
+            let (x, y, _, _) = bbox_to_xyz(lon, lat, lon, lat, z);
+            // pan around the popular area by a view or so
+            let mut pan = |v: u32, size: u32, min: u32, max: u32| {
+                let v = (v + rnd.range(0, 2 * size)).saturating_sub(size);
+                v.clamp(min, max)
+            };
+            (
+                pan(x, VIEW_WIDTH, min_x, max_x),
+                pan(y, VIEW_HEIGHT, min_y, max_y),
+            )
+        } else {
+            (rnd.range(min_x, max_x), rnd.range(min_y, max_y))
+        };
+        let xs = x.saturating_sub(VIEW_WIDTH / 2).max(min_x)..=(x + VIEW_WIDTH / 2).min(max_x);
+        let ys = y.saturating_sub(VIEW_HEIGHT / 2).max(min_y)..=(y + VIEW_HEIGHT / 2).min(max_y);
+        xs.flat_map(move |x| ys.clone().map(move |y| TileCoord { z, x, y }))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Zoom levels to request, limited to the zoom levels of the source
+fn zoom_range(args: &BenchArgs, tilejson: &TileJSON) -> MartinResult<(u8, u8)> {
+    let min = (*args.zoom.start()).max(tilejson.minzoom.unwrap_or(0));
+    let max = (*args.zoom.end()).min(tilejson.maxzoom.unwrap_or(30));
+    if min > max {
+        return Err(CommandError(format!(
+            "source {} has no zoom levels between {} and {}",
+            args.source,
+            args.zoom.start(),
+            args.zoom.end()
+        )));
+    }
+    Ok((min, max))
+}
+
+/// Result of a single request: the size of the tile, or `None` if it failed
+type Sample = (Duration, Option<u64>);
+
+async fn run<F, Fut>(args: &BenchArgs, tilejson: &TileJSON, fetch: F) -> MartinResult<BenchReport>
+where
+    F: Fn(TileCoord) -> Fut,
+    Fut: std::future::Future<Output = Option<u64>>,
+{
+    let zoom = zoom_range(args, tilejson)?;
+    let bounds = tilejson.bounds.unwrap_or_default();
+    let max_duration = args.duration.map(Duration::from_secs);
+    info!(
+        "Requesting {} tiles of source {} at zoom {}-{} with {} concurrent requests",
+        args.requests, args.source, zoom.0, zoom.1, args.concurrency
+    );
+
+    let start = Instant::now();
+    let samples: Vec<Sample> = stream::iter(tile_requests(zoom, bounds, args.seed))
+        .take(usize::try_from(args.requests).unwrap_or(usize::MAX))
+        .take_while(|_| std::future::ready(max_duration.map_or(true, |d| start.elapsed() < d)))
+        .map(|xyz| {
+            let fut = fetch(xyz);
+            async move {
+                let started = Instant::now();
+                let size = fut.await;
+                (started.elapsed(), size)
+            }
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut report = BenchReport {
+        elapsed: start.elapsed(),
+        ..Default::default()
+    };
+    for (latency, size) in samples {
+        match size {
+            Some(size) => {
+                report.latencies.push(latency);
+                report.bytes += size;
+                if size == 0 {
+                    report.empty += 1;
+                }
+            }
+            None => report.errors += 1,
+        }
+    }
+    report.latencies.sort_unstable();
+    Ok(report)
+}
+
+/// Request tiles from the resolved sources, without the HTTP server
+pub async fn bench_sources(args: &BenchArgs, state: &ServerState) -> MartinResult<BenchReport> {
+    let tilejson = state
+        .tiles
+        .get_source(&args.source)
+        .map_err(|e| CommandError(e.to_string()))?
+        .get_tilejson()
+        .clone();
+    let src = DynTileSource::new(
+        &state.tiles,
+        &args.source,
+        None,
+        args.url_query.as_deref().unwrap_or_default(),
+        Some(accept_encoding(&args.encoding)?),
+        None,
+        state.cache.as_ref(),
+    )
+    .map_err(|e| CommandError(e.to_string()))?;
+    let src = &src;
+
+    run(args, &tilejson, |xyz| async move {
+        let tile = src.get_tile_content(xyz).await.ok()?;
+        Some(tile.data.len() as u64)
+    })
+    .await
+}
+
+/// Request tiles from a running server
+pub async fn bench_server(args: &BenchArgs, url: &str) -> MartinResult<BenchReport> {
+    let client = reqwest::Client::new();
+    let base = url.trim_end_matches('/');
+    let tilejson: TileJSON = client
+        .get(format!("{base}/{}", args.source))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| CommandError(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| CommandError(format!("invalid TileJSON of {}: {e}", args.source)))?;
+    let query = args
+        .url_query
+        .as_deref()
+        .map(|q| format!("?{q}"))
+        .unwrap_or_default();
+    let (client, query) = (&client, &query);
+
+    run(args, &tilejson, |xyz| async move {
+        let response = client
+            .get(format!("{base}/{}/{xyz:#}{query}", args.source))
+            .header(ACCEPT_ENCODING.as_str(), args.encoding.as_str())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .ok()?;
+        let body = response.bytes().await.ok()?;
+        Some(body.len() as u64)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_tile_requests() {
+        let bounds = Bounds::new(-10.0, -10.0, 10.0, 10.0);
+        let tiles: Vec<_> = tile_requests((2, 8), bounds, 7).take(5000).collect();
+        assert_eq!(
+            tiles,
+            tile_requests((2, 8), bounds, 7)
+                .take(5000)
+                .collect::<Vec<_>>()
+        );
+        assert_ne!(
+            tiles,
+            tile_requests((2, 8), bounds, 8)
+                .take(5000)
+                .collect::<Vec<_>>()
+        );
+
+        let mut counts = HashMap::new();
+        for xyz in &tiles {
+            assert!((2..=8).contains(&xyz.z));
+            let (min_x, min_y, max_x, max_y) = bbox_to_xyz(-10.0, -10.0, 10.0, 10.0, xyz.z);
+            assert!((min_x..=max_x).contains(&xyz.x), "{xyz}");
+            assert!((min_y..=max_y).contains(&xyz.y), "{xyz}");
+            *counts.entry(*xyz).or_insert(0) += 1;
+        }
+        // popular tiles are requested many times
+        assert!(counts.values().any(|v| *v > 20));
+    }
+
+    #[test]
+    fn test_report() {
+        let report = BenchReport {
+            elapsed: Duration::from_secs(2),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            empty: 5,
+            errors: 2,
+            bytes: 2048,
+        };
+        assert_eq!(report.requests(), 102);
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(
+            report.to_string(),
+            "Requests:   102 in 2.00s (5 empty, 2 errors)\n\
+             Throughput: 51.0 requests/s, 1.0 KiB/s\n\
+             Latency:    p50 50.00ms, p90 90.00ms, p99 99.00ms, max 100.00ms"
+        );
+        assert_eq!(BenchReport::default().percentile(50.0), Duration::ZERO);
+    }
+}
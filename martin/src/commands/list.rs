@@ -0,0 +1,183 @@
+use std::fmt::Write as _;
+
+use serde::Serialize;
+use tilejson::Bounds;
+
+use crate::args::ListFormat;
+use crate::srv::SrvConfig;
+use crate::MartinError::CommandError;
+use crate::{MartinResult, ServerState, TileSources};
+
+/// A tile source, as listed by the `list` command
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SourceListEntry {
+    pub id: String,
+    /// Tenant of the source, or `None` for the sources of the main config
+    pub tenant: Option<String>,
+    #[serde(rename = "type")]
+    pub source_type: &'static str,
+    pub format: String,
+    pub encoding: Option<String>,
+    pub minzoom: Option<u8>,
+    pub maxzoom: Option<u8>,
+    pub bounds: Option<Bounds>,
+    /// Served, but not listed in the catalog
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+}
+
+impl SourceListEntry {
+    fn zoom(&self) -> String {
+        match (self.minzoom, self.maxzoom) {
+            (None, None) => String::new(),
+            (min, max) => format!("{}-{}", min.unwrap_or(0), max.unwrap_or(30)),
+        }
+    }
+
+    fn path(&self) -> String {
+        match &self.tenant {
+            Some(tenant) => format!("t/{tenant}/{}", self.id),
+            None => self.id.clone(),
+        }
+    }
+}
+
+fn list(sources: &TileSources, srv: &SrvConfig, tenant: Option<&str>) -> Vec<SourceListEntry> {
+    sources
+        .get_catalog()
+        .into_keys()
+        .filter_map(|id| {
+            let src = sources.get_source(&id).ok()?;
+            let tilejson = src.get_tilejson();
+            let info = src.get_tile_info();
+            let hidden = srv
+                .get_source_options(&id)
+                .and_then(|o| o.hidden)
+                .unwrap_or_default();
+            Some(SourceListEntry {
+                tenant: tenant.map(ToString::to_string),
+                source_type: src.get_source_type(),
+                format: info.format.to_string(),
+                encoding: info.encoding.content_encoding().map(ToString::to_string),
+                minzoom: tilejson.minzoom,
+                maxzoom: tilejson.maxzoom,
+                bounds: tilejson.bounds,
+                hidden,
+                id,
+            })
+        })
+        .collect()
+}
+
+/// All tile sources of the main config and of the tenants, sorted by tenant and ID
+#[must_use]
+pub fn list_sources(state: &ServerState, srv: &SrvConfig) -> Vec<SourceListEntry> {
+    let mut result = list(&state.tiles, srv, None);
+    for (name, tenant) in &state.tenants {
+        result.extend(list(&tenant.state.tiles, &tenant.srv, Some(name)));
+    }
+    result
+}
+
+/// Format the sources as an aligned table, or as JSON
+pub fn format_sources(sources: &[SourceListEntry], format: ListFormat) -> MartinResult<String> {
+    if format == ListFormat::Json {
+        return serde_json::to_string_pretty(sources)
+            .map_err(|e| CommandError(format!("unable to serialize the sources: {e}")));
+    }
+
+    let header = ["ID", "TYPE", "FORMAT", "ZOOM", "BOUNDS"].map(ToString::to_string);
+    let rows: Vec<[String; 5]> = sources
+        .iter()
+        .map(|src| {
+            let mut id = src.path();
+            if src.hidden {
+                id.push_str(" (hidden)");
+            }
+            let format = match &src.encoding {
+                Some(encoding) => format!("{} ({encoding})", src.format),
+                None => src.format.clone(),
+            };
+            let bounds = src.bounds.map(|b| b.to_string()).unwrap_or_default();
+            [id, src.source_type.to_string(), format, src.zoom(), bounds]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|v| v.len());
+    for row in &rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.len());
+        }
+    }
+    let mut result = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .fold(String::new(), |mut line, (value, width)| {
+                let _ = write!(line, "{value:width$}  ");
+                line
+            });
+        result.push_str(line.trim_end());
+        result.push('\n');
+    }
+    Ok(result.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use indoc::indoc;
+
+    use super::*;
+    use crate::debug::{DebugConfig, DebugFormat};
+    use crate::srv::SourceOptions;
+    use crate::Config;
+
+    #[actix_rt::test]
+    async fn test_list_sources() {
+        let mut config = Config {
+            debug: Some(BTreeMap::from([
+                ("vector".to_string(), DebugConfig::default()),
+                (
+                    "raster".to_string(),
+                    DebugConfig {
+                        format: Some(DebugFormat::Png),
+                        minzoom: Some(2),
+                        maxzoom: Some(12),
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+        config.srv.source_options = Some(BTreeMap::from([(
+            "vector".to_string(),
+            SourceOptions {
+                hidden: Some(true),
+                ..Default::default()
+            },
+        )]));
+        config.finalize().unwrap();
+        let state = config.resolve().await.unwrap();
+
+        let sources = list_sources(&state, &config.srv);
+        assert_eq!(
+            format_sources(&sources, ListFormat::Table).unwrap(),
+            indoc! {"
+                ID               TYPE   FORMAT  ZOOM  BOUNDS
+                raster           debug  png     2-12
+                vector (hidden)  debug  mvt"}
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_sources(&sources, ListFormat::Json).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"id": "raster", "type": "debug", "format": "png", "minzoom": 2, "maxzoom": 12},
+                {"id": "vector", "type": "debug", "format": "mvt", "hidden": true},
+            ])
+        );
+    }
+}
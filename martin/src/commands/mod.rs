@@ -0,0 +1,30 @@
+//! Commands of the `martin` binary that use the configured sources without starting the server
+
+use actix_web::http::header::{AcceptEncoding, Header as _, ACCEPT_ENCODING};
+use actix_web::test::TestRequest;
+
+use crate::MartinError::CommandError;
+use crate::MartinResult;
+
+mod bench;
+pub use bench::{bench_server, bench_sources, BenchReport};
+
+mod list;
+pub use list::{format_sources, list_sources, SourceListEntry};
+
+mod style;
+pub use style::get_style;
+
+mod tile;
+pub use tile::{get_tile, TileSummary};
+
+mod tilejson;
+pub use tilejson::get_tilejson;
+
+/// Parse an `Accept-Encoding` header value, e.g. `gzip, br`
+fn accept_encoding(value: &str) -> MartinResult<AcceptEncoding> {
+    let req = TestRequest::default()
+        .insert_header((ACCEPT_ENCODING, value))
+        .to_http_request();
+    AcceptEncoding::parse(&req).map_err(|e| CommandError(format!("invalid encoding {value}: {e}")))
+}
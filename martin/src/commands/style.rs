@@ -0,0 +1,19 @@
+use serde_json::Value;
+
+use crate::args::StyleArgs;
+use crate::srv::{generate_style, SrvConfig};
+use crate::MartinError::CommandError;
+use crate::{MartinResult, ServerState};
+
+/// The style the server would serve at `/style/{source}`, using the `TileJSON` on the given public URL
+pub fn get_style(args: &StyleArgs, state: &ServerState, srv: &SrvConfig) -> MartinResult<Value> {
+    let sources = state
+        .tiles
+        .get_sources(&args.source, None)
+        .map_err(|e| CommandError(e.to_string()))?
+        .0;
+    let base_url = args.base_url.trim_end_matches('/');
+    let base_path = srv.base_path.as_deref().unwrap_or_default();
+    let tilejson_url = format!("{base_url}{base_path}/{}", args.source);
+    Ok(generate_style(&args.source, &sources, &tilejson_url))
+}
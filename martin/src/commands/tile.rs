@@ -0,0 +1,157 @@
+use std::fmt::{Display, Formatter};
+use std::io::Write as _;
+use std::path::Path;
+
+use martin_tile_utils::{Encoding, Format, TileInfo};
+
+use crate::args::TileArgs;
+use crate::commands::accept_encoding;
+use crate::srv::DynTileSource;
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd, mvt_layer_stats, MvtLayerStats};
+use crate::MartinError::CommandError;
+use crate::{MartinResult, ServerState, TileCoord};
+
+/// Description of a tile, printed by the `tile` command
+#[derive(Debug)]
+pub struct TileSummary {
+    pub source: String,
+    pub xyz: TileCoord,
+    pub info: TileInfo,
+    pub size: usize,
+    /// Layers of a vector tile, if it was inspected
+    pub layers: Option<Vec<MvtLayerStats>>,
+}
+
+impl Display for TileSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tile {:#} of {}: ", self.xyz, self.source)?;
+        if self.size == 0 {
+            write!(f, "empty")?;
+        } else {
+            write!(f, "{} bytes, {}", self.size, self.info)?;
+        }
+        for layer in self.layers.iter().flatten() {
+            write!(
+                f,
+                "\n  {}: {} features ({} points, {} lines, {} polygons), extent {}, {} bytes",
+                layer.name,
+                layer.features,
+                layer.points,
+                layer.lines,
+                layer.polygons,
+                layer.extent,
+                layer.size
+            )?;
+            if !layer.keys.is_empty() {
+                write!(f, "\n    attributes: {}", layer.keys.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get a tile from the resolved sources, and save it to the output file if there is one
+pub async fn get_tile(args: &TileArgs, state: &ServerState) -> MartinResult<TileSummary> {
+    let xyz = TileCoord {
+        z: args.z,
+        x: args.x,
+        y: args.y,
+    };
+    if !xyz.is_valid() {
+        return Err(CommandError(format!("invalid tile coordinates {xyz:#}")));
+    }
+    let src = DynTileSource::new(
+        &state.tiles,
+        &args.source,
+        Some(xyz.z),
+        args.url_query.as_deref().unwrap_or_default(),
+        Some(accept_encoding(&args.encoding)?),
+        None,
+        None,
+    )
+    .map_err(|e| CommandError(e.to_string()))?;
+    let tile = src
+        .get_tile_content(xyz)
+        .await
+        .map_err(|e| CommandError(e.to_string()))?;
+
+    if let Some(output) = &args.output {
+        if output == Path::new("-") {
+            std::io::stdout().write_all(&tile.data)?;
+        } else {
+            std::fs::write(output, &tile.data)?;
+        }
+    }
+
+    let layers = if args.inspect && tile.info.format == Format::Mvt {
+        let data = match tile.info.encoding {
+            Encoding::Gzip => decode_gzip(&tile.data)?,
+            Encoding::Brotli => decode_brotli(&tile.data)?,
+            Encoding::Zstd => decode_zstd(&tile.data)?,
+            _ => tile.data.to_vec(),
+        };
+        Some(mvt_layer_stats(&data)?)
+    } else {
+        None
+    };
+
+    Ok(TileSummary {
+        source: args.source.clone(),
+        xyz,
+        info: tile.info,
+        size: tile.data.len(),
+        layers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use clap::Parser as _;
+
+    use super::*;
+    use crate::args::{Args, Command};
+    use crate::debug::DebugConfig;
+    use crate::Config;
+
+    fn tile_args(args: &[&str]) -> TileArgs {
+        match Args::parse_from(args).command {
+            Some(Command::Tile(args)) => args,
+            v => panic!("unexpected command {v:?}"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_get_tile() {
+        let mut config = Config {
+            debug: Some(BTreeMap::from([(
+                "dbg".to_string(),
+                DebugConfig::default(),
+            )])),
+            ..Default::default()
+        };
+        config.finalize().unwrap();
+        let state = config.resolve().await.unwrap();
+
+        let args = tile_args(&["martin", "tile", "dbg", "3", "2", "5", "--inspect"]);
+        let summary = get_tile(&args, &state).await.unwrap();
+        let layers = summary.layers.as_ref().unwrap();
+        assert_eq!(layers[0].name, "debug");
+        assert_eq!(layers[0].polygons, 1);
+        assert!(summary.to_string().starts_with(&format!(
+            "Tile 3/2/5 of dbg: {} bytes, application/x-protobuf\n  debug: 2 features (1 points, 0 lines, 1 polygons)",
+            summary.size
+        )));
+
+        let args = tile_args(&["martin", "tile", "dbg", "3", "2", "5", "--encoding", "gzip"]);
+        let summary = get_tile(&args, &state).await.unwrap();
+        assert_eq!(summary.info.encoding, Encoding::Gzip);
+        assert!(summary.layers.is_none());
+
+        let args = tile_args(&["martin", "tile", "dbg", "1", "2", "0"]);
+        assert!(get_tile(&args, &state).await.is_err());
+        let args = tile_args(&["martin", "tile", "missing", "0", "0", "0"]);
+        assert!(get_tile(&args, &state).await.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use tilejson::TileJSON;
+
+use crate::args::TileJsonArgs;
+use crate::srv::{merge_tilejson, SrvConfig};
+use crate::MartinError::CommandError;
+use crate::{MartinResult, ServerState};
+
+/// The `TileJSON` the server would serve for the source, with the tile URLs on the given public URL
+pub fn get_tilejson(
+    args: &TileJsonArgs,
+    state: &ServerState,
+    srv: &SrvConfig,
+) -> MartinResult<TileJSON> {
+    let sources = state
+        .tiles
+        .get_sources(&args.source, None)
+        .map_err(|e| CommandError(e.to_string()))?
+        .0;
+    let base_url = args.base_url.trim_end_matches('/');
+    let base_path = srv.base_path.as_deref().unwrap_or_default();
+    let mut tiles_url = format!("{base_url}{base_path}/{}/{{z}}/{{x}}/{{y}}", args.source);
+    if let Some(query) = args.url_query.as_deref().filter(|v| !v.is_empty()) {
+        tiles_url.push('?');
+        tiles_url.push_str(query);
+    }
+    Ok(merge_tilejson(&sources, tiles_url))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::debug::DebugConfig;
+    use crate::Config;
+
+    #[actix_rt::test]
+    async fn test_get_tilejson() {
+        let mut config = Config {
+            debug: Some(BTreeMap::from([
+                ("a".to_string(), DebugConfig::default()),
+                ("b".to_string(), DebugConfig::default()),
+            ])),
+            ..Default::default()
+        };
+        config.srv.base_path = Some("/tiles".to_string());
+        config.finalize().unwrap();
+        let state = config.resolve().await.unwrap();
+        let args = |source: &str, url_query: Option<&str>| TileJsonArgs {
+            source: source.to_string(),
+            base_url: "https://example.com/".to_string(),
+            url_query: url_query.map(ToString::to_string),
+        };
+
+        let tj = get_tilejson(&args("a", None), &state, &config.srv).unwrap();
+        assert_eq!(tj.tiles, vec!["https://example.com/tiles/a/{z}/{x}/{y}"]);
+        assert_eq!(tj.name.as_deref(), Some("a"));
+        let tj = get_tilejson(&args("a,b", Some("key=1")), &state, &config.srv).unwrap();
+        assert_eq!(
+            tj.tiles,
+            vec!["https://example.com/tiles/a,b/{z}/{x}/{y}?key=1"]
+        );
+        assert_eq!(tj.vector_layers.unwrap().len(), 2);
+        assert!(get_tilejson(&args("c", None), &state, &config.srv).is_err());
+    }
+}
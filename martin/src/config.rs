@@ -1,31 +1,42 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::future::Future;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::Duration;
 
 use futures::future::try_join_all;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use subst::VariableMap;
 
-#[cfg(any(feature = "mbtiles", feature = "pmtiles", feature = "sprites"))]
+use crate::debug::{DebugConfig, DebugSource};
+#[cfg(any(
+    feature = "gdal",
+    feature = "mbtiles",
+    feature = "pmtiles",
+    feature = "sprites"
+))]
 use crate::file_config::FileConfigEnum;
+use crate::file_config::InvalidSourcePolicy;
 #[cfg(feature = "fonts")]
 use crate::fonts::FontSources;
 use crate::source::{TileInfoSources, TileSources};
 #[cfg(feature = "sprites")]
 use crate::sprites::{SpriteConfig, SpriteSources};
-use crate::srv::{SrvConfig, RESERVED_KEYWORDS};
-use crate::utils::{parse_base_path, CacheValue, MainCache, OptMainCache};
-use crate::MartinError::{ConfigLoadError, ConfigParseError, ConfigWriteError, NoSources};
-use crate::{IdResolver, MartinResult, OptOneMany};
+use crate::srv::{SecurityHeadersConfig, SourceOptions, SrvConfig, UrlTemplate, RESERVED_KEYWORDS};
+use crate::upstream::UpstreamConfig;
+use crate::utils::{fetch_text, is_remote, parse_base_path, CacheValue, MainCache, OptMainCache};
+use crate::MartinError::{
+    ConfigLoadError, ConfigParseError, ConfigWriteError, IdCollisions, InvalidTenant, NoSources,
+};
+use crate::{IdCollisionPolicy, IdResolver, MartinResult, OptOneMany};
 
 pub type UnrecognizedValues = HashMap<String, serde_yaml::Value>;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ServerState {
     pub cache: OptMainCache,
     pub tiles: TileSources,
@@ -33,6 +44,55 @@ pub struct ServerState {
     pub sprites: SpriteSources,
     #[cfg(feature = "fonts")]
     pub fonts: FontSources,
+    /// IDs of the Postgres function sources by their `schema.function` name,
+    /// used by the legacy `/rpc` routes
+    #[cfg(feature = "postgres")]
+    pub pg_functions: HashMap<String, String>,
+    /// Parsed `url_templates` source options with their source IDs, matched against every request
+    pub url_templates: Vec<(String, UrlTemplate)>,
+    /// Isolated sources of each tenant, by tenant name
+    pub tenants: BTreeMap<String, TenantState>,
+    /// Items that failed to be discovered and were skipped, e.g. a file that cannot be opened
+    pub discovery_failures: Vec<String>,
+}
+
+impl ServerState {
+    /// Items that failed to be discovered, including those of the tenants
+    #[must_use]
+    pub fn all_discovery_failures(&self) -> Vec<String> {
+        let tenants = self.tenants.iter().flat_map(|(name, tenant)| {
+            tenant
+                .state
+                .discovery_failures
+                .iter()
+                .map(move |v| format!("tenant {name}: {v}"))
+        });
+        self.discovery_failures
+            .iter()
+            .cloned()
+            .chain(tenants)
+            .collect()
+    }
+}
+
+/// Resolved sources of a tenant, served at `/t/{tenant}/...` and on the tenant's host names
+#[derive(Clone, Default)]
+pub struct TenantState {
+    pub hosts: Vec<String>,
+    /// Server settings of the main config, with the tenant's own `source_options`
+    pub srv: SrvConfig,
+    pub state: ServerState,
+}
+
+/// Config section of a tenant. It may contain any source sections, `cache_size_mb`,
+/// and `source_options`, but no other server settings.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// Host names served with the sources of this tenant, e.g. `maps.example.com`
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub hosts: OptOneMany<String>,
+    #[serde(flatten)]
+    pub config: Config,
 }
 
 #[serde_with::skip_serializing_none]
@@ -40,6 +100,14 @@ pub struct ServerState {
 pub struct Config {
     pub cache_size_mb: Option<u64>,
 
+    /// What to do with an `MBTiles`, `PMTiles`, or GDAL source that cannot be opened:
+    /// `abort` the startup, `skip` it, or skip it with a warning [default: abort]
+    pub on_invalid_source: Option<InvalidSourcePolicy>,
+
+    /// What to do with a source whose ID is already used by another source or is reserved:
+    /// add a `suffix` like `.1`, abort with an `error`, `skip` it, or `qualify` it with its schema [default: suffix]
+    pub id_collisions: Option<IdCollisionPolicy>,
+
     #[serde(flatten)]
     pub srv: SrvConfig,
 
@@ -55,6 +123,11 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
     pub mbtiles: FileConfigEnum<crate::mbtiles::MbtConfig>,
 
+    /// Raster files of any format supported by GDAL, warped to Web Mercator. Requires the `gdal` feature.
+    #[cfg(feature = "gdal")]
+    #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
+    pub gdal: FileConfigEnum<crate::raster::GdalConfig>,
+
     #[cfg(feature = "sprites")]
     #[serde(default, skip_serializing_if = "FileConfigEnum::is_none")]
     pub sprites: FileConfigEnum<SpriteConfig>,
@@ -62,6 +135,30 @@ pub struct Config {
     #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
     pub fonts: OptOneMany<PathBuf>,
 
+    /// Synthetic sources showing the coordinates and boundaries of each tile, by source ID
+    pub debug: Option<BTreeMap<String, DebugConfig>>,
+
+    /// Other Martin servers whose tile sources are served and cached by this server, acting as their edge
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub upstream: OptOneMany<UpstreamConfig>,
+
+    /// Tile sources that try other sources in order, until one of them returns a non-empty tile,
+    /// e.g. `osm: [osm_db, osm_mbtiles]`
+    pub fallbacks: Option<BTreeMap<String, Vec<String>>>,
+
+    /// Tenants with their own isolated sources, served at `/t/{tenant}/...` and on their own host names
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, TenantConfig>,
+
+    /// Raster sources blending the tiles of other raster sources, by source ID. Requires the `raster` feature.
+    #[cfg(feature = "raster")]
+    pub raster_composites: Option<BTreeMap<String, crate::raster::RasterCompositeConfig>>,
+
+    /// Vector sources with contour lines generated from raster sources with elevations encoded as colors,
+    /// by source ID. Requires the `raster` feature.
+    #[cfg(feature = "raster")]
+    pub contours: Option<BTreeMap<String, crate::raster::ContourConfig>>,
+
     #[serde(flatten)]
     pub unrecognized: UnrecognizedValues,
 }
@@ -75,6 +172,14 @@ impl Config {
         if let Some(path) = &self.srv.base_path {
             self.srv.base_path = Some(parse_base_path(path)?);
         }
+        self.srv.validate_source_headers()?;
+        self.srv.parse_url_templates()?;
+        if let Some(cors) = &self.srv.cors {
+            cors.validate()?;
+        }
+        if let Some(headers) = SecurityHeadersConfig::from_opt(&self.srv.security_headers) {
+            headers.validate()?;
+        }
 
         #[cfg(feature = "postgres")]
         for pg in self.postgres.iter_mut() {
@@ -87,13 +192,25 @@ impl Config {
         #[cfg(feature = "mbtiles")]
         res.extend(self.mbtiles.finalize("mbtiles.")?);
 
+        #[cfg(feature = "gdal")]
+        res.extend(self.gdal.finalize("gdal.")?);
+
         #[cfg(feature = "sprites")]
         res.extend(self.sprites.finalize("sprites.")?);
 
         // TODO: support for unrecognized fonts?
         // res.extend(self.fonts.finalize("fonts.")?);
 
-        let is_empty = true;
+        for (name, tenant) in &mut self.tenants {
+            res.extend(
+                tenant
+                    .finalize(name)?
+                    .into_iter()
+                    .map(|(k, v)| (format!("tenants.{name}.{k}"), v)),
+            );
+        }
+
+        let is_empty = self.tenants.is_empty() && self.debug.is_none() && self.upstream.is_empty();
 
         #[cfg(feature = "postgres")]
         let is_empty = is_empty && self.postgres.is_empty();
@@ -104,6 +221,9 @@ impl Config {
         #[cfg(feature = "mbtiles")]
         let is_empty = is_empty && self.mbtiles.is_empty();
 
+        #[cfg(feature = "gdal")]
+        let is_empty = is_empty && self.gdal.is_empty();
+
         #[cfg(feature = "sprites")]
         let is_empty = is_empty && self.sprites.is_empty();
 
@@ -118,15 +238,54 @@ impl Config {
     }
 
     pub async fn resolve(&mut self) -> MartinResult<ServerState> {
-        let resolver = IdResolver::new(RESERVED_KEYWORDS);
-        let cache_size = self.cache_size_mb.unwrap_or(512) * 1024 * 1024;
+        let mut state = self.resolve_sources(None, None, None).await?;
+        let cache_size_mb = self.cache_size_mb;
+        let on_invalid_source = self.on_invalid_source;
+        let id_collisions = self.id_collisions;
+        let tenants = try_join_all(self.tenants.iter_mut().map(|(name, tenant)| async move {
+            info!("Resolving sources of tenant {name}");
+            let state = tenant
+                .config
+                .resolve_sources(cache_size_mb, on_invalid_source, id_collisions)
+                .await?;
+            MartinResult::Ok((name, tenant, state))
+        }))
+        .await?;
+        for (name, tenant, tenant_state) in tenants {
+            let srv = SrvConfig {
+                source_options: tenant.config.srv.source_options.clone(),
+                ..self.srv.clone()
+            };
+            state.tenants.insert(
+                name.clone(),
+                TenantState {
+                    hosts: tenant.hosts.iter().cloned().collect(),
+                    srv,
+                    state: tenant_state,
+                },
+            );
+        }
+        Ok(state)
+    }
+
+    /// Resolve the sources of this config section, ignoring the tenants
+    async fn resolve_sources(
+        &mut self,
+        default_cache_size_mb: Option<u64>,
+        default_on_invalid_source: Option<InvalidSourcePolicy>,
+        default_id_collisions: Option<IdCollisionPolicy>,
+    ) -> MartinResult<ServerState> {
+        let id_collisions = self.id_collisions.or(default_id_collisions);
+        let resolver = IdResolver::new(RESERVED_KEYWORDS)
+            .with_collision_policy(id_collisions.unwrap_or_default());
+        let cache_size = self.cache_size_mb.or(default_cache_size_mb).unwrap_or(512) * 1024 * 1024;
         let cache = if cache_size > 0 {
             info!("Initializing main cache with maximum size {cache_size}B");
             Some(
                 MainCache::builder()
                     .weigher(|_key, value: &CacheValue| -> u32 {
                         match value {
-                            CacheValue::Tile(v) => v.len().try_into().unwrap_or(u32::MAX),
+                            CacheValue::Tile(v, ..) => v.len().try_into().unwrap_or(u32::MAX),
                             #[cfg(feature = "pmtiles")]
                             CacheValue::PmtDirectory(v) => {
                                 v.get_approx_byte_size().try_into().unwrap_or(u32::MAX)
@@ -134,6 +293,8 @@ impl Config {
                         }
                     })
                     .max_capacity(cache_size)
+                    // allows removing the tiles of a source with `POST /{source_ids}/purge`
+                    .support_invalidation_closures()
                     .build(),
             )
         } else {
@@ -141,20 +302,77 @@ impl Config {
             None
         };
 
+        // Scanning sprite and font directories is blocking, so it runs on other threads
+        // while the tile sources are being resolved
+        #[cfg(feature = "sprites")]
+        let sprites = {
+            let mut cfg = self.sprites.clone();
+            tokio::task::spawn_blocking(move || SpriteSources::resolve(&mut cfg).map(|v| (v, cfg)))
+        };
+        #[cfg(feature = "fonts")]
+        let fonts = {
+            let mut cfg = self.fonts.clone();
+            tokio::task::spawn_blocking(move || FontSources::resolve(&mut cfg).map(|v| (v, cfg)))
+        };
+        let on_invalid_source = self
+            .on_invalid_source
+            .or(default_on_invalid_source)
+            .unwrap_or_default();
+        let tiles = self
+            .resolve_tile_sources(&resolver, cache.clone(), on_invalid_source)
+            .await?;
+        let collisions = resolver.collisions();
+        if id_collisions == Some(IdCollisionPolicy::Error) && !collisions.is_empty() {
+            return Err(IdCollisions(collisions));
+        }
+        #[cfg(feature = "sprites")]
+        let sprites = {
+            let (sprites, cfg) = sprites
+                .await
+                .map_err(|e| crate::MartinError::InternalError(e.into()))??;
+            self.sprites = cfg;
+            sprites
+        };
+        #[cfg(feature = "fonts")]
+        let fonts = {
+            let (fonts, cfg) = fonts
+                .await
+                .map_err(|e| crate::MartinError::InternalError(e.into()))??;
+            self.fonts = cfg;
+            fonts
+        };
+
         Ok(ServerState {
-            tiles: self.resolve_tile_sources(&resolver, cache.clone()).await?,
+            tiles,
             #[cfg(feature = "sprites")]
-            sprites: SpriteSources::resolve(&mut self.sprites)?,
+            sprites,
             #[cfg(feature = "fonts")]
-            fonts: FontSources::resolve(&mut self.fonts)?,
+            fonts,
+            #[cfg(feature = "postgres")]
+            pg_functions: self.get_pg_functions(),
+            url_templates: self.srv.parse_url_templates()?,
             cache,
+            tenants: BTreeMap::new(),
+            discovery_failures: resolver.failures(),
         })
     }
 
+    /// Must be called after the sources are resolved, which stores the discovered functions in the config
+    #[cfg(feature = "postgres")]
+    fn get_pg_functions(&self) -> HashMap<String, String> {
+        self.postgres
+            .iter()
+            .flat_map(|pg| pg.functions.iter().flatten())
+            .map(|(id, info)| (format!("{}.{}", info.schema, info.function), id.clone()))
+            .collect()
+    }
+
+    #[allow(clippy::too_many_lines)]
     async fn resolve_tile_sources(
         &mut self,
         #[allow(unused_variables)] idr: &IdResolver,
         #[allow(unused_variables)] cache: OptMainCache,
+        #[allow(unused_variables)] on_invalid_source: InvalidSourcePolicy,
     ) -> MartinResult<TileSources> {
         #[allow(unused_mut)]
         let mut sources: Vec<Pin<Box<dyn Future<Output = MartinResult<TileInfoSources>>>>> =
@@ -168,18 +386,146 @@ impl Config {
         #[cfg(feature = "pmtiles")]
         if !self.pmtiles.is_empty() {
             let cfg = &mut self.pmtiles;
-            let val = crate::file_config::resolve_files(cfg, idr, cache.clone(), "pmtiles");
+            let val = crate::file_config::resolve_files(
+                cfg,
+                idr,
+                cache.clone(),
+                "pmtiles",
+                on_invalid_source,
+            );
             sources.push(Box::pin(val));
         }
 
         #[cfg(feature = "mbtiles")]
         if !self.mbtiles.is_empty() {
             let cfg = &mut self.mbtiles;
-            let val = crate::file_config::resolve_files(cfg, idr, cache.clone(), "mbtiles");
+            let val = crate::file_config::resolve_files(
+                cfg,
+                idr,
+                cache.clone(),
+                "mbtiles",
+                on_invalid_source,
+            );
             sources.push(Box::pin(val));
         }
 
-        Ok(TileSources::new(try_join_all(sources).await?))
+        // directories are scanned for GeoTIFF files, other formats must be listed individually
+        #[cfg(feature = "gdal")]
+        if !self.gdal.is_empty() {
+            let cfg = &mut self.gdal;
+            let val = crate::file_config::resolve_files(
+                cfg,
+                idr,
+                cache.clone(),
+                "tif",
+                on_invalid_source,
+            );
+            sources.push(Box::pin(val));
+        }
+
+        for upstream in self.upstream.iter() {
+            sources.push(Box::pin(upstream.resolve(idr.clone())));
+        }
+
+        let mut sources = try_join_all(sources).await?;
+        let skipped = idr.skipped_ids();
+        if !skipped.is_empty() {
+            for group in &mut sources {
+                group.retain(|s| !skipped.contains(s.get_id()));
+            }
+        }
+        if let Some(debug) = &self.debug {
+            sources.push(
+                debug
+                    .iter()
+                    .map(|(id, cfg)| {
+                        let id = idr.resolve(id, format!("debug:{id}"));
+                        Box::new(DebugSource::new(id, cfg)) as Box<dyn crate::Source>
+                    })
+                    .collect(),
+            );
+        }
+        let mut sources = TileSources::new(sources);
+        // the options of the sources in a fallback chain or a derived source apply to them before
+        // they are combined, e.g. a timeout of the primary source lets the next source answer in time
+        let fallbacks = self.fallbacks.clone().unwrap_or_default();
+        #[allow(unused_mut)]
+        let mut derived: Vec<&String> = fallbacks.keys().collect();
+        #[cfg(feature = "raster")]
+        derived.extend(self.raster_composites.iter().flatten().map(|(id, _)| id));
+        #[cfg(feature = "raster")]
+        derived.extend(self.contours.iter().flatten().map(|(id, _)| id));
+        let options = self.srv.source_options.iter().flatten();
+        for (id, opts) in options.clone().filter(|(id, _)| !derived.contains(id)) {
+            Self::apply_source_options(&mut sources, id, opts)?;
+        }
+        for (id, chain) in &fallbacks {
+            sources.add_fallback(id, chain)?;
+        }
+        #[cfg(feature = "raster")]
+        for (id, cfg) in self.raster_composites.iter().flatten() {
+            let source = crate::raster::RasterCompositeSource::new(id.clone(), cfg, &sources)?;
+            if sources.add_source(Box::new(source)).is_some() {
+                return Err(crate::raster::RasterError::InvalidComposite(
+                    id.clone(),
+                    "a source with the same ID already exists".to_string(),
+                ))?;
+            }
+        }
+        #[cfg(feature = "raster")]
+        for (id, cfg) in self.contours.iter().flatten() {
+            let source =
+                crate::raster::ContourSource::new(id.clone(), cfg, &sources, cache.clone())?;
+            if sources.add_source(Box::new(source)).is_some() {
+                return Err(crate::raster::RasterError::InvalidContours(
+                    id.clone(),
+                    "a source with the same ID already exists".to_string(),
+                ))?;
+            }
+        }
+        for (id, opts) in options.filter(|(id, _)| derived.contains(id)) {
+            Self::apply_source_options(&mut sources, id, opts)?;
+        }
+        Ok(sources)
+    }
+
+    #[cfg_attr(not(feature = "wasm"), allow(clippy::unnecessary_wraps))]
+    fn apply_source_options(
+        sources: &mut TileSources,
+        id: &str,
+        opts: &SourceOptions,
+    ) -> MartinResult<()> {
+        if let Some(pipeline) = &opts.pipeline {
+            if !sources.add_pipeline(id, pipeline.clone()) {
+                warn!("Unable to add tile pipeline to source {id} because it does not exist or does not contain vector tiles");
+            }
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(paths) = &opts.plugins {
+            let plugins = paths
+                .iter()
+                .map(|path| crate::plugins::WasmPlugin::load(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !sources.add_plugins(id, plugins) {
+                warn!("Unable to add plugins to source {id} because it does not exist");
+            }
+        }
+        if let Some(max_concurrency) = opts.max_concurrency {
+            if !sources.limit_concurrency(id, max_concurrency) {
+                warn!("Unable to limit concurrency of source {id} because it does not exist");
+            }
+        }
+        if let Some(timeout) = opts.timeout_ms {
+            if !sources.add_timeout(id, Duration::from_millis(timeout)) {
+                warn!("Unable to add timeout to source {id} because it does not exist");
+            }
+        }
+        if let Some(cb) = &opts.circuit_breaker {
+            if !sources.add_circuit_breaker(id, cb.get_failures(), cb.get_cooldown()) {
+                warn!("Unable to add circuit breaker to source {id} because it does not exist");
+            }
+        }
+        Ok(())
     }
 
     pub fn save_to_file(&self, file_name: PathBuf) -> MartinResult<()> {
@@ -203,6 +549,36 @@ impl Config {
     }
 }
 
+impl TenantConfig {
+    fn finalize(&mut self, name: &str) -> MartinResult<UnrecognizedValues> {
+        // tenant names are used as a path segment in /t/{tenant}
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(InvalidTenant(
+                name.to_string(),
+                "the name may only contain letters, digits, '-' and '_'",
+            ));
+        }
+        if !self.config.tenants.is_empty() {
+            return Err(InvalidTenant(name.to_string(), "tenants cannot be nested"));
+        }
+        let srv = SrvConfig {
+            source_options: self.config.srv.source_options.clone(),
+            ..SrvConfig::default()
+        };
+        if srv != self.config.srv {
+            return Err(InvalidTenant(
+                name.to_string(),
+                "only source_options of the server settings can be set per tenant",
+            ));
+        }
+        self.config.finalize()
+    }
+}
+
 pub fn copy_unrecognized_config(
     result: &mut UnrecognizedValues,
     prefix: &str,
@@ -215,6 +591,18 @@ pub fn copy_unrecognized_config(
     );
 }
 
+/// Read config from a local file, or download it from an `http(s)://` or `s3://` URL
+pub async fn load_config<'a, M>(location: &Path, env: &'a M) -> MartinResult<Config>
+where
+    M: VariableMap<'a>,
+    M::Value: AsRef<str>,
+{
+    match location.to_str() {
+        Some(url) if is_remote(url) => parse_config(&fetch_text(url).await?, env, location),
+        _ => read_config(location, env),
+    }
+}
+
 /// Read config from a file
 pub fn read_config<'a, M>(file_name: &Path, env: &'a M) -> MartinResult<Config>
 where
@@ -253,4 +641,46 @@ pub mod tests {
         assert!(res.is_empty(), "unrecognized config: {res:?}");
         assert_eq!(&config, expected);
     }
+
+    #[test]
+    fn parse_tenants() {
+        use indoc::indoc;
+
+        use crate::pg::PgConfig;
+        use crate::test_utils::some;
+        use crate::utils::OptBoolObj;
+
+        let mut config = parse_cfg(indoc! {"
+            tenants:
+              acme:
+                hosts: maps.acme.com
+                postgres:
+                  connection_string: 'postgresql://localhost/acme'
+        "});
+        assert!(config.finalize().unwrap().is_empty());
+        let tenant = TenantConfig {
+            hosts: OptOneMany::One("maps.acme.com".to_string()),
+            config: Config {
+                postgres: OptOneMany::One(PgConfig {
+                    connection_string: some("postgresql://localhost/acme"),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            config.tenants,
+            BTreeMap::from([("acme".to_string(), tenant)])
+        );
+
+        for yaml in [
+            "tenants: { 'a/b': { postgres: { connection_string: 'postgresql://localhost/db' } } }",
+            "tenants: { a: { keep_alive: 5, postgres: { connection_string: 'postgresql://localhost/db' } } }",
+            "tenants: { a: { tenants: { b: { postgres: { connection_string: 'postgresql://localhost/db' } } } } }",
+        ] {
+            let mut config = parse_cfg(yaml);
+            assert!(matches!(config.finalize(), Err(InvalidTenant(..))), "{yaml}");
+        }
+    }
 }
@@ -0,0 +1,343 @@
+//! Synthetic tile sources that need no data. Each tile shows its boundary and its `z/x/y` coordinates,
+//! which helps to verify the projection and tile scheme of a client, and to load test the server
+//! without a database.
+
+use std::fmt::{Debug, Formatter};
+use std::io::Write as _;
+
+use async_trait::async_trait;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, TileJSON, VectorLayer};
+
+use crate::source::UrlQuery;
+use crate::utils::{
+    command, write_bytes, write_key, write_packed, write_uint, write_varint, zigzag, WIRE_LEN,
+};
+use crate::{MartinResult, Source, TileCoord, TileData};
+
+/// Name of the single layer of the vector tiles
+pub const DEBUG_LAYER: &str = "debug";
+/// Extent of the vector tiles
+const EXTENT: u32 = 4096;
+/// Width and height of the raster tiles
+const PNG_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebugFormat {
+    /// Vector tiles with a `debug` layer, containing the tile boundary polygon and a point in its center.
+    /// Both features have the `z`, `x`, `y`, and `label` attributes.
+    #[default]
+    Mvt,
+    /// 256x256 PNG images with the tile boundary and the coordinates drawn on a transparent background
+    Png,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugConfig {
+    /// Tile format [default: mvt]
+    pub format: Option<DebugFormat>,
+    /// Minimum zoom level [default: 0]
+    pub minzoom: Option<u8>,
+    /// Maximum zoom level [default: 30]
+    pub maxzoom: Option<u8>,
+}
+
+#[derive(Clone)]
+pub struct DebugSource {
+    id: String,
+    format: DebugFormat,
+    tilejson: TileJSON,
+}
+
+impl Debug for DebugSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "DebugSource {{ id: {}, format: {:?} }}",
+            self.id, self.format
+        )
+    }
+}
+
+impl DebugSource {
+    #[must_use]
+    pub fn new(id: String, config: &DebugConfig) -> Self {
+        let format = config.format.unwrap_or_default();
+        let mut tilejson = tilejson! {
+            tiles: vec![],
+            name: id.clone(),
+            description: "Debug tiles with their coordinates".to_string(),
+        };
+        tilejson.minzoom = config.minzoom;
+        tilejson.maxzoom = config.maxzoom;
+        if format == DebugFormat::Mvt {
+            let fields = [
+                ("z", "Zoom level"),
+                ("x", "Tile column"),
+                ("y", "Tile row"),
+                ("label", "Tile coordinates as z/x/y"),
+            ];
+            let fields = fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            tilejson.vector_layers = Some(vec![VectorLayer::new(DEBUG_LAYER.to_string(), fields)]);
+        }
+        Self {
+            id,
+            format,
+            tilejson,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for DebugSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "debug"
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        match self.format {
+            DebugFormat::Mvt => TileInfo::new(Format::Mvt, Encoding::Uncompressed),
+            DebugFormat::Png => TileInfo::new(Format::Png, Encoding::Internal),
+        }
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        Ok(match self.format {
+            DebugFormat::Mvt => mvt_tile(xyz),
+            DebugFormat::Png => png_tile(xyz),
+        }
+        .into())
+    }
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn mvt_tile(xyz: TileCoord) -> Vec<u8> {
+    const MOVE_TO: u32 = 1;
+    const LINE_TO: u32 = 2;
+    const CLOSE_PATH: u32 = 7;
+    const POINT: u64 = 1;
+    const POLYGON: u64 = 3;
+    let size = EXTENT as i32;
+    let center = zigzag(size / 2);
+
+    let boundary = [
+        command(MOVE_TO, 1),
+        zigzag(0),
+        zigzag(0),
+        command(LINE_TO, 3),
+        zigzag(size),
+        zigzag(0),
+        zigzag(0),
+        zigzag(size),
+        zigzag(-size),
+        zigzag(0),
+        command(CLOSE_PATH, 1),
+    ];
+    let label = [command(MOVE_TO, 1), center, center];
+
+    let mut layer = Vec::new();
+    write_uint(&mut layer, 15, 2); // version
+    write_bytes(&mut layer, 1, DEBUG_LAYER.as_bytes());
+    for (id, geom_type, geometry) in [(1, POLYGON, &boundary[..]), (2, POINT, &label[..])] {
+        let mut feature = Vec::new();
+        write_uint(&mut feature, 1, id);
+        write_packed(&mut feature, 2, &[0, 0, 1, 1, 2, 2, 3, 3]);
+        write_uint(&mut feature, 3, geom_type);
+        write_packed(&mut feature, 4, geometry);
+        write_bytes(&mut layer, 2, &feature);
+    }
+    for key in ["z", "x", "y", "label"] {
+        write_bytes(&mut layer, 3, key.as_bytes());
+    }
+    for v in [xyz.z.into(), xyz.x.into(), xyz.y.into()] {
+        let mut value = Vec::new();
+        write_uint(&mut value, 5, v); // uint_value
+        write_bytes(&mut layer, 4, &value);
+    }
+    let mut value = Vec::new();
+    write_bytes(&mut value, 1, format!("{xyz:#}").as_bytes()); // string_value
+    write_bytes(&mut layer, 4, &value);
+    write_uint(&mut layer, 5, EXTENT.into());
+
+    let mut tile = Vec::new();
+    write_key(&mut tile, 3, WIRE_LEN);
+    write_varint(&mut tile, layer.len() as u64);
+    tile.extend_from_slice(&layer);
+    tile
+}
+
+/// 3x5 pixel glyphs of the digits and `/`, each row is stored in the lowest 3 bits
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        _ => [0; 5],
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn png_tile(xyz: TileCoord) -> Vec<u8> {
+    const BORDER: [u8; 4] = [255, 0, 0, 255];
+    const TEXT: [u8; 4] = [0, 0, 0, 255];
+    const BACKGROUND: [u8; 4] = [255, 255, 255, 200];
+    let mut pixels = vec![[0_u8; 4]; PNG_SIZE * PNG_SIZE];
+    let mut fill = |x0: usize, y0: usize, w: usize, h: usize, color: [u8; 4]| {
+        for y in y0..(y0 + h).min(PNG_SIZE) {
+            pixels[y * PNG_SIZE + x0..y * PNG_SIZE + (x0 + w).min(PNG_SIZE)].fill(color);
+        }
+    };
+
+    for (x, y, w, h) in [
+        (0, 0, PNG_SIZE, 2),
+        (0, PNG_SIZE - 2, PNG_SIZE, 2),
+        (0, 0, 2, PNG_SIZE),
+        (PNG_SIZE - 2, 0, 2, PNG_SIZE),
+    ] {
+        fill(x, y, w, h, BORDER);
+    }
+
+    // each glyph is 3 pixels wide with a 1 pixel gap, scaled to fit into the tile
+    let label = format!("{xyz:#}");
+    let columns = label.len() * 4 - 1;
+    let scale = ((PNG_SIZE - 32) / columns).clamp(1, 6);
+    let (width, height) = (columns * scale, 5 * scale);
+    let (left, top) = ((PNG_SIZE - width) / 2, (PNG_SIZE - height) / 2);
+    fill(
+        left - scale,
+        top - scale,
+        width + 2 * scale,
+        height + 2 * scale,
+        BACKGROUND,
+    );
+    for (i, c) in label.chars().enumerate() {
+        for (row, bits) in glyph(c).into_iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    let x = left + (i * 4 + col) * scale;
+                    fill(x, top + row * scale, scale, scale, TEXT);
+                }
+            }
+        }
+    }
+
+    let mut raw = Vec::with_capacity(PNG_SIZE * (PNG_SIZE * 4 + 1));
+    for row in pixels.chunks(PNG_SIZE) {
+        raw.push(0); // no filter
+        raw.extend(row.iter().flatten());
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    // writing to a Vec cannot fail
+    let _ = encoder.write_all(&raw);
+    let data = encoder.finish().unwrap_or_default();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(PNG_SIZE as u32).to_be_bytes());
+    header.extend_from_slice(&(PNG_SIZE as u32).to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, no filter, no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (chunk_type, chunk) in [(b"IHDR", &header), (b"IDAT", &data), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        png.extend_from_slice(chunk_type);
+        png.extend_from_slice(chunk);
+        let mut crc = Crc::new();
+        crc.update(chunk_type);
+        crc.update(chunk);
+        png.extend_from_slice(&crc.sum().to_be_bytes());
+    }
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::ZlibDecoder;
+    use std::io::Read as _;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_mvt() {
+        let src = DebugSource::new("dbg".to_string(), &DebugConfig::default());
+        assert_eq!(src.get_tile_info().format, Format::Mvt);
+        let xyz = TileCoord { z: 3, x: 2, y: 5 };
+        let tile = src.get_tile(xyz, None).await.unwrap();
+        // the tile can be decoded by the pipelines
+        let pipeline = crate::MvtPipeline {
+            drop_attributes: Some(vec!["label".to_string()]),
+            ..Default::default()
+        };
+        let dropped = pipeline.apply(&tile).unwrap();
+        assert!(!dropped.windows(5).any(|v| v == b"3/2/5"));
+        assert!(crate::utils::filter_mvt_layers(&tile, |_| false)
+            .unwrap()
+            .is_empty());
+        assert!(tile.windows(5).any(|v| v == b"3/2/5"));
+        let layers = src.get_tilejson().vector_layers.as_ref().unwrap();
+        assert_eq!(layers[0].id, DEBUG_LAYER);
+    }
+
+    #[actix_rt::test]
+    async fn test_png() {
+        let cfg = DebugConfig {
+            format: Some(DebugFormat::Png),
+            ..Default::default()
+        };
+        let src = DebugSource::new("dbg".to_string(), &cfg);
+        let xyz = TileCoord {
+            z: 22,
+            x: 4_194_303,
+            y: 4_194_303,
+        };
+        let tile = src.get_tile(xyz, None).await.unwrap();
+        assert_eq!(TileInfo::detect(&tile), Some(src.get_tile_info()));
+        assert_eq!(&tile[12..16], b"IHDR");
+        assert_eq!(&tile[16..20], &256_u32.to_be_bytes());
+
+        let idat_len = u32::from_be_bytes(tile[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&tile[37..41], b"IDAT");
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&tile[41..41 + idat_len])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw.len(), PNG_SIZE * (PNG_SIZE * 4 + 1));
+        // the top left pixel is on the border, the one next to it is transparent
+        assert_eq!(raw[1..5], [255, 0, 0, 255]);
+        let row = PNG_SIZE * 4 + 1;
+        assert_eq!(raw[3 * row + 13..3 * row + 17], [0, 0, 0, 0]);
+        assert!(tile.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
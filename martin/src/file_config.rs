@@ -3,8 +3,9 @@ use std::fmt::Debug;
 use std::mem;
 use std::path::{Path, PathBuf};
 
-use futures::TryFutureExt;
-use log::{info, warn};
+use clap::ValueEnum;
+use futures::{stream, FutureExt as _, StreamExt as _, TryFutureExt};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -19,6 +20,9 @@ use crate::OptOneMany::{Many, One};
 
 pub type FileResult<T> = Result<T, FileError>;
 
+/// Maximum number of files opened at the same time while resolving the sources
+const FILE_OPEN_CONCURRENCY: usize = 16;
+
 #[derive(thiserror::Error, Debug)]
 pub enum FileError {
     #[error("IO error {0}: {}", .1.display())]
@@ -42,9 +46,55 @@ pub enum FileError {
     #[error(r#"Unable to acquire connection to file: {0}"#)]
     AcquireConnError(String),
 
+    #[cfg(feature = "mbtiles")]
+    #[error("Invalid UTFGrid of tile {1} in source {2}: {0}")]
+    InvalidGrid(String, crate::TileCoord, String),
+
     #[cfg(feature = "pmtiles")]
     #[error(r#"PMTiles error {0} processing {1}"#)]
     PmtError(pmtiles::PmtError, String),
+
+    #[cfg(feature = "gdal")]
+    #[error("GDAL error {0} processing {}", .1.display())]
+    GdalError(gdal::errors::GdalError, PathBuf),
+
+    #[cfg(feature = "gdal")]
+    #[error("Invalid bands of GDAL source {0}: {1}")]
+    InvalidGdalBands(String, String),
+}
+
+/// What to do with a file source that cannot be opened, e.g. a corrupt `MBTiles` file or an unreachable `PMTiles` URL
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidSourcePolicy {
+    /// Fail to start the server, or to refresh the sources
+    #[default]
+    Abort,
+    /// Serve the other sources, only logging the error at the debug level
+    Skip,
+    /// Serve the other sources, logging a warning
+    Warn,
+}
+
+impl InvalidSourcePolicy {
+    /// The value, or `None` if the source is invalid and is skipped.
+    /// Skipped sources are recorded as failures of the discovery.
+    fn check<T>(self, result: FileResult<T>, idr: &IdResolver) -> FileResult<Option<T>> {
+        let e = match (result, self) {
+            (Ok(v), _) => return Ok(Some(v)),
+            (Err(e), Self::Abort) => return Err(e),
+            (Err(e), Self::Skip) => {
+                debug!("Skipping invalid source: {e}");
+                e
+            }
+            (Err(e), Self::Warn) => {
+                warn!("Skipping invalid source: {e}");
+                e
+            }
+        };
+        idr.add_failure(e.to_string());
+        Ok(None)
+    }
 }
 
 pub trait ConfigExtras: Clone + Debug + Default + PartialEq + Send {
@@ -232,55 +282,70 @@ pub async fn resolve_files<T: SourceConfigExtras>(
     idr: &IdResolver,
     cache: OptMainCache,
     extension: &str,
+    policy: InvalidSourcePolicy,
 ) -> MartinResult<TileInfoSources> {
-    resolve_int(config, idr, cache, extension)
+    resolve_int(config, idr, cache, extension, policy)
         .map_err(crate::MartinError::from)
         .await
 }
 
+#[allow(clippy::too_many_lines)]
 async fn resolve_int<T: SourceConfigExtras>(
     config: &mut FileConfigEnum<T>,
     idr: &IdResolver,
     cache: OptMainCache,
     extension: &str,
+    policy: InvalidSourcePolicy,
 ) -> FileResult<TileInfoSources> {
     let Some(cfg) = config.extract_file_config(cache)? else {
         return Ok(TileInfoSources::default());
     };
 
-    let mut results = TileInfoSources::default();
+    let mut pending = Vec::new();
     let mut configs = BTreeMap::new();
     let mut files = HashSet::new();
     let mut directories = Vec::new();
 
     if let Some(sources) = cfg.sources {
         for (id, source) in sources {
-            if let Some(url) = parse_url(T::parse_urls(), source.get_path())? {
+            let Some(url) = policy.check(parse_url(T::parse_urls(), source.get_path()), idr)?
+            else {
+                continue;
+            };
+            if let Some(url) = url {
                 let dup = !files.insert(source.get_path().clone());
                 let dup = if dup { "duplicate " } else { "" };
                 let id = idr.resolve(&id, url.to_string());
                 configs.insert(id.clone(), source);
-                results.push(cfg.custom.new_sources_url(id.clone(), url.clone()).await?);
                 info!("Configured {dup}source {id} from {}", sanitize_url(&url));
+                pending.push(cfg.custom.new_sources_url(id, url).boxed());
             } else {
-                let can = source.abs_path()?;
-                if !can.is_file() {
-                    // todo: maybe warn instead?
-                    return Err(InvalidSourceFilePath(id.to_string(), can));
-                }
+                let can = source.abs_path().and_then(|can| {
+                    if can.is_file() {
+                        Ok(can)
+                    } else {
+                        Err(InvalidSourceFilePath(id.clone(), can))
+                    }
+                });
+                let Some(can) = policy.check(can, idr)? else {
+                    continue;
+                };
 
                 let dup = !files.insert(can.clone());
                 let dup = if dup { "duplicate " } else { "" };
                 let id = idr.resolve(&id, can.to_string_lossy().to_string());
                 info!("Configured {dup}source {id} from {}", can.display());
                 configs.insert(id.clone(), source.clone());
-                results.push(cfg.custom.new_sources(id, source.into_path()).await?);
+                pending.push(cfg.custom.new_sources(id, source.into_path()).boxed());
             }
         }
     }
 
     for path in cfg.paths {
-        if let Some(url) = parse_url(T::parse_urls(), &path)? {
+        let Some(url) = policy.check(parse_url(T::parse_urls(), &path), idr)? else {
+            continue;
+        };
+        if let Some(url) = url {
             let id = url
                 .path_segments()
                 .and_then(Iterator::last)
@@ -294,21 +359,27 @@ async fn resolve_int<T: SourceConfigExtras>(
 
             let id = idr.resolve(id, url.to_string());
             configs.insert(id.clone(), FileConfigSrc::Path(path));
-            results.push(cfg.custom.new_sources_url(id.clone(), url.clone()).await?);
             info!("Configured source {id} from URL {}", sanitize_url(&url));
+            pending.push(cfg.custom.new_sources_url(id, url).boxed());
         } else {
             let is_dir = path.is_dir();
             let dir_files = if is_dir {
                 // directories will be kept in the config just in case there are new files
                 directories.push(path.clone());
-                dir_to_paths(&path, extension)?
+                dir_to_paths(&path, extension)
             } else if path.is_file() {
-                vec![path]
+                Ok(vec![path])
             } else {
-                return Err(InvalidFilePath(path.canonicalize().unwrap_or(path)));
+                Err(InvalidFilePath(path.canonicalize().unwrap_or(path)))
+            };
+            let Some(dir_files) = policy.check(dir_files, idr)? else {
+                continue;
             };
             for path in dir_files {
-                let can = path.canonicalize().map_err(|e| IoError(e, path.clone()))?;
+                let can = path.canonicalize().map_err(|e| IoError(e, path.clone()));
+                let Some(can) = policy.check(can, idr)? else {
+                    continue;
+                };
                 if files.contains(&can) {
                     if !is_dir {
                         warn!("Ignoring duplicate MBTiles path: {}", can.display());
@@ -323,11 +394,21 @@ async fn resolve_int<T: SourceConfigExtras>(
                 info!("Configured source {id} from {}", can.display());
                 files.insert(can);
                 configs.insert(id.clone(), FileConfigSrc::Path(path.clone()));
-                results.push(cfg.custom.new_sources(id, path).await?);
+                pending.push(cfg.custom.new_sources(id, path).boxed());
             }
         }
     }
 
+    // Open the files concurrently, keeping the order of the sources
+    let opened: Vec<_> = stream::iter(pending)
+        .buffered(FILE_OPEN_CONCURRENCY)
+        .collect()
+        .await;
+    let mut results = TileInfoSources::default();
+    for source in opened {
+        results.extend(policy.check(source, idr)?);
+    }
+
     *config = FileConfigEnum::new_extended(directories, configs, cfg.custom);
 
     Ok(results)
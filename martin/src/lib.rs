@@ -2,18 +2,22 @@
 #![forbid(unsafe_code)]
 
 mod config;
-pub use config::{read_config, Config, ServerState};
+pub use config::{load_config, read_config, Config, ServerState, TenantConfig, TenantState};
 
 mod source;
-pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, UrlQuery};
+pub use source::{CatalogSourceEntry, Source, Tile, TileData, TileSources, TileStream, UrlQuery};
 
 mod utils;
 pub use utils::{
-    append_rect, decode_brotli, decode_gzip, IdResolver, MartinError, MartinResult, OptBoolObj,
-    OptOneMany, TileCoord, TileRect, NO_MAIN_CACHE,
+    append_rect, configure_logging, decode_brotli, decode_gzip, decode_zstd, init_logging,
+    AttributeType, ErrorKind, ErrorReport, ErrorReportingConfig, IdCollision, IdCollisionPolicy,
+    IdResolver, LogConfig, LogFileConfig, LogOutput, MartinError, MartinResult, MvtPipeline,
+    OptBoolObj, OptOneMany, TileCoord, TileRect, UtfGrid, LOG_LEVEL_DEFAULT, NO_MAIN_CACHE,
 };
 
 pub mod args;
+pub mod commands;
+pub mod debug;
 pub mod file_config;
 #[cfg(feature = "fonts")]
 pub mod fonts;
@@ -21,11 +25,16 @@ pub mod fonts;
 pub mod mbtiles;
 #[cfg(feature = "postgres")]
 pub mod pg;
+#[cfg(feature = "wasm")]
+pub mod plugins;
 #[cfg(feature = "pmtiles")]
 pub mod pmtiles;
+#[cfg(feature = "raster")]
+pub mod raster;
 #[cfg(feature = "sprites")]
 pub mod sprites;
 pub mod srv;
+pub mod upstream;
 
 #[cfg(test)]
 #[path = "utils/test_utils.rs"]
@@ -1,28 +1,80 @@
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::io;
+use std::io::Read as _;
+use std::num::NonZeroU32;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
+use flate2::read::ZlibDecoder;
 use log::trace;
 use martin_tile_utils::TileInfo;
-use mbtiles::MbtilesPool;
+use mbtiles::{MbtPoolOptions, MbtilesPool};
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
 use url::Url;
 
 use crate::config::UnrecognizedValues;
-use crate::file_config::FileError::{AcquireConnError, InvalidMetadata, IoError};
+use crate::file_config::FileError::{AcquireConnError, InvalidGrid, InvalidMetadata, IoError};
 use crate::file_config::{ConfigExtras, FileResult, SourceConfigExtras};
 use crate::source::{TileData, UrlQuery};
-use crate::{MartinResult, Source, TileCoord};
+use crate::utils::decode_gzip;
+use crate::utils::UTFGRID_SIZE;
+use crate::{MartinResult, Source, TileCoord, UtfGrid};
 
+const MMAP_SIZE_MB_DEFAULT: u64 = 1024;
+const CACHE_SIZE_MB_DEFAULT: u64 = 64;
+
+#[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct MbtConfig {
+    /// Open the files as read-only and immutable, and memory-map them, which makes reading tiles
+    /// from large static archives faster. The files must not be modified while Martin is running.
+    pub memory_map: Option<bool>,
+    /// Number of megabytes of each file to memory-map [default: 1024]
+    pub mmap_size_mb: Option<u64>,
+    /// Size of the page cache of each connection in megabytes when memory-mapping [default: 64]
+    pub cache_size_mb: Option<u64>,
+    /// Maximum number of connections to each file [default: 10]
+    pub pool_size: Option<NonZeroU32>,
+    /// How long (in milliseconds) to wait for a locked file before failing [default: 5000]
+    pub busy_timeout_ms: Option<u64>,
+    /// Connection settings for individual files, keyed by source ID
+    pub source_options: Option<BTreeMap<String, MbtSourceOptions>>,
     #[serde(flatten)]
     pub unrecognized: UnrecognizedValues,
 }
 
+/// Connection settings of a single file, overriding the ones of the `mbtiles` section
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MbtSourceOptions {
+    pub pool_size: Option<NonZeroU32>,
+    pub busy_timeout_ms: Option<u64>,
+}
+
+impl MbtConfig {
+    fn pool_options(&self, id: &str) -> MbtPoolOptions {
+        let source = self.source_options.as_ref().and_then(|v| v.get(id));
+        let mut options = MbtPoolOptions {
+            max_connections: source.and_then(|v| v.pool_size).or(self.pool_size),
+            busy_timeout: source
+                .and_then(|v| v.busy_timeout_ms)
+                .or(self.busy_timeout_ms)
+                .map(Duration::from_millis),
+            ..MbtPoolOptions::default()
+        };
+        if self.memory_map.unwrap_or_default() {
+            let mb = |v: u64| v.saturating_mul(1024 * 1024);
+            options.mmap_size = Some(mb(self.mmap_size_mb.unwrap_or(MMAP_SIZE_MB_DEFAULT)));
+            options.cache_size = Some(mb(self.cache_size_mb.unwrap_or(CACHE_SIZE_MB_DEFAULT)));
+        }
+        options
+    }
+}
+
 impl ConfigExtras for MbtConfig {
     fn get_unrecognized(&self) -> &UnrecognizedValues {
         &self.unrecognized
@@ -31,7 +83,9 @@ impl ConfigExtras for MbtConfig {
 
 impl SourceConfigExtras for MbtConfig {
     async fn new_sources(&self, id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
-        Ok(Box::new(MbtSource::new(id, path).await?))
+        Ok(Box::new(
+            MbtSource::new(id.clone(), path, self.pool_options(&id)).await?,
+        ))
     }
 
     // TODO: Remove #[allow] after switching to Rust/Clippy v1.78+ in CI
@@ -48,6 +102,9 @@ pub struct MbtSource {
     mbtiles: Arc<MbtilesPool>,
     tilejson: TileJSON,
     tile_info: TileInfo,
+    last_modified: Option<SystemTime>,
+    /// If the file has the `grids` table, and if it also has the `grid_data` table
+    grids: Option<bool>,
 }
 
 impl Debug for MbtSource {
@@ -62,22 +119,34 @@ impl Debug for MbtSource {
 }
 
 impl MbtSource {
-    async fn new(id: String, path: PathBuf) -> FileResult<Self> {
-        let mbt = MbtilesPool::new(&path)
+    async fn new(id: String, path: PathBuf, options: MbtPoolOptions) -> FileResult<Self> {
+        let mbt = MbtilesPool::new_with_options(&path, options)
             .await
             .map_err(|e| io::Error::other(format!("{e:?}: Cannot open file {}", path.display())))
             .map_err(|e| IoError(e, path.clone()))?;
 
-        let meta = mbt
+        let mut meta = mbt
             .get_metadata()
             .await
-            .map_err(|e| InvalidMetadata(e.to_string(), path))?;
+            .map_err(|e| InvalidMetadata(e.to_string(), path.clone()))?;
+
+        // the URL of the grids is set when the TileJSON is requested
+        let grids = match mbt.has_grids().await {
+            Ok((true, has_data)) => {
+                meta.tilejson.grids = Some(vec![]);
+                Some(has_data)
+            }
+            Ok((false, _)) => None,
+            Err(e) => return Err(InvalidMetadata(e.to_string(), path)),
+        };
 
         Ok(Self {
             id,
             mbtiles: Arc::new(mbt),
             tilejson: meta.tilejson,
             tile_info: meta.tile_info,
+            last_modified: path.metadata().and_then(|m| m.modified()).ok(),
+            grids,
         })
     }
 }
@@ -100,6 +169,19 @@ impl Source for MbtSource {
         Box::new(self.clone())
     }
 
+    fn get_source_type(&self) -> &'static str {
+        "mbtiles"
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        Ok(self.last_modified)
+    }
+
+    /// The `version` of the `MBTiles` metadata
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        Ok(self.tilejson.version.clone())
+    }
+
     async fn get_tile(
         &self,
         xyz: TileCoord,
@@ -111,7 +193,7 @@ impl Source for MbtSource {
             .await
             .map_err(|_| AcquireConnError(self.id.clone()))?
         {
-            Ok(tile)
+            Ok(tile.into())
         } else {
             trace!(
                 "Couldn't find tile data in {}/{}/{} of {}",
@@ -120,20 +202,82 @@ impl Source for MbtSource {
                 xyz.y,
                 &self.id
             );
-            Ok(Vec::new())
+            Ok(TileData::new())
+        }
+    }
+
+    async fn get_tile_size(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        let size = self
+            .mbtiles
+            .get_tile_size(xyz.z, xyz.x, xyz.y)
+            .await
+            .map_err(|_| AcquireConnError(self.id.clone()))?;
+        Ok(Some(size.unwrap_or_default()))
+    }
+
+    /// The `UTFGrid` of the `grids` table, with the data of its keys from the `grid_data` table.
+    /// Tiles without a grid have an empty grid.
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        let Some(has_data) = self.grids else {
+            return Ok(None);
+        };
+        let Some(data) = self
+            .mbtiles
+            .get_grid(xyz.z, xyz.x, xyz.y)
+            .await
+            .map_err(|_| AcquireConnError(self.id.clone()))?
+        else {
+            return Ok(Some(UtfGrid::new(UTFGRID_SIZE, [])));
+        };
+        let invalid = |e: String| InvalidGrid(e, xyz, self.id.clone());
+        let mut grid = parse_grid(&data).map_err(|e| invalid(e.to_string()))?;
+        if has_data {
+            let keys = self
+                .mbtiles
+                .get_grid_data(xyz.z, xyz.x, xyz.y)
+                .await
+                .map_err(|_| AcquireConnError(self.id.clone()))?;
+            for (key, json) in keys {
+                let value = serde_json::from_str(&json).map_err(|e| invalid(e.to_string()))?;
+                grid.data.insert(key, value);
+            }
         }
+        Ok(Some(grid))
     }
 }
 
+/// Grids are stored as zlib compressed JSON, or gzip compressed by some tools
+fn parse_grid(data: &[u8]) -> io::Result<UtfGrid> {
+    let json = if data.starts_with(&[0x1f, 0x8b]) {
+        decode_gzip(data)?
+    } else {
+        let mut json = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut json)?;
+        json
+    };
+    Ok(serde_json::from_slice(&json)?)
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
+    use std::num::NonZeroU32;
     use std::path::PathBuf;
+    use std::time::Duration;
 
     use indoc::indoc;
 
-    use crate::file_config::{FileConfigEnum, FileConfigSource, FileConfigSrc};
-    use crate::mbtiles::MbtConfig;
+    use crate::file_config::{
+        resolve_files, FileConfigEnum, FileConfigSource, FileConfigSrc, InvalidSourcePolicy,
+    };
+    use mbtiles::MbtPoolOptions;
+
+    use crate::mbtiles::{MbtConfig, MbtSource};
+    use crate::{IdResolver, Source as _, TileCoord};
 
     #[test]
     fn parse() {
@@ -191,4 +335,86 @@ mod tests {
             ]))
         );
     }
+
+    #[test]
+    fn memory_map_options() {
+        let cfg: MbtConfig = serde_yaml::from_str("memory_map: true\nmmap_size_mb: 16").unwrap();
+        assert_eq!(
+            cfg.pool_options("src"),
+            MbtPoolOptions {
+                mmap_size: Some(16 * 1024 * 1024),
+                cache_size: Some(64 * 1024 * 1024),
+                ..MbtPoolOptions::default()
+            }
+        );
+        let cfg: MbtConfig = serde_yaml::from_str("mmap_size_mb: 16").unwrap();
+        assert_eq!(cfg.pool_options("src"), MbtPoolOptions::default());
+    }
+
+    #[test]
+    fn pool_options() {
+        let cfg: MbtConfig = serde_yaml::from_str(indoc! {"
+            pool_size: 4
+            busy_timeout_ms: 1000
+            source_options:
+              hot:
+                pool_size: 32
+        "})
+        .unwrap();
+        let options = cfg.pool_options("hot");
+        assert_eq!(options.max_connections, NonZeroU32::new(32));
+        assert_eq!(options.busy_timeout, Some(Duration::from_secs(1)));
+        let options = cfg.pool_options("other");
+        assert_eq!(options.max_connections, NonZeroU32::new(4));
+    }
+
+    #[actix_rt::test]
+    async fn utfgrid() {
+        let path = PathBuf::from("../tests/fixtures/mbtiles/geography-class-png.mbtiles");
+        let src = MbtSource::new("geo".to_string(), path, MbtPoolOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(src.get_tilejson().grids, Some(vec![]));
+        let grid = src
+            .get_utfgrid(TileCoord { z: 0, x: 0, y: 0 })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(grid.grid.len(), 64);
+        assert_eq!(grid.data["10"]["admin"], "Argentina");
+        assert!(grid.keys.iter().skip(1).all(|k| grid.data.contains_key(k)));
+
+        let path = PathBuf::from("../tests/fixtures/mbtiles/world_cities.mbtiles");
+        let src = MbtSource::new("cities".to_string(), path, MbtPoolOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(src.get_tilejson().grids, None);
+        let grid = src.get_utfgrid(TileCoord { z: 0, x: 0, y: 0 }).await;
+        assert!(grid.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn invalid_source_policy() {
+        let resolve = |policy| async move {
+            let mut cfg = serde_yaml::from_str::<FileConfigEnum<MbtConfig>>(indoc! {"
+                sources:
+                  cities: ../tests/fixtures/mbtiles/world_cities.mbtiles
+                  missing: ../tests/fixtures/mbtiles/missing.mbtiles
+                  corrupt: ../README.md
+            "})
+            .unwrap();
+            let idr = IdResolver::new(&[]);
+            let sources = resolve_files(&mut cfg, &idr, None, "mbtiles", policy).await;
+            (sources, idr.failures())
+        };
+        assert!(resolve(InvalidSourcePolicy::Abort).await.0.is_err());
+        for policy in [InvalidSourcePolicy::Skip, InvalidSourcePolicy::Warn] {
+            let (sources, failures) = resolve(policy).await;
+            let sources = sources.unwrap();
+            let ids: Vec<_> = sources.iter().map(|s| s.get_id()).collect();
+            assert_eq!(ids, vec!["cities"]);
+            // the skipped sources are retried with `discovery_retry`
+            assert_eq!(failures.len(), 2);
+        }
+    }
 }
@@ -0,0 +1,261 @@
+//! Table bounds computed after the sources are published, either in the background with `auto_bounds: background`,
+//! or on demand with the admin endpoint. The `TileJSON` of the source is patched once the bounds are known.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use deadpool_postgres::tokio_postgres::types::Type;
+use log::{debug, info, warn};
+use postgis::ewkb;
+use postgres_protocol::escape::escape_identifier;
+use serde::{Deserialize, Serialize};
+use tilejson::{Bounds, TileJSON};
+
+use crate::pg::config::PgInfo;
+use crate::pg::config_table::TableInfo;
+use crate::pg::pool::PgPool;
+use crate::pg::query_tables::calc_bounds;
+use crate::pg::utils::polygon_to_bbox;
+use crate::pg::PgError::PostgresError;
+use crate::pg::PgResult;
+
+/// Status of the background bounds computation of every table source
+static BOUNDS_STATUS: Mutex<BTreeMap<String, BoundsStatus>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BoundsStatus {
+    Pending,
+    Done {
+        /// Computed bounds, or none if the table has no geometries
+        bounds: Option<Bounds>,
+        method: BoundsMethod,
+        duration_ms: u64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BoundsMethod {
+    /// `ST_EstimatedExtent`, based on the table statistics gathered by `ANALYZE`
+    Estimated,
+    /// `ST_Extent` of all geometries of the table
+    Exact,
+}
+
+/// Status of the background bounds computation of all table sources, by source ID
+#[must_use]
+pub fn bounds_statuses() -> BTreeMap<String, BoundsStatus> {
+    BOUNDS_STATUS.lock().unwrap().clone()
+}
+
+fn set_status(id: &str, status: BoundsStatus) {
+    BOUNDS_STATUS.lock().unwrap().insert(id.to_string(), status);
+}
+
+/// Table sources whose bounds can be recomputed, by source ID
+static TABLES: Mutex<BTreeMap<String, TableBounds>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug)]
+struct TableBounds {
+    info: TableInfo,
+    pool: PgPool,
+    patch: Arc<TileJsonPatch>,
+}
+
+/// A `TileJSON` that replaces the one a source was created with.
+/// It can be set multiple times while references to the previous values are still in use,
+/// so every value is kept until the source is dropped. Replacing it should be rare.
+#[derive(Debug, Default)]
+pub(crate) struct TileJsonPatch(OnceLock<(TileJSON, Box<TileJsonPatch>)>);
+
+impl TileJsonPatch {
+    /// The most recently set `TileJSON`, if any
+    pub(crate) fn get(&self) -> Option<&TileJSON> {
+        let mut value = self.0.get()?;
+        while let Some(next) = value.1 .0.get() {
+            value = next;
+        }
+        Some(&value.0)
+    }
+
+    pub(crate) fn set(&self, tilejson: TileJSON) {
+        let mut node = self;
+        let mut value = (tilejson, Box::default());
+        loop {
+            match node.0.set(value) {
+                Ok(()) => return,
+                Err(v) => {
+                    value = v;
+                    node = &node.0.get().unwrap().1;
+                }
+            }
+        }
+    }
+}
+
+/// Register a table source, so that its bounds can be recomputed with [`recompute_bounds`]
+pub(crate) fn register_table(id: String, info: TableInfo, pool: PgPool, patch: Arc<TileJsonPatch>) {
+    TABLES
+        .lock()
+        .unwrap()
+        .insert(id, TableBounds { info, pool, patch });
+}
+
+/// Compute the table bounds in a background task, and set the patched `TileJSON` once they are known
+pub(crate) fn spawn_bounds_calc(id: String) {
+    set_status(&id, BoundsStatus::Pending);
+    tokio::spawn(async move {
+        // errors are logged and reported in the status
+        let _ = recompute_bounds(&id, BoundsMethod::Estimated).await;
+    });
+}
+
+/// Compute the bounds of a table source, and update its `TileJSON`.
+/// The estimated method falls back to the exact one if the table has no statistics.
+/// Returns `None` if there is no table source with this ID.
+pub async fn recompute_bounds(id: &str, method: BoundsMethod) -> PgResult<Option<BoundsStatus>> {
+    let Some(TableBounds {
+        mut info,
+        pool,
+        patch,
+    }) = TABLES.lock().unwrap().get(id).cloned()
+    else {
+        return Ok(None);
+    };
+    let start = Instant::now();
+    let result = match method {
+        BoundsMethod::Estimated => estimate_or_calc_bounds(id, &info, &pool).await,
+        BoundsMethod::Exact => calc_exact_bounds(&info, &pool)
+            .await
+            .map(|b| (b, BoundsMethod::Exact)),
+    };
+    match result {
+        Ok((bounds, method)) => {
+            info!(
+                "Computed {method:?} bounds for {id}: {}",
+                bounds.map_or_else(|| "none".to_string(), |b| b.to_string())
+            );
+            let duration_ms = u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX);
+            info.bounds = bounds;
+            patch.set(info.to_tilejson(id.to_string()));
+            let status = BoundsStatus::Done {
+                bounds,
+                method,
+                duration_ms,
+            };
+            set_status(id, status.clone());
+            Ok(Some(status))
+        }
+        Err(e) => {
+            warn!("Failed to compute bounds for {id}: {e}");
+            set_status(
+                id,
+                BoundsStatus::Failed {
+                    error: e.to_string(),
+                },
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Use the estimated extent if the table has statistics, otherwise compute the exact bounds
+async fn estimate_or_calc_bounds(
+    id: &str,
+    info: &TableInfo,
+    pool: &PgPool,
+) -> PgResult<(Option<Bounds>, BoundsMethod)> {
+    match estimate_bounds(info, pool).await {
+        Ok(Some(bounds)) => return Ok((Some(bounds), BoundsMethod::Estimated)),
+        Ok(None) => debug!("No estimated extent for {id}, computing exact bounds"),
+        Err(e) => debug!("Unable to estimate the extent of {id}, computing exact bounds: {e}"),
+    }
+    Ok((calc_exact_bounds(info, pool).await?, BoundsMethod::Exact))
+}
+
+async fn calc_exact_bounds(info: &TableInfo, pool: &PgPool) -> PgResult<Option<Bounds>> {
+    calc_bounds(
+        pool,
+        &escape_identifier(&info.schema),
+        &escape_identifier(&info.table),
+        &escape_identifier(&info.geometry_column),
+        info.srid,
+    )
+    .await
+}
+
+/// Get the extent from the table statistics. Returns none if the table has not been analyzed,
+/// or if the estimated extent is a single point or a line.
+async fn estimate_bounds(info: &TableInfo, pool: &PgPool) -> PgResult<Option<Bounds>> {
+    let srid = info.srid;
+    let rows = pool
+        .get()
+        .await?
+        .query_typed(
+            &format!(
+                r"
+SELECT ST_Transform(ST_SetSRID(extent::geometry, {srid}), 4326) AS bounds
+FROM ST_EstimatedExtent($1, $2, $3) AS extent
+WHERE ST_XMin(extent) < ST_XMax(extent) AND ST_YMin(extent) < ST_YMax(extent)"
+            ),
+            &[
+                (&info.schema, Type::TEXT),
+                (&info.table, Type::TEXT),
+                (&info.geometry_column, Type::TEXT),
+            ],
+        )
+        .await
+        .map_err(|e| PostgresError(e, "querying estimated table extent"))?;
+    Ok(rows
+        .first()
+        .and_then(|row| row.get::<_, Option<ewkb::Polygon>>("bounds"))
+        .and_then(|p| polygon_to_bbox(&p)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tilejson_patch() {
+        let patch = TileJsonPatch::default();
+        assert!(patch.get().is_none());
+        patch.set(tilejson::tilejson! { tiles: vec![], name: "first".to_string() });
+        let first = patch.get().unwrap();
+        patch.set(tilejson::tilejson! { tiles: vec![], name: "second".to_string() });
+        assert_eq!(first.name.as_deref(), Some("first"));
+        assert_eq!(patch.get().unwrap().name.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn test_bounds_status_json() {
+        set_status("test_bounds_pending", BoundsStatus::Pending);
+        set_status(
+            "test_bounds_done",
+            BoundsStatus::Done {
+                bounds: Some(Bounds::new(-10.0, -20.0, 10.0, 20.0)),
+                method: BoundsMethod::Estimated,
+                duration_ms: 5,
+            },
+        );
+        let statuses = bounds_statuses();
+        assert_eq!(
+            serde_json::to_value(&statuses["test_bounds_pending"]).unwrap(),
+            serde_json::json!({"status": "pending"})
+        );
+        assert_eq!(
+            serde_json::to_value(&statuses["test_bounds_done"]).unwrap(),
+            serde_json::json!({
+                "status": "done",
+                "bounds": [-10.0, -20.0, 10.0, 20.0],
+                "method": "estimated",
+                "duration_ms": 5,
+            })
+        );
+    }
+}
@@ -4,22 +4,26 @@ use std::collections::HashSet;
 use futures::future::join_all;
 use itertools::Itertools as _;
 use log::{debug, error, info, warn};
+use martin_tile_utils::tile_index;
+use tilejson::VectorLayer;
 
 use crate::args::BoundsCalcType;
+use crate::pg::bounds::{register_table, spawn_bounds_calc};
 use crate::pg::config::{PgConfig, PgInfo};
 use crate::pg::config_function::{FuncInfoSources, FunctionInfo};
 use crate::pg::config_table::{TableInfo, TableInfoSources};
-use crate::pg::pg_source::{PgSource, PgSqlInfo};
+use crate::pg::pg_source::{version_query, PgSource, PgSqlInfo};
 use crate::pg::pool::PgPool;
 use crate::pg::query_functions::query_available_function;
 use crate::pg::query_tables::{query_available_tables, table_to_query};
 use crate::pg::utils::{find_info, find_kv_ignore_case, normalize_key, InfoMap};
 use crate::pg::PgError::InvalidTableExtent;
 use crate::pg::{PgCfgPublish, PgCfgPublishFuncs, PgResult};
-use crate::source::TileInfoSources;
-use crate::utils::IdResolver;
+use crate::source::{Source as _, TileInfoSources};
 use crate::utils::OptOneMany::NoVals;
+use crate::utils::{mvt_layer_stats, IdResolver};
 use crate::OptBoolObj::{Bool, NoValue, Object};
+use crate::TileCoord;
 
 pub type SqlFuncInfoMapMap = InfoMap<InfoMap<(PgSqlInfo, FunctionInfo)>>;
 pub type SqlTableInfoMapMapMap = InfoMap<InfoMap<InfoMap<TableInfo>>>;
@@ -199,23 +203,45 @@ impl PgBuilder {
             }
         }
 
-        let mut res = TileInfoSources::default();
-        let mut info_map = TableInfoSources::new();
-        let pending = join_all(pending).await;
-        for src in pending {
+        let mut prepared = Vec::new();
+        for src in join_all(pending).await {
             match src {
                 Err(v) => {
                     error!("Failed to create a source: {v}");
-                    continue;
+                    self.id_resolver.add_failure(v.to_string());
                 }
                 Ok((id, pg_sql, src_inf)) => {
                     debug!("{id} query: {}", pg_sql.sql_query);
-                    self.add_func_src(&mut res, id.clone(), &src_inf, pg_sql.clone());
-                    info_map.insert(id, src_inf);
+                    let source = self.new_pg_source(id.clone(), &src_inf, pg_sql);
+                    prepared.push(async move {
+                        let result = source.prepare().await;
+                        (id, source, src_inf, result)
+                    });
                 }
             }
         }
 
+        let mut res = TileInfoSources::default();
+        let mut info_map = TableInfoSources::new();
+        for (id, source, src_inf, result) in join_all(prepared).await {
+            if let Err(e) = result {
+                error!("Failed to create a source: {e}");
+                self.id_resolver.add_failure(format!("{id}: {e}"));
+                continue;
+            }
+            register_table(
+                id.clone(),
+                src_inf.clone(),
+                self.pool.clone(),
+                source.patched_tilejson(),
+            );
+            if self.auto_bounds == BoundsCalcType::Background && src_inf.bounds.is_none() {
+                spawn_bounds_calc(id.clone());
+            }
+            res.push(Box::new(source));
+            info_map.insert(id, src_inf);
+        }
+
         Ok((res, info_map))
     }
 
@@ -243,7 +269,18 @@ impl PgBuilder {
             let dup = !used.insert((&cfg_inf.schema, func_name));
             let dup = if dup { "duplicate " } else { "" };
             let id2 = self.resolve_id(id, &merged_inf);
-            self.add_func_src(&mut res, id2.clone(), &merged_inf, pg_sql.clone());
+            let mut pg_sql = pg_sql.clone();
+            pg_sql.begin_query = merged_inf.settings.begin_query();
+            pg_sql.version_query = merged_inf.version_sequence.as_deref().map(version_query);
+            if let Some(defaults) = &merged_inf.defaults {
+                if pg_sql.use_url_query {
+                    pg_sql.query_defaults.clone_from(defaults);
+                } else {
+                    warn!("Function source {id} has query parameter defaults, but function {} does not accept query parameters", pg_sql.signature);
+                }
+            }
+            self.add_func_src(&mut res, id2.clone(), &merged_inf, pg_sql.clone())
+                .await;
             warn_on_rename(id, &id2, "Function");
             let signature = &pg_sql.signature;
             info!("Configured {dup}source {id2} from the function {signature}");
@@ -277,7 +314,8 @@ impl PgBuilder {
                         .replace("{schema}", &schema)
                         .replace("{function}", &func);
                     let id2 = self.resolve_id(&source_id, &db_inf);
-                    self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone());
+                    self.add_func_src(&mut res, id2.clone(), &db_inf, pg_sql.clone())
+                        .await;
                     info!("Discovered source {id2} from function {}", pg_sql.signature);
                     debug!("{id2} query: {}", pg_sql.sql_query);
                     info_map.insert(id2, db_inf);
@@ -292,17 +330,69 @@ impl PgBuilder {
         self.id_resolver.resolve(id, signature)
     }
 
-    fn add_func_src(
+    async fn add_func_src(
         &self,
         sources: &mut TileInfoSources,
         id: String,
-        pg_info: &impl PgInfo,
+        pg_info: &FunctionInfo,
         sql_info: PgSqlInfo,
     ) {
-        let tilejson = pg_info.to_tilejson(id.clone());
-        let source = PgSource::new(id, sql_info, tilejson, self.pool.clone());
+        let source = self.new_pg_source(id, pg_info, sql_info);
+        if pg_info.introspect == Some(true) {
+            introspect_layers(&source).await;
+        }
         sources.push(Box::new(source));
     }
+
+    fn new_pg_source(&self, id: String, pg_info: &impl PgInfo, sql_info: PgSqlInfo) -> PgSource {
+        let tilejson = pg_info.to_tilejson(id.clone());
+        PgSource::new(id, sql_info, tilejson, self.pool.clone())
+    }
+}
+
+/// Set the `vector_layers` of a function source from the layers of a sample tile, unless they are already known.
+/// The sample is the tile at the minimum zoom that contains the center of the source bounds.
+/// Errors are logged, and the source is used without the layers.
+async fn introspect_layers(source: &PgSource) {
+    let tilejson = source.get_tilejson();
+    if tilejson.vector_layers.is_some() {
+        return;
+    }
+    let z = tilejson.minzoom.unwrap_or(0);
+    let (lng, lat) = tilejson.bounds.map_or((0.0, 0.0), |b| {
+        ((b.left + b.right) / 2.0, (b.bottom + b.top) / 2.0)
+    });
+    let (x, y) = tile_index(lng, lat, z);
+    let xyz = TileCoord { z, x, y };
+    let id = source.get_id();
+    let layers = match source.get_tile(xyz, None).await {
+        Ok(tile) => mvt_layer_stats(&tile).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match layers {
+        Ok(layers) if layers.is_empty() => {
+            warn!(
+                "Sample tile {xyz:#} of source {id} has no layers, its vector_layers are unknown"
+            );
+        }
+        Ok(layers) => {
+            info!(
+                "Source {id} has the layers [{}] in the sample tile {xyz:#}",
+                layers.iter().map(|l| &l.name).join(", ")
+            );
+            let mut tilejson = tilejson.clone();
+            tilejson.vector_layers = Some(
+                layers
+                    .into_iter()
+                    .map(|l| VectorLayer::new(l.name, l.fields))
+                    .collect(),
+            );
+            source.patched_tilejson().set(tilejson);
+        }
+        Err(e) => {
+            warn!("Unable to get the layers of source {id} from the sample tile {xyz:#}: {e}");
+        }
+    }
 }
 
 fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTables) {
@@ -364,6 +454,7 @@ fn update_auto_fields(id: &str, inf: &mut TableInfo, auto_tables: &PgBuilderTabl
 }
 
 fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFuncs>) {
+    let prefix = config.id_prefix.as_deref().unwrap_or_default();
     let auto_tables = if use_auto_publish(config, false) {
         let schemas = get_auto_schemas!(config, tables);
         let bld = if let Object(PgCfgPublish {
@@ -372,11 +463,10 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
         {
             PgBuilderTables {
                 schemas,
-                source_id_format: v
-                    .source_id_format
-                    .as_deref()
-                    .unwrap_or("{table}")
-                    .to_string(),
+                source_id_format: format!(
+                    "{prefix}{}",
+                    v.source_id_format.as_deref().unwrap_or("{table}")
+                ),
                 id_columns: v.id_columns.opt_iter().map(|v| v.cloned().collect()),
                 clip_geom: v.clip_geom,
                 buffer: v.buffer,
@@ -385,7 +475,7 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
         } else {
             PgBuilderTables {
                 schemas,
-                source_id_format: "{table}".to_string(),
+                source_id_format: format!("{prefix}{{table}}"),
                 ..Default::default()
             }
         };
@@ -406,9 +496,9 @@ fn calc_auto(config: &PgConfig) -> (Option<PgBuilderTables>, Option<PgBuilderFun
                 ..
             }) = &config.auto_publish
             {
-                v.clone()
+                format!("{prefix}{v}")
             } else {
-                "{function}".to_string()
+                format!("{prefix}{{function}}")
             },
         })
     } else {
@@ -634,4 +724,31 @@ mod tests {
             auto_funcs: ~
             "###);
     }
+
+    #[test]
+    fn test_auto_publish_id_prefix() {
+        let cfg = auto("id_prefix: prod_");
+        assert_yaml_snapshot!(cfg, @r###"
+            ---
+            auto_table:
+              source_id_format: "prod_{table}"
+            auto_funcs:
+              source_id_format: "prod_{function}"
+            "###);
+
+        let cfg = auto(indoc! {"
+            id_prefix: analytics_
+            auto_publish:
+                tables:
+                    source_id_format: '{schema}.{table}'
+                functions:
+                    source_id_format: 'fn_{function}'"});
+        assert_yaml_snapshot!(cfg, @r###"
+            ---
+            auto_table:
+              source_id_format: "analytics_{schema}.{table}"
+            auto_funcs:
+              source_id_format: "analytics_fn_{function}"
+            "###);
+    }
 }
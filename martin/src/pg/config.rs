@@ -1,8 +1,11 @@
 use std::ops::Add;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use futures::future::try_join;
+use itertools::Itertools as _;
 use log::warn;
+use postgres_protocol::escape::{escape_identifier, escape_literal};
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
 
@@ -12,9 +15,10 @@ use crate::pg::builder::PgBuilder;
 use crate::pg::config_function::FuncInfoSources;
 use crate::pg::config_table::TableInfoSources;
 use crate::pg::utils::on_slow;
+use crate::pg::PgError::{AmbiguousConnectionString, AmbiguousPassword, NoConnectionString};
 use crate::pg::PgResult;
 use crate::source::TileInfoSources;
-use crate::utils::{IdResolver, OptBoolObj, OptOneMany};
+use crate::utils::{read_secret, read_secret_file, IdResolver, OptBoolObj, OptOneMany};
 use crate::MartinResult;
 
 pub trait PgInfo {
@@ -27,27 +31,109 @@ pub trait PgInfo {
 pub struct PgSslCerts {
     /// Same as PGSSLCERT
     /// ([docs](https://www.postgresql.org/docs/current/libpq-connect.html#LIBPQ-CONNECT-SSLCERT))
-    pub ssl_cert: Option<std::path::PathBuf>,
+    pub ssl_cert: Option<PathBuf>,
     /// Same as PGSSLKEY
     /// ([docs](https://www.postgresql.org/docs/current/libpq-connect.html#LIBPQ-CONNECT-SSLKEY))
-    pub ssl_key: Option<std::path::PathBuf>,
+    pub ssl_key: Option<PathBuf>,
     /// Same as PGSSLROOTCERT
     /// ([docs](https://www.postgresql.org/docs/current/libpq-connect.html#LIBPQ-CONNECT-SSLROOTCERT))
-    pub ssl_root_cert: Option<std::path::PathBuf>,
+    pub ssl_root_cert: Option<PathBuf>,
+}
+
+/// Postgres settings limiting the resources of the queries.
+/// They can be set for a connection, or for the queries of a single source.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PgSettings {
+    /// Abort any query that takes longer, in milliseconds
+    pub statement_timeout_ms: Option<u64>,
+    /// Abort any query that waits longer for a lock, in milliseconds
+    pub lock_timeout_ms: Option<u64>,
+    /// Memory used by a query for sorting and hashing before using temporary files, e.g. `64MB`
+    pub work_mem: Option<String>,
+    /// Database role used to run the queries, restricting them to the grants and row level security
+    /// policies of that role. The connection user must be a member of the role.
+    pub role: Option<String>,
+}
+
+impl PgSettings {
+    /// Names and values of the settings that are set
+    fn values(&self) -> Vec<(&'static str, String)> {
+        [
+            (
+                "statement_timeout",
+                self.statement_timeout_ms.map(|v| v.to_string()),
+            ),
+            ("lock_timeout", self.lock_timeout_ms.map(|v| v.to_string())),
+            ("work_mem", self.work_mem.clone()),
+            ("role", self.role.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect()
+    }
+
+    /// The `options` of a connection string that apply the settings to every query of the connection
+    #[must_use]
+    pub fn connection_options(&self) -> Option<String> {
+        let values = self.values();
+        (!values.is_empty()).then(|| {
+            values
+                .iter()
+                .map(|(name, value)| {
+                    let value = value.replace('\\', "\\\\").replace(' ', "\\ ");
+                    format!("-c {name}={value}")
+                })
+                .join(" ")
+        })
+    }
+
+    /// Statements starting a transaction with the settings applied to it
+    #[must_use]
+    pub fn begin_query(&self) -> Option<String> {
+        let values = self.values();
+        (!values.is_empty()).then(|| {
+            let settings = values
+                .iter()
+                .map(|(name, value)| format!("SET LOCAL {name} = {}", escape_literal(value)))
+                .join("; ");
+            format!("BEGIN; {settings}")
+        })
+    }
 }
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct PgConfig {
     pub connection_string: Option<String>,
+    /// Read the connection string from a file, e.g. a Docker or Kubernetes secret
+    pub connection_string_file: Option<PathBuf>,
+    /// Read the connection string from a secret manager, e.g. `aws-sm://<secret-id>` or `vault://<path>#<key>`
+    pub connection_string_secret: Option<String>,
+    /// Read the password from a file. It replaces the password of the connection string, if any.
+    pub password_file: Option<PathBuf>,
+    /// Read the password from a secret manager, e.g. `aws-sm://<secret-id>#password`
+    pub password_secret: Option<String>,
     #[serde(flatten)]
     pub ssl_certificates: PgSslCerts,
     pub default_srid: Option<i32>,
     pub auto_bounds: Option<BoundsCalcType>,
     pub max_feature_count: Option<usize>,
     pub pool_size: Option<usize>,
+    /// Do not use prepared statements or session settings, so that Martin can connect through
+    /// a pooler in transaction pooling mode, like `PgBouncer` with `pool_mode = transaction`
+    pub transaction_pooling: Option<bool>,
+    /// Settings of all queries of the connection, including the discovery and bounds queries
+    #[serde(flatten)]
+    pub settings: PgSettings,
+    /// Schemas used to resolve unqualified table and function names in the queries of the connection
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub search_path: OptOneMany<String>,
     #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
     pub auto_publish: OptBoolObj<PgCfgPublish>,
+    /// Prefix of the IDs of the auto-published sources of this connection, e.g. `prod_`, so that the tables
+    /// with the same names in several databases do not collide. The IDs of the configured sources are kept as is.
+    pub id_prefix: Option<String>,
     pub tables: Option<TableInfoSources>,
     pub functions: Option<FuncInfoSources>,
 }
@@ -95,6 +181,20 @@ pub struct PgCfgPublishFuncs {
 impl PgConfig {
     /// Apply defaults to the config, and validate if there is a connection string
     pub fn finalize(&mut self) -> PgResult<UnrecognizedValues> {
+        let conn_sources = [
+            self.connection_string.is_some(),
+            self.connection_string_file.is_some(),
+            self.connection_string_secret.is_some(),
+        ];
+        match conn_sources.iter().filter(|v| **v).count() {
+            0 => return Err(NoConnectionString),
+            1 => {}
+            _ => return Err(AmbiguousConnectionString),
+        }
+        if self.password_file.is_some() && self.password_secret.is_some() {
+            return Err(AmbiguousPassword);
+        }
+
         let mut res = UnrecognizedValues::new();
         if let Some(ref ts) = self.tables {
             for (k, v) in ts {
@@ -113,6 +213,50 @@ impl PgConfig {
         Ok(res)
     }
 
+    /// Get the connection string, reading it from a file or a secret manager if needed.
+    /// Secrets are never stored in the config, so they cannot leak with `--save-config`.
+    pub async fn get_connection_string(&self) -> PgResult<String> {
+        if let Some(path) = &self.connection_string_file {
+            Ok(read_secret_file(path)?)
+        } else if let Some(reference) = &self.connection_string_secret {
+            Ok(read_secret(reference).await?)
+        } else {
+            self.connection_string.clone().ok_or(NoConnectionString)
+        }
+    }
+
+    /// Get the password from a file or a secret manager, if configured
+    pub async fn get_password(&self) -> PgResult<Option<String>> {
+        if let Some(path) = &self.password_file {
+            Ok(Some(read_secret_file(path)?))
+        } else if let Some(reference) = &self.password_secret {
+            Ok(Some(read_secret(reference).await?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Startup options of the connection with its settings and search path, e.g. `-c search_path="gis","public"`
+    #[must_use]
+    pub fn connection_options(&self) -> Option<String> {
+        let search_path = (!self.search_path.is_empty()).then(|| {
+            let schemas = self
+                .search_path
+                .iter()
+                .map(|s| escape_identifier(s))
+                .join(",");
+            format!(
+                "-c search_path={}",
+                schemas.replace('\\', "\\\\").replace(' ', "\\ ")
+            )
+        });
+        self.settings
+            .connection_options()
+            .into_iter()
+            .chain(search_path)
+            .reduce(|a, b| format!("{a} {b}"))
+    }
+
     pub async fn resolve(&mut self, id_resolver: IdResolver) -> MartinResult<TileInfoSources> {
         let pg = PgBuilder::new(self, id_resolver).await?;
         let inst_tables = on_slow(
@@ -120,7 +264,10 @@ impl PgConfig {
             // warn only if default bounds timeout has already passed
             DEFAULT_BOUNDS_TIMEOUT.add(Duration::from_secs(1)),
             || {
-                if pg.auto_bounds() == BoundsCalcType::Skip {
+                if matches!(
+                    pg.auto_bounds(),
+                    BoundsCalcType::Skip | BoundsCalcType::Background
+                ) {
                     warn!("Discovering tables in PostgreSQL database '{}' is taking too long. Bounds calculation is already disabled. You may need to tune your database.", pg.get_id());
                 } else {
                     warn!("Discovering tables in PostgreSQL database '{}' is taking too long. Make sure your table geo columns have a GIS index, or use '--auto-bounds skip' CLI/config to skip bbox calculation.", pg.get_id());
@@ -145,7 +292,7 @@ mod tests {
     use tilejson::Bounds;
 
     use super::*;
-    use crate::config::tests::assert_config;
+    use crate::config::tests::{assert_config, parse_cfg};
     use crate::config::Config;
     use crate::pg::config_function::FunctionInfo;
     use crate::pg::config_table::TableInfo;
@@ -196,6 +343,150 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_pg_curated_and_discovered() {
+        let mut cfg = parse_cfg(indoc! {"
+            postgres:
+              - connection_string: 'postgresql://postgres@prod/db'
+                auto_publish: false
+                tables:
+                  roads:
+                    schema: public
+                    table: roads
+                    geometry_column: geom
+                    srid: 4326
+                    geometry_type: LINESTRING
+              - connection_string: 'postgresql://postgres@staging/db'
+                id_prefix: staging_
+        "});
+        cfg.finalize().unwrap();
+        let [curated, discovered] = cfg.postgres.iter().collect::<Vec<_>>()[..] else {
+            panic!("expected two connections");
+        };
+        // the listed tables do not enable the discovery of a connection that disabled it
+        assert_eq!(curated.auto_publish, OptBoolObj::Bool(false));
+        assert_eq!(curated.tables.as_ref().unwrap().len(), 1);
+        assert_eq!(discovered.auto_publish, OptBoolObj::Bool(true));
+        assert_eq!(discovered.id_prefix.as_deref(), Some("staging_"));
+    }
+
+    #[test]
+    fn parse_pg_secrets() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string_file: /run/secrets/db_url
+              password_secret: 'aws-sm://prod/martin#password'
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string_file: Some(PathBuf::from("/run/secrets/db_url")),
+                    password_secret: some("aws-sm://prod/martin#password"),
+                    auto_publish: OptBoolObj::Bool(true),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+
+        let mut cfg = PgConfig::default();
+        assert!(matches!(cfg.finalize(), Err(NoConnectionString)));
+        let mut cfg = PgConfig {
+            connection_string: some("postgres://localhost/db"),
+            connection_string_secret: some("vault://secret/data/martin#url"),
+            ..Default::default()
+        };
+        assert!(matches!(cfg.finalize(), Err(AmbiguousConnectionString)));
+    }
+
+    #[test]
+    fn parse_pg_settings() {
+        assert_config(
+            indoc! {"
+            postgres:
+              connection_string: 'postgres://postgres@localhost:5432/db'
+              statement_timeout_ms: 30000
+              tables:
+                table_source:
+                  schema: public
+                  table: table_source
+                  srid: 4326
+                  geometry_column: geom
+                  statement_timeout_ms: 5000
+                  work_mem: 64MB
+        "},
+            &Config {
+                postgres: One(PgConfig {
+                    connection_string: some("postgres://postgres@localhost:5432/db"),
+                    settings: PgSettings {
+                        statement_timeout_ms: Some(30000),
+                        ..Default::default()
+                    },
+                    tables: Some(BTreeMap::from([(
+                        "table_source".to_string(),
+                        TableInfo {
+                            schema: "public".to_string(),
+                            table: "table_source".to_string(),
+                            srid: 4326,
+                            geometry_column: "geom".to_string(),
+                            settings: PgSettings {
+                                statement_timeout_ms: Some(5000),
+                                work_mem: some("64MB"),
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn pg_settings_queries() {
+        let settings = PgSettings {
+            statement_timeout_ms: Some(5000),
+            work_mem: some("64 MB"),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.connection_options().unwrap(),
+            r"-c statement_timeout=5000 -c work_mem=64\ MB"
+        );
+        assert_eq!(
+            settings.begin_query().unwrap(),
+            "BEGIN; SET LOCAL statement_timeout = '5000'; SET LOCAL work_mem = '64 MB'"
+        );
+        let settings = PgSettings {
+            role: some("tiles_reader"),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.begin_query().unwrap(),
+            "BEGIN; SET LOCAL role = 'tiles_reader'"
+        );
+        assert_eq!(PgSettings::default().begin_query(), None);
+        assert_eq!(PgSettings::default().connection_options(), None);
+    }
+
+    #[test]
+    fn pg_search_path() {
+        let mut cfg = PgConfig {
+            search_path: One("gis".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cfg.connection_options().unwrap(), r#"-c search_path="gis""#);
+        cfg.search_path = Many(vec!["$user".to_string(), "my schema".to_string()]);
+        cfg.settings.work_mem = some("64MB");
+        assert_eq!(
+            cfg.connection_options().unwrap(),
+            r#"-c work_mem=64MB -c search_path="$user","my\ schema""#
+        );
+        assert_eq!(PgConfig::default().connection_options(), None);
+    }
+
     #[test]
     fn parse_pg_config() {
         assert_config(
@@ -230,6 +521,7 @@ mod tests {
                   minzoom: 0
                   maxzoom: 30
                   bounds: [-180.0, -90.0, 180.0, 90.0]
+                  defaults: {srid: 3857, lang: en}
         "},
             &Config {
                 postgres: One(PgConfig {
@@ -260,13 +552,19 @@ mod tests {
                     )])),
                     functions: Some(BTreeMap::from([(
                         "function_zxy_query".to_string(),
-                        FunctionInfo::new_extended(
-                            "public".to_string(),
-                            "function_zxy_query".to_string(),
-                            0,
-                            30,
-                            Bounds::MAX,
-                        ),
+                        FunctionInfo {
+                            defaults: Some(BTreeMap::from([
+                                ("lang".to_string(), serde_json::json!("en")),
+                                ("srid".to_string(), serde_json::json!(3857)),
+                            ])),
+                            ..FunctionInfo::new_extended(
+                                "public".to_string(),
+                                "function_zxy_query".to_string(),
+                                0,
+                                30,
+                                Bounds::MAX,
+                            )
+                        },
                     )])),
                     ..Default::default()
                 }),
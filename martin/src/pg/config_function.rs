@@ -1,8 +1,10 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
-use tilejson::{Bounds, TileJSON};
+use tilejson::{Bounds, TileJSON, VectorLayer};
 
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::pg::config::{PgInfo, PgSettings};
 use crate::pg::utils::{patch_json, InfoMap};
 
 pub type FuncInfoSources = InfoMap<FunctionInfo>;
@@ -28,6 +30,26 @@ pub struct FunctionInfo {
     /// Values may be integers or floating point numbers.
     pub bounds: Option<Bounds>,
 
+    /// Values of the query parameters passed to the function if they are not given in the request URL,
+    /// e.g. `{srid: 3857, lang: en}`. Only used by functions with a `query_params` argument.
+    pub defaults: Option<BTreeMap<String, serde_json::Value>>,
+
+    /// Layers of the tiles, with the types of their attributes, e.g. `[{id: roads, fields: {name: String}}]`.
+    /// They replace the `vector_layers` of the SQL function comment.
+    pub vector_layers: Option<Vec<VectorLayer>>,
+
+    /// Get a sample tile at startup, and list its layers and attribute types in the `TileJSON`
+    /// if the `vector_layers` are neither configured nor set by the SQL function comment
+    pub introspect: Option<bool>,
+
+    /// Sequence whose current value is the version of the function data, e.g. `public.roads_version`.
+    /// Calling `nextval` on it after a data update invalidates the cached tiles.
+    pub version_sequence: Option<String>,
+
+    /// Settings of the queries of this function, in addition to the ones of the connection
+    #[serde(flatten)]
+    pub settings: PgSettings,
+
     /// TileJSON provided by the SQL function comment. Not serialized.
     #[serde(skip)]
     pub tilejson: Option<serde_json::Value>,
@@ -80,7 +102,11 @@ impl PgInfo for FunctionInfo {
         tilejson.minzoom = self.minzoom;
         tilejson.maxzoom = self.maxzoom;
         tilejson.bounds = self.bounds;
-        patch_json(tilejson, self.tilejson.as_ref())
+        let mut tilejson = patch_json(tilejson, self.tilejson.as_ref());
+        if let Some(layers) = &self.vector_layers {
+            tilejson.vector_layers = Some(layers.clone());
+        }
+        tilejson
     }
 }
 
@@ -95,3 +121,31 @@ impl FunctionInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_vector_layers() {
+        let mut info = FunctionInfo::new(
+            "public".to_string(),
+            "roads".to_string(),
+            Some(json!({"vector_layers": [{"id": "comment", "fields": {}}]})),
+        );
+        let tj = info.to_tilejson("roads".to_string());
+        assert_eq!(tj.vector_layers.unwrap()[0].id, "comment");
+
+        info.vector_layers = Some(vec![VectorLayer::new(
+            "roads".to_string(),
+            [("name".to_string(), "String".to_string())].into(),
+        )]);
+        let tj = info.to_tilejson("roads".to_string());
+        let layers = tj.vector_layers.unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].id, "roads");
+        assert_eq!(layers[0].fields["name"], "String");
+    }
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use tilejson::{Bounds, TileJSON, VectorLayer};
 
 use crate::config::UnrecognizedValues;
-use crate::pg::config::PgInfo;
+use crate::pg::config::{PgInfo, PgSettings};
 use crate::pg::utils::{normalize_key, patch_json, InfoMap};
 
 pub type TableInfoSources = InfoMap<TableInfo>;
@@ -66,6 +66,22 @@ pub struct TableInfo {
     /// List of columns, that should be encoded as tile properties
     pub properties: Option<BTreeMap<String, String>>,
 
+    /// Column with a unique key of each feature. Enables `UTFGrid` interaction data for this table,
+    /// with the listed properties as the data of each feature.
+    pub utfgrid_key: Option<String>,
+
+    /// Sequence whose current value is the version of the table data, e.g. `public.roads_version`.
+    /// Calling `nextval` on it after a data update invalidates the cached tiles.
+    pub version_sequence: Option<String>,
+
+    /// Timestamp column with the time each row was last modified, e.g. `updated_at`.
+    /// Its most recent value is the `Last-Modified` date of the tiles.
+    pub last_modified_column: Option<String>,
+
+    /// Settings of the queries of this table, in addition to the ones of the connection
+    #[serde(flatten)]
+    pub settings: PgSettings,
+
     /// Mapping of properties to the actual table columns
     #[serde(skip)]
     pub prop_mapping: HashMap<String, String>,
@@ -101,6 +117,10 @@ impl PgInfo for TableInfo {
             other: BTreeMap::default(),
         };
         tilejson.vector_layers = Some(vec![layer]);
+        if self.utfgrid_key.is_some() {
+            // grid URLs are set when serving the TileJSON, just like the tile URLs
+            tilejson.grids = Some(vec![]);
+        }
         patch_json(tilejson, self.tilejson.as_ref())
     }
 }
@@ -148,6 +168,11 @@ impl TableInfo {
             inf.prop_mapping.insert(id_column.clone(), prop);
         }
 
+        if let Some(key) = &cfg_inf.utfgrid_key {
+            let prop = normalize_key(props, key.as_str(), "utfgrid_key", new_id)?;
+            inf.prop_mapping.insert(key.clone(), prop);
+        }
+
         if let Some(p) = &cfg_inf.properties {
             for key in p.keys() {
                 let prop = normalize_key(props, key.as_str(), "property", new_id)?;
@@ -43,6 +43,18 @@ pub enum PgError {
     #[error("Unable to get a Postgres connection from the pool {1}: {0}")]
     PostgresPoolConnError(#[source] PoolError, String),
 
+    #[error("Postgres connection requires one of connection_string, connection_string_file or connection_string_secret")]
+    NoConnectionString,
+
+    #[error("Only one of connection_string, connection_string_file and connection_string_secret can be set for a Postgres connection")]
+    AmbiguousConnectionString,
+
+    #[error("Only one of password_file and password_secret can be set for a Postgres connection")]
+    AmbiguousPassword,
+
+    #[error(transparent)]
+    SecretError(#[from] crate::utils::SecretError),
+
     #[error("Unable to parse connection string {1}: {0}")]
     BadConnectionString(#[source] TokioPgError, String),
 
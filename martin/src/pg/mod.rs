@@ -1,3 +1,4 @@
+mod bounds;
 mod builder;
 mod config;
 mod config_function;
@@ -10,9 +11,12 @@ mod query_tables;
 mod tls;
 mod utils;
 
-pub use config::{PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSslCerts};
+pub use bounds::{bounds_statuses, recompute_bounds, BoundsMethod, BoundsStatus};
+pub use config::{
+    PgCfgPublish, PgCfgPublishFuncs, PgCfgPublishTables, PgConfig, PgSettings, PgSslCerts,
+};
 pub use config_function::FunctionInfo;
 pub use config_table::TableInfo;
 pub use errors::{PgError, PgResult};
-pub use pool::{PgPool, POOL_SIZE_DEFAULT};
+pub use pool::{pool_statuses, PgPool, PoolStatus, POOL_SIZE_DEFAULT};
 pub use query_functions::query_available_function;
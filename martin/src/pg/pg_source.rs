@@ -1,16 +1,68 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use async_trait::async_trait;
-use deadpool_postgres::tokio_postgres::types::{ToSql, Type};
+use deadpool_postgres::tokio_postgres::types::{Json, ToSql, Type};
+use deadpool_postgres::tokio_postgres::{CancelToken, Error as PostgresQueryError};
+use deadpool_postgres::Object;
 use log::debug;
 use martin_tile_utils::Encoding::Uncompressed;
 use martin_tile_utils::Format::Mvt;
 use martin_tile_utils::TileInfo;
+use postgres_protocol::escape::escape_identifier;
+use serde_json::Value;
 use tilejson::TileJSON;
+use tokio::sync::Mutex;
 
+use crate::pg::bounds::TileJsonPatch;
 use crate::pg::pool::PgPool;
 use crate::pg::utils::query_to_json;
-use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PrepareQueryError};
+use crate::pg::PgError::{GetTileError, GetTileWithQueryError, PostgresError, PrepareQueryError};
+use crate::pg::PgResult;
 use crate::source::{Source, TileData, UrlQuery};
-use crate::{MartinResult, TileCoord};
+use crate::srv::current_request_id;
+use crate::utils::{record_phase, UTFGRID_SIZE};
+use crate::{MartinResult, TileCoord, UtfGrid};
+
+/// Types of the zoom, x and y parameters of the tile queries
+const XYZ_PARAM_TYPES: &[Type] = &[Type::INT2, Type::INT8, Type::INT8];
+
+const SET_APPLICATION_NAME: &str = "SELECT set_config('application_name', $1, false)";
+
+/// How long the results of the data version and modification time queries are reused
+/// before the database is asked again
+const METADATA_QUERY_TTL: Duration = Duration::from_secs(5);
+
+/// Result of a metadata query, e.g. the data version, reused for a while instead of querying
+/// the database on every tile request. Errors are not cached.
+#[derive(Debug, Default)]
+struct CachedQuery<T> {
+    value: Mutex<Option<(Instant, T)>>,
+}
+
+impl<T: Clone> CachedQuery<T> {
+    /// Returns the cached value if it is younger than `ttl`, or runs the query otherwise.
+    /// Concurrent callers wait for the running query instead of starting their own.
+    async fn get_or_query<F, Fut>(&self, ttl: Duration, query: F) -> MartinResult<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = MartinResult<T>>,
+    {
+        let mut value = self.value.lock().await;
+        if let Some((queried, cached)) = value.as_ref() {
+            if queried.elapsed() < ttl {
+                return Ok(cached.clone());
+            }
+        }
+        let result = query().await?;
+        *value = Some((Instant::now(), result.clone()));
+        Ok(result)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct PgSource {
@@ -18,6 +70,12 @@ pub struct PgSource {
     info: PgSqlInfo,
     pool: PgPool,
     tilejson: TileJSON,
+    /// `TileJSON` with the bounds computed after startup, shared by all clones of the source
+    patched_tilejson: Arc<TileJsonPatch>,
+    /// Last value of the `version_sequence`, shared by all clones of the source
+    data_version: Arc<CachedQuery<Option<String>>>,
+    /// Last value of the `last_modified_column`, shared by all clones of the source
+    last_modified: Arc<CachedQuery<Option<SystemTime>>>,
 }
 
 impl PgSource {
@@ -28,8 +86,69 @@ impl PgSource {
             info,
             pool,
             tilejson,
+            patched_tilejson: Arc::default(),
+            data_version: Arc::default(),
+            last_modified: Arc::default(),
+        }
+    }
+
+    /// Prepare the tile queries on one of the pool connections. Invalid queries are reported at startup
+    /// instead of on every tile request, and the connection keeps the statements in its cache.
+    /// Nothing is prepared behind a transaction pooler, which cannot keep prepared statements.
+    pub(crate) async fn prepare(&self) -> PgResult<()> {
+        if self.pool.transaction_pooling() {
+            return Ok(());
+        }
+        let conn = self.pool.get().await?;
+        let mut queries = vec![(&self.info.sql_query, self.tile_param_types())];
+        if let Some(sql) = &self.info.utfgrid_query {
+            queries.push((sql, XYZ_PARAM_TYPES));
+        }
+        for (sql, param_types) in queries {
+            conn.prepare_typed_cached(sql, param_types)
+                .await
+                .map_err(|e| {
+                    PrepareQueryError(e, self.id.clone(), self.info.signature.clone(), sql.clone())
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Run a query in a transaction with the settings of the source, if it has any.
+    /// The statements are pipelined with the query, so the transaction adds no round trips.
+    async fn in_transaction<T>(
+        &self,
+        conn: &PooledConn,
+        query: impl Future<Output = Result<T, PostgresQueryError>>,
+    ) -> Result<T, PostgresQueryError> {
+        let Some(begin) = &self.info.begin_query else {
+            return query.await;
+        };
+        conn.in_transaction.store(true, Ordering::Relaxed);
+        let (begin, result, commit) = futures::join!(
+            conn.batch_execute(begin),
+            query,
+            conn.batch_execute("COMMIT")
+        );
+        if commit.is_ok() {
+            conn.in_transaction.store(false, Ordering::Relaxed);
+        }
+        begin.and(result)
+    }
+
+    /// Tile coordinates are always bound as parameters, and so is the URL query if the source supports it
+    fn tile_param_types(&self) -> &'static [Type] {
+        if self.support_url_query() {
+            &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
+        } else {
+            XYZ_PARAM_TYPES
         }
     }
+
+    /// Replaces the `TileJSON` of this source and all its clones once set
+    pub(crate) fn patched_tilejson(&self) -> Arc<TileJsonPatch> {
+        self.patched_tilejson.clone()
+    }
 }
 
 #[async_trait]
@@ -39,7 +158,7 @@ impl Source for PgSource {
     }
 
     fn get_tilejson(&self) -> &TileJSON {
-        &self.tilejson
+        self.patched_tilejson.get().unwrap_or(&self.tilejson)
     }
 
     fn get_tile_info(&self) -> TileInfo {
@@ -54,52 +173,132 @@ impl Source for PgSource {
         self.info.use_url_query
     }
 
+    fn get_source_type(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        let Some(sql) = &self.info.version_query else {
+            return Ok(None);
+        };
+        self.data_version
+            .get_or_query(METADATA_QUERY_TTL, || async {
+                let conn = PooledConn::new(self.pool.get().await?);
+                let row = self
+                    .in_transaction(&conn, conn.query_one(sql, &[]))
+                    .await
+                    .map_err(|e| PostgresError(e, "querying the data version"))?;
+                Ok(Some(row.get(0)))
+            })
+            .await
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        let Some(sql) = &self.info.last_modified_query else {
+            return Ok(None);
+        };
+        self.last_modified
+            .get_or_query(METADATA_QUERY_TTL, || async {
+                let conn = PooledConn::new(self.pool.get().await?);
+                let row = self
+                    .in_transaction(&conn, conn.query_one(sql, &[]))
+                    .await
+                    .map_err(|e| PostgresError(e, "querying the last modification time"))?;
+                // an empty table has no modification time
+                let secs: Option<f64> = row.get(0);
+                Ok(secs
+                    .filter(|v| v.is_finite() && *v >= 0.0)
+                    .map(|v| UNIX_EPOCH + Duration::from_secs_f64(v)))
+            })
+            .await
+    }
+
     async fn get_tile(
         &self,
         xyz: TileCoord,
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData> {
-        let conn = self.pool.get().await?;
-        let param_types: &[Type] = if self.support_url_query() {
-            &[Type::INT2, Type::INT8, Type::INT8, Type::JSON]
-        } else {
-            &[Type::INT2, Type::INT8, Type::INT8]
-        };
+        let start = Instant::now();
+        let conn = PooledConn::new(self.pool.get().await?);
+        record_phase("pool", start.elapsed());
 
         let sql = &self.info.sql_query;
-        let prep_query = conn
-            .prepare_typed_cached(sql, param_types)
-            .await
-            .map_err(|e| {
-                PrepareQueryError(
-                    e,
-                    self.id.to_string(),
-                    self.info.signature.to_string(),
-                    self.info.sql_query.to_string(),
-                )
-            })?;
-
-        let tile = if self.support_url_query() {
-            let json = query_to_json(url_query);
+        let (z, x, y) = (i16::from(xyz.z), i64::from(xyz.x), i64::from(xyz.y));
+        let json = self
+            .support_url_query()
+            .then(|| self.info.query_to_json(url_query));
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&z, &x, &y];
+        if let Some(json) = &json {
             debug!("SQL: {sql} [{xyz}, {json:?}]");
-            let params: &[&(dyn ToSql + Sync)] = &[
-                &i16::from(xyz.z),
-                &i64::from(xyz.x),
-                &i64::from(xyz.y),
-                &json,
-            ];
-            conn.query_opt(&prep_query, params).await
+            params.push(json);
         } else {
             debug!("SQL: {sql} [{xyz}]");
-            conn.query_opt(
-                &prep_query,
-                &[&i16::from(xyz.z), &i64::from(xyz.x), &i64::from(xyz.y)],
-            )
-            .await
+        }
+
+        // If the request is dropped while waiting for the query (e.g. on timeout), stop the query too
+        let cancel_guard = QueryCancelGuard {
+            pool: &self.pool,
+            token: Some(conn.cancel_token()),
+        };
+        let tile = if self.pool.transaction_pooling() {
+            // A transaction pooler may run every statement on a different server connection,
+            // so the query is parsed and executed at once, and the connection is not labeled
+            let start = Instant::now();
+            let params = params
+                .into_iter()
+                .zip(self.tile_param_types().iter().cloned())
+                .collect::<Vec<_>>();
+            let rows = self
+                .in_transaction(&conn, conn.query_typed(sql, &params))
+                .await;
+            cancel_guard.disarm();
+            record_phase("query", start.elapsed());
+            rows.map(|rows| rows.into_iter().next())
+        } else {
+            let start = Instant::now();
+            let prep_query = conn
+                .prepare_typed_cached(sql, self.tile_param_types())
+                .await
+                .map_err(|e| {
+                    PrepareQueryError(
+                        e,
+                        self.id.to_string(),
+                        self.info.signature.to_string(),
+                        self.info.sql_query.to_string(),
+                    )
+                })?;
+            // Label the connection with the request ID, so that slow queries in the Postgres logs
+            // and in pg_stat_activity can be traced back to the client's request
+            let app_name = current_request_id().map(|id| self.pool.request_application_name(&id));
+            let set_app_name = match app_name {
+                Some(ref name) => Some((
+                    conn.prepare_typed_cached(SET_APPLICATION_NAME, &[Type::TEXT])
+                        .await
+                        .map_err(|e| PostgresError(e, "preparing to set application_name"))?,
+                    name,
+                )),
+                None => None,
+            };
+            record_phase("prepare", start.elapsed());
+
+            let start = Instant::now();
+            let label = async {
+                if let Some((stmt, name)) = &set_app_name {
+                    if let Err(e) = conn.execute(stmt, &[name]).await {
+                        debug!("Unable to set application_name of {}: {e}", self.id);
+                    }
+                }
+            };
+            // both statements are pipelined on the connection, so labeling it adds no round trip
+            let query = self.in_transaction(&conn, conn.query_opt(&prep_query, &params));
+            let ((), tile) = futures::join!(label, query);
+            cancel_guard.disarm();
+            record_phase("query", start.elapsed());
+            tile
         };
 
         let tile = tile
-            .map(|row| row.and_then(|r| r.get::<_, Option<TileData>>(0)))
+            .map(|row| row.and_then(|r| r.get::<_, Option<Vec<u8>>>(0)))
             .map_err(|e| {
                 if self.support_url_query() {
                     GetTileWithQueryError(e, self.id.to_string(), xyz, url_query.cloned())
@@ -107,10 +306,107 @@ impl Source for PgSource {
                     GetTileError(e, self.id.to_string(), xyz)
                 }
             })?
-            .unwrap_or_default();
+            .map_or_else(TileData::new, TileData::from);
 
         Ok(tile)
     }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        let Some(sql) = &self.info.utfgrid_query else {
+            return Ok(None);
+        };
+        let conn = PooledConn::new(self.pool.get().await?);
+        let (z, x, y) = (i16::from(xyz.z), i64::from(xyz.x), i64::from(xyz.y));
+        let cancel_guard = QueryCancelGuard {
+            pool: &self.pool,
+            token: Some(conn.cancel_token()),
+        };
+        debug!("SQL: {sql} [{xyz}]");
+        let rows = if self.pool.transaction_pooling() {
+            let params: [(&(dyn ToSql + Sync), Type); 3] =
+                [(&z, Type::INT2), (&x, Type::INT8), (&y, Type::INT8)];
+            self.in_transaction(&conn, conn.query_typed(sql, &params))
+                .await
+        } else {
+            let prep_query = conn
+                .prepare_typed_cached(sql, XYZ_PARAM_TYPES)
+                .await
+                .map_err(|e| {
+                    PrepareQueryError(e, self.id.clone(), self.info.signature.clone(), sql.clone())
+                })?;
+            self.in_transaction(&conn, conn.query(&prep_query, &[&z, &x, &y]))
+                .await
+        };
+        cancel_guard.disarm();
+        let rows = rows.map_err(|e| GetTileError(e, self.id.clone(), xyz))?;
+
+        let cells = rows.iter().filter_map(|row| {
+            Some((
+                usize::try_from(row.get::<_, i32>(0)).ok()?,
+                usize::try_from(row.get::<_, i32>(1)).ok()?,
+                row.get::<_, String>(2),
+                row.get::<_, Value>(3),
+            ))
+        });
+        Ok(Some(UtfGrid::new(UTFGRID_SIZE, cells)))
+    }
+}
+
+/// A pooled connection that is closed instead of being returned to the pool
+/// if it may still be in a transaction, e.g. when the request is dropped while waiting for the query
+struct PooledConn {
+    conn: Option<Object>,
+    in_transaction: AtomicBool,
+}
+
+impl PooledConn {
+    fn new(conn: Object) -> Self {
+        Self {
+            conn: Some(conn),
+            in_transaction: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Deref for PooledConn {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        self.conn
+            .as_ref()
+            .expect("connection is only taken when dropped")
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        if *self.in_transaction.get_mut() {
+            if let Some(conn) = self.conn.take() {
+                drop(Object::take(conn));
+            }
+        }
+    }
+}
+
+/// Cancels the query running on the server unless disarmed before being dropped
+struct QueryCancelGuard<'a> {
+    pool: &'a PgPool,
+    token: Option<CancelToken>,
+}
+
+impl QueryCancelGuard<'_> {
+    fn disarm(mut self) {
+        self.token = None;
+    }
+}
+
+impl Drop for QueryCancelGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            debug!("Cancelling a query in {}", self.pool.get_id());
+            self.pool.cancel_query(token);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -118,6 +414,36 @@ pub struct PgSqlInfo {
     pub sql_query: String,
     pub use_url_query: bool,
     pub signature: String,
+    /// Query returning the `(row, column, key, data)` of the non-empty `UTFGrid` cells
+    pub utfgrid_query: Option<String>,
+    /// Query parameters used when they are not given in the request URL
+    pub query_defaults: BTreeMap<String, Value>,
+    /// Statements starting the transaction of each query, with the settings of the source
+    pub begin_query: Option<String>,
+    /// Query returning the version of the source data as text
+    pub version_query: Option<String>,
+    /// Query returning the last modification time of the source data in seconds since the epoch
+    pub last_modified_query: Option<String>,
+}
+
+/// Query returning the current value of a sequence, given as `name` or `schema.name`, as text
+#[must_use]
+pub fn version_query(sequence: &str) -> String {
+    let name = sequence
+        .split('.')
+        .map(escape_identifier)
+        .collect::<Vec<_>>()
+        .join(".");
+    format!("SELECT last_value::text FROM {name}")
+}
+
+/// Query returning the most recent value of a timestamp column of a table in seconds since the epoch
+#[must_use]
+pub fn last_modified_query(schema: &str, table: &str, column: &str) -> String {
+    let schema = escape_identifier(schema);
+    let table = escape_identifier(table);
+    let column = escape_identifier(column);
+    format!("SELECT extract(epoch FROM max({column}))::float8 FROM {schema}.{table}")
 }
 
 impl PgSqlInfo {
@@ -127,6 +453,63 @@ impl PgSqlInfo {
             sql_query: query,
             use_url_query: has_query_params,
             signature,
+            utfgrid_query: None,
+            query_defaults: BTreeMap::new(),
+            begin_query: None,
+            version_query: None,
+            last_modified_query: None,
+        }
+    }
+
+    /// Query parameters of the request as JSON, with the configured defaults for the missing ones
+    #[must_use]
+    pub fn query_to_json(&self, url_query: Option<&UrlQuery>) -> Json<HashMap<String, Value>> {
+        let mut json = query_to_json(url_query);
+        for (key, value) in &self.query_defaults {
+            json.0.entry(key.clone()).or_insert_with(|| value.clone());
         }
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_version_query() {
+        assert_eq!(
+            version_query("public.roads_version"),
+            r#"SELECT last_value::text FROM "public"."roads_version""#
+        );
+        assert_eq!(
+            version_query("Epoch"),
+            r#"SELECT last_value::text FROM "Epoch""#
+        );
+    }
+
+    #[test]
+    fn test_last_modified_query() {
+        assert_eq!(
+            last_modified_query("public", "Roads", "updated_at"),
+            r#"SELECT extract(epoch FROM max("updated_at"))::float8 FROM "public"."Roads""#
+        );
+    }
+
+    #[test]
+    fn test_query_defaults() {
+        let mut info = PgSqlInfo::new(String::new(), true, String::new());
+        info.query_defaults = BTreeMap::from([
+            ("srid".to_string(), json!(3857)),
+            ("lang".to_string(), json!("en")),
+        ]);
+        let query = UrlQuery::from([("lang".to_string(), "de".to_string())]);
+        let expected = HashMap::from([
+            ("srid".to_string(), json!(3857)),
+            ("lang".to_string(), json!("de")),
+        ]);
+        assert_eq!(info.query_to_json(Some(&query)).0, expected);
     }
 }
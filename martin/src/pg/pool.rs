@@ -1,7 +1,14 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Mutex;
+
+use deadpool_postgres::tokio_postgres::{CancelToken, NoTls};
 use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
 use log::{info, warn};
 use postgres::config::SslMode;
 use semver::Version;
+use serde::Serialize;
+use tokio_postgres_rustls::MakeRustlsConnect;
 
 use crate::pg::config::PgConfig;
 use crate::pg::tls::{make_connector, parse_conn_str, SslModeOverride};
@@ -18,17 +25,65 @@ const MINIMUM_POSTGIS_VER: Version = Version::new(3, 0, 0);
 // After this version we can use margin parameter in ST_TileEnvelope
 const RECOMMENDED_POSTGIS_VER: Version = Version::new(3, 1, 0);
 
+/// The latest pool of each connection ID, for diagnostics.
+/// A pool is replaced when the connection is configured again, e.g. when the config is reloaded.
+static POOLS: Mutex<BTreeMap<String, PgPool>> = Mutex::new(BTreeMap::new());
+
+/// Usage of a connection pool
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub id: String,
+    /// Maximum number of connections
+    pub max_size: usize,
+    /// Number of open connections
+    pub size: usize,
+    /// Number of idle connections
+    pub available: usize,
+    /// Number of requests waiting for a connection
+    pub waiting: usize,
+}
+
+/// Usage of the Postgres connection pools
+#[must_use]
+pub fn pool_statuses() -> Vec<PoolStatus> {
+    POOLS.lock().unwrap().values().map(PgPool::status).collect()
+}
+
+/// TLS connector used to send query cancellation requests, same as the one used by the pool
+#[derive(Clone)]
+enum CancelTls {
+    NoTls,
+    Rustls(MakeRustlsConnect),
+}
+
+impl Debug for CancelTls {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoTls => write!(f, "NoTls"),
+            Self::Rustls(_) => write!(f, "Rustls"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PgPool {
     id: String,
     pool: Pool,
+    cancel_tls: CancelTls,
+    /// Application name of the connections, as set in the connection string or by Martin
+    application_name: String,
+    /// Connections go through a transaction pooler like `PgBouncer`, so session state cannot be used
+    transaction_pooling: bool,
     // When true, we can use margin parameter in ST_TileEnvelope
     margin: bool,
 }
 
 impl PgPool {
     pub async fn new(config: &PgConfig) -> PgResult<Self> {
-        let (id, mgr) = Self::parse_config(config)?;
+        let conn_str = config.get_connection_string().await?;
+        let password = config.get_password().await?;
+        let (id, mgr, cancel_tls, application_name) =
+            Self::parse_config(config, &conn_str, password)?;
 
         let pool = Pool::builder(mgr)
             .max_size(config.pool_size.unwrap_or(POOL_SIZE_DEFAULT))
@@ -37,7 +92,7 @@ impl PgPool {
 
         let version: String = get_conn(&pool, id.as_str())
             .await?
-            .query_one(
+            .query_typed(
                 r"
 SELECT
     (regexp_matches(
@@ -49,8 +104,10 @@ SELECT
                 &[],
             )
             .await
+            .map_err(|e| PostgresError(e, "querying postgis version"))?
+            .first()
             .map(|row| row.get("version"))
-            .map_err(|e| PostgresError(e, "querying postgis version"))?;
+            .unwrap_or_default();
 
         let version: Version = version.parse().map_err(|e| BadPostgisVersion(e, version))?;
         if version < MINIMUM_POSTGIS_VER {
@@ -61,26 +118,56 @@ SELECT
         }
 
         let margin = version >= RECOMMENDED_POSTGIS_VER;
-        Ok(Self { id, pool, margin })
+        let pool = Self {
+            id,
+            pool,
+            cancel_tls,
+            application_name,
+            transaction_pooling: config.transaction_pooling.unwrap_or_default(),
+            margin,
+        };
+        POOLS.lock().unwrap().insert(pool.id.clone(), pool.clone());
+        Ok(pool)
     }
 
-    fn parse_config(config: &PgConfig) -> PgResult<(String, Manager)> {
-        let conn_str = config.connection_string.as_ref().unwrap().as_str();
-        let (pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
+    fn parse_config(
+        config: &PgConfig,
+        conn_str: &str,
+        password: Option<String>,
+    ) -> PgResult<(String, Manager, CancelTls, String)> {
+        let (mut pg_cfg, ssl_mode) = parse_conn_str(conn_str)?;
+        if let Some(password) = password {
+            pg_cfg.password(password);
+        }
+        if let Some(options) = config.connection_options() {
+            let options = match pg_cfg.get_options() {
+                Some(existing) => format!("{existing} {options}"),
+                None => options,
+            };
+            pg_cfg.options(&options);
+        }
 
         let id = pg_cfg.get_dbname().map_or_else(
             || format!("{:?}", pg_cfg.get_hosts()[0]),
             ToString::to_string,
         );
 
+        let application_name = pg_cfg
+            .get_application_name()
+            .unwrap_or_default()
+            .to_string();
+
         let mgr_config = ManagerConfig {
             recycling_method: RecyclingMethod::Fast,
         };
 
-        let mgr = if pg_cfg.get_ssl_mode() == SslMode::Disable {
+        let (mgr, cancel_tls) = if pg_cfg.get_ssl_mode() == SslMode::Disable {
             info!("Connecting without SSL support: {pg_cfg:?}");
-            let connector = deadpool_postgres::tokio_postgres::NoTls {};
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            let connector = NoTls {};
+            (
+                Manager::from_config(pg_cfg, connector, mgr_config),
+                CancelTls::NoTls,
+            )
         } else {
             match ssl_mode {
                 SslModeOverride::Unmodified(_) => {
@@ -92,23 +179,69 @@ SELECT
                 SslModeOverride::VerifyFull => {
                     info!("Using sslmode=verify-full to connect: {pg_cfg:?}");
                 }
-            };
+            }
             let connector = make_connector(&config.ssl_certificates, ssl_mode)?;
-            Manager::from_config(pg_cfg, connector, mgr_config)
+            (
+                Manager::from_config(pg_cfg, connector.clone(), mgr_config),
+                CancelTls::Rustls(connector),
+            )
         };
 
-        Ok((id, mgr))
+        Ok((id, mgr, cancel_tls, application_name))
     }
 
     pub async fn get(&self) -> PgResult<Object> {
         get_conn(&self.pool, self.id.as_str()).await
     }
 
+    /// Ask the server to cancel the query currently running on the connection the token belongs to.
+    /// The request is sent in the background because this is usually called from a `Drop` impl.
+    pub fn cancel_query(&self, token: CancelToken) {
+        let tls = self.cancel_tls.clone();
+        let id = self.id.clone();
+        tokio::spawn(async move {
+            let result = match tls {
+                CancelTls::NoTls => token.cancel_query(NoTls).await,
+                CancelTls::Rustls(connector) => token.cancel_query(connector).await,
+            };
+            if let Err(e) = result {
+                warn!("Unable to cancel a query in {id}: {e}");
+            }
+        });
+    }
+
+    #[must_use]
+    pub fn status(&self) -> PoolStatus {
+        let status = self.pool.status();
+        PoolStatus {
+            id: self.id.clone(),
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+        }
+    }
+
     #[must_use]
     pub fn get_id(&self) -> &str {
         self.id.as_str()
     }
 
+    /// Application name of a connection handling the request with the given ID.
+    /// The request ID comes first because Postgres truncates the names to 63 bytes.
+    #[must_use]
+    pub fn request_application_name(&self, request_id: &str) -> String {
+        format!("request={request_id} {}", self.application_name)
+    }
+
+    /// When true, the queries must not rely on prepared statements or on settings of the session,
+    /// because consecutive statements may run on different server connections.
+    /// Queries that only run once always use `query_typed`, which parses and executes them at once.
+    #[must_use]
+    pub fn transaction_pooling(&self) -> bool {
+        self.transaction_pooling
+    }
+
     #[must_use]
     pub fn supports_tile_margin(&self) -> bool {
         self.margin
@@ -21,7 +21,7 @@ pub async fn query_available_function(pool: &PgPool) -> PgResult<SqlFuncInfoMapM
 
     pool.get()
         .await?
-        .query(include_str!("scripts/query_available_function.sql"), &[])
+        .query_typed(include_str!("scripts/query_available_function.sql"), &[])
         .await
         .map_err(|e| PostgresError(e, "querying available functions"))?
         .into_iter()
@@ -12,11 +12,12 @@ use crate::args::{BoundsCalcType, DEFAULT_BOUNDS_TIMEOUT};
 use crate::pg::builder::SqlTableInfoMapMapMap;
 use crate::pg::config::PgInfo;
 use crate::pg::config_table::TableInfo;
-use crate::pg::pg_source::PgSqlInfo;
+use crate::pg::pg_source::{last_modified_query, version_query, PgSqlInfo};
 use crate::pg::pool::PgPool;
 use crate::pg::utils::{json_to_hashmap, polygon_to_bbox};
 use crate::pg::PgError::PostgresError;
 use crate::pg::PgResult;
+use crate::utils::UTFGRID_SIZE;
 
 static DEFAULT_EXTENT: u32 = 4096;
 static DEFAULT_BUFFER: u32 = 64;
@@ -26,7 +27,7 @@ static DEFAULT_CLIP_GEOM: bool = true;
 pub async fn query_available_tables(pool: &PgPool) -> PgResult<SqlTableInfoMapMapMap> {
     let conn = pool.get().await?;
     let rows = conn
-        .query(include_str!("scripts/query_available_tables.sql"), &[])
+        .query_typed(include_str!("scripts/query_available_tables.sql"), &[])
         .await
         .map_err(|e| PostgresError(e, "querying available tables"))?;
 
@@ -114,7 +115,8 @@ pub async fn table_to_query(
 
     if info.bounds.is_none() {
         match bounds_type {
-            BoundsCalcType::Skip => {}
+            // background bounds are computed once the source is published
+            BoundsCalcType::Skip | BoundsCalcType::Background => {}
             BoundsCalcType::Calc => {
                 debug!("Computing {} table bounds for {id}", info.format_id());
                 info.bounds = calc_bounds(&pool, &schema, &table, &geometry_column, srid).await?;
@@ -207,11 +209,86 @@ FROM (
     .trim()
     .to_string();
 
-    Ok((id, PgSqlInfo::new(query, false, info.format_id()), info))
+    let mut sql_info = PgSqlInfo::new(query, false, info.format_id());
+    sql_info.utfgrid_query = utfgrid_query(&info, &limit_clause);
+    sql_info.begin_query = info.settings.begin_query();
+    sql_info.version_query = info.version_sequence.as_deref().map(version_query);
+    sql_info.last_modified_query = info
+        .last_modified_column
+        .as_deref()
+        .map(|column| last_modified_query(&info.schema, &info.table, column));
+    Ok((id, sql_info, info))
+}
+
+/// Generate a query to get the key and the properties of the feature at the center of each `UTFGrid` cell.
+/// Points and lines match the cells within half a cell of them.
+fn utfgrid_query(info: &TableInfo, limit_clause: &str) -> Option<String> {
+    let column =
+        |field: &str| escape_identifier(info.prop_mapping.get(field).map_or(field, String::as_str));
+    let key = column(info.utfgrid_key.as_ref()?);
+    let data = match &info.properties {
+        Some(props) if !props.is_empty() => format!(
+            "jsonb_build_object({})",
+            props
+                .keys()
+                .map(|field| format!("{}, {}", escape_literal(field), column(field)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        _ => "'{}'::jsonb".to_string(),
+    };
+    let schema = escape_identifier(&info.schema);
+    let table = escape_identifier(&info.table);
+    let geometry_column = escape_identifier(&info.geometry_column);
+    let srid = info.srid;
+    let size = UTFGRID_SIZE;
+
+    Some(
+        format!(
+            r"
+WITH features AS (
+  SELECT
+    {key}::text AS key,
+    {data} AS data,
+    ST_Transform(ST_CurveToLine({geometry_column}), 3857) AS geom
+  FROM
+    {schema}.{table}
+  WHERE
+    {geometry_column} && ST_Transform(ST_TileEnvelope($1::integer, $2::integer, $3::integer), {srid})
+    AND {key} IS NOT NULL
+  {limit_clause}
+), cells AS (
+  SELECT
+    grid_row,
+    grid_col,
+    ST_SetSRID(ST_MakePoint(
+      ST_XMin(env) + (grid_col + 0.5) * (ST_XMax(env) - ST_XMin(env)) / {size},
+      ST_YMax(env) - (grid_row + 0.5) * (ST_YMax(env) - ST_YMin(env)) / {size}
+    ), 3857) AS center,
+    (ST_XMax(env) - ST_XMin(env)) / {size} / 2 AS tolerance
+  FROM
+    ST_TileEnvelope($1::integer, $2::integer, $3::integer) AS env,
+    generate_series(0, {size} - 1) AS grid_row,
+    generate_series(0, {size} - 1) AS grid_col
+)
+SELECT cells.grid_row, cells.grid_col, feature.key, feature.data
+FROM cells
+CROSS JOIN LATERAL (
+  SELECT key, data
+  FROM features
+  WHERE ST_DWithin(features.geom, cells.center, cells.tolerance)
+  ORDER BY ST_Distance(features.geom, cells.center)
+  LIMIT 1
+) AS feature;
+"
+        )
+        .trim()
+        .to_string(),
+    )
 }
 
 /// Compute the bounds of a table. This could be slow if the table is large or has no geo index.
-async fn calc_bounds(
+pub(crate) async fn calc_bounds(
     pool: &PgPool,
     schema: &str,
     table: &str,
@@ -220,7 +297,7 @@ async fn calc_bounds(
 ) -> PgResult<Option<Bounds>> {
     Ok(pool.get()
         .await?
-        .query_one(&format!(
+        .query_typed(&format!(
             r#"
 WITH real_bounds AS (SELECT ST_SetSRID(ST_Extent({geometry_column}), {srid}) AS rb FROM {schema}.{table})
 SELECT ST_Transform(
@@ -235,6 +312,7 @@ FROM {schema}.{table};
                 "#), &[])
         .await
         .map_err(|e| PostgresError(e, "querying table bounds"))?
-        .get::<_, Option<ewkb::Polygon>>("bounds")
+        .first()
+        .and_then(|row| row.get::<_, Option<ewkb::Polygon>>("bounds"))
         .and_then(|p| polygon_to_bbox(&p)))
 }
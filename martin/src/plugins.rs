@@ -0,0 +1,358 @@
+//! Tile post-processing with WebAssembly plugins.
+//!
+//! A plugin is a WASM module exporting its `memory`, and two functions:
+//! * `alloc(len: i32) -> i32` - allocate `len` bytes in the module memory, returning the pointer
+//! * `process_tile(id_ptr: i32, id_len: i32, z: i32, x: i32, y: i32, data_ptr: i32, data_len: i32) -> i64`
+//!   - receives the source ID and the tile data as returned by the source (possibly compressed),
+//!     and returns the pointer and the length of the resulting tile packed as `ptr << 32 | len`,
+//!     or `-1` to veto the tile, which is then served as an empty tile.
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use martin_tile_utils::TileInfo;
+use tilejson::TileJSON;
+use wasmi::{Config, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::plugins::PluginError::{InvalidPlugin, PluginFailed, PluginReadError};
+use crate::source::{CatalogSourceEntry, Source, TileData, UrlQuery};
+use crate::{MartinResult, TileCoord, UtfGrid};
+
+/// Maximum number of instructions a plugin may execute for a single tile,
+/// so that a buggy plugin cannot block the server forever.
+const FUEL_PER_TILE: u64 = 1_000_000_000;
+
+/// Maximum size of the memory of a plugin instance processing a single tile,
+/// so that a buggy plugin cannot exhaust the memory of the server.
+const MEMORY_PER_TILE: usize = 64 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PluginError {
+    #[error("Unable to read WASM plugin {}: {0}", .1.display())]
+    PluginReadError(std::io::Error, PathBuf),
+
+    #[error("Invalid WASM plugin {}: {0}", .1.display())]
+    InvalidPlugin(String, PathBuf),
+
+    #[error("WASM plugin {} failed to process tile {2:#} of source {1}: {0}", .3.display())]
+    PluginFailed(String, String, TileCoord, PathBuf),
+}
+
+/// A compiled WASM plugin. Each tile is processed by a new instance of the module,
+/// so plugins cannot keep state between tiles.
+pub struct WasmPlugin {
+    path: PathBuf,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    memory: usize,
+}
+
+impl Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path) -> Result<Self, PluginError> {
+        let wasm = std::fs::read(path).map_err(|e| PluginReadError(e, path.to_path_buf()))?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm.as_slice())
+            .map_err(|e| InvalidPlugin(e.to_string(), path.to_path_buf()))?;
+        let plugin = Self {
+            path: path.to_path_buf(),
+            engine,
+            module,
+            fuel: FUEL_PER_TILE,
+            memory: MEMORY_PER_TILE,
+        };
+        // make sure the module can be instantiated and has all the required exports
+        plugin
+            .instantiate()
+            .map_err(|e| InvalidPlugin(e, path.to_path_buf()))?;
+        Ok(plugin)
+    }
+
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, Instance), String> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.memory)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(self.fuel).map_err(|e| e.to_string())?;
+        let instance = Linker::<StoreLimits>::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|v| v.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        if instance.get_memory(&store, "memory").is_none() {
+            return Err("module does not export its memory".to_string());
+        }
+        instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|e| format!("invalid alloc function: {e}"))?;
+        instance
+            .get_typed_func::<(i32, i32, i32, i32, i32, i32, i32), i64>(&store, "process_tile")
+            .map_err(|e| format!("invalid process_tile function: {e}"))?;
+        Ok((store, instance))
+    }
+
+    /// Process a tile, returning `None` if the plugin vetoed it
+    pub fn process(
+        &self,
+        id: &str,
+        xyz: TileCoord,
+        data: &[u8],
+    ) -> Result<Option<TileData>, PluginError> {
+        self.call(id, xyz, data)
+            .map_err(|e| PluginFailed(e, id.to_string(), xyz, self.path.clone()))
+    }
+
+    fn call(&self, id: &str, xyz: TileCoord, data: &[u8]) -> Result<Option<TileData>, String> {
+        let (mut store, instance) = self.instantiate()?;
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|e| e.to_string())?;
+        let process = instance
+            .get_typed_func::<(i32, i32, i32, i32, i32, i32, i32), i64>(&store, "process_tile")
+            .map_err(|e| e.to_string())?;
+
+        let write = |store: &mut Store<StoreLimits>, bytes: &[u8]| -> Result<(i32, i32), String> {
+            let len = i32::try_from(bytes.len()).map_err(|e| e.to_string())?;
+            let ptr = alloc.call(&mut *store, len).map_err(|e| e.to_string())?;
+            memory
+                .write(&mut *store, to_offset(ptr)?, bytes)
+                .map_err(|e| e.to_string())?;
+            Ok((ptr, len))
+        };
+        let (id_ptr, id_len) = write(&mut store, id.as_bytes())?;
+        let (data_ptr, data_len) = write(&mut store, data)?;
+        let coord = |v: u32| i32::try_from(v).map_err(|e| e.to_string());
+        let result = process
+            .call(
+                &mut store,
+                (
+                    id_ptr,
+                    id_len,
+                    i32::from(xyz.z),
+                    coord(xyz.x)?,
+                    coord(xyz.y)?,
+                    data_ptr,
+                    data_len,
+                ),
+            )
+            .map_err(|e| e.to_string())?;
+        if result < 0 {
+            return Ok(None);
+        }
+        let ptr = usize::try_from(result >> 32).map_err(|e| e.to_string())?;
+        let len = usize::try_from(result & 0xFFFF_FFFF).map_err(|e| e.to_string())?;
+        let mut tile = vec![0; len];
+        memory
+            .read(&store, ptr, &mut tile)
+            .map_err(|e| e.to_string())?;
+        Ok(Some(tile.into()))
+    }
+}
+
+fn to_offset(ptr: i32) -> Result<usize, String> {
+    usize::try_from(ptr).map_err(|_| format!("invalid pointer {ptr}"))
+}
+
+/// A source wrapper that passes every tile through a chain of WASM plugins
+#[derive(Debug, Clone)]
+pub struct PluginSource {
+    source: Box<dyn Source>,
+    plugins: Arc<Vec<WasmPlugin>>,
+}
+
+impl PluginSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, plugins: Vec<WasmPlugin>) -> Self {
+        Self {
+            source,
+            plugins: Arc::new(plugins),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for PluginSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let data = self.source.get_tile(xyz, url_query).await?;
+        let id = self.get_id().to_string();
+        let plugins = self.plugins.clone();
+        // plugins may run for a while, so keep them off the async executor threads
+        let result = tokio::task::spawn_blocking(move || {
+            let mut data = data;
+            for plugin in plugins.iter() {
+                match plugin.process(&id, xyz, &data)? {
+                    Some(tile) => data = tile,
+                    None => return Ok(TileData::new()),
+                }
+            }
+            Ok::<_, PluginError>(data)
+        })
+        .await
+        .map_err(|e| crate::MartinError::InternalError(e.into()))??;
+        Ok(result)
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        self.source.get_last_modified().await
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        self.source.get_data_version().await
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        self.source.get_utfgrid(xyz).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plugin that reverses the tile data, and vetoes empty tiles and tiles of the `secret` source
+    const REVERSE_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 16))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "process_tile")
+            (param $id_ptr i32) (param $id_len i32) (param $z i32) (param $x i32) (param $y i32)
+            (param $ptr i32) (param $len i32) (result i64)
+            (local $out i32) (local $i i32)
+            (if (i32.eqz (local.get $len)) (then (return (i64.const -1))))
+            ;; "secret" source ID starts with 's'
+            (if (i32.eq (i32.load8_u (local.get $id_ptr)) (i32.const 115))
+              (then (return (i64.const -1))))
+            (local.set $out (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (block $done
+              (loop $copy
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (i32.store8
+                  (i32.add (local.get $out) (local.get $i))
+                  (i32.load8_u (i32.sub (i32.add (local.get $ptr) (local.get $len))
+                                        (i32.add (local.get $i) (i32.const 1)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $copy)))
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    fn load_wat(wat: &str) -> Result<WasmPlugin, PluginError> {
+        let dir = std::env::temp_dir().join(format!("martin-plugin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!(
+            "{:x}.wasm",
+            xxhash_rust::xxh3::xxh3_64(wat.as_bytes())
+        ));
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        WasmPlugin::load(&path)
+    }
+
+    #[test]
+    fn test_plugin() {
+        let plugin = load_wat(REVERSE_WAT).unwrap();
+        let xyz = TileCoord { z: 1, x: 2, y: 3 };
+        assert_eq!(
+            plugin.process("tiles", xyz, &[1, 2, 3]).unwrap(),
+            Some(TileData::from_static(&[3, 2, 1]))
+        );
+        assert_eq!(plugin.process("tiles", xyz, &[]).unwrap(), None);
+        assert_eq!(plugin.process("secret", xyz, &[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_plugin() {
+        let err = load_wat(r#"(module (memory (export "memory") 1))"#).unwrap_err();
+        assert!(matches!(err, InvalidPlugin(..)), "{err}");
+
+        let looping = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "process_tile")
+                (param i32 i32 i32 i32 i32 i32 i32) (result i64)
+                (loop $forever (br $forever))
+                (i64.const -1)))
+        "#;
+        let mut plugin = load_wat(looping).unwrap();
+        plugin.fuel = 10_000;
+        let err = plugin
+            .process("src", TileCoord { z: 0, x: 0, y: 0 }, &[1])
+            .unwrap_err();
+        assert!(matches!(err, PluginFailed(..)), "{err}");
+
+        // grows the memory by 16 pages of 64 KiB
+        let growing = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "process_tile")
+                (param i32 i32 i32 i32 i32 i32 i32) (result i64)
+                (drop (memory.grow (i32.const 16)))
+                (i64.const -1)))
+        "#;
+        let mut plugin = load_wat(growing).unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        assert_eq!(plugin.process("src", xyz, &[1]).unwrap(), None);
+        plugin.memory = 8 * 64 * 1024;
+        let err = plugin.process("src", xyz, &[1]).unwrap_err();
+        assert!(matches!(err, PluginFailed(..)), "{err}");
+    }
+}
@@ -5,6 +5,7 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use log::{trace, warn};
@@ -162,6 +163,7 @@ macro_rules! impl_pmtiles_source {
             pmtiles: Arc<AsyncPmTilesReader<$backend, PmtCache>>,
             tilejson: TileJSON,
             tile_info: TileInfo,
+            last_modified: Option<SystemTime>,
         }
 
         impl Debug for $name {
@@ -181,6 +183,7 @@ macro_rules! impl_pmtiles_source {
                 id: String,
                 path: $path,
                 reader: AsyncPmTilesReader<$backend, PmtCache>,
+                last_modified: Option<SystemTime>,
             ) -> FileResult<Self> {
                 let hdr = &reader.get_header();
 
@@ -232,6 +235,7 @@ macro_rules! impl_pmtiles_source {
                     pmtiles: Arc::new(reader),
                     tilejson,
                     tile_info: format,
+                    last_modified,
                 })
             }
         }
@@ -254,18 +258,25 @@ macro_rules! impl_pmtiles_source {
                 Box::new(self.clone())
             }
 
+            fn get_source_type(&self) -> &'static str {
+                "pmtiles"
+            }
+
+            async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+                Ok(self.last_modified)
+            }
+
             async fn get_tile(
                 &self,
                 xyz: TileCoord,
                 _url_query: Option<&UrlQuery>,
             ) -> MartinResult<TileData> {
-                // TODO: optimize to return Bytes
                 if let Some(t) = self
                     .pmtiles
                     .get_tile(xyz.z, u64::from(xyz.x), u64::from(xyz.y))
                     .await?
                 {
-                    Ok(t.to_vec())
+                    Ok(t)
                 } else {
                     trace!(
                         "Couldn't find tile data in {}/{}/{} of {}",
@@ -274,7 +285,7 @@ macro_rules! impl_pmtiles_source {
                         xyz.y,
                         &self.id
                     );
-                    Ok(Vec::new())
+                    Ok(TileData::new())
                 }
             }
         }
@@ -294,7 +305,7 @@ impl PmtHttpSource {
         let reader = AsyncPmTilesReader::new_with_cached_url(cache, client, url.clone()).await;
         let reader = reader.map_err(|e| FileError::PmtError(e, url.to_string()))?;
 
-        Self::new_int(id, url, reader).await
+        Self::new_int(id, url, reader, None).await
     }
 }
 
@@ -318,6 +329,7 @@ impl PmtFileSource {
             .map_err(|e| io::Error::other(format!("{e:?}: Cannot open file {}", path.display())))
             .map_err(|e| IoError(e, path.clone()))?;
 
-        Self::new_int(id, path, reader).await
+        let last_modified = path.metadata().and_then(|m| m.modified()).ok();
+        Self::new_int(id, path, reader, last_modified).await
     }
 }
@@ -0,0 +1,328 @@
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use image::imageops::{resize, FilterType};
+use image::RgbaImage;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, TileJSON};
+
+use crate::raster::RasterError::{DecodeError, EncodeError, InvalidComposite};
+use crate::raster::{blend, RasterFormat};
+use crate::source::{Source, TileData, TileSources, UrlQuery};
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd};
+use crate::{MartinError, MartinResult, TileCoord};
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RasterCompositeConfig {
+    /// Raster sources to blend, from the bottom to the top
+    pub layers: Vec<RasterLayerConfig>,
+    /// Format of the blended tiles [default: png]
+    pub format: Option<RasterFormat>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RasterLayerConfig {
+    pub source: String,
+    /// Opacity of the layer, from 0 to 1 [default: 1]
+    pub opacity: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+struct RasterLayer {
+    source: Box<dyn Source>,
+    opacity: f32,
+}
+
+/// A raster source that blends the tiles of other raster sources into a single image,
+/// so that clients do not need to request and draw several layers
+#[derive(Clone, Debug)]
+pub struct RasterCompositeSource {
+    id: String,
+    tilejson: TileJSON,
+    format: RasterFormat,
+    layers: Vec<RasterLayer>,
+}
+
+impl RasterCompositeSource {
+    pub fn new(
+        id: String,
+        cfg: &RasterCompositeConfig,
+        sources: &TileSources,
+    ) -> MartinResult<Self> {
+        let invalid = |msg: String| MartinError::from(InvalidComposite(id.clone(), msg));
+        if cfg.layers.is_empty() {
+            return Err(invalid("no layers to blend".to_string()));
+        }
+        let mut layers = Vec::with_capacity(cfg.layers.len());
+        for layer in &cfg.layers {
+            let source = sources
+                .get_source(&layer.source)
+                .map_err(|_| invalid(format!("source {} does not exist", layer.source)))?;
+            let format = source.get_tile_info().format;
+            if !matches!(
+                format,
+                Format::Png | Format::Jpeg | Format::Webp | Format::Gif
+            ) {
+                return Err(invalid(format!(
+                    "source {} has {format} tiles, but only raster sources can be blended",
+                    layer.source
+                )));
+            }
+            let opacity = layer.opacity.unwrap_or(1.0);
+            if !(0.0..=1.0).contains(&opacity) {
+                return Err(invalid(format!(
+                    "opacity of source {} must be between 0 and 1",
+                    layer.source
+                )));
+            }
+            layers.push(RasterLayer {
+                source: source.clone_source(),
+                opacity,
+            });
+        }
+
+        // a tile exists if any of the layers has it
+        let mut tilejson = tilejson! { tiles: vec![], name: id.clone() };
+        let tilejsons = layers.iter().map(|l| l.source.get_tilejson());
+        tilejson.minzoom = tilejsons.clone().filter_map(|tj| tj.minzoom).min();
+        tilejson.maxzoom = tilejsons.clone().filter_map(|tj| tj.maxzoom).max();
+        tilejson.bounds = tilejsons
+            .clone()
+            .map(|tj| tj.bounds)
+            .reduce(|a, b| a.zip(b).map(|(a, b)| a + b))
+            .flatten();
+        let attributions: Vec<_> = tilejsons.filter_map(|tj| tj.attribution.clone()).collect();
+        if !attributions.is_empty() {
+            tilejson.attribution = Some(attributions.join(" "));
+        }
+
+        Ok(Self {
+            id,
+            tilejson,
+            format: cfg.format.unwrap_or_default(),
+            layers,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for RasterCompositeSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::new(self.format.format(), Encoding::Internal)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.layers.iter().any(|l| l.source.support_url_query())
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "raster_composite"
+    }
+
+    /// Returns an empty tile if none of the layers has the tile
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let layers = self.layers.iter().filter(|l| l.source.is_valid_zoom(xyz.z));
+        let tiles = try_join_all(layers.map(|layer| async move {
+            let query = url_query.filter(|_| layer.source.support_url_query());
+            let data = layer.source.get_tile(xyz, query).await?;
+            MartinResult::Ok((layer, data))
+        }))
+        .await?;
+        let tiles: Vec<_> = tiles
+            .into_iter()
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(layer, data)| {
+                let id = layer.source.get_id().to_string();
+                (
+                    id,
+                    layer.source.get_tile_info().encoding,
+                    layer.opacity,
+                    data,
+                )
+            })
+            .collect();
+        if tiles.is_empty() {
+            return Ok(TileData::new());
+        }
+
+        let format = self.format;
+        let id = self.id.clone();
+        // decoding and blending images takes a while, so it must not block the server
+        tokio::task::spawn_blocking(move || {
+            let mut images = Vec::with_capacity(tiles.len());
+            for (src_id, encoding, opacity, data) in tiles {
+                let data = match encoding {
+                    Encoding::Gzip => decode_gzip(&data)?,
+                    Encoding::Brotli => decode_brotli(&data)?,
+                    Encoding::Zstd => decode_zstd(&data)?,
+                    _ => data.to_vec(),
+                };
+                let image = image::load_from_memory(&data)
+                    .map_err(|e| DecodeError(e, xyz, src_id))?
+                    .to_rgba8();
+                images.push((image, opacity));
+            }
+            let data = format
+                .encode(&composite(&images))
+                .map_err(|e| EncodeError(e, xyz, id))?;
+            MartinResult::Ok(TileData::from(data))
+        })
+        .await
+        .map_err(|e| MartinError::InternalError(e.into()))?
+    }
+}
+
+/// Blend the images from the bottom to the top. Smaller images are scaled to the size of the largest one,
+/// e.g. when blending 256px and 512px tiles.
+fn composite(images: &[(RgbaImage, f32)]) -> RgbaImage {
+    let width = images.iter().map(|(img, _)| img.width()).max().unwrap_or(0);
+    let height = images
+        .iter()
+        .map(|(img, _)| img.height())
+        .max()
+        .unwrap_or(0);
+    let mut result = RgbaImage::new(width, height);
+    for (image, opacity) in images {
+        if image.dimensions() == (width, height) {
+            blend(&mut result, image, *opacity);
+        } else {
+            let scaled = resize(image, width, height, FilterType::Triangle);
+            blend(&mut result, &scaled, *opacity);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    struct ImageSource {
+        id: &'static str,
+        tj: TileJSON,
+        data: TileData,
+    }
+
+    #[async_trait]
+    impl Source for ImageSource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Png, Encoding::Internal)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(
+            &self,
+            _xyz: TileCoord,
+            _url_query: Option<&UrlQuery>,
+        ) -> MartinResult<TileData> {
+            Ok(self.data.clone())
+        }
+    }
+
+    fn png(size: u32, color: [u8; 4]) -> TileData {
+        RasterFormat::Png
+            .encode(&RgbaImage::from_pixel(size, size, Rgba(color)))
+            .unwrap()
+            .into()
+    }
+
+    fn raster_source(id: &'static str, data: TileData) -> Box<dyn Source> {
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.attribution = Some(format!("© {id}"));
+        Box::new(ImageSource { id, tj, data })
+    }
+
+    #[actix_rt::test]
+    async fn test_composite_source() {
+        let mut sources = TileSources::default();
+        sources.add_source(raster_source("imagery", png(512, [0, 0, 255, 255])));
+        sources.add_source(raster_source("hillshade", png(256, [255, 0, 0, 255])));
+        sources.add_source(raster_source("empty", TileData::new()));
+        let cfg: RasterCompositeConfig = serde_yaml::from_str(
+            "layers: [{source: imagery}, {source: hillshade, opacity: 0.5}, {source: empty}]",
+        )
+        .unwrap();
+        let src = RasterCompositeSource::new("blended".to_string(), &cfg, &sources).unwrap();
+        assert_eq!(
+            src.get_tile_info(),
+            TileInfo::new(Format::Png, Encoding::Internal)
+        );
+        assert_eq!(
+            src.get_tilejson().attribution.as_deref(),
+            Some("© imagery © hillshade © empty")
+        );
+
+        let tile = src
+            .get_tile(TileCoord { z: 0, x: 0, y: 0 }, None)
+            .await
+            .unwrap();
+        let image = image::load_from_memory(&tile).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (512, 512));
+        assert_eq!(image.get_pixel(100, 100), &Rgba([128, 0, 128, 255]));
+
+        let cfg: RasterCompositeConfig = serde_yaml::from_str("layers: [{source: empty}]").unwrap();
+        let src = RasterCompositeSource::new("none".to_string(), &cfg, &sources).unwrap();
+        let tile = src
+            .get_tile(TileCoord { z: 0, x: 0, y: 0 }, None)
+            .await
+            .unwrap();
+        assert!(tile.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_composite() {
+        let mut sources = TileSources::default();
+        sources.add_source(raster_source("imagery", TileData::new()));
+        let new = |yaml: &str| {
+            let cfg: RasterCompositeConfig = serde_yaml::from_str(yaml).unwrap();
+            RasterCompositeSource::new("blended".to_string(), &cfg, &sources)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            new("layers: []"),
+            "Invalid raster composite blended: no layers to blend"
+        );
+        assert_eq!(
+            new("layers: [{source: missing}]"),
+            "Invalid raster composite blended: source missing does not exist"
+        );
+        assert_eq!(
+            new("layers: [{source: imagery, opacity: 2}]"),
+            "Invalid raster composite blended: opacity of source imagery must be between 0 and 1"
+        );
+    }
+}
@@ -0,0 +1,661 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use async_trait::async_trait;
+use futures::future::try_join_all;
+use image::RgbaImage;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, TileJSON, VectorLayer};
+
+use crate::raster::DemEncoding;
+use crate::raster::RasterError::{DecodeError, InvalidContours};
+use crate::source::{Source, TileData, TileSources, UrlQuery};
+use crate::utils::cache::{get_or_insert_tile, TileCachePolicy};
+use crate::utils::{
+    command, decode_brotli, decode_gzip, decode_zstd, write_bytes, write_key, write_packed,
+    write_uint, write_varint, zigzag, CacheKey, OptMainCache, WIRE_I64, WIRE_LEN,
+};
+use crate::{MartinError, MartinResult, TileCoord};
+
+const EXTENT: u32 = 4096;
+const CONTOUR_LAYER_DEFAULT: &str = "contours";
+const MAJOR_DEFAULT: u32 = 5;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContourConfig {
+    /// Raster source with elevations encoded as colors, e.g. a Mapbox Terrain-RGB source
+    pub source: String,
+    /// How the elevations are encoded in the colors of the source [default: mapbox]
+    pub encoding: Option<DemEncoding>,
+    /// Elevation difference between the lines, by the zoom level from which it applies,
+    /// e.g. `{10: 200, 12: 50, 14: 10}`. There are no lines below the lowest zoom level.
+    #[serde(default)]
+    pub intervals: BTreeMap<u8, f64>,
+    /// Every n-th line is a major line with `level: 1`, the others have `level: 0` [default: 5]
+    pub major: Option<u32>,
+    /// Name of the vector layer with the lines [default: contours]
+    pub layer: Option<String>,
+}
+
+/// A vector source with contour lines generated on the fly from the tiles of a raster source
+/// with elevations encoded as colors, so that no pre-built contours database is needed
+#[derive(Clone, Debug)]
+pub struct ContourSource {
+    id: String,
+    tilejson: TileJSON,
+    dem: Box<dyn Source>,
+    encoding: DemEncoding,
+    intervals: BTreeMap<u8, f64>,
+    major: u32,
+    layer: String,
+    cache: OptMainCache,
+}
+
+impl ContourSource {
+    pub fn new(
+        id: String,
+        cfg: &ContourConfig,
+        sources: &TileSources,
+        cache: OptMainCache,
+    ) -> MartinResult<Self> {
+        let invalid = |msg: String| MartinError::from(InvalidContours(id.clone(), msg));
+        let dem = sources
+            .get_source(&cfg.source)
+            .map_err(|_| invalid(format!("source {} does not exist", cfg.source)))?;
+        let format = dem.get_tile_info().format;
+        if !matches!(format, Format::Png | Format::Webp) {
+            return Err(invalid(format!(
+                "source {} has {format} tiles, but elevations must be stored in PNG or WebP tiles",
+                cfg.source
+            )));
+        }
+        let Some((&min_interval_zoom, _)) = cfg.intervals.first_key_value() else {
+            return Err(invalid("no contour intervals".to_string()));
+        };
+        if cfg.intervals.values().any(|v| !v.is_finite() || *v <= 0.0) {
+            return Err(invalid("contour intervals must be positive".to_string()));
+        }
+        let major = cfg.major.unwrap_or(MAJOR_DEFAULT);
+        if major == 0 {
+            return Err(invalid("major must be positive".to_string()));
+        }
+        let layer = cfg
+            .layer
+            .clone()
+            .unwrap_or_else(|| CONTOUR_LAYER_DEFAULT.to_string());
+
+        let dem_tj = dem.get_tilejson();
+        let mut tilejson = tilejson! { tiles: vec![], name: id.clone() };
+        tilejson.minzoom = Some(dem_tj.minzoom.unwrap_or(0).max(min_interval_zoom));
+        tilejson.maxzoom = dem_tj.maxzoom;
+        tilejson.bounds = dem_tj.bounds;
+        tilejson.attribution.clone_from(&dem_tj.attribution);
+        let fields = [("ele", "Number"), ("level", "Number")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        tilejson.vector_layers = Some(vec![VectorLayer::new(layer.clone(), fields)]);
+
+        Ok(Self {
+            id,
+            tilejson,
+            dem: dem.clone_source(),
+            encoding: cfg.encoding.unwrap_or_default(),
+            intervals: cfg.intervals.clone(),
+            major,
+            layer,
+            cache,
+        })
+    }
+
+    /// The interval of the highest zoom level of the config that is not above the given zoom
+    fn interval(&self, zoom: u8) -> Option<f64> {
+        self.intervals.range(..=zoom).next_back().map(|(_, v)| *v)
+    }
+
+    /// Elevation tiles are shared with requests of the raster source itself, and never expire,
+    /// because each of them is used by up to four contour tiles.
+    async fn get_dem_tile(&self, xyz: TileCoord) -> MartinResult<TileData> {
+        let tile = get_or_insert_tile(
+            self.cache.as_ref(),
+            TileCachePolicy::default(),
+            self.dem.get_tile(xyz, None),
+            || CacheKey::Tile(self.dem.get_id().to_string(), xyz),
+        )
+        .await?;
+        Ok(tile.data)
+    }
+}
+
+#[async_trait]
+impl Source for ContourSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "contours"
+    }
+
+    /// The right and bottom neighbours of the elevation tile are also used,
+    /// so that the lines of adjacent tiles meet at the tile edges.
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let Some(interval) = self.interval(xyz.z) else {
+            return Ok(TileData::new());
+        };
+        let last = (1_u32 << xyz.z) - 1;
+        // the tiles wrap around the antimeridian, but not around the poles
+        let right = if xyz.x == last { 0 } else { xyz.x + 1 };
+        let bottom = (xyz.y < last).then_some(xyz.y + 1);
+        let coords = [
+            Some(xyz),
+            Some(TileCoord { x: right, ..xyz }),
+            bottom.map(|y| TileCoord { y, ..xyz }),
+            bottom.map(|y| TileCoord { x: right, y, ..xyz }),
+        ];
+        let tiles = try_join_all(coords.map(|xyz| async move {
+            match xyz {
+                Some(xyz) => self.get_dem_tile(xyz).await.map(Some),
+                None => Ok(None),
+            }
+        }))
+        .await?;
+        if tiles[0].as_ref().map_or(true, TileData::is_empty) {
+            return Ok(TileData::new());
+        }
+
+        let src_id = self.dem.get_id().to_string();
+        let src_encoding = self.dem.get_tile_info().encoding;
+        let encoding = self.encoding;
+        let major = self.major;
+        let layer = self.layer.clone();
+        // decoding the elevations and tracing the lines takes a while, so it must not block the server
+        tokio::task::spawn_blocking(move || {
+            let mut images = Vec::with_capacity(tiles.len());
+            for (data, xyz) in tiles.into_iter().zip(coords) {
+                let (Some(data), Some(xyz)) = (data, xyz) else {
+                    images.push(None);
+                    continue;
+                };
+                if data.is_empty() {
+                    images.push(None);
+                    continue;
+                }
+                let data = match src_encoding {
+                    Encoding::Gzip => decode_gzip(&data)?,
+                    Encoding::Brotli => decode_brotli(&data)?,
+                    Encoding::Zstd => decode_zstd(&data)?,
+                    _ => data.to_vec(),
+                };
+                let image = image::load_from_memory(&data)
+                    .map_err(|e| DecodeError(e, xyz, src_id.clone()))?
+                    .to_rgba8();
+                images.push(Some(image));
+            }
+            let [Some(center), right, bottom, corner] = <[_; 4]>::try_from(images)
+                .unwrap_or_else(|_| unreachable!("four tiles were requested"))
+            else {
+                unreachable!("the center tile is not empty")
+            };
+            let (grid, width) = elevation_grid(
+                encoding,
+                &center,
+                [right.as_ref(), bottom.as_ref(), corner.as_ref()],
+            );
+            let contours = contours(&grid, width, interval, major);
+            MartinResult::Ok(TileData::from(contours_to_mvt(
+                &layer,
+                &contours,
+                width - 1,
+            )))
+        })
+        .await
+        .map_err(|e| MartinError::InternalError(e.into()))?
+    }
+}
+
+/// Elevations at the pixel centers of the tile, with an extra column and row from the first pixels
+/// of the right, bottom, and bottom-right neighbours. Missing neighbours, or ones of a different size,
+/// repeat the last column or row of the tile. Transparent pixels have no elevation (NaN).
+fn elevation_grid(
+    encoding: DemEncoding,
+    center: &RgbaImage,
+    [right, bottom, corner]: [Option<&RgbaImage>; 3],
+) -> (Vec<f64>, usize) {
+    let (w, h) = center.dimensions();
+    let mut grid = Vec::with_capacity((w as usize + 1) * (h as usize + 1));
+    for y in 0..=h {
+        for x in 0..=w {
+            let (image, px, py) = match (x == w, y == h) {
+                (false, false) => (Some(center), x, y),
+                (true, false) => (right, 0, y),
+                (false, true) => (bottom, x, 0),
+                (true, true) => (corner, 0, 0),
+            };
+            let pixel = match image.filter(|img| img.dimensions() == (w, h)) {
+                Some(image) => image.get_pixel(px, py),
+                None => center.get_pixel(x.min(w - 1), y.min(h - 1)),
+            };
+            grid.push(if pixel[3] == 0 {
+                f64::NAN
+            } else {
+                encoding.elevation(*pixel)
+            });
+        }
+    }
+    (grid, w as usize + 1)
+}
+
+/// A contour line at an elevation, with each line as grid coordinates
+#[derive(Debug)]
+struct Contour {
+    elevation: f64,
+    is_major: bool,
+    lines: Vec<Vec<[f64; 2]>>,
+}
+
+/// Contour lines of a grid of elevations at every multiple of the interval
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn contours(grid: &[f64], width: usize, interval: f64, major: u32) -> Vec<Contour> {
+    let values = grid.iter().filter(|v| !v.is_nan());
+    let min = values.clone().copied().fold(f64::INFINITY, f64::min);
+    let max = values.copied().fold(f64::NEG_INFINITY, f64::max);
+    if min > max {
+        return Vec::new();
+    }
+    let first = (min / interval).ceil() as i64;
+    let last = (max / interval).floor() as i64;
+    (first..=last)
+        .filter_map(|step| {
+            let elevation = step as f64 * interval;
+            let lines = isolines(grid, width, elevation);
+            (!lines.is_empty()).then(|| Contour {
+                elevation,
+                is_major: step % i64::from(major) == 0,
+                lines,
+            })
+        })
+        .collect()
+}
+
+/// Trace the lines where the elevations of the grid cross the level with marching squares,
+/// joining the segments of adjacent cells. Cells with a missing elevation are skipped.
+fn isolines(grid: &[f64], width: usize, level: f64) -> Vec<Vec<[f64; 2]>> {
+    let height = grid.len() / width;
+    // Line points are identified by the cell edge they are on:
+    // `2 * i` for the edge right of the grid point `i`, and `2 * i + 1` for the edge below it
+    let mut segments: Vec<[usize; 2]> = Vec::new();
+    for y in 0..height.saturating_sub(1) {
+        for x in 0..width - 1 {
+            let i = y * width + x;
+            // top-left, top-right, bottom-right, bottom-left
+            let corners = [grid[i], grid[i + 1], grid[i + width + 1], grid[i + width]];
+            if corners.iter().any(|v| v.is_nan()) {
+                continue;
+            }
+            let (top, right, bottom, left) = (2 * i, 2 * (i + 1) + 1, 2 * (i + width), 2 * i + 1);
+            let case = corners
+                .iter()
+                .fold(0, |case, v| (case << 1) | u8::from(*v >= level));
+            // saddles are resolved with the average of the corners
+            let center_above = corners.iter().sum::<f64>() / 4.0 >= level;
+            let pairs = match case {
+                1 | 14 => [Some([left, bottom]), None],
+                2 | 13 => [Some([bottom, right]), None],
+                3 | 12 => [Some([left, right]), None],
+                4 | 11 => [Some([top, right]), None],
+                6 | 9 => [Some([top, bottom]), None],
+                7 | 8 => [Some([left, top]), None],
+                5 if center_above => [Some([left, top]), Some([bottom, right])],
+                10 if !center_above => [Some([left, top]), Some([bottom, right])],
+                5 | 10 => [Some([top, right]), Some([left, bottom])],
+                _ => [None, None],
+            };
+            segments.extend(pairs.into_iter().flatten());
+        }
+    }
+
+    // each point is shared by at most two segments of adjacent cells
+    let mut by_point: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (idx, segment) in segments.iter().enumerate() {
+        for point in segment {
+            by_point.entry(*point).or_default().push(idx);
+        }
+    }
+    let mut used = vec![false; segments.len()];
+    let mut lines = Vec::new();
+    for start in 0..segments.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let mut line = VecDeque::from(segments[start]);
+        for forward in [true, false] {
+            loop {
+                let end = if forward { line.back() } else { line.front() };
+                let end = *end.expect("lines are never empty");
+                let Some(&next) = by_point[&end].iter().find(|idx| !used[**idx]) else {
+                    break;
+                };
+                used[next] = true;
+                let [a, b] = segments[next];
+                let other = if a == end { b } else { a };
+                if forward {
+                    line.push_back(other);
+                } else {
+                    line.push_front(other);
+                }
+            }
+        }
+        lines.push(
+            line.into_iter()
+                .map(|point| crossing(grid, width, level, point))
+                .collect(),
+        );
+    }
+    lines
+}
+
+/// The grid coordinates where the level crosses the cell edge, see [`isolines`]
+#[allow(clippy::cast_precision_loss)]
+fn crossing(grid: &[f64], width: usize, level: f64, edge: usize) -> [f64; 2] {
+    let start = edge / 2;
+    let (x, y) = ((start % width) as f64, (start / width) as f64);
+    let end = if edge % 2 == 0 {
+        start + 1
+    } else {
+        start + width
+    };
+    let ratio = (level - grid[start]) / (grid[end] - grid[start]);
+    if edge % 2 == 0 {
+        [x + ratio, y]
+    } else {
+        [x, y + ratio]
+    }
+}
+
+/// Encode the contours as a vector tile with a feature per elevation. Grid point `i` is at the center
+/// of pixel `i` of a tile with the given size, so the lines of adjacent tiles meet half a pixel
+/// beyond the tile edge.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn contours_to_mvt(layer_name: &str, contours: &[Contour], tile_size: usize) -> Vec<u8> {
+    const MOVE_TO: u32 = 1;
+    const LINE_TO: u32 = 2;
+    const LINESTRING: u64 = 2;
+    let scale = f64::from(EXTENT) / tile_size as f64;
+
+    let mut layer = Vec::new();
+    write_uint(&mut layer, 15, 2); // version
+    write_bytes(&mut layer, 1, layer_name.as_bytes());
+    let mut feature_id = 0;
+    for (idx, contour) in contours.iter().enumerate() {
+        let mut geometry = Vec::new();
+        let mut cursor = [0, 0];
+        for line in &contour.lines {
+            let mut points: Vec<[i32; 2]> = line
+                .iter()
+                .map(|p| p.map(|v| ((v + 0.5) * scale).round() as i32))
+                .collect();
+            points.dedup();
+            remove_collinear(&mut points);
+            if points.len() < 2 {
+                continue;
+            }
+            for (i, point) in points.iter().enumerate() {
+                if i == 0 {
+                    geometry.push(command(MOVE_TO, 1));
+                } else if i == 1 {
+                    geometry.push(command(LINE_TO, (points.len() - 1) as u32));
+                }
+                geometry.push(zigzag(point[0] - cursor[0]));
+                geometry.push(zigzag(point[1] - cursor[1]));
+                cursor = *point;
+            }
+        }
+        if geometry.is_empty() {
+            continue;
+        }
+        feature_id += 1;
+        let mut feature = Vec::new();
+        write_uint(&mut feature, 1, feature_id);
+        // values 0 and 1 are the levels, followed by the elevations
+        let level = u32::from(contour.is_major);
+        let elevation = idx as u32 + 2;
+        write_packed(&mut feature, 2, &[0, elevation, 1, level]);
+        write_uint(&mut feature, 3, LINESTRING);
+        write_packed(&mut feature, 4, &geometry);
+        write_bytes(&mut layer, 2, &feature);
+    }
+    if feature_id == 0 {
+        return Vec::new();
+    }
+    for key in ["ele", "level"] {
+        write_bytes(&mut layer, 3, key.as_bytes());
+    }
+    for level in [0, 1] {
+        let mut value = Vec::new();
+        write_uint(&mut value, 5, level); // uint_value
+        write_bytes(&mut layer, 4, &value);
+    }
+    for contour in contours {
+        let mut value = Vec::new();
+        if contour.elevation.fract() == 0.0 {
+            write_uint(&mut value, 6, zigzag_i64(contour.elevation as i64)); // sint_value
+        } else {
+            write_key(&mut value, 3, WIRE_I64); // double_value
+            value.extend_from_slice(&contour.elevation.to_le_bytes());
+        }
+        write_bytes(&mut layer, 4, &value);
+    }
+    write_uint(&mut layer, 5, EXTENT.into());
+
+    let mut tile = Vec::new();
+    write_key(&mut tile, 3, WIRE_LEN);
+    write_varint(&mut tile, layer.len() as u64);
+    tile.extend_from_slice(&layer);
+    tile
+}
+
+/// Remove the points in the middle of a straight segment, keeping the first and the last point
+fn remove_collinear(points: &mut Vec<[i32; 2]>) {
+    let mut result: Vec<[i32; 2]> = Vec::with_capacity(points.len());
+    for point in points.iter() {
+        if let [.., a, b] = result[..] {
+            let (ab, bp) = (
+                [b[0] - a[0], b[1] - a[1]],
+                [point[0] - b[0], point[1] - b[1]],
+            );
+            let cross = i64::from(ab[0]) * i64::from(bp[1]) - i64::from(ab[1]) * i64::from(bp[0]);
+            let dot = i64::from(ab[0]) * i64::from(bp[0]) + i64::from(ab[1]) * i64::from(bp[1]);
+            if cross == 0 && dot > 0 {
+                result.pop();
+            }
+        }
+        result.push(*point);
+    }
+    *points = result;
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn zigzag_i64(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::{decode_mvt, GeomType};
+
+    #[derive(Clone, Debug)]
+    struct DemSource {
+        tj: TileJSON,
+        format: Format,
+    }
+
+    #[async_trait]
+    impl Source for DemSource {
+        fn get_id(&self) -> &'static str {
+            "dem"
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(self.format, Encoding::Internal)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        /// Terrarium elevations rising by one meter per pixel to the east, from 0 at the antimeridian
+        async fn get_tile(
+            &self,
+            xyz: TileCoord,
+            _url_query: Option<&UrlQuery>,
+        ) -> MartinResult<TileData> {
+            let image = RgbaImage::from_fn(16, 16, |x, _| {
+                let [hi, lo] = u16::try_from(xyz.x * 16 + x + 32768).unwrap().to_be_bytes();
+                Rgba([hi, lo, 0, 255])
+            });
+            Ok(crate::raster::RasterFormat::Png
+                .encode(&image)
+                .unwrap()
+                .into())
+        }
+    }
+
+    fn sources(format: Format) -> TileSources {
+        let mut tj = tilejson! { tiles: vec![] };
+        tj.minzoom = Some(1);
+        tj.maxzoom = Some(10);
+        let mut sources = TileSources::default();
+        sources.add_source(Box::new(DemSource { tj, format }));
+        sources
+    }
+
+    #[test]
+    fn test_isolines() {
+        // a peak in the middle of a 4x4 grid is surrounded by a closed line
+        #[rustfmt::skip]
+        let grid = [
+            0.0, 0.0, 0.0, 0.0,
+            0.0, 4.0, 4.0, 0.0,
+            0.0, 4.0, 4.0, 0.0,
+            0.0, 0.0, 0.0, 0.0,
+        ];
+        let lines = isolines(&grid, 4, 2.0);
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!(line.len(), 9);
+        assert_eq!(line.first(), line.last());
+        for point in line {
+            let distance = point.map(|v| (v - 1.5).abs());
+            assert!(
+                (distance[0].max(distance[1]) - 1.0).abs() < 1e-9,
+                "{point:?}"
+            );
+        }
+
+        // a slope is crossed by an open line, and cells without elevations are skipped
+        let grid = [0.0, 1.0, 2.0, 0.0, 1.0, 2.0, 0.0, 1.0, f64::NAN];
+        assert_eq!(isolines(&grid, 3, 1.5), vec![vec![[1.5, 0.0], [1.5, 1.0]]]);
+        assert!(isolines(&grid, 3, 5.0).is_empty());
+
+        let contours = contours(&grid, 3, 0.5, 2);
+        let levels: Vec<_> = contours
+            .iter()
+            .map(|c| (c.elevation.to_string(), c.is_major))
+            .collect();
+        let expected = [("0.5", false), ("1", true), ("1.5", false), ("2", true)];
+        assert_eq!(levels, expected.map(|(e, m)| (e.to_string(), m)));
+    }
+
+    #[actix_rt::test]
+    async fn test_contour_source() {
+        let sources = sources(Format::Png);
+        let cfg: ContourConfig =
+            serde_yaml::from_str("{source: dem, encoding: terrarium, intervals: {3: 8, 5: 2}}")
+                .unwrap();
+        let src = ContourSource::new("contours".to_string(), &cfg, &sources, None).unwrap();
+        let tj = src.get_tilejson();
+        assert_eq!((tj.minzoom, tj.maxzoom), (Some(3), Some(10)));
+        assert_eq!(src.interval(4), Some(8.0));
+        assert_eq!(src.interval(12), Some(2.0));
+        assert!(src
+            .get_tile(TileCoord { z: 2, x: 0, y: 0 }, None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // elevations 16 to 32 with the right neighbour, the line at 16 belongs to the tile on the left
+        let tile = src
+            .get_tile(TileCoord { z: 3, x: 1, y: 2 }, None)
+            .await
+            .unwrap();
+        let layers = decode_mvt(&tile).unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].name, "contours");
+        let features = &layers[0].features;
+        let props: Vec<_> = features.iter().map(|f| json!(f.properties)).collect();
+        assert_eq!(
+            props,
+            vec![
+                json!({"ele": 24, "level": 0}),
+                json!({"ele": 32, "level": 0}),
+            ]
+        );
+        // straight lines at the pixel centers, the last one half a pixel beyond the tile edge
+        assert_eq!(features[0].geom_type, GeomType::LineString);
+        assert_eq!(features[0].geometry, vec![vec![[2176, 128], [2176, 4224]]]);
+        assert_eq!(features[1].geometry, vec![vec![[4224, 128], [4224, 4224]]]);
+    }
+
+    #[test]
+    fn test_invalid_contours() {
+        let new = |format: Format, yaml: &str| {
+            let cfg: ContourConfig = serde_yaml::from_str(yaml).unwrap();
+            ContourSource::new("contours".to_string(), &cfg, &sources(format), None)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            new(Format::Png, "{source: missing, intervals: {0: 10}}"),
+            "Invalid contour source contours: source missing does not exist"
+        );
+        assert_eq!(
+            new(Format::Jpeg, "{source: dem, intervals: {0: 10}}"),
+            "Invalid contour source contours: source dem has jpeg tiles, but elevations must be stored in PNG or WebP tiles"
+        );
+        assert_eq!(
+            new(Format::Png, "{source: dem}"),
+            "Invalid contour source contours: no contour intervals"
+        );
+        assert_eq!(
+            new(Format::Png, "{source: dem, intervals: {0: -1}}"),
+            "Invalid contour source contours: contour intervals must be positive"
+        );
+    }
+}
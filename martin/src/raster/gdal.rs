@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use gdal::errors::GdalError;
+use gdal::raster::GdalDataType;
+use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef};
+use gdal::{Dataset, DriverManager};
+use image::{Rgba, RgbaImage};
+use martin_tile_utils::{wgs84_to_webmercator, Encoding, TileInfo, EARTH_CIRCUMFERENCE, MAX_ZOOM};
+use serde::{Deserialize, Serialize};
+use tilejson::{tilejson, Bounds, TileJSON};
+use url::Url;
+
+use crate::config::UnrecognizedValues;
+use crate::file_config::FileError::{
+    AcquireConnError, GdalError as GdalFileError, InvalidGdalBands,
+};
+use crate::file_config::{ConfigExtras, FileResult, SourceConfigExtras};
+use crate::raster::RasterError::EncodeError;
+use crate::raster::{RasterFormat, RENDER_TILE_SIZE_DEFAULT};
+use crate::source::{TileData, UrlQuery};
+use crate::{MartinError, MartinResult, Source, TileCoord};
+
+/// Number of points per edge of the file's extent used to compute its bounds in WGS84,
+/// so that curved edges of reprojected files are not cut off
+const EDGE_POINTS: usize = 21;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GdalConfig {
+    /// Format of the generated tiles [default: png]
+    pub format: Option<RasterFormat>,
+    /// Size of the generated tiles in pixels [default: 256]
+    pub tile_size: Option<u32>,
+    /// Bands and value ranges of individual files, keyed by source ID
+    pub source_options: Option<BTreeMap<String, GdalSourceOptions>>,
+    #[serde(flatten)]
+    pub unrecognized: UnrecognizedValues,
+}
+
+/// How the values of a single file are drawn, overriding the defaults of the `gdal` section
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GdalSourceOptions {
+    /// Bands to draw, starting from 1. One band is drawn as grayscale, three as RGB, and four as RGBA.
+    /// [default: `[1, 2, 3]` if the file has three or more bands, `[1]` otherwise]
+    pub bands: Option<Vec<usize>>,
+    /// Values mapped to the darkest and the brightest color, e.g. `[-40, 40]` for temperatures.
+    /// [default: `[0, 255]` for 8-bit bands, the minimum and maximum of each band otherwise]
+    pub rescale: Option<[f64; 2]>,
+    pub minzoom: Option<u8>,
+    /// [default: the zoom level matching the resolution of the file]
+    pub maxzoom: Option<u8>,
+}
+
+impl ConfigExtras for GdalConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn get_unrecognized(&self) -> &UnrecognizedValues {
+        &self.unrecognized
+    }
+}
+
+impl SourceConfigExtras for GdalConfig {
+    async fn new_sources(&self, id: String, path: PathBuf) -> FileResult<Box<dyn Source>> {
+        let options = self
+            .source_options
+            .as_ref()
+            .and_then(|v| v.get(&id))
+            .cloned()
+            .unwrap_or_default();
+        let format = self.format.unwrap_or_default();
+        let tile_size = self.tile_size.unwrap_or(RENDER_TILE_SIZE_DEFAULT);
+        // opening a file may scan all of its values for their range
+        let source = tokio::task::spawn_blocking(move || {
+            GdalSource::new(id, path, &options, format, tile_size)
+        })
+        .await
+        .map_err(|e| AcquireConnError(e.to_string()))??;
+        Ok(Box::new(source))
+    }
+
+    // TODO: Remove #[allow] after switching to Rust/Clippy v1.78+ in CI
+    //       See https://github.com/rust-lang/rust-clippy/pull/12323
+    #[allow(clippy::no_effect_underscore_binding)]
+    async fn new_sources_url(&self, _id: String, _url: Url) -> FileResult<Box<dyn Source>> {
+        unreachable!()
+    }
+}
+
+/// A raster source reading any file supported by GDAL, e.g. `NetCDF`, GRIB, or JPEG 2000,
+/// and warping it to Web Mercator when a tile is requested
+#[derive(Clone, Debug)]
+pub struct GdalSource {
+    id: String,
+    path: PathBuf,
+    /// GDAL connection string of the file with only the drawn bands
+    dataset: String,
+    /// Range of the values of each drawn band
+    rescale: Vec<[f64; 2]>,
+    format: RasterFormat,
+    tile_size: u32,
+    tilejson: TileJSON,
+}
+
+impl GdalSource {
+    fn new(
+        id: String,
+        path: PathBuf,
+        options: &GdalSourceOptions,
+        format: RasterFormat,
+        tile_size: u32,
+    ) -> FileResult<Self> {
+        let gdal_err = |e: GdalError| GdalFileError(e, path.clone());
+        let dataset = Dataset::open(&path).map_err(gdal_err)?;
+
+        let count = dataset.raster_count();
+        let bands =
+            options
+                .bands
+                .clone()
+                .unwrap_or_else(|| if count >= 3 { vec![1, 2, 3] } else { vec![1] });
+        if !matches!(bands.len(), 1 | 3 | 4) {
+            return Err(InvalidGdalBands(
+                id,
+                "one, three, or four bands must be drawn".to_string(),
+            ));
+        }
+        if let Some(band) = bands.iter().find(|&&b| b == 0 || b > count) {
+            return Err(InvalidGdalBands(
+                id,
+                format!("band {band} does not exist, the file has {count} bands"),
+            ));
+        }
+
+        let mut rescale = Vec::with_capacity(bands.len());
+        for &band in &bands {
+            let band = dataset.rasterband(band).map_err(gdal_err)?;
+            rescale.push(if let Some(range) = options.rescale {
+                range
+            } else if band.band_type() == GdalDataType::UInt8 {
+                [0.0, 255.0]
+            } else {
+                let stats = band.compute_raster_min_max(true).map_err(gdal_err)?;
+                [stats.min, stats.max]
+            });
+        }
+
+        let bounds = wgs84_bounds(&dataset).map_err(gdal_err)?;
+        let mut tilejson = tilejson! { tiles: vec![], name: id.clone() };
+        tilejson.bounds = Some(bounds);
+        tilejson.minzoom = Some(options.minzoom.unwrap_or(0));
+        tilejson.maxzoom = Some(
+            options
+                .maxzoom
+                .unwrap_or_else(|| native_zoom(bounds, dataset.raster_size().0, tile_size)),
+        );
+
+        let bands: Vec<_> = bands.iter().map(ToString::to_string).collect();
+        Ok(Self {
+            dataset: format!("vrt://{}?bands={}", path.display(), bands.join(",")),
+            id,
+            path,
+            rescale,
+            format,
+            tile_size,
+            tilejson,
+        })
+    }
+
+    /// Warp the area of the tile to Web Mercator, and return the values of each drawn band,
+    /// with `NaN` where the file has no data
+    fn warp(&self, xyz: TileCoord) -> Result<Vec<Vec<f64>>, GdalError> {
+        let src = Dataset::open(&self.dataset)?;
+        let size = self.tile_size as usize;
+        let mut dst = DriverManager::get_driver_by_name("MEM")?.create_with_band_type::<f64, _>(
+            "",
+            size,
+            size,
+            self.rescale.len(),
+        )?;
+        dst.set_spatial_ref(&SpatialRef::from_epsg(3857)?)?;
+        let tile = EARTH_CIRCUMFERENCE / f64::from(1_u32 << xyz.z);
+        let resolution = tile / f64::from(self.tile_size);
+        dst.set_geo_transform(&[
+            -EARTH_CIRCUMFERENCE / 2.0 + f64::from(xyz.x) * tile,
+            resolution,
+            0.0,
+            EARTH_CIRCUMFERENCE / 2.0 - f64::from(xyz.y) * tile,
+            0.0,
+            -resolution,
+        ])?;
+        // pixels outside of the file keep their initial value
+        for band in 1..=self.rescale.len() {
+            let mut band = dst.rasterband(band)?;
+            band.set_no_data_value(Some(f64::NAN))?;
+            band.fill(f64::NAN, None)?;
+        }
+        gdal::raster::reproject(&src, &dst)?;
+
+        (1..=self.rescale.len())
+            .map(|band| {
+                let buffer = dst.rasterband(band)?.read_as::<f64>(
+                    (0, 0),
+                    (size, size),
+                    (size, size),
+                    None,
+                )?;
+                Ok(buffer.into_shape_and_vec().1)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Source for GdalSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        TileInfo::new(self.format.format(), Encoding::Internal)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "gdal"
+    }
+
+    /// Returns an empty tile if the file has no data in the tile
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let source = self.clone();
+        // reading and warping the file takes a while, so it must not block the server
+        tokio::task::spawn_blocking(move || {
+            let bands = source
+                .warp(xyz)
+                .map_err(|e| GdalFileError(e, source.path.clone()))?;
+            let Some(image) = to_image(&bands, &source.rescale, source.tile_size) else {
+                return Ok(TileData::new());
+            };
+            let data = source
+                .format
+                .encode(&image)
+                .map_err(|e| EncodeError(e, xyz, source.id.clone()))?;
+            MartinResult::Ok(TileData::from(data))
+        })
+        .await
+        .map_err(|e| MartinError::InternalError(e.into()))?
+    }
+}
+
+/// The extent of the file in WGS84, densifying its edges because they may be curved
+fn wgs84_bounds(dataset: &Dataset) -> Result<Bounds, GdalError> {
+    let [x0, dx, rx, y0, ry, dy] = dataset.geo_transform()?;
+    let (width, height) = dataset.raster_size();
+    #[allow(clippy::cast_precision_loss)]
+    let (width, height) = (width as f64, height as f64);
+    let (mut xs, mut ys) = (Vec::new(), Vec::new());
+    #[allow(clippy::cast_precision_loss)]
+    for i in 0..EDGE_POINTS {
+        let t = i as f64 / (EDGE_POINTS - 1) as f64;
+        for (col, row) in [
+            (t * width, 0.0),
+            (t * width, height),
+            (0.0, t * height),
+            (width, t * height),
+        ] {
+            xs.push(x0 + col * dx + row * rx);
+            ys.push(y0 + col * ry + row * dy);
+        }
+    }
+
+    let mut src = dataset.spatial_ref()?;
+    src.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    let mut dst = SpatialRef::from_epsg(4326)?;
+    dst.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+    CoordTransform::new(&src, &dst)?.transform_coords(&mut xs, &mut ys, &mut [])?;
+
+    let min = |v: &[f64]| v.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = |v: &[f64]| v.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    Ok(Bounds::new(
+        min(&xs).max(-180.0),
+        min(&ys).max(-90.0),
+        max(&xs).min(180.0),
+        max(&ys).min(90.0),
+    ))
+}
+
+/// The lowest zoom level at which the tiles have at least the resolution of the file
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn native_zoom(bounds: Bounds, width: usize, tile_size: u32) -> u8 {
+    let (left, _) = wgs84_to_webmercator(bounds.left, 0.0);
+    let (right, _) = wgs84_to_webmercator(bounds.right, 0.0);
+    #[allow(clippy::cast_precision_loss)]
+    let resolution = (right - left) / width.max(1) as f64;
+    let zoom = (EARTH_CIRCUMFERENCE / (f64::from(tile_size) * resolution))
+        .log2()
+        .ceil();
+    zoom.clamp(0.0, f64::from(MAX_ZOOM)) as u8
+}
+
+/// Draw the values of one (grayscale), three (RGB), or four (RGBA) bands, scaling each band
+/// from its range to 0..255. Pixels where any band has no data are transparent.
+/// Returns `None` if all pixels are transparent.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_image(bands: &[Vec<f64>], rescale: &[[f64; 2]], size: u32) -> Option<RgbaImage> {
+    let mut image = RgbaImage::new(size, size);
+    let mut is_empty = true;
+    for (i, pixel) in image.pixels_mut().enumerate() {
+        let values: Option<Vec<u8>> = bands
+            .iter()
+            .zip(rescale)
+            .map(|(band, [min, max])| {
+                let value = band.get(i).copied().filter(|v| !v.is_nan())?;
+                let range = (max - min).max(f64::EPSILON);
+                Some(((value - min) / range * 255.0).round().clamp(0.0, 255.0) as u8)
+            })
+            .collect();
+        *pixel = match values.as_deref() {
+            Some(&[gray]) => Rgba([gray, gray, gray, 255]),
+            Some(&[red, green, blue]) => Rgba([red, green, blue, 255]),
+            Some(&[red, green, blue, alpha]) => Rgba([red, green, blue, alpha]),
+            _ => continue,
+        };
+        is_empty = false;
+    }
+    (!is_empty).then_some(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let cfg: GdalConfig = serde_yaml::from_str(indoc! {"
+            format: webp
+            source_options:
+              temperature:
+                bands: [1]
+                rescale: [-40, 40]
+                maxzoom: 6
+        "})
+        .unwrap();
+        assert_eq!(cfg.format, Some(RasterFormat::Webp));
+        assert!(cfg.unrecognized.is_empty());
+        let options = &cfg.source_options.unwrap()["temperature"];
+        assert_eq!(options.bands, Some(vec![1]));
+        assert_eq!(options.rescale, Some([-40.0, 40.0]));
+        assert_eq!(options.maxzoom, Some(6));
+    }
+
+    #[test]
+    fn test_native_zoom() {
+        // a global file of 1 degree cells
+        let bounds = Bounds::new(-180.0, -90.0, 180.0, 90.0);
+        assert_eq!(native_zoom(bounds, 360, 256), 1);
+        assert_eq!(native_zoom(bounds, 4096, 256), 4);
+        assert_eq!(native_zoom(bounds, 4096, 512), 3);
+    }
+
+    #[test]
+    fn test_to_image() {
+        let gray = vec![vec![-40.0, 0.0, 40.0, f64::NAN]];
+        let image = to_image(&gray, &[[-40.0, 40.0]], 2).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([128, 128, 128, 255]));
+        assert_eq!(image.get_pixel(0, 1), &Rgba([255, 255, 255, 255]));
+        assert_eq!(image.get_pixel(1, 1), &Rgba([0, 0, 0, 0]));
+
+        let rgb = vec![vec![255.0; 4], vec![0.0; 4], vec![f64::NAN, 0.0, 0.0, 0.0]];
+        let image = to_image(&rgb, &[[0.0, 255.0]; 3], 2).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(1, 0), &Rgba([255, 0, 0, 255]));
+
+        assert!(to_image(&[vec![f64::NAN; 4]], &[[0.0, 1.0]], 2).is_none());
+    }
+}
@@ -0,0 +1,178 @@
+//! Raster tiles derived from other sources, e.g. imagery blended with a hillshade,
+//! or vector tiles rendered with a style. Requires the `raster` feature.
+
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use image::{DynamicImage, ImageFormat, ImageResult, RgbaImage};
+use martin_tile_utils::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::TileCoord;
+
+mod composite;
+pub use composite::{RasterCompositeConfig, RasterCompositeSource, RasterLayerConfig};
+
+mod contour;
+pub use contour::{ContourConfig, ContourSource};
+
+#[cfg(feature = "gdal")]
+mod gdal;
+#[cfg(feature = "gdal")]
+pub use self::gdal::{GdalConfig, GdalSource, GdalSourceOptions};
+
+mod render;
+pub use render::{
+    render_tile, unsupported_style_features, RenderConfig, MAX_IMAGE_SIZE_DEFAULT,
+    RENDER_TILE_SIZE_DEFAULT,
+};
+
+mod static_map;
+pub use static_map::{StaticMap, STATIC_MAP_TILE_SIZE};
+
+mod terrain;
+pub use terrain::{
+    layer_json, quantized_mesh, DemEncoding, TerrainConfig, TERRAIN_GRID_SIZE_DEFAULT,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RasterError {
+    #[error("Invalid raster composite {0}: {1}")]
+    InvalidComposite(String, String),
+
+    #[error("Invalid contour source {0}: {1}")]
+    InvalidContours(String, String),
+
+    #[error("Unable to decode tile {1} of source {2}: {0}")]
+    DecodeError(#[source] image::ImageError, TileCoord, String),
+
+    #[error("Unable to encode tile {1} of source {2}: {0}")]
+    EncodeError(#[source] image::ImageError, TileCoord, String),
+
+    #[error("Unable to read style {1}: {0}")]
+    StyleReadError(#[source] std::io::Error, PathBuf),
+
+    #[error("Invalid style {1}: {0}")]
+    InvalidStyle(#[source] serde_json::Error, PathBuf),
+
+    #[error("Unable to render tile {1}: {0}")]
+    RenderError(String, TileCoord),
+}
+
+/// Image format of the generated raster tiles
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RasterFormat {
+    #[default]
+    Png,
+    /// Transparent areas are drawn white, because JPEG has no alpha channel
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+}
+
+impl RasterFormat {
+    #[must_use]
+    pub fn format(self) -> Format {
+        match self {
+            Self::Png => Format::Png,
+            Self::Jpeg => Format::Jpeg,
+            Self::Webp => Format::Webp,
+        }
+    }
+
+    /// Encode the image in this format
+    pub fn encode(self, image: &RgbaImage) -> ImageResult<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut writer = Cursor::new(&mut data);
+        match self {
+            Self::Png => image.write_to(&mut writer, ImageFormat::Png)?,
+            Self::Webp => image.write_to(&mut writer, ImageFormat::WebP)?,
+            Self::Jpeg => {
+                let mut background = RgbaImage::from_pixel(
+                    image.width(),
+                    image.height(),
+                    image::Rgba([255, 255, 255, 255]),
+                );
+                blend(&mut background, image, 1.0);
+                DynamicImage::ImageRgba8(background)
+                    .to_rgb8()
+                    .write_to(&mut writer, ImageFormat::Jpeg)?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Draw `top` over `bottom` with the given opacity, using the "source over" operator.
+/// Both images must have the same size.
+pub fn blend(bottom: &mut RgbaImage, top: &RgbaImage, opacity: f32) {
+    for (dst, src) in bottom.pixels_mut().zip(top.pixels()) {
+        let src_alpha = f32::from(src[3]) / 255.0 * opacity;
+        if src_alpha <= 0.0 {
+            continue;
+        }
+        let dst_alpha = f32::from(dst[3]) / 255.0;
+        let alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+        for i in 0..3 {
+            let color = (f32::from(src[i]) * src_alpha
+                + f32::from(dst[i]) * dst_alpha * (1.0 - src_alpha))
+                / alpha;
+            dst[i] = to_u8(color);
+        }
+        dst[3] = to_u8(alpha * 255.0);
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_u8(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use martin_tile_utils::TileInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_blend() {
+        let mut bottom = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255]));
+        blend(
+            &mut bottom,
+            &RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 255])),
+            0.5,
+        );
+        assert_eq!(bottom.get_pixel(0, 0), &Rgba([128, 0, 128, 255]));
+
+        // transparent pixels do not change the image
+        blend(
+            &mut bottom,
+            &RgbaImage::from_pixel(1, 1, Rgba([0, 255, 0, 0])),
+            1.0,
+        );
+        assert_eq!(bottom.get_pixel(0, 0), &Rgba([128, 0, 128, 255]));
+
+        let mut bottom = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
+        blend(
+            &mut bottom,
+            &RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 255])),
+            0.5,
+        );
+        assert_eq!(bottom.get_pixel(0, 0), &Rgba([255, 0, 0, 128]));
+    }
+
+    #[test]
+    fn test_encode() {
+        let image = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 0]));
+        for format in [RasterFormat::Png, RasterFormat::Jpeg, RasterFormat::Webp] {
+            let data = format.encode(&image).unwrap();
+            assert_eq!(TileInfo::detect(&data).unwrap().format, format.format());
+        }
+        let jpeg = RasterFormat::Jpeg.encode(&image).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().to_rgba8();
+        // transparent pixels become white
+        assert!(decoded.get_pixel(0, 0)[0] > 250);
+    }
+}
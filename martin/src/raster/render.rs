@@ -0,0 +1,731 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tiny_skia::{
+    Color, FillRule, LineCap, LineJoin, Paint, PathBuilder, Pixmap, Rect, Stroke, StrokeDash,
+    Transform,
+};
+
+use crate::raster::RasterError::{InvalidStyle, RenderError, StyleReadError};
+use crate::utils::{decode_mvt, GeomType, MvtFeature, MvtLayer};
+use crate::{MartinResult, TileCoord};
+
+pub const RENDER_TILE_SIZE_DEFAULT: u32 = 256;
+pub const MAX_IMAGE_SIZE_DEFAULT: u32 = 2048;
+
+/// Rendering of vector tiles as images with `/{source_ids}/{z}/{x}/{y}.png`
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenderConfig {
+    /// `MapLibre` style files that can be selected with the `style` query parameter, by name.
+    /// The files are read on every request, so they can be edited without restarting the server.
+    pub styles: Option<BTreeMap<String, PathBuf>>,
+    /// Width and height of the rendered tiles in pixels, between 64 and 2048 [default: 256]
+    pub tile_size: Option<u32>,
+    /// Maximum width and height of the static map images of `/image`, in pixels [default: 2048]
+    pub max_image_size: Option<u32>,
+}
+
+impl RenderConfig {
+    #[must_use]
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+            .unwrap_or(RENDER_TILE_SIZE_DEFAULT)
+            .clamp(64, 2048)
+    }
+
+    #[must_use]
+    pub fn max_image_size(&self) -> u32 {
+        self.max_image_size.unwrap_or(MAX_IMAGE_SIZE_DEFAULT)
+    }
+
+    /// Read the named style, or return `None` if there is no style with this name
+    pub async fn load_style(&self, name: &str) -> MartinResult<Option<Value>> {
+        let Some(path) = self.styles.as_ref().and_then(|v| v.get(name)) else {
+            return Ok(None);
+        };
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| StyleReadError(e, path.clone()))?;
+        let style = serde_json::from_slice(&data).map_err(|e| InvalidStyle(e, path.clone()))?;
+        Ok(Some(style))
+    }
+}
+
+/// Layer types drawn by [`render_tile`]
+const SUPPORTED_LAYER_TYPES: [&str; 4] = ["background", "fill", "line", "circle"];
+
+/// Paint and layout properties used by [`render_tile`], all others are ignored
+const SUPPORTED_PROPERTIES: [&str; 18] = [
+    "visibility",
+    "background-color",
+    "background-opacity",
+    "fill-color",
+    "fill-opacity",
+    "fill-outline-color",
+    "line-color",
+    "line-width",
+    "line-opacity",
+    "line-dasharray",
+    "line-cap",
+    "line-join",
+    "circle-color",
+    "circle-radius",
+    "circle-opacity",
+    "circle-stroke-color",
+    "circle-stroke-width",
+    "circle-stroke-opacity",
+];
+
+/// The visible layers and the properties of a style that [`render_tile`] does not draw,
+/// e.g. `symbol layer labels` or `fill-pattern of layer parks`
+#[must_use]
+pub fn unsupported_style_features(style: &Value) -> Vec<String> {
+    let mut unsupported = Vec::new();
+    for layer in style["layers"].as_array().into_iter().flatten() {
+        if layer["layout"]["visibility"] == "none" {
+            continue;
+        }
+        let id = layer["id"].as_str().unwrap_or_default();
+        let layer_type = layer["type"].as_str().unwrap_or_default();
+        if !SUPPORTED_LAYER_TYPES.contains(&layer_type) {
+            unsupported.push(format!("{layer_type} layer {id}"));
+            continue;
+        }
+        let properties = ["paint", "layout"]
+            .iter()
+            .filter_map(|section| layer[section].as_object())
+            .flat_map(|v| v.keys());
+        for name in properties {
+            if !SUPPORTED_PROPERTIES.contains(&name.as_str()) {
+                unsupported.push(format!("{name} of layer {id}"));
+            }
+        }
+    }
+    unsupported
+}
+
+/// Render an uncompressed vector tile with the `background`, `fill`, `line`, and `circle` layers
+/// of a `MapLibre` style. The layers are matched to the tile layers by their `source-layer`,
+/// regardless of their style source. Other layer types, e.g. labels, and the properties
+/// not in [`SUPPORTED_PROPERTIES`] are not drawn, see [`unsupported_style_features`].
+/// Paint properties may be constants or depend on the zoom level,
+/// and filter expressions that cannot be evaluated match all features.
+#[allow(clippy::cast_precision_loss)]
+pub fn render_tile(
+    style: &Value,
+    mvt: &[u8],
+    xyz: TileCoord,
+    size: u32,
+) -> MartinResult<RgbaImage> {
+    let tile = decode_mvt(mvt).map_err(|e| RenderError(e.to_string(), xyz))?;
+    let mut pixmap =
+        Pixmap::new(size, size).ok_or_else(|| RenderError(format!("invalid size {size}"), xyz))?;
+    let renderer = Renderer {
+        zoom: f64::from(xyz.z),
+        pixel_ratio: size as f32 / 256.0,
+        size: size as f32,
+    };
+    for layer in style["layers"].as_array().into_iter().flatten() {
+        renderer.draw_layer(&mut pixmap, layer, &tile);
+    }
+
+    let mut image = RgbaImage::new(size, size);
+    for (dst, src) in image.pixels_mut().zip(pixmap.pixels()) {
+        let color = src.demultiply();
+        *dst = image::Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    Ok(image)
+}
+
+struct Renderer {
+    zoom: f64,
+    /// Widths and radii of the style are given for 256px tiles
+    pixel_ratio: f32,
+    size: f32,
+}
+
+impl Renderer {
+    #[allow(clippy::cast_precision_loss)]
+    fn draw_layer(&self, pixmap: &mut Pixmap, layer: &Value, tile: &[MvtLayer]) {
+        if layer["layout"]["visibility"] == "none"
+            || layer["minzoom"].as_f64().is_some_and(|v| self.zoom < v)
+            || layer["maxzoom"].as_f64().is_some_and(|v| self.zoom >= v)
+        {
+            return;
+        }
+        let layer_type = layer["type"].as_str().unwrap_or_default();
+        if layer_type == "background" {
+            let color = self.color(layer, "background-color", Color::BLACK);
+            let color = with_opacity(color, self.number(layer, "background-opacity", 1.0));
+            if let Some(rect) = Rect::from_xywh(0.0, 0.0, self.size, self.size) {
+                pixmap.fill_rect(rect, &paint(color), Transform::identity(), None);
+            }
+            return;
+        }
+        let Some(source_layer) = layer["source-layer"].as_str() else {
+            return;
+        };
+        for tile_layer in tile.iter().filter(|l| l.name == source_layer) {
+            let scale = self.size / tile_layer.extent.max(1) as f32;
+            for feature in &tile_layer.features {
+                if !matches_filter(&layer["filter"], feature, self.zoom) {
+                    continue;
+                }
+                match (layer_type, feature.geom_type) {
+                    ("fill", GeomType::Polygon) => self.draw_fill(pixmap, layer, feature, scale),
+                    ("line", GeomType::LineString | GeomType::Polygon) => {
+                        self.draw_line(pixmap, layer, feature, scale);
+                    }
+                    ("circle", GeomType::Point) => self.draw_circle(pixmap, layer, feature, scale),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn draw_fill(&self, pixmap: &mut Pixmap, layer: &Value, feature: &MvtFeature, scale: f32) {
+        let Some(path) = to_path(feature, scale, true) else {
+            return;
+        };
+        let opacity = self.number(layer, "fill-opacity", 1.0);
+        let color = with_opacity(self.color(layer, "fill-color", Color::BLACK), opacity);
+        pixmap.fill_path(
+            &path,
+            &paint(color),
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+        if !layer["paint"]["fill-outline-color"].is_null() {
+            let color = with_opacity(self.color(layer, "fill-outline-color", color), opacity);
+            let stroke = Stroke {
+                width: self.pixel_ratio,
+                ..Stroke::default()
+            };
+            pixmap.stroke_path(&path, &paint(color), &stroke, Transform::identity(), None);
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn draw_line(&self, pixmap: &mut Pixmap, layer: &Value, feature: &MvtFeature, scale: f32) {
+        let Some(path) = to_path(feature, scale, feature.geom_type == GeomType::Polygon) else {
+            return;
+        };
+        let width = self.number(layer, "line-width", 1.0) * self.pixel_ratio;
+        if width <= 0.0 {
+            return;
+        }
+        let color = with_opacity(
+            self.color(layer, "line-color", Color::BLACK),
+            self.number(layer, "line-opacity", 1.0),
+        );
+        let dash = self.paint(layer, "line-dasharray").and_then(|v| {
+            let dashes = v
+                .as_array()?
+                .iter()
+                .map(|v| v.as_f64().map(|v| v as f32 * width))
+                .collect::<Option<Vec<_>>>()?;
+            // tiny-skia needs an even number of dashes
+            StrokeDash::new([dashes.clone(), dashes].concat(), 0.0)
+        });
+        let layout = &layer["layout"];
+        let stroke = Stroke {
+            width,
+            line_cap: match layout["line-cap"].as_str() {
+                Some("round") => LineCap::Round,
+                Some("square") => LineCap::Square,
+                _ => LineCap::Butt,
+            },
+            line_join: match layout["line-join"].as_str() {
+                Some("round") => LineJoin::Round,
+                Some("bevel") => LineJoin::Bevel,
+                _ => LineJoin::Miter,
+            },
+            dash,
+            ..Stroke::default()
+        };
+        pixmap.stroke_path(&path, &paint(color), &stroke, Transform::identity(), None);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn draw_circle(&self, pixmap: &mut Pixmap, layer: &Value, feature: &MvtFeature, scale: f32) {
+        let radius = self.number(layer, "circle-radius", 5.0) * self.pixel_ratio;
+        let opacity = self.number(layer, "circle-opacity", 1.0);
+        let color = with_opacity(self.color(layer, "circle-color", Color::BLACK), opacity);
+        let stroke_width = self.number(layer, "circle-stroke-width", 0.0) * self.pixel_ratio;
+        let stroke_color = with_opacity(
+            self.color(layer, "circle-stroke-color", Color::BLACK),
+            self.number(layer, "circle-stroke-opacity", 1.0),
+        );
+        for point in feature.geometry.iter().flatten() {
+            let (x, y) = (point[0] as f32 * scale, point[1] as f32 * scale);
+            let Some(path) = PathBuilder::from_circle(x, y, radius) else {
+                continue;
+            };
+            pixmap.fill_path(
+                &path,
+                &paint(color),
+                FillRule::Winding,
+                Transform::identity(),
+                None,
+            );
+            if stroke_width > 0.0 {
+                let stroke = Stroke {
+                    width: stroke_width,
+                    ..Stroke::default()
+                };
+                pixmap.stroke_path(
+                    &path,
+                    &paint(stroke_color),
+                    &stroke,
+                    Transform::identity(),
+                    None,
+                );
+            }
+        }
+    }
+
+    /// The value of a paint property at the current zoom level
+    fn paint(&self, layer: &Value, name: &str) -> Option<Value> {
+        let value = layer["paint"].get(name)?;
+        Some(eval_zoom(value, self.zoom).unwrap_or_else(|| value.clone()))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn number(&self, layer: &Value, name: &str, default: f32) -> f32 {
+        self.paint(layer, name)
+            .and_then(|v| v.as_f64())
+            .map_or(default, |v| v as f32)
+    }
+
+    fn color(&self, layer: &Value, name: &str, default: Color) -> Color {
+        self.paint(layer, name)
+            .and_then(|v| v.as_str().and_then(parse_color))
+            .unwrap_or(default)
+    }
+}
+
+fn paint(color: Color) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color(color);
+    paint.anti_alias = true;
+    paint
+}
+
+fn with_opacity(mut color: Color, opacity: f32) -> Color {
+    color.apply_opacity(opacity);
+    color
+}
+
+/// Build a path from the feature geometry, scaled from the tile extent to pixels
+#[allow(clippy::cast_precision_loss)]
+fn to_path(feature: &MvtFeature, scale: f32, close: bool) -> Option<tiny_skia::Path> {
+    let mut builder = PathBuilder::new();
+    for part in &feature.geometry {
+        let mut points = part
+            .iter()
+            .map(|p| (p[0] as f32 * scale, p[1] as f32 * scale));
+        let Some((x, y)) = points.next() else {
+            continue;
+        };
+        builder.move_to(x, y);
+        for (x, y) in points {
+            builder.line_to(x, y);
+        }
+        if close {
+            builder.close();
+        }
+    }
+    builder.finish()
+}
+
+/// Evaluate zoom functions, i.e. `{"stops": [[z, value], ...]}`, and the `interpolate` and `step`
+/// expressions with the `["zoom"]` input. Returns `None` for other values.
+fn eval_zoom(value: &Value, zoom: f64) -> Option<Value> {
+    let (stops, base, interpolate) = if let Some(stops) = value.get("stops") {
+        let stops = stops
+            .as_array()?
+            .iter()
+            .map(|stop| Some((stop.get(0)?.as_f64()?, stop.get(1)?.clone())))
+            .collect::<Option<Vec<_>>>()?;
+        let base = value["base"].as_f64().unwrap_or(1.0);
+        (stops, base, value["type"] != "interval")
+    } else {
+        let expr = value.as_array()?;
+        match expr.first()?.as_str()? {
+            "interpolate" if expr.get(2)? == &serde_json::json!(["zoom"]) => {
+                let base = match expr[1][0].as_str()? {
+                    "exponential" => expr[1][1].as_f64().unwrap_or(1.0),
+                    _ => 1.0,
+                };
+                (pairs(&expr[3..])?, base, true)
+            }
+            "step" if expr.get(1)? == &serde_json::json!(["zoom"]) => {
+                let mut stops = vec![(f64::NEG_INFINITY, expr.get(2)?.clone())];
+                stops.extend(pairs(&expr[3..])?);
+                (stops, 1.0, false)
+            }
+            _ => return None,
+        }
+    };
+
+    let idx = stops.iter().rposition(|(z, _)| *z <= zoom);
+    let Some(idx) = idx else {
+        return stops.first().map(|(_, v)| v.clone());
+    };
+    let (z0, v0) = &stops[idx];
+    let Some((z1, v1)) = stops.get(idx + 1).filter(|_| interpolate) else {
+        return Some(v0.clone());
+    };
+    let t = if (base - 1.0).abs() < f64::EPSILON {
+        (zoom - z0) / (z1 - z0)
+    } else {
+        (base.powf(zoom - z0) - 1.0) / (base.powf(z1 - z0) - 1.0)
+    };
+    Some(lerp(v0, v1, t).unwrap_or_else(|| v0.clone()))
+}
+
+/// Read the `zoom, value` pairs of an expression
+fn pairs(values: &[Value]) -> Option<Vec<(f64, Value)>> {
+    values
+        .chunks(2)
+        .map(|pair| Some((pair[0].as_f64()?, pair.get(1)?.clone())))
+        .collect()
+}
+
+/// Interpolate two numbers or colors
+#[allow(clippy::cast_possible_truncation)]
+fn lerp(a: &Value, b: &Value, t: f64) -> Option<Value> {
+    if let (Some(a), Some(b)) = (a.as_f64(), b.as_f64()) {
+        return Some((a + (b - a) * t).into());
+    }
+    let (a, b) = (parse_color(a.as_str()?)?, parse_color(b.as_str()?)?);
+    let t = t as f32;
+    let mix = |a: f32, b: f32| a + (b - a) * t;
+    let color = Color::from_rgba(
+        mix(a.red(), b.red()),
+        mix(a.green(), b.green()),
+        mix(a.blue(), b.blue()),
+        mix(a.alpha(), b.alpha()),
+    )?
+    .to_color_u8();
+    Some(
+        format!(
+            "rgba({}, {}, {}, {})",
+            color.red(),
+            color.green(),
+            color.blue(),
+            f32::from(color.alpha()) / 255.0
+        )
+        .into(),
+    )
+}
+
+/// Parse the `#rgb`, `#rrggbb`, `#rrggbbaa`, `rgb()`, `rgba()`, `hsl()`, and `hsla()` CSS colors,
+/// and a few color names
+fn parse_color(value: &str) -> Option<Color> {
+    let value = value.trim().to_ascii_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        let digit = |idx: usize, len: usize| u8::from_str_radix(hex.get(idx..idx + len)?, 16).ok();
+        return match hex.len() {
+            3 => Some(Color::from_rgba8(
+                digit(0, 1)? * 17,
+                digit(1, 1)? * 17,
+                digit(2, 1)? * 17,
+                255,
+            )),
+            6 | 8 => Some(Color::from_rgba8(
+                digit(0, 2)?,
+                digit(2, 2)?,
+                digit(4, 2)?,
+                if hex.len() == 8 { digit(6, 2)? } else { 255 },
+            )),
+            _ => None,
+        };
+    }
+    if let Some((name, args)) = value.strip_suffix(')').and_then(|v| v.split_once('(')) {
+        let args: Vec<f32> = args
+            .split(',')
+            .map(|v| v.trim().trim_end_matches('%').parse().ok())
+            .collect::<Option<_>>()?;
+        let alpha = args.get(3).copied().unwrap_or(1.0);
+        return match (name.trim(), args.len()) {
+            ("rgb" | "rgba", 3 | 4) => {
+                Color::from_rgba(args[0] / 255.0, args[1] / 255.0, args[2] / 255.0, alpha)
+            }
+            ("hsl" | "hsla", 3 | 4) => {
+                let (r, g, b) = hsl_to_rgb(args[0], args[1] / 100.0, args[2] / 100.0);
+                Color::from_rgba(r, g, b, alpha)
+            }
+            _ => None,
+        };
+    }
+    Some(match value.as_str() {
+        "transparent" => Color::TRANSPARENT,
+        "black" => Color::BLACK,
+        "white" => Color::WHITE,
+        "red" => Color::from_rgba8(255, 0, 0, 255),
+        "green" => Color::from_rgba8(0, 128, 0, 255),
+        "blue" => Color::from_rgba8(0, 0, 255, 255),
+        "yellow" => Color::from_rgba8(255, 255, 0, 255),
+        "gray" | "grey" => Color::from_rgba8(128, 128, 128, 255),
+        _ => None?,
+    })
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (f32, f32, f32) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let second = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (red, green, blue) = match hue {
+        h if h < 1.0 => (chroma, second, 0.0),
+        h if h < 2.0 => (second, chroma, 0.0),
+        h if h < 3.0 => (0.0, chroma, second),
+        h if h < 4.0 => (0.0, second, chroma),
+        h if h < 5.0 => (second, 0.0, chroma),
+        _ => (chroma, 0.0, second),
+    };
+    let offset = lightness - chroma / 2.0;
+    (red + offset, green + offset, blue + offset)
+}
+
+fn geometry_type(feature: &MvtFeature) -> &'static str {
+    match feature.geom_type {
+        GeomType::Point => "Point",
+        GeomType::LineString => "LineString",
+        GeomType::Polygon => "Polygon",
+    }
+}
+
+/// Evaluate a legacy filter like `["==", "class", "park"]`, or a filter expression like
+/// `["==", ["get", "class"], "park"]`. Unsupported operators match all features.
+fn matches_filter(filter: &Value, feature: &MvtFeature, zoom: f64) -> bool {
+    let Some((op, args)) = filter.as_array().and_then(|v| v.split_first()) else {
+        return filter.as_bool().unwrap_or(true);
+    };
+    // legacy filters refer to the attributes by name
+    let is_legacy = args.first().is_some_and(Value::is_string);
+    let get = |value: &Value| {
+        if is_legacy {
+            legacy_value(value.as_str().unwrap_or_default(), feature)
+        } else {
+            eval_expression(value, feature, zoom)
+        }
+    };
+    match op.as_str().unwrap_or_default() {
+        "all" => args.iter().all(|f| matches_filter(f, feature, zoom)),
+        "any" => args.iter().any(|f| matches_filter(f, feature, zoom)),
+        "none" => !args.iter().any(|f| matches_filter(f, feature, zoom)),
+        "!" => !args
+            .first()
+            .is_some_and(|f| matches_filter(f, feature, zoom)),
+        op @ ("has" | "!has") => {
+            let key = args.first().and_then(Value::as_str).unwrap_or_default();
+            feature.properties.contains_key(key) == (op == "has")
+        }
+        op @ ("in" | "!in") if is_legacy => {
+            let value = get(&args[0]);
+            args[1..].iter().any(|v| equals(&value, v)) == (op == "in")
+        }
+        op @ ("==" | "!=" | "<" | "<=" | ">" | ">=") => {
+            let (Some(a), Some(b)) = (args.first(), args.get(1)) else {
+                return false;
+            };
+            let a = get(a);
+            let b = if is_legacy {
+                b.clone()
+            } else {
+                eval_expression(b, feature, zoom)
+            };
+            match op {
+                "==" => equals(&a, &b),
+                "!=" => !equals(&a, &b),
+                _ => compare(&a, &b).is_some_and(|ord| match op {
+                    "<" => ord.is_lt(),
+                    "<=" => ord.is_le(),
+                    ">" => ord.is_gt(),
+                    _ => ord.is_ge(),
+                }),
+            }
+        }
+        _ => true,
+    }
+}
+
+fn legacy_value(key: &str, feature: &MvtFeature) -> Value {
+    match key {
+        "$type" => geometry_type(feature).into(),
+        "$id" => feature.id.map_or(Value::Null, Value::from),
+        key => feature.properties.get(key).cloned().unwrap_or_default(),
+    }
+}
+
+fn eval_expression(expr: &Value, feature: &MvtFeature, zoom: f64) -> Value {
+    let Some((op, args)) = expr.as_array().and_then(|v| v.split_first()) else {
+        return expr.clone();
+    };
+    match op.as_str().unwrap_or_default() {
+        "get" => args
+            .first()
+            .and_then(Value::as_str)
+            .and_then(|key| feature.properties.get(key))
+            .cloned()
+            .unwrap_or_default(),
+        "geometry-type" => geometry_type(feature).into(),
+        "id" => feature.id.map_or(Value::Null, Value::from),
+        "zoom" => zoom.into(),
+        "literal" => args.first().cloned().unwrap_or_default(),
+        _ => Value::Null,
+    }
+}
+
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => (a - b).abs() < f64::EPSILON,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+    use serde_json::json;
+
+    use super::*;
+    use crate::debug::{DebugConfig, DebugSource};
+    use crate::source::Source as _;
+
+    #[test]
+    fn test_parse_color() {
+        let rgba = |v: &str| parse_color(v).map(|c| c.to_color_u8());
+        let expected = |r, g, b, a| Some(Color::from_rgba8(r, g, b, a).to_color_u8());
+        assert_eq!(rgba("#f80"), expected(255, 136, 0, 255));
+        assert_eq!(rgba("#FF880080"), expected(255, 136, 0, 128));
+        assert_eq!(rgba("rgba(255, 136, 0, 0.5)"), expected(255, 136, 0, 128));
+        assert_eq!(rgba("hsl(120, 100%, 50%)"), expected(0, 255, 0, 255));
+        assert_eq!(rgba("white"), expected(255, 255, 255, 255));
+        assert_eq!(rgba("#12345"), None);
+        assert_eq!(rgba("cmyk(1, 2, 3, 4)"), None);
+    }
+
+    #[test]
+    fn test_eval_zoom() {
+        let stops = json!({"stops": [[5, 1], [10, 6]]});
+        assert_eq!(eval_zoom(&stops, 2.0), Some(json!(1)));
+        assert_eq!(eval_zoom(&stops, 7.0), Some(json!(3.0)));
+        assert_eq!(eval_zoom(&stops, 12.0), Some(json!(6)));
+
+        let expr = json!([
+            "interpolate",
+            ["linear"],
+            ["zoom"],
+            0,
+            "#000000",
+            10,
+            "#ffffff"
+        ]);
+        assert_eq!(eval_zoom(&expr, 5.0), Some(json!("rgba(128, 128, 128, 1)")));
+        let expr = json!(["step", ["zoom"], 1, 8, 2, 12, 4]);
+        assert_eq!(eval_zoom(&expr, 3.0), Some(json!(1)));
+        assert_eq!(eval_zoom(&expr, 10.0), Some(json!(2)));
+        assert_eq!(eval_zoom(&json!(3), 10.0), None);
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let feature = MvtFeature {
+            id: Some(7),
+            geom_type: GeomType::Polygon,
+            geometry: vec![],
+            properties: json!({"class": "park", "rank": 3})
+                .as_object()
+                .unwrap()
+                .clone(),
+        };
+        let matches = |filter: Value| matches_filter(&filter, &feature, 10.0);
+        assert!(matches(Value::Null));
+        assert!(matches(json!(["==", "$type", "Polygon"])));
+        assert!(matches(json!(["==", "class", "park"])));
+        assert!(!matches(json!(["!=", "class", "park"])));
+        assert!(matches(json!(["in", "class", "wood", "park"])));
+        assert!(matches(json!(["!has", "name"])));
+        assert!(matches(json!(["==", ["geometry-type"], "Polygon"])));
+        assert!(matches(json!([
+            "all",
+            [">=", ["get", "rank"], 3],
+            ["<", ["zoom"], 12]
+        ])));
+        assert!(!matches(json!([
+            "any",
+            ["==", ["id"], 8],
+            [">", "rank", 3]
+        ])));
+        assert!(matches(json!([
+            "match",
+            ["get", "class"],
+            "park",
+            true,
+            false
+        ])));
+    }
+
+    #[actix_rt::test]
+    async fn test_render_tile() {
+        let xyz = TileCoord { z: 3, x: 2, y: 5 };
+        let src = DebugSource::new("dbg".to_string(), &DebugConfig::default());
+        let mvt = src.get_tile(xyz, None).await.unwrap();
+        let style = json!({"layers": [
+            {"id": "bg", "type": "background", "paint": {"background-color": "#0000ff"}},
+            {"id": "fill", "type": "fill", "source": "dbg", "source-layer": "debug",
+                "filter": ["==", "$type", "Polygon"], "paint": {"fill-color": "#ff0000"}},
+            {"id": "point", "type": "circle", "source": "dbg", "source-layer": "debug",
+                "paint": {"circle-color": "#00ff00", "circle-radius": 10}},
+            {"id": "hidden", "type": "fill", "source": "dbg", "source-layer": "debug",
+                "minzoom": 5, "paint": {"fill-color": "#ffffff"}},
+        ]});
+        let image = render_tile(&style, &mvt, xyz, 512).unwrap();
+        assert_eq!(image.dimensions(), (512, 512));
+        // the tile boundary polygon covers the whole tile, and the point is in its center
+        assert_eq!(image.get_pixel(100, 100), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(256, 256), &Rgba([0, 255, 0, 255]));
+
+        let style = json!({"layers": [
+            {"id": "bg", "type": "background", "paint": {"background-color": "#0000ff", "background-opacity": 0.5}},
+        ]});
+        let image = render_tile(&style, &[], xyz, 256).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 255, 128]));
+    }
+
+    #[test]
+    fn test_unsupported_style_features() {
+        let style = json!({"layers": [
+            {"id": "bg", "type": "background", "paint": {"background-color": "#0000ff"}},
+            {"id": "parks", "type": "fill", "source-layer": "parks",
+                "paint": {"fill-color": "#00ff00", "fill-pattern": "trees"}},
+            {"id": "roads", "type": "line", "source-layer": "roads",
+                "layout": {"line-cap": "round", "line-sort-key": 1}, "paint": {"line-width": 2}},
+            {"id": "labels", "type": "symbol", "source-layer": "places"},
+            {"id": "hidden", "type": "symbol", "layout": {"visibility": "none"}},
+        ]});
+        assert_eq!(
+            unsupported_style_features(&style),
+            vec![
+                "fill-pattern of layer parks",
+                "line-sort-key of layer roads",
+                "symbol layer labels"
+            ]
+        );
+        assert!(unsupported_style_features(&json!({"layers": []})).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use std::f64::consts::PI;
+
+use image::imageops::{overlay, resize, FilterType};
+use image::RgbaImage;
+
+use crate::TileCoord;
+
+/// Size of the tiles the static maps are stitched from, in pixels
+pub const STATIC_MAP_TILE_SIZE: u32 = 256;
+
+/// The tiles covering a bounding box, and how to stitch them into an image of the requested size
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticMap {
+    pub zoom: u8,
+    /// The area of the image in the pixel coordinates of the whole world at `zoom`
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
+    /// Size of the resulting image
+    image_width: u32,
+    image_height: u32,
+}
+
+impl StaticMap {
+    /// Pick the zoom level at which the `[west, south, east, north]` bounding box fits the image best,
+    /// limited to `minzoom..=maxzoom`. The area is centered on the bounding box, and extended
+    /// in one direction if the bounding box has another aspect ratio than the image.
+    /// If `west` is greater than `east`, the bounding box crosses the antimeridian.
+    #[must_use]
+    pub fn new(
+        bbox: [f64; 4],
+        image_width: u32,
+        image_height: u32,
+        minzoom: u8,
+        maxzoom: u8,
+    ) -> Self {
+        let [west, south, east, north] = bbox;
+        let (left, top) = (mercator_x(west), mercator_y(north));
+        let (mut right, bottom) = (mercator_x(east), mercator_y(south));
+        if right < left {
+            right += 1.0;
+        }
+        let world = f64::from(STATIC_MAP_TILE_SIZE);
+        // fractional zoom level at which the bounding box fits in the image
+        let fit = (f64::from(image_width) / (world * (right - left).max(f64::EPSILON)))
+            .min(f64::from(image_height) / (world * (bottom - top).max(f64::EPSILON)))
+            .log2();
+        let zoom = fit
+            .round()
+            .clamp(f64::from(minzoom), f64::from(maxzoom.max(minzoom)));
+        // pixels of the tiles at the chosen zoom per pixel of the image
+        let scale = 2_f64.powf(zoom - fit);
+        let world = world * 2_f64.powf(zoom);
+        let (width, height) = (
+            f64::from(image_width) * scale,
+            f64::from(image_height) * scale,
+        );
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Self {
+            zoom: zoom as u8,
+            left: (left + right) / 2.0 * world - width / 2.0,
+            top: (top + bottom) / 2.0 * world - height / 2.0,
+            width,
+            height,
+            image_width,
+            image_height,
+        }
+    }
+
+    /// The `(column, row)` of the tiles covering the area, from the top left to the bottom right.
+    /// Columns may be outside of the world if the area crosses the antimeridian, see [`Self::tile_coord`].
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn tiles(&self) -> Vec<(i64, i64)> {
+        let size = f64::from(STATIC_MAP_TILE_SIZE);
+        let first_col = (self.left / size).floor() as i64;
+        let last_col = ((self.left + self.width) / size).ceil() as i64;
+        let first_row = (self.top / size).floor().max(0.0) as i64;
+        let last_row = ((self.top + self.height) / size)
+            .ceil()
+            .min(2_f64.powi(i32::from(self.zoom))) as i64;
+        (first_row..last_row)
+            .flat_map(|row| (first_col..last_col).map(move |col| (col, row)))
+            .collect()
+    }
+
+    /// The coordinate of the tile at a column and row, wrapping around the antimeridian
+    #[must_use]
+    pub fn tile_coord(&self, col: i64, row: i64) -> TileCoord {
+        let tiles = 1_i64 << self.zoom;
+        TileCoord {
+            z: self.zoom,
+            x: u32::try_from(col.rem_euclid(tiles)).unwrap_or_default(),
+            y: u32::try_from(row).unwrap_or_default(),
+        }
+    }
+
+    /// Draw the tiles at their `(column, row)` and scale the result to the size of the image.
+    /// Tiles of another size than [`STATIC_MAP_TILE_SIZE`] are scaled.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn compose(&self, tiles: &[(i64, i64, RgbaImage)]) -> RgbaImage {
+        let size = STATIC_MAP_TILE_SIZE;
+        let mut canvas = RgbaImage::new(self.width.ceil() as u32, self.height.ceil() as u32);
+        let (left, top) = (self.left.floor() as i64, self.top.floor() as i64);
+        for (col, row, tile) in tiles {
+            let (x, y) = (col * i64::from(size) - left, row * i64::from(size) - top);
+            if tile.dimensions() == (size, size) {
+                overlay(&mut canvas, tile, x, y);
+            } else {
+                overlay(
+                    &mut canvas,
+                    &resize(tile, size, size, FilterType::Triangle),
+                    x,
+                    y,
+                );
+            }
+        }
+        if canvas.dimensions() == (self.image_width, self.image_height) {
+            canvas
+        } else {
+            resize(
+                &canvas,
+                self.image_width,
+                self.image_height,
+                FilterType::Triangle,
+            )
+        }
+    }
+}
+
+/// Web Mercator x coordinate of a longitude, from 0 to 1
+fn mercator_x(lon: f64) -> f64 {
+    (lon + 180.0) / 360.0
+}
+
+/// Web Mercator y coordinate of a latitude, from 0 at the top to 1 at the bottom
+fn mercator_y(lat: f64) -> f64 {
+    let lat = lat.clamp(-85.051_128_78, 85.051_128_78).to_radians();
+    (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_static_map() {
+        // the whole world fits into a single tile
+        let map = StaticMap::new([-180.0, -85.0511, 180.0, 85.0511], 256, 256, 0, 20);
+        assert_eq!(map.zoom, 0);
+        assert_eq!(map.tiles(), vec![(0, 0)]);
+
+        let map = StaticMap::new([-180.0, -85.0511, 180.0, 85.0511], 512, 256, 0, 20);
+        assert_eq!(map.zoom, 0);
+        // the map is extended to the sides, repeating the world
+        assert_eq!(map.tiles(), vec![(-1, 0), (0, 0), (1, 0)]);
+        assert_eq!(map.tile_coord(-1, 0), TileCoord { z: 0, x: 0, y: 0 });
+
+        let map = StaticMap::new([-180.0, -85.0511, 180.0, 85.0511], 1024, 1024, 0, 20);
+        assert_eq!(map.zoom, 2);
+        assert_eq!(map.tiles().len(), 16);
+        let map = StaticMap::new([-180.0, -85.0511, 180.0, 85.0511], 1024, 1024, 0, 1);
+        assert_eq!(map.zoom, 1);
+        assert_eq!(map.tiles().len(), 4);
+
+        // crossing the antimeridian
+        let map = StaticMap::new([90.0, -60.0, -90.0, 60.0], 512, 512, 0, 20);
+        assert_eq!(map.zoom, 2);
+        assert_eq!(map.tiles(), vec![(3, 1), (4, 1), (3, 2), (4, 2)]);
+        assert_eq!(map.tile_coord(4, 1), TileCoord { z: 2, x: 0, y: 1 });
+
+        // a small area within a single tile
+        let map = StaticMap::new([13.38, 52.51, 13.39, 52.52], 40, 40, 0, 22);
+        assert_eq!(map.zoom, 12);
+        assert_eq!(
+            map.tiles()
+                .iter()
+                .map(|(col, row)| map.tile_coord(*col, *row))
+                .collect::<Vec<_>>(),
+            vec![TileCoord {
+                z: 12,
+                x: 2200,
+                y: 1343
+            }]
+        );
+    }
+
+    #[test]
+    fn test_compose() {
+        let map = StaticMap::new([-180.0, -85.0511, 180.0, 85.0511], 128, 128, 1, 1);
+        let tiles = vec![
+            (
+                0,
+                0,
+                RgbaImage::from_pixel(256, 256, Rgba([255, 0, 0, 255])),
+            ),
+            (
+                1,
+                1,
+                RgbaImage::from_pixel(512, 512, Rgba([0, 0, 255, 255])),
+            ),
+        ];
+        let image = map.compose(&tiles);
+        assert_eq!(image.dimensions(), (128, 128));
+        assert_eq!(image.get_pixel(10, 10), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(100, 100), &Rgba([0, 0, 255, 255]));
+        assert_eq!(image.get_pixel(100, 10), &Rgba([0, 0, 0, 0]));
+    }
+}
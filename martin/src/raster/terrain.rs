@@ -0,0 +1,468 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use image::{Rgba, RgbaImage};
+use martin_tile_utils::bbox_to_xyz;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tilejson::TileJSON;
+
+use crate::TileCoord;
+
+pub const TERRAIN_GRID_SIZE_DEFAULT: u16 = 64;
+
+/// Vertex coordinates and heights are quantized to `0..=32767`
+const QUANTIZED_MAX: f64 = 32767.0;
+/// Semi-major and semi-minor axes of the WGS84 ellipsoid in meters
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_B: f64 = 6_356_752.314_245_179;
+/// Web Mercator latitude limit
+const MAX_LAT: f64 = 85.051_128_78;
+
+/// Terrain meshes for Cesium with `/{source_id}/layer.json` and `/{source_id}/{z}/{x}/{y}.terrain`,
+/// generated from raster sources with elevations encoded as colors
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerrainConfig {
+    /// How elevations are encoded in the colors of the sources [default: mapbox]
+    pub encoding: Option<DemEncoding>,
+    /// Encodings of individual sources, by source ID
+    pub source_encodings: Option<BTreeMap<String, DemEncoding>>,
+    /// Number of cells per side of the mesh of each tile, between 1 and 255 [default: 64]
+    pub grid_size: Option<u16>,
+}
+
+impl TerrainConfig {
+    #[must_use]
+    pub fn encoding(&self, source_id: &str) -> DemEncoding {
+        self.source_encodings
+            .as_ref()
+            .and_then(|v| v.get(source_id).copied())
+            .or(self.encoding)
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn grid_size(&self) -> u16 {
+        self.grid_size
+            .unwrap_or(TERRAIN_GRID_SIZE_DEFAULT)
+            .clamp(1, 255)
+    }
+}
+
+/// How the elevation of a pixel is encoded in its color
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DemEncoding {
+    /// Mapbox Terrain-RGB: `-10000 + (R * 65536 + G * 256 + B) * 0.1` meters
+    #[default]
+    Mapbox,
+    /// Terrarium: `R * 256 + G + B / 256 - 32768` meters
+    Terrarium,
+}
+
+impl DemEncoding {
+    #[must_use]
+    pub fn elevation(self, pixel: Rgba<u8>) -> f64 {
+        let [r, g, b, _] = pixel.0.map(f64::from);
+        match self {
+            Self::Mapbox => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+            Self::Terrarium => r * 256.0 + g + b / 256.0 - 32768.0,
+        }
+    }
+}
+
+/// The `layer.json` of a terrain source, with the `{z}/{x}/{y}.terrain` tiles in the TMS scheme
+/// of the Web Mercator projection, available from zoom 0 to the maximum zoom of the source
+#[must_use]
+pub fn layer_json(id: &str, tilejson: &TileJSON) -> Value {
+    let bounds = tilejson.bounds.unwrap_or_default();
+    let (west, east) = (bounds.left.max(-180.0), bounds.right.min(180.0));
+    let (south, north) = (bounds.bottom.max(-MAX_LAT), bounds.top.min(MAX_LAT));
+    let maxzoom = tilejson.maxzoom.unwrap_or(22);
+    let available: Vec<_> = (0..=maxzoom)
+        .map(|zoom| {
+            let (min_x, min_y, max_x, max_y) = bbox_to_xyz(west, south, east, north, zoom);
+            let last = (1_u32 << zoom) - 1;
+            json!([{
+                "startX": min_x,
+                "startY": last - max_y,
+                "endX": max_x,
+                "endY": last - min_y,
+            }])
+        })
+        .collect();
+    let mut layer = json!({
+        "tilejson": "2.1.0",
+        "name": tilejson.name.as_deref().unwrap_or(id),
+        "version": "1.0.0",
+        "format": "quantized-mesh-1.0",
+        "scheme": "tms",
+        "projection": "EPSG:3857",
+        "tiles": ["{z}/{x}/{y}.terrain"],
+        "bounds": [west, south, east, north],
+        "minzoom": 0,
+        "maxzoom": maxzoom,
+        "available": available,
+    });
+    if let Some(attribution) = &tilejson.attribution {
+        layer["attribution"] = json!(attribution);
+    }
+    layer
+}
+
+/// A quantized-mesh tile of a regular grid of `grid_size` cells per side, with the elevations
+/// of the DEM tile interpolated at its vertices. Without a DEM tile, the mesh is flat at sea level.
+/// See <https://github.com/CesiumGS/quantized-mesh>
+#[must_use]
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss,
+    clippy::too_many_lines
+)]
+pub fn quantized_mesh(
+    dem: Option<&RgbaImage>,
+    encoding: DemEncoding,
+    xyz: TileCoord,
+    grid_size: u16,
+) -> Vec<u8> {
+    let cells = usize::from(grid_size.clamp(1, 255));
+    let tiles = f64::from(1_u32 << xyz.z);
+    let west = f64::from(xyz.x) / tiles * 360.0 - 180.0;
+    let east = f64::from(xyz.x + 1) / tiles * 360.0 - 180.0;
+    let north = tile_lat(f64::from(xyz.y) / tiles);
+    let south = tile_lat(f64::from(xyz.y + 1) / tiles);
+
+    // vertices row by row from the south-west corner, linear in longitude and latitude like Cesium expects
+    let mut grid = Vec::with_capacity((cells + 1) * (cells + 1));
+    for row in 0..=cells {
+        let v = row as f64 / cells as f64;
+        let lat = south + (north - south) * v;
+        // position of the latitude in the Web Mercator tile, from 0 at the top to 1 at the bottom
+        let tile_y = (mercator_y(lat) * tiles - f64::from(xyz.y)).clamp(0.0, 1.0);
+        for col in 0..=cells {
+            let u = col as f64 / cells as f64;
+            let height = dem.map_or(0.0, |dem| sample(dem, encoding, u, tile_y));
+            grid.push((u, v, west + (east - west) * u, lat, height));
+        }
+    }
+
+    // two counter-clockwise triangles per cell
+    let index = |col: usize, row: usize| row * (cells + 1) + col;
+    let mut triangles = Vec::with_capacity(cells * cells * 6);
+    for row in 0..cells {
+        for col in 0..cells {
+            let (sw, se) = (index(col, row), index(col + 1, row));
+            let (nw, ne) = (index(col, row + 1), index(col + 1, row + 1));
+            triangles.extend([sw, se, ne, sw, ne, nw]);
+        }
+    }
+    // the high-water mark encoding of the indices requires the vertices in the order of their first use
+    let mut new_index = vec![usize::MAX; grid.len()];
+    let mut vertices = Vec::with_capacity(grid.len());
+    for i in &mut triangles {
+        if new_index[*i] == usize::MAX {
+            new_index[*i] = vertices.len();
+            vertices.push(grid[*i]);
+        }
+        *i = new_index[*i];
+    }
+
+    let min_height = vertices.iter().map(|v| v.4).fold(f64::INFINITY, f64::min);
+    let max_height = vertices
+        .iter()
+        .map(|v| v.4)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let points: Vec<_> = vertices
+        .iter()
+        .map(|&(_, _, lon, lat, height)| to_ecef(lon, lat, height))
+        .collect();
+    let center = to_ecef(
+        (west + east) / 2.0,
+        (south + north) / 2.0,
+        (min_height + max_height) / 2.0,
+    );
+    let (sphere_center, sphere_radius) = bounding_sphere(&points);
+
+    let mut data = Vec::new();
+    for value in center {
+        data.extend(value.to_le_bytes());
+    }
+    data.extend((min_height as f32).to_le_bytes());
+    data.extend((max_height as f32).to_le_bytes());
+    for value in sphere_center {
+        data.extend(value.to_le_bytes());
+    }
+    data.extend(sphere_radius.to_le_bytes());
+    for value in horizon_occlusion_point(&points, sphere_center) {
+        data.extend(value.to_le_bytes());
+    }
+
+    let quantize = |value: f64| (value * QUANTIZED_MAX).round() as u16;
+    let height_range = (max_height - min_height).max(f64::EPSILON);
+    let us: Vec<_> = vertices.iter().map(|v| quantize(v.0)).collect();
+    let vs: Vec<_> = vertices.iter().map(|v| quantize(v.1)).collect();
+    let heights: Vec<_> = vertices
+        .iter()
+        .map(|v| quantize((v.4 - min_height) / height_range))
+        .collect();
+    data.extend((vertices.len() as u32).to_le_bytes());
+    for values in [&us, &vs, &heights] {
+        let mut previous = 0_i32;
+        for &value in values {
+            let delta = i32::from(value) - previous;
+            previous = i32::from(value);
+            data.extend((((delta << 1) ^ (delta >> 31)) as u16).to_le_bytes());
+        }
+    }
+
+    // at most 256 * 256 vertices, so the indices always fit into 16 bits
+    data.extend(((triangles.len() / 3) as u32).to_le_bytes());
+    let mut highest = 0;
+    for &i in &triangles {
+        data.extend(((highest - i) as u16).to_le_bytes());
+        if i == highest {
+            highest += 1;
+        }
+    }
+    let edges: [fn(u16, u16) -> bool; 4] = [
+        |u, _| u == 0,
+        |_, v| v == 0,
+        |u, _| u == 32767,
+        |_, v| v == 32767,
+    ];
+    for is_edge in edges {
+        let edge: Vec<_> = (0..vertices.len())
+            .filter(|&i| is_edge(us[i], vs[i]))
+            .collect();
+        data.extend((edge.len() as u32).to_le_bytes());
+        for i in edge {
+            data.extend((i as u16).to_le_bytes());
+        }
+    }
+    data
+}
+
+/// Bilinear interpolation of the elevation at a position of the DEM tile, from 0 to 1 in both directions
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn sample(dem: &RgbaImage, encoding: DemEncoding, x: f64, y: f64) -> f64 {
+    let (width, height) = dem.dimensions();
+    let x = (x * f64::from(width) - 0.5).clamp(0.0, f64::from(width - 1));
+    let y = (y * f64::from(height) - 0.5).clamp(0.0, f64::from(height - 1));
+    let (left, top) = (x.floor() as u32, y.floor() as u32);
+    let (right, bottom) = ((left + 1).min(width - 1), (top + 1).min(height - 1));
+    let (dx, dy) = (x - f64::from(left), y - f64::from(top));
+    let elevation = |x: u32, y: u32| encoding.elevation(*dem.get_pixel(x, y));
+    let upper = elevation(left, top) * (1.0 - dx) + elevation(right, top) * dx;
+    let lower = elevation(left, bottom) * (1.0 - dx) + elevation(right, bottom) * dx;
+    upper * (1.0 - dy) + lower * dy
+}
+
+/// Latitude of a Web Mercator y coordinate, from 0 at the top to 1 at the bottom
+fn tile_lat(y: f64) -> f64 {
+    (PI * (1.0 - 2.0 * y)).sinh().atan().to_degrees()
+}
+
+/// Web Mercator y coordinate of a latitude, from 0 at the top to 1 at the bottom
+fn mercator_y(lat: f64) -> f64 {
+    let lat = lat.clamp(-MAX_LAT, MAX_LAT).to_radians();
+    (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0
+}
+
+/// Earth-centered, earth-fixed coordinates of a position on the WGS84 ellipsoid
+fn to_ecef(lon: f64, lat: f64, height: f64) -> [f64; 3] {
+    let e2 = 1.0 - (WGS84_B * WGS84_B) / (WGS84_A * WGS84_A);
+    let (lon, lat) = (lon.to_radians(), lat.to_radians());
+    let n = WGS84_A / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+    [
+        (n + height) * lat.cos() * lon.cos(),
+        (n + height) * lat.cos() * lon.sin(),
+        (n * (1.0 - e2) + height) * lat.sin(),
+    ]
+}
+
+/// A sphere around the center of the bounding box of the points
+fn bounding_sphere(points: &[[f64; 3]]) -> ([f64; 3], f64) {
+    let mut center = [0.0; 3];
+    for (axis, value) in center.iter_mut().enumerate() {
+        let min = points.iter().map(|p| p[axis]).fold(f64::INFINITY, f64::min);
+        let max = points
+            .iter()
+            .map(|p| p[axis])
+            .fold(f64::NEG_INFINITY, f64::max);
+        *value = (min + max) / 2.0;
+    }
+    let radius = points
+        .iter()
+        .map(|p| distance(*p, center))
+        .fold(0.0, f64::max);
+    (center, radius)
+}
+
+/// The point in the ellipsoid-scaled space that is hidden behind the horizon only if all the points are,
+/// computed like Cesium's `EllipsoidalOccluder`. Tiles facing more than a hemisphere have no such point,
+/// and get a `NaN` point instead, which Cesium never considers hidden.
+fn horizon_occlusion_point(points: &[[f64; 3]], center: [f64; 3]) -> [f64; 3] {
+    let scaled = |p: [f64; 3]| [p[0] / WGS84_A, p[1] / WGS84_A, p[2] / WGS84_B];
+    let direction = normalize(scaled(center));
+    let mut max_magnitude = 0.0_f64;
+    for point in points {
+        let point = scaled(*point);
+        let magnitude = distance(point, [0.0; 3]);
+        let point_direction = normalize(point);
+        // points below the ellipsoid are considered to be on it
+        let magnitude = magnitude.max(1.0);
+        let cos_alpha = dot(point_direction, direction);
+        let sin_alpha = distance(cross(point_direction, direction), [0.0; 3]);
+        let cos_beta = 1.0 / magnitude;
+        let sin_beta = (magnitude * magnitude - 1.0).sqrt() * cos_beta;
+        let candidate = 1.0 / (cos_alpha * cos_beta - sin_alpha * sin_beta);
+        if !(candidate > 0.0 && candidate.is_finite()) {
+            return [f64::NAN; 3];
+        }
+        max_magnitude = max_magnitude.max(candidate);
+    }
+    direction.map(|v| v * max_magnitude)
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    dot(d, d).sqrt()
+}
+
+fn normalize(v: [f64; 3]) -> [f64; 3] {
+    let length = distance(v, [0.0; 3]);
+    v.map(|v| v / length)
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::tilejson;
+
+    use super::*;
+
+    #[test]
+    fn test_elevation() {
+        assert!((DemEncoding::Mapbox.elevation(Rgba([1, 134, 160, 255])) - 0.0).abs() < 0.01);
+        assert!((DemEncoding::Mapbox.elevation(Rgba([1, 200, 0, 255])) - 1673.6).abs() < 0.01);
+        assert!((DemEncoding::Terrarium.elevation(Rgba([128, 0, 0, 255])) - 0.0).abs() < 0.01);
+        assert!(
+            (DemEncoding::Terrarium.elevation(Rgba([128, 100, 128, 255])) - 100.5).abs() < 0.01
+        );
+    }
+
+    /// Read the vertices and the triangles of a quantized-mesh tile with 16-bit indices
+    fn read_mesh(data: &[u8]) -> (f32, f32, Vec<[u16; 3]>, Vec<[usize; 3]>) {
+        let f32_at = |pos: usize| f32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let u32_at =
+            |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let u16_at = |pos: usize| u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap());
+        let (min_height, max_height) = (f32_at(24), f32_at(28));
+        let count = u32_at(88);
+        let mut vertices = vec![[0_u16; 3]; count];
+        for axis in 0..3 {
+            let mut value = 0_i32;
+            for (i, vertex) in vertices.iter_mut().enumerate() {
+                let code = i32::from(u16_at(92 + (axis * count + i) * 2));
+                value += (code >> 1) ^ -(code & 1);
+                vertex[axis] = u16::try_from(value).unwrap();
+            }
+        }
+        let pos = 92 + 3 * count * 2;
+        let triangle_count = u32_at(pos);
+        let mut highest = 0;
+        let mut indices = Vec::new();
+        for i in 0..triangle_count * 3 {
+            let code = usize::from(u16_at(pos + 4 + i * 2));
+            indices.push(highest - code);
+            if code == 0 {
+                highest += 1;
+            }
+        }
+        let triangles = indices.chunks(3).map(|t| [t[0], t[1], t[2]]).collect();
+        (min_height, max_height, vertices, triangles)
+    }
+
+    #[test]
+    fn test_quantized_mesh() {
+        // elevations from 0 at the left to 1000 meters at the right in Terrarium encoding
+        let dem = RgbaImage::from_fn(256, 256, |x, _| {
+            let [r, g] = (u16::try_from(x * 1000 / 255).unwrap() + 32768).to_be_bytes();
+            Rgba([r, g, 0, 255])
+        });
+        let xyz = TileCoord { z: 5, x: 17, y: 11 };
+        let data = quantized_mesh(Some(&dem), DemEncoding::Terrarium, xyz, 4);
+        let (min_height, max_height, vertices, triangles) = read_mesh(&data);
+        assert!((min_height - 0.0).abs() < 5.0);
+        assert!((max_height - 1000.0).abs() < 5.0);
+        assert_eq!(vertices.len(), 25);
+        assert_eq!(triangles.len(), 32);
+        assert!(triangles.iter().flatten().all(|&i| i < vertices.len()));
+        // the west edge is low and the east edge is high
+        for [u, v, height] in &vertices {
+            assert!(*v <= 32767);
+            if *u == 0 {
+                assert!(*height < 100);
+            } else if *u == 32767 {
+                assert!(*height > 32000);
+            }
+        }
+        // the horizon occlusion point is above the tile, outside of the ellipsoid
+        let f64_at = |pos: usize| f64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+        let point = [f64_at(64), f64_at(72), f64_at(80)];
+        assert!(distance(point, [0.0; 3]) > 1.0);
+
+        // edges have 5 vertices each
+        let edges = 92 + 25 * 6 + 4 + 32 * 6;
+        for i in 0..4 {
+            let pos = edges + i * (4 + 5 * 2);
+            assert_eq!(
+                u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()),
+                5
+            );
+        }
+        assert_eq!(data.len(), edges + 4 * (4 + 5 * 2));
+
+        let flat = quantized_mesh(
+            None,
+            DemEncoding::Mapbox,
+            TileCoord { z: 0, x: 0, y: 0 },
+            64,
+        );
+        let (min_height, max_height, vertices, _) = read_mesh(&flat);
+        assert!(min_height.abs() < f32::EPSILON && max_height.abs() < f32::EPSILON);
+        assert_eq!(vertices.len(), 65 * 65);
+    }
+
+    #[test]
+    fn test_layer_json() {
+        let mut tilejson = tilejson! { tiles: vec![] };
+        tilejson.maxzoom = Some(2);
+        tilejson.bounds = Some(tilejson::Bounds::new(0.0, 0.0, 90.0, 60.0));
+        let layer = layer_json("dem", &tilejson);
+        assert_eq!(layer["name"], "dem");
+        assert_eq!(layer["format"], "quantized-mesh-1.0");
+        assert_eq!(layer["projection"], "EPSG:3857");
+        assert_eq!(
+            layer["available"],
+            json!([
+                [{"startX": 0, "startY": 0, "endX": 0, "endY": 0}],
+                [{"startX": 1, "startY": 0, "endX": 1, "endY": 1}],
+                [{"startX": 2, "startY": 1, "endX": 3, "endY": 2}],
+            ])
+        );
+    }
+}
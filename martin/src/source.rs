@@ -1,16 +1,28 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use actix_web::error::ErrorNotFound;
 use async_trait::async_trait;
-use log::debug;
-use martin_tile_utils::TileInfo;
+use bytes::Bytes;
+use futures::future::try_join_all;
+use futures::stream::BoxStream;
+use log::{debug, warn};
+use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::{Deserialize, Serialize};
 use tilejson::TileJSON;
+use tokio::sync::Semaphore;
 
+use crate::utils::{decode_brotli, decode_gzip, decode_zstd, MvtPipeline, UtfGrid};
+use crate::MartinError::{FallbackSourceError, InternalError, SourceTimeout, SourceUnavailable};
 use crate::{MartinResult, TileCoord};
 
-pub type TileData = Vec<u8>;
+/// Tile content, shared between the cache and the responses without copying
+pub type TileData = Bytes;
+/// The data of a tile read in chunks, see [`Source::get_tile_stream`]
+pub type TileStream = BoxStream<'static, MartinResult<Bytes>>;
 pub type UrlQuery = HashMap<String, String>;
 
 pub type TileInfoSource = Box<dyn Source>;
@@ -90,6 +102,94 @@ impl TileSources {
         Ok((sources, use_url_query, info.unwrap()))
     }
 
+    /// Add a source, replacing and returning any existing source with the same ID
+    pub fn add_source(&mut self, source: Box<dyn Source>) -> Option<Box<dyn Source>> {
+        self.0.insert(source.get_id().to_string(), source)
+    }
+
+    /// Limit the number of tiles the source may generate at the same time.
+    /// Additional requests wait until one of the running requests completes.
+    /// Returns false if the source does not exist.
+    pub fn limit_concurrency(&mut self, id: &str, max_concurrency: NonZeroUsize) -> bool {
+        self.wrap_source(id, |src| {
+            Box::new(ConcurrencyLimitedSource::new(src, max_concurrency))
+        })
+    }
+
+    /// Fail fast for `cooldown` after the source failed `max_failures` times in a row.
+    /// Returns false if the source does not exist.
+    pub fn add_circuit_breaker(
+        &mut self,
+        id: &str,
+        max_failures: NonZeroU32,
+        cooldown: Duration,
+    ) -> bool {
+        self.wrap_source(id, |src| {
+            Box::new(CircuitBreakerSource::new(src, max_failures, cooldown))
+        })
+    }
+
+    /// Abort tile requests that take longer than `timeout`.
+    /// Returns false if the source does not exist.
+    pub fn add_timeout(&mut self, id: &str, timeout: Duration) -> bool {
+        self.wrap_source(id, |src| Box::new(TimeoutSource::new(src, timeout)))
+    }
+
+    /// Apply a post-processing pipeline to every vector tile of the source.
+    /// Returns false if the source does not exist, or does not serve vector tiles.
+    pub fn add_pipeline(&mut self, id: &str, pipeline: MvtPipeline) -> bool {
+        if self
+            .0
+            .get(id)
+            .is_some_and(|src| src.get_tile_info().format != Format::Mvt)
+        {
+            return false;
+        }
+        self.wrap_source(id, |src| Box::new(PipelineSource::new(src, pipeline)))
+    }
+
+    /// Pass every tile of the source through the WASM plugins, in order.
+    /// Returns false if the source does not exist.
+    #[cfg(feature = "wasm")]
+    pub fn add_plugins(&mut self, id: &str, plugins: Vec<crate::plugins::WasmPlugin>) -> bool {
+        self.wrap_source(id, |src| {
+            Box::new(crate::plugins::PluginSource::new(src, plugins))
+        })
+    }
+
+    /// Add a source that tries the existing sources in the given order, until one of them returns a non-empty tile
+    pub fn add_fallback(&mut self, id: &str, chain: &[String]) -> MartinResult<()> {
+        if self.0.contains_key(id) {
+            return Err(FallbackSourceError(
+                id.to_string(),
+                "a source with the same ID already exists".to_string(),
+            ));
+        }
+        let sources = chain
+            .iter()
+            .map(|src_id| {
+                self.0.get(src_id).cloned().ok_or_else(|| {
+                    FallbackSourceError(id.to_string(), format!("source {src_id} does not exist"))
+                })
+            })
+            .collect::<MartinResult<Vec<_>>>()?;
+        let source = FallbackSource::new(id.to_string(), sources)?;
+        self.0.insert(id.to_string(), Box::new(source));
+        Ok(())
+    }
+
+    fn wrap_source(
+        &mut self,
+        id: &str,
+        wrap: impl FnOnce(Box<dyn Source>) -> Box<dyn Source>,
+    ) -> bool {
+        let Some(src) = self.0.remove(id) else {
+            return false;
+        };
+        self.0.insert(id.to_string(), wrap(src));
+        true
+    }
+
     pub fn check_zoom(src: &dyn Source, id: &str, zoom: u8) -> bool {
         let is_valid = src.is_valid_zoom(zoom);
         if !is_valid {
@@ -100,7 +200,7 @@ impl TileSources {
 }
 
 #[async_trait]
-pub trait Source: Send + Debug {
+pub trait Source: Send + Sync + Debug {
     fn get_id(&self) -> &str;
 
     fn get_tilejson(&self) -> &TileJSON;
@@ -119,6 +219,48 @@ pub trait Source: Send + Debug {
         url_query: Option<&UrlQuery>,
     ) -> MartinResult<TileData>;
 
+    /// Kind of the source, e.g. `postgres` or `mbtiles`
+    fn get_source_type(&self) -> &'static str {
+        "other"
+    }
+
+    /// The time the source data was last modified, if known
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// Version of the source data, if the source has one. It is part of the cache keys and the `ETag`
+    /// of the tiles, so changing the version invalidates both the server and the client caches.
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        Ok(None)
+    }
+
+    /// Get the `UTFGrid` interaction data of a tile, or `None` if the source does not support it
+    async fn get_utfgrid(&self, _xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        Ok(None)
+    }
+
+    /// Size in bytes of the tile data `get_tile` would return, if the source can find it out
+    /// without reading the data. Used to answer `HEAD` requests. Empty tiles have the size 0.
+    async fn get_tile_size(
+        &self,
+        _xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        Ok(None)
+    }
+
+    /// Read the data `get_tile` would return in chunks, if the source supports it, so that large tiles
+    /// can be sent to the client without holding them in memory. Only used for tiles of at least
+    /// `stream_min_size` bytes according to `get_tile_size`.
+    async fn get_tile_stream(
+        &self,
+        _xyz: TileCoord,
+        _url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<TileStream>> {
+        Ok(None)
+    }
+
     fn is_valid_zoom(&self, zoom: u8) -> bool {
         let tj = self.get_tilejson();
         tj.minzoom.map_or(true, |minzoom| zoom >= minzoom)
@@ -135,6 +277,7 @@ pub trait Source: Send + Debug {
             name: tilejson.name.as_ref().filter(|v| *v != id).cloned(),
             description: tilejson.description.clone(),
             attribution: tilejson.attribution.clone(),
+            ..Default::default()
         }
     }
 }
@@ -145,6 +288,589 @@ impl Clone for Box<dyn Source> {
     }
 }
 
+/// A source wrapper that limits the number of concurrent `get_tile` calls,
+/// so that a single expensive source cannot exhaust shared resources like a connection pool.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitedSource {
+    source: Box<dyn Source>,
+    permits: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitedSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, max_concurrency: NonZeroUsize) -> Self {
+        Self {
+            source,
+            permits: Arc::new(Semaphore::new(max_concurrency.get())),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for ConcurrencyLimitedSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        // The semaphore is never closed, so acquiring a permit cannot fail
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.source.get_tile(xyz, url_query).await
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        self.source.get_last_modified().await
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        self.source.get_data_version().await
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.source.get_utfgrid(xyz).await
+    }
+
+    async fn get_tile_size(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.source.get_tile_size(xyz, url_query).await
+    }
+
+    /// The permit is only held until the stream is opened, not while the chunks are read
+    async fn get_tile_stream(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<TileStream>> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.source.get_tile_stream(xyz, url_query).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+/// A source wrapper that aborts `get_tile` calls taking longer than the timeout.
+/// Dropping the inner future also cancels any work the source may be doing, e.g. a Postgres query.
+#[derive(Debug, Clone)]
+pub struct TimeoutSource {
+    source: Box<dyn Source>,
+    timeout: Duration,
+}
+
+impl TimeoutSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, timeout: Duration) -> Self {
+        Self { source, timeout }
+    }
+}
+
+#[async_trait]
+impl Source for TimeoutSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        tokio::time::timeout(self.timeout, self.source.get_tile(xyz, url_query))
+            .await
+            .map_err(|_| SourceTimeout(self.get_id().to_string(), self.timeout))?
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        self.source.get_last_modified().await
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        self.source.get_data_version().await
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        tokio::time::timeout(self.timeout, self.source.get_utfgrid(xyz))
+            .await
+            .map_err(|_| SourceTimeout(self.get_id().to_string(), self.timeout))?
+    }
+
+    async fn get_tile_size(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        tokio::time::timeout(self.timeout, self.source.get_tile_size(xyz, url_query))
+            .await
+            .map_err(|_| SourceTimeout(self.get_id().to_string(), self.timeout))?
+    }
+
+    /// The timeout only applies to opening the stream, not to reading the chunks
+    async fn get_tile_stream(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<TileStream>> {
+        tokio::time::timeout(self.timeout, self.source.get_tile_stream(xyz, url_query))
+            .await
+            .map_err(|_| SourceTimeout(self.get_id().to_string(), self.timeout))?
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+/// A source wrapper that transforms vector tiles, e.g. to drop or rename attributes.
+/// Tiles are decompressed to be transformed, so the wrapped source always returns uncompressed tiles.
+#[derive(Debug, Clone)]
+pub struct PipelineSource {
+    source: Box<dyn Source>,
+    pipeline: Arc<MvtPipeline>,
+}
+
+impl PipelineSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, pipeline: MvtPipeline) -> Self {
+        Self {
+            source,
+            pipeline: Arc::new(pipeline),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for PipelineSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info().encoding(Encoding::Uncompressed)
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let data = self.source.get_tile(xyz, url_query).await?;
+        if data.is_empty() {
+            return Ok(data);
+        }
+        let data = decompress(data, &*self.source, xyz)?;
+        Ok(self.pipeline.apply(&data)?.into())
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        self.source.get_last_modified().await
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        self.source.get_data_version().await
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        self.source.get_utfgrid(xyz).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        CatalogSourceEntry {
+            content_encoding: None,
+            ..self.source.get_catalog_entry()
+        }
+    }
+}
+
+/// Decompress a non-empty tile of the source
+fn decompress(data: TileData, source: &dyn Source, xyz: TileCoord) -> MartinResult<TileData> {
+    Ok(match source.get_tile_info().encoding {
+        Encoding::Uncompressed => data,
+        Encoding::Gzip => decode_gzip(&data)?.into(),
+        Encoding::Brotli => decode_brotli(&data)?.into(),
+        Encoding::Zstd => decode_zstd(&data)?.into(),
+        enc => Err(InternalError(
+            format!(
+                "Unable to decompress tile {xyz} of source {} stored with {enc:?} encoding",
+                source.get_id()
+            )
+            .into(),
+        ))?,
+    })
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// A source wrapper that stops calling a failing source for a while, e.g. while its database is down.
+/// Once the cooldown expires, the next request is let through to check if the source has recovered.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerSource {
+    source: Box<dyn Source>,
+    max_failures: NonZeroU32,
+    cooldown: Duration,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+impl CircuitBreakerSource {
+    #[must_use]
+    pub fn new(source: Box<dyn Source>, max_failures: NonZeroU32, cooldown: Duration) -> Self {
+        Self {
+            source,
+            max_failures,
+            cooldown,
+            state: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Source for CircuitBreakerSource {
+    fn get_id(&self) -> &str {
+        self.source.get_id()
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        self.source.get_tilejson()
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.source.get_tile_info()
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.source.support_url_query()
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let open_until = self.state.lock().unwrap().open_until;
+        if let Some(retry_after) = open_until.and_then(|v| v.checked_duration_since(Instant::now()))
+        {
+            return Err(SourceUnavailable(self.get_id().to_string(), retry_after));
+        }
+
+        let result = self.source.get_tile(xyz, url_query).await;
+        let mut state = self.state.lock().unwrap();
+        if result.is_ok() {
+            state.failures = 0;
+            state.open_until = None;
+        } else {
+            state.failures = state.failures.saturating_add(1);
+            if state.failures >= self.max_failures.get() {
+                if state.open_until.is_none() {
+                    warn!(
+                        "Source {} failed {} times in a row, pausing it for {:?}",
+                        self.get_id(),
+                        state.failures,
+                        self.cooldown
+                    );
+                }
+                state.open_until = Some(Instant::now() + self.cooldown);
+            }
+        }
+        result
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        self.source.get_source_type()
+    }
+
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        self.source.get_last_modified().await
+    }
+
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        self.source.get_data_version().await
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        self.source.get_utfgrid(xyz).await
+    }
+
+    /// The size is unknown while the breaker is open, so that the request fails the same way as `get_tile`
+    async fn get_tile_size(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        let open_until = self.state.lock().unwrap().open_until;
+        if open_until.is_some_and(|v| v > Instant::now()) {
+            return Ok(None);
+        }
+        self.source.get_tile_size(xyz, url_query).await
+    }
+
+    /// Streaming is not possible while the breaker is open, so that the request fails the same way as `get_tile`
+    async fn get_tile_stream(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<TileStream>> {
+        let open_until = self.state.lock().unwrap().open_until;
+        if open_until.is_some_and(|v| v > Instant::now()) {
+            return Ok(None);
+        }
+        self.source.get_tile_stream(xyz, url_query).await
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.source.is_valid_zoom(zoom)
+    }
+
+    fn get_catalog_entry(&self) -> CatalogSourceEntry {
+        self.source.get_catalog_entry()
+    }
+}
+
+/// A source that tries its sources in order, until one of them returns a non-empty tile,
+/// e.g. a database source with a static `MBTiles` file as a backup.
+/// If the sources store tiles with different encodings, all tiles are served uncompressed.
+#[derive(Debug, Clone)]
+pub struct FallbackSource {
+    id: String,
+    tilejson: TileJSON,
+    info: TileInfo,
+    sources: Vec<Box<dyn Source>>,
+}
+
+impl FallbackSource {
+    pub fn new(id: String, sources: Vec<Box<dyn Source>>) -> MartinResult<Self> {
+        let Some(first) = sources.first() else {
+            return Err(FallbackSourceError(id, "no sources to try".to_string()));
+        };
+        let mut info = first.get_tile_info();
+        let mut tilejson = first.get_tilejson().clone();
+        for src in &sources[1..] {
+            let src_info = src.get_tile_info();
+            if src_info.format != info.format {
+                return Err(FallbackSourceError(
+                    id,
+                    format!(
+                        "source {} has {} tiles, but source {} has {} tiles",
+                        src.get_id(),
+                        src_info.format,
+                        first.get_id(),
+                        info.format
+                    ),
+                ));
+            }
+            if src_info.encoding != info.encoding {
+                info = info.encoding(Encoding::Uncompressed);
+            }
+            // the tiles of any of the sources can be served
+            let tj = src.get_tilejson();
+            tilejson.minzoom = tilejson.minzoom.zip(tj.minzoom).map(|(a, b)| a.min(b));
+            tilejson.maxzoom = tilejson.maxzoom.zip(tj.maxzoom).map(|(a, b)| a.max(b));
+            tilejson.bounds = tilejson.bounds.zip(tj.bounds).map(|(a, b)| a + b);
+        }
+        Ok(Self {
+            id,
+            tilejson,
+            info,
+            sources,
+        })
+    }
+}
+
+#[async_trait]
+impl Source for FallbackSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.info
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    fn support_url_query(&self) -> bool {
+        self.sources.iter().any(|src| src.support_url_query())
+    }
+
+    /// Returns an empty tile if at least one of the sources returned an empty tile,
+    /// or the error of the last source if all of them failed
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let mut is_empty = false;
+        let mut error = None;
+        for src in self.sources.iter().filter(|src| src.is_valid_zoom(xyz.z)) {
+            let query = url_query.filter(|_| src.support_url_query());
+            match src.get_tile(xyz, query).await {
+                Ok(data) if data.is_empty() => is_empty = true,
+                Ok(data) if src.get_tile_info().encoding == self.info.encoding => return Ok(data),
+                Ok(data) => return decompress(data, &**src, xyz),
+                Err(e) => {
+                    debug!(
+                        "Source {} failed to get tile {xyz} of {}, trying the next source: {e}",
+                        src.get_id(),
+                        self.id
+                    );
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if !is_empty => Err(e),
+            _ => Ok(TileData::new()),
+        }
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "fallback"
+    }
+
+    /// The most recent date of the sources, or `None` if any of them has no known date
+    async fn get_last_modified(&self) -> MartinResult<Option<SystemTime>> {
+        let dates = try_join_all(self.sources.iter().map(|src| src.get_last_modified())).await?;
+        Ok(dates
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .and_then(|dates| dates.into_iter().max()))
+    }
+
+    /// The versions of all the sources, so that a change in any of them invalidates the tiles
+    async fn get_data_version(&self) -> MartinResult<Option<String>> {
+        let versions = try_join_all(self.sources.iter().map(|src| src.get_data_version())).await?;
+        if versions.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        Ok(Some(
+            versions
+                .into_iter()
+                .map(Option::unwrap_or_default)
+                .collect::<Vec<_>>()
+                .join(","),
+        ))
+    }
+
+    async fn get_utfgrid(&self, xyz: TileCoord) -> MartinResult<Option<UtfGrid>> {
+        for src in self.sources.iter().filter(|src| src.is_valid_zoom(xyz.z)) {
+            if let Ok(Some(grid)) = src.get_utfgrid(xyz).await {
+                return Ok(Some(grid));
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_valid_zoom(&self, zoom: u8) -> bool {
+        self.sources.iter().any(|src| src.is_valid_zoom(zoom))
+    }
+}
+
 #[serde_with::skip_serializing_none]
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CatalogSourceEntry {
@@ -153,10 +879,24 @@ pub struct CatalogSourceEntry {
     pub name: Option<String>,
     pub description: Option<String>,
     pub attribution: Option<String>,
+    /// Absolute URL of the `TileJSON`, added when the catalog is served
+    pub tilejson: Option<String>,
+    /// Absolute URL template of the tiles, added when the catalog is served
+    pub tiles: Option<String>,
+    /// Absolute URL of a generated `MapLibre` style to preview the source, added when the catalog is served
+    pub style: Option<String>,
+    /// Health of the source, added when the catalog is served unless the source is `ok`
+    pub health: Option<crate::srv::SourceHealth>,
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use futures::future::join_all;
+    use martin_tile_utils::{Encoding, Format};
+    use tilejson::tilejson;
+
     use super::*;
 
     #[test]
@@ -165,6 +905,266 @@ mod tests {
         assert_eq!(format!("{xyz}"), "1,2,3");
         assert_eq!(format!("{xyz:#}"), "1/2/3");
     }
+
+    #[derive(Debug, Clone)]
+    struct SlowSource {
+        id: &'static str,
+        tj: TileJSON,
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Source for SlowSource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(&self, _: TileCoord, _: Option<&UrlQuery>) -> MartinResult<TileData> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(TileData::new())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn concurrency_limit() {
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let mut sources = TileSources::new(vec![vec![Box::new(SlowSource {
+            id: "slow",
+            tj: tilejson! { tiles: vec![] },
+            running: Arc::new(AtomicUsize::new(0)),
+            max_running: max_running.clone(),
+        })]]);
+        assert!(sources.limit_concurrency("slow", NonZeroUsize::new(2).unwrap()));
+        assert!(!sources.limit_concurrency("missing", NonZeroUsize::new(2).unwrap()));
+
+        let src = sources.get_source("slow").unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let results = join_all((0..6).map(|_| src.get_tile(xyz, None))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn timeout() {
+        let new_source = |id| -> Box<dyn Source> {
+            Box::new(SlowSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                running: Arc::new(AtomicUsize::new(0)),
+                max_running: Arc::new(AtomicUsize::new(0)),
+            })
+        };
+        let mut sources = TileSources::new(vec![vec![new_source("fast"), new_source("slow")]]);
+        assert!(sources.add_timeout("fast", Duration::from_secs(10)));
+        assert!(sources.add_timeout("slow", Duration::from_millis(1)));
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let fast = sources.get_source("fast").unwrap();
+        assert!(fast.get_tile(xyz, None).await.is_ok());
+        let slow = sources.get_source("slow").unwrap();
+        assert!(matches!(
+            slow.get_tile(xyz, None).await,
+            Err(SourceTimeout(id, _)) if id == "slow"
+        ));
+    }
+
+    #[derive(Debug, Clone)]
+    struct FlakySource {
+        id: &'static str,
+        tj: TileJSON,
+        fail: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Source for FlakySource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(&self, _: TileCoord, _: Option<&UrlQuery>) -> MartinResult<TileData> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fail.load(Ordering::SeqCst) {
+                Err(InternalError("failed".into()))
+            } else {
+                Ok(TileData::new())
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn circuit_breaker() {
+        let fail = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut sources = TileSources::new(vec![vec![Box::new(FlakySource {
+            id: "flaky",
+            tj: tilejson! { tiles: vec![] },
+            fail: fail.clone(),
+            calls: calls.clone(),
+        })]]);
+        let cooldown = Duration::from_millis(20);
+        assert!(sources.add_circuit_breaker("flaky", NonZeroU32::new(2).unwrap(), cooldown));
+
+        let src = sources.get_source("flaky").unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        assert!(matches!(
+            src.get_tile(xyz, None).await,
+            Err(InternalError(_))
+        ));
+        assert!(matches!(
+            src.get_tile(xyz, None).await,
+            Err(InternalError(_))
+        ));
+        // the circuit is open, the source is not called
+        assert!(matches!(
+            src.get_tile(xyz, None).await,
+            Err(SourceUnavailable(id, _)) if id == "flaky"
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // once the cooldown expires, the source is tried again
+        fail.store(false, Ordering::SeqCst);
+        actix_rt::time::sleep(cooldown).await;
+        assert!(src.get_tile(xyz, None).await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    /// Returns the same tile for all coordinates, or fails if there is no tile
+    #[derive(Debug, Clone)]
+    struct FixedSource {
+        id: &'static str,
+        tj: TileJSON,
+        encoding: Encoding,
+        tile: Option<TileData>,
+        version: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl Source for FixedSource {
+        fn get_id(&self) -> &str {
+            self.id
+        }
+
+        fn get_tilejson(&self) -> &TileJSON {
+            &self.tj
+        }
+
+        fn get_tile_info(&self) -> TileInfo {
+            TileInfo::new(Format::Mvt, self.encoding)
+        }
+
+        fn clone_source(&self) -> Box<dyn Source> {
+            Box::new(self.clone())
+        }
+
+        async fn get_tile(&self, _: TileCoord, _: Option<&UrlQuery>) -> MartinResult<TileData> {
+            self.tile.clone().ok_or(InternalError("failed".into()))
+        }
+
+        async fn get_data_version(&self) -> MartinResult<Option<String>> {
+            Ok(self.version.map(ToString::to_string))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fallback() {
+        let source = |id, encoding, tile: Option<&[u8]>| -> Box<dyn Source> {
+            Box::new(FixedSource {
+                id,
+                tj: tilejson! { tiles: vec![], minzoom: 0, maxzoom: 10 },
+                encoding,
+                tile: tile.map(TileData::copy_from_slice),
+                version: None,
+            })
+        };
+        let gzipped = crate::utils::encode_gzip(b"backup").unwrap();
+        let mut sources = TileSources::new(vec![vec![
+            source("failing", Encoding::Uncompressed, None),
+            source("empty", Encoding::Uncompressed, Some(b"")),
+            source("backup", Encoding::Gzip, Some(&gzipped)),
+        ]]);
+        let chain = |ids: &[&str]| ids.iter().map(ToString::to_string).collect::<Vec<_>>();
+        sources
+            .add_fallback("a", &chain(&["failing", "backup"]))
+            .unwrap();
+        sources
+            .add_fallback("b", &chain(&["failing", "empty"]))
+            .unwrap();
+        sources.add_fallback("c", &chain(&["failing"])).unwrap();
+        assert!(sources.add_fallback("d", &chain(&["missing"])).is_err());
+        assert!(sources.add_fallback("a", &chain(&["backup"])).is_err());
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        // the tiles are decompressed, because the sources have different encodings
+        let src = sources.get_source("a").unwrap();
+        assert_eq!(src.get_tile_info().encoding, Encoding::Uncompressed);
+        assert_eq!(src.get_tile(xyz, None).await.unwrap(), &b"backup"[..]);
+        // an empty tile is a valid answer, even if another source failed
+        let src = sources.get_source("b").unwrap();
+        assert!(src.get_tile(xyz, None).await.unwrap().is_empty());
+        let src = sources.get_source("c").unwrap();
+        assert!(src.get_tile(xyz, None).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn fallback_data_version() {
+        let source = |id, version| -> Box<dyn Source> {
+            Box::new(FixedSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                encoding: Encoding::Uncompressed,
+                tile: None,
+                version,
+            })
+        };
+        let mut sources = TileSources::new(vec![vec![
+            source("none", None),
+            source("v1", Some("1")),
+            source("v2", Some("2")),
+        ]]);
+        let chain = |ids: &[&str]| ids.iter().map(ToString::to_string).collect::<Vec<_>>();
+        sources
+            .add_fallback("a", &chain(&["none", "v1", "v2"]))
+            .unwrap();
+        sources.add_fallback("b", &chain(&["none"])).unwrap();
+
+        // a new version of any of the sources changes the version of the fallback
+        let src = sources.get_source("a").unwrap();
+        assert_eq!(
+            src.get_data_version().await.unwrap().as_deref(),
+            Some(",1,2")
+        );
+        let src = sources.get_source("b").unwrap();
+        assert_eq!(src.get_data_version().await.unwrap(), None);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -175,7 +1175,10 @@ pub struct Tile {
 
 impl Tile {
     #[must_use]
-    pub fn new(data: TileData, info: TileInfo) -> Self {
-        Self { data, info }
+    pub fn new(data: impl Into<TileData>, info: TileInfo) -> Self {
+        Self {
+            data: data.into(),
+            info,
+        }
     }
 }
@@ -0,0 +1,404 @@
+//! IP address based access rules for each class of endpoints, e.g. to only allow the admin endpoints
+//! from a private network while serving tiles to anyone. Behind reverse proxies, the client address
+//! is taken from the `X-Forwarded-For` header, but only if the request comes from a trusted proxy.
+
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use ipnet::IpNet;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct AccessConfig {
+    /// Networks of the reverse proxies whose `X-Forwarded-For` header is trusted.
+    /// The client address is the last address of the header that is not a trusted proxy.
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    /// Tiles, including batch requests and `UTFGrid`
+    pub tiles: Option<AccessRule>,
+    pub sprites: Option<AccessRule>,
+    pub fonts: Option<AccessRule>,
+    /// All other routes of the main server, e.g. the catalog and `TileJSON`
+    pub metadata: Option<AccessRule>,
+    /// The endpoints of the admin server, and `/refresh`, `/{source_ids}/purge`, `/metrics`,
+    /// and `/debug/runtime` of the main server
+    pub admin: Option<AccessRule>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct AccessRule {
+    /// Only allow clients from these networks [default: any client]
+    pub allow: Option<Vec<IpNet>>,
+    /// Reject clients from these networks, even if they are allowed
+    pub deny: Option<Vec<IpNet>>,
+}
+
+impl AccessRule {
+    #[must_use]
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let contains = |nets: &Vec<IpNet>| nets.iter().any(|net| net.contains(&ip));
+        !self.deny.as_ref().is_some_and(contains) && self.allow.as_ref().map_or(true, contains)
+    }
+}
+
+/// Class of endpoints with their own access rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointClass {
+    Tiles,
+    Sprites,
+    Fonts,
+    Metadata,
+    Admin,
+}
+
+impl EndpointClass {
+    /// Classify a request of the main server by its method and percent-decoded path, before it is routed.
+    #[must_use]
+    pub fn from_route(method: &Method, path: &str) -> Self {
+        let segments: Vec<_> = path.split('/').filter(|v| !v.is_empty()).collect();
+        let is_number = |v: &str| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit());
+        // tiles end with the z/x/y coordinates, and y may have an extension, e.g. `.grid.json`
+        let is_tile = match segments.as_slice() {
+            [.., _, z, x, y] => {
+                is_number(z) && is_number(x) && y.split('.').next().is_some_and(is_number)
+            }
+            _ => false,
+        };
+        if Self::is_admin(method, &segments) {
+            Self::Admin
+        } else if segments.contains(&"sprite") {
+            Self::Sprites
+        } else if segments.contains(&"font") {
+            Self::Fonts
+        } else if is_tile || segments.last() == Some(&"tiles") {
+            Self::Tiles
+        } else {
+            Self::Metadata
+        }
+    }
+
+    /// Admin endpoints are only routed at these exact positions, anywhere else the same names are sources,
+    /// e.g. `GET /refresh` or `/v1/metrics` is the `TileJSON` of a source.
+    /// Tenants have no admin endpoints.
+    fn is_admin(method: &Method, segments: &[&str]) -> bool {
+        match segments {
+            ["t", ..] => false,
+            ["v1", rest @ ..] => {
+                *method == Method::POST && matches!(rest, ["refresh"] | [_, "purge"])
+            }
+            ["metrics"] => cfg!(feature = "metrics"),
+            ["debug", "runtime"] => cfg!(feature = "console"),
+            _ => *method == Method::POST && matches!(segments, ["refresh"] | [_, "purge"]),
+        }
+    }
+}
+
+impl AccessConfig {
+    #[must_use]
+    pub fn rule(&self, class: EndpointClass) -> Option<&AccessRule> {
+        match class {
+            EndpointClass::Tiles => self.tiles.as_ref(),
+            EndpointClass::Sprites => self.sprites.as_ref(),
+            EndpointClass::Fonts => self.fonts.as_ref(),
+            EndpointClass::Metadata => self.metadata.as_ref(),
+            EndpointClass::Admin => self.admin.as_ref(),
+        }
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .flatten()
+            .any(|net| net.contains(&ip))
+    }
+
+    /// True if the request was sent by a trusted proxy, whose headers about the client can be believed
+    #[must_use]
+    pub fn is_from_trusted_proxy(&self, req: &HttpRequest) -> bool {
+        req.peer_addr()
+            .is_some_and(|addr| self.is_trusted_proxy(addr.ip()))
+    }
+
+    /// The address of the client. Without trusted proxies, it is the address of the connection.
+    #[must_use]
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let mut ip = req.peer_addr()?.ip();
+        if !self.is_trusted_proxy(ip) {
+            return Some(ip);
+        }
+        let forwarded = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>();
+        // each proxy appends the address it got the request from, so only the end of the list is trustworthy
+        for addr in forwarded.iter().rev() {
+            match addr.trim().parse() {
+                Ok(addr) => {
+                    ip = addr;
+                    if !self.is_trusted_proxy(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Some(ip)
+    }
+}
+
+/// Middleware rejecting requests not allowed by the access rules with `403 Forbidden`
+#[derive(Clone, Debug)]
+pub struct AccessControl {
+    config: Arc<AccessConfig>,
+    /// Use this class for all requests instead of classifying them by path, e.g. for the admin server
+    class: Option<EndpointClass>,
+}
+
+impl AccessControl {
+    #[must_use]
+    pub fn new(config: AccessConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            class: None,
+        }
+    }
+
+    #[must_use]
+    pub fn admin(config: AccessConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            class: Some(EndpointClass::Admin),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessControl
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AccessControlMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessControlMiddleware {
+            service: Rc::new(service),
+            access: self.clone(),
+        }))
+    }
+}
+
+pub struct AccessControlMiddleware<S> {
+    service: Rc<S>,
+    access: AccessControl,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessControlMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // classify the percent-decoded path the router uses, not the raw one,
+        // so that e.g. `/%72efresh` cannot slip past the admin rule
+        let path = req.match_info().as_str();
+        let class = self
+            .access
+            .class
+            .unwrap_or_else(|| EndpointClass::from_route(req.method(), path));
+        if let Some(rule) = self.access.config.rule(class) {
+            let ip = self.access.config.client_ip(req.request());
+            if !ip.is_some_and(|ip| rule.is_allowed(ip)) {
+                debug!("Denied access to {path} for client {ip:?}");
+                let response = HttpResponse::Forbidden().body("Access denied");
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use indoc::indoc;
+
+    use super::*;
+
+    fn config() -> AccessConfig {
+        serde_yaml::from_str(indoc! {"
+            trusted_proxies: [10.0.0.1/32]
+            tiles:
+              deny: [192.168.1.0/24]
+            metadata:
+              allow: [10.0.0.0/8, 192.168.0.0/16]
+        "})
+        .unwrap()
+    }
+
+    #[test]
+    fn test_endpoint_class() {
+        use EndpointClass::{Admin, Fonts, Metadata, Sprites, Tiles};
+        let get = |path| EndpointClass::from_route(&Method::GET, path);
+        let post = |path| EndpointClass::from_route(&Method::POST, path);
+        assert_eq!(get("/src/1/2/3"), Tiles);
+        assert_eq!(get("/v1/a,b/1/2/3"), Tiles);
+        assert_eq!(get("/t/acme/src/1/2/3.grid.json"), Tiles);
+        assert_eq!(get("/rpc/fnc/1/2/3.pbf"), Tiles);
+        assert_eq!(get("/src/tiles"), Tiles);
+        assert_eq!(get("/sprite/src.png"), Sprites);
+        assert_eq!(get("/font/Arial/0-255"), Fonts);
+        assert_eq!(get("/src"), Metadata);
+        assert_eq!(get("/catalog"), Metadata);
+        assert_eq!(get("/1/2/3"), Metadata);
+        for prefix in ["", "/v1"] {
+            let class = |path| EndpointClass::from_route(&Method::POST, &format!("{prefix}{path}"));
+            assert_eq!(class("/refresh"), Admin, "{prefix}");
+            assert_eq!(class("/a,b/purge"), Admin, "{prefix}");
+        }
+        let admin_if = |enabled| if enabled { Admin } else { Metadata };
+        assert_eq!(get("/metrics"), admin_if(cfg!(feature = "metrics")));
+        assert_eq!(get("/debug/runtime"), admin_if(cfg!(feature = "console")));
+
+        // sources may be called like the admin endpoints
+        for path in ["/refresh", "/purge", "/v1/refresh", "/src/purge"] {
+            assert_eq!(get(path), Metadata, "{path}");
+        }
+        for path in [
+            "/v1/metrics",
+            "/t/acme/metrics",
+            "/metrics/purge/x",
+            "/src/metrics",
+        ] {
+            assert_eq!(get(path), Metadata, "{path}");
+        }
+        assert_eq!(get("/metrics/1/2/3"), Tiles);
+        assert_eq!(get("/v1/debug/runtime"), Metadata);
+        // tenants have no admin endpoints
+        for path in ["/t/acme/refresh", "/t/acme/src/purge", "/t/acme/v1/refresh"] {
+            assert_eq!(post(path), Metadata, "{path}");
+        }
+        assert_eq!(post("/purge"), Metadata);
+        assert_eq!(post("/a/b/purge"), Metadata);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let cfg = config();
+        let req = |peer: &str, forwarded: &str| {
+            TestRequest::default()
+                .peer_addr(SocketAddr::new(peer.parse().unwrap(), 1234))
+                .insert_header(("X-Forwarded-For", forwarded))
+                .to_http_request()
+        };
+        let ip = |v: &str| Some(v.parse::<IpAddr>().unwrap());
+        // untrusted peers cannot pretend to be someone else
+        assert_eq!(cfg.client_ip(&req("1.2.3.4", "10.0.0.5")), ip("1.2.3.4"));
+        assert_eq!(cfg.client_ip(&req("10.0.0.1", "10.0.0.5")), ip("10.0.0.5"));
+        assert_eq!(
+            cfg.client_ip(&req("10.0.0.1", "1.1.1.1, 10.0.0.5, 10.0.0.1")),
+            ip("10.0.0.5")
+        );
+        assert_eq!(cfg.client_ip(&req("10.0.0.1", "invalid")), ip("10.0.0.1"));
+    }
+
+    #[actix_rt::test]
+    async fn test_access_control() {
+        let app = init_service(
+            App::new()
+                .wrap(AccessControl::new(config()))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |path: &str, peer: &str| {
+            let req = TestRequest::get()
+                .uri(path)
+                .peer_addr(SocketAddr::new(peer.parse().unwrap(), 1234))
+                .to_request();
+            let app = &app;
+            async move { call_service(app, req).await.status() }
+        };
+        assert_eq!(status("/src/0/0/0", "1.2.3.4").await, StatusCode::OK);
+        assert_eq!(
+            status("/src/0/0/0", "192.168.1.5").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status("/catalog", "192.168.1.5").await, StatusCode::OK);
+        assert_eq!(status("/catalog", "1.2.3.4").await, StatusCode::FORBIDDEN);
+        // no rule for sprites
+        assert_eq!(status("/sprite/src.json", "1.2.3.4").await, StatusCode::OK);
+
+        let app = init_service(
+            App::new()
+                .wrap(AccessControl::new(AccessConfig {
+                    admin: Some(AccessRule {
+                        allow: Some(vec!["127.0.0.1/32".parse().unwrap()]),
+                        deny: None,
+                    }),
+                    ..Default::default()
+                }))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |path: &str| {
+            let req = TestRequest::post()
+                .uri(path)
+                .peer_addr("1.2.3.4:1234".parse().unwrap())
+                .to_request();
+            let app = &app;
+            async move { call_service(app, req).await.status() }
+        };
+        // percent-encoded paths are routed like the decoded ones
+        for path in ["/refresh", "/%72efresh", "/src/%70urge", "/%76%31/refresh"] {
+            assert_eq!(status(path).await, StatusCode::FORBIDDEN, "{path}");
+        }
+        assert_eq!(status("/%73rc/0/0/0").await, StatusCode::OK);
+
+        let app = init_service(
+            App::new()
+                .wrap(AccessControl::admin(AccessConfig {
+                    admin: Some(AccessRule {
+                        allow: Some(vec!["127.0.0.1/32".parse().unwrap()]),
+                        deny: None,
+                    }),
+                    ..Default::default()
+                }))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/src/0/0/0")
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
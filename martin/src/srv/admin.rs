@@ -0,0 +1,287 @@
+//! Administrative endpoints, served on their own socket address that should never be exposed publicly.
+
+use actix_web::dev::Server;
+use std::sync::Arc;
+
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::ContentType;
+use actix_web::middleware::Logger;
+use actix_web::{route, web, App, HttpRequest, HttpResponse, HttpServer, Result as ActixResult};
+
+use crate::srv::problem::problem_json;
+use crate::srv::{
+    AccessConfig, AccessControl, AuditAction, AuditConfig, AuditRecord, ErrorDetail, UsageRecorder,
+};
+use crate::utils::{get_log_level, set_log_level};
+use crate::MartinError::BindingError;
+use crate::MartinResult;
+
+/// Get the log filter in use
+#[route("/admin/log-level", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_admin_log_level() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(get_log_level())
+}
+
+/// Replace the log filter with the one in the request body, e.g. `info,martin::pg=debug`
+#[route("/admin/log-level", method = "PUT")]
+async fn put_admin_log_level(
+    req: HttpRequest,
+    audit: Option<web::Data<AdminAudit>>,
+    body: String,
+) -> ActixResult<HttpResponse> {
+    let before = get_log_level();
+    let result = set_log_level(Some(body.trim()));
+    AdminAudit::write(
+        audit.as_ref().map(web::Data::get_ref),
+        &req,
+        AuditAction::SetLogLevel,
+        |record| AuditRecord {
+            before: Some(before.into()),
+            after: result.as_ref().ok().map(|v| v.clone().into()),
+            ..record.with_result(&result)
+        },
+    )
+    .await;
+    let level = result.map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(level))
+}
+
+/// Restore the log filter set with `RUST_LOG` or in the config file
+#[route("/admin/log-level", method = "DELETE")]
+async fn delete_admin_log_level(
+    req: HttpRequest,
+    audit: Option<web::Data<AdminAudit>>,
+) -> ActixResult<HttpResponse> {
+    let before = get_log_level();
+    let result = set_log_level(None);
+    AdminAudit::write(
+        audit.as_ref().map(web::Data::get_ref),
+        &req,
+        AuditAction::ResetLogLevel,
+        |record| AuditRecord {
+            before: Some(before.into()),
+            after: result.as_ref().ok().map(|v| v.clone().into()),
+            ..record.with_result(&result)
+        },
+    )
+    .await;
+    let level = result.map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(level))
+}
+
+/// Progress of the table bounds computed in the background, by source ID
+#[cfg(feature = "postgres")]
+#[route("/admin/bounds-status", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_admin_bounds_status() -> HttpResponse {
+    HttpResponse::Ok().json(crate::pg::bounds_statuses())
+}
+
+#[cfg(feature = "postgres")]
+#[derive(serde::Deserialize)]
+struct RecomputeBoundsQuery {
+    method: Option<crate::pg::BoundsMethod>,
+}
+
+/// Recompute the bounds of a table source, e.g. after a large data load, and update its `TileJSON`
+#[cfg(feature = "postgres")]
+#[route("/admin/{source_id}/recompute-bounds", method = "POST")]
+async fn post_admin_recompute_bounds(
+    req: HttpRequest,
+    audit: Option<web::Data<AdminAudit>>,
+    path: web::Path<String>,
+    query: web::Query<RecomputeBoundsQuery>,
+) -> ActixResult<HttpResponse> {
+    let source_id = path.into_inner();
+    let method = query.method.unwrap_or(crate::pg::BoundsMethod::Exact);
+    let result = crate::pg::recompute_bounds(&source_id, method).await;
+    AdminAudit::write(
+        audit.as_ref().map(web::Data::get_ref),
+        &req,
+        AuditAction::RecomputeBounds,
+        |record| AuditRecord {
+            sources: Some(source_id.clone()),
+            after: result
+                .as_ref()
+                .ok()
+                .and_then(|status| serde_json::to_value(status).ok()),
+            ..record.with_result(&result)
+        },
+    )
+    .await;
+    match result {
+        Ok(Some(status)) => Ok(HttpResponse::Ok().json(status)),
+        Ok(None) => Err(ErrorNotFound(format!(
+            "Table source {source_id} does not exist"
+        ))),
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e.to_string())),
+    }
+}
+
+/// Tiles and bytes served to each API key by source since the start of the server
+#[route("/admin/usage", method = "GET")]
+#[allow(clippy::unused_async)]
+async fn get_admin_usage(
+    usage: web::Data<Option<Arc<UsageRecorder>>>,
+) -> ActixResult<HttpResponse> {
+    let recorder = usage
+        .as_ref()
+        .as_ref()
+        .ok_or_else(|| ErrorNotFound("Usage analytics are not enabled"))?;
+    Ok(HttpResponse::Ok().json(recorder.totals()))
+}
+
+pub fn admin_router(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_admin_log_level)
+        .service(put_admin_log_level)
+        .service(delete_admin_log_level)
+        .service(get_admin_usage);
+    #[cfg(feature = "postgres")]
+    cfg.service(get_admin_bounds_status)
+        .service(post_admin_recompute_bounds);
+}
+
+/// The audit log of the admin endpoints, if enabled
+struct AdminAudit {
+    audit: Option<AuditConfig>,
+    access: AccessConfig,
+}
+
+impl AdminAudit {
+    /// Write the record of an action, if the audit log is enabled.
+    /// The admin router may be used without it, e.g. in tests.
+    async fn write(
+        admin_audit: Option<&Self>,
+        req: &HttpRequest,
+        action: AuditAction,
+        record: impl FnOnce(AuditRecord) -> AuditRecord,
+    ) {
+        let Some(Self {
+            audit: Some(audit),
+            access,
+        }) = admin_audit
+        else {
+            return;
+        };
+        audit
+            .write(&record(audit.record(req, Some(access), action)))
+            .await;
+    }
+}
+
+/// Create a single-worker server for the admin endpoints
+pub fn new_admin_server(
+    address: &str,
+    access: AccessConfig,
+    usage: Option<Arc<UsageRecorder>>,
+    audit: Option<AuditConfig>,
+) -> MartinResult<Server> {
+    let admin_audit = web::Data::new(AdminAudit {
+        audit,
+        access: access.clone(),
+    });
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(usage.clone()))
+            .app_data(admin_audit.clone())
+            .wrap(AccessControl::admin(access.clone()))
+            .wrap(problem_json(ErrorDetail::Full))
+            .wrap(Logger::default())
+            .configure(admin_router)
+    })
+    .workers(1)
+    .bind(address)
+    .map_err(|e| BindingError(e, address.to_string()))?;
+    Ok(server.run())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_log_level() {
+        let app = init_service(App::new().configure(admin_router)).await;
+
+        let req = TestRequest::put()
+            .uri("/admin/log-level")
+            .set_payload("info,martin::pg=debug")
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert_eq!(body, "info,martin::pg=debug");
+        let req = TestRequest::get().uri("/admin/log-level").to_request();
+        assert_eq!(call_and_read_body(&app, req).await, "info,martin::pg=debug");
+
+        let req = TestRequest::put()
+            .uri("/admin/log-level")
+            .set_payload("martin=loud")
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::delete().uri("/admin/log-level").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let req = TestRequest::get().uri("/admin/log-level").to_request();
+        assert_ne!(call_and_read_body(&app, req).await, "info,martin::pg=debug");
+    }
+
+    #[actix_rt::test]
+    async fn test_usage() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(None::<Arc<UsageRecorder>>))
+                .configure(admin_router),
+        )
+        .await;
+        let req = TestRequest::get().uri("/admin/usage").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let recorder = UsageRecorder::new(&crate::srv::UsageConfig::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Some(Arc::new(recorder))))
+                .configure(admin_router),
+        )
+        .await;
+        let req = TestRequest::get().uri("/admin/usage").to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["keys"], serde_json::json!({}));
+        assert!(body["since"].is_string());
+    }
+
+    #[cfg(feature = "postgres")]
+    #[actix_rt::test]
+    async fn test_bounds_status() {
+        let app = init_service(App::new().configure(admin_router)).await;
+        let req = TestRequest::get().uri("/admin/bounds-status").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+    }
+
+    #[cfg(feature = "postgres")]
+    #[actix_rt::test]
+    async fn test_recompute_unknown_source() {
+        let app = init_service(App::new().configure(admin_router)).await;
+        let req = TestRequest::post()
+            .uri("/admin/no_such_source/recompute-bounds?method=estimated")
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,127 @@
+use actix_web::web::{Data, ServiceConfig};
+use log::warn;
+use tokio::sync::RwLock;
+
+use crate::config::ServerState;
+use crate::source::Source;
+use crate::srv::server::static_router;
+use crate::srv::{Catalog, SrvConfig, TileService};
+use crate::MartinResult;
+
+/// Builder to serve Martin's endpoints from an existing actix-web application,
+/// including custom [`Source`] implementations.
+///
+/// ```
+/// # use actix_web::{web, App};
+/// # use martin::srv::MartinApp;
+/// # fn example(my_source: Box<dyn martin::Source>) -> martin::MartinResult<()> {
+/// let martin = MartinApp::new().add_source(my_source).router()?;
+/// let app = App::new().service(web::scope("/tiles").configure(martin));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The `/refresh` endpoint is not available, as there is no configuration file to re-read.
+/// Middleware like CORS or rate limiting is left to the host application.
+#[derive(Default)]
+pub struct MartinApp {
+    state: ServerState,
+    config: SrvConfig,
+}
+
+impl MartinApp {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start with the sources of a resolved configuration, see [`crate::Config::resolve`]
+    #[must_use]
+    pub fn with_state(mut self, state: ServerState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Use the server configuration for the HTTP options of the endpoints, e.g. `source_options`.
+    /// The listening and worker settings are ignored.
+    #[must_use]
+    pub fn with_config(mut self, config: SrvConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Add a tile source. A source with the same ID is replaced.
+    #[must_use]
+    pub fn add_source(mut self, source: Box<dyn Source>) -> Self {
+        if self.state.tiles.add_source(source).is_some() {
+            warn!("Replaced an existing tile source with a custom source with the same ID");
+        }
+        self
+    }
+
+    /// Create a function that configures all the endpoints and their data,
+    /// to be used with [`actix_web::App::configure`] or [`actix_web::Scope::configure`].
+    pub fn router(self) -> MartinResult<impl Fn(&mut ServiceConfig) + Clone> {
+        let catalog = Catalog::new(&self.state)?.without_hidden(&self.config);
+        let Self { state, config } = self;
+        Ok(move |cfg: &mut ServiceConfig| {
+            cfg.app_data(Data::new(RwLock::new(state.tiles.clone())))
+                .app_data(Data::new(RwLock::new(state.cache.clone())))
+                .app_data(Data::new(RwLock::new(catalog.clone())))
+                .app_data(Data::new(RwLock::new(config.clone())))
+                .app_data(Data::new(RwLock::new(state.clone())));
+
+            #[cfg(feature = "sprites")]
+            cfg.app_data(Data::new(RwLock::new(state.sprites.clone())));
+
+            #[cfg(feature = "fonts")]
+            cfg.app_data(Data::new(RwLock::new(state.fonts.clone())));
+
+            static_router(cfg);
+        })
+    }
+
+    /// Create a framework-independent [`TileService`] with the tile, `TileJSON`, and catalog endpoints.
+    /// With the `tower` feature, it can be mounted directly into an axum or hyper application.
+    pub fn service(self) -> MartinResult<TileService> {
+        TileService::new(self.state, self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::TileData;
+
+    #[actix_rt::test]
+    async fn test_martin_app() {
+        let router = MartinApp::new()
+            .add_source(Box::new(TestSource {
+                id: "custom",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }))
+            .router()
+            .unwrap();
+        let app = init_service(App::new().service(web::scope("/tiles").configure(router))).await;
+
+        let req = TestRequest::get().uri("/tiles/custom/0/0/0").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, vec![1_u8, 2, 3]);
+
+        let req = TestRequest::get().uri("/tiles/catalog").to_request();
+        let catalog: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert!(catalog["tiles"]["custom"].is_object());
+
+        let req = TestRequest::post().uri("/tiles/refresh").to_request();
+        let response = call_service(&app, req).await;
+        assert!(response.status().is_client_error());
+    }
+}
@@ -0,0 +1,233 @@
+//! Audit log of the administrative actions, e.g. refreshes, purges, and config file reloads,
+//! kept separately from the access log as one JSON object per line, as required in regulated environments.
+
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use actix_web::HttpRequest;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use crate::config::ServerState;
+use crate::srv::{AccessConfig, CatalogDiff};
+
+pub const AUDIT_ACTOR_HEADER_DEFAULT: &str = "X-Forwarded-User";
+
+/// Actor of the actions that are not requested by a client, i.e. the reloads of a changed config file
+pub const AUDIT_ACTOR_CONFIG_WATCHER: &str = "config-watcher";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// File the audit records are appended to
+    pub path: PathBuf,
+    /// Request header with the identity of the user, e.g. set by an authenticating proxy [default: `X-Forwarded-User`].
+    /// Only used if the request comes from one of the `trusted_proxies` of the access rules.
+    pub actor_header: Option<String>,
+}
+
+impl AuditConfig {
+    /// A record of an action requested by a client, with the actor and the address of the client.
+    /// Any client can send the actor header, so it is ignored unless a trusted proxy sent the request.
+    #[must_use]
+    pub fn record(
+        &self,
+        req: &HttpRequest,
+        access: Option<&AccessConfig>,
+        action: AuditAction,
+    ) -> AuditRecord {
+        let header = self
+            .actor_header
+            .as_deref()
+            .unwrap_or(AUDIT_ACTOR_HEADER_DEFAULT);
+        let mut actor = req
+            .headers()
+            .get(header)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+        if actor.is_some() && !access.is_some_and(|a| a.is_from_trusted_proxy(req)) {
+            debug!("Ignoring the {header} header of a request not sent by a trusted proxy");
+            actor = None;
+        }
+        AuditRecord {
+            actor,
+            client_ip: access
+                .map_or_else(|| req.peer_addr().map(|v| v.ip()), |a| a.client_ip(req))
+                .map(|v| v.to_string()),
+            peer_ip: req.peer_addr().map(|v| v.ip().to_string()),
+            ..AuditRecord::new(action)
+        }
+    }
+
+    /// Append the record to the audit log on a blocking thread. Failures are logged, but do not fail the action,
+    /// which has already been done when it is recorded.
+    pub async fn write(&self, record: &AuditRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line + "\n",
+            Err(e) => {
+                warn!("Unable to serialize the audit record: {e}");
+                return;
+            }
+        };
+        let path = self.path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?
+                .write_all(line.as_bytes())
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|v| v);
+        if let Err(e) = result {
+            warn!("Unable to write the audit log {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    /// `POST /refresh`
+    Refresh,
+    /// Reload of a changed config file with `--config-refresh`
+    Reload,
+    /// `POST /{source_ids}/purge`
+    Purge,
+    /// `PUT /admin/log-level`
+    SetLogLevel,
+    /// `DELETE /admin/log-level`
+    ResetLogLevel,
+    /// `POST /admin/{source_id}/recompute-bounds`
+    RecomputeBounds,
+}
+
+/// A single administrative action, written as a line of the audit log
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub action: AuditAction,
+    /// The user from the actor header, or `config-watcher` for config file reloads
+    pub actor: Option<String>,
+    pub client_ip: Option<String>,
+    /// Address of the connection, i.e. of the proxy if the request was forwarded
+    pub peer_ip: Option<String>,
+    /// Source IDs the action applies to, e.g. `roads,water`
+    pub sources: Option<String>,
+    /// Summary of the state before the action, e.g. the number of sources or the log filter
+    pub before: Option<Value>,
+    /// Summary of the state after the action
+    pub after: Option<Value>,
+    /// The error message if the action failed
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    #[must_use]
+    pub fn new(action: AuditAction) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            action,
+            actor: None,
+            client_ip: None,
+            peer_ip: None,
+            sources: None,
+            before: None,
+            after: None,
+            error: None,
+        }
+    }
+
+    /// Summarize the sources before and after a refresh or a reload, with the changed ones
+    pub fn set_catalog_change(&mut self, old: &ServerState, new: &ServerState, diff: &CatalogDiff) {
+        self.before = Some(catalog_summary(old, None));
+        self.after = Some(catalog_summary(new, Some(diff)));
+    }
+
+    #[must_use]
+    pub fn with_result<T, E: std::fmt::Display>(mut self, result: &Result<T, E>) -> Self {
+        if let Err(e) = result {
+            self.error = Some(e.to_string());
+        }
+        self
+    }
+}
+
+fn catalog_summary(state: &ServerState, diff: Option<&CatalogDiff>) -> Value {
+    let mut summary = json!({
+        "sources": state.tiles.get_catalog().len(),
+        "tenants": state.tenants.len(),
+    });
+    if let Some(diff) = diff {
+        summary["changes"] = json!(diff);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_audit_record() {
+        let path = std::env::temp_dir().join("martin_test_audit.log");
+        let _ = std::fs::remove_file(&path);
+        let config = AuditConfig {
+            path,
+            actor_header: Some("X-User".to_string()),
+        };
+        let access: AccessConfig = serde_yaml::from_str("trusted_proxies: [10.0.0.1/32]").unwrap();
+        let req = |peer: &str| {
+            TestRequest::post()
+                .insert_header(("X-User", "alice"))
+                .insert_header(("X-Forwarded-For", "192.168.1.2"))
+                .peer_addr(peer.parse().unwrap())
+                .to_http_request()
+        };
+        let record = AuditRecord {
+            sources: Some("roads".to_string()),
+            ..config.record(&req("10.0.0.1:1234"), Some(&access), AuditAction::Purge)
+        };
+        config.write(&record).await;
+        config
+            .write(&config.record(&req("10.0.0.2:1234"), Some(&access), AuditAction::Refresh))
+            .await;
+        config
+            .write(&config.record(&req("10.0.0.1:1234"), None, AuditAction::Refresh))
+            .await;
+        config
+            .write(
+                &AuditRecord::new(AuditAction::Reload).with_result(&Err::<(), _>("invalid config")),
+            )
+            .await;
+
+        let log = std::fs::read_to_string(&config.path).unwrap();
+        let lines: Vec<Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["action"], "purge");
+        assert_eq!(lines[0]["actor"], "alice");
+        assert_eq!(lines[0]["client_ip"], "192.168.1.2");
+        assert_eq!(lines[0]["peer_ip"], "10.0.0.1");
+        assert_eq!(lines[0]["sources"], "roads");
+        assert!(lines[0].get("error").is_none());
+        // the actor header is only trusted from the trusted proxies
+        assert!(lines[1].get("actor").is_none());
+        assert_eq!(lines[1]["client_ip"], "10.0.0.2");
+        assert_eq!(lines[1]["peer_ip"], "10.0.0.2");
+        assert!(lines[2].get("actor").is_none());
+        assert_eq!(lines[3]["action"], "reload");
+        assert_eq!(lines[3]["error"], "invalid config");
+        assert!(lines[3].get("actor").is_none());
+    }
+}
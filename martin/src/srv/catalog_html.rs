@@ -0,0 +1,145 @@
+use std::fmt::Write as _;
+
+use crate::srv::Catalog;
+
+/// Render the catalog as a simple human-readable HTML page, with links to the `TileJSON`
+/// and to a `MapLibre` style previewing each source.
+/// Links are relative to the `/catalog` path, so they keep working behind a reverse proxy.
+#[must_use]
+pub fn catalog_to_html(catalog: &Catalog) -> String {
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n",
+        "<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Martin Catalog</title>\n",
+        "<style>body{font-family:sans-serif}table{border-collapse:collapse}",
+        "th,td{border:1px solid #ccc;padding:4px 8px;text-align:left}</style>\n",
+        "</head>\n<body>\n<h1>Martin Catalog</h1>\n",
+    ));
+
+    html.push_str("<h2>Tile Sources</h2>\n");
+    if catalog.tiles.is_empty() {
+        html.push_str("<p>No tile sources</p>\n");
+    } else {
+        html.push_str("<table>\n<tr><th>ID</th><th>Name</th><th>Content Type</th><th>Encoding</th><th>Description</th><th>Attribution</th><th>Preview</th></tr>\n");
+        for (id, entry) in &catalog.tiles {
+            let href = escape(&encode_segment(id));
+            let id = escape(id);
+            let _ = writeln!(
+                html,
+                "<tr><td><a href=\"./{href}\">{id}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td><a href=\"./style/{href}\">style</a></td></tr>",
+                escape_opt(entry.name.as_deref()),
+                escape(&entry.content_type),
+                escape_opt(entry.content_encoding.as_deref()),
+                escape_opt(entry.description.as_deref()),
+                escape_opt(entry.attribution.as_deref()),
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    #[cfg(feature = "sprites")]
+    {
+        html.push_str("<h2>Sprites</h2>\n");
+        if catalog.sprites.is_empty() {
+            html.push_str("<p>No sprite sources</p>\n");
+        } else {
+            html.push_str("<table>\n<tr><th>ID</th><th>Images</th></tr>\n");
+            for (id, entry) in &catalog.sprites {
+                let href = escape(&encode_segment(id));
+                let id = escape(id);
+                let _ = writeln!(
+                    html,
+                    "<tr><td><a href=\"./sprite/{href}.json\">{id}</a></td><td>{}</td></tr>",
+                    escape(&entry.images.join(", ")),
+                );
+            }
+            html.push_str("</table>\n");
+        }
+    }
+
+    #[cfg(feature = "fonts")]
+    {
+        html.push_str("<h2>Fonts</h2>\n");
+        if catalog.fonts.is_empty() {
+            html.push_str("<p>No font sources</p>\n");
+        } else {
+            html.push_str(
+                "<table>\n<tr><th>Name</th><th>Family</th><th>Style</th><th>Glyphs</th></tr>\n",
+            );
+            for (id, entry) in &catalog.fonts {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    escape(id),
+                    escape(&entry.family),
+                    escape_opt(entry.style.as_deref()),
+                    entry.glyphs,
+                );
+            }
+            html.push_str("</table>\n");
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Percent-encode a path segment, so that IDs with characters like `?`, `#`, or `:` make valid links
+pub(crate) fn encode_segment(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            result.push(char::from(byte));
+        } else {
+            let _ = write!(result, "%{byte:02X}");
+        }
+    }
+    result
+}
+
+fn escape_opt(value: Option<&str>) -> String {
+    value.map(escape).unwrap_or_default()
+}
+
+fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CatalogSourceEntry;
+
+    #[test]
+    fn test_catalog_to_html() {
+        let mut catalog = Catalog::default();
+        catalog.tiles.insert(
+            "src".to_string(),
+            CatalogSourceEntry {
+                content_type: "application/x-protobuf".to_string(),
+                name: Some("<b>Name</b>".to_string()),
+                ..Default::default()
+            },
+        );
+        catalog
+            .tiles
+            .insert("a b?c#d:e".to_string(), CatalogSourceEntry::default());
+        let html = catalog_to_html(&catalog);
+        assert!(html.contains("<a href=\"./src\">src</a>"));
+        assert!(html.contains("<a href=\"./style/src\">style</a>"));
+        assert!(html.contains("<a href=\"./a%20b%3Fc%23d%3Ae\">a b?c#d:e</a>"));
+        assert!(html.contains("<a href=\"./style/a%20b%3Fc%23d%3Ae\">style</a>"));
+        assert!(html.contains("&lt;b&gt;Name&lt;/b&gt;"));
+        assert!(!html.contains("<b>Name</b>"));
+    }
+}
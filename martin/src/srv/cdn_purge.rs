@@ -0,0 +1,229 @@
+//! Purging the tiles of changed sources from CDNs that support tag-based purging,
+//! so that edge caches are invalidated together with the data.
+
+use actix_web::web::{Data, Path};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::RwLock;
+
+use crate::srv::{AuditAction, AuditRecord, SourceIDsRequest, SrvConfig};
+use crate::utils::{invalidate_sources, OptMainCache};
+use crate::TileSources;
+
+const FASTLY_API_URL: &str = "https://api.fastly.com";
+const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// A CDN API to purge the cached tiles of a source with, using its surrogate key
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum CdnPurgeConfig {
+    Fastly {
+        service_id: String,
+        api_token: String,
+    },
+    Cloudflare {
+        zone_id: String,
+        api_token: String,
+    },
+}
+
+impl CdnPurgeConfig {
+    fn request(&self, client: &reqwest::Client, keys: &[String]) -> reqwest::RequestBuilder {
+        match self {
+            Self::Fastly {
+                service_id,
+                api_token,
+            } => client
+                .post(format!("{FASTLY_API_URL}/service/{service_id}/purge"))
+                .header("Fastly-Key", api_token)
+                .json(&json!({ "surrogate_keys": keys })),
+            Self::Cloudflare { zone_id, api_token } => client
+                .post(format!("{CLOUDFLARE_API_URL}/zones/{zone_id}/purge_cache"))
+                .bearer_auth(api_token)
+                .json(&json!({ "tags": keys })),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Fastly { .. } => "Fastly",
+            Self::Cloudflare { .. } => "Cloudflare",
+        }
+    }
+}
+
+/// The surrogate key (cache tag) of all tiles of a source
+#[must_use]
+pub fn source_surrogate_key(source_id: &str) -> String {
+    format!("src:{source_id}")
+}
+
+/// Purge the tiles of the sources from all CDNs in the background.
+/// Failed requests are logged, but not retried.
+pub fn purge_cdn<I: IntoIterator<Item = String>>(cdns: &[CdnPurgeConfig], source_ids: I) {
+    let keys: Vec<_> = source_ids
+        .into_iter()
+        .map(|id| source_surrogate_key(&id))
+        .collect();
+    if cdns.is_empty() || keys.is_empty() {
+        return;
+    }
+    let cdns = cdns.to_vec();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        for cdn in cdns {
+            match cdn
+                .request(&client, &keys)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                Ok(_) => debug!("Purged {} from {}", keys.join(" "), cdn.name()),
+                Err(e) => warn!(
+                    "Unable to purge {} from {}: {e}",
+                    keys.join(" "),
+                    cdn.name()
+                ),
+            }
+        }
+    });
+}
+
+/// Remove the cached tiles of the sources from the main cache, and purge them from the CDNs
+#[route("/{source_ids}/purge", method = "POST")]
+async fn purge_sources(
+    req: HttpRequest,
+    path: Path<SourceIDsRequest>,
+    sources: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> ActixResult<HttpResponse> {
+    let ids: Vec<String> = path
+        .source_ids
+        .split(',')
+        .map(ToString::to_string)
+        .collect();
+    // fail with 404 if any of the sources does not exist
+    sources.read().await.get_sources(&path.source_ids, None)?;
+
+    if let Some(cache) = cache.read().await.as_ref() {
+        if let Err(e) = invalidate_sources(cache, &ids) {
+            warn!(
+                "Unable to remove the tiles of {} from the cache: {e}",
+                path.source_ids
+            );
+        }
+    }
+    let srv_config = srv_config.read().await;
+    // the other replicas drop the tiles from their caches too
+    #[cfg(feature = "redis")]
+    if let Some(cfg) = &srv_config.cache_invalidation {
+        cfg.publish(&crate::srv::Invalidation {
+            sources: ids.clone(),
+            ..Default::default()
+        });
+    }
+    purge_cdn(srv_config.cdn_purge.as_deref().unwrap_or_default(), ids);
+    if let Some(audit) = &srv_config.audit {
+        audit
+            .write(&AuditRecord {
+                sources: Some(path.source_ids.clone()),
+                ..audit.record(&req, srv_config.access.as_ref(), AuditAction::Purge)
+            })
+            .await;
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::utils::{CacheKey, CacheValue, MainCache};
+    use crate::{TileCoord, TileData};
+
+    #[actix_rt::test]
+    async fn test_purge_sources() {
+        let mut sources = TileSources::default();
+        for id in ["roads", "water"] {
+            sources.add_source(Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            }));
+        }
+        let cache = MainCache::builder().support_invalidation_closures().build();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        for id in ["roads", "water"] {
+            let value = CacheValue::Tile(TileData::new(), std::time::Instant::now(), false);
+            cache
+                .insert(CacheKey::Tile(id.to_string(), xyz), value)
+                .await;
+        }
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(RwLock::new(sources)))
+                .app_data(Data::new(RwLock::new(Some(cache.clone()))))
+                .app_data(Data::new(RwLock::new(SrvConfig::default())))
+                .service(purge_sources),
+        )
+        .await;
+
+        let req = TestRequest::post().uri("/roads/purge").to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let key = |id: &str| CacheKey::Tile(id.to_string(), xyz);
+        assert!(cache.get(&key("roads")).await.is_none());
+        assert!(cache.get(&key("water")).await.is_some());
+
+        let req = TestRequest::post().uri("/missing/purge").to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_purge_request() {
+        let client = reqwest::Client::new();
+        let keys = vec![source_surrogate_key("roads"), source_surrogate_key("water")];
+
+        let cdn: CdnPurgeConfig =
+            serde_yaml::from_str("provider: fastly\nservice_id: svc\napi_token: tok").unwrap();
+        let request = cdn.request(&client, &keys).build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.fastly.com/service/svc/purge"
+        );
+        assert_eq!(request.headers()["Fastly-Key"], "tok");
+        let body = request.body().and_then(reqwest::Body::as_bytes).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(body).unwrap(),
+            json!({"surrogate_keys": ["src:roads", "src:water"]})
+        );
+
+        let cdn: CdnPurgeConfig =
+            serde_yaml::from_str("provider: cloudflare\nzone_id: zone\napi_token: tok").unwrap();
+        let request = cdn.request(&client, &keys).build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.cloudflare.com/client/v4/zones/zone/purge_cache"
+        );
+        assert_eq!(request.headers()["Authorization"], "Bearer tok");
+        let body = request.body().and_then(reqwest::Body::as_bytes).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(body).unwrap(),
+            json!({"tags": ["src:roads", "src:water"]})
+        );
+    }
+}
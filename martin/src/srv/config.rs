@@ -1,18 +1,352 @@
+use std::collections::BTreeMap;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::time::Duration;
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
 use crate::args::PreferredEncoding;
+use crate::srv::{
+    AccessConfig, CdnPurgeConfig, CorsConfig, RateLimitConfig, SecurityHeadersConfig, UrlTemplate,
+    UsageConfig, WebhookConfig,
+};
+use crate::utils::cache::TileCachePolicy;
+use crate::utils::{ErrorReportingConfig, LogConfig, MvtPipeline, OptBoolObj};
+use crate::MartinError::{InvalidSourceHeader, InvalidUrlTemplate};
+use crate::MartinResult;
 
 pub const KEEP_ALIVE_DEFAULT: u64 = 75;
+pub const SHUTDOWN_TIMEOUT_DEFAULT: u64 = 30;
 pub const LISTEN_ADDRESSES_DEFAULT: &str = "0.0.0.0:3000";
+pub const CIRCUIT_BREAKER_FAILURES_DEFAULT: u32 = 5;
+pub const CIRCUIT_BREAKER_COOLDOWN_DEFAULT: u64 = 30;
+pub const DISCOVERY_RETRY_INITIAL_DELAY_DEFAULT: u64 = 5;
+pub const DISCOVERY_RETRY_MAX_DELAY_DEFAULT: u64 = 300;
 
 #[serde_with::skip_serializing_none]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct SrvConfig {
     pub keep_alive: Option<u64>,
+    /// How long (in seconds) to wait for in-flight requests to complete after SIGTERM
+    /// before stopping the server [default: 30]
+    pub shutdown_timeout: Option<u64>,
     pub listen_addresses: Option<String>,
+    /// The socket address of the gRPC endpoint, which is disabled if not set
+    #[cfg(feature = "grpc")]
+    pub grpc_listen_address: Option<String>,
+    /// The socket address of the admin endpoints, e.g. `127.0.0.1:3001`, which are disabled if not set.
+    /// The admin endpoints have no authentication, so this address must not be reachable publicly.
+    pub admin_listen_address: Option<String>,
+    /// Serve only tiles and metadata, without the `/refresh` and admin endpoints, e.g. for public-facing replicas [default: false]
+    pub read_only: Option<bool>,
     pub base_path: Option<String>,
     pub worker_processes: Option<usize>,
+    /// Maximum number of threads in the blocking task pool of each worker [default: 512 divided by the number of workers]
+    pub worker_max_blocking_threads: Option<usize>,
+    /// Maximum number of concurrent connections of each worker [default: 25000]
+    pub max_connections: Option<usize>,
+    /// Maximum number of pending connections waiting to be accepted [default: 2048]
+    pub backlog: Option<u32>,
+    /// Idle time (in seconds) before TCP keepalive probes are sent on client connections [default: disabled]
+    pub tcp_keepalive: Option<u64>,
     pub preferred_encoding: Option<PreferredEncoding>,
+    /// How long (in seconds) cached tiles stay valid. By default, tiles stay in the cache until evicted.
+    pub cache_ttl_sec: Option<u64>,
+    /// Serve expired tiles from the cache if the source fails to generate a new one [default: false]
+    pub cache_stale_on_error: Option<bool>,
+    pub cors: Option<CorsConfig>,
+    /// Client IP address rules for each class of endpoints, including the admin endpoints
+    pub access: Option<AccessConfig>,
+    /// Security headers added to all responses, enabled with the defaults unless set to `false`
+    #[serde(default, skip_serializing_if = "OptBoolObj::is_none")]
+    pub security_headers: OptBoolObj<SecurityHeadersConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Count the tiles and bytes served to each API key by source, see `/admin/usage`
+    pub usage: Option<UsageConfig>,
+    /// Prometheus metrics served at `/metrics`
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<crate::srv::MetricsConfig>,
+    /// Serving options for individual tile sources, keyed by source ID
+    pub source_options: Option<BTreeMap<String, SourceOptions>>,
+    /// Also serve Postgres function sources at the `/rpc/{schema}.{function}` paths
+    /// of the older Martin versions [default: false]
+    pub legacy_rpc_routes: Option<bool>,
+    /// Reject tile requests with query parameters not listed in `query_params` of the source options
+    /// with `400 Bad Request`, so that clients cannot fill the cache with arbitrary parameters [default: false]
+    pub strict_query_params: Option<bool>,
+    /// Respond with an empty tile without querying a source if the tile is outside of the source's bounds [default: true]
+    pub check_tile_bounds: Option<bool>,
+    /// Log a warning with the time spent in each phase of the source when a tile takes
+    /// at least this many milliseconds to generate. Disabled if not set.
+    pub slow_tile_ms: Option<u64>,
+    /// Stream tiles of at least this many bytes to the client in chunks as the source reads them,
+    /// instead of reading them into memory first. Only used for sources that support it, e.g. upstream sources,
+    /// if the tile is sent as stored. Streamed tiles are not cached and have no `ETag`. Disabled if not set.
+    pub stream_min_size: Option<usize>,
+    /// Discover the sources again with an exponential backoff while some of them could not be discovered at startup,
+    /// e.g. a file skipped with `on_invalid_source`, or a table that could not be read. Disabled if not set.
+    pub discovery_retry: Option<DiscoveryRetryConfig>,
+    /// Audit log of the administrative actions, e.g. refreshes, purges, and config file reloads. Disabled if not set.
+    pub audit: Option<crate::srv::AuditConfig>,
+    /// Report panics and repeatedly failing sources to Sentry or a webhook
+    pub error_reporting: Option<ErrorReportingConfig>,
+    /// Log outputs and per-module log levels, applied again when the config file is reloaded
+    pub log: Option<LogConfig>,
+    /// How much of the error message to include in error responses [default: full]
+    pub error_detail: Option<ErrorDetail>,
+    /// Webhooks notified with the added, removed, and changed sources whenever the catalog
+    /// changes after a refresh or a config file reload
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    /// CDNs to purge the tiles of the sources from, when the sources change or are purged with `POST /{source_ids}/purge`
+    pub cdn_purge: Option<Vec<CdnPurgeConfig>>,
+    /// Share refreshes and purges with the other replicas over Redis pub/sub, so that all of them
+    /// drop the cached tiles of the changed sources together
+    #[cfg(feature = "redis")]
+    pub cache_invalidation: Option<crate::srv::CacheInvalidationConfig>,
+    /// Tag tile responses with the `src:{source_id}` and `z:{zoom}` keys in this header,
+    /// so that CDNs with tag-based purging can invalidate a single source or zoom level. Disabled if not set.
+    pub cache_tags: Option<CacheTagHeader>,
+    /// Serve a `/robots.txt` that disallows crawling the tiles of all listed sources [default: true]
+    pub robots_txt: Option<bool>,
+    /// Render vector tiles as images with `/{source_ids}/{z}/{x}/{y}.png`, `.jpg`, or `.webp`. Disabled if not set.
+    #[cfg(feature = "raster")]
+    pub render: Option<crate::raster::RenderConfig>,
+    /// Serve raster sources with elevations encoded as colors as Cesium terrain with `/{source_id}/layer.json`
+    /// and `/{source_id}/{z}/{x}/{y}.terrain`. Disabled if not set.
+    #[cfg(feature = "raster")]
+    pub terrain: Option<crate::raster::TerrainConfig>,
+}
+
+impl SrvConfig {
+    /// Make sure all configured response headers are valid HTTP header names and values
+    pub fn validate_source_headers(&self) -> MartinResult<()> {
+        for (id, opts) in self.source_options.iter().flatten() {
+            for (name, value) in opts.headers.iter().flatten() {
+                if HeaderName::try_from(name.as_str()).is_err()
+                    || HeaderValue::try_from(value.as_str()).is_err()
+                {
+                    return Err(InvalidSourceHeader(name.clone(), id.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse all configured URL templates of the sources, failing on the first invalid one
+    pub fn parse_url_templates(&self) -> MartinResult<Vec<(String, UrlTemplate)>> {
+        let mut templates = Vec::new();
+        for (id, opts) in self.source_options.iter().flatten() {
+            for template in opts.url_templates.iter().flatten() {
+                match UrlTemplate::parse(template, id) {
+                    Ok(parsed) => templates.push((id.clone(), parsed)),
+                    Err(reason) => {
+                        return Err(InvalidUrlTemplate(template.clone(), id.clone(), reason))
+                    }
+                }
+            }
+        }
+        Ok(templates)
+    }
+
+    #[must_use]
+    pub fn get_cache_policy(&self) -> TileCachePolicy {
+        TileCachePolicy {
+            ttl: self.cache_ttl_sec.map(Duration::from_secs),
+            serve_stale_on_error: self.cache_stale_on_error.unwrap_or_default(),
+        }
+    }
+
+    #[must_use]
+    pub fn get_source_options(&self, source_id: &str) -> Option<&SourceOptions> {
+        self.source_options.as_ref()?.get(source_id)
+    }
+
+    /// True if the responses of the source should not be indexed by search engines
+    #[must_use]
+    pub fn is_noindex(&self, source_id: &str) -> bool {
+        self.get_source_options(source_id)
+            .and_then(|o| o.noindex)
+            .unwrap_or_default()
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct SourceOptions {
+    /// Modification time of the source data in RFC 3339 format, used as the `Last-Modified` header.
+    /// Overrides the modification time detected from the source itself, e.g. the `MBTiles` file time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub last_modified: Option<OffsetDateTime>,
+    /// Static HTTP headers to add to every tile response of this source
+    pub headers: Option<BTreeMap<String, String>>,
+    /// How to serve pre-compressed tiles to clients that do not accept their encoding
+    pub stored_encoding: Option<StoredEncodingPolicy>,
+    /// Maximum number of tiles of this source generated at the same time.
+    /// Additional requests wait for the running ones to complete.
+    pub max_concurrency: Option<NonZeroUsize>,
+    /// Maximum time (in milliseconds) to generate a tile, after which the request is cancelled
+    /// and the client gets a `503 Service Unavailable` response
+    pub timeout_ms: Option<u64>,
+    /// Stop using the source for a while after it fails repeatedly
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Maximum size and number of features of the tiles of this source
+    pub limits: Option<TileLimits>,
+    /// Drop layers, and drop, rename, or convert attributes of the vector tiles before they are served
+    pub pipeline: Option<MvtPipeline>,
+    /// Query parameters accepted by this source when `strict_query_params` is enabled.
+    /// The `layers` parameter is always accepted.
+    pub query_params: Option<Vec<String>>,
+    /// Serve the source, but do not list it in the catalog, the index page, or the `OpenAPI` document,
+    /// e.g. for deprecated or internal sources [default: false]
+    pub hidden: Option<bool>,
+    /// Version of the source data, part of the cache keys and the `ETag` of the tiles, so that changing it
+    /// invalidates the cached tiles. Replaces the `version` of the `MBTiles` metadata or the `version_sequence`
+    /// of Postgres sources.
+    pub data_version: Option<String>,
+    /// Ask search engines not to index the tiles and the `TileJSON` of this source
+    /// with the `X-Robots-Tag: noindex` header [default: false]
+    pub noindex: Option<bool>,
+    /// Additional URLs of the tiles of this source, e.g. `/tiles/{source}/{z}/{x}/{y}` or `/{source}@{z}/{x}/{y}.mvt`,
+    /// to keep the clients of a previous tile server working. `{source}` is replaced with the source ID.
+    pub url_templates: Option<Vec<String>>,
+    /// WASM plugins that may transform or veto each tile, applied in order after the pipeline
+    #[cfg(feature = "wasm")]
+    pub plugins: Option<Vec<std::path::PathBuf>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failures before the source is paused [default: 5]
+    pub failures: Option<NonZeroU32>,
+    /// How long (in seconds) to pause the source, responding with `503 Service Unavailable` [default: 30]
+    pub cooldown: Option<u64>,
+}
+
+impl CircuitBreakerConfig {
+    #[must_use]
+    pub fn get_failures(&self) -> NonZeroU32 {
+        self.failures
+            .unwrap_or(NonZeroU32::new(CIRCUIT_BREAKER_FAILURES_DEFAULT).unwrap())
+    }
+
+    #[must_use]
+    pub fn get_cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown.unwrap_or(CIRCUIT_BREAKER_COOLDOWN_DEFAULT))
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct DiscoveryRetryConfig {
+    /// How long (in seconds) to wait before the first retry, doubled after each one [default: 5]
+    pub initial_delay: Option<u64>,
+    /// Maximum time (in seconds) between two retries [default: 300]
+    pub max_delay: Option<u64>,
+}
+
+impl DiscoveryRetryConfig {
+    #[must_use]
+    pub fn get_initial_delay(&self) -> Duration {
+        Duration::from_secs(
+            self.initial_delay
+                .unwrap_or(DISCOVERY_RETRY_INITIAL_DELAY_DEFAULT),
+        )
+    }
+
+    #[must_use]
+    pub fn get_max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay.unwrap_or(DISCOVERY_RETRY_MAX_DELAY_DEFAULT))
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct TileLimits {
+    /// Maximum size of a tile in bytes, as returned by the source, i.e. usually compressed
+    pub max_size: Option<usize>,
+    /// Maximum number of features of all layers of a vector tile
+    pub max_features: Option<usize>,
+    /// Steps to make vector tiles larger than `max_size` smaller, e.g. by dropping low-priority attributes first,
+    /// and whole layers next. The steps are applied one after another until the tile fits.
+    pub reduce: Option<Vec<MvtPipeline>>,
+    /// What to do with tiles exceeding the limits [default: reject]
+    pub on_exceed: Option<LimitPolicy>,
+}
+
+/// What to do with tiles exceeding the [`TileLimits`] of their source
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitPolicy {
+    /// Respond with an error explaining which limit was exceeded
+    #[default]
+    Reject,
+    /// Drop the features of vector tiles exceeding the limits, and add a `Warning` header to the response.
+    /// Other tiles are rejected.
+    Truncate,
+}
+
+/// What to do with tiles stored in a compressed form (e.g. gzip) when the client
+/// does not list that encoding in its `Accept-Encoding` header.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoredEncodingPolicy {
+    /// Send the tile as is, e.g. for clients that handle gzip without announcing it
+    Passthrough,
+    /// Decompress the tile, and compress it again with an encoding accepted by the client
+    #[default]
+    Reencode,
+    /// Decompress the tile, and send it uncompressed
+    Decompress,
+}
+
+/// The header with the cache tags of tile responses, depending on the CDN
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheTagHeader {
+    /// `Surrogate-Key` with space-separated keys, used by Fastly
+    SurrogateKey,
+    /// `Cache-Tag` with comma-separated tags, used by Cloudflare and Akamai
+    CacheTag,
+}
+
+impl CacheTagHeader {
+    /// The header with the tags
+    #[must_use]
+    pub fn header(self, tags: &[String]) -> (&'static str, String) {
+        match self {
+            Self::SurrogateKey => ("Surrogate-Key", tags.join(" ")),
+            Self::CacheTag => ("Cache-Tag", tags.join(",")),
+        }
+    }
+}
+
+/// How much of the error message is included in the `detail` of error responses.
+/// Server errors are always logged with their full message.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorDetail {
+    /// The complete error message, which may include SQL fragments or file paths of server errors
+    #[default]
+    Full,
+    /// The message of client errors, e.g. invalid tile coordinates, but nothing about server errors
+    Message,
+    /// No detail, only the status of the error
+    None,
+}
+
+impl ErrorDetail {
+    /// Whether the message of an error with this status is sent to the client
+    #[must_use]
+    pub fn includes(self, status: StatusCode) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Message => status.is_client_error(),
+            Self::None => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -23,6 +357,7 @@ mod tests {
     use crate::test_utils::some;
 
     #[test]
+    #[allow(clippy::too_many_lines)]
     fn parse_config() {
         assert_eq!(
             serde_yaml::from_str::<SrvConfig>(indoc! {"
@@ -33,10 +368,49 @@ mod tests {
             .unwrap(),
             SrvConfig {
                 keep_alive: Some(75),
+                shutdown_timeout: None,
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
+                worker_max_blocking_threads: None,
+                max_connections: None,
+                backlog: None,
+                tcp_keepalive: None,
                 preferred_encoding: None,
+                admin_listen_address: None,
+                read_only: None,
                 base_path: None,
+                cache_ttl_sec: None,
+                cache_stale_on_error: None,
+                cors: None,
+                access: None,
+                security_headers: OptBoolObj::NoValue,
+                rate_limit: None,
+                usage: None,
+                #[cfg(feature = "metrics")]
+                metrics: None,
+                source_options: None,
+                legacy_rpc_routes: None,
+                strict_query_params: None,
+                check_tile_bounds: None,
+                slow_tile_ms: None,
+                stream_min_size: None,
+                discovery_retry: None,
+                audit: None,
+                error_reporting: None,
+                log: None,
+                error_detail: None,
+                webhooks: None,
+                cdn_purge: None,
+                #[cfg(feature = "redis")]
+                cache_invalidation: None,
+                cache_tags: None,
+                robots_txt: None,
+                #[cfg(feature = "raster")]
+                render: None,
+                #[cfg(feature = "raster")]
+                terrain: None,
+                #[cfg(feature = "grpc")]
+                grpc_listen_address: None,
             }
         );
         assert_eq!(
@@ -49,10 +423,49 @@ mod tests {
             .unwrap(),
             SrvConfig {
                 keep_alive: Some(75),
+                shutdown_timeout: None,
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
+                worker_max_blocking_threads: None,
+                max_connections: None,
+                backlog: None,
+                tcp_keepalive: None,
                 preferred_encoding: Some(PreferredEncoding::Brotli),
-                base_path: None
+                admin_listen_address: None,
+                read_only: None,
+                base_path: None,
+                cache_ttl_sec: None,
+                cache_stale_on_error: None,
+                cors: None,
+                access: None,
+                security_headers: OptBoolObj::NoValue,
+                rate_limit: None,
+                usage: None,
+                #[cfg(feature = "metrics")]
+                metrics: None,
+                source_options: None,
+                legacy_rpc_routes: None,
+                strict_query_params: None,
+                check_tile_bounds: None,
+                slow_tile_ms: None,
+                stream_min_size: None,
+                discovery_retry: None,
+                audit: None,
+                error_reporting: None,
+                log: None,
+                error_detail: None,
+                webhooks: None,
+                cdn_purge: None,
+                #[cfg(feature = "redis")]
+                cache_invalidation: None,
+                cache_tags: None,
+                robots_txt: None,
+                #[cfg(feature = "raster")]
+                render: None,
+                #[cfg(feature = "raster")]
+                terrain: None,
+                #[cfg(feature = "grpc")]
+                grpc_listen_address: None,
             }
         );
         assert_eq!(
@@ -65,11 +478,110 @@ mod tests {
             .unwrap(),
             SrvConfig {
                 keep_alive: Some(75),
+                shutdown_timeout: None,
                 listen_addresses: some("0.0.0.0:3000"),
                 worker_processes: Some(8),
+                worker_max_blocking_threads: None,
+                max_connections: None,
+                backlog: None,
+                tcp_keepalive: None,
                 preferred_encoding: Some(PreferredEncoding::Brotli),
+                admin_listen_address: None,
+                read_only: None,
                 base_path: None,
+                cache_ttl_sec: None,
+                cache_stale_on_error: None,
+                cors: None,
+                access: None,
+                security_headers: OptBoolObj::NoValue,
+                rate_limit: None,
+                usage: None,
+                #[cfg(feature = "metrics")]
+                metrics: None,
+                source_options: None,
+                legacy_rpc_routes: None,
+                strict_query_params: None,
+                check_tile_bounds: None,
+                slow_tile_ms: None,
+                stream_min_size: None,
+                discovery_retry: None,
+                audit: None,
+                error_reporting: None,
+                log: None,
+                error_detail: None,
+                webhooks: None,
+                cdn_purge: None,
+                #[cfg(feature = "redis")]
+                cache_invalidation: None,
+                cache_tags: None,
+                robots_txt: None,
+                #[cfg(feature = "raster")]
+                render: None,
+                #[cfg(feature = "raster")]
+                terrain: None,
+                #[cfg(feature = "grpc")]
+                grpc_listen_address: None,
             }
         );
     }
+
+    #[test]
+    fn parse_server_tuning() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            worker_max_blocking_threads: 64
+            max_connections: 1000
+            backlog: 512
+            tcp_keepalive: 60
+        "})
+        .unwrap();
+        assert_eq!(cfg.worker_max_blocking_threads, Some(64));
+        assert_eq!(cfg.max_connections, Some(1000));
+        assert_eq!(cfg.backlog, Some(512));
+        assert_eq!(cfg.tcp_keepalive, Some(60));
+    }
+
+    #[test]
+    fn parse_source_options() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            source_options:
+              src1:
+                last_modified: 2024-01-02T03:04:05Z
+                headers:
+                  X-Robots-Tag: noindex
+                stored_encoding: passthrough
+              src2: {}
+        "})
+        .unwrap();
+        let opts = cfg.get_source_options("src1").unwrap();
+        assert_eq!(opts.last_modified.unwrap().unix_timestamp(), 1_704_164_645);
+        assert_eq!(
+            opts.headers.as_ref().unwrap().get("X-Robots-Tag").unwrap(),
+            "noindex"
+        );
+        assert_eq!(
+            opts.stored_encoding,
+            Some(StoredEncodingPolicy::Passthrough)
+        );
+        assert!(cfg.validate_source_headers().is_ok());
+        assert_eq!(
+            cfg.get_source_options("src2"),
+            Some(&SourceOptions::default())
+        );
+        assert_eq!(cfg.get_source_options("src3"), None);
+    }
+
+    #[test]
+    fn invalid_source_headers() {
+        let cfg = serde_yaml::from_str::<SrvConfig>(indoc! {"
+            source_options:
+              src1:
+                headers:
+                  'Bad Header': value
+        "})
+        .unwrap();
+        assert!(matches!(
+            cfg.validate_source_headers(),
+            Err(InvalidSourceHeader(name, id)) if name == "Bad Header" && id == "src1"
+        ));
+    }
 }
@@ -0,0 +1,150 @@
+use actix_cors::Cors;
+use actix_web::http::header::HeaderName;
+use actix_web::http::{Method, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::MartinError::CorsConfigError;
+use crate::MartinResult;
+
+pub const CORS_METHODS_DEFAULT: &[&str] = &["GET"];
+
+/// Cross-Origin Resource Sharing policy. Any origin is allowed to `GET` tiles unless configured otherwise.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct CorsConfig {
+    /// List of allowed origins, e.g. `https://example.org`. Any origin is allowed if not set.
+    pub origins: Option<Vec<String>>,
+    /// List of allowed HTTP methods [default: `GET`]
+    pub methods: Option<Vec<String>>,
+    /// List of request headers the client is allowed to send
+    pub headers: Option<Vec<String>>,
+    /// Allow requests with credentials (cookies, authorization headers, or TLS client certificates).
+    /// Cannot be used without an explicit list of origins.
+    pub credentials: Option<bool>,
+    /// How long (in seconds) the browser may cache the results of a preflight request
+    pub max_age: Option<usize>,
+}
+
+impl CorsConfig {
+    /// Make sure the policy can be turned into a CORS middleware.
+    /// `actix-cors` only reports misconfiguration when the server starts its workers,
+    /// so it is better to catch it while loading the configuration.
+    pub fn validate(&self) -> MartinResult<()> {
+        for origin in self.origins.iter().flatten() {
+            if origin == "*" || origin.parse::<Uri>().is_err() {
+                return Err(CorsConfigError(format!("invalid origin '{origin}'")));
+            }
+        }
+        for method in self.methods.iter().flatten() {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(CorsConfigError(format!("invalid method '{method}'")));
+            }
+        }
+        for header in self.headers.iter().flatten() {
+            if HeaderName::try_from(header.as_str()).is_err() {
+                return Err(CorsConfigError(format!("invalid header '{header}'")));
+            }
+        }
+        if self.credentials == Some(true) && self.origins.is_none() {
+            return Err(CorsConfigError(
+                "credentials can only be allowed for an explicit list of origins".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn make_middleware(&self) -> Cors {
+        let mut cors = Cors::default();
+        cors = match &self.origins {
+            Some(origins) => origins
+                .iter()
+                .fold(cors, |cors, origin| cors.allowed_origin(origin)),
+            None => cors.allow_any_origin(),
+        };
+        cors = match &self.methods {
+            Some(methods) => cors.allowed_methods(methods.iter().map(String::as_str)),
+            None => cors.allowed_methods(CORS_METHODS_DEFAULT.iter().copied()),
+        };
+        if let Some(headers) = &self.headers {
+            cors = cors.allowed_headers(
+                headers
+                    .iter()
+                    .filter_map(|v| HeaderName::try_from(v.as_str()).ok()),
+            );
+        }
+        if self.credentials == Some(true) {
+            cors = cors.supports_credentials();
+        }
+        cors.max_age(self.max_age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use indoc::indoc;
+
+    use super::*;
+
+    async fn get_allowed_origin(cfg: &CorsConfig, origin: &str) -> Option<String> {
+        let app = init_service(
+            App::new()
+                .wrap(cfg.make_middleware())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((ORIGIN, origin))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        resp.headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[actix_rt::test]
+    async fn parse_cors() {
+        let cfg: CorsConfig = serde_yaml::from_str(indoc! {"
+            origins:
+              - https://example.org
+            methods: [GET, HEAD]
+            headers: [Authorization]
+            credentials: true
+            max_age: 3600
+        "})
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(
+            get_allowed_origin(&cfg, "https://example.org").await,
+            Some("https://example.org".to_string())
+        );
+        assert_eq!(
+            get_allowed_origin(&cfg, "https://other.example").await,
+            None
+        );
+
+        let cfg = CorsConfig::default();
+        assert!(cfg.validate().is_ok());
+        assert_eq!(
+            get_allowed_origin(&cfg, "https://other.example").await,
+            Some("https://other.example".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_cors() {
+        for yaml in [
+            "origins: ['*']",
+            "methods: ['G E T']",
+            "headers: ['Bad Header']",
+            "credentials: true",
+        ] {
+            let cfg: CorsConfig = serde_yaml::from_str(yaml).unwrap();
+            assert!(cfg.validate().is_err(), "{yaml}");
+        }
+    }
+}
@@ -3,10 +3,11 @@ use tokio::sync::RwLock;
 
 use actix_web::error::{ErrorBadRequest, ErrorNotFound};
 use actix_web::web::{Data, Path};
-use actix_web::{middleware, route, HttpResponse, Result as ActixResult};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
 use serde::Deserialize;
 
 use crate::fonts::{FontError, FontSources};
+use crate::srv::range::ranged_response;
 use crate::srv::server::map_internal_error;
 
 #[derive(Deserialize, Debug)]
@@ -23,6 +24,7 @@ struct FontRequest {
 )]
 #[allow(clippy::unused_async)]
 async fn get_font(
+    req: HttpRequest,
     path: Path<FontRequest>,
     fonts: Data<RwLock<FontSources>>,
 ) -> ActixResult<HttpResponse> {
@@ -30,9 +32,9 @@ async fn get_font(
     let data = fonts_guard
         .get_font_range(&path.fontstack, path.start, path.end)
         .map_err(map_font_error)?;
-    Ok(HttpResponse::Ok()
-        .content_type("application/x-protobuf")
-        .body(data))
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/x-protobuf");
+    Ok(ranged_response(&req, response, data.into()))
 }
 
 pub fn map_font_error(e: FontError) -> actix_web::Error {
@@ -0,0 +1,630 @@
+//! gRPC endpoint for internal consumers, see `martin/proto/martin.proto`.
+//! The message types and the service routing are written by hand to avoid requiring `protoc` at build time,
+//! and must be kept in sync with the proto file, which is checked by `test_proto_in_sync`.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use actix_web::http::header::{AcceptEncoding, Encoding, Preference, QualityItem};
+use actix_web::http::StatusCode;
+use bytes::Bytes;
+use futures::future::select;
+use futures::FutureExt as _;
+use tokio::sync::watch;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService};
+use tonic::{Code, Status};
+
+use crate::config::ServerState;
+use crate::source::TileSources;
+use crate::srv::{merge_tilejson, DynTileSource, SrvConfig};
+use crate::utils::OptMainCache;
+use crate::MartinError::BindingError;
+use crate::{MartinResult, TileCoord};
+
+const GET_TILE_PATH: &str = "/martin.v1.TileService/GetTile";
+const GET_TILEJSON_PATH: &str = "/martin.v1.TileService/GetTileJson";
+const LIST_SOURCES_PATH: &str = "/martin.v1.TileService/ListSources";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileRequest {
+    #[prost(string, tag = "1")]
+    pub source_ids: String,
+    #[prost(uint32, tag = "2")]
+    pub z: u32,
+    #[prost(uint32, tag = "3")]
+    pub x: u32,
+    #[prost(uint32, tag = "4")]
+    pub y: u32,
+    #[prost(string, tag = "5")]
+    pub query: String,
+    #[prost(string, repeated, tag = "6")]
+    pub accept_encoding: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileResponse {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub data: Bytes,
+    #[prost(string, tag = "2")]
+    pub content_type: String,
+    #[prost(string, tag = "3")]
+    pub content_encoding: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileJsonRequest {
+    #[prost(string, tag = "1")]
+    pub source_ids: String,
+    #[prost(string, tag = "2")]
+    pub tiles_url: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTileJsonResponse {
+    #[prost(string, tag = "1")]
+    pub tilejson: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListSourcesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListSourcesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub sources: Vec<SourceInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SourceInfo {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub content_type: String,
+    #[prost(string, tag = "3")]
+    pub content_encoding: String,
+    #[prost(string, tag = "4")]
+    pub name: String,
+    #[prost(string, tag = "5")]
+    pub description: String,
+    #[prost(string, tag = "6")]
+    pub attribution: String,
+}
+
+/// The sources the gRPC methods are served from
+struct GrpcState {
+    tiles: TileSources,
+    cache: OptMainCache,
+    config: SrvConfig,
+}
+
+impl GrpcState {
+    fn new(state: &ServerState, config: SrvConfig) -> Self {
+        Self {
+            tiles: state.tiles.clone(),
+            cache: state.cache.clone(),
+            config,
+        }
+    }
+}
+
+/// The `martin.v1.TileService` gRPC service.
+/// Each request is served from the current state, which is replaced together with the one of the HTTP server.
+#[derive(Clone)]
+pub struct GrpcTileService(Arc<RwLock<Arc<GrpcState>>>);
+
+impl GrpcTileService {
+    #[must_use]
+    pub fn new(state: &ServerState, config: SrvConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(GrpcState::new(
+            state, config,
+        )))))
+    }
+
+    fn current(&self) -> Arc<GrpcState> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the state whenever a new one is published, e.g. by `/refresh`, the config watcher,
+    /// or the discovery retries. Ends when the sender is dropped.
+    pub async fn follow(self, mut updates: watch::Receiver<Option<(SrvConfig, ServerState)>>) {
+        while updates.changed().await.is_ok() {
+            let update = updates.borrow_and_update().clone();
+            if let Some((config, state)) = update {
+                *self.0.write().unwrap() = Arc::new(GrpcState::new(&state, config));
+            }
+        }
+    }
+}
+
+impl NamedService for GrpcTileService {
+    const NAME: &'static str = "martin.v1.TileService";
+}
+
+impl<B> Service<http::Request<B>> for GrpcTileService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let state = self.current();
+        match req.uri().path() {
+            GET_TILE_PATH => Box::pin(async move {
+                let method = Unary(move |r| get_tile(state.clone(), r));
+                Ok(Grpc::new(tonic::codec::ProstCodec::default())
+                    .unary(method, req)
+                    .await)
+            }),
+            GET_TILEJSON_PATH => Box::pin(async move {
+                let method = Unary(move |r| get_tilejson(state.clone(), r));
+                Ok(Grpc::new(tonic::codec::ProstCodec::default())
+                    .unary(method, req)
+                    .await)
+            }),
+            LIST_SOURCES_PATH => Box::pin(async move {
+                let method = Unary(move |r| list_sources(state.clone(), r));
+                Ok(Grpc::new(tonic::codec::ProstCodec::default())
+                    .unary(method, req)
+                    .await)
+            }),
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(empty_body());
+                let headers = response.headers_mut();
+                headers.insert("grpc-status", (Code::Unimplemented as i32).into());
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    http::HeaderValue::from_static("application/grpc"),
+                );
+                Ok(response)
+            }),
+        }
+    }
+}
+
+/// Adapts an async function to a unary gRPC method
+struct Unary<F>(F);
+
+impl<F, Req, Res, Fut> Service<tonic::Request<Req>> for Unary<F>
+where
+    F: FnMut(Req) -> Fut,
+    Fut: Future<Output = Result<Res, Status>> + Send + 'static,
+{
+    type Response = tonic::Response<Res>;
+    type Error = Status;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: tonic::Request<Req>) -> Self::Future {
+        let fut = (self.0)(req.into_inner());
+        Box::pin(async move { fut.await.map(tonic::Response::new) })
+    }
+}
+
+async fn get_tile(state: Arc<GrpcState>, req: GetTileRequest) -> Result<GetTileResponse, Status> {
+    let z = u8::try_from(req.z).map_err(|_| Status::invalid_argument("Invalid zoom level"))?;
+    let accept_enc = if req.accept_encoding.is_empty() {
+        None
+    } else {
+        let encodings = req
+            .accept_encoding
+            .iter()
+            .map(|v| v.parse::<QualityItem<Preference<Encoding>>>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::invalid_argument(format!("Invalid accept_encoding: {e}")))?;
+        Some(AcceptEncoding(encodings))
+    };
+
+    let src = DynTileSource::new(
+        &state.tiles,
+        &req.source_ids,
+        Some(z),
+        &req.query,
+        accept_enc,
+        Some(&state.config),
+        state.cache.as_ref(),
+    )
+    .map_err(to_status)?;
+    let tile = src
+        .get_tile_content(TileCoord {
+            z,
+            x: req.x,
+            y: req.y,
+        })
+        .await
+        .map_err(to_status)?;
+
+    Ok(GetTileResponse {
+        content_type: tile.info.format.content_type().to_string(),
+        content_encoding: tile
+            .info
+            .encoding
+            .content_encoding()
+            .unwrap_or_default()
+            .to_string(),
+        data: tile.data,
+    })
+}
+
+#[allow(clippy::unused_async)]
+async fn get_tilejson(
+    state: Arc<GrpcState>,
+    req: GetTileJsonRequest,
+) -> Result<GetTileJsonResponse, Status> {
+    let sources = state
+        .tiles
+        .get_sources(&req.source_ids, None)
+        .map_err(to_status)?
+        .0;
+    let tilejson = merge_tilejson(&sources, req.tiles_url);
+    Ok(GetTileJsonResponse {
+        tilejson: serde_json::to_string(&tilejson).map_err(|e| Status::internal(e.to_string()))?,
+    })
+}
+
+#[allow(clippy::unused_async)]
+async fn list_sources(
+    state: Arc<GrpcState>,
+    _req: ListSourcesRequest,
+) -> Result<ListSourcesResponse, Status> {
+    let sources = state
+        .tiles
+        .get_catalog()
+        .into_iter()
+        .filter(|(id, _)| {
+            !state
+                .config
+                .get_source_options(id)
+                .and_then(|o| o.hidden)
+                .unwrap_or_default()
+        })
+        .map(|(id, entry)| SourceInfo {
+            id,
+            content_type: entry.content_type,
+            content_encoding: entry.content_encoding.unwrap_or_default(),
+            name: entry.name.unwrap_or_default(),
+            description: entry.description.unwrap_or_default(),
+            attribution: entry.attribution.unwrap_or_default(),
+        })
+        .collect();
+    Ok(ListSourcesResponse { sources })
+}
+
+fn to_status<E: Into<actix_web::Error>>(e: E) -> Status {
+    let e = e.into();
+    let code = match e.as_response_error().status_code() {
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::BAD_REQUEST | StatusCode::NOT_ACCEPTABLE => Code::InvalidArgument,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
+
+/// Create a future for the gRPC server listening on the given address,
+/// serving the states published to `updates` once they replace the initial one
+pub fn new_grpc_server(
+    address: &str,
+    state: &ServerState,
+    config: SrvConfig,
+    updates: watch::Receiver<Option<(SrvConfig, ServerState)>>,
+) -> MartinResult<impl Future<Output = MartinResult<()>>> {
+    let addr: SocketAddr = address.parse().map_err(|_| {
+        BindingError(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid socket address"),
+            address.to_string(),
+        )
+    })?;
+    let service = GrpcTileService::new(state, config);
+    let updater = service.clone().follow(updates);
+    let address = address.to_string();
+    let server = async move {
+        tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(addr)
+            .await
+            .map_err(|e| BindingError(std::io::Error::other(e), address))
+    };
+    Ok(async move {
+        // the server keeps running when nothing is published anymore
+        let updater = updater.then(|()| std::future::pending());
+        select(pin!(server), pin!(updater)).await.factor_first().0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use prost::encoding::{DecodeContext, WireType};
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::TileData;
+
+    fn new_state() -> Arc<GrpcState> {
+        let mut tiles = TileSources::default();
+        tiles.add_source(Box::new(TestSource {
+            id: "custom",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(&[1, 2, 3]),
+        }));
+        Arc::new(GrpcState {
+            tiles,
+            cache: None,
+            config: SrvConfig::default(),
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_grpc_methods() {
+        let state = new_state();
+        let req = GetTileRequest {
+            source_ids: "custom".to_string(),
+            ..Default::default()
+        };
+        let tile = get_tile(state.clone(), req.clone()).await.unwrap();
+        assert_eq!(tile.data, vec![1_u8, 2, 3]);
+        assert_eq!(tile.content_type, "application/x-protobuf");
+
+        let missing = GetTileRequest {
+            source_ids: "missing".to_string(),
+            ..req.clone()
+        };
+        let err = get_tile(state.clone(), missing).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let invalid = GetTileRequest { z: 300, ..req };
+        let err = get_tile(state.clone(), invalid).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let req = GetTileJsonRequest {
+            source_ids: "custom".to_string(),
+            tiles_url: "http://example.org/custom/{z}/{x}/{y}".to_string(),
+        };
+        let tj = get_tilejson(state.clone(), req).await.unwrap().tilejson;
+        assert!(tj.contains("http://example.org/custom/{z}/{x}/{y}"), "{tj}");
+
+        let sources = list_sources(state, ListSourcesRequest {})
+            .await
+            .unwrap()
+            .sources;
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].id, "custom");
+    }
+
+    #[actix_rt::test]
+    async fn test_grpc_service() {
+        let service = GrpcTileService(Arc::new(RwLock::new(new_state())));
+        let mut client = tonic::client::Grpc::new(service);
+        client.ready().await.unwrap();
+        let response: tonic::Response<ListSourcesResponse> = client
+            .unary(
+                tonic::Request::new(ListSourcesRequest {}),
+                http::uri::PathAndQuery::from_static("/martin.v1.TileService/ListSources"),
+                tonic::codec::ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().sources[0].id, "custom");
+
+        client.ready().await.unwrap();
+        let err = client
+            .unary::<_, ListSourcesResponse, _>(
+                tonic::Request::new(ListSourcesRequest {}),
+                http::uri::PathAndQuery::from_static("/martin.v1.TileService/Unknown"),
+                tonic::codec::ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unimplemented);
+    }
+
+    #[actix_rt::test]
+    async fn test_grpc_follows_updates() {
+        let source = |id| {
+            Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            })
+        };
+        let mut state = ServerState::default();
+        state.tiles.add_source(source("old"));
+        let service = GrpcTileService::new(&state, SrvConfig::default());
+        let (sender, receiver) = watch::channel(None);
+        let updater = actix_rt::spawn(service.clone().follow(receiver));
+
+        let ids = |service: &GrpcTileService| {
+            service
+                .current()
+                .tiles
+                .get_catalog()
+                .into_keys()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&service), ["old"]);
+        let mut state = ServerState::default();
+        state.tiles.add_source(source("new"));
+        sender.send_replace(Some((SrvConfig::default(), state)));
+        drop(sender);
+        updater.await.unwrap();
+        assert_eq!(ids(&service), ["new"]);
+    }
+
+    /// A field of a message in `martin.proto`
+    #[derive(Debug, PartialEq)]
+    struct ProtoField {
+        name: String,
+        tag: u32,
+        wire_type: WireType,
+        repeated: bool,
+    }
+
+    /// Messages and methods of the proto file, which only uses the simple syntax of this service
+    fn parse_proto() -> (BTreeMap<String, Vec<ProtoField>>, Vec<String>) {
+        let proto = include_str!("../../proto/martin.proto");
+        let lines = proto
+            .lines()
+            .map(|line| line.split("//").next().unwrap().trim())
+            .filter(|line| !line.is_empty());
+        let mut messages = BTreeMap::new();
+        let mut methods = Vec::new();
+        let mut current = None;
+        for line in lines {
+            let words: Vec<_> = line
+                .split(|c: char| c.is_whitespace() || "(){};=".contains(c))
+                .filter(|v| !v.is_empty())
+                .collect();
+            match words.as_slice() {
+                ["message", name] => {
+                    messages.insert((*name).to_string(), Vec::new());
+                    current = line.ends_with('{').then(|| (*name).to_string());
+                }
+                ["rpc", name, ..] => methods.push(format!("/martin.v1.TileService/{name}")),
+                [.., field_type, name, tag] if current.is_some() => {
+                    let wire_type = match *field_type {
+                        "uint32" => WireType::Varint,
+                        _ => WireType::LengthDelimited,
+                    };
+                    let fields = messages.get_mut(current.as_ref().unwrap()).unwrap();
+                    fields.push(ProtoField {
+                        name: (*name).to_string(),
+                        tag: tag.parse().unwrap(),
+                        wire_type,
+                        repeated: words[0] == "repeated",
+                    });
+                }
+                _ => {}
+            }
+            if line == "}" {
+                current = None;
+            }
+        }
+        (messages, methods)
+    }
+
+    /// Names of the fields of a message in its debug output, e.g. `Msg { a: "text", b: [1, 2] }`.
+    /// The string values must not contain quotes.
+    fn field_names(debug: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut token = String::new();
+        for c in debug.chars() {
+            match c {
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '{' | '[' | '(' => depth += 1,
+                '}' | ']' | ')' => depth -= 1,
+                ':' if depth == 1 => names.push(std::mem::take(&mut token).trim().to_string()),
+                ',' => token.clear(),
+                _ if depth == 1 => token.push(c),
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// The fields of a message with all fields set, as found in its encoding and its debug output
+    fn message_fields<M: prost::Message>(message: &M) -> Vec<ProtoField> {
+        let names = field_names(&format!("{message:?}"));
+        let encoded = message.encode_to_vec();
+        let mut buf = encoded.as_slice();
+        let mut fields: Vec<ProtoField> = Vec::new();
+        while !buf.is_empty() {
+            let (tag, wire_type) = prost::encoding::decode_key(&mut buf).unwrap();
+            prost::encoding::skip_field(wire_type, tag, &mut buf, DecodeContext::default())
+                .unwrap();
+            if let Some(field) = fields.iter_mut().find(|f| f.tag == tag) {
+                field.repeated = true;
+            } else {
+                fields.push(ProtoField {
+                    name: names[fields.len()].clone(),
+                    tag,
+                    wire_type,
+                    repeated: false,
+                });
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_proto_in_sync() {
+        let (messages, methods) = parse_proto();
+        let text = || "text".to_string();
+        let info = SourceInfo {
+            id: text(),
+            content_type: text(),
+            content_encoding: text(),
+            name: text(),
+            description: text(),
+            attribution: text(),
+        };
+        // every field is set, and repeated fields have two values
+        let actual = BTreeMap::from([
+            (
+                "GetTileRequest".to_string(),
+                message_fields(&GetTileRequest {
+                    source_ids: text(),
+                    z: 1,
+                    x: 1,
+                    y: 1,
+                    query: text(),
+                    accept_encoding: vec![text(), text()],
+                }),
+            ),
+            (
+                "GetTileResponse".to_string(),
+                message_fields(&GetTileResponse {
+                    data: Bytes::from_static(b"tile"),
+                    content_type: text(),
+                    content_encoding: text(),
+                }),
+            ),
+            (
+                "GetTileJsonRequest".to_string(),
+                message_fields(&GetTileJsonRequest {
+                    source_ids: text(),
+                    tiles_url: text(),
+                }),
+            ),
+            (
+                "GetTileJsonResponse".to_string(),
+                message_fields(&GetTileJsonResponse { tilejson: text() }),
+            ),
+            (
+                "ListSourcesRequest".to_string(),
+                message_fields(&ListSourcesRequest {}),
+            ),
+            (
+                "ListSourcesResponse".to_string(),
+                message_fields(&ListSourcesResponse {
+                    sources: vec![info.clone(), info.clone()],
+                }),
+            ),
+            ("SourceInfo".to_string(), message_fields(&info)),
+        ]);
+        assert_eq!(messages, actual);
+        assert_eq!(
+            methods,
+            [GET_TILE_PATH, GET_TILEJSON_PATH, LIST_SOURCES_PATH]
+        );
+    }
+}
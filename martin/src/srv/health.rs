@@ -0,0 +1,188 @@
+//! Health of each tile source, derived from the results of its recent tile requests,
+//! so that dashboards can show which sources are broken and why.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+
+use crate::srv::{Catalog, ErrorDetail, SrvConfig};
+use crate::MartinError::SourceUnavailable;
+use crate::MartinResult;
+
+/// A source with an error within this period is `degraded`
+const DEGRADED_PERIOD: Duration = Duration::from_secs(300);
+
+/// Number of consecutive failures after which a source is `failing`
+const FAILING_AFTER: u32 = 5;
+
+static HEALTH: Mutex<Option<HashMap<String, HealthTracker>>> = Mutex::new(None);
+
+/// Ordered from the best to the worst, so that the worst status of several sources is the maximum
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// No errors recently
+    #[default]
+    Ok,
+    /// Some requests failed recently, but the source is not failing
+    Degraded,
+    /// The last requests all failed
+    Failing,
+    /// Paused by the circuit breaker after failing repeatedly
+    Disabled,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceHealth {
+    pub status: HealthStatus,
+    /// Number of failed tile requests in a row
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Message of the last error, omitted unless `error_detail` is `full`
+    pub last_error: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub last_error_at: Option<OffsetDateTime>,
+}
+
+#[derive(Default)]
+struct HealthTracker {
+    consecutive_failures: u32,
+    last_error: Option<(Instant, OffsetDateTime, String)>,
+    is_disabled: bool,
+}
+
+/// Track the health of a source with the result of one of its tile requests.
+/// The errors of a source paused by the circuit breaker only mark it as disabled.
+pub(crate) fn record_source_health<T>(source_id: &str, result: &MartinResult<T>) {
+    let mut health = HEALTH.lock().unwrap();
+    let tracker = health
+        .get_or_insert_with(HashMap::new)
+        .entry(source_id.to_string())
+        .or_default();
+    match result {
+        Ok(_) => {
+            tracker.consecutive_failures = 0;
+            tracker.is_disabled = false;
+        }
+        Err(SourceUnavailable(..)) => tracker.is_disabled = true,
+        Err(e) => {
+            tracker.consecutive_failures = tracker.consecutive_failures.saturating_add(1);
+            tracker.last_error = Some((Instant::now(), OffsetDateTime::now_utc(), e.to_string()));
+            tracker.is_disabled = false;
+        }
+    }
+}
+
+/// The health of a source, which is `ok` if it has not been requested yet
+#[must_use]
+pub fn source_health(source_id: &str, error_detail: ErrorDetail) -> SourceHealth {
+    let health = HEALTH.lock().unwrap();
+    let Some(tracker) = health.as_ref().and_then(|v| v.get(source_id)) else {
+        return SourceHealth::default();
+    };
+    let status = if tracker.is_disabled {
+        HealthStatus::Disabled
+    } else if tracker.consecutive_failures >= FAILING_AFTER {
+        HealthStatus::Failing
+    } else if tracker
+        .last_error
+        .as_ref()
+        .is_some_and(|(at, _, _)| at.elapsed() < DEGRADED_PERIOD)
+    {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    };
+    SourceHealth {
+        status,
+        consecutive_failures: tracker.consecutive_failures,
+        last_error: tracker
+            .last_error
+            .as_ref()
+            .filter(|_| error_detail == ErrorDetail::Full)
+            .map(|(_, _, message)| message.clone()),
+        last_error_at: tracker.last_error.as_ref().map(|(_, at, _)| *at),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthDetail {
+    /// The worst status of all sources
+    status: HealthStatus,
+    sources: BTreeMap<String, SourceHealth>,
+}
+
+/// Health of each listed tile source, always with `200 OK` so that dashboards can show the broken sources
+#[route("/health/detail", method = "GET", method = "HEAD")]
+async fn get_health_detail(
+    catalog: Data<RwLock<Catalog>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> HttpResponse {
+    let error_detail = srv_config.read().await.error_detail.unwrap_or_default();
+    let sources: BTreeMap<_, _> = catalog
+        .read()
+        .await
+        .tiles
+        .keys()
+        .map(|id| (id.clone(), source_health(id, error_detail)))
+        .collect();
+    let status = sources.values().map(|v| v.status).max().unwrap_or_default();
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .json(HealthDetail { status, sources })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::MartinError::InternalError;
+
+    #[test]
+    fn test_source_health() {
+        let id = "test_source_health";
+        assert_eq!(
+            source_health(id, ErrorDetail::Full),
+            SourceHealth::default()
+        );
+
+        let failure = || Err::<(), _>(InternalError("broken".into()));
+        record_source_health(id, &failure());
+        let health = source_health(id, ErrorDetail::Full);
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.consecutive_failures, 1);
+        assert!(health.last_error.unwrap().contains("broken"));
+        assert!(health.last_error_at.is_some());
+        assert_eq!(source_health(id, ErrorDetail::Message).last_error, None);
+
+        for _ in 1..FAILING_AFTER {
+            record_source_health(id, &failure());
+        }
+        assert_eq!(
+            source_health(id, ErrorDetail::Full).status,
+            HealthStatus::Failing
+        );
+
+        let paused = Err::<(), _>(SourceUnavailable(id.to_string(), Duration::from_secs(1)));
+        record_source_health(id, &paused);
+        assert_eq!(
+            source_health(id, ErrorDetail::Full).status,
+            HealthStatus::Disabled
+        );
+
+        // the recent error still counts after the source recovered
+        record_source_health(id, &Ok(()));
+        let health = source_health(id, ErrorDetail::Full);
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.consecutive_failures, 0);
+    }
+}
@@ -0,0 +1,187 @@
+//! Sharing cache invalidations between replicas over a Redis pub/sub channel. When the sources of
+//! one replica are refreshed or purged, all replicas drop the cached tiles of these sources, so that
+//! the replicas behind a load balancer do not serve different versions of the same tile.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+
+use futures::StreamExt as _;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::srv::CatalogDiff;
+
+pub const INVALIDATION_CHANNEL_DEFAULT: &str = "martin:invalidation";
+
+/// How long to wait before reconnecting after the subscription failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheInvalidationConfig {
+    /// Redis connection URL, e.g. `redis://redis:6379`
+    pub redis_url: String,
+    /// Pub/sub channel shared by all replicas [default: martin:invalidation]
+    pub channel: Option<String>,
+}
+
+/// The sources whose cached tiles must be dropped, sent as JSON over the channel
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invalidation {
+    pub sources: Vec<String>,
+    /// Sources of the tenants, by tenant name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, Vec<String>>,
+}
+
+impl Invalidation {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty() && self.tenants.values().all(Vec::is_empty)
+    }
+}
+
+impl From<&CatalogDiff> for Invalidation {
+    /// All added, removed, and changed sources, because a removed and re-added source may have other tiles
+    fn from(diff: &CatalogDiff) -> Self {
+        Self {
+            sources: diff
+                .added
+                .iter()
+                .chain(&diff.removed)
+                .chain(&diff.changed)
+                .cloned()
+                .collect(),
+            tenants: diff
+                .tenants
+                .iter()
+                .map(|(name, diff)| (name.clone(), diff.source_ids().into_iter().collect()))
+                .filter(|(_, ids): &(String, Vec<String>)| !ids.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl CacheInvalidationConfig {
+    fn channel(&self) -> &str {
+        self.channel
+            .as_deref()
+            .unwrap_or(INVALIDATION_CHANNEL_DEFAULT)
+    }
+
+    /// Send the invalidation to all replicas in the background, including this one.
+    /// Failed requests are logged, but not retried.
+    pub fn publish(&self, invalidation: &Invalidation) {
+        if invalidation.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_string(invalidation) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Unable to serialize the cache invalidation: {e}");
+                return;
+            }
+        };
+        let cfg = self.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let client = redis::Client::open(cfg.redis_url.as_str())?;
+                let mut con = client.get_multiplexed_async_connection().await?;
+                redis::cmd("PUBLISH")
+                    .arg(cfg.channel())
+                    .arg(&payload)
+                    .query_async::<i64>(&mut con)
+                    .await
+            };
+            match result.await {
+                Ok(receivers) => debug!(
+                    "Sent cache invalidation {payload} to {receivers} subscribers of {}",
+                    cfg.channel()
+                ),
+                Err(e) => warn!(
+                    "Unable to send cache invalidation {payload} to {}: {e}",
+                    cfg.channel()
+                ),
+            }
+        });
+    }
+
+    /// Call `handler` with every invalidation received on the channel. Runs forever,
+    /// reconnecting whenever the connection to Redis is lost.
+    pub async fn subscribe<F, Fut>(self, handler: F)
+    where
+        F: Fn(Invalidation) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            if let Err(e) = self.receive(&handler).await {
+                warn!(
+                    "Lost the subscription to the cache invalidation channel {}, reconnecting in {RECONNECT_DELAY:?}: {e}",
+                    self.channel()
+                );
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn receive<F, Fut>(&self, handler: &F) -> redis::RedisResult<()>
+    where
+        F: Fn(Invalidation) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let client = redis::Client::open(self.redis_url.as_str())?;
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(self.channel()).await?;
+        info!(
+            "Subscribed to the cache invalidation channel {}",
+            self.channel()
+        );
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            match serde_json::from_slice::<Invalidation>(msg.get_payload_bytes()) {
+                Ok(invalidation) => handler(invalidation).await,
+                Err(e) => warn!("Ignoring an invalid cache invalidation message: {e}"),
+            }
+        }
+        Err(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "the connection was closed",
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidation_from_diff() {
+        let diff = CatalogDiff {
+            added: vec!["new".to_string()],
+            removed: vec!["old".to_string()],
+            changed: vec!["roads".to_string()],
+            tenants: BTreeMap::from([
+                (
+                    "acme".to_string(),
+                    CatalogDiff {
+                        changed: vec!["water".to_string()],
+                        ..Default::default()
+                    },
+                ),
+                ("other".to_string(), CatalogDiff::default()),
+            ]),
+        };
+        let invalidation = Invalidation::from(&diff);
+        assert_eq!(invalidation.sources, vec!["new", "old", "roads"]);
+        assert_eq!(
+            invalidation.tenants,
+            BTreeMap::from([("acme".to_string(), vec!["water".to_string()])])
+        );
+        assert_eq!(
+            serde_json::to_value(&invalidation).unwrap(),
+            serde_json::json!({"sources": ["new", "old", "roads"], "tenants": {"acme": ["water"]}})
+        );
+        assert!(Invalidation::from(&CatalogDiff::default()).is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+//! Run the web app in AWS Lambda. Handles the events of API Gateway REST and HTTP APIs,
+//! Lambda Function URLs, and Application Load Balancer target groups.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+use actix_http::Request;
+use actix_service::{IntoServiceFactory, Service, ServiceFactory};
+use actix_web::body::{to_bytes, MessageBody};
+use actix_web::dev::{AppConfig, ServiceResponse};
+use actix_web::http::header::{HeaderName, CONTENT_ENCODING, CONTENT_TYPE, SET_COOKIE};
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::TestRequest;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use lambda_runtime::{service_fn, LambdaEvent};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use url::form_urlencoded;
+
+use crate::MartinError::InternalError;
+use crate::MartinResult;
+
+/// True if Martin is started by the AWS Lambda runtime
+#[must_use]
+pub fn is_running_on_lambda() -> bool {
+    std::env::var_os("AWS_LAMBDA_RUNTIME_API").is_some()
+}
+
+/// Handle Lambda events with the app created by the factory until the runtime stops Martin
+pub async fn run_on_lambda<F, I, S, B>(factory: F) -> MartinResult<()>
+where
+    F: Fn() -> I,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<
+        Request,
+        Config = AppConfig,
+        Response = ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    S::InitError: std::fmt::Debug,
+    B: MessageBody,
+{
+    let service = factory()
+        .into_factory()
+        .new_service(AppConfig::default())
+        .await
+        .map_err(|e| InternalError(format!("Unable to create the web app: {e:?}").into()))?;
+    let service = Rc::new(service);
+
+    lambda_runtime::run(service_fn(move |event: LambdaEvent<LambdaRequest>| {
+        let service = service.clone();
+        async move { Ok::<_, lambda_runtime::Error>(handle(&*service, event.payload).await) }
+    }))
+    .await?;
+    Ok(())
+}
+
+/// An HTTP request event in any of the supported formats, see
+/// <https://docs.aws.amazon.com/lambda/latest/dg/services-apigateway.html>,
+/// <https://docs.aws.amazon.com/lambda/latest/dg/urls-invocation.html>, and
+/// <https://docs.aws.amazon.com/elasticloadbalancing/latest/application/lambda-functions.html>
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LambdaRequest {
+    /// `2.0` for HTTP APIs and Function URLs, missing or `1.0` for REST APIs and ALB
+    version: Option<String>,
+    raw_path: Option<String>,
+    raw_query_string: Option<String>,
+    cookies: Option<Vec<String>>,
+    http_method: Option<String>,
+    path: Option<String>,
+    query_string_parameters: Option<HashMap<String, String>>,
+    multi_value_query_string_parameters: Option<HashMap<String, Vec<String>>>,
+    headers: Option<HashMap<String, String>>,
+    multi_value_headers: Option<HashMap<String, Vec<String>>>,
+    body: Option<String>,
+    #[serde(default)]
+    is_base64_encoded: bool,
+    #[serde(default)]
+    request_context: RequestContext,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestContext {
+    /// Only set for payload version 2.0
+    http: Option<HttpContext>,
+    /// Only set for REST APIs
+    identity: Option<Identity>,
+    /// Only set for ALB target groups
+    elb: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpContext {
+    method: String,
+    source_ip: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Identity {
+    source_ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventKind {
+    /// HTTP API payload version 2.0, also used by Function URLs
+    V2,
+    RestApi,
+    Alb,
+}
+
+impl LambdaRequest {
+    fn kind(&self) -> EventKind {
+        if self.version.as_deref() == Some("2.0") {
+            EventKind::V2
+        } else if self.request_context.elb.is_some() {
+            EventKind::Alb
+        } else {
+            EventKind::RestApi
+        }
+    }
+
+    fn method(&self) -> &str {
+        match &self.request_context.http {
+            Some(http) => &http.method,
+            None => self.http_method.as_deref().unwrap_or("GET"),
+        }
+    }
+
+    fn path_and_query(&self) -> String {
+        let path = self
+            .raw_path
+            .as_deref()
+            .or(self.path.as_deref())
+            .unwrap_or("/");
+        let query = if let Some(query) = &self.raw_query_string {
+            query.clone()
+        } else {
+            let params: Vec<_> = if let Some(params) = &self.multi_value_query_string_parameters {
+                params
+                    .iter()
+                    .flat_map(|(k, values)| values.iter().map(move |v| (k, v)))
+                    .collect()
+            } else {
+                self.query_string_parameters.iter().flatten().collect()
+            };
+            if self.kind() == EventKind::Alb {
+                // ALB passes the parameters exactly as they were sent, i.e. still URL-encoded
+                params
+                    .into_iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>()
+                    .join("&")
+            } else {
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(params)
+                    .finish()
+            }
+        };
+        if query.is_empty() {
+            path.to_string()
+        } else {
+            format!("{path}?{query}")
+        }
+    }
+
+    fn headers(&self) -> Vec<(&str, &str)> {
+        let mut headers: Vec<_> = if let Some(headers) = &self.multi_value_headers {
+            headers
+                .iter()
+                .flat_map(|(k, values)| values.iter().map(move |v| (k.as_str(), v.as_str())))
+                .collect()
+        } else {
+            self.headers
+                .iter()
+                .flatten()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect()
+        };
+        // payload version 2.0 moves the cookies out of the headers
+        headers.extend(
+            self.cookies
+                .iter()
+                .flatten()
+                .map(|v| ("cookie", v.as_str())),
+        );
+        headers
+    }
+
+    fn source_ip(&self) -> Option<IpAddr> {
+        let ctx = &self.request_context;
+        let ip = ctx
+            .http
+            .as_ref()
+            .and_then(|v| v.source_ip.as_deref())
+            .or_else(|| ctx.identity.as_ref().and_then(|v| v.source_ip.as_deref()))
+            .or_else(|| {
+                // ALB only reports the client address in the X-Forwarded-For header. It appends the address
+                // to the header sent by the client, so only the last entry can be trusted.
+                self.headers()
+                    .into_iter()
+                    .rfind(|(k, _)| k.eq_ignore_ascii_case("x-forwarded-for"))
+                    .and_then(|(_, v)| v.rsplit(',').next())
+            })?;
+        ip.trim().parse().ok()
+    }
+
+    fn to_request(&self) -> MartinResult<Request> {
+        let method =
+            Method::from_bytes(self.method().as_bytes()).map_err(|e| InternalError(Box::new(e)))?;
+        let mut req = TestRequest::default()
+            .method(method)
+            .uri(&self.path_and_query());
+        for (name, value) in self.headers() {
+            req = req.append_header((name, value));
+        }
+        if let Some(ip) = self.source_ip() {
+            req = req.peer_addr(SocketAddr::new(ip, 0));
+        }
+        if let Some(body) = &self.body {
+            req = req.set_payload(if self.is_base64_encoded {
+                BASE64_STANDARD
+                    .decode(body)
+                    .map_err(|e| InternalError(Box::new(e)))?
+            } else {
+                body.clone().into_bytes()
+            });
+        }
+        Ok(req.to_request())
+    }
+}
+
+async fn handle<S, B>(service: &S, event: LambdaRequest) -> Value
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let kind = event.kind();
+    let multi_value = event.multi_value_headers.is_some();
+    let Ok(request) = event.to_request() else {
+        return LambdaResponse::text(400, "Bad Request").to_json(kind, multi_value);
+    };
+    let response = match service.call(request).await {
+        Ok(response) => LambdaResponse::new(response.into_parts().1).await,
+        Err(e) => LambdaResponse::new(e.error_response()).await,
+    };
+    response
+        .unwrap_or_else(|| LambdaResponse::text(500, "Internal Server Error"))
+        .to_json(kind, multi_value)
+}
+
+struct LambdaResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+impl LambdaResponse {
+    async fn new<B: MessageBody>(response: HttpResponse<B>) -> Option<Self> {
+        let status = response.status();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        let body = to_bytes(response.into_body()).await.ok()?;
+        Some(Self {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn text(status: u16, body: &'static str) -> Self {
+        Self {
+            status: StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            headers: vec![(CONTENT_TYPE.to_string(), "text/plain".to_string())],
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    fn header(&self, name: &HeaderName) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name.as_str())
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Only uncompressed text can be returned as is, everything else - including all tiles - must be base64-encoded
+    fn is_text(&self) -> bool {
+        self.header(&CONTENT_ENCODING).is_none()
+            && self.header(&CONTENT_TYPE).is_some_and(|v| {
+                let v = v.to_ascii_lowercase();
+                v.starts_with("text/")
+                    || v.starts_with("application/json")
+                    || v.starts_with("application/xml")
+                    || v.starts_with("application/javascript")
+                    || v.starts_with("image/svg")
+            })
+            && std::str::from_utf8(&self.body).is_ok()
+    }
+
+    /// Serialize the response in the format expected by the service that sent the request
+    fn to_json(&self, kind: EventKind, multi_value: bool) -> Value {
+        let (body, is_base64) = if self.is_text() {
+            (String::from_utf8_lossy(&self.body).to_string(), false)
+        } else {
+            (BASE64_STANDARD.encode(&self.body), true)
+        };
+        let mut result = json!({
+            "statusCode": self.status.as_u16(),
+            "body": body,
+            "isBase64Encoded": is_base64,
+        });
+
+        if kind == EventKind::Alb {
+            result["statusDescription"] = json!(self.status.to_string());
+        }
+        if kind == EventKind::V2 {
+            let (cookies, headers): (Vec<_>, Vec<_>) = self
+                .headers
+                .iter()
+                .partition(|(k, _)| k == SET_COOKIE.as_str());
+            result["cookies"] = json!(cookies.into_iter().map(|(_, v)| v).collect::<Vec<_>>());
+            result["headers"] = Value::Object(join_headers(headers));
+        } else if multi_value || kind == EventKind::RestApi {
+            let mut headers = Map::new();
+            for (k, v) in &self.headers {
+                if let Value::Array(values) = headers.entry(k).or_insert_with(|| json!([])) {
+                    values.push(json!(v));
+                }
+            }
+            result["multiValueHeaders"] = Value::Object(headers);
+        } else {
+            result["headers"] = Value::Object(join_headers(self.headers.iter().collect()));
+        }
+        result
+    }
+}
+
+/// Combine repeated headers into a single comma-separated value
+fn join_headers(headers: Vec<&(String, String)>) -> Map<String, Value> {
+    let mut result = Map::new();
+    for (k, v) in headers {
+        match result.get_mut(k) {
+            Some(Value::String(value)) => {
+                value.push_str(", ");
+                value.push_str(v);
+            }
+            _ => {
+                result.insert(k.clone(), json!(v));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web, App, HttpRequest};
+
+    use super::*;
+
+    async fn call(event: Value) -> Value {
+        let app = App::new()
+            .route(
+                "/echo",
+                web::get().to(|req: HttpRequest| async move {
+                    HttpResponse::Ok().json(json!({
+                        "query": req.query_string(),
+                        "ip": req.peer_addr().map(|v| v.ip().to_string()),
+                        "accept": req.headers().get("accept-encoding").map(|v| v.to_str().unwrap()),
+                    }))
+                }),
+            )
+            .route(
+                "/tile",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("application/x-protobuf")
+                        .insert_header((CONTENT_ENCODING, "gzip"))
+                        .body(vec![0x1f_u8, 0x8b, 0, 255])
+                }),
+            );
+        let service = app
+            .into_factory()
+            .new_service(AppConfig::default())
+            .await
+            .unwrap();
+        handle(&service, serde_json::from_value(event).unwrap()).await
+    }
+
+    #[actix_rt::test]
+    async fn test_function_url() {
+        let response = call(json!({
+            "version": "2.0",
+            "rawPath": "/echo",
+            "rawQueryString": "a=1&b=x%20y",
+            "headers": {"accept-encoding": "gzip"},
+            "isBase64Encoded": false,
+            "requestContext": {"http": {"method": "GET", "sourceIp": "10.0.0.1"}},
+        }))
+        .await;
+        assert_eq!(response["statusCode"], 200);
+        assert_eq!(response["isBase64Encoded"], false);
+        assert_eq!(response["headers"]["content-type"], "application/json");
+        let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"query": "a=1&b=x%20y", "ip": "10.0.0.1", "accept": "gzip"})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_alb_binary() {
+        let response = call(json!({
+            "httpMethod": "GET",
+            "path": "/tile",
+            "queryStringParameters": {},
+            "headers": {"accept-encoding": "gzip", "x-forwarded-for": "10.0.0.2"},
+            "body": "",
+            "isBase64Encoded": false,
+            "requestContext": {"elb": {"targetGroupArn": "arn:aws:elasticloadbalancing:..."}},
+        }))
+        .await;
+        assert_eq!(response["statusCode"], 200);
+        assert_eq!(response["statusDescription"], "200 OK");
+        assert_eq!(response["isBase64Encoded"], true);
+        assert_eq!(response["body"], "H4sA/w==");
+        assert_eq!(response["headers"]["content-encoding"], "gzip");
+        assert!(response.get("multiValueHeaders").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_alb_multi_value() {
+        let response = call(json!({
+            "httpMethod": "GET",
+            "path": "/echo",
+            "multiValueQueryStringParameters": {"a": ["x%20y"]},
+            "multiValueHeaders": {"x-forwarded-for": ["10.0.0.3, 10.0.0.4"]},
+            "requestContext": {"elb": {}},
+        }))
+        .await;
+        assert_eq!(
+            response["multiValueHeaders"]["content-type"],
+            json!(["application/json"])
+        );
+        let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"query": "a=x%20y", "ip": "10.0.0.4", "accept": null})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_alb_spoofed_forwarded_for() {
+        let ip = |headers: Value| async move {
+            let response = call(json!({
+                "httpMethod": "GET",
+                "path": "/echo",
+                "multiValueHeaders": headers,
+                "requestContext": {"elb": {}},
+            }))
+            .await;
+            let body: Value = serde_json::from_str(response["body"].as_str().unwrap()).unwrap();
+            body["ip"].clone()
+        };
+        // the client sent its own X-Forwarded-For header, and ALB appended the real address to it
+        assert_eq!(
+            ip(json!({"x-forwarded-for": ["1.2.3.4, 10.0.0.6"]})).await,
+            "10.0.0.6"
+        );
+        assert_eq!(
+            ip(json!({"x-forwarded-for": ["1.2.3.4", "10.0.0.7"]})).await,
+            "10.0.0.7"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_rest_api() {
+        let response = call(json!({
+            "httpMethod": "GET",
+            "path": "/missing",
+            "queryStringParameters": {"a": "x y"},
+            "multiValueHeaders": {},
+            "requestContext": {"identity": {"sourceIp": "10.0.0.5"}},
+        }))
+        .await;
+        assert_eq!(response["statusCode"], 404);
+        assert!(response["multiValueHeaders"].is_object());
+    }
+}
@@ -0,0 +1,161 @@
+//! Routes of the older Martin versions, enabled with the `legacy_rpc_routes` option
+//! to ease upgrading existing clients.
+
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header::AcceptEncoding;
+use actix_web::web::{Data, Path};
+use actix_web::{route, HttpMessage as _, HttpRequest, HttpResponse, Result as ActixResult};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::config::ServerState;
+use crate::srv::rate_limit::ServedTiles;
+use crate::srv::{merge_tilejson, DynTileSource, SrvConfig};
+use crate::utils::OptMainCache;
+use crate::TileCoord;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    function: String,
+}
+
+#[derive(Deserialize)]
+struct RpcTileRequest {
+    function: String,
+    z: u8,
+    x: u32,
+    y: u32,
+}
+
+/// Find the ID of a function source by its `schema.function` name
+async fn get_source_id(
+    srv_config: &SrvConfig,
+    state: &RwLock<ServerState>,
+    function: &str,
+) -> ActixResult<String> {
+    if srv_config.legacy_rpc_routes != Some(true) {
+        return Err(ErrorNotFound("Legacy routes are disabled"));
+    }
+    state
+        .read()
+        .await
+        .pg_functions
+        .get(function)
+        .cloned()
+        .ok_or_else(|| ErrorNotFound(format!("Function {function} does not exist")))
+}
+
+#[route("/rpc/{function}.json", method = "GET", method = "HEAD")]
+async fn get_rpc_tilejson(
+    req: HttpRequest,
+    path: Path<RpcRequest>,
+    srv_config: Data<RwLock<SrvConfig>>,
+    state: Data<RwLock<ServerState>>,
+) -> ActixResult<HttpResponse> {
+    let srv_config_guard = srv_config.read().await;
+    let source_id = get_source_id(&srv_config_guard, &state, &path.function).await?;
+    let state_guard = state.read().await;
+    let sources = state_guard.tiles.get_sources(&source_id, None)?.0;
+
+    let info = req.connection_info();
+    let base_path = srv_config_guard.base_path.as_deref().unwrap_or_default();
+    let tiles_url = format!(
+        "{}://{}{base_path}/rpc/{}/{{z}}/{{x}}/{{y}}.pbf",
+        info.scheme(),
+        info.host(),
+        path.function
+    );
+    Ok(HttpResponse::Ok().json(merge_tilejson(&sources, tiles_url)))
+}
+
+#[route("/rpc/{function}/{z}/{x}/{y}.pbf", method = "GET", method = "HEAD")]
+async fn get_rpc_tile(
+    req: HttpRequest,
+    path: Path<RpcTileRequest>,
+    srv_config: Data<RwLock<SrvConfig>>,
+    state: Data<RwLock<ServerState>>,
+    cache: Data<RwLock<OptMainCache>>,
+) -> ActixResult<HttpResponse> {
+    let srv_config_guard = srv_config.read().await;
+    let source_id = get_source_id(&srv_config_guard, &state, &path.function).await?;
+    let state_guard = state.read().await;
+    let cache_guard = cache.read().await;
+
+    let src = DynTileSource::new(
+        &state_guard.tiles,
+        &source_id,
+        Some(path.z),
+        req.query_string(),
+        req.get_header::<AcceptEncoding>(),
+        Some(&srv_config_guard),
+        cache_guard.as_ref(),
+    )?;
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    let mut response = src.get_http_response(xyz, &req).await?;
+    response
+        .extensions_mut()
+        .insert(ServedTiles::new(1, &source_id));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::App;
+    use tilejson::{tilejson, TileJSON};
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::TileData;
+
+    async fn get(legacy_rpc_routes: Option<bool>, path: &str) -> actix_web::dev::ServiceResponse {
+        let mut state = ServerState::default();
+        state.tiles.add_source(Box::new(TestSource {
+            id: "fnc",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(&[1, 2, 3]),
+        }));
+        state
+            .pg_functions
+            .insert("public.my_function".to_string(), "fnc".to_string());
+        let srv_config = SrvConfig {
+            legacy_rpc_routes,
+            ..SrvConfig::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(RwLock::new(state)))
+                .app_data(Data::new(RwLock::new(srv_config)))
+                .app_data(Data::new(RwLock::new(OptMainCache::None)))
+                .service(get_rpc_tilejson)
+                .service(get_rpc_tile),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri(path).to_request()).await
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_routes() {
+        let response = get(Some(true), "/rpc/public.my_function/0/0/0.pbf").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, vec![1_u8, 2, 3]);
+
+        let response = get(Some(true), "/rpc/public.my_function.json").await;
+        let tj: TileJSON = read_body_json(response).await;
+        assert_eq!(
+            tj.tiles,
+            vec!["http://localhost:8080/rpc/public.my_function/{z}/{x}/{y}.pbf"]
+        );
+
+        let response = get(Some(true), "/rpc/public.other/0/0/0.pbf").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = get(None, "/rpc/public.my_function/0/0/0.pbf").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,252 @@
+//! Prometheus metrics of the HTTP server, available at `/metrics` with the `metrics` feature.
+//! Request durations are recorded in a separate histogram for each class of routes,
+//! so that e.g. the tile latency can be measured without the much faster metadata endpoints.
+
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::ContentType;
+use actix_web::web::Data;
+use actix_web::{route, HttpResponse};
+use enum_display::EnumDisplay;
+use futures::future::LocalBoxFuture;
+use prometheus::{
+    Encoder as _, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::MartinResult;
+
+/// Shared by all registries, so that tiles can be counted without access to the app data
+static SLOW_TILES: OnceLock<IntCounterVec> = OnceLock::new();
+
+/// Routes with separate request duration histograms
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, EnumDisplay,
+)]
+#[serde(rename_all = "lowercase")]
+#[enum_display(case = "Lower")]
+pub enum RouteClass {
+    /// Tiles, including batch requests and `UTFGrid`
+    Tiles,
+    Sprites,
+    Fonts,
+    /// All other routes, e.g. the catalog, `TileJSON`, and health check
+    Metadata,
+}
+
+impl RouteClass {
+    pub const ALL: [Self; 4] = [Self::Tiles, Self::Sprites, Self::Fonts, Self::Metadata];
+
+    /// Classify a request by the path pattern of the route that handled it
+    #[must_use]
+    pub fn from_pattern(pattern: Option<&str>) -> Self {
+        match pattern {
+            Some(v) if v.contains("{z}") || v.ends_with("/tiles") => Self::Tiles,
+            Some(v) if v.contains("/sprite/") => Self::Sprites,
+            Some(v) if v.contains("/font/") => Self::Fonts,
+            _ => Self::Metadata,
+        }
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct MetricsConfig {
+    /// Upper bounds (in seconds) of the request duration histogram buckets for each route class.
+    /// Route classes without buckets use the Prometheus default buckets.
+    pub buckets: Option<BTreeMap<RouteClass, Vec<f64>>>,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    durations: BTreeMap<RouteClass, HistogramVec>,
+}
+
+impl Metrics {
+    pub fn new(config: &MetricsConfig) -> MartinResult<Self> {
+        let registry = Registry::new_custom(Some("martin".to_string()), None)?;
+        let mut durations = BTreeMap::new();
+        for class in RouteClass::ALL {
+            let mut opts = HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests",
+            )
+            .const_label("route", class.to_string());
+            if let Some(buckets) = config.buckets.as_ref().and_then(|v| v.get(&class)) {
+                if buckets.is_empty() || buckets.windows(2).any(|v| v[0] >= v[1]) {
+                    return Err(prometheus::Error::Msg(format!(
+                        "buckets of the {class} routes must be in increasing order"
+                    )))?;
+                }
+                opts = opts.buckets(buckets.clone());
+            }
+            let histogram = HistogramVec::new(opts, &["method", "status"])?;
+            registry.register(Box::new(histogram.clone()))?;
+            durations.insert(class, histogram);
+        }
+        registry.register(Box::new(slow_tiles().clone()))?;
+        Ok(Self {
+            registry,
+            durations,
+        })
+    }
+
+    fn observe(&self, class: RouteClass, method: &str, status: u16, seconds: f64) {
+        self.durations[&class]
+            .with_label_values(&[method, &status.to_string()])
+            .observe(seconds);
+    }
+
+    /// All metrics in the Prometheus text format
+    #[must_use]
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        // encoding to a vector can only fail for invalid metrics, which are rejected on registration
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+fn slow_tiles() -> &'static IntCounterVec {
+    SLOW_TILES.get_or_init(|| {
+        let opts = Opts::new(
+            "slow_tiles_total",
+            "Number of tiles that took longer than slow_tile_ms to generate",
+        );
+        IntCounterVec::new(opts, &["source"]).expect("valid slow tiles counter")
+    })
+}
+
+/// Count a tile of the source that took longer than the configured threshold
+pub(crate) fn count_slow_tile(source_id: &str) {
+    slow_tiles().with_label_values(&[source_id]).inc();
+}
+
+#[route("/metrics", method = "GET")]
+#[allow(clippy::unused_async)]
+pub(crate) async fn get_metrics(metrics: Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(metrics.encode())
+}
+
+/// Middleware that records the duration of every request in the metrics
+#[derive(Clone)]
+pub struct HttpMetrics(pub Arc<Metrics>);
+
+impl<S, B> Transform<S, ServiceRequest> for HttpMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = HttpMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpMetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.0.clone(),
+        }))
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            let request = response.request();
+            metrics.observe(
+                RouteClass::from_pattern(request.match_pattern().as_deref()),
+                request.method().as_str(),
+                response.status().as_u16(),
+                start.elapsed().as_secs_f64(),
+            );
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App};
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn test_route_class() {
+        let class = |v| RouteClass::from_pattern(Some(v));
+        assert_eq!(class("/{source_ids}/{z}/{x}/{y}"), RouteClass::Tiles);
+        assert_eq!(class("/v1/{source_ids}/tiles"), RouteClass::Tiles);
+        assert_eq!(
+            class("/t/{name}/sprite/{source_ids}.png"),
+            RouteClass::Sprites
+        );
+        assert_eq!(class("/font/{fontstack}/{start}-{end}"), RouteClass::Fonts);
+        assert_eq!(class("/{source_ids}"), RouteClass::Metadata);
+        assert_eq!(RouteClass::from_pattern(None), RouteClass::Metadata);
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        let config: MetricsConfig = serde_yaml::from_str(indoc! {"
+            buckets:
+              tiles: [0.05, 0.5]
+        "})
+        .unwrap();
+        let metrics = Arc::new(Metrics::new(&config).unwrap());
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(metrics.clone()))
+                .wrap(HttpMetrics(metrics.clone()))
+                .service(get_metrics)
+                .route("/{source_ids}/{z}/{x}/{y}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        call_service(&app, TestRequest::get().uri("/src/0/0/0").to_request()).await;
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"martin_http_request_duration_seconds_bucket{method="GET",route="tiles",status="200",le="0.5"} 1"#
+        ));
+        assert!(!body.contains(r#"route="tiles",status="200",le="0.1""#));
+
+        count_slow_tile("src");
+        let body = metrics.encode();
+        assert!(body.contains(r#"martin_slow_tiles_total{source="src"}"#));
+    }
+
+    #[test]
+    fn test_invalid_buckets() {
+        let config: MetricsConfig = serde_yaml::from_str("buckets: { fonts: [1, 0.5] }").unwrap();
+        assert!(Metrics::new(&config).is_err());
+    }
+}
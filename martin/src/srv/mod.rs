@@ -1,11 +1,107 @@
+mod access;
+pub use access::{AccessConfig, AccessControl, AccessRule, EndpointClass};
+
+mod admin;
+pub use admin::{admin_router, new_admin_server};
+
+mod app;
+pub use app::MartinApp;
+
+mod audit;
+pub use audit::{
+    AuditAction, AuditConfig, AuditRecord, AUDIT_ACTOR_CONFIG_WATCHER, AUDIT_ACTOR_HEADER_DEFAULT,
+};
+
+mod catalog_html;
+
+mod cdn_purge;
+pub use cdn_purge::{purge_cdn, source_surrogate_key, CdnPurgeConfig};
+
 mod config;
-pub use config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
+pub use config::{
+    CacheTagHeader, CircuitBreakerConfig, DiscoveryRetryConfig, ErrorDetail, LimitPolicy,
+    SourceOptions, SrvConfig, StoredEncodingPolicy, TileLimits, KEEP_ALIVE_DEFAULT,
+    LISTEN_ADDRESSES_DEFAULT, SHUTDOWN_TIMEOUT_DEFAULT,
+};
+
+mod cors;
+pub use cors::{CorsConfig, CORS_METHODS_DEFAULT};
 
 #[cfg(feature = "fonts")]
 mod fonts;
 
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "grpc")]
+pub use grpc::{new_grpc_server, GrpcTileService};
+
+mod health;
+pub(crate) use health::record_source_health;
+pub use health::{source_health, HealthStatus, SourceHealth};
+
+#[cfg(feature = "redis")]
+mod invalidation;
+#[cfg(feature = "redis")]
+pub use invalidation::{CacheInvalidationConfig, Invalidation, INVALIDATION_CHANNEL_DEFAULT};
+
+#[cfg(feature = "lambda")]
+mod lambda;
+
+#[cfg(feature = "postgres")]
+mod legacy;
+
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub(crate) use metrics::count_slow_tile;
+#[cfg(feature = "metrics")]
+pub use metrics::{HttpMetrics, Metrics, MetricsConfig, RouteClass};
+
+mod openapi;
+pub use openapi::openapi_spec;
+
+mod problem;
+pub use problem::{problem_json, Problem, ProblemTile, PROBLEM_JSON};
+
+mod rate_limit;
+pub use rate_limit::{
+    FileQuotaStore, LimitConfig, QuotaStore, QuotaUsage, RateLimit, RateLimitConfig, RateLimiter,
+    API_KEY_HEADER_DEFAULT,
+};
+
+#[cfg(any(feature = "fonts", feature = "sprites"))]
+mod range;
+
+#[cfg(feature = "raster")]
+mod render;
+#[cfg(feature = "raster")]
+pub use render::RENDER_WARNING_HEADER;
+
+mod request_id;
+pub(crate) use request_id::current_request_id;
+pub use request_id::{RequestId, ACCESS_LOG_FORMAT, REQUEST_ID_HEADER};
+
+mod robots;
+pub use robots::X_ROBOTS_TAG;
+
+#[cfg(feature = "console")]
+mod runtime;
+#[cfg(feature = "console")]
+pub use runtime::{runtime_stats, RuntimeStats, WorkerStats};
+
+mod security_headers;
+pub use security_headers::{
+    SecurityHeadersConfig, CONTENT_SECURITY_POLICY_DEFAULT, REFERRER_POLICY_DEFAULT,
+};
+
 mod server;
-pub use server::{new_server, router, Catalog, RESERVED_KEYWORDS};
+pub use server::{new_server, router, Catalog, CatalogDiff, RESERVED_KEYWORDS};
+
+mod service;
+pub use service::{ServiceRequest, ServiceResponse, TileService};
+
+#[cfg(feature = "raster")]
+mod terrain;
 
 mod tiles;
 pub use tiles::{DynTileSource, TileRequest};
@@ -15,3 +111,29 @@ pub use tiles_info::{merge_tilejson, SourceIDsRequest};
 
 #[cfg(feature = "sprites")]
 mod sprites;
+
+mod style;
+pub use style::generate_style;
+
+#[cfg(feature = "systemd")]
+mod systemd;
+#[cfg(feature = "systemd")]
+pub use systemd::notify_ready;
+
+#[cfg(feature = "tower")]
+mod tower;
+
+mod url_template;
+pub use url_template::{UrlTemplate, UrlTemplates};
+
+mod usage;
+pub use usage::{
+    UsageByKey, UsageConfig, UsageCounter, UsageRecord, UsageRecorder, UsageSink, UsageSinkConfig,
+    UsageTotals, UsageTracking, OTHER_API_KEY, USAGE_FLUSH_INTERVAL_DEFAULT,
+};
+
+mod version;
+pub use version::{ApiVersion, API_VERSION, API_VERSION_HEADER, API_VERSION_PREFIX};
+
+mod webhooks;
+pub use webhooks::{notify_webhooks, webhook_signature, WebhookConfig, WEBHOOK_SIGNATURE_HEADER};
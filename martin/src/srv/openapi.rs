@@ -0,0 +1,549 @@
+use actix_web::web::Data;
+use actix_web::{middleware, route, HttpResponse};
+use serde_json::{json, Map, Value};
+use tokio::sync::RwLock;
+
+use crate::srv::{Catalog, SrvConfig, UrlTemplate, API_VERSION_PREFIX, PROBLEM_JSON};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Serve an `OpenAPI` 3 document describing all routes, including one entry per tile source.
+/// Only the routes available with the server configuration are listed, e.g. no `/refresh` in read-only mode.
+#[route(
+    "/api.json",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_openapi(
+    catalog: Data<RwLock<Catalog>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> HttpResponse {
+    let catalog_guard = catalog.read().await;
+    let srv_config_guard = srv_config.read().await;
+    HttpResponse::Ok().json(openapi_spec(&catalog_guard, &srv_config_guard))
+}
+
+/// The `OpenAPI` document of the routes registered by the server with this configuration.
+/// The routes of the current API version are listed both with and without the `/v1` prefix.
+#[must_use]
+#[allow(clippy::too_many_lines)]
+pub fn openapi_spec(catalog: &Catalog, srv_config: &SrvConfig) -> Value {
+    let read_only = srv_config.read_only.unwrap_or_default();
+    let mut paths = Map::new();
+
+    paths.insert(
+        "/".to_string(),
+        get_op("Server status text", "text/plain", &[]),
+    );
+    paths.insert(
+        "/health".to_string(),
+        get_op("Health check, returns 200 OK if healthy", "text/plain", &[]),
+    );
+    paths.insert(
+        "/health/detail".to_string(),
+        get_op(
+            "Health of each tile source: ok, degraded, failing, or disabled, with the last error",
+            "application/json",
+            &[],
+        ),
+    );
+    paths.insert(
+        "/catalog".to_string(),
+        get_op("List of all available sources", "application/json", &[]),
+    );
+    paths.insert(
+        "/robots.txt".to_string(),
+        get_op(
+            "Crawling rules that disallow the tiles of all sources",
+            "text/plain",
+            &[],
+        ),
+    );
+    paths.insert(
+        "/api.json".to_string(),
+        get_op("This OpenAPI document", "application/json", &[]),
+    );
+    if !read_only {
+        paths.insert(
+            "/refresh".to_string(),
+            json!({ "post": {
+                "summary": "Re-read configuration and refresh all sources",
+                "responses": { "200": {
+                    "description": "Sources have been refreshed. Lists the IDs of the added, removed, and changed sources",
+                    "content": { "application/json": {} },
+                } },
+            }}),
+        );
+        paths.insert(
+            "/{source_ids}/purge".to_string(),
+            json!({ "post": {
+                "summary": "Remove the tiles of one or more comma-separated tile sources from the cache and the CDNs",
+                "parameters": path_params(&["source_ids"]),
+                "responses": {
+                    "204": { "description": "The tiles have been purged" },
+                    "404": problem("Not found"),
+                },
+            }}),
+        );
+    }
+    paths.insert(
+        "/{source_ids}".to_string(),
+        get_op(
+            "TileJSON for one or more comma-separated tile sources",
+            "application/json",
+            &["source_ids"],
+        ),
+    );
+    paths.insert(
+        "/{source_ids}/{z}/{x}/{y}".to_string(),
+        tile_op(
+            "Tile from one or more comma-separated tile sources",
+            "application/octet-stream",
+            &["source_ids", "z", "x", "y"],
+        ),
+    );
+    paths.insert("/{source_ids}/tiles".to_string(), batch_op());
+    paths.insert(
+        "/{source_id}/{z}/{x}/{y}.grid.json".to_string(),
+        tile_op(
+            "UTFGrid interaction data of a single table source with a utfgrid_key",
+            "application/json",
+            &["source_id", "z", "x", "y"],
+        ),
+    );
+    paths.insert(
+        "/style/{source_ids}".to_string(),
+        get_op(
+            "Minimal MapLibre style showing all layers of one or more comma-separated tile sources",
+            "application/json",
+            &["source_ids"],
+        ),
+    );
+
+    for (id, entry) in &catalog.tiles {
+        let name = entry.name.as_deref().unwrap_or(id);
+        paths.insert(
+            format!("/{id}"),
+            get_op(&format!("TileJSON for {name}"), "application/json", &[]),
+        );
+        paths.insert(
+            format!("/{id}/{{z}}/{{x}}/{{y}}"),
+            tile_op(
+                &format!("Tile from {name}"),
+                &entry.content_type,
+                &["z", "x", "y"],
+            ),
+        );
+    }
+
+    #[cfg(feature = "sprites")]
+    {
+        paths.insert(
+            "/sprite/{source_ids}.json".to_string(),
+            get_op("Sprite index", "application/json", &["source_ids"]),
+        );
+        paths.insert(
+            "/sprite/{source_ids}.png".to_string(),
+            get_op("Sprite image", "image/png", &["source_ids"]),
+        );
+    }
+
+    #[cfg(feature = "raster")]
+    {
+        paths.insert(
+            "/{source_ids}/{z}/{x}/{y}.{format}".to_string(),
+            tile_op(
+                "Vector tile from one or more comma-separated tile sources rendered as a png, jpg, or webp image, if rendering is enabled",
+                "image/png",
+                &["source_ids", "z", "x", "y", "format"],
+            ),
+        );
+        paths.insert(
+            "/image".to_string(),
+            get_op(
+                "Static map image of the bbox area with the given size, drawn from the tiles of the source query parameter, if rendering is enabled",
+                "image/png",
+                &[],
+            ),
+        );
+        paths.insert(
+            "/{source_id}/layer.json".to_string(),
+            get_op(
+                "Cesium terrain metadata of a raster source with elevations encoded as colors, if terrain is enabled",
+                "application/json",
+                &["source_id"],
+            ),
+        );
+        paths.insert(
+            "/{source_id}/{z}/{x}/{y}.terrain".to_string(),
+            get_op(
+                "Quantized-mesh terrain tile with the y coordinate in the TMS scheme, if terrain is enabled",
+                "application/vnd.quantized-mesh",
+                &["source_id", "z", "x", "y"],
+            ),
+        );
+    }
+
+    #[cfg(feature = "fonts")]
+    paths.insert(
+        "/font/{fontstack}/{start}-{end}".to_string(),
+        get_op(
+            "Font glyph range for one or more comma-separated fonts",
+            "application/x-protobuf",
+            &["fontstack", "start", "end"],
+        ),
+    );
+
+    #[cfg(feature = "postgres")]
+    {
+        paths.insert(
+            "/rpc/{function}.json".to_string(),
+            get_op(
+                "TileJSON of a function source at its legacy URL",
+                "application/json",
+                &["function"],
+            ),
+        );
+        paths.insert(
+            "/rpc/{function}/{z}/{x}/{y}.pbf".to_string(),
+            tile_op(
+                "Tile of a function source at its legacy URL",
+                "application/x-protobuf",
+                &["function", "z", "x", "y"],
+            ),
+        );
+    }
+
+    let versioned: Vec<_> = paths
+        .iter()
+        .map(|(path, op)| (format!("{API_VERSION_PREFIX}{path}"), op.clone()))
+        .collect();
+    paths.extend(versioned);
+
+    if !read_only {
+        #[cfg(feature = "metrics")]
+        paths.insert(
+            "/metrics".to_string(),
+            get_op("Prometheus metrics", "text/plain", &[]),
+        );
+        #[cfg(feature = "console")]
+        paths.insert(
+            "/debug/runtime".to_string(),
+            get_op(
+                "Statistics of the async runtime of each worker",
+                "application/json",
+                &[],
+            ),
+        );
+    }
+
+    // alternative tile URLs of the sources, rewritten to the standard tile path before routing
+    for (id, opts) in srv_config.source_options.iter().flatten() {
+        let Some(entry) = catalog.tiles.get(id) else {
+            continue;
+        };
+        let name = entry.name.as_deref().unwrap_or(id);
+        for template in opts.url_templates.iter().flatten() {
+            if UrlTemplate::parse(template, id).is_ok() {
+                paths.insert(
+                    template.replace("{source}", id),
+                    tile_op(
+                        &format!("Tile from {name}"),
+                        &entry.content_type,
+                        &["z", "x", "y"],
+                    ),
+                );
+            }
+        }
+    }
+
+    let mut spec = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Martin",
+            "description": "Blazing fast and lightweight tile server with PostGIS, MBTiles, and PMTiles support",
+            "version": VERSION,
+        },
+        "paths": paths,
+        "components": { "schemas": { "Problem": problem_schema() } },
+    });
+    if let Some(base_path) = &srv_config.base_path {
+        spec["servers"] = json!([{ "url": base_path }]);
+    }
+    spec
+}
+
+fn get_op(summary: &str, content_type: &str, params: &[&str]) -> Value {
+    json!({ "get": {
+        "summary": summary,
+        "parameters": path_params(params),
+        "responses": {
+            "200": {
+                "description": "OK",
+                "content": { content_type: {} },
+            },
+            "404": problem("Not found"),
+        },
+    }})
+}
+
+fn tile_op(summary: &str, content_type: &str, params: &[&str]) -> Value {
+    let mut op = get_op(summary, content_type, params);
+    op["get"]["responses"]["204"] = json!({ "description": "No data for this tile" });
+    op
+}
+
+fn batch_op() -> Value {
+    json!({ "post": {
+        "summary": "Many tiles from one or more comma-separated tile sources at once",
+        "parameters": path_params(&["source_ids"]),
+        "requestBody": {
+            "required": true,
+            "content": { "application/json": { "schema": {
+                "type": "array",
+                "items": { "type": "string", "example": "0/0/0" },
+            }}},
+        },
+        "responses": {
+            "200": {
+                "description": "One part per requested tile",
+                "content": { "multipart/mixed": {} },
+            },
+            "400": problem("Invalid tile coordinates"),
+            "404": problem("Not found"),
+        },
+    }})
+}
+
+/// An error response with problem details
+fn problem(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { PROBLEM_JSON: { "schema": { "$ref": "#/components/schemas/Problem" } } },
+    })
+}
+
+fn problem_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["type", "title", "status"],
+        "properties": {
+            "type": { "type": "string" },
+            "title": { "type": "string" },
+            "status": { "type": "integer" },
+            "detail": { "type": "string" },
+            "source_id": { "type": "string" },
+            "tile": {
+                "type": "object",
+                "properties": {
+                    "z": { "type": "integer" },
+                    "x": { "type": "integer" },
+                    "y": { "type": "integer" },
+                },
+            },
+        },
+    })
+}
+
+fn path_params(params: &[&str]) -> Value {
+    params
+        .iter()
+        .map(|name| {
+            let schema = match *name {
+                "source_ids" | "source_id" | "function" | "fontstack" | "format" => {
+                    json!({ "type": "string" })
+                }
+                _ => json!({ "type": "integer", "minimum": 0 }),
+            };
+            json!({ "name": name, "in": "path", "required": true, "schema": schema })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use actix_web::http::Method;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    use super::*;
+    use crate::srv::server::{router, static_router};
+    use crate::srv::SourceOptions;
+    use crate::CatalogSourceEntry;
+
+    #[test]
+    fn test_openapi_spec() {
+        let mut catalog = Catalog::default();
+        catalog.tiles.insert(
+            "src".to_string(),
+            CatalogSourceEntry {
+                content_type: "image/png".to_string(),
+                ..Default::default()
+            },
+        );
+        let srv_config = SrvConfig {
+            base_path: Some("/tiles".to_string()),
+            source_options: Some(BTreeMap::from([(
+                "src".to_string(),
+                SourceOptions {
+                    url_templates: Some(vec!["/old/{source}/{z}/{x}/{y}.png".to_string()]),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        let spec = openapi_spec(&catalog, &srv_config);
+        assert_eq!(spec["openapi"], "3.0.3");
+        assert_eq!(spec["servers"][0]["url"], "/tiles");
+        let tile = &spec["paths"]["/src/{z}/{x}/{y}"]["get"];
+        assert!(tile["responses"]["200"]["content"]["image/png"].is_object());
+        assert_eq!(tile["parameters"].as_array().unwrap().len(), 3);
+        assert!(spec["paths"]["/src"]["get"].is_object());
+        assert!(spec["paths"]["/v1/src"]["get"].is_object());
+        assert_eq!(
+            tile["responses"]["404"]["content"][PROBLEM_JSON]["schema"]["$ref"],
+            "#/components/schemas/Problem"
+        );
+        let template = &spec["paths"]["/old/src/{z}/{x}/{y}.png"]["get"];
+        assert!(template["responses"]["200"]["content"]["image/png"].is_object());
+        assert!(spec["paths"]["/refresh"]["post"].is_object());
+
+        let srv_config = SrvConfig {
+            read_only: Some(true),
+            ..Default::default()
+        };
+        let spec = openapi_spec(&catalog, &srv_config);
+        for path in ["/refresh", "/v1/refresh", "/{source_ids}/purge", "/metrics"] {
+            assert!(spec["paths"].get(path).is_none(), "{path}");
+        }
+    }
+
+    /// A path of the document with sample values for its parameters
+    fn sample_path(path: &str) -> String {
+        path.replace("{source_ids}", "src")
+            .replace("{source_id}", "src")
+            .replace("{function}", "src")
+            .replace("{fontstack}", "src")
+            .replace("{format}", "png")
+            .replace("{start}", "0")
+            .replace("{end}", "255")
+            .replace("{z}", "1")
+            .replace("{x}", "2")
+            .replace("{y}", "3")
+    }
+
+    /// The route pattern without the regular expressions of its parameters, e.g. `{format:(png|jpg)}`
+    fn without_regex(pattern: &str) -> String {
+        let mut result = String::new();
+        let mut in_regex = false;
+        let mut depth = 0;
+        for c in pattern.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    in_regex = false;
+                }
+                ':' if depth > 0 => in_regex = true,
+                _ => {}
+            }
+            if !in_regex {
+                result.push(c);
+            }
+        }
+        result
+    }
+
+    #[actix_rt::test]
+    async fn test_openapi_routes() {
+        let catalog = Catalog::default();
+        for read_only in [false, true] {
+            let srv_config = SrvConfig {
+                read_only: Some(read_only),
+                ..Default::default()
+            };
+            let spec = openapi_spec(&catalog, &srv_config);
+            let paths = spec["paths"].as_object().unwrap();
+            let main_router = if read_only { static_router } else { router };
+            let app = init_service(App::new().configure(main_router)).await;
+
+            // every documented path is routed to the handler with the same pattern
+            for (path, ops) in paths {
+                for method in ops.as_object().unwrap().keys() {
+                    let req = TestRequest::default()
+                        .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+                        .uri(&sample_path(path))
+                        .to_request();
+                    let response = call_service(&app, req).await;
+                    let pattern = response
+                        .request()
+                        .match_pattern()
+                        .map(|v| without_regex(&v));
+                    assert_eq!(pattern.as_deref(), Some(path.as_str()), "{method} {path}");
+                }
+            }
+
+            // every registered route is documented
+            let mut routes = vec![
+                ("GET", "/"),
+                ("GET", "/health"),
+                ("GET", "/health/detail"),
+                ("GET", "/catalog"),
+                ("GET", "/api.json"),
+                ("GET", "/robots.txt"),
+                ("GET", "/style/src"),
+                ("GET", "/src"),
+                ("GET", "/src/1/2/3"),
+                ("GET", "/src/1/2/3.grid.json"),
+                ("POST", "/src/tiles"),
+                ("GET", "/sprite/src.json"),
+                ("GET", "/sprite/src.png"),
+                ("GET", "/font/Arial/0-255"),
+                ("GET", "/rpc/fnc.json"),
+                ("GET", "/rpc/fnc/1/2/3.pbf"),
+                ("GET", "/image"),
+                ("GET", "/src/1/2/3.png"),
+                ("GET", "/src/layer.json"),
+                ("GET", "/src/1/2/3.terrain"),
+            ];
+            if !read_only {
+                routes.extend([("POST", "/refresh"), ("POST", "/src/purge")]);
+            }
+            let versioned: Vec<_> = routes
+                .iter()
+                .map(|(m, p)| (*m, format!("/v1{p}")))
+                .collect();
+            let mut routes: Vec<_> = routes
+                .into_iter()
+                .map(|(m, p)| (m, p.to_string()))
+                .collect();
+            routes.extend(versioned);
+            routes.extend([
+                ("GET", "/metrics".to_string()),
+                ("GET", "/debug/runtime".to_string()),
+            ]);
+            for (method, path) in routes {
+                let req = TestRequest::default()
+                    .method(Method::from_bytes(method.as_bytes()).unwrap())
+                    .uri(&path)
+                    .to_request();
+                let response = call_service(&app, req).await;
+                // routes of disabled features are not registered
+                let Some(pattern) = response.request().match_pattern() else {
+                    continue;
+                };
+                let pattern = without_regex(&pattern);
+                assert!(
+                    paths
+                        .get(&pattern)
+                        .is_some_and(|ops| ops.get(method.to_lowercase()).is_some()),
+                    "{method} {path} is routed to {pattern}, which is not documented"
+                );
+            }
+        }
+    }
+}
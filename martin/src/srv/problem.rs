@@ -0,0 +1,217 @@
+//! Error responses as `application/problem+json` (RFC 7807), so that API clients can handle errors
+//! without parsing the error messages. The message of the original error response becomes the `detail`,
+//! and the source IDs and tile coordinates of the request are added when the route has them.
+
+use actix_web::body::{BoxBody, EitherBody, MessageBody};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+use crate::srv::ErrorDetail;
+use crate::TileCoord;
+
+/// Content type of the error responses
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Problem details of an error response
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct Problem {
+    /// Always `about:blank`, the problem is identified by its status code
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: String,
+    pub status: u16,
+    pub detail: Option<String>,
+    /// Requested source ID, or comma-separated source IDs
+    pub source_id: Option<String>,
+    pub tile: Option<ProblemTile>,
+}
+
+/// Requested tile coordinates
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct ProblemTile {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Problem {
+    #[must_use]
+    pub fn new(status: StatusCode, detail: Option<String>, req: &HttpRequest) -> Self {
+        let info = req.match_info();
+        let source_id = info
+            .get("source_ids")
+            .or_else(|| info.get("source_id"))
+            .map(ToString::to_string);
+        let tile = match (info.get("z"), info.get("x"), info.get("y")) {
+            (Some(z), Some(x), Some(y)) => match (z.parse(), x.parse(), y.parse()) {
+                (Ok(z), Ok(x), Ok(y)) => Some(ProblemTile { z, x, y }),
+                _ => None,
+            },
+            _ => None,
+        };
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            source_id,
+            tile,
+        }
+    }
+
+    /// Problem details of a single tile, e.g. of a part of a batch response
+    #[must_use]
+    pub fn for_tile(
+        status: StatusCode,
+        detail: Option<String>,
+        source_ids: &str,
+        xyz: TileCoord,
+    ) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            source_id: Some(source_ids.to_string()),
+            tile: Some(ProblemTile {
+                z: xyz.z,
+                x: xyz.x,
+                y: xyz.y,
+            }),
+        }
+    }
+}
+
+/// Middleware replacing the body of all client and server error responses with problem details
+#[must_use]
+pub fn problem_json<B: MessageBody + 'static>(error_detail: ErrorDetail) -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(move |res| into_problem_json(res, error_detail))
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn into_problem_json<B: MessageBody + 'static>(
+    res: ServiceResponse<B>,
+    error_detail: ErrorDetail,
+) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_problem = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(PROBLEM_JSON.as_bytes()));
+    if is_problem {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let (req, res) = res.into_parts();
+    let status = res.status();
+    let (res, body) = res.into_parts();
+    let detail = error_detail.includes(status);
+    let detail = body
+        .try_into_bytes()
+        .ok()
+        .filter(|_| detail)
+        .and_then(|b| String::from_utf8(b.to_vec()).ok())
+        .filter(|v| !v.is_empty());
+    let problem = Problem::new(status, detail, &req);
+    let body = serde_json::to_string(&problem).unwrap_or_default();
+    let mut res = res.set_body(BoxBody::new(body));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, res).map_body(|_, body| EitherBody::right(body)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_problem_json() {
+        let app = init_service(
+            App::new()
+                .wrap(problem_json(ErrorDetail::Full))
+                .route(
+                    "/{source_ids}/{z}/{x}/{y}",
+                    web::get().to(|| async {
+                        Err::<HttpResponse, _>(ErrorNotFound("Tile 0/0/0 does not exist"))
+                    }),
+                )
+                .route("/ok", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/a,b/0/0/0").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let body: Value = read_body_json(response).await;
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Tile 0/0/0 does not exist",
+                "source_id": "a,b",
+                "tile": {"z": 0, "x": 0, "y": 0},
+            })
+        );
+
+        // unknown routes have no detail
+        let req = TestRequest::get().uri("/unknown").to_request();
+        let body: Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(
+            body,
+            json!({"type": "about:blank", "title": "Not Found", "status": 404})
+        );
+
+        let req = TestRequest::get().uri("/ok").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_TYPE).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_error_detail() {
+        async fn detail(error_detail: ErrorDetail, uri: &str) -> Value {
+            let app = init_service(
+                App::new()
+                    .wrap(problem_json(error_detail))
+                    .route(
+                        "/server",
+                        web::get().to(|| async {
+                            Err::<HttpResponse, _>(ErrorInternalServerError("/secret/path"))
+                        }),
+                    )
+                    .route(
+                        "/client",
+                        web::get().to(|| async {
+                            Err::<HttpResponse, _>(ErrorNotFound("Source a does not exist"))
+                        }),
+                    ),
+            )
+            .await;
+            let req = TestRequest::get().uri(uri).to_request();
+            let body: Value = read_body_json(call_service(&app, req).await).await;
+            body["detail"].clone()
+        }
+
+        assert_eq!(detail(ErrorDetail::Full, "/server").await, "/secret/path");
+        assert_eq!(detail(ErrorDetail::Message, "/server").await, Value::Null);
+        assert_eq!(
+            detail(ErrorDetail::Message, "/client").await,
+            "Source a does not exist"
+        );
+        assert_eq!(detail(ErrorDetail::None, "/client").await, Value::Null);
+    }
+}
@@ -0,0 +1,112 @@
+//! `Range` requests of large responses, e.g. sprite sheets and font glyphs,
+//! so that interrupted downloads can be resumed and the data can be consumed progressively.
+
+use actix_web::http::header::{
+    ContentEncoding, ContentRange, ContentRangeSpec, Range, ACCEPT_RANGES, IF_RANGE,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage as _, HttpRequest, HttpResponse, HttpResponseBuilder};
+
+/// Respond with the byte range of the data requested with a `Range` header as `206 Partial Content`,
+/// or with all of the data otherwise. Requests for several ranges get all of the data as well.
+/// These responses have no validators to compare `If-Range` with, so conditional ranges get all of the data too.
+pub fn ranged_response(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    data: Bytes,
+) -> HttpResponse {
+    response.insert_header((ACCEPT_RANGES, "bytes"));
+    let spec = match req.get_header::<Range>() {
+        Some(Range::Bytes(specs)) if specs.len() == 1 && !req.headers().contains_key(IF_RANGE) => {
+            specs[0].clone()
+        }
+        _ => return response.body(data),
+    };
+    let len = data.len() as u64;
+    let Some((start, end)) = spec.to_satisfiable_range(len) else {
+        return response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(len),
+            }))
+            .finish();
+    };
+    // the range applies to the data as is, so it must not be compressed by the `Compress` middleware
+    response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .insert_header(ContentEncoding::Identity)
+        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+            range: Some((start, end)),
+            instance_length: Some(len),
+        }))
+        .body(data.slice(usize::try_from(start).unwrap()..=usize::try_from(end).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::http::header::{CONTENT_RANGE, RANGE};
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    async fn get(headers: &[(&str, &str)]) -> (StatusCode, Option<String>, Bytes) {
+        let mut req = TestRequest::get();
+        for &(name, value) in headers {
+            req = req.insert_header((name, value));
+        }
+        let resp = ranged_response(
+            &req.to_http_request(),
+            HttpResponse::Ok(),
+            Bytes::from_static(b"0123456789"),
+        );
+        assert_eq!(resp.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        let range = resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .map(|v| v.to_str().unwrap().to_string());
+        (
+            resp.status(),
+            range,
+            to_bytes(resp.into_body()).await.unwrap(),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn test_ranged_response() {
+        let (status, range, body) = get(&[]).await;
+        assert_eq!(
+            (status, range, body.as_ref()),
+            (StatusCode::OK, None, &b"0123456789"[..])
+        );
+
+        for (value, expected_range, expected_body) in [
+            ("bytes=2-4", "bytes 2-4/10", &b"234"[..]),
+            ("bytes=7-", "bytes 7-9/10", b"789"),
+            ("bytes=-2", "bytes 8-9/10", b"89"),
+            ("bytes=5-100", "bytes 5-9/10", b"56789"),
+        ] {
+            let (status, range, body) = get(&[(RANGE.as_str(), value)]).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT, "{value}");
+            assert_eq!(range.as_deref(), Some(expected_range), "{value}");
+            assert_eq!(body.as_ref(), expected_body, "{value}");
+        }
+
+        let (status, range, _) = get(&[(RANGE.as_str(), "bytes=10-")]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(range.as_deref(), Some("bytes */10"));
+
+        for headers in [
+            &[(RANGE.as_str(), "bytes=0-1,4-5")][..],
+            &[
+                (RANGE.as_str(), "bytes=0-1"),
+                (IF_RANGE.as_str(), "\"abc\""),
+            ],
+        ] {
+            let (status, _, body) = get(headers).await;
+            assert_eq!((status, body.len()), (StatusCode::OK, 10));
+        }
+    }
+}
@@ -0,0 +1,649 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::InternalError;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{HttpMessage as _, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use governor::clock::{Clock as _, DefaultClock};
+use governor::{DefaultDirectRateLimiter, DefaultKeyedRateLimiter, NotUntil, Quota};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use time::{Month, OffsetDateTime, Time};
+
+use crate::srv::{AccessConfig, EndpointClass, OTHER_API_KEY};
+use crate::utils::retry_after_value;
+
+pub const API_KEY_HEADER_DEFAULT: &str = "X-API-Key";
+
+/// How often the monthly quota usage is written to the quota store
+const QUOTA_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keyed limiters keep state for every key they have seen, clean them up once they grow this big.
+/// Without the list of `api_keys`, at most this many keys get their own monthly quota.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct RateLimitConfig {
+    /// Limit for all requests combined
+    pub global: Option<LimitConfig>,
+    /// Limit for each client IP address, taken from the `X-Forwarded-For` header of the trusted proxies of `access`
+    pub per_ip: Option<LimitConfig>,
+    /// Limit for each API key. Requests without a key are only subject to the global and per-IP limits.
+    pub per_key: Option<LimitConfig>,
+    /// Name of the request header with the API key [default: `X-API-Key`]
+    pub key_header: Option<String>,
+    /// Maximum number of tiles each API key may get in a calendar month (UTC)
+    pub monthly_quota: Option<u64>,
+    /// Only these API keys get their own monthly quota, all other keys share the quota of `(other)`.
+    /// Without the list, the first 10000 keys get their own quota.
+    pub api_keys: Option<Vec<String>>,
+    /// JSON file to keep the monthly quota usage in between restarts
+    pub quota_file: Option<PathBuf>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LimitConfig {
+    /// Number of requests per second
+    pub per_second: NonZeroU32,
+    /// Number of requests allowed in a short burst [default: same as `per_second`]
+    pub burst: Option<NonZeroU32>,
+}
+
+impl LimitConfig {
+    fn quota(self) -> Quota {
+        Quota::per_second(self.per_second).allow_burst(self.burst.unwrap_or(self.per_second))
+    }
+}
+
+/// Number of tiles served to each API key during a month
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct QuotaUsage {
+    /// Month of the usage in the `YYYY-MM` format
+    pub month: String,
+    pub tiles: HashMap<String, u64>,
+}
+
+/// Persistence hook for the monthly quota usage, e.g. to share it between instances
+pub trait QuotaStore: Send + Sync {
+    fn load(&self) -> io::Result<Option<QuotaUsage>>;
+    fn save(&self, usage: &QuotaUsage) -> io::Result<()>;
+}
+
+/// Keep the quota usage in a local JSON file
+pub struct FileQuotaStore(pub PathBuf);
+
+impl QuotaStore for FileQuotaStore {
+    fn load(&self) -> io::Result<Option<QuotaUsage>> {
+        match fs::read(&self.0) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, usage: &QuotaUsage) -> io::Result<()> {
+        fs::write(&self.0, serde_json::to_vec(usage)?)
+    }
+}
+
+struct QuotaState {
+    usage: QuotaUsage,
+    last_saved: Instant,
+}
+
+pub struct RateLimiter {
+    global: Option<DefaultDirectRateLimiter>,
+    per_ip: Option<DefaultKeyedRateLimiter<IpAddr>>,
+    per_key: Option<DefaultKeyedRateLimiter<String>>,
+    key_header: String,
+    monthly_quota: Option<u64>,
+    api_keys: Option<HashSet<String>>,
+    quota_store: Option<Arc<dyn QuotaStore>>,
+    quota: Mutex<QuotaState>,
+    /// Trusted proxies whose `X-Forwarded-For` header has the client address
+    access: AccessConfig,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(cfg: &RateLimitConfig) -> Self {
+        let limiter = Self {
+            global: cfg
+                .global
+                .map(|v| DefaultDirectRateLimiter::direct(v.quota())),
+            per_ip: cfg
+                .per_ip
+                .map(|v| DefaultKeyedRateLimiter::keyed(v.quota())),
+            per_key: cfg
+                .per_key
+                .map(|v| DefaultKeyedRateLimiter::keyed(v.quota())),
+            key_header: cfg
+                .key_header
+                .clone()
+                .unwrap_or_else(|| API_KEY_HEADER_DEFAULT.to_string()),
+            monthly_quota: cfg.monthly_quota,
+            api_keys: cfg.api_keys.as_ref().map(|v| v.iter().cloned().collect()),
+            quota_store: None,
+            quota: Mutex::new(QuotaState {
+                usage: QuotaUsage {
+                    month: current_month(),
+                    tiles: HashMap::new(),
+                },
+                last_saved: Instant::now(),
+            }),
+            access: AccessConfig::default(),
+        };
+        match &cfg.quota_file {
+            Some(path) => limiter.with_quota_store(Box::new(FileQuotaStore(path.clone()))),
+            None => limiter,
+        }
+    }
+
+    /// Use a custom store for the monthly quota usage, loading the previously saved usage from it
+    #[must_use]
+    pub fn with_quota_store(mut self, store: Box<dyn QuotaStore>) -> Self {
+        match store.load() {
+            Ok(Some(usage)) if usage.month == current_month() => {
+                self.quota.get_mut().unwrap().usage = usage;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Unable to load rate limit quota usage: {e}"),
+        }
+        self.quota_store = Some(Arc::from(store));
+        self
+    }
+
+    /// Get the client address of the per-IP limit from the `X-Forwarded-For` header of the trusted proxies
+    #[must_use]
+    pub fn with_access(mut self, access: AccessConfig) -> Self {
+        self.access = access;
+        self
+    }
+
+    fn get_api_key(&self, req: &HttpRequest) -> Option<String> {
+        let value = req.headers().get(self.key_header.as_str())?;
+        value.to_str().ok().map(ToString::to_string)
+    }
+
+    /// Check the request rate limits, returning how long the client should wait if any are exceeded.
+    /// The monthly quota is only checked for the tiles, see [`reserve_tiles`].
+    fn check(&self, req: &HttpRequest, api_key: Option<&String>) -> Result<(), Duration> {
+        if let Some(limiter) = &self.global {
+            limiter.check().map_err(|v| wait_time(&v))?;
+        }
+        if let (Some(limiter), Some(ip)) = (&self.per_ip, self.access.client_ip(req)) {
+            if limiter.len() > MAX_TRACKED_KEYS {
+                limiter.retain_recent();
+            }
+            limiter.check_key(&ip).map_err(|v| wait_time(&v))?;
+        }
+        if let Some(key) = api_key {
+            if let Some(limiter) = &self.per_key {
+                if limiter.len() > MAX_TRACKED_KEYS {
+                    limiter.retain_recent();
+                }
+                limiter.check_key(key).map_err(|v| wait_time(&v))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add tiles to the monthly usage of the API key before they are served,
+    /// unless they would exceed its quota, so that concurrent requests cannot exceed it either
+    fn reserve(&self, api_key: &str, count: u64) -> Result<(), Duration> {
+        let Some(max_tiles) = self.monthly_quota else {
+            return Ok(());
+        };
+        let mut quota = self.quota.lock().unwrap();
+        Self::reset_if_new_month(&mut quota);
+        let api_key = self.counted_key(api_key, &quota.usage);
+        let used = quota.usage.tiles.get(api_key).copied().unwrap_or_default();
+        if used + count > max_tiles {
+            return Err(until_next_month(OffsetDateTime::now_utc()));
+        }
+        *quota.usage.tiles.entry(api_key.to_string()).or_default() += count;
+        self.save_if_due(&mut quota);
+        Ok(())
+    }
+
+    /// Replace the reserved tiles of a request with the tiles it actually served,
+    /// e.g. release them if it failed. Never refused, as the tiles have already been served.
+    fn settle(&self, api_key: &str, reserved: u64, served: u64) {
+        if self.monthly_quota.is_none() || reserved == served {
+            return;
+        }
+        let mut quota = self.quota.lock().unwrap();
+        Self::reset_if_new_month(&mut quota);
+        let api_key = self.counted_key(api_key, &quota.usage);
+        if served > reserved {
+            *quota.usage.tiles.entry(api_key.to_string()).or_default() += served - reserved;
+        } else if let Some(used) = quota.usage.tiles.get_mut(api_key) {
+            *used = used.saturating_sub(reserved - served);
+        }
+        self.save_if_due(&mut quota);
+    }
+
+    fn save_if_due(&self, quota: &mut QuotaState) {
+        let Some(store) = &self.quota_store else {
+            return;
+        };
+        if quota.last_saved.elapsed() >= QUOTA_SAVE_INTERVAL {
+            quota.last_saved = Instant::now();
+            // save a snapshot on a blocking thread, without holding the lock
+            let usage = quota.usage.clone();
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = store.save(&usage) {
+                    warn!("Unable to save rate limit quota usage: {e}");
+                }
+            });
+        }
+    }
+
+    /// The key to count the tiles of an API key under, so that clients cannot grow the usage
+    /// without limit, or get a fresh quota, by sending random keys
+    fn counted_key<'a>(&self, api_key: &'a str, usage: &QuotaUsage) -> &'a str {
+        let is_known = match &self.api_keys {
+            Some(api_keys) => api_keys.contains(api_key),
+            None => usage.tiles.contains_key(api_key) || usage.tiles.len() < MAX_TRACKED_KEYS,
+        };
+        if is_known {
+            api_key
+        } else {
+            OTHER_API_KEY
+        }
+    }
+
+    fn reset_if_new_month(quota: &mut QuotaState) {
+        let month = current_month();
+        if quota.usage.month != month {
+            quota.usage = QuotaUsage {
+                month,
+                tiles: HashMap::new(),
+            };
+        }
+    }
+}
+
+fn wait_time(not_until: &NotUntil<<DefaultClock as governor::clock::Clock>::Instant>) -> Duration {
+    not_until.wait_time_from(DefaultClock::default().now())
+}
+
+fn current_month() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!("{:04}-{:02}", now.year(), u8::from(now.month()))
+}
+
+fn until_next_month(now: OffsetDateTime) -> Duration {
+    let (year, month) = match now.month() {
+        Month::December => (now.year() + 1, Month::January),
+        month => (now.year(), month.next()),
+    };
+    let next = now
+        .replace_time(Time::MIDNIGHT)
+        .replace_day(1)
+        .and_then(|v| v.replace_month(month))
+        .and_then(|v| v.replace_year(year))
+        .expect("first day of a month is always valid");
+    (next - now).try_into().unwrap_or_default()
+}
+
+fn too_many_requests(wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, retry_after_value(wait)))
+        .body("Too many requests")
+}
+
+/// Response extension with the tiles of a response, counted against the monthly quota and in the usage.
+/// Set by all the handlers serving tiles, whatever the path they are mounted at.
+pub(crate) struct ServedTiles {
+    pub count: usize,
+    /// Comma-separated IDs of the requested sources
+    pub source_ids: String,
+}
+
+impl ServedTiles {
+    pub(crate) fn new(count: usize, source_ids: &str) -> Self {
+        Self {
+            count,
+            source_ids: source_ids.to_string(),
+        }
+    }
+}
+
+/// Number of tiles the response counts as for the monthly quota
+pub(crate) fn served_tiles<B>(response: &ServiceResponse<B>) -> usize {
+    response
+        .response()
+        .extensions()
+        .get::<ServedTiles>()
+        .map_or(0, |served| served.count)
+}
+
+/// Tiles reserved against the monthly quota of an API key while its request is served,
+/// stored in the request extensions. Settled with the served tiles once the last clone is dropped.
+#[derive(Clone)]
+struct QuotaReservation(Rc<ReservedTiles>);
+
+struct ReservedTiles {
+    limiter: Arc<RateLimiter>,
+    api_key: String,
+    reserved: Cell<u64>,
+    served: Cell<u64>,
+}
+
+impl QuotaReservation {
+    /// Reserve more tiles, so that `count` tiles are reserved in total
+    fn reserve_total(&self, count: u64) -> Result<(), Duration> {
+        let reserved = &self.0.reserved;
+        if count > reserved.get() {
+            self.0
+                .limiter
+                .reserve(&self.0.api_key, count - reserved.get())?;
+            reserved.set(count);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ReservedTiles {
+    fn drop(&mut self) {
+        self.limiter
+            .settle(&self.api_key, self.reserved.get(), self.served.get());
+    }
+}
+
+/// Reserve the tiles a handler is about to get against the monthly quota of the request's API key,
+/// refusing the request with `429 Too Many Requests` if they would exceed it.
+/// Must be called by the handlers serving more than one tile, the middleware reserves one for each tile request.
+pub(crate) fn reserve_tiles(req: &HttpRequest, count: usize) -> actix_web::Result<()> {
+    let Some(reservation) = req.extensions().get::<QuotaReservation>().cloned() else {
+        return Ok(());
+    };
+    reservation.reserve_total(count as u64).map_err(|wait| {
+        InternalError::from_response("Monthly quota exceeded", too_many_requests(wait)).into()
+    })
+}
+
+/// Middleware that rejects requests exceeding the configured rate limits with `429 Too Many Requests`.
+/// Does nothing if there is no limiter.
+#[derive(Clone, Default)]
+pub struct RateLimit(pub Option<Arc<RateLimiter>>);
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.0.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(limiter) = self.limiter.clone() else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { Ok(fut.await?.map_into_left_body()) });
+        };
+
+        let api_key = limiter.get_api_key(req.request());
+        if let Err(wait) = limiter.check(req.request(), api_key.as_ref()) {
+            let response = req.into_response(too_many_requests(wait));
+            return Box::pin(ready(Ok(response.map_into_right_body())));
+        }
+
+        let reservation = api_key
+            .filter(|_| limiter.monthly_quota.is_some())
+            .map(|api_key| {
+                QuotaReservation(Rc::new(ReservedTiles {
+                    limiter,
+                    api_key,
+                    reserved: Cell::new(0),
+                    served: Cell::new(0),
+                }))
+            });
+        if let Some(reservation) = &reservation {
+            // refuse the tile requests over the quota before getting the tile, other requests are never refused
+            let class = EndpointClass::from_route(req.method(), req.match_info().as_str());
+            if class == EndpointClass::Tiles {
+                if let Err(wait) = reservation.reserve_total(1) {
+                    let response = req.into_response(too_many_requests(wait));
+                    return Box::pin(ready(Ok(response.map_into_right_body())));
+                }
+            }
+            req.extensions_mut().insert(reservation.clone());
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            if let Some(reservation) = reservation {
+                if response.status().is_success() {
+                    reservation.0.served.set(served_tiles(&response) as u64);
+                }
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+    use indoc::indoc;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let cfg: RateLimitConfig = serde_yaml::from_str(indoc! {"
+            per_key:
+              per_second: 1
+              burst: 2
+            monthly_quota: 3
+        "})
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(RateLimit(Some(Arc::new(RateLimiter::new(&cfg)))))
+                .route("/{source_ids}/{z}/{x}/{y}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |key: &'static str| {
+            TestRequest::get()
+                .uri("/src/0/0/0")
+                .insert_header((API_KEY_HEADER_DEFAULT, key))
+                .to_request()
+        };
+
+        assert!(call_service(&app, get("a")).await.status().is_success());
+        assert!(call_service(&app, get("a")).await.status().is_success());
+        let response = call_service(&app, get("a")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        // other keys have their own limits
+        assert!(call_service(&app, get("b")).await.status().is_success());
+        // requests without a key are not limited by the per-key limit
+        for _ in 0..5 {
+            let req = TestRequest::get().uri("/src/0/0/0").to_request();
+            assert!(call_service(&app, req).await.status().is_success());
+        }
+    }
+
+    #[test]
+    fn test_monthly_quota() {
+        let cfg = RateLimitConfig {
+            monthly_quota: Some(2),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&cfg);
+        assert!(limiter.reserve("key", 1).is_ok());
+        // a reservation going over the quota is refused as a whole
+        assert!(limiter.reserve("key", 2).is_err());
+        assert!(limiter.reserve("key", 1).is_ok());
+        assert!(limiter.reserve("key", 1).is_err());
+        // the reserved tiles of a failed request are released
+        limiter.settle("key", 1, 0);
+        assert!(limiter.reserve("key", 1).is_ok());
+        assert!(limiter.reserve("other", 2).is_ok());
+    }
+
+    #[test]
+    fn test_monthly_quota_keys() {
+        let cfg = RateLimitConfig {
+            monthly_quota: Some(2),
+            api_keys: Some(vec!["known".to_string()]),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(&cfg);
+        limiter.reserve("known", 1).unwrap();
+        // unknown keys share a quota, so sending a new key does not reset it
+        limiter.reserve("random1", 1).unwrap();
+        limiter.reserve("random2", 1).unwrap();
+        assert!(limiter.reserve("random3", 1).is_err());
+        assert!(limiter.reserve("known", 1).is_ok());
+        let usage = limiter.quota.lock().unwrap().usage.clone();
+        assert_eq!(
+            usage.tiles,
+            HashMap::from([("known".to_string(), 2), (OTHER_API_KEY.to_string(), 2)])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_monthly_quota_requests() {
+        let cfg = RateLimitConfig {
+            monthly_quota: Some(3),
+            ..Default::default()
+        };
+        let limiter = Arc::new(RateLimiter::new(&cfg));
+        let app = init_service(
+            App::new()
+                .wrap(RateLimit(Some(limiter.clone())))
+                .route(
+                    "/{source_ids}/{z}/{x}/{y}",
+                    web::get().to(|path: web::Path<(String, u8, u32, u32)>| async move {
+                        if path.0 == "missing" {
+                            return HttpResponse::NotFound().finish();
+                        }
+                        let mut response = HttpResponse::Ok().finish();
+                        response
+                            .extensions_mut()
+                            .insert(ServedTiles::new(1, &path.0));
+                        response
+                    }),
+                )
+                .route(
+                    "/{source_ids}/tiles",
+                    web::post().to(|req: HttpRequest| async move {
+                        reserve_tiles(&req, 2)?;
+                        let mut response = HttpResponse::Ok().finish();
+                        response.extensions_mut().insert(ServedTiles::new(2, "src"));
+                        Ok::<_, actix_web::Error>(response)
+                    }),
+                )
+                .route("/catalog", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |req: TestRequest| {
+            let req = req
+                .insert_header((API_KEY_HEADER_DEFAULT, "key"))
+                .to_request();
+            let app = &app;
+            async move { call_service(app, req).await.status() }
+        };
+        let get = |path: &str| TestRequest::get().uri(path);
+        let batch = || TestRequest::post().uri("/src/tiles");
+
+        assert_eq!(status(get("/missing/0/0/0")).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(batch()).await, StatusCode::OK);
+        // the batch would exceed the quota, so it is refused before getting any tile
+        assert_eq!(status(batch()).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(get("/src/0/0/0")).await, StatusCode::OK);
+        assert_eq!(
+            status(get("/src/0/0/0")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // only the tiles count against the quota
+        for _ in 0..5 {
+            assert_eq!(status(get("/catalog")).await, StatusCode::OK);
+        }
+        let usage = limiter.quota.lock().unwrap().usage.clone();
+        assert_eq!(usage.tiles, HashMap::from([("key".to_string(), 3)]));
+    }
+
+    #[test]
+    fn test_per_ip_behind_proxy() {
+        let cfg: RateLimitConfig = serde_yaml::from_str("per_ip: {per_second: 1}").unwrap();
+        let access: AccessConfig = serde_yaml::from_str("trusted_proxies: [10.0.0.1/32]").unwrap();
+        let limiter = RateLimiter::new(&cfg).with_access(access);
+        let req = |peer: &str, forwarded: &str| {
+            TestRequest::default()
+                .peer_addr(format!("{peer}:1234").parse().unwrap())
+                .insert_header(("X-Forwarded-For", forwarded))
+                .to_http_request()
+        };
+        assert!(limiter.check(&req("1.2.3.4", "5.5.5.1"), None).is_ok());
+        // other clients cannot get a fresh limit by sending a new header
+        assert!(limiter.check(&req("1.2.3.4", "5.5.5.2"), None).is_err());
+        // the proxy forwards the requests of different clients
+        assert!(limiter.check(&req("10.0.0.1", "5.5.5.1"), None).is_ok());
+        assert!(limiter.check(&req("10.0.0.1", "5.5.5.2"), None).is_ok());
+        assert!(limiter
+            .check(&req("10.0.0.1", "1.1.1.1, 5.5.5.2"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_until_next_month() {
+        // 2023-12-31 23:00 UTC
+        assert_eq!(
+            until_next_month(OffsetDateTime::from_unix_timestamp(1_704_063_600).unwrap()),
+            Duration::from_secs(3600)
+        );
+        // 2024-02-29 00:00 UTC
+        assert_eq!(
+            until_next_month(OffsetDateTime::from_unix_timestamp(1_709_164_800).unwrap()),
+            Duration::from_secs(24 * 3600)
+        );
+    }
+}
@@ -0,0 +1,519 @@
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::{ContentType, HeaderName, HeaderValue};
+use actix_web::web::{Data, Path, Query};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use futures::future::try_join_all;
+use log::warn;
+use martin_tile_utils::Format;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use crate::raster::RasterError::{DecodeError, EncodeError};
+use crate::raster::{
+    render_tile, unsupported_style_features, RasterFormat, RenderConfig, StaticMap,
+    STATIC_MAP_TILE_SIZE,
+};
+use crate::source::{Source, TileSources};
+use crate::srv::rate_limit::{reserve_tiles, ServedTiles};
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::{decode, with_etag};
+use crate::srv::{generate_style, DynTileSource, SrvConfig};
+use crate::utils::OptMainCache;
+use crate::{MartinResult, TileCoord};
+
+/// Response header listing the layers and properties of the style that were not drawn, if any
+pub const RENDER_WARNING_HEADER: HeaderName = HeaderName::from_static("x-martin-render-warning");
+
+#[derive(Deserialize)]
+pub struct RenderRequest {
+    source_ids: String,
+    z: u8,
+    x: u32,
+    y: u32,
+    format: String,
+}
+
+#[derive(Deserialize)]
+struct StyleQuery {
+    style: Option<String>,
+}
+
+/// Vector tiles rendered as images, for clients that cannot draw vector tiles, e.g. emails or PDFs.
+/// The tiles are drawn with the style named in the `style` query parameter, or with the generated style
+/// of `/style/{source_ids}` if not set. Must be registered before the tile route, which would match the same paths.
+#[route(
+    "/{source_ids}/{z}/{x}/{y}.{format:(png|jpg|jpeg|webp)}",
+    method = "GET",
+    method = "HEAD"
+)]
+async fn get_rendered_tile(
+    req: HttpRequest,
+    srv_config: Data<RwLock<SrvConfig>>,
+    path: Path<RenderRequest>,
+    sources: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+) -> ActixResult<HttpResponse> {
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    if !xyz.is_valid() {
+        return Err(ErrorNotFound(format!("Tile {xyz:#} does not exist")));
+    }
+    let format = match path.format.as_str() {
+        "png" => RasterFormat::Png,
+        "webp" => RasterFormat::Webp,
+        _ => RasterFormat::Jpeg,
+    };
+    let srv_config_guard = srv_config.read().await;
+    let sources_guard = sources.read().await;
+    let cache_guard = cache.read().await;
+    render_response(
+        &req,
+        &srv_config_guard,
+        &sources_guard,
+        &cache_guard,
+        &path.source_ids,
+        xyz,
+        format,
+    )
+    .await
+}
+
+/// Render a vector tile of the sources as an image with the style of the `style` query parameter.
+/// Also used for the tile URLs without extension if the client only accepts images.
+pub(crate) async fn render_response(
+    req: &HttpRequest,
+    srv_config: &SrvConfig,
+    sources: &TileSources,
+    cache: &OptMainCache,
+    source_ids: &str,
+    xyz: TileCoord,
+    format: RasterFormat,
+) -> ActixResult<HttpResponse> {
+    let Some(render) = &srv_config.render else {
+        return Err(ErrorNotFound("Tile rendering is not enabled"));
+    };
+    let style_name = Query::<StyleQuery>::from_query(req.query_string())?
+        .into_inner()
+        .style;
+    let query = without_params(req.query_string(), &["style"]);
+
+    let src = DynTileSource::new(
+        sources,
+        source_ids,
+        Some(xyz.z),
+        &query,
+        None,
+        Some(srv_config),
+        cache.as_ref(),
+    )?;
+    if src.info.format != Format::Mvt {
+        return Err(ErrorBadRequest(format!(
+            "Only vector tiles can be rendered, but {source_ids} has {} tiles",
+            src.info
+        )));
+    }
+    let style = get_style(render, style_name.as_deref(), source_ids, &src.sources).await?;
+    let warning = render_warning(style_name.as_deref(), &style);
+
+    let tile = decode(src.get_tile_content(xyz).await?)?;
+    let size = render.tile_size();
+    let served_tiles = ServedTiles::new(1, source_ids);
+    let source_ids = source_ids.to_string();
+    // rendering takes a while, so it must not block the server
+    let data = tokio::task::spawn_blocking(move || {
+        let image = render_tile(&style, &tile.data, xyz, size)?;
+        MartinResult::Ok(
+            format
+                .encode(&image)
+                .map_err(|e| EncodeError(e, xyz, source_ids))?,
+        )
+    })
+    .await
+    .map_err(map_internal_error)?
+    .map_err(map_internal_error)?;
+
+    let mut response = image_response(req, format, data)?;
+    response.extensions_mut().insert(served_tiles);
+    if let Some(warning) = warning {
+        response
+            .headers_mut()
+            .insert(RENDER_WARNING_HEADER, warning);
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct ImageQuery {
+    source: String,
+    bbox: String,
+    size: String,
+    style: Option<String>,
+    format: Option<String>,
+}
+
+/// A static map image of a bounding box, stitched from the tiles of vector or raster sources,
+/// e.g. for reports or social media previews. Vector tiles are rendered like with [`get_rendered_tile`].
+/// Must be registered before the source `TileJSON` route, which would match the same path.
+#[route("/image", method = "GET", method = "HEAD")]
+#[allow(clippy::too_many_lines)]
+async fn get_static_image(
+    req: HttpRequest,
+    srv_config: Data<RwLock<SrvConfig>>,
+    sources: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+) -> ActixResult<HttpResponse> {
+    let srv_config_guard = srv_config.read().await;
+    let Some(render) = &srv_config_guard.render else {
+        return Err(ErrorNotFound("Static map images are not enabled"));
+    };
+    let params = Query::<ImageQuery>::from_query(req.query_string())?.into_inner();
+    let bbox = parse_bbox(&params.bbox).ok_or_else(|| {
+        ErrorBadRequest("bbox must be west,south,east,north in degrees, e.g. 13.3,52.4,13.5,52.6")
+    })?;
+    let max_size = render.max_image_size();
+    let (width, height) = parse_size(&params.size)
+        .filter(|(w, h)| (1..=max_size).contains(w) && (1..=max_size).contains(h))
+        .ok_or_else(|| {
+            ErrorBadRequest(format!(
+                "size must be WIDTHxHEIGHT, e.g. 800x600, up to {max_size}x{max_size} pixels"
+            ))
+        })?;
+    let format = match params.format.as_deref() {
+        None | Some("png") => RasterFormat::Png,
+        Some("jpg" | "jpeg") => RasterFormat::Jpeg,
+        Some("webp") => RasterFormat::Webp,
+        Some(v) => return Err(ErrorBadRequest(format!("Unsupported image format {v}"))),
+    };
+    let query = without_params(
+        req.query_string(),
+        &["source", "bbox", "size", "style", "format"],
+    );
+
+    let sources_guard = sources.read().await;
+    let cache_guard = cache.read().await;
+    let (all_sources, _, _) = sources_guard.get_sources(&params.source, None)?;
+    let tilejsons = all_sources.iter().map(|s| s.get_tilejson());
+    let minzoom = tilejsons.clone().filter_map(|tj| tj.minzoom).min();
+    let maxzoom = tilejsons.filter_map(|tj| tj.maxzoom).max();
+    let map = StaticMap::new(
+        bbox,
+        width,
+        height,
+        minzoom.unwrap_or(0),
+        maxzoom.unwrap_or(22),
+    );
+    let src = DynTileSource::new(
+        &sources_guard,
+        &params.source,
+        Some(map.zoom),
+        &query,
+        None,
+        Some(&srv_config_guard),
+        cache_guard.as_ref(),
+    )?;
+    let style = match src.info.format {
+        Format::Mvt => Some(
+            get_style(
+                render,
+                params.style.as_deref(),
+                &params.source,
+                &src.sources,
+            )
+            .await?,
+        ),
+        Format::Png | Format::Jpeg | Format::Webp | Format::Gif => None,
+        Format::Json => {
+            return Err(ErrorBadRequest(format!(
+                "Only vector and raster tiles can be drawn, but {} has {} tiles",
+                params.source, src.info
+            )))
+        }
+    };
+    let warning = style
+        .as_ref()
+        .and_then(|style| render_warning(params.style.as_deref(), style));
+
+    let map_tiles = map.tiles();
+    reserve_tiles(&req, map_tiles.len())?;
+    let tiles = try_join_all(map_tiles.into_iter().map(|(col, row)| {
+        let src = &src;
+        let xyz = map.tile_coord(col, row);
+        async move {
+            let tile = decode(src.get_tile_content(xyz).await?)?;
+            Ok::<_, actix_web::Error>((col, row, xyz, tile.data))
+        }
+    }))
+    .await?;
+
+    let served_tiles = ServedTiles::new(tiles.len(), &params.source);
+    let source_ids = params.source;
+    // rendering takes a while, so it must not block the server
+    let data = tokio::task::spawn_blocking(move || {
+        let mut images = Vec::with_capacity(tiles.len());
+        for (col, row, xyz, data) in tiles {
+            let image = match &style {
+                Some(style) => render_tile(style, &data, xyz, STATIC_MAP_TILE_SIZE)?,
+                None if data.is_empty() => continue,
+                None => image::load_from_memory(&data)
+                    .map_err(|e| DecodeError(e, xyz, source_ids.clone()))?
+                    .to_rgba8(),
+            };
+            images.push((col, row, image));
+        }
+        let image = map.compose(&images);
+        let xyz = TileCoord {
+            z: map.zoom,
+            x: 0,
+            y: 0,
+        };
+        MartinResult::Ok(
+            format
+                .encode(&image)
+                .map_err(|e| EncodeError(e, xyz, source_ids))?,
+        )
+    })
+    .await
+    .map_err(map_internal_error)?
+    .map_err(map_internal_error)?;
+
+    let mut response = image_response(&req, format, data)?;
+    response.extensions_mut().insert(served_tiles);
+    if let Some(warning) = warning {
+        response
+            .headers_mut()
+            .insert(RENDER_WARNING_HEADER, warning);
+    }
+    Ok(response)
+}
+
+/// The named style, or the generated style of the sources if no name is given
+async fn get_style(
+    render: &RenderConfig,
+    name: Option<&str>,
+    source_ids: &str,
+    sources: &[&dyn Source],
+) -> ActixResult<Value> {
+    match name {
+        Some(name) => render
+            .load_style(name)
+            .await
+            .map_err(map_internal_error)?
+            .ok_or_else(|| ErrorNotFound(format!("Style {name} does not exist"))),
+        None => Ok(generate_style(source_ids, sources, "")),
+    }
+}
+
+/// Log the features of a style file that cannot be rendered, and list them for the response header.
+/// The generated styles are always fully supported.
+fn render_warning(style_name: Option<&str>, style: &Value) -> Option<HeaderValue> {
+    let name = style_name?;
+    let unsupported = unsupported_style_features(style);
+    if unsupported.is_empty() {
+        return None;
+    }
+    let unsupported = unsupported.join(", ");
+    warn!("Style {name} uses features that are not rendered: {unsupported}");
+    HeaderValue::from_str(&format!("Not drawn: {unsupported}"))
+        .or_else(|_| HeaderValue::from_str("Some features of the style were not drawn"))
+        .ok()
+}
+
+/// Remove the parameters that select how the image is drawn, so that the rest
+/// can be passed to the sources
+fn without_params(query: &str, names: &[&str]) -> String {
+    query
+        .split('&')
+        .filter(|v| !names.contains(&v.split_once('=').map_or(*v, |(name, _)| name)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn parse_bbox(value: &str) -> Option<[f64; 4]> {
+    let values: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    let [west, south, east, north] = values.try_into().ok()?;
+    let is_valid = (-180.0..=180.0).contains(&west)
+        && (-180.0..=180.0).contains(&east)
+        && (-90.0..=90.0).contains(&south)
+        && (-90.0..=90.0).contains(&north)
+        && (west - east).abs() > f64::EPSILON
+        && south < north;
+    is_valid.then_some([west, south, east, north])
+}
+
+fn parse_size(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn image_response(
+    req: &HttpRequest,
+    format: RasterFormat,
+    data: Vec<u8>,
+) -> ActixResult<HttpResponse> {
+    let content_type = format
+        .format()
+        .content_type()
+        .parse()
+        .map_err(map_internal_error)?;
+    Ok(with_etag(req, ContentType(content_type), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use martin_tile_utils::TileInfo;
+
+    use super::*;
+    use crate::debug::{DebugConfig, DebugFormat, DebugSource};
+    use crate::raster::RenderConfig;
+
+    async fn get(render: Option<RenderConfig>, path: &str) -> actix_web::dev::ServiceResponse {
+        let mut sources = TileSources::default();
+        sources.add_source(Box::new(DebugSource::new(
+            "dbg".to_string(),
+            &DebugConfig::default(),
+        )));
+        let png = DebugConfig {
+            format: Some(DebugFormat::Png),
+            ..Default::default()
+        };
+        sources.add_source(Box::new(DebugSource::new("png".to_string(), &png)));
+        let srv_config = SrvConfig {
+            render,
+            ..SrvConfig::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(RwLock::new(sources)))
+                .app_data(Data::new(RwLock::new(srv_config)))
+                .app_data(Data::new(RwLock::new(OptMainCache::None)))
+                .service(get_static_image)
+                .service(get_rendered_tile),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri(path).to_request()).await
+    }
+
+    #[actix_rt::test]
+    async fn test_rendered_tile() {
+        let style = std::env::temp_dir().join("martin_test_render_style.json");
+        std::fs::write(
+            &style,
+            r##"{"version": 8, "layers": [{"id": "bg", "type": "background", "paint": {"background-color": "#00ff00"}}]}"##,
+        )
+        .unwrap();
+        let render = RenderConfig {
+            styles: Some(BTreeMap::from([("green".to_string(), style)])),
+            tile_size: Some(512),
+            ..Default::default()
+        };
+
+        let response = get(Some(render.clone()), "/dbg/3/2/5.png").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+        let image = image::load_from_memory(&read_body(response).await)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.dimensions(), (512, 512));
+
+        let response = get(Some(render.clone()), "/dbg/3/2/5.webp?style=green").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(RENDER_WARNING_HEADER));
+        let data = read_body(response).await;
+        assert_eq!(TileInfo::detect(&data).unwrap().format, Format::Webp);
+        let image = image::load_from_memory(&data).unwrap().to_rgba8();
+        assert_eq!(image.get_pixel(0, 0), &image::Rgba([0, 255, 0, 255]));
+
+        let labels = std::env::temp_dir().join("martin_test_render_labels.json");
+        std::fs::write(
+            &labels,
+            r#"{"version": 8, "layers": [{"id": "names", "type": "symbol", "source-layer": "debug"}]}"#,
+        )
+        .unwrap();
+        let mut labels_render = render.clone();
+        labels_render
+            .styles
+            .get_or_insert_with(BTreeMap::new)
+            .insert("labels".to_string(), labels);
+        let response = get(Some(labels_render), "/dbg/3/2/5.png?style=labels").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(RENDER_WARNING_HEADER).unwrap(),
+            "Not drawn: symbol layer names"
+        );
+
+        let response = get(Some(render.clone()), "/dbg/3/2/5.jpg?style=missing").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(Some(render), "/png/3/2/5.png").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = get(None, "/dbg/3/2/5.png").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_static_image() {
+        let render = RenderConfig {
+            max_image_size: Some(1000),
+            ..Default::default()
+        };
+        let response = get(
+            Some(render.clone()),
+            "/image?source=dbg&bbox=-10,-10,10,10&size=300x200",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+        let image = image::load_from_memory(&read_body(response).await)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.dimensions(), (300, 200));
+
+        let response = get(
+            Some(render.clone()),
+            "/image?source=png&bbox=-10,-10,10,10&size=300x200&format=jpg",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = read_body(response).await;
+        assert_eq!(TileInfo::detect(&data).unwrap().format, Format::Jpeg);
+
+        for query in [
+            "source=dbg&bbox=-10,10,10,-10&size=300x200",
+            "source=dbg&bbox=-10,-10,10&size=300x200",
+            "source=dbg&bbox=-10,-10,10,10&size=3000x200",
+            "source=dbg&bbox=-10,-10,10,10&size=300",
+            "source=dbg&bbox=-10,-10,10,10&size=300x200&format=tiff",
+        ] {
+            let response = get(Some(render.clone()), &format!("/image?{query}")).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{query}");
+        }
+        let response = get(
+            Some(render),
+            "/image?source=missing&bbox=-10,-10,10,10&size=300x200",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(None, "/image?source=dbg&bbox=-10,-10,10,10&size=300x200").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_without_params() {
+        assert_eq!(
+            without_params("style=basic&date=2024&styles&style", &["style"]),
+            "date=2024&styles"
+        );
+        assert_eq!(without_params("", &["style"]), "");
+    }
+}
@@ -0,0 +1,153 @@
+//! Request IDs, used to correlate the log lines and Postgres queries of a request with the client's request.
+//! A valid `X-Request-Id` header of the request is kept, otherwise a new ID is generated.
+//! The ID is returned in the `X-Request-Id` response header.
+
+use std::collections::hash_map::RandomState;
+use std::future::{ready, Ready};
+use std::hash::{BuildHasher as _, Hasher as _};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use futures::future::LocalBoxFuture;
+
+/// Request and response header with the ID of the request
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest request ID accepted from clients
+const REQUEST_ID_MAX_LEN: usize = 64;
+
+/// Format of the access log, same as the actix default with the request ID appended
+pub const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{X-Request-Id}o"#;
+
+tokio::task_local! {
+    static REQUEST_ID: Arc<str>;
+}
+
+/// The ID of the request being handled by the current task, if any
+pub(crate) fn current_request_id() -> Option<Arc<str>> {
+    REQUEST_ID.try_with(Arc::clone).ok()
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= REQUEST_ID_MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn new_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // every RandomState is seeded differently, so the IDs are unpredictable and unique across restarts
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Middleware that assigns an ID to every request, and makes it available to the request's handler
+#[derive(Clone, Default)]
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id: Arc<str> = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| is_valid_request_id(v))
+            .map_or_else(|| new_request_id().into(), Into::into);
+        let fut = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let mut response = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), value);
+            }
+            Ok(response)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let app = init_service(App::new().wrap(RequestId).route(
+            "/",
+            web::get().to(|| async { current_request_id().unwrap_or_default().to_string() }),
+        ))
+        .await;
+
+        let req = TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "abc-123"
+        );
+        let req = TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        assert_eq!(call_and_read_body(&app, req).await, "abc-123");
+
+        // invalid IDs are replaced
+        let req = TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "a b"))
+            .to_request();
+        let response = call_service(&app, req).await;
+        let id = response.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert_eq!(id.len(), 16);
+        assert_ne!(id, "a b");
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[test]
+    fn test_new_request_id() {
+        assert_ne!(new_request_id(), new_request_id());
+        assert!(is_valid_request_id(&new_request_id()));
+        assert!(!is_valid_request_id(&"a".repeat(65)));
+    }
+}
@@ -0,0 +1,62 @@
+//! A generated `/robots.txt`, so that well-behaved crawlers do not request every tile of the public sources.
+
+use std::fmt::Write as _;
+
+use actix_web::error::ErrorNotFound;
+use actix_web::http::header::{ContentType, HeaderName};
+use actix_web::web::Data;
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use tokio::sync::RwLock;
+
+use crate::srv::tiles_info::public_base_path;
+use crate::srv::{Catalog, SrvConfig};
+
+/// Asks search engines not to index a response, set for the sources with `noindex`
+pub const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
+
+#[route("/robots.txt", method = "GET", method = "HEAD")]
+async fn get_robots_txt(
+    req: HttpRequest,
+    catalog: Data<RwLock<Catalog>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> ActixResult<HttpResponse> {
+    let srv_config = srv_config.read().await;
+    if !srv_config.robots_txt.unwrap_or(true) {
+        return Err(ErrorNotFound("robots.txt is disabled"));
+    }
+    let prefix = public_base_path(&req, srv_config.base_path.as_deref(), "/robots.txt");
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(robots_txt(&*catalog.read().await, &prefix)))
+}
+
+/// Disallow the paths of all listed tile sources. Hidden sources are not listed,
+/// so that `robots.txt` does not reveal them.
+fn robots_txt(catalog: &Catalog, prefix: &str) -> String {
+    let mut result = "User-agent: *\n".to_string();
+    for id in catalog.tiles.keys() {
+        let _ = writeln!(result, "Disallow: {prefix}/{id}/");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CatalogSourceEntry;
+
+    #[test]
+    fn test_robots_txt() {
+        let mut catalog = Catalog::default();
+        for id in ["roads", "water"] {
+            catalog
+                .tiles
+                .insert(id.to_string(), CatalogSourceEntry::default());
+        }
+        assert_eq!(
+            robots_txt(&catalog, "/tiles"),
+            "User-agent: *\nDisallow: /tiles/roads/\nDisallow: /tiles/water/\n"
+        );
+        assert_eq!(robots_txt(&Catalog::default(), ""), "User-agent: *\n");
+    }
+}
@@ -0,0 +1,82 @@
+//! Runtime diagnostics, available at `/debug/runtime` with the `console` feature.
+//! Each server worker runs its own Tokio runtime, so the statistics are reported for every worker,
+//! making it possible to tell a starved worker from a busy server.
+
+use std::sync::Mutex;
+
+use actix_web::route;
+use actix_web::web::Json;
+use serde::Serialize;
+use tokio::runtime::Handle;
+
+/// Runtimes of all server workers, registered when a worker starts
+static RUNTIMES: Mutex<Vec<Handle>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct RuntimeStats {
+    pub workers: Vec<WorkerStats>,
+    #[cfg(feature = "postgres")]
+    pub postgres_pools: Vec<crate::pg::PoolStatus>,
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Number of threads of the worker's runtime
+    pub threads: usize,
+    /// Number of tasks that have not finished yet
+    pub alive_tasks: usize,
+    /// Number of tasks scheduled, but not yet picked up by a thread
+    pub queued_tasks: usize,
+}
+
+impl WorkerStats {
+    fn new(handle: &Handle) -> Self {
+        let metrics = handle.metrics();
+        Self {
+            threads: metrics.num_workers(),
+            alive_tasks: metrics.num_alive_tasks(),
+            queued_tasks: metrics.global_queue_depth(),
+        }
+    }
+}
+
+/// Register the runtime of the current server worker. Does nothing outside of a Tokio runtime.
+pub(crate) fn register_worker_runtime() {
+    if let Ok(handle) = Handle::try_current() {
+        RUNTIMES.lock().unwrap().push(handle);
+    }
+}
+
+#[must_use]
+pub fn runtime_stats() -> RuntimeStats {
+    RuntimeStats {
+        workers: RUNTIMES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(WorkerStats::new)
+            .collect(),
+        #[cfg(feature = "postgres")]
+        postgres_pools: crate::pg::pool_statuses(),
+    }
+}
+
+#[route("/debug/runtime", method = "GET")]
+#[allow(clippy::unused_async)]
+pub(crate) async fn get_runtime_stats() -> Json<RuntimeStats> {
+    Json(runtime_stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_runtime_stats() {
+        register_worker_runtime();
+        let stats = runtime_stats();
+        let worker = stats.workers.last().unwrap();
+        assert_eq!(worker.threads, 1);
+        assert!(worker.alive_tasks <= 1);
+    }
+}
@@ -0,0 +1,166 @@
+use actix_web::http::header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS,
+};
+use actix_web::middleware::DefaultHeaders;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::OptBoolObj;
+use crate::MartinError::SecurityHeaderError;
+use crate::MartinResult;
+
+pub const REFERRER_POLICY_DEFAULT: &str = "strict-origin-when-cross-origin";
+pub const CONTENT_SECURITY_POLICY_DEFAULT: &str =
+    "default-src 'self'; style-src 'self' 'unsafe-inline'";
+
+/// Standard security headers added to every response that does not have them yet.
+/// They are enabled with the defaults unless `security_headers: false` is configured.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SecurityHeadersConfig {
+    /// `max-age` (in seconds) of the `Strict-Transport-Security` header. Only set this if clients always
+    /// connect with HTTPS, e.g. through a TLS-terminating load balancer [default: no header]
+    pub hsts_max_age: Option<u64>,
+    /// Apply `Strict-Transport-Security` to all subdomains [default: false]
+    pub hsts_include_subdomains: Option<bool>,
+    /// Send `X-Content-Type-Options: nosniff` [default: true]
+    pub content_type_options: Option<bool>,
+    /// The `Referrer-Policy` header, or an empty string to not send it [default: `strict-origin-when-cross-origin`]
+    pub referrer_policy: Option<String>,
+    /// The `Content-Security-Policy` header, or an empty string to not send it
+    /// [default: `default-src 'self'; style-src 'self' 'unsafe-inline'`]
+    pub content_security_policy: Option<String>,
+}
+
+impl SecurityHeadersConfig {
+    /// The configured headers, or all the default headers if not configured, or none if disabled
+    #[must_use]
+    pub fn from_opt(config: &OptBoolObj<Self>) -> Option<Self> {
+        match config {
+            OptBoolObj::NoValue | OptBoolObj::Bool(true) => Some(Self::default()),
+            OptBoolObj::Bool(false) => None,
+            OptBoolObj::Object(config) => Some(config.clone()),
+        }
+    }
+
+    /// All headers to add to the responses
+    pub fn headers(&self) -> MartinResult<Vec<(HeaderName, HeaderValue)>> {
+        let mut headers = Vec::new();
+        if let Some(max_age) = self.hsts_max_age {
+            let value = if self.hsts_include_subdomains.unwrap_or_default() {
+                format!("max-age={max_age}; includeSubDomains")
+            } else {
+                format!("max-age={max_age}")
+            };
+            headers.push((STRICT_TRANSPORT_SECURITY, value));
+        }
+        if self.content_type_options.unwrap_or(true) {
+            headers.push((X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()));
+        }
+        let referrer_policy = self.referrer_policy.as_deref();
+        let csp = self.content_security_policy.as_deref();
+        headers.push((
+            REFERRER_POLICY,
+            referrer_policy.unwrap_or(REFERRER_POLICY_DEFAULT).into(),
+        ));
+        headers.push((
+            CONTENT_SECURITY_POLICY,
+            csp.unwrap_or(CONTENT_SECURITY_POLICY_DEFAULT).into(),
+        ));
+
+        headers
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| {
+                HeaderValue::try_from(value)
+                    .map(|value| (name.clone(), value))
+                    .map_err(|_| SecurityHeaderError(name.to_string()))
+            })
+            .collect()
+    }
+
+    /// Make sure all configured values are valid HTTP header values
+    pub fn validate(&self) -> MartinResult<()> {
+        self.headers().map(|_| ())
+    }
+
+    #[must_use]
+    pub fn make_middleware(&self) -> DefaultHeaders {
+        self.headers()
+            .unwrap_or_default()
+            .into_iter()
+            .fold(DefaultHeaders::new(), DefaultHeaders::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use indoc::indoc;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_default_headers() {
+        let cfg = SecurityHeadersConfig::from_opt(&OptBoolObj::NoValue).unwrap();
+        let app = init_service(App::new().wrap(cfg.make_middleware()).route(
+            "/",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((REFERRER_POLICY, "no-referrer"))
+                    .finish()
+            }),
+        ))
+        .await;
+        let response = call_service(&app, TestRequest::get().to_request()).await;
+        let headers = response.headers();
+        assert_eq!(headers.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(
+            headers.get(CONTENT_SECURITY_POLICY).unwrap(),
+            CONTENT_SECURITY_POLICY_DEFAULT
+        );
+        // headers set by the route are kept
+        assert_eq!(headers.get(REFERRER_POLICY).unwrap(), "no-referrer");
+        assert!(headers.get(STRICT_TRANSPORT_SECURITY).is_none());
+    }
+
+    #[test]
+    fn test_configured_headers() {
+        let cfg: OptBoolObj<SecurityHeadersConfig> = serde_yaml::from_str(indoc! {"
+            hsts_max_age: 31536000
+            hsts_include_subdomains: true
+            content_type_options: false
+            referrer_policy: ''
+        "})
+        .unwrap();
+        let headers = SecurityHeadersConfig::from_opt(&cfg)
+            .unwrap()
+            .headers()
+            .unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    STRICT_TRANSPORT_SECURITY,
+                    HeaderValue::from_static("max-age=31536000; includeSubDomains")
+                ),
+                (
+                    CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static(CONTENT_SECURITY_POLICY_DEFAULT)
+                ),
+            ]
+        );
+
+        assert_eq!(
+            SecurityHeadersConfig::from_opt(&OptBoolObj::Bool(false)),
+            None
+        );
+
+        let cfg = SecurityHeadersConfig {
+            content_security_policy: Some("default-src\n'self'".to_string()),
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_err());
+    }
+}
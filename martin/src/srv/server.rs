@@ -1,37 +1,80 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::future::Future;
-use std::pin::Pin;
+use std::path::PathBuf;
+use std::pin::{pin, Pin};
 use std::string::ToString;
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::args::{Args, OsEnv};
 use crate::config::ServerState;
-use crate::source::TileCatalog;
-use crate::srv::config::{SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT};
-use crate::srv::tiles::get_tile;
-use crate::srv::tiles_info::get_source_info;
-use crate::utils::OptMainCache;
+use crate::source::{Source, TileCatalog};
+use crate::srv::access::AccessControl;
+use crate::srv::admin::new_admin_server;
+use crate::srv::audit::{AuditAction, AuditRecord, AUDIT_ACTOR_CONFIG_WATCHER};
+use crate::srv::catalog_html::{catalog_to_html, encode_segment};
+use crate::srv::cdn_purge::{purge_cdn, purge_sources};
+use crate::srv::config::{
+    DiscoveryRetryConfig, ErrorDetail, SrvConfig, KEEP_ALIVE_DEFAULT, LISTEN_ADDRESSES_DEFAULT,
+    SHUTDOWN_TIMEOUT_DEFAULT,
+};
+use crate::srv::health::{source_health, HealthStatus};
+use crate::srv::openapi::get_openapi;
+use crate::srv::problem::problem_json;
+use crate::srv::rate_limit::{RateLimit, RateLimiter};
+use crate::srv::request_id::{RequestId, ACCESS_LOG_FORMAT};
+use crate::srv::security_headers::SecurityHeadersConfig;
+use crate::srv::tiles::{get_tile, get_tiles_batch, get_utfgrid, with_etag};
+use crate::srv::tiles_info::{get_source_info, public_base_url};
+use crate::srv::url_template::UrlTemplates;
+use crate::srv::usage::{UsageRecorder, UsageTracking};
+use crate::srv::version::{ApiVersion, API_VERSION_PREFIX};
+use crate::srv::webhooks::notify_webhooks;
+use crate::utils::{configure_logging, start_error_reporting, OptMainCache};
 use crate::MartinError::BindingError;
-use crate::{read_config, TileSources};
+use crate::{load_config, TileSources};
 use crate::{Config, MartinResult};
-use actix_cors::Cors;
 use actix_web::error::ErrorInternalServerError;
-use actix_web::http::header::CACHE_CONTROL;
+use actix_web::http::header::{Accept, ContentType, HeaderValue, CACHE_CONTROL, VARY};
 use actix_web::middleware::TrailingSlash;
+use actix_web::rt::net::TcpStream;
 use actix_web::web::Data;
-use actix_web::{middleware, route, web, App, HttpResponse, HttpServer, Responder};
-use futures::TryFutureExt;
-#[cfg(feature = "lambda")]
-use lambda_web::{is_running_on_lambda, run_actix_on_lambda};
-use log::{error, info};
+use actix_web::{
+    guard, middleware, route, web, App, HttpMessage as _, HttpRequest, HttpResponse, HttpServer,
+    Responder,
+};
+use futures::future::{join_all, select, try_join, Either};
+use futures::{FutureExt as _, TryFutureExt};
+use itertools::Itertools as _;
+use log::{error, info, warn};
+use martin_tile_utils::Format;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::sync::{watch, RwLock};
+use tokio::time::MissedTickBehavior;
 
 /// List of keywords that cannot be used as source IDs. Some of these are reserved for future use.
 /// Reserved keywords must never end in a "dot number" (e.g. ".1").
 /// This list is documented in the `docs/src/using.md` file, which should be kept in sync.
 pub const RESERVED_KEYWORDS: &[&str] = &[
-    "_", "catalog", "config", "font", "health", "help", "index", "manifest", "metrics", "refresh",
-    "reload", "sprite", "status",
+    "_",
+    "catalog",
+    "config",
+    "font",
+    "health",
+    "help",
+    "image",
+    "index",
+    "manifest",
+    "metrics",
+    "refresh",
+    "reload",
+    "robots.txt",
+    "sprite",
+    "status",
+    "style",
+    "t",
+    "v1",
 ];
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
@@ -53,6 +96,142 @@ impl Catalog {
             fonts: state.fonts.get_catalog(),
         })
     }
+
+    /// Add the absolute `TileJSON`, tiles, and style URLs to the tile sources, using the public URL of the server.
+    /// Only vector sources get a style URL.
+    #[must_use]
+    pub fn with_urls(mut self, base_url: &str) -> Self {
+        for (id, entry) in &mut self.tiles {
+            let id = encode_segment(id);
+            entry.tilejson = Some(format!("{base_url}/{id}"));
+            entry.tiles = Some(format!("{base_url}/{id}/{{z}}/{{x}}/{{y}}"));
+            entry.style = (entry.content_type == Format::Mvt.content_type())
+                .then(|| format!("{base_url}/style/{id}"));
+        }
+        self
+    }
+
+    /// Add the health of the tile sources that are not `ok`
+    #[must_use]
+    pub fn with_health(mut self, error_detail: ErrorDetail) -> Self {
+        for (id, entry) in &mut self.tiles {
+            let health = source_health(id, error_detail);
+            entry.health = (health.status != HealthStatus::Ok).then_some(health);
+        }
+        self
+    }
+
+    /// Remove the tile sources marked as hidden in the source options, which are served but not listed
+    #[must_use]
+    pub fn without_hidden(mut self, srv_config: &SrvConfig) -> Self {
+        self.tiles.retain(|id, _| {
+            !srv_config
+                .get_source_options(id)
+                .and_then(|o| o.hidden)
+                .unwrap_or_default()
+        });
+        self
+    }
+}
+
+/// Tile sources added, removed, or with a changed `TileJSON` after the sources have been reloaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CatalogDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// Changes of the tenants that existed before and after the reload
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, CatalogDiff>,
+}
+
+impl CatalogDiff {
+    #[must_use]
+    pub fn new(old: &ServerState, new: &ServerState) -> Self {
+        let old_ids = old.tiles.get_catalog();
+        let new_ids = new.tiles.get_catalog();
+        let changed = new_ids
+            .keys()
+            .filter(|id| old_ids.contains_key(*id))
+            .filter(|id| {
+                let old_tj = old.tiles.get_source(id).map(Source::get_tilejson);
+                let new_tj = new.tiles.get_source(id).map(Source::get_tilejson);
+                old_tj.ok() != new_tj.ok()
+            })
+            .cloned()
+            .collect();
+        let tenants = new
+            .tenants
+            .iter()
+            .filter_map(|(name, tenant)| {
+                let old_tenant = old.tenants.get(name)?;
+                let diff = Self::new(&old_tenant.state, &tenant.state);
+                (!diff.is_empty()).then(|| (name.clone(), diff))
+            })
+            .collect();
+        Self {
+            added: new_ids
+                .keys()
+                .filter(|id| !old_ids.contains_key(*id))
+                .cloned()
+                .collect(),
+            removed: old_ids
+                .keys()
+                .filter(|id| !new_ids.contains_key(*id))
+                .cloned()
+                .collect(),
+            changed,
+            tenants,
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.tenants.is_empty()
+    }
+
+    /// IDs of all added, removed, and changed sources, including the ones of the tenants
+    #[must_use]
+    pub fn source_ids(&self) -> BTreeSet<String> {
+        let mut ids: BTreeSet<_> = self
+            .added
+            .iter()
+            .chain(&self.removed)
+            .chain(&self.changed)
+            .cloned()
+            .collect();
+        for tenant in self.tenants.values() {
+            ids.extend(tenant.source_ids());
+        }
+        ids
+    }
+}
+
+impl std::fmt::Display for CatalogDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no sources changed");
+        }
+        let lists = [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ];
+        let mut parts = lists
+            .iter()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(name, ids)| format!("{name} {}", ids.join(", ")))
+            .collect::<Vec<_>>();
+        parts.extend(
+            self.tenants
+                .iter()
+                .map(|(name, diff)| format!("tenant {name} ({diff})")),
+        );
+        write!(f, "{}", parts.join("; "))
+    }
 }
 
 pub fn map_internal_error<T: std::fmt::Display>(e: T) -> actix_web::Error {
@@ -79,69 +258,441 @@ async fn get_health() -> impl Responder {
         .message_body("OK")
 }
 
-#[allow(clippy::too_many_arguments)]
 #[route("/refresh", method = "POST")]
-#[allow(clippy::unused_async)]
 async fn refresh_catalog(
+    req: HttpRequest,
     args: Data<Args>,
     env: Data<OsEnv>,
-    srv_config_guard: Data<RwLock<SrvConfig>>,
-    catalog_guard: Data<RwLock<Catalog>>,
-    state_guard: Data<RwLock<ServerState>>,
-    tiles_guard: Data<RwLock<TileSources>>,
-    cache_guard: Data<RwLock<OptMainCache>>,
-
-    #[cfg(feature = "sprites")] sprites_guard: Data<RwLock<crate::sprites::SpriteSources>>,
-
-    #[cfg(feature = "fonts")] fonts_guard: Data<RwLock<crate::fonts::FontSources>>,
+    app_state: Data<AppState>,
+    reload: Option<Data<StateSender>>,
 ) -> actix_web::error::Result<HttpResponse> {
-    let mut config = if let Some(ref cfg_filename) = args.meta.config {
+    let mut audit = {
+        let srv_config = app_state.srv_config.read().await;
+        srv_config.audit.clone().map(|cfg| {
+            let record = cfg.record(&req, srv_config.access.as_ref(), AuditAction::Refresh);
+            (cfg, record)
+        })
+    };
+    let result = refresh_sources(
+        &args,
+        &env,
+        &app_state,
+        reload.as_ref().map(Data::get_ref),
+        audit.as_mut().map(|v| &mut v.1),
+    )
+    .await;
+    if let Some((cfg, record)) = audit {
+        cfg.write(&record.with_result(&result)).await;
+    }
+    Ok(HttpResponse::Ok().json(result.map_err(map_internal_error)?))
+}
+
+/// Reload the config file, or the default config if there is none, and replace the sources with its ones.
+/// The new state is also published to the other workers and to the gRPC server.
+async fn refresh_sources(
+    args: &Args,
+    env: &OsEnv,
+    app_state: &AppState,
+    reload: Option<&StateSender>,
+    audit: Option<&mut AuditRecord>,
+) -> MartinResult<CatalogDiff> {
+    let config = if let Some(ref cfg_filename) = args.meta.config {
         info!("Using {} to refresh catalog", cfg_filename.display());
-        read_config(cfg_filename, env.get_ref()).map_err(map_internal_error)?
+        load_config(cfg_filename, env).await?
     } else {
         info!("Config file is not specified, an default config will be used to refresh catalog");
         Config::default()
     };
-    let cloned_args = (**args).clone();
-    cloned_args
-        .merge_into_config(&mut config, env.get_ref())
-        .map_err(map_internal_error)?;
+    let (new_srv_config, new_state) = resolve_config(config, args, env).await?;
+
+    let diff = {
+        let old_state = app_state.state.read().await;
+        let diff = CatalogDiff::new(&old_state, &new_state);
+        if let Some(record) = audit {
+            record.set_catalog_change(&old_state, &new_state, &diff);
+        }
+        diff
+    };
+    let webhooks = new_srv_config.webhooks.clone().unwrap_or_default();
+    let cdns = new_srv_config.cdn_purge.clone().unwrap_or_default();
+    #[cfg(feature = "redis")]
+    let cache_invalidation = new_srv_config.cache_invalidation.clone();
+    if let Some(sender) = reload {
+        sender.send_replace(Some((new_srv_config.clone(), new_state.clone())));
+    }
+    app_state.replace(new_srv_config, new_state).await?;
 
-    config.finalize().map_err(map_internal_error)?;
+    info!("Refreshed the catalog: {diff}");
+    notify_webhooks(&webhooks, &diff);
+    purge_cdn(&cdns, diff.source_ids());
+    #[cfg(feature = "redis")]
+    if let Some(cfg) = cache_invalidation {
+        cfg.publish(&(&diff).into());
+    }
+    Ok(diff)
+}
 
-    let sources = config.resolve().await.map_err(map_internal_error)?;
+/// Apply the command line arguments to a freshly loaded config, and resolve all of its sources
+async fn resolve_config(
+    mut config: Config,
+    args: &Args,
+    env: &OsEnv,
+) -> MartinResult<(SrvConfig, ServerState)> {
+    args.clone().merge_into_config(&mut config, env)?;
+    config.finalize()?;
+    let state = config.resolve().await?;
+    Ok((config.srv, state))
+}
 
-    // update these two guards
-    let new_srv_config = config.srv;
-    let new_state = sources;
-    let new_catalog = Catalog::new(&new_state).map_err(map_internal_error)?;
-    let new_tiles = new_state.tiles.clone();
-    let new_cache = new_state.cache.clone();
+/// Publishes a new server state to the workers and to the gRPC server
+type StateSender = watch::Sender<Option<(SrvConfig, ServerState)>>;
 
-    let mut srv_config = srv_config_guard.write().await;
-    let mut state = state_guard.write().await;
-    let mut catalog = catalog_guard.write().await;
-    let mut tiles = tiles_guard.write().await;
-    let mut cache = cache_guard.write().await;
+/// The state last published by a background task or a `/refresh` request, or the initial one if there is none yet
+fn published_state<'a>(
+    published: &'a Option<(SrvConfig, ServerState)>,
+    initial_state: &'a ServerState,
+) -> &'a ServerState {
+    published.as_ref().map_or(initial_state, |(_, state)| state)
+}
 
-    #[cfg(feature = "sprites")]
-    {
-        let mut sprites = sprites_guard.write().await;
-        *sprites = new_state.sprites.clone();
+/// Re-fetch the config file every `period`, and publish the new server state whenever it changes.
+/// Errors are logged, and the server keeps running with the last good configuration.
+async fn watch_config(
+    location: PathBuf,
+    period: Duration,
+    args: Args,
+    env: OsEnv,
+    initial_state: ServerState,
+    sender: StateSender,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_config = None;
+    loop {
+        interval.tick().await;
+        let config = match load_config(&location, &env).await {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("{e}");
+                continue;
+            }
+        };
+        if last_config.as_ref() == Some(&config) {
+            continue;
+        }
+        // the first fetch only records the config the server was started with
+        let Some(previous) = last_config.replace(config.clone()) else {
+            continue;
+        };
+        info!("Config file {} has changed, reloading", location.display());
+        let audit = previous.srv.audit.clone();
+        let mut record = AuditRecord {
+            actor: Some(AUDIT_ACTOR_CONFIG_WATCHER.to_string()),
+            ..AuditRecord::new(AuditAction::Reload)
+        };
+        if previous.srv.log != config.srv.log {
+            if let Err(e) = configure_logging(&config.srv.log.clone().unwrap_or_default()) {
+                warn!("Unable to change the logging configuration: {e}");
+            }
+        }
+        match resolve_config(config, &args, &env).await {
+            Ok((srv_config, state)) => {
+                // compare with the state being served, which may come from another task or a `/refresh` request
+                let mut diff = None;
+                sender.send_modify(|published| {
+                    let last_state = published_state(published, &initial_state);
+                    let new_diff = CatalogDiff::new(last_state, &state);
+                    record.set_catalog_change(last_state, &state, &new_diff);
+                    diff = Some(new_diff);
+                    *published = Some((srv_config.clone(), state));
+                });
+                let diff = diff.unwrap_or_default();
+                info!("Reloaded config file {}: {diff}", location.display());
+                announce_catalog_change(&srv_config, &diff);
+            }
+            Err(e) => {
+                warn!("Unable to reload config file {}: {e}", location.display());
+                record.error = Some(e.to_string());
+            }
+        }
+        if let Some(audit) = audit {
+            audit.write(&record).await;
+        }
+    }
+}
+
+/// Discover the sources again with an exponential backoff for as long as some of them fail to be discovered,
+/// and publish the new server state whenever fewer of them fail. The state is not replaced otherwise,
+/// so the sources discovered before keep being served. The failures are those of the state being served,
+/// which may have been replaced in the meantime, e.g. by a `/refresh` request.
+async fn retry_discovery(
+    retry: DiscoveryRetryConfig,
+    args: Args,
+    env: OsEnv,
+    initial_state: ServerState,
+    sender: StateSender,
+) {
+    let mut delay = retry.get_initial_delay();
+    loop {
+        let failures = published_state(&sender.borrow(), &initial_state).all_discovery_failures();
+        if failures.is_empty() {
+            break;
+        }
+        warn!(
+            "Retrying the discovery of {} failed items in {}s: {}",
+            failures.len(),
+            delay.as_secs(),
+            failures.join("; ")
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(retry.get_max_delay());
+        let config = match &args.meta.config {
+            Some(location) => load_config(location, &env).await,
+            None => Ok(Config::default()),
+        };
+        let (srv_config, state) = match config {
+            Ok(config) => match resolve_config(config, &args, &env).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Unable to retry the discovery: {e}");
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("Unable to retry the discovery: {e}");
+                continue;
+            }
+        };
+        let new_failures = state.all_discovery_failures().len();
+        let mut diff = None;
+        sender.send_if_modified(|published| {
+            let last_state = published_state(published, &initial_state);
+            if new_failures >= last_state.all_discovery_failures().len() {
+                return false;
+            }
+            diff = Some(CatalogDiff::new(last_state, &state));
+            *published = Some((srv_config.clone(), state));
+            true
+        });
+        if let Some(diff) = diff {
+            info!("Retried the discovery of the failed items: {diff}");
+            announce_catalog_change(&srv_config, &diff);
+        }
     }
+}
+
+/// Let the webhooks, the CDNs, and the other replicas know about the changed sources
+fn announce_catalog_change(srv_config: &SrvConfig, diff: &CatalogDiff) {
+    notify_webhooks(srv_config.webhooks.as_deref().unwrap_or_default(), diff);
+    purge_cdn(
+        srv_config.cdn_purge.as_deref().unwrap_or_default(),
+        diff.source_ids(),
+    );
+    #[cfg(feature = "redis")]
+    if let Some(cfg) = &srv_config.cache_invalidation {
+        cfg.publish(&diff.into());
+    }
+}
+
+/// Shared state of a single worker, replaced as a whole when the configuration is reloaded
+#[derive(Clone)]
+struct AppState {
+    srv_config: Data<RwLock<SrvConfig>>,
+    catalog: Data<RwLock<Catalog>>,
+    state: Data<RwLock<ServerState>>,
+    tiles: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+    #[cfg(feature = "sprites")]
+    sprites: Data<RwLock<crate::sprites::SpriteSources>>,
     #[cfg(feature = "fonts")]
-    {
-        let mut fonts = fonts_guard.write().await;
-        *fonts = new_state.fonts.clone();
+    fonts: Data<RwLock<crate::fonts::FontSources>>,
+    tenants: Vec<TenantApp>,
+}
+
+#[derive(Clone)]
+struct TenantApp {
+    name: String,
+    hosts: Vec<String>,
+    app_state: AppState,
+}
+
+impl AppState {
+    /// The catalogs of the tenants are computed in advance because this cannot fail
+    fn new(
+        srv_config: &SrvConfig,
+        state: &ServerState,
+        catalog: &Catalog,
+        tenant_catalogs: &BTreeMap<String, Catalog>,
+    ) -> Self {
+        let tenants = state
+            .tenants
+            .iter()
+            .filter_map(|(name, tenant)| {
+                let catalog = tenant_catalogs.get(name)?;
+                Some(TenantApp {
+                    name: name.clone(),
+                    hosts: tenant.hosts.clone(),
+                    app_state: Self::new(&tenant.srv, &tenant.state, catalog, &BTreeMap::new()),
+                })
+            })
+            .collect();
+        Self {
+            srv_config: Data::new(RwLock::new(srv_config.clone())),
+            catalog: Data::new(RwLock::new(catalog.clone())),
+            state: Data::new(RwLock::new(state.clone())),
+            tiles: Data::new(RwLock::new(state.tiles.clone())),
+            cache: Data::new(RwLock::new(state.cache.clone())),
+            #[cfg(feature = "sprites")]
+            sprites: Data::new(RwLock::new(state.sprites.clone())),
+            #[cfg(feature = "fonts")]
+            fonts: Data::new(RwLock::new(state.fonts.clone())),
+            tenants,
+        }
+    }
+
+    /// Register the data used by the endpoints
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.tiles.clone())
+            .app_data(self.cache.clone())
+            .app_data(self.state.clone())
+            .app_data(self.catalog.clone())
+            .app_data(self.srv_config.clone());
+
+        #[cfg(feature = "sprites")]
+        cfg.app_data(self.sprites.clone());
+
+        #[cfg(feature = "fonts")]
+        cfg.app_data(self.fonts.clone());
+    }
+
+    /// Serve each tenant at `/t/{tenant}` and on its host names, with its own data.
+    /// Must be configured before the main routes, so that tenant hosts are matched first.
+    fn tenant_router(&self, cfg: &mut web::ServiceConfig) {
+        for tenant in &self.tenants {
+            let app_state = tenant.app_state.clone();
+            let configure = move |cfg: &mut web::ServiceConfig| {
+                app_state.register(cfg);
+                static_router(cfg);
+            };
+            if let Some((first, rest)) = tenant.hosts.split_first() {
+                let hosts = rest
+                    .iter()
+                    .fold(guard::Any(guard::Host(first.clone())), |g, host| {
+                        g.or(guard::Host(host.clone()))
+                    });
+                cfg.service(web::scope("").guard(hosts).configure(configure.clone()));
+            }
+            cfg.service(web::scope(&format!("/t/{}", tenant.name)).configure(configure));
+        }
+    }
+
+    /// Replace the sources of the main config and of all tenants.
+    /// Adding or removing tenants, or changing their hosts, requires a restart.
+    async fn replace(&self, new_srv_config: SrvConfig, new_state: ServerState) -> MartinResult<()> {
+        for tenant in &self.tenants {
+            if let Some(new_tenant) = new_state.tenants.get(&tenant.name) {
+                let new_catalog = Catalog::new(&new_tenant.state)?.without_hidden(&new_tenant.srv);
+                tenant
+                    .app_state
+                    .replace_sources(
+                        new_tenant.srv.clone(),
+                        new_tenant.state.clone(),
+                        new_catalog,
+                    )
+                    .await;
+            } else {
+                warn!("Tenant {} is no longer configured, but will be served until Martin is restarted", tenant.name);
+            }
+        }
+        for name in new_state.tenants.keys() {
+            if !self.tenants.iter().any(|t| &t.name == name) {
+                warn!("Tenant {name} has been added, but will not be served until Martin is restarted");
+            }
+        }
+        let new_catalog = Catalog::new(&new_state)?.without_hidden(&new_srv_config);
+        self.replace_sources(new_srv_config, new_state, new_catalog)
+            .await;
+        Ok(())
+    }
+
+    async fn replace_sources(
+        &self,
+        new_srv_config: SrvConfig,
+        new_state: ServerState,
+        new_catalog: Catalog,
+    ) {
+        let new_tiles = new_state.tiles.clone();
+        let new_cache = new_state.cache.clone();
+
+        let mut srv_config = self.srv_config.write().await;
+        let mut state = self.state.write().await;
+        let mut catalog = self.catalog.write().await;
+        let mut tiles = self.tiles.write().await;
+        let mut cache = self.cache.write().await;
+
+        #[cfg(feature = "sprites")]
+        {
+            let mut sprites = self.sprites.write().await;
+            *sprites = new_state.sprites.clone();
+        }
+        #[cfg(feature = "fonts")]
+        {
+            let mut fonts = self.fonts.write().await;
+            *fonts = new_state.fonts.clone();
+        }
+
+        *srv_config = new_srv_config;
+        *state = new_state;
+        *catalog = new_catalog;
+        *tiles = new_tiles;
+        *cache = new_cache;
+    }
+
+    /// Drop the cached tiles of the sources whenever a replica publishes an invalidation, including this one
+    #[cfg(feature = "redis")]
+    fn spawn_invalidation_listener(&self, config: crate::srv::CacheInvalidationConfig) {
+        let app_state = self.clone();
+        actix_rt::spawn(config.subscribe(move |invalidation| {
+            let app_state = app_state.clone();
+            async move { app_state.invalidate(&invalidation).await }
+        }));
     }
 
-    *srv_config = new_srv_config;
-    *state = new_state;
-    *catalog = new_catalog;
-    *tiles = new_tiles;
-    *cache = new_cache;
+    #[cfg(feature = "redis")]
+    async fn invalidate(&self, invalidation: &crate::srv::Invalidation) {
+        let caches = std::iter::once((&self.cache, &invalidation.sources)).chain(
+            self.tenants.iter().filter_map(|tenant| {
+                let ids = invalidation.tenants.get(&tenant.name)?;
+                Some((&tenant.app_state.cache, ids))
+            }),
+        );
+        for (cache, ids) in caches {
+            if let Some(cache) = cache.read().await.as_ref() {
+                if let Err(e) = crate::utils::invalidate_sources(cache, ids) {
+                    warn!(
+                        "Unable to remove the tiles of {} from the cache: {e}",
+                        ids.join(",")
+                    );
+                }
+            }
+        }
+    }
 
-    Ok(HttpResponse::Ok().finish())
+    /// Replace the state of this worker whenever the config watcher publishes a new one.
+    /// The task ends as soon as the sender is dropped, i.e. right away if the config is not refreshed.
+    fn spawn_updater(&self, mut receiver: watch::Receiver<Option<(SrvConfig, ServerState)>>) {
+        let updater = self.clone();
+        actix_rt::spawn(async move {
+            while receiver.changed().await.is_ok() {
+                let update = receiver.borrow_and_update().clone();
+                if let Some((srv_config, state)) = update {
+                    if let Err(e) = updater.replace(srv_config, state).await {
+                        warn!("Unable to apply the reloaded config: {e}");
+                    }
+                }
+            }
+        });
+    }
 }
 
 #[route(
@@ -151,18 +702,91 @@ async fn refresh_catalog(
     wrap = "middleware::Compress::default()"
 )]
 #[allow(clippy::unused_async)]
-async fn get_catalog(catalog: Data<RwLock<Catalog>>) -> impl Responder {
-    let catalog_guard = catalog.read().await;
-    HttpResponse::Ok().json(&*catalog_guard)
+async fn get_catalog(
+    req: HttpRequest,
+    catalog: Data<RwLock<Catalog>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> actix_web::Result<HttpResponse> {
+    let (base_path, error_detail) = {
+        let srv_config = srv_config.read().await;
+        let error_detail = srv_config.error_detail.unwrap_or_default();
+        (srv_config.base_path.clone(), error_detail)
+    };
+    let base_url = public_base_url(&req, base_path.as_deref(), "/catalog");
+    let catalog = catalog
+        .read()
+        .await
+        .clone()
+        .with_urls(&base_url)
+        .with_health(error_detail);
+    let mut response = if prefers_html(&req) {
+        with_etag(
+            &req,
+            ContentType::html(),
+            catalog_to_html(&catalog).into_bytes(),
+        )
+    } else {
+        let body = serde_json::to_vec(&catalog).map_err(map_internal_error)?;
+        with_etag(&req, ContentType::json(), body)
+    };
+    // the same URL is either HTML or JSON, so caches must keep them apart
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("Accept"));
+    Ok(response)
+}
+
+/// Browsers list `text/html` as their most preferred type, whereas API clients
+/// usually send `*/*` or `application/json`.
+fn prefers_html(req: &HttpRequest) -> bool {
+    req.get_header::<Accept>()
+        .is_some_and(|accept| accept.preference().essence_str() == "text/html")
 }
 
 pub fn router(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "metrics")]
+    cfg.service(crate::srv::metrics::get_metrics);
+    #[cfg(feature = "console")]
+    cfg.service(crate::srv::runtime::get_runtime_stats);
+    cfg.service(
+        web::scope(API_VERSION_PREFIX)
+            .service(refresh_catalog)
+            .service(purge_sources)
+            .configure(routes),
+    )
+    .service(refresh_catalog)
+    .service(purge_sources);
+    routes(cfg);
+}
+
+/// All routes except the admin endpoints, i.e. `/refresh`, which re-reads the configuration
+/// the server was started with, `/{source_ids}/purge`, `/metrics`, and `/debug/runtime`
+pub(crate) fn static_router(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope(API_VERSION_PREFIX).configure(routes));
+    routes(cfg);
+}
+
+/// Routes of the current API version, registered both with and without the version prefix
+fn routes(cfg: &mut web::ServiceConfig) {
     cfg.service(get_health)
+        .service(crate::srv::health::get_health_detail)
         .service(get_index)
         .service(get_catalog)
-        .service(refresh_catalog)
-        .service(get_source_info)
-        .service(get_tile);
+        .service(get_openapi)
+        .service(crate::srv::style::get_style)
+        .service(crate::srv::robots::get_robots_txt);
+
+    #[cfg(feature = "raster")]
+    cfg.service(crate::srv::render::get_static_image);
+
+    cfg.service(get_source_info).service(get_utfgrid);
+
+    #[cfg(feature = "raster")]
+    cfg.service(crate::srv::render::get_rendered_tile)
+        .service(crate::srv::terrain::get_terrain_layer)
+        .service(crate::srv::terrain::get_terrain_tile);
+
+    cfg.service(get_tile).service(get_tiles_batch);
 
     #[cfg(feature = "sprites")]
     cfg.service(crate::srv::sprites::get_sprite_json)
@@ -170,77 +794,275 @@ pub fn router(cfg: &mut web::ServiceConfig) {
 
     #[cfg(feature = "fonts")]
     cfg.service(crate::srv::fonts::get_font);
+
+    #[cfg(feature = "postgres")]
+    cfg.service(crate::srv::legacy::get_rpc_tilejson)
+        .service(crate::srv::legacy::get_rpc_tile);
 }
 
 type Server = Pin<Box<dyn Future<Output = MartinResult<()>>>>;
 
-/// Create a future for an Actix web server together with the listening address.
+type BackgroundTask = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Run the background tasks, e.g. the config watcher, for as long as the server is running.
+/// The server keeps running when all of the tasks are done.
+async fn with_background_tasks(
+    server: impl Future<Output = MartinResult<()>>,
+    tasks: Vec<BackgroundTask>,
+) -> MartinResult<()> {
+    if tasks.is_empty() {
+        return server.await;
+    }
+    let tasks = join_all(tasks).then(|_| std::future::pending::<()>());
+    match select(pin!(server), pin!(tasks)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(((), _)) => Ok(()),
+    }
+}
+
+/// Write the usage counted since the last flush once the server has stopped, instead of losing it
+async fn flush_usage_on_exit(
+    server: impl Future<Output = MartinResult<()>>,
+    recorder: Option<Arc<UsageRecorder>>,
+) -> MartinResult<()> {
+    let result = server.await;
+    if let Some(recorder) = recorder {
+        recorder.flush().await;
+    }
+    result
+}
+
+/// Create a future for an Actix web server together with the comma-separated addresses it is bound to.
+#[allow(clippy::too_many_lines)]
 pub fn new_server(
     env: OsEnv,
     args: Args,
     config: SrvConfig,
     state: ServerState,
 ) -> MartinResult<(Server, String)> {
-    let catalog = Catalog::new(&state)?;
+    if let Some(error_reporting) = &config.error_reporting {
+        start_error_reporting(error_reporting)?;
+    }
+    let catalog = Catalog::new(&state)?.without_hidden(&config);
+    let tenant_catalogs = state
+        .tenants
+        .iter()
+        .map(|(name, tenant)| {
+            Ok((
+                name.clone(),
+                Catalog::new(&tenant.state)?.without_hidden(&tenant.srv),
+            ))
+        })
+        .collect::<MartinResult<BTreeMap<_, _>>>()?;
     let keep_alive = Duration::from_secs(config.keep_alive.unwrap_or(KEEP_ALIVE_DEFAULT));
+    let shutdown_timeout = config.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT_DEFAULT);
     let worker_processes = config.worker_processes.unwrap_or_else(num_cpus::get);
+    let worker_max_blocking_threads = config.worker_max_blocking_threads;
+    let max_connections = config.max_connections;
+    let backlog = config.backlog;
+    let tcp_keepalive = config.tcp_keepalive.map(Duration::from_secs);
     let listen_addresses = config
         .listen_addresses
         .clone()
         .unwrap_or_else(|| LISTEN_ADDRESSES_DEFAULT.to_string());
 
-    let factory = move || {
-        let cors_middleware = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET"]);
+    let access = config.access.clone().unwrap_or_default();
+    let rate_limit = RateLimit(
+        config
+            .rate_limit
+            .as_ref()
+            .map(|v| Arc::new(RateLimiter::new(v).with_access(access.clone()))),
+    );
 
-        let app = App::new()
-            .app_data(Data::new(RwLock::new(state.tiles.clone())))
-            .app_data(Data::new(RwLock::new(state.cache.clone())))
-            .app_data(Data::new(RwLock::new(state.clone())));
+    let usage = UsageTracking(
+        config
+            .usage
+            .as_ref()
+            .map(|v| Arc::new(UsageRecorder::new(v))),
+    );
 
-        #[cfg(feature = "sprites")]
-        let app = app.app_data(Data::new(RwLock::new(state.sprites.clone())));
+    let read_only = config.read_only.unwrap_or_default();
+    let admin_server = match config.admin_listen_address.as_deref() {
+        Some(address) if read_only => {
+            warn!("Ignoring admin_listen_address {address}, admin endpoints are disabled in read-only mode");
+            None
+        }
+        Some(address) => Some(new_admin_server(
+            address,
+            config.access.clone().unwrap_or_default(),
+            usage.0.clone(),
+            config.audit.clone(),
+        )?),
+        None => None,
+    };
+    // In read-only mode, only the routes serving tiles and metadata are available
+    let main_router: fn(&mut web::ServiceConfig) = if read_only { static_router } else { router };
 
-        #[cfg(feature = "fonts")]
-        let app = app.app_data(Data::new(RwLock::new(state.fonts.clone())));
+    #[cfg(feature = "metrics")]
+    let metrics = Arc::new(crate::srv::Metrics::new(
+        &config.metrics.clone().unwrap_or_default(),
+    )?);
+
+    // Every worker and the gRPC server replace their state when a new one is published,
+    // i.e. with --config-refresh, discovery_retry, or after a /refresh request handled by any of the workers
+    let (reload_sender, reload_receiver) = watch::channel(None);
+    #[cfg(feature = "grpc")]
+    let grpc_server = config
+        .grpc_listen_address
+        .as_deref()
+        .map(|address| {
+            crate::srv::new_grpc_server(address, &state, config.clone(), reload_receiver.clone())
+        })
+        .transpose()?;
+    let mut background_tasks: Vec<BackgroundTask> = Vec::new();
+    if let Some(retry) = config.discovery_retry {
+        if !state.all_discovery_failures().is_empty() {
+            background_tasks.push(Box::pin(retry_discovery(
+                retry,
+                args.clone(),
+                env.clone(),
+                state.clone(),
+                reload_sender.clone(),
+            )));
+        }
+    }
+    if let Some(recorder) = &usage.0 {
+        background_tasks.push(Box::pin(recorder.clone().run_flusher()));
+    }
+    if let Some((location, period)) = args.meta.config.clone().zip(args.meta.config_refresh) {
+        background_tasks.push(Box::pin(watch_config(
+            location,
+            Duration::from_secs(period),
+            args.clone(),
+            env.clone(),
+            state.clone(),
+            reload_sender.clone(),
+        )));
+    }
+
+    let usage_recorder = usage.0.clone();
+    let factory = move || {
+        let cors_middleware = config.cors.clone().unwrap_or_default().make_middleware();
+        let security_headers = SecurityHeadersConfig::from_opt(&config.security_headers)
+            .map(|v| v.make_middleware())
+            .unwrap_or_default();
+        let app_state = AppState::new(&config, &state, &catalog, &tenant_catalogs);
+        app_state.spawn_updater(reload_receiver.clone());
+        #[cfg(feature = "redis")]
+        if let Some(cfg) = &config.cache_invalidation {
+            app_state.spawn_invalidation_listener(cfg.clone());
+        }
+        #[cfg(feature = "console")]
+        crate::srv::runtime::register_worker_runtime();
 
-        app.app_data(Data::new(env.clone()))
+        let app = App::new()
+            .app_data(Data::new(reload_sender.clone()))
+            .app_data(Data::new(env.clone()))
             .app_data(Data::new(args.clone()))
-            .app_data(Data::new(RwLock::new(catalog.clone())))
-            .app_data(Data::new(RwLock::new(config.clone())))
+            .app_data(Data::new(app_state.clone()))
+            .configure(|cfg| {
+                app_state.register(cfg);
+                #[cfg(feature = "metrics")]
+                cfg.app_data(Data::from(metrics.clone()));
+                app_state.tenant_router(cfg);
+            })
+            .wrap(ApiVersion)
+            .wrap(usage.clone())
+            .wrap(rate_limit.clone())
+            .wrap(AccessControl::new(access.clone()))
+            .wrap(problem_json(config.error_detail.unwrap_or_default()))
             .wrap(cors_middleware)
-            .wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
-            .wrap(middleware::Logger::default())
-            .configure(router)
+            .wrap(security_headers)
+            .wrap(UrlTemplates);
+        #[cfg(feature = "metrics")]
+        let app = app.wrap(crate::srv::HttpMetrics(metrics.clone()));
+        app.wrap(middleware::NormalizePath::new(TrailingSlash::MergeOnly))
+            .wrap(RequestId)
+            .wrap(middleware::Logger::new(ACCESS_LOG_FORMAT))
+            .configure(main_router)
     };
 
     #[cfg(feature = "lambda")]
-    if is_running_on_lambda() {
-        let server = run_actix_on_lambda(factory).err_into();
+    if crate::srv::lambda::is_running_on_lambda() {
+        let server =
+            with_background_tasks(crate::srv::lambda::run_on_lambda(factory), background_tasks);
+        let server = flush_usage_on_exit(server, usage_recorder);
         return Ok((Box::pin(server), "(aws lambda)".into()));
     }
 
-    let server = HttpServer::new(factory)
-        .bind(listen_addresses.clone())
-        .map_err(|e| BindingError(e, listen_addresses.clone()))?
+    let mut server = HttpServer::new(factory)
         .keep_alive(keep_alive)
-        .shutdown_timeout(0)
-        .workers(worker_processes)
-        .run()
-        .err_into();
+        // on SIGTERM, stop accepting connections and let in-flight requests finish
+        .shutdown_timeout(shutdown_timeout)
+        .workers(worker_processes);
+    if let Some(threads) = worker_max_blocking_threads {
+        server = server.worker_max_blocking_threads(threads);
+    }
+    if let Some(max_connections) = max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if let Some(backlog) = backlog {
+        // must be set before binding to have any effect
+        server = server.backlog(backlog);
+    }
+    if let Some(idle_time) = tcp_keepalive {
+        let tcp_keepalive = TcpKeepalive::new().with_time(idle_time);
+        server = server.on_connect(move |conn, _| {
+            if let Some(stream) = conn.downcast_ref::<TcpStream>() {
+                if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&tcp_keepalive) {
+                    warn!("Unable to enable TCP keepalive: {e}");
+                }
+            }
+        });
+    }
+    #[cfg(feature = "systemd")]
+    let listeners = crate::srv::systemd::take_listeners()
+        .map_err(|e| BindingError(e, "systemd sockets".to_string()))?;
+    #[cfg(not(feature = "systemd"))]
+    let listeners = Vec::<std::net::TcpListener>::new();
+
+    let server = if listeners.is_empty() {
+        server
+            .bind(listen_addresses.clone())
+            .map_err(|e| BindingError(e, listen_addresses.clone()))?
+    } else {
+        // listen_addresses is ignored, the sockets are configured in the systemd socket unit
+        listeners.into_iter().try_fold(server, |server, listener| {
+            let address = listener
+                .local_addr()
+                .map_or_else(|_| "systemd socket".to_string(), |v| v.to_string());
+            server
+                .listen(listener)
+                .map_err(|e| BindingError(e, address))
+        })?
+    };
+    // With port 0, the OS picks a free port, so report the addresses actually bound to
+    let listen_addresses = server.addrs().iter().map(ToString::to_string).join(",");
+    let mut server: Server = Box::pin(flush_usage_on_exit(
+        with_background_tasks(server.run().err_into(), background_tasks),
+        usage_recorder,
+    ));
+    if let Some(admin_server) = admin_server {
+        server = Box::pin(try_join(server, admin_server.err_into()).map_ok(|_| ()));
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_server) = grpc_server {
+        let server = try_join(server, grpc_server).map_ok(|_| ());
+        return Ok((Box::pin(server), listen_addresses));
+    }
 
-    Ok((Box::pin(server), listen_addresses))
+    Ok((server, listen_addresses))
 }
 
 #[cfg(test)]
 pub mod tests {
     use async_trait::async_trait;
-    use martin_tile_utils::{Encoding, Format, TileInfo};
+    use martin_tile_utils::{Encoding, TileInfo};
     use tilejson::TileJSON;
 
     use super::*;
-    use crate::source::{Source, TileData};
+    use crate::source::{CatalogSourceEntry, Source, TileData};
     use crate::{TileCoord, UrlQuery};
 
     #[derive(Debug, Clone)]
@@ -265,7 +1087,7 @@ pub mod tests {
         }
 
         fn clone_source(&self) -> Box<dyn Source> {
-            unimplemented!()
+            Box::new(self.clone())
         }
 
         async fn get_tile(
@@ -276,4 +1098,402 @@ pub mod tests {
             Ok(self.data.clone())
         }
     }
+
+    #[actix_rt::test]
+    async fn test_tenants() {
+        use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+        use tilejson::tilejson;
+
+        use crate::config::TenantState;
+        use crate::srv::SourceOptions;
+
+        let source = |id| {
+            Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            })
+        };
+        let mut state = ServerState::default();
+        state.tiles.add_source(source("main"));
+        state.tiles.add_source(source("hidden"));
+        let srv_config = SrvConfig {
+            source_options: Some(BTreeMap::from([(
+                "hidden".to_string(),
+                SourceOptions {
+                    hidden: Some(true),
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        let mut tenant = TenantState {
+            hosts: vec!["maps.acme.com".to_string()],
+            ..TenantState::default()
+        };
+        tenant.state.tiles.add_source(source("acme"));
+        state.tenants.insert("acme".to_string(), tenant);
+
+        let catalog = Catalog::new(&state).unwrap().without_hidden(&srv_config);
+        let tenant_catalogs = BTreeMap::from([(
+            "acme".to_string(),
+            Catalog::new(&state.tenants["acme"].state).unwrap(),
+        )]);
+        let app_state = AppState::new(&srv_config, &state, &catalog, &tenant_catalogs);
+        let app = init_service(
+            App::new()
+                .configure(|cfg| {
+                    app_state.register(cfg);
+                    app_state.tenant_router(cfg);
+                })
+                .configure(router),
+        )
+        .await;
+
+        let sources = |catalog: Catalog| catalog.tiles.into_keys().collect::<Vec<_>>();
+        let req = TestRequest::get().uri("/catalog").to_request();
+        assert_eq!(sources(call_and_read_body_json(&app, req).await), ["main"]);
+        // hidden sources are not listed, but still served
+        let req = TestRequest::get().uri("/hidden").to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+
+        let req = TestRequest::get().uri("/t/acme/catalog").to_request();
+        assert_eq!(sources(call_and_read_body_json(&app, req).await), ["acme"]);
+
+        let req = TestRequest::get().uri("/t/acme/catalog").to_request();
+        let catalog: Catalog = call_and_read_body_json(&app, req).await;
+        let entry = &catalog.tiles["acme"];
+        assert_eq!(
+            entry.tilejson.as_deref(),
+            Some("http://localhost:8080/t/acme/acme")
+        );
+        assert_eq!(
+            entry.tiles.as_deref(),
+            Some("http://localhost:8080/t/acme/acme/{z}/{x}/{y}")
+        );
+        assert_eq!(
+            entry.style.as_deref(),
+            Some("http://localhost:8080/t/acme/style/acme")
+        );
+        let req = TestRequest::get()
+            .uri("/v1/catalog")
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "tiles.example.com"))
+            .to_request();
+        let catalog: Catalog = call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            catalog.tiles["main"].tilejson.as_deref(),
+            Some("https://tiles.example.com/v1/main")
+        );
+
+        let req = TestRequest::get()
+            .uri("/catalog")
+            .insert_header(("Host", "maps.acme.com:3000"))
+            .to_request();
+        assert_eq!(sources(call_and_read_body_json(&app, req).await), ["acme"]);
+
+        for path in ["/catalog", "/main"] {
+            let req = TestRequest::get().uri(path).to_request();
+            let response = call_service(&app, req).await;
+            let etag = response.headers().get(ETAG).unwrap().clone();
+            assert!(etag.to_str().unwrap().starts_with("W/"));
+            let req = TestRequest::get()
+                .uri(path)
+                .insert_header((IF_NONE_MATCH, etag))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        }
+        let req = TestRequest::get()
+            .uri("/catalog")
+            .insert_header((IF_NONE_MATCH, "W/\"outdated\""))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_read_only() {
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        let state = ServerState::default();
+        let srv_config = SrvConfig::default();
+        let catalog = Catalog::new(&state).unwrap();
+        let tenant_catalogs = BTreeMap::new();
+        let app_state = AppState::new(&srv_config, &state, &catalog, &tenant_catalogs);
+        let app = init_service(
+            App::new()
+                .configure(|cfg| app_state.register(cfg))
+                .configure(static_router),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/catalog").to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+        for path in ["/refresh", "/v1/refresh", "/src/purge", "/v1/src/purge"] {
+            let req = TestRequest::post().uri(path).to_request();
+            let status = call_service(&app, req).await.status();
+            assert!(status.is_client_error(), "{path}: {status}");
+        }
+        for path in ["/metrics", "/debug/runtime"] {
+            let req = TestRequest::get().uri(path).to_request();
+            let status = call_service(&app, req).await.status();
+            assert_eq!(status, StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_catalog_urls() {
+        let mut catalog = Catalog::new(&ServerState::default()).unwrap();
+        for (id, format) in [("a b", Format::Mvt), ("img", Format::Png)] {
+            let entry = CatalogSourceEntry {
+                content_type: format.content_type().to_string(),
+                ..Default::default()
+            };
+            catalog.tiles.insert(id.to_string(), entry);
+        }
+        let catalog = catalog.with_urls("https://example.org/tiles");
+
+        let entry = &catalog.tiles["a b"];
+        assert_eq!(
+            entry.tilejson.as_deref(),
+            Some("https://example.org/tiles/a%20b")
+        );
+        assert_eq!(
+            entry.tiles.as_deref(),
+            Some("https://example.org/tiles/a%20b/{z}/{x}/{y}")
+        );
+        assert_eq!(
+            entry.style.as_deref(),
+            Some("https://example.org/tiles/style/a%20b")
+        );
+        let entry = &catalog.tiles["img"];
+        assert_eq!(
+            entry.tilejson.as_deref(),
+            Some("https://example.org/tiles/img")
+        );
+        assert_eq!(entry.style, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_catalog_vary() {
+        use actix_web::http::header::{ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_service, init_service, TestRequest};
+
+        let state = ServerState::default();
+        let srv_config = SrvConfig::default();
+        let catalog = Catalog::new(&state).unwrap();
+        let tenant_catalogs = BTreeMap::new();
+        let app_state = AppState::new(&srv_config, &state, &catalog, &tenant_catalogs);
+        let app = init_service(
+            App::new()
+                .configure(|cfg| app_state.register(cfg))
+                .configure(router),
+        )
+        .await;
+
+        for (accept, content_type) in [
+            ("text/html", "text/html; charset=utf-8"),
+            ("application/json", "application/json"),
+        ] {
+            let req = TestRequest::get()
+                .uri("/catalog")
+                .insert_header((ACCEPT, accept))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), content_type);
+            let vary: Vec<_> = response.headers().get_all(VARY).collect();
+            assert!(vary.iter().any(|v| *v == "Accept"), "{accept}: {vary:?}");
+
+            let req = TestRequest::get()
+                .uri("/catalog")
+                .insert_header((ACCEPT, accept))
+                .insert_header((IF_NONE_MATCH, response.headers().get(ETAG).unwrap()))
+                .to_request();
+            let response = call_service(&app, req).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert!(response.headers().get_all(VARY).any(|v| v == "Accept"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_quota_counts_all_tile_paths() {
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use tilejson::tilejson;
+
+        use crate::config::TenantState;
+        use crate::srv::rate_limit::{RateLimitConfig, API_KEY_HEADER_DEFAULT};
+
+        let source = |id| {
+            Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            })
+        };
+        let mut state = ServerState::default();
+        state.tiles.add_source(source("main"));
+        let mut tenant = TenantState::default();
+        tenant.state.tiles.add_source(source("acme"));
+        state.tenants.insert("acme".to_string(), tenant);
+
+        let srv_config = SrvConfig::default();
+        let catalog = Catalog::new(&state).unwrap();
+        let tenant_catalogs = BTreeMap::from([(
+            "acme".to_string(),
+            Catalog::new(&state.tenants["acme"].state).unwrap(),
+        )]);
+        let app_state = AppState::new(&srv_config, &state, &catalog, &tenant_catalogs);
+        let limits = RateLimitConfig {
+            monthly_quota: Some(3),
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .wrap(RateLimit(Some(Arc::new(RateLimiter::new(&limits)))))
+                .configure(|cfg| {
+                    app_state.register(cfg);
+                    app_state.tenant_router(cfg);
+                })
+                .configure(router),
+        )
+        .await;
+        let get = |path| {
+            TestRequest::get()
+                .uri(path)
+                .insert_header((API_KEY_HEADER_DEFAULT, "key"))
+                .to_request()
+        };
+
+        // the catalog is not a tile
+        let response = call_service(&app, get("/catalog")).await;
+        assert!(response.status().is_success());
+        for path in ["/main/0/0/0", "/v1/main/0/0/0", "/t/acme/acme/0/0/0"] {
+            let response = call_service(&app, get(path)).await;
+            assert!(response.status().is_success(), "{path}");
+        }
+        let response = call_service(&app, get("/t/acme/v1/acme/0/0/0")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_retry_discovery_uses_published_state() {
+        let initial_state = ServerState {
+            discovery_failures: vec!["missing.mbtiles".to_string()],
+            ..Default::default()
+        };
+        // e.g. a `/refresh` request discovered all the sources in the meantime
+        let (sender, _receiver) =
+            watch::channel(Some((SrvConfig::default(), ServerState::default())));
+        let retry = DiscoveryRetryConfig {
+            initial_delay: Some(3600),
+            max_delay: None,
+        };
+        let task = retry_discovery(
+            retry,
+            Args::default(),
+            OsEnv::default(),
+            initial_state,
+            sender,
+        );
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("nothing is left to retry");
+    }
+
+    #[test]
+    fn test_catalog_diff() {
+        use tilejson::tilejson;
+
+        let source = |id, name: &str| {
+            Box::new(TestSource {
+                id,
+                tj: tilejson! { tiles: vec![], name: name.to_string() },
+                data: TileData::new(),
+            })
+        };
+        let mut old = ServerState::default();
+        old.tiles.add_source(source("kept", "kept"));
+        old.tiles.add_source(source("removed", "removed"));
+        old.tiles.add_source(source("changed", "before"));
+        let mut new = ServerState::default();
+        new.tiles.add_source(source("kept", "kept"));
+        new.tiles.add_source(source("changed", "after"));
+        new.tiles.add_source(source("added", "added"));
+
+        let diff = CatalogDiff::new(&old, &new);
+        assert_eq!(diff.added, ["added"]);
+        assert_eq!(diff.removed, ["removed"]);
+        assert_eq!(diff.changed, ["changed"]);
+        assert_eq!(
+            diff.to_string(),
+            "added added; removed removed; changed changed"
+        );
+        assert_eq!(
+            diff.source_ids().into_iter().collect::<Vec<_>>(),
+            ["added", "changed", "removed"]
+        );
+        assert!(CatalogDiff::new(&new, &new).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_limit_problem() {
+        use actix_web::http::header::CONTENT_TYPE;
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+        use tilejson::tilejson;
+
+        use crate::srv::PROBLEM_JSON;
+
+        let mut state = ServerState::default();
+        state.tiles.add_source(Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(&[1, 2, 3]),
+        }));
+        let srv_config: SrvConfig =
+            serde_yaml::from_str("source_options: { src: { limits: { max_size: 2 } } }").unwrap();
+        let catalog = Catalog::new(&state).unwrap();
+        let tenant_catalogs = BTreeMap::new();
+        let app_state = AppState::new(&srv_config, &state, &catalog, &tenant_catalogs);
+        let app = init_service(
+            App::new()
+                .wrap(problem_json(srv_config.error_detail.unwrap_or_default()))
+                .configure(|cfg| app_state.register(cfg))
+                .configure(router),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/src/0/0/0").to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let problem: serde_json::Value = read_body_json(response).await;
+        assert_eq!(problem["status"], 422);
+        assert_eq!(problem["source_id"], "src");
+        assert_eq!(problem["tile"], serde_json::json!({"z": 0, "x": 0, "y": 0}));
+    }
+
+    #[actix_rt::test]
+    async fn test_bind_port_0() {
+        let config = SrvConfig {
+            listen_addresses: Some("127.0.0.1:0".to_string()),
+            worker_processes: Some(1),
+            ..SrvConfig::default()
+        };
+        let (_, addresses) = new_server(
+            OsEnv::default(),
+            Args::default(),
+            config,
+            ServerState::default(),
+        )
+        .unwrap();
+        let address: std::net::SocketAddr = addresses.parse().unwrap();
+        assert_eq!(address.ip().to_string(), "127.0.0.1");
+        assert_ne!(address.port(), 0);
+    }
 }
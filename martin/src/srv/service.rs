@@ -0,0 +1,260 @@
+use std::fmt::Display;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::config::ServerState;
+use crate::source::TileSources;
+use crate::srv::tiles::parse_header;
+use crate::srv::{merge_tilejson, Catalog, DynTileSource, SrvConfig};
+use crate::utils::OptMainCache;
+use crate::{MartinResult, TileCoord};
+
+/// A request to [`TileService::handle`], independent of the HTTP framework that received it.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceRequest {
+    /// Request path relative to where the service is mounted, e.g. `/my_source/0/0/0`
+    pub path: String,
+    /// Query string without the leading `?`
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ServiceRequest {
+    /// The value of the first header with the given name, ignoring the case of the name
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// A response of [`TileService::handle`], also used by the actix handlers sharing the same logic.
+/// Header names are lowercase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl ServiceResponse {
+    #[must_use]
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    /// A `200 OK` response with the value serialized as JSON
+    #[must_use]
+    pub fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::new(200)
+                .with_header("content-type", "application/json")
+                .with_body(body),
+            Err(e) => Self::text(500, e.to_string()),
+        }
+    }
+
+    #[must_use]
+    pub fn text(status: u16, body: impl Into<Bytes>) -> Self {
+        Self::new(status)
+            .with_header("content-type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Set a header, replacing the headers with the same name
+    #[must_use]
+    pub fn with_header(mut self, name: impl AsRef<str>, value: impl Display) -> Self {
+        let name = name.as_ref();
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.append_header(name, value)
+    }
+
+    /// Add a header, keeping the headers with the same name
+    #[must_use]
+    pub fn append_header(mut self, name: impl AsRef<str>, value: impl Display) -> Self {
+        self.headers
+            .push((name.as_ref().to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    #[must_use]
+    pub fn with_body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The value of the first header with the given name, ignoring the case of the name
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// Martin's read-only endpoints - `/health`, `/catalog`, `/{source_ids}`, and
+/// `/{source_ids}/{z}/{x}/{y}` - without a web server, so that they can be served by
+/// any HTTP framework. See [`crate::srv::MartinApp::service`].
+/// The tiles are built by [`DynTileSource::get_tile_response`], just like for the actix handlers.
+#[derive(Clone)]
+pub struct TileService(Arc<ServiceState>);
+
+struct ServiceState {
+    tiles: TileSources,
+    cache: OptMainCache,
+    catalog: Catalog,
+    config: SrvConfig,
+}
+
+/// Errors are responses too, which allows the `?` operator for any error convertible to a response
+type ServiceResult = Result<ServiceResponse, ServiceResponse>;
+
+impl TileService {
+    pub fn new(state: ServerState, config: SrvConfig) -> MartinResult<Self> {
+        let catalog = Catalog::new(&state)?.without_hidden(&config);
+        Ok(Self(Arc::new(ServiceState {
+            tiles: state.tiles,
+            cache: state.cache,
+            catalog,
+            config,
+        })))
+    }
+
+    /// Handle a `GET` request. `HEAD` requests are handled the same way, but the body must not be sent.
+    pub async fn handle(&self, req: &ServiceRequest) -> ServiceResponse {
+        let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+        let response = match segments.as_slice() {
+            ["health"] => {
+                Ok(ServiceResponse::text(200, "OK").with_header("cache-control", "no-cache"))
+            }
+            ["catalog"] => Ok(ServiceResponse::json(&self.0.catalog)),
+            [source_ids] if !source_ids.is_empty() => self.get_tilejson(source_ids, req),
+            [source_ids, z, x, y] => match (z.parse(), x.parse(), y.parse()) {
+                (Ok(z), Ok(x), Ok(y)) => {
+                    self.get_tile(source_ids, TileCoord { z, x, y }, req).await
+                }
+                _ => Err(ServiceResponse::text(404, "Invalid tile coordinates")),
+            },
+            _ => Err(ServiceResponse::text(404, "Not found")),
+        };
+        response.unwrap_or_else(|e| e)
+    }
+
+    fn get_tilejson(&self, source_ids: &str, req: &ServiceRequest) -> ServiceResult {
+        let sources = self.0.tiles.get_sources(source_ids, None)?.0;
+        let tiles_path = match &self.0.config.base_path {
+            Some(base_path) => format!("{base_path}/{source_ids}"),
+            None => req.path.trim_end_matches('/').to_string(),
+        };
+        let mut tiles_url = match req.header("host") {
+            Some(host) => {
+                let scheme = req.header("x-forwarded-proto").unwrap_or("http");
+                format!("{scheme}://{host}{tiles_path}/{{z}}/{{x}}/{{y}}")
+            }
+            None => format!("{tiles_path}/{{z}}/{{x}}/{{y}}"),
+        };
+        if !req.query.is_empty() {
+            tiles_url = format!("{tiles_url}?{}", req.query);
+        }
+        Ok(ServiceResponse::json(&merge_tilejson(&sources, tiles_url)))
+    }
+
+    async fn get_tile(
+        &self,
+        source_ids: &str,
+        xyz: TileCoord,
+        req: &ServiceRequest,
+    ) -> ServiceResult {
+        let src = DynTileSource::new(
+            &self.0.tiles,
+            source_ids,
+            Some(xyz.z),
+            &req.query,
+            req.header("accept-encoding").and_then(parse_header),
+            Some(&self.0.config),
+            self.0.cache.as_ref(),
+        )?;
+        Ok(src
+            .get_tile_response(
+                xyz,
+                req.header("if-none-match"),
+                req.header("if-modified-since"),
+            )
+            .await?)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::srv::MartinApp;
+    use crate::TileData;
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> ServiceRequest {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        ServiceRequest {
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                .collect(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_service() {
+        let service = MartinApp::new()
+            .add_source(Box::new(TestSource {
+                id: "custom",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }))
+            .service()
+            .unwrap();
+
+        let response = service.handle(&get("/custom/0/0/0", &[])).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, vec![1_u8, 2, 3]);
+        let etag = response.header("etag").unwrap().to_string();
+
+        let response = service
+            .handle(&get("/custom/0/0/0", &[("If-None-Match", &etag)]))
+            .await;
+        assert_eq!(response.status, 304);
+
+        let response = service
+            .handle(&get("/custom?key=1", &[("Host", "example.org")]))
+            .await;
+        assert_eq!(response.status, 200);
+        let tj: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(
+            tj["tiles"][0],
+            "http://example.org/custom/{z}/{x}/{y}?key=1"
+        );
+
+        let response = service.handle(&get("/catalog", &[])).await;
+        let catalog: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert!(catalog["tiles"]["custom"].is_object());
+
+        assert_eq!(
+            service.handle(&get("/missing/0/0/0", &[])).await.status,
+            404
+        );
+        assert_eq!(service.handle(&get("/custom/0/0/y", &[])).await.status, 404);
+        assert_eq!(service.handle(&get("/", &[])).await.status, 404);
+    }
+}
@@ -3,24 +3,27 @@ use std::string::ToString;
 use actix_web::error::ErrorNotFound;
 use actix_web::http::header::ContentType;
 use actix_web::web::{Data, Path};
-use actix_web::{middleware, route, HttpResponse, Result as ActixResult};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
 use spreet::Spritesheet;
 use tokio::sync::RwLock;
 
 use crate::sprites::{SpriteError, SpriteSources};
+use crate::srv::range::ranged_response;
 use crate::srv::server::map_internal_error;
 use crate::srv::SourceIDsRequest;
 
 #[route("/sprite/{source_ids}.png", method = "GET", method = "HEAD")]
 async fn get_sprite_png(
+    req: HttpRequest,
     path: Path<SourceIDsRequest>,
     sprites: Data<RwLock<SpriteSources>>,
 ) -> ActixResult<HttpResponse> {
     let sprites_guard = sprites.read().await;
     let sheet = get_sprite(&path, &sprites_guard).await?;
-    Ok(HttpResponse::Ok()
-        .content_type(ContentType::png())
-        .body(sheet.encode_png().map_err(map_internal_error)?))
+    let png = sheet.encode_png().map_err(map_internal_error)?;
+    let mut response = HttpResponse::Ok();
+    response.content_type(ContentType::png());
+    Ok(ranged_response(&req, response, png.into()))
 }
 
 #[route(
@@ -0,0 +1,140 @@
+//! Minimal `MapLibre` styles for the tile sources, as a starting point for styling.
+//! Each layer of a vector source gets its own color, and is drawn as polygons, lines, and points
+//! depending on the geometry type of the features.
+
+use actix_web::web::{Data, Path};
+use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::Format;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+use crate::source::{Source, TileSources};
+use crate::srv::tiles_info::public_base_url;
+use crate::srv::{merge_tilejson, SourceIDsRequest, SrvConfig};
+
+/// Colors assigned to the layers in order, repeated if there are more layers
+const COLORS: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+    "#800000", "#469990", "#808000", "#000075",
+];
+
+#[route(
+    "/style/{source_ids}",
+    method = "GET",
+    method = "HEAD",
+    wrap = "middleware::Compress::default()"
+)]
+#[allow(clippy::unused_async)]
+async fn get_style(
+    req: HttpRequest,
+    path: Path<SourceIDsRequest>,
+    sources: Data<RwLock<TileSources>>,
+    srv_config: Data<RwLock<SrvConfig>>,
+) -> ActixResult<HttpResponse> {
+    let sources = sources.read().await;
+    let sources = sources.get_sources(&path.source_ids, None)?.0;
+    let base_path = srv_config.read().await.base_path.clone();
+    let route_path = format!("/style/{}", path.source_ids);
+    let base_url = public_base_url(&req, base_path.as_deref(), &route_path);
+    let mut tilejson_url = format!("{base_url}/{}", path.source_ids);
+    if !req.query_string().is_empty() {
+        tilejson_url = format!("{tilejson_url}?{}", req.query_string());
+    }
+
+    Ok(HttpResponse::Ok().json(generate_style(&path.source_ids, &sources, &tilejson_url)))
+}
+
+/// Generate a style showing all layers of the sources, which are served as a single (composite) source
+#[must_use]
+pub fn generate_style(source_ids: &str, sources: &[&dyn Source], tilejson_url: &str) -> Value {
+    let is_vector = sources
+        .first()
+        .is_some_and(|s| s.get_tile_info().format == Format::Mvt);
+    let mut layers = vec![json!({
+        "id": "background",
+        "type": "background",
+        "paint": {"background-color": "#f8f4f0"}
+    })];
+
+    let source = if is_vector {
+        let tilejson = merge_tilejson(sources, String::new());
+        for (idx, layer) in tilejson.vector_layers.iter().flatten().enumerate() {
+            let color = COLORS[idx % COLORS.len()];
+            let id = &layer.id;
+            layers.push(json!({
+                "id": format!("{id}-fill"),
+                "type": "fill",
+                "source": source_ids,
+                "source-layer": id,
+                "filter": ["==", ["geometry-type"], "Polygon"],
+                "paint": {"fill-color": color, "fill-opacity": 0.4, "fill-outline-color": color}
+            }));
+            layers.push(json!({
+                "id": format!("{id}-line"),
+                "type": "line",
+                "source": source_ids,
+                "source-layer": id,
+                "filter": ["==", ["geometry-type"], "LineString"],
+                "paint": {"line-color": color, "line-width": 1.5}
+            }));
+            layers.push(json!({
+                "id": format!("{id}-circle"),
+                "type": "circle",
+                "source": source_ids,
+                "source-layer": id,
+                "filter": ["==", ["geometry-type"], "Point"],
+                "paint": {
+                    "circle-color": color,
+                    "circle-radius": 4,
+                    "circle-stroke-color": "#ffffff",
+                    "circle-stroke-width": 1
+                }
+            }));
+        }
+        json!({"type": "vector", "url": tilejson_url})
+    } else {
+        layers.push(json!({"id": source_ids, "type": "raster", "source": source_ids}));
+        json!({"type": "raster", "url": tilejson_url, "tileSize": 256})
+    };
+
+    json!({
+        "version": 8,
+        "name": source_ids,
+        "sources": {source_ids: source},
+        "layers": layers
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug::{DebugConfig, DebugFormat, DebugSource};
+
+    use super::*;
+
+    #[test]
+    fn test_generate_style() {
+        let src = DebugSource::new("dbg".to_string(), &DebugConfig::default());
+        let style = generate_style("dbg", &[&src], "http://localhost:3000/dbg");
+        assert_eq!(
+            style["sources"],
+            json!({"dbg": {"type": "vector", "url": "http://localhost:3000/dbg"}})
+        );
+        let layers = style["layers"].as_array().unwrap();
+        let ids: Vec<_> = layers.iter().map(|l| l["id"].as_str().unwrap()).collect();
+        assert_eq!(
+            ids,
+            vec!["background", "debug-fill", "debug-line", "debug-circle"]
+        );
+        assert_eq!(layers[1]["source-layer"], "debug");
+        assert_eq!(layers[1]["paint"]["fill-color"], COLORS[0]);
+
+        let cfg = DebugConfig {
+            format: Some(DebugFormat::Png),
+            ..Default::default()
+        };
+        let src = DebugSource::new("png".to_string(), &cfg);
+        let style = generate_style("png", &[&src], "http://localhost:3000/png");
+        assert_eq!(style["sources"]["png"]["type"], "raster");
+        assert_eq!(style["layers"][1]["type"], "raster");
+    }
+}
@@ -0,0 +1,44 @@
+//! Integration with systemd: socket activation and readiness notification.
+
+use std::io;
+use std::net::TcpListener;
+
+use listenfd::ListenFd;
+use log::{debug, info, warn};
+use sd_notify::NotifyState;
+
+/// Take the TCP sockets passed by systemd socket activation, if any.
+/// When systemd owns the sockets, they stay open while Martin restarts, so no connections are refused.
+pub(crate) fn take_listeners() -> io::Result<Vec<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+    let mut listeners = Vec::with_capacity(fds.len());
+    for idx in 0..fds.len() {
+        if let Some(listener) = fds.take_tcp_listener(idx)? {
+            listeners.push(listener);
+        }
+    }
+    if !listeners.is_empty() {
+        info!("Using {} socket(s) passed by systemd", listeners.len());
+    }
+    Ok(listeners)
+}
+
+/// Tell systemd that Martin has resolved its sources and is ready to serve requests.
+/// Does nothing if Martin is not started by systemd with `Type=notify`.
+pub fn notify_ready() {
+    match sd_notify::notify(true, &[NotifyState::Ready]) {
+        Ok(()) => debug!("Notified systemd that Martin is ready"),
+        Err(e) => warn!("Unable to notify systemd that Martin is ready: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_listeners() {
+        // tests are not started with socket activation
+        assert!(take_listeners().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use actix_web::error::{ErrorBadRequest, ErrorNotFound};
+use actix_web::http::header::ContentType;
+use actix_web::middleware::Compress;
+use actix_web::web::{Data, Path};
+use actix_web::{route, HttpRequest, HttpResponse, Result as ActixResult};
+use martin_tile_utils::Format;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::raster::RasterError::DecodeError;
+use crate::raster::{layer_json, quantized_mesh};
+use crate::source::TileSources;
+use crate::srv::rate_limit::ServedTiles;
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::{decode, with_etag};
+use crate::srv::{DynTileSource, SrvConfig};
+use crate::utils::OptMainCache;
+use crate::{MartinResult, TileCoord};
+
+const QUANTIZED_MESH_CONTENT_TYPE: &str = "application/vnd.quantized-mesh";
+
+#[derive(Deserialize)]
+pub struct TerrainLayerRequest {
+    source_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct TerrainRequest {
+    source_id: String,
+    z: u8,
+    x: u32,
+    y: u32,
+}
+
+/// The `layer.json` of a raster source with elevations encoded as colors, for Cesium's `CesiumTerrainProvider`
+#[route(
+    "/{source_id}/layer.json",
+    method = "GET",
+    method = "HEAD",
+    wrap = "Compress::default()"
+)]
+async fn get_terrain_layer(
+    path: Path<TerrainLayerRequest>,
+    srv_config: Data<RwLock<SrvConfig>>,
+    sources: Data<RwLock<TileSources>>,
+) -> ActixResult<HttpResponse> {
+    if srv_config.read().await.terrain.is_none() {
+        return Err(ErrorNotFound("Terrain is not enabled"));
+    }
+    let sources_guard = sources.read().await;
+    let source = sources_guard.get_source(&path.source_id)?;
+    check_dem_format(&path.source_id, source.get_tile_info().format)?;
+    Ok(HttpResponse::Ok().json(layer_json(&path.source_id, source.get_tilejson())))
+}
+
+/// A quantized-mesh terrain tile generated from the tile of a raster source with elevations encoded as colors.
+/// The `y` coordinate is in the TMS scheme, as requested by Cesium. Tiles outside of the zoom range
+/// of the source are flat. Must be registered before the tile route, which would match the same paths.
+#[route(
+    "/{source_id}/{z}/{x}/{y}.terrain",
+    method = "GET",
+    method = "HEAD",
+    wrap = "Compress::default()"
+)]
+async fn get_terrain_tile(
+    req: HttpRequest,
+    path: Path<TerrainRequest>,
+    srv_config: Data<RwLock<SrvConfig>>,
+    sources: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+) -> ActixResult<HttpResponse> {
+    let srv_config_guard = srv_config.read().await;
+    let Some(terrain) = &srv_config_guard.terrain else {
+        return Err(ErrorNotFound("Terrain is not enabled"));
+    };
+    let rows = 1_u32.checked_shl(path.z.into()).unwrap_or_default();
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path
+            .y
+            .checked_add(1)
+            .and_then(|y| rows.checked_sub(y))
+            .unwrap_or(u32::MAX),
+    };
+    if !xyz.is_valid() {
+        return Err(ErrorNotFound(format!("Tile {xyz:#} does not exist")));
+    }
+
+    let sources_guard = sources.read().await;
+    let source = sources_guard.get_source(&path.source_id)?;
+    check_dem_format(&path.source_id, source.get_tile_info().format)?;
+    let dem = if source.is_valid_zoom(xyz.z) {
+        let cache_guard = cache.read().await;
+        let src = DynTileSource::new(
+            &sources_guard,
+            &path.source_id,
+            Some(xyz.z),
+            req.query_string(),
+            None,
+            Some(&srv_config_guard),
+            cache_guard.as_ref(),
+        )?;
+        Some(decode(src.get_tile_content(xyz).await?)?.data)
+    } else {
+        None
+    };
+
+    let encoding = terrain.encoding(&path.source_id);
+    let grid_size = terrain.grid_size();
+    let source_id = path.source_id.clone();
+    // decoding the elevations and building the mesh takes a while, so it must not block the server
+    let data = tokio::task::spawn_blocking(move || {
+        let dem = match dem {
+            Some(data) if !data.is_empty() => Some(
+                image::load_from_memory(&data)
+                    .map_err(|e| DecodeError(e, xyz, source_id))?
+                    .to_rgba8(),
+            ),
+            _ => None,
+        };
+        MartinResult::Ok(quantized_mesh(dem.as_ref(), encoding, xyz, grid_size))
+    })
+    .await
+    .map_err(map_internal_error)?
+    .map_err(map_internal_error)?;
+
+    let content_type = QUANTIZED_MESH_CONTENT_TYPE
+        .parse()
+        .map_err(map_internal_error)?;
+    let mut response = with_etag(&req, ContentType(content_type), data);
+    response
+        .extensions_mut()
+        .insert(ServedTiles::new(1, &path.source_id));
+    Ok(response)
+}
+
+/// Elevations must be stored losslessly, so only PNG and WebP sources can be used
+fn check_dem_format(source_id: &str, format: Format) -> ActixResult<()> {
+    if matches!(format, Format::Png | Format::Webp) {
+        Ok(())
+    } else {
+        Err(ErrorBadRequest(format!(
+            "Only PNG and WebP sources can be used as terrain, but {source_id} has {format} tiles"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::debug::{DebugConfig, DebugFormat, DebugSource};
+    use crate::raster::TerrainConfig;
+
+    async fn get(terrain: Option<TerrainConfig>, path: &str) -> actix_web::dev::ServiceResponse {
+        let mut sources = TileSources::default();
+        sources.add_source(Box::new(DebugSource::new(
+            "dbg".to_string(),
+            &DebugConfig::default(),
+        )));
+        let png = DebugConfig {
+            format: Some(DebugFormat::Png),
+            minzoom: Some(2),
+            maxzoom: Some(4),
+        };
+        sources.add_source(Box::new(DebugSource::new("dem".to_string(), &png)));
+        let srv_config = SrvConfig {
+            terrain,
+            ..SrvConfig::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(RwLock::new(sources)))
+                .app_data(Data::new(RwLock::new(srv_config)))
+                .app_data(Data::new(RwLock::new(OptMainCache::None)))
+                .service(get_terrain_layer)
+                .service(get_terrain_tile),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri(path).to_request()).await
+    }
+
+    #[actix_rt::test]
+    async fn test_terrain() {
+        let terrain = Some(TerrainConfig::default());
+        let response = get(terrain.clone(), "/dem/layer.json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let layer: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(layer["format"], "quantized-mesh-1.0");
+        assert_eq!(layer["tiles"][0], "{z}/{x}/{y}.terrain");
+        assert_eq!(layer["maxzoom"], 4);
+
+        // tile 3/2/5 in the TMS scheme
+        let response = get(terrain.clone(), "/dem/3/2/2.terrain").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            QUANTIZED_MESH_CONTENT_TYPE
+        );
+        let data = read_body(response).await;
+        let vertices = u32::from_le_bytes(data[88..92].try_into().unwrap());
+        assert_eq!(vertices, 65 * 65);
+
+        // a flat tile outside of the zoom range of the source
+        let response = get(terrain.clone(), "/dem/0/0/0.terrain").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(terrain.clone(), "/dem/3/2/8.terrain").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(terrain.clone(), "/dbg/3/2/2.terrain").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = get(terrain, "/missing/layer.json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(None, "/dem/layer.json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
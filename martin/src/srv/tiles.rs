@@ -1,34 +1,111 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::hash::BuildHasher as _;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use actix_http::header::Quality;
 use actix_http::ContentEncoding;
-use actix_web::error::{ErrorBadRequest, ErrorNotAcceptable, ErrorNotFound};
+use actix_web::error::{
+    ErrorBadRequest, ErrorNotAcceptable, ErrorNotFound, ErrorUnprocessableEntity, InternalError,
+};
 use actix_web::http::header::{
-    AcceptEncoding, Encoding as HeaderEnc, Preference, CONTENT_ENCODING,
+    Accept, AcceptEncoding, ContentType, ETag, Encoding as HeaderEnc, EntityTag, Header,
+    HeaderName, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch, LastModified, Preference,
+    CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    RETRY_AFTER, VARY, WARNING,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{Bytes, Data, Json, Path, Query};
+use actix_web::{
+    route, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Result as ActixResult,
 };
-use actix_web::web::{Data, Path, Query};
-use actix_web::{route, HttpMessage, HttpRequest, HttpResponse, Result as ActixResult};
 use futures::future::try_join_all;
-use log::trace;
+use futures::{stream, FutureExt as _, StreamExt as _, TryStreamExt as _};
+use itertools::Itertools as _;
+use log::{debug, warn};
 use martin_tile_utils::{Encoding, Format, TileInfo};
 use serde::Deserialize;
-use tokio::sync::RwLock;
+use tokio::sync::{OnceCell, RwLock};
+use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
 
 use crate::args::PreferredEncoding;
+#[cfg(feature = "raster")]
+use crate::raster::RasterFormat;
 use crate::source::{Source, TileSources, UrlQuery};
+use crate::srv::rate_limit::{reserve_tiles, ServedTiles};
 use crate::srv::server::map_internal_error;
-use crate::srv::SrvConfig;
-use crate::utils::cache::get_or_insert_cached_value;
+use crate::srv::{
+    record_source_health, source_surrogate_key, LimitPolicy, Problem, ServiceResponse,
+    SourceIDsRequest, SourceOptions, SrvConfig, StoredEncodingPolicy, PROBLEM_JSON, X_ROBOTS_TAG,
+};
+use crate::utils::cache::{get_or_insert_limited_tile, get_or_insert_tile, TileCachePolicy};
 use crate::utils::{
-    decode_brotli, decode_gzip, encode_brotli, encode_gzip, CacheKey, CacheValue, MainCache,
-    OptMainCache,
+    count_mvt_features, decode_brotli, decode_gzip, decode_mvt, decode_zstd, encode_brotli,
+    encode_gzip, encode_zstd, filter_mvt_layers, log_if_slow, mvt_to_geojson, record_source_result,
+    retry_after_value, truncate_mvt, CacheKey, MainCache, OptMainCache,
+};
+use crate::MartinError::{
+    InternalError as MartinInternalError, SourceTimeout, SourceUnavailable, TileLimitExceeded,
 };
-use crate::{Tile, TileCoord, TileData};
+use crate::{MartinError, MartinResult, Tile, TileCoord, TileData};
 
 static SUPPORTED_ENC: &[HeaderEnc] = &[
     HeaderEnc::gzip(),
     HeaderEnc::brotli(),
+    HeaderEnc::zstd(),
     HeaderEnc::identity(),
 ];
 
+/// Warnings about the content of a tile, sent to the client as `Warning` headers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TileWarnings {
+    /// Any of the tiles is an expired copy from the cache, served because the source failed
+    is_stale: bool,
+    /// Any of the tiles exceeded the limits of its source, and was truncated
+    is_truncated: bool,
+}
+
+impl TileWarnings {
+    fn headers(self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.is_stale, STALE_WARNING),
+            (self.is_truncated, TRUNCATED_WARNING),
+        ]
+        .into_iter()
+        .filter_map(|(is_set, warning)| is_set.then_some(warning))
+    }
+}
+
+/// Maximum number of tiles that can be requested at once with `POST /{source_ids}/tiles`
+pub const MAX_BATCH_TILES: usize = 1000;
+
+/// Number of tiles of a batch request that are fetched from the sources at the same time
+const BATCH_CONCURRENCY: usize = 8;
+
+/// A part of a batch response: the tile, an empty part if there is no tile, or the error of the tile
+type BatchPart = Result<Option<(Tile, TileWarnings)>, Problem>;
+
+/// Content type of vector tiles registered with IANA, accepted in addition to the one Martin sends
+const MVT_TYPE: &str = "application/vnd.mapbox-vector-tile";
+
+/// Content type of vector tiles converted to `GeoJSON`
+const GEOJSON_TYPE: &str = "application/geo+json";
+
+/// `Warning` header value for expired tiles served from cache, see RFC 7234
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// `Warning` header value for tiles exceeding the limits of their source, see RFC 7234
+const TRUNCATED_WARNING: &str = "199 - \"Tile truncated to the limits of the source\"";
+
+/// If the client accepts several encodings equally, and none of them is the preferred one,
+/// pick the one that usually produces the smallest tiles.
+static COMPRESSION_ORDER: &[ContentEncoding] = &[
+    ContentEncoding::Brotli,
+    ContentEncoding::Zstd,
+    ContentEncoding::Gzip,
+];
+
 #[derive(Deserialize, Clone)]
 pub struct TileRequest {
     source_ids: String,
@@ -45,6 +122,15 @@ async fn get_tile(
     sources: Data<RwLock<TileSources>>,
     cache: Data<RwLock<OptMainCache>>,
 ) -> ActixResult<HttpResponse> {
+    let xyz = TileCoord {
+        z: path.z,
+        x: path.x,
+        y: path.y,
+    };
+    if !xyz.is_valid() {
+        return Err(ErrorNotFound(format!("Tile {xyz:#} does not exist")));
+    }
+
     let sources_guard = sources.read().await;
     let srv_config_guard = srv_config.read().await;
     let cache_guard = cache.read().await;
@@ -55,16 +141,347 @@ async fn get_tile(
         Some(path.z),
         req.query_string(),
         req.get_header::<AcceptEncoding>(),
-        srv_config_guard.preferred_encoding,
+        Some(&srv_config_guard),
         cache_guard.as_ref(),
     )?;
 
-    src.get_http_response(TileCoord {
+    let format = src.info.format;
+    #[cfg(feature = "raster")]
+    let can_render = srv_config_guard.render.is_some();
+    #[cfg(not(feature = "raster"))]
+    let can_render = false;
+    let accept = req.get_header::<Accept>();
+    let mut response = match negotiate(accept.as_ref(), format, can_render) {
+        TileRepresentation::Native => src.get_http_response(xyz, &req).await?,
+        TileRepresentation::GeoJson => {
+            let tile = decode(src.get_tile_content(xyz).await?)?;
+            let layers = decode_mvt(&tile.data).map_err(map_internal_error)?;
+            let body = serde_json::to_vec(&mvt_to_geojson(&layers, xyz))?;
+            let content_type = GEOJSON_TYPE.parse().map_err(map_internal_error)?;
+            with_etag(&req, ContentType(content_type), body)
+        }
+        #[cfg(feature = "raster")]
+        TileRepresentation::Rendered(format) => {
+            drop(src);
+            crate::srv::render::render_response(
+                &req,
+                &srv_config_guard,
+                &sources_guard,
+                &cache_guard,
+                &path.source_ids,
+                xyz,
+                format,
+            )
+            .await?
+        }
+    };
+    if format == Format::Mvt {
+        // the same URL has other representations, so caches must key them by the header
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+    }
+    response
+        .extensions_mut()
+        .insert(ServedTiles::new(1, &path.source_ids));
+    Ok(response)
+}
+
+/// How a tile of an extension-less tile URL is sent, chosen with the `Accept` header of the request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TileRepresentation {
+    /// The tile as stored in the sources
+    Native,
+    /// A vector tile converted to `GeoJSON`, e.g. to inspect it in a browser
+    GeoJson,
+    /// A vector tile rendered as an image
+    #[cfg(feature = "raster")]
+    Rendered(RasterFormat),
+}
+
+/// Pick the first representation the client accepts, in the order of its preference.
+/// Only vector tiles can be converted. The tile is sent as is if the client accepts nothing
+/// that can be produced, as most tile clients do not send a meaningful `Accept` header.
+#[cfg_attr(not(feature = "raster"), allow(unused_variables))]
+fn negotiate(accept: Option<&Accept>, format: Format, can_render: bool) -> TileRepresentation {
+    let Some(accept) = accept else {
+        return TileRepresentation::Native;
+    };
+    let native = format.content_type().split_once('/');
+    // q=0 means "not acceptable"
+    let accepted = Accept(
+        accept
+            .iter()
+            .filter(|v| v.quality > Quality::ZERO)
+            .cloned()
+            .collect(),
+    );
+    for mime in accepted.ranked() {
+        let (type_, subtype) = (mime.type_().as_str(), mime.subtype().as_str());
+        let is_native = type_ == "*"
+            || native.is_some_and(|(t, s)| type_ == t && (subtype == "*" || subtype == s))
+            || (format == Format::Mvt && mime.essence_str() == MVT_TYPE);
+        if is_native {
+            return TileRepresentation::Native;
+        }
+        if format != Format::Mvt {
+            continue;
+        }
+        if mime.essence_str() == GEOJSON_TYPE {
+            return TileRepresentation::GeoJson;
+        }
+        #[cfg(feature = "raster")]
+        if can_render && type_ == "image" {
+            let format = match subtype {
+                "png" | "*" => Some(RasterFormat::Png),
+                "webp" => Some(RasterFormat::Webp),
+                "jpeg" => Some(RasterFormat::Jpeg),
+                _ => None,
+            };
+            if let Some(format) = format {
+                return TileRepresentation::Rendered(format);
+            }
+        }
+    }
+    TileRepresentation::Native
+}
+
+#[derive(Deserialize)]
+pub struct UtfGridRequest {
+    source_id: String,
+    z: u8,
+    x: u32,
+    y: u32,
+}
+
+/// `UTFGrid` interaction data of a single source, e.g. for hover effects in Leaflet.
+/// Must be registered before the tile route, which would match the same paths.
+#[route("/{source_id}/{z}/{x}/{y}.grid.json", method = "GET", method = "HEAD")]
+async fn get_utfgrid(
+    path: Path<UtfGridRequest>,
+    sources: Data<RwLock<TileSources>>,
+) -> ActixResult<HttpResponse> {
+    let sources_guard = sources.read().await;
+    let source = sources_guard.get_source(&path.source_id)?;
+    let xyz = TileCoord {
         z: path.z,
         x: path.x,
         y: path.y,
-    })
-    .await
+    };
+    if !source.is_valid_zoom(path.z) || !xyz.is_valid() {
+        return Err(ErrorNotFound("No valid sources found"));
+    }
+    let grid = source
+        .get_utfgrid(xyz)
+        .await
+        .map_err(map_tile_error)?
+        .ok_or_else(|| {
+            ErrorNotFound(format!(
+                "Source {} does not support UTFGrid",
+                path.source_id
+            ))
+        })?;
+    Ok(HttpResponse::Ok().json(grid))
+}
+
+/// Get many tiles of the same source(s) with a single request, returned as a `multipart/mixed` response.
+/// The request body is a JSON array of `z/x/y` strings. Each part of the response has
+/// the `Content-Location` header set to the `z/x/y` of its tile, in the same order as requested.
+/// Empty tiles, including those outside of the sources' zoom range, are returned as empty parts,
+/// and tiles that could not be read as `application/problem+json` parts, without failing the other tiles.
+#[route("/{source_ids}/tiles", method = "POST")]
+async fn get_tiles_batch(
+    req: HttpRequest,
+    srv_config: Data<RwLock<SrvConfig>>,
+    path: Path<SourceIDsRequest>,
+    body: Json<Vec<String>>,
+    sources: Data<RwLock<TileSources>>,
+    cache: Data<RwLock<OptMainCache>>,
+) -> ActixResult<HttpResponse> {
+    if body.len() > MAX_BATCH_TILES {
+        return Err(ErrorBadRequest(format!(
+            "At most {MAX_BATCH_TILES} tiles can be requested at once"
+        )));
+    }
+    let coords = body
+        .iter()
+        .map(|v| v.parse::<TileCoord>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ErrorBadRequest)?;
+    reserve_tiles(&req, coords.len())?;
+
+    let sources_guard = sources.read().await;
+    let srv_config_guard = srv_config.read().await;
+    let cache_guard = cache.read().await;
+    let new_source = |zoom| {
+        DynTileSource::new(
+            &sources_guard,
+            &path.source_ids,
+            zoom,
+            req.query_string(),
+            req.get_header::<AcceptEncoding>(),
+            Some(&srv_config_guard),
+            cache_guard.as_ref(),
+        )
+    };
+
+    // Sources are filtered by zoom, so each zoom level needs its own dynamic source
+    let src = new_source(None)?;
+    let mut zoom_sources = HashMap::new();
+    for zoom in coords.iter().map(|xyz| xyz.z).unique() {
+        let (zoom_srcs, ..) = sources_guard.get_sources(&path.source_ids, Some(zoom))?;
+        let zoom_src = if zoom_srcs.is_empty() {
+            None
+        } else {
+            Some(new_source(Some(zoom))?)
+        };
+        zoom_sources.insert(zoom, zoom_src);
+    }
+
+    let error_detail = srv_config_guard.error_detail.unwrap_or_default();
+    let zoom_sources = &zoom_sources;
+    let source_ids = &path.source_ids;
+    let tiles: Vec<BatchPart> = stream::iter(coords.iter().map(|&xyz| async move {
+        let Some(zoom_src) = &zoom_sources[&xyz.z] else {
+            return Ok(None);
+        };
+        zoom_src
+            .get_cached_tile_content(xyz)
+            .await
+            .map(Some)
+            .map_err(|e| {
+                let status = e.as_response_error().status_code();
+                warn!("Unable to get tile {xyz:#} of {source_ids} in a batch: {e}");
+                let detail = Some(e.to_string()).filter(|_| error_detail.includes(status));
+                Problem::for_tile(status, detail, source_ids, xyz)
+            })
+    }))
+    // a small number at a time, so that a batch does not take all the connections of the sources
+    .buffered(BATCH_CONCURRENCY)
+    .collect()
+    .await;
+
+    let (boundary, body) = multipart_tiles(&coords, &tiles);
+    let mut response = src
+        .response_builder(None, HttpResponse::Ok())
+        .content_type(format!("multipart/mixed; boundary={boundary}"))
+        .body(body);
+    response
+        .extensions_mut()
+        .insert(ServedTiles::new(coords.len(), &path.source_ids));
+    Ok(response)
+}
+
+/// Build a `multipart/mixed` body with one part per tile, see RFC 2046.
+/// The boundary is derived from the tile content, so it is very unlikely to appear inside of it.
+fn multipart_tiles(coords: &[TileCoord], tiles: &[BatchPart]) -> (String, Vec<u8>) {
+    let problems: Vec<_> = tiles
+        .iter()
+        .map(|part| match part {
+            Err(problem) => serde_json::to_vec(problem).unwrap_or_default(),
+            Ok(_) => Vec::new(),
+        })
+        .collect();
+    let parts: Vec<&[u8]> = tiles
+        .iter()
+        .zip(&problems)
+        .map(|(part, problem)| match part {
+            Ok(Some((tile, _))) => tile.data.as_ref(),
+            _ => problem.as_slice(),
+        })
+        .collect();
+    let boundary = multipart_boundary(&parts);
+
+    let mut body = Vec::new();
+    for ((xyz, tile), problem) in coords.iter().zip(tiles).zip(&problems) {
+        let mut headers = format!("--{boundary}\r\nContent-Location: {xyz:#}\r\n");
+        let data = match tile {
+            Err(_) => {
+                let _ = write!(headers, "Content-Type: {PROBLEM_JSON}\r\n");
+                problem.as_slice()
+            }
+            Ok(Some((tile, warnings))) if !tile.data.is_empty() => {
+                let _ = write!(
+                    headers,
+                    "Content-Type: {}\r\n",
+                    tile.info.format.content_type()
+                );
+                if let Some(val) = tile.info.encoding.content_encoding() {
+                    let _ = write!(headers, "Content-Encoding: {val}\r\n");
+                }
+                for warning in warnings.headers() {
+                    let _ = write!(headers, "Warning: {warning}\r\n");
+                }
+                tile.data.as_ref()
+            }
+            _ => &[],
+        };
+        let _ = write!(headers, "Content-Length: {}\r\n\r\n", data.len());
+        body.extend_from_slice(headers.as_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    (boundary, body)
+}
+
+/// A random boundary, so that it cannot be guessed by whoever controls the tile data,
+/// which is checked not to appear in any of the parts
+fn multipart_boundary(parts: &[&[u8]]) -> String {
+    let random = RandomState::new();
+    let mut idx = 0_u64;
+    loop {
+        let boundary = format!("martin-{:016x}", random.hash_one(idx));
+        let delimiter = format!("--{boundary}");
+        let delimiter = delimiter.as_bytes();
+        let is_unique = !parts
+            .iter()
+            .any(|data| data.windows(delimiter.len()).any(|v| v == delimiter));
+        if is_unique {
+            return boundary;
+        }
+        idx += 1;
+    }
+}
+
+/// With `strict_query_params`, reject query parameters not declared in `query_params`
+/// of any requested source, so they cannot be used to bypass the tile caches.
+fn check_query_params(
+    srv_config: &SrvConfig,
+    sources: &[&dyn Source],
+    query: &str,
+) -> ActixResult<()> {
+    if !srv_config.strict_query_params.unwrap_or_default() || query.is_empty() {
+        return Ok(());
+    }
+    let params = Query::<UrlQuery>::from_query(query)?.into_inner();
+    let allowed = |name: &str| {
+        name == "layers"
+            || sources.iter().any(|src| {
+                srv_config
+                    .get_source_options(src.get_id())
+                    .and_then(|opts| opts.query_params.as_ref())
+                    .is_some_and(|v| v.iter().any(|p| p == name))
+            })
+    };
+    let mut rejected: Vec<&str> = params
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !allowed(name))
+        .collect();
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    rejected.sort_unstable();
+    Err(ErrorBadRequest(format!(
+        "Query parameters {} are not declared for this source, see query_params of the source options",
+        rejected.join(", ")
+    )))
+}
+
+#[derive(Deserialize)]
+struct LayersQuery {
+    layers: Option<String>,
 }
 
 pub struct DynTileSource<'a> {
@@ -72,9 +489,15 @@ pub struct DynTileSource<'a> {
     pub info: TileInfo,
     pub query_str: Option<&'a str>,
     pub query_obj: Option<UrlQuery>,
+    /// Only keep these layers of the vector tiles, set with the `layers` query parameter
+    pub layers: Option<BTreeSet<String>>,
     pub accept_enc: Option<AcceptEncoding>,
-    pub preferred_enc: Option<PreferredEncoding>,
+    pub srv_config: Option<&'a SrvConfig>,
     pub cache: Option<&'a MainCache>,
+    /// Data versions of the sources, queried once per request when first needed
+    data_versions: OnceCell<Vec<Option<String>>>,
+    /// Modification time of the sources, queried once per request when first needed
+    last_modified: OnceCell<Option<SystemTime>>,
 }
 
 impl<'a> DynTileSource<'a> {
@@ -84,7 +507,7 @@ impl<'a> DynTileSource<'a> {
         zoom: Option<u8>,
         query: &'a str,
         accept_enc: Option<AcceptEncoding>,
-        preferred_enc: Option<PreferredEncoding>,
+        srv_config: Option<&'a SrvConfig>,
         cache: Option<&'a MainCache>,
     ) -> ActixResult<Self> {
         let (sources, use_url_query, info) = sources.get_sources(source_ids, zoom)?;
@@ -92,6 +515,9 @@ impl<'a> DynTileSource<'a> {
         if sources.is_empty() {
             return Err(ErrorNotFound("No valid sources found"));
         }
+        if let Some(srv_config) = srv_config {
+            check_query_params(srv_config, &sources, query)?;
+        }
 
         let mut query_obj = None;
         let mut query_str = None;
@@ -100,50 +526,370 @@ impl<'a> DynTileSource<'a> {
             query_str = Some(query);
         }
 
+        let layers = Query::<LayersQuery>::from_query(query)?
+            .into_inner()
+            .layers
+            .map(|v| {
+                v.split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(ToString::to_string)
+                    .collect::<BTreeSet<_>>()
+            })
+            .filter(|v| !v.is_empty());
+        if layers.is_some() && info.format != Format::Mvt {
+            return Err(ErrorBadRequest(format!(
+                "Layers can only be selected for vector tiles, not {info}"
+            )));
+        }
+
         Ok(Self {
             sources,
             info,
             query_str,
             query_obj,
+            layers,
             accept_enc,
-            preferred_enc,
+            srv_config,
             cache,
+            data_versions: OnceCell::new(),
+            last_modified: OnceCell::new(),
         })
     }
 
-    pub async fn get_http_response(&self, xyz: TileCoord) -> ActixResult<HttpResponse> {
-        let tile = self.get_tile_content(xyz).await?;
+    pub async fn get_http_response(
+        &self,
+        xyz: TileCoord,
+        req: &HttpRequest,
+    ) -> ActixResult<HttpResponse> {
+        if req.method() == Method::HEAD {
+            if let Some(response) = self.get_head_response(xyz, req).await? {
+                return Ok(response);
+            }
+        }
+        if req.method() == Method::GET {
+            if let Some(response) = self.get_streaming_response(xyz, req).await? {
+                return Ok(response);
+            }
+        }
+        let if_none_match = joined_header(req, &IF_NONE_MATCH);
+        let if_modified_since = joined_header(req, &IF_MODIFIED_SINCE);
+        let response = self
+            .get_tile_response(xyz, if_none_match.as_deref(), if_modified_since.as_deref())
+            .await?;
+        Ok(response.into())
+    }
+
+    /// Build the response for a tile independently of the HTTP framework. This is the core shared
+    /// by the actix handlers and [`crate::srv::TileService`], which only convert the request and response.
+    /// The conditional request headers are passed as sent by the client.
+    pub async fn get_tile_response(
+        &self,
+        xyz: TileCoord,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> ActixResult<ServiceResponse> {
+        let last_modified = self.get_last_modified().await?.map(HttpDate::from);
+        let if_none_match = if_none_match.and_then(parse_header::<IfNoneMatch>);
+        if if_none_match.is_none() {
+            // If-Modified-Since must be ignored when If-None-Match is present, see RFC 9110
+            let since = if_modified_since.and_then(parse_header::<IfModifiedSince>);
+            if let (Some(last_modified), Some(IfModifiedSince(since))) = (last_modified, since) {
+                if last_modified <= since {
+                    return Ok(self
+                        .tile_response(Some(xyz.z), StatusCode::NOT_MODIFIED)
+                        .with_header(LAST_MODIFIED, last_modified));
+                }
+            }
+        }
+
+        let (tile, warnings) = self.get_cached_tile_content(xyz).await?;
+        if tile.data.is_empty() {
+            return Ok(self.tile_response(Some(xyz.z), StatusCode::NO_CONTENT));
+        }
+        let etag = tile_etag(&tile.data, self.get_data_version().as_deref());
+        if is_not_modified(if_none_match.as_ref(), &etag) {
+            return Ok(self
+                .tile_response(Some(xyz.z), StatusCode::NOT_MODIFIED)
+                .with_header(ETAG, etag));
+        }
+        let mut response = self
+            .tile_response(Some(xyz.z), StatusCode::OK)
+            .with_header(ETAG, etag);
+        for warning in warnings.headers() {
+            response = response.append_header(WARNING, warning);
+        }
+        if let Some(last_modified) = last_modified {
+            response = response.with_header(LAST_MODIFIED, last_modified);
+        }
+        response = response.with_header(CONTENT_TYPE, tile.info.format.content_type());
+        if let Some(val) = tile.info.encoding.content_encoding() {
+            response = response.with_header(CONTENT_ENCODING, val);
+        }
+        Ok(response.with_body(tile.data))
+    }
 
-        Ok(if tile.data.is_empty() {
-            HttpResponse::NoContent().finish()
+    /// Answer a `HEAD` request using the tile size reported by the source, without reading the tile data.
+    /// This is only possible if the stored tile would be sent as is, and the request is not conditional
+    /// because the `ETag` is computed from the data, which is also why the response has no `ETag`.
+    /// Returns `None` if the full response has to be built instead.
+    async fn get_head_response(
+        &self,
+        xyz: TileCoord,
+        req: &HttpRequest,
+    ) -> ActixResult<Option<HttpResponse>> {
+        let Some((_, size)) = self.get_stored_tile_size(xyz, req).await? else {
+            return Ok(None);
+        };
+        Ok(Some(if size == 0 {
+            self.response_builder(Some(xyz.z), HttpResponse::NoContent())
+                .finish()
         } else {
-            let mut response = HttpResponse::Ok();
-            response.content_type(tile.info.format.content_type());
-            if let Some(val) = tile.info.encoding.content_encoding() {
-                response.insert_header((CONTENT_ENCODING, val));
+            // Only a streaming body keeps the explicit Content-Length header, HEAD responses never send it
+            self.stored_response_builder(xyz)
+                .await?
+                .no_chunking(size as u64)
+                .streaming(stream::empty::<ActixResult<Bytes>>())
+        }))
+    }
+
+    /// Stream a tile of at least `stream_min_size` bytes to the client in chunks as the source reads it,
+    /// instead of holding it in memory. The same conditions as for [`Self::get_head_response`] apply,
+    /// the source must support [`Source::get_tile_stream`], and the tile is not cached.
+    /// Returns `None` if the full response has to be built instead.
+    async fn get_streaming_response(
+        &self,
+        xyz: TileCoord,
+        req: &HttpRequest,
+    ) -> ActixResult<Option<HttpResponse>> {
+        let Some(min_size) = self.srv_config.and_then(|c| c.stream_min_size) else {
+            return Ok(None);
+        };
+        let Some((source, size)) = self.get_stored_tile_size(xyz, req).await? else {
+            return Ok(None);
+        };
+        if size == 0 || size < min_size {
+            return Ok(None);
+        }
+        let Some(chunks) = source
+            .get_tile_stream(xyz, self.query_obj.as_ref())
+            .await
+            .map_err(map_tile_error)?
+        else {
+            return Ok(None);
+        };
+        let id = source.get_id().to_string();
+        let chunks = chunks.inspect_err(move |e| {
+            warn!("Failed to stream tile {xyz:#} of source {id}: {e}");
+        });
+        Ok(Some(
+            self.stored_response_builder(xyz).await?.streaming(chunks),
+        ))
+    }
+
+    /// The single source and the size of its tile, if the tile would be sent as the source stores it,
+    /// the request is not conditional, and the source can find out the size without reading the data
+    async fn get_stored_tile_size(
+        &self,
+        xyz: TileCoord,
+        req: &HttpRequest,
+    ) -> ActixResult<Option<(&dyn Source, usize)>> {
+        let [source] = self.sources.as_slice() else {
+            return Ok(None);
+        };
+        if req.headers().contains_key(IF_NONE_MATCH)
+            || req.headers().contains_key(IF_MODIFIED_SINCE)
+            || !self.is_sent_as_stored()
+            || !self.is_in_bounds(*source, xyz)
+        {
+            return Ok(None);
+        }
+        Ok(source
+            .get_tile_size(xyz, self.query_obj.as_ref())
+            .await
+            .map_err(map_tile_error)?
+            .map(|size| (*source, size)))
+    }
+
+    /// The headers of a non-empty tile sent as stored, without the `ETag` computed from the data
+    async fn stored_response_builder(&self, xyz: TileCoord) -> ActixResult<HttpResponseBuilder> {
+        let last_modified = self.get_last_modified().await?;
+        let mut response = self.response_builder(Some(xyz.z), HttpResponse::Ok());
+        if let Some(last_modified) = last_modified {
+            response.insert_header(LastModified(HttpDate::from(last_modified)));
+        }
+        response.content_type(self.info.format.content_type());
+        if let Some(val) = self.info.encoding.content_encoding() {
+            response.insert_header((CONTENT_ENCODING, val));
+        }
+        Ok(response)
+    }
+
+    /// True if the tile data is sent to the client exactly as the source stores it,
+    /// i.e. no layers are removed, no limits apply, and the tile is neither decompressed nor (re-)compressed.
+    fn is_sent_as_stored(&self) -> bool {
+        if self.layers.is_some()
+            || self.sources.iter().any(|src| {
+                self.get_source_options(src.get_id())
+                    .is_some_and(|o| o.limits.is_some())
+            })
+        {
+            return false;
+        }
+        let Some(accept_enc) = &self.accept_enc else {
+            return !self.info.encoding.is_encoded()
+                || self.get_stored_encoding_policy() == StoredEncodingPolicy::Passthrough;
+        };
+        if self.info.encoding.is_encoded() {
+            accepts_encoding(accept_enc, self.info.encoding)
+                || self.get_stored_encoding_policy() == StoredEncodingPolicy::Passthrough
+        } else {
+            self.decide_encoding(accept_enc)
+                .is_ok_and(|enc| enc.and_then(to_encoding).map_or(true, |e| !e.is_encoded()))
+        }
+    }
+
+    /// Add the headers of [`Self::tile_headers`] to an actix response
+    fn response_builder(
+        &self,
+        zoom: Option<u8>,
+        mut response: HttpResponseBuilder,
+    ) -> HttpResponseBuilder {
+        for header in self.tile_headers(zoom) {
+            response.insert_header(header);
+        }
+        response
+    }
+
+    /// A response with the headers of [`Self::tile_headers`] and no body
+    fn tile_response(&self, zoom: Option<u8>, status: StatusCode) -> ServiceResponse {
+        self.tile_headers(zoom)
+            .into_iter()
+            .fold(ServiceResponse::new(status.as_u16()), |response, (k, v)| {
+                response.with_header(k, v)
+            })
+    }
+
+    /// The cache tags of the sources and of the zoom level, if there is a single one,
+    /// and the custom headers configured for each of the sources.
+    /// If several sources set the same header, the last one wins.
+    fn tile_headers(&self, zoom: Option<u8>) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(cache_tags) = self.srv_config.and_then(|c| c.cache_tags) {
+            let mut tags: Vec<_> = self
+                .sources
+                .iter()
+                .map(|src| source_surrogate_key(src.get_id()))
+                .collect();
+            tags.extend(zoom.map(|z| format!("z:{z}")));
+            let (name, value) = cache_tags.header(&tags);
+            headers.push((name.to_string(), value));
+        }
+        if self
+            .sources
+            .iter()
+            .any(|src| self.srv_config.is_some_and(|c| c.is_noindex(src.get_id())))
+        {
+            headers.push((X_ROBOTS_TAG.to_string(), "noindex".to_string()));
+        }
+        for src in &self.sources {
+            if let Some(custom) = self
+                .get_source_options(src.get_id())
+                .and_then(|o| o.headers.as_ref())
+            {
+                headers.extend(custom.iter().map(|(k, v)| (k.clone(), v.clone())));
             }
-            response.body(tile.data)
-        })
+        }
+        headers
+    }
+
+    /// A composite source is only as fresh as its most recently modified part,
+    /// and its modification time is unknown if any of its parts are unknown.
+    /// The result is truncated to whole seconds to match the HTTP date precision.
+    pub async fn get_last_modified(&self) -> ActixResult<Option<SystemTime>> {
+        // the actix error is not `Send`, so it must not be held across the `.await` of the cell
+        let last_modified = self
+            .last_modified
+            .get_or_try_init(|| async {
+                let dates = try_join_all(self.sources.iter().map(|src| async {
+                    let configured = self
+                        .get_source_options(src.get_id())
+                        .and_then(|o| o.last_modified)
+                        .map(SystemTime::from);
+                    match configured {
+                        Some(date) => MartinResult::Ok(Some(date)),
+                        None => src.get_last_modified().await,
+                    }
+                }))
+                .await?;
+                let last_modified = dates
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|dates| dates.into_iter().max());
+                MartinResult::Ok(last_modified.and_then(|date| {
+                    let secs = date.duration_since(UNIX_EPOCH).ok()?.as_secs();
+                    Some(UNIX_EPOCH + Duration::from_secs(secs))
+                }))
+            })
+            .await
+            .map_err(map_tile_error)?;
+        Ok(*last_modified)
+    }
+
+    fn get_source_options(&self, source_id: &str) -> Option<&SourceOptions> {
+        self.srv_config?.get_source_options(source_id)
     }
 
     pub async fn get_tile_content(&self, xyz: TileCoord) -> ActixResult<Tile> {
-        let mut tiles = try_join_all(self.sources.iter().map(|s| async {
-            get_or_insert_cached_value!(
+        Ok(self.get_cached_tile_content(xyz).await?.0)
+    }
+
+    /// Same as [`Self::get_tile_content`], but also returns the warnings about the tile content
+    async fn get_cached_tile_content(&self, xyz: TileCoord) -> ActixResult<(Tile, TileWarnings)> {
+        let policy = self.get_cache_policy();
+        let slow_tile = self
+            .srv_config
+            .and_then(|c| c.slow_tile_ms)
+            .map(Duration::from_millis);
+        let versions = self.get_data_versions().await?;
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .zip(versions)
+            .filter(|(s, _)| self.is_in_bounds(**s, xyz))
+            .collect();
+        let tiles = try_join_all(sources.iter().map(|(s, version)| {
+            get_or_insert_limited_tile(
                 self.cache,
-                CacheValue::Tile,
-                s.get_tile(xyz, self.query_obj.as_ref()),
-                {
+                policy,
+                log_if_slow(
+                    s.get_tile(xyz, self.query_obj.as_ref()),
+                    slow_tile,
+                    s.get_id(),
+                    xyz,
+                    self.query_str,
+                )
+                .inspect(|result| {
+                    record_source_result(s.get_id(), xyz, result);
+                    record_source_health(s.get_id(), result);
+                })
+                // the reduced tile is cached, so that it is not reduced again for each request
+                .map(|result| self.apply_limits(**s, xyz, result?)),
+                || {
                     let id = s.get_id().to_string();
-                    if let Some(query_str) = self.query_str {
-                        CacheKey::TileWithQuery(id, xyz, query_str.to_string())
-                    } else {
-                        CacheKey::Tile(id, xyz)
+                    match with_version(self.query_str, version.as_deref()) {
+                        Some(query) => CacheKey::TileWithQuery(id, xyz, query),
+                        None => CacheKey::Tile(id, xyz),
                     }
-                }
+                },
             )
         }))
         .await
-        .map_err(map_internal_error)?;
+        .map_err(map_tile_error)?;
+        let warnings = TileWarnings {
+            is_stale: tiles.iter().any(|t| t.is_stale),
+            is_truncated: tiles.iter().any(|t| t.is_truncated),
+        };
+        let mut tiles: Vec<_> = tiles.into_iter().map(|t| t.data).collect();
 
         let mut layer_count = 0;
         let mut last_non_empty_layer = 0;
@@ -157,7 +903,7 @@ impl<'a> DynTileSource<'a> {
         // Minor optimization to prevent concatenation if there are less than 2 tiles
         let data = match layer_count {
             1 => tiles.swap_remove(last_non_empty_layer),
-            0 => return Ok(Tile::new(Vec::new(), self.info)),
+            0 => return Ok((Tile::new(Vec::new(), self.info), warnings)),
             _ => {
                 // Make sure tiles can be concatenated, or if not, that there is only one non-empty tile for each zoom level
                 // TODO: can zlib, brotli, or zstd be concatenated?
@@ -172,87 +918,345 @@ impl<'a> DynTileSource<'a> {
                         xyz.z
                     )))?;
                 }
-                tiles.concat()
+                tiles.concat().into()
             }
         };
+        let tile = self.select_layers(Tile::new(data, self.info))?;
+        if tile.data.is_empty() {
+            return Ok((Tile::new(Vec::new(), self.info), warnings));
+        }
 
         // decide if (re-)encoding of the tile data is needed, and recompress if so
-        self.recompress(data)
+        let tile = self.recompress(tile, xyz, warnings.is_stale).await?;
+        Ok((tile, warnings))
+    }
+
+    /// Check the tile of a source against the `limits` configured for it. Vector tiles that are too large
+    /// are reduced first, and the tiles still exceeding the limits are rejected or truncated.
+    /// Returns true if the tile was truncated.
+    fn apply_limits(
+        &self,
+        src: &dyn Source,
+        xyz: TileCoord,
+        mut data: TileData,
+    ) -> MartinResult<(TileData, bool)> {
+        let Some(limits) = self
+            .get_source_options(src.get_id())
+            .and_then(|o| o.limits.as_ref())
+        else {
+            return Ok((data, false));
+        };
+        let id = src.get_id();
+        let is_mvt = self.info.format == Format::Mvt;
+        let max_size = limits.max_size.unwrap_or(usize::MAX);
+        let max_features = limits.max_features.filter(|_| is_mvt).unwrap_or(usize::MAX);
+
+        // the uncompressed tile, if it had to be decoded
+        let mut decoded = None;
+        let reduce = limits.reduce.as_deref().unwrap_or_default();
+        if is_mvt && data.len() > max_size && !reduce.is_empty() {
+            let size = data.len();
+            let mut tile = self.decode_like_source(data.clone())?;
+            for step in reduce {
+                tile = step.apply(&tile)?.into();
+                data = self.encode_like_source(tile.clone())?;
+                if data.len() <= max_size {
+                    break;
+                }
+            }
+            debug!(
+                "Reduced tile {xyz:#} of source {id} from {size} to {} bytes",
+                data.len()
+            );
+            decoded = Some(tile);
+        }
+
+        let exceeded = if data.len() > max_size {
+            Some(format!(
+                "{} bytes, more than the limit of {max_size}",
+                data.len()
+            ))
+        } else if max_features < usize::MAX && !data.is_empty() {
+            let tile = match decoded.take() {
+                Some(tile) => tile,
+                None => self.decode_like_source(data.clone())?,
+            };
+            let count = count_mvt_features(&tile)?;
+            decoded = Some(tile);
+            (count > max_features)
+                .then(|| format!("{count} features, more than the limit of {max_features}"))
+        } else {
+            None
+        };
+        let Some(reason) = exceeded else {
+            return Ok((data, false));
+        };
+
+        if !is_mvt || limits.on_exceed.unwrap_or_default() == LimitPolicy::Reject {
+            let message = format!("Tile {xyz:#} of source {id} has {reason}");
+            warn!("{message}");
+            return Err(TileLimitExceeded(message));
+        }
+        debug!("Truncating tile {xyz:#} of source {id} with {reason}");
+        let tile = match decoded {
+            Some(tile) => tile,
+            None => self.decode_like_source(data)?,
+        };
+        let data = truncate_mvt(&tile, max_features, max_size)?;
+        Ok((self.encode_like_source(data.into())?, true))
+    }
+
+    /// Compress an uncompressed tile like the source did, so that it can be combined with the tiles of other sources
+    fn encode_like_source(&self, data: TileData) -> MartinResult<TileData> {
+        Ok(match self.info.encoding {
+            Encoding::Gzip => encode_gzip(&data)?.into(),
+            Encoding::Brotli => encode_brotli(&data)?.into(),
+            Encoding::Zstd => encode_zstd(&data)?.into(),
+            _ => data,
+        })
+    }
+
+    /// Decompress a tile of the source, the reverse of [`Self::encode_like_source`]
+    fn decode_like_source(&self, data: TileData) -> MartinResult<TileData> {
+        Ok(match self.info.encoding {
+            Encoding::Gzip => decode_gzip(&data)?.into(),
+            Encoding::Brotli => decode_brotli(&data)?.into(),
+            Encoding::Zstd => decode_zstd(&data)?.into(),
+            enc if enc.is_encoded() => Err(MartinInternalError(
+                format!("Unable to decompress tiles stored with {enc:?} encoding").into(),
+            ))?,
+            _ => data,
+        })
+    }
+
+    /// False if the tile does not exist, or if it is outside of the source bounds
+    /// so that the source would only return an empty tile.
+    /// Bounds crossing the antimeridian are not checked.
+    fn is_in_bounds(&self, src: &dyn Source, xyz: TileCoord) -> bool {
+        if !xyz.is_valid() {
+            return false;
+        }
+        let check_bounds = self
+            .srv_config
+            .map_or(true, |cfg| cfg.check_tile_bounds.unwrap_or(true));
+        let Some(bounds) = src.get_tilejson().bounds.filter(|_| check_bounds) else {
+            return true;
+        };
+        if bounds.left > bounds.right {
+            return true;
+        }
+        let [left, bottom, right, top] = xyz.bbox();
+        let is_in_bounds = left <= bounds.right
+            && right >= bounds.left
+            && bottom <= bounds.top
+            && top >= bounds.bottom;
+        if !is_in_bounds {
+            debug!(
+                "Tile {xyz:#} is outside of the bounds of source {}",
+                src.get_id()
+            );
+        }
+        is_in_bounds
+    }
+
+    /// Remove the layers not requested with the `layers` query parameter.
+    /// The filtered tile is uncompressed, and gets compressed again as needed.
+    fn select_layers(&self, tile: Tile) -> ActixResult<Tile> {
+        let Some(layers) = &self.layers else {
+            return Ok(tile);
+        };
+        let tile = decode(tile)?;
+        let data = filter_mvt_layers(&tile.data, |name| layers.contains(name))?;
+        Ok(Tile::new(data, tile.info))
+    }
+
+    /// The query-dependent part of the cache key for the final tile
+    fn get_query_cache_key(&self) -> Option<String> {
+        let query = match &self.layers {
+            Some(layers) => Some(format!(
+                "{}#layers={}",
+                self.query_str.unwrap_or_default(),
+                layers.iter().join(",")
+            )),
+            None => self.query_str.map(ToString::to_string),
+        };
+        with_version(query.as_deref(), self.get_data_version().as_deref())
+    }
+
+    /// The data version of each source: the configured `data_version`, or the one reported by the source
+    async fn get_data_versions(&self) -> ActixResult<&Vec<Option<String>>> {
+        // the actix error is not `Send`, so it must not be held across the `.await` of the cell
+        self.data_versions
+            .get_or_try_init(|| {
+                try_join_all(self.sources.iter().map(|src| async {
+                    let configured = self
+                        .get_source_options(src.get_id())
+                        .and_then(|o| o.data_version.clone());
+                    match configured {
+                        Some(version) => MartinResult::Ok(Some(version)),
+                        None => src.get_data_version().await,
+                    }
+                }))
+            })
+            .await
+            .map_err(map_tile_error)
+    }
+
+    /// The combined data version of the sources, or `None` if none of them has a version
+    /// or if the versions have not been queried yet
+    fn get_data_version(&self) -> Option<String> {
+        let versions = self.data_versions.get()?;
+        versions.iter().any(Option::is_some).then(|| {
+            versions
+                .iter()
+                .map(|v| v.as_deref().unwrap_or_default())
+                .join(",")
+        })
+    }
+
+    fn get_cache_policy(&self) -> TileCachePolicy {
+        self.srv_config
+            .map(SrvConfig::get_cache_policy)
+            .unwrap_or_default()
     }
 
     /// Decide which encoding to use for the uncompressed tile data, based on the client's Accept-Encoding header
     fn decide_encoding(&self, accept_enc: &AcceptEncoding) -> ActixResult<Option<ContentEncoding>> {
-        let mut q_gzip = None;
-        let mut q_brotli = None;
+        let mut q_values: [Option<Quality>; 3] = [None; 3];
         for enc in accept_enc.iter() {
             if let Preference::Specific(HeaderEnc::Known(e)) = enc.item {
-                match e {
-                    ContentEncoding::Gzip => q_gzip = Some(enc.quality),
-                    ContentEncoding::Brotli => q_brotli = Some(enc.quality),
-                    _ => {}
+                if let Some(idx) = COMPRESSION_ORDER.iter().position(|v| *v == e) {
+                    q_values[idx] = Some(enc.quality);
                 }
             } else if let Preference::Any = enc.item {
-                q_gzip.get_or_insert(enc.quality);
-                q_brotli.get_or_insert(enc.quality);
-            }
-        }
-        Ok(match (q_gzip, q_brotli) {
-            (Some(q_gzip), Some(q_brotli)) if q_gzip == q_brotli => {
-                if q_gzip > Quality::ZERO {
-                    Some(self.get_preferred_enc())
-                } else {
-                    None
-                }
-            }
-            (Some(q_gzip), Some(q_brotli)) if q_brotli > q_gzip => Some(ContentEncoding::Brotli),
-            (Some(_), Some(_)) => Some(ContentEncoding::Gzip),
-            _ => {
-                if let Some(HeaderEnc::Known(enc)) = accept_enc.negotiate(SUPPORTED_ENC.iter()) {
-                    Some(enc)
-                } else {
-                    return Err(ErrorNotAcceptable("No supported encoding found"));
+                for q in &mut q_values {
+                    q.get_or_insert(enc.quality);
                 }
             }
+        }
+
+        let accepted = || {
+            COMPRESSION_ORDER
+                .iter()
+                .zip(q_values)
+                .filter_map(|(enc, q)| Some((*enc, q?)))
+        };
+        if accepted().count() < 2 {
+            // Let the standard negotiation handle the simple cases, including the identity encoding
+            return if let Some(HeaderEnc::Known(enc)) = accept_enc.negotiate(SUPPORTED_ENC.iter()) {
+                Ok(Some(enc))
+            } else {
+                Err(ErrorNotAcceptable("No supported encoding found"))
+            };
+        }
+
+        let Some(max_q) = accepted()
+            .map(|(_, q)| q)
+            .max()
+            .filter(|q| *q > Quality::ZERO)
+        else {
+            return Ok(None);
+        };
+        let preferred = self.get_preferred_enc();
+        let mut best = accepted().filter(|(_, q)| *q == max_q).map(|(enc, _)| enc);
+        Ok(if best.clone().any(|enc| enc == preferred) {
+            Some(preferred)
+        } else {
+            best.next()
         })
     }
 
     fn get_preferred_enc(&self) -> ContentEncoding {
-        match self.preferred_enc {
+        match self.srv_config.and_then(|c| c.preferred_encoding) {
             None | Some(PreferredEncoding::Gzip) => ContentEncoding::Gzip,
             Some(PreferredEncoding::Brotli) => ContentEncoding::Brotli,
+            Some(PreferredEncoding::Zstd) => ContentEncoding::Zstd,
         }
     }
 
-    fn recompress(&self, tile: TileData) -> ActixResult<Tile> {
-        let mut tile = Tile::new(tile, self.info);
+    async fn recompress(
+        &self,
+        mut tile: Tile,
+        xyz: TileCoord,
+        is_stale: bool,
+    ) -> ActixResult<Tile> {
+        let policy = self.get_stored_encoding_policy();
         if let Some(accept_enc) = &self.accept_enc {
             if self.info.encoding.is_encoded() {
                 // already compressed, see if we can send it as is, or need to re-compress
-                if !accept_enc.iter().any(|e| {
-                    if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
-                        to_encoding(enc) == Some(tile.info.encoding)
-                    } else {
-                        false
+                if !accepts_encoding(accept_enc, tile.info.encoding) {
+                    match policy {
+                        StoredEncodingPolicy::Passthrough => return Ok(tile),
+                        StoredEncodingPolicy::Decompress => return decode(tile),
+                        // need to re-compress the tile - uncompress it first
+                        StoredEncodingPolicy::Reencode => tile = decode(tile)?,
                     }
-                }) {
-                    // need to re-compress the tile - uncompress it first
-                    tile = decode(tile)?;
                 }
             }
 
+            // The decision is made before awaiting, so that the future stays `Send`
+            let preferred_enc = if tile.info.encoding == Encoding::Uncompressed {
+                self.decide_encoding(accept_enc)?
+            } else {
+                None
+            };
             if tile.info.encoding == Encoding::Uncompressed {
-                if let Some(enc) = self.decide_encoding(accept_enc)? {
-                    // (re-)compress the tile into the preferred encoding
-                    tile = encode(tile, enc)?;
+                if let Some(enc) = preferred_enc {
+                    // (re-)compress the tile into the preferred encoding,
+                    // caching the result to avoid re-compressing popular tiles.
+                    // Expired tiles are not cached to avoid making them look fresh.
+                    if let Some(encoding) = to_encoding(enc).filter(Encoding::is_encoded) {
+                        let cache = self.cache.filter(|_| !is_stale);
+                        let info = tile.info.encoding(encoding);
+                        let data = get_or_insert_tile(
+                            cache,
+                            self.get_cache_policy(),
+                            async { encode(tile, enc).map(|t| t.data) },
+                            || {
+                                let ids = self.sources.iter().map(|s| s.get_id()).join(",");
+                                let query = self.get_query_cache_key();
+                                CacheKey::EncodedTile(ids, xyz, query, encoding)
+                            },
+                        )
+                        .await?
+                        .data;
+                        tile = Tile::new(data, info);
+                    }
                 }
             }
 
+            Ok(tile)
+        } else if policy == StoredEncodingPolicy::Passthrough {
             Ok(tile)
         } else {
             // no accepted-encoding header, decode the tile if compressed
             decode(tile)
         }
     }
+
+    /// Composite sources only use a non-default policy if all of their sources agree on it.
+    fn get_stored_encoding_policy(&self) -> StoredEncodingPolicy {
+        self.sources
+            .iter()
+            .map(|src| {
+                self.get_source_options(src.get_id())
+                    .and_then(|o| o.stored_encoding)
+                    .unwrap_or_default()
+            })
+            .all_equal_value()
+            .unwrap_or_default()
+    }
+}
+
+/// True if the client explicitly listed the given encoding in its `Accept-Encoding` header
+fn accepts_encoding(accept_enc: &AcceptEncoding, encoding: Encoding) -> bool {
+    accept_enc.iter().any(|e| {
+        if let Preference::Specific(HeaderEnc::Known(enc)) = e.item {
+            to_encoding(enc) == Some(encoding)
+        } else {
+            false
+        }
+    })
 }
 
 fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
@@ -264,11 +1268,14 @@ fn encode(tile: Tile, enc: ContentEncoding) -> ActixResult<Tile> {
         ContentEncoding::Gzip => {
             Tile::new(encode_gzip(&tile.data)?, tile.info.encoding(Encoding::Gzip))
         }
+        ContentEncoding::Zstd => {
+            Tile::new(encode_zstd(&tile.data)?, tile.info.encoding(Encoding::Zstd))
+        }
         _ => tile,
     })
 }
 
-fn decode(tile: Tile) -> ActixResult<Tile> {
+pub(crate) fn decode(tile: Tile) -> ActixResult<Tile> {
     let info = tile.info;
     Ok(if info.encoding.is_encoded() {
         match info.encoding {
@@ -280,6 +1287,10 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
                 decode_brotli(&tile.data)?,
                 info.encoding(Encoding::Uncompressed),
             ),
+            Encoding::Zstd => Tile::new(
+                decode_zstd(&tile.data)?,
+                info.encoding(Encoding::Uncompressed),
+            ),
             _ => Err(ErrorBadRequest(format!(
                 "Tile is is stored as {info}, but the client does not accept this encoding"
             )))?,
@@ -289,23 +1300,152 @@ fn decode(tile: Tile) -> ActixResult<Tile> {
     })
 }
 
+fn map_tile_error(e: MartinError) -> actix_web::Error {
+    if let SourceUnavailable(_, retry_after) | SourceTimeout(_, retry_after) = &e {
+        warn!("{e}");
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, retry_after_value(*retry_after)))
+            .body(e.to_string());
+        InternalError::from_response(e, response).into()
+    } else if let TileLimitExceeded(message) = e {
+        // the server works as configured, but the tile can't be served within the limits of its source
+        ErrorUnprocessableEntity(message)
+    } else {
+        map_internal_error(e)
+    }
+}
+
+/// A strong `ETag` based on the hash of the tile content as it is sent to the client.
+/// Each encoding of the same tile gets its own tag, and so does each data version of the sources.
+#[must_use]
+pub fn tile_etag(data: &[u8], data_version: Option<&str>) -> EntityTag {
+    match data_version {
+        Some(version) => EntityTag::new_strong(format!(
+            "{:x}-{:x}",
+            xxh3_128(data),
+            xxh3_64(version.as_bytes())
+        )),
+        None => EntityTag::new_strong(format!("{:x}", xxh3_128(data))),
+    }
+}
+
+/// Add the data version to the query-dependent part of a cache key
+fn with_version(query: Option<&str>, data_version: Option<&str>) -> Option<String> {
+    match data_version {
+        Some(version) => Some(format!("{}#version={version}", query.unwrap_or_default())),
+        None => query.map(ToString::to_string),
+    }
+}
+
+/// A response with the given body and a weak `ETag` based on its hash, or an empty `304 Not Modified`
+/// response if the client already has the same content. Used for the metadata endpoints
+/// that are polled often, like the catalog. The tag is weak because the body may be compressed later.
+pub(crate) fn with_etag(
+    req: &HttpRequest,
+    content_type: ContentType,
+    body: Vec<u8>,
+) -> HttpResponse {
+    let etag = EntityTag::new_weak(format!("{:x}", xxh3_128(&body)));
+    if is_not_modified(req.get_header::<IfNoneMatch>().as_ref(), &etag) {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ETag(etag))
+        .body(body)
+}
+
+/// All values of a request header joined into one, as if they were sent in a single line
+fn joined_header(req: &HttpRequest, name: &HeaderName) -> Option<String> {
+    let values: Vec<_> = req
+        .headers()
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// Parse a typed header from its raw value, for the callers without an actix request
+pub(crate) fn parse_header<H: Header>(value: &str) -> Option<H> {
+    let mut msg = actix_http::Request::new();
+    msg.headers_mut()
+        .insert(H::name(), HeaderValue::from_str(value).ok()?);
+    H::parse(&msg).ok()
+}
+
+impl From<ServiceResponse> for HttpResponse {
+    fn from(response: ServiceResponse) -> Self {
+        let status =
+            StatusCode::from_u16(response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut builder = HttpResponse::build(status);
+        for header in response.headers {
+            builder.append_header(header);
+        }
+        if response.body.is_empty() {
+            builder.finish()
+        } else {
+            builder.body(response.body)
+        }
+    }
+}
+
+impl From<HttpResponse> for ServiceResponse {
+    fn from(response: HttpResponse) -> Self {
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect();
+        // all of Martin's non-streaming responses have an in-memory body
+        let body =
+            actix_web::body::MessageBody::try_into_bytes(response.into_body()).unwrap_or_default();
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+}
+
+impl From<actix_web::Error> for ServiceResponse {
+    fn from(error: actix_web::Error) -> Self {
+        error.error_response().into()
+    }
+}
+
+fn is_not_modified(if_none_match: Option<&IfNoneMatch>, etag: &EntityTag) -> bool {
+    match if_none_match {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
 pub fn to_encoding(val: ContentEncoding) -> Option<Encoding> {
     Some(match val {
         ContentEncoding::Identity => Encoding::Uncompressed,
         ContentEncoding::Gzip => Encoding::Gzip,
         ContentEncoding::Brotli => Encoding::Brotli,
-        // TODO: Deflate => Encoding::Zstd or Encoding::Zlib ?
+        ContentEncoding::Zstd => Encoding::Zstd,
+        // TODO: Deflate => Encoding::Zlib ?
         _ => None?,
     })
 }
 
 #[cfg(test)]
 mod tests {
+    use actix_web::test::TestRequest;
+    use indoc::indoc;
     use rstest::rstest;
-    use tilejson::tilejson;
+    use tilejson::{tilejson, Bounds};
 
     use super::*;
     use crate::srv::server::tests::TestSource;
+    use crate::utils::CacheValue;
+    use crate::TileData;
 
     #[actix_rt::test]
     async fn test_deleteme() {
@@ -320,6 +1460,10 @@ mod tests {
     #[case(&["br;q=1", "gzip;q=1"], Some(PreferredEncoding::Gzip), Encoding::Gzip)]
     #[case(&["gzip;q=1", "br;q=1"], Some(PreferredEncoding::Brotli), Encoding::Brotli)]
     #[case(&["gzip;q=1", "br;q=0.5"], Some(PreferredEncoding::Brotli), Encoding::Gzip)]
+    #[case(&["gzip", "br", "zstd"], Some(PreferredEncoding::Zstd), Encoding::Zstd)]
+    #[case(&["gzip;q=0.5", "zstd"], None, Encoding::Zstd)]
+    #[case(&["br", "zstd"], None, Encoding::Brotli)]
+    #[case(&["zstd"], None, Encoding::Zstd)]
     #[actix_rt::test]
     async fn test_enc_preference(
         #[case] accept_enc: &[&'static str],
@@ -329,20 +1473,24 @@ mod tests {
         let sources = TileSources::new(vec![vec![Box::new(TestSource {
             id: "test_source",
             tj: tilejson! { tiles: vec![] },
-            data: vec![1_u8, 2, 3],
+            data: TileData::from_static(&[1, 2, 3]),
         })]]);
 
         let accept_enc = Some(AcceptEncoding(
             accept_enc.iter().map(|s| s.parse().unwrap()).collect(),
         ));
 
+        let srv_config = SrvConfig {
+            preferred_encoding: preferred_enc,
+            ..Default::default()
+        };
         let src = DynTileSource::new(
             &sources,
             "test_source",
             None,
             "",
             accept_enc,
-            preferred_enc,
+            Some(&srv_config),
             None,
         )
         .unwrap();
@@ -352,17 +1500,164 @@ mod tests {
         assert_eq!(tile.info.encoding, expected_enc);
     }
 
+    #[rstest]
+    #[case(None, Some("gzip"), Encoding::Gzip)]
+    #[case(None, Some("br"), Encoding::Brotli)]
+    #[case(None, None, Encoding::Uncompressed)]
+    #[case(Some("passthrough"), Some("br"), Encoding::Gzip)]
+    #[case(Some("passthrough"), None, Encoding::Gzip)]
+    #[case(Some("decompress"), Some("br"), Encoding::Uncompressed)]
+    #[case(Some("decompress"), Some("gzip"), Encoding::Gzip)]
+    #[case(Some("reencode"), Some("br"), Encoding::Brotli)]
+    #[actix_rt::test]
+    async fn test_stored_encoding_policy(
+        #[case] policy: Option<&str>,
+        #[case] accept_enc: Option<&str>,
+        #[case] expected_enc: Encoding,
+    ) {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: encode_gzip(&[1_u8, 2, 3]).unwrap().into(),
+        })]]);
+        let mut srv_config = SrvConfig::default();
+        if let Some(policy) = policy {
+            srv_config = serde_yaml::from_str(&format!(
+                "source_options: {{ src: {{ stored_encoding: {policy} }} }}"
+            ))
+            .unwrap();
+        }
+        let accept_enc = accept_enc.map(|v| AcceptEncoding(vec![v.parse().unwrap()]));
+        let mut src = DynTileSource::new(
+            &sources,
+            "src",
+            None,
+            "",
+            accept_enc,
+            Some(&srv_config),
+            None,
+        )
+        .unwrap();
+        src.info = src.info.encoding(Encoding::Gzip);
+
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let tile = src.get_tile_content(xyz).await.unwrap();
+        assert_eq!(tile.info.encoding, expected_enc);
+    }
+
+    #[test]
+    fn test_etag() {
+        let etag = tile_etag(&[1_u8, 2, 3], None);
+        assert_eq!(etag, tile_etag(&[1_u8, 2, 3], None));
+        assert_ne!(etag, tile_etag(&[1_u8, 2], None));
+        assert_ne!(etag, tile_etag(&[1_u8, 2, 3], Some("2")));
+        assert_ne!(
+            tile_etag(&[1_u8, 2, 3], Some("1")),
+            tile_etag(&[1_u8, 2, 3], Some("2"))
+        );
+        assert!(!etag.weak);
+
+        let other = EntityTag::new_weak("abc".to_string());
+        assert!(!is_not_modified(None, &etag));
+        assert!(is_not_modified(Some(&IfNoneMatch::Any), &etag));
+        let tags = IfNoneMatch::Items(vec![other.clone(), etag.clone()]);
+        assert!(is_not_modified(Some(&tags), &etag));
+        let tags = IfNoneMatch::Items(vec![other]);
+        assert!(!is_not_modified(Some(&tags), &etag));
+    }
+
+    #[test]
+    fn test_multipart_tiles() {
+        let xyz = |z| TileCoord { z, x: 0, y: 0 };
+        let tile = Tile::new(
+            TileData::from_static(b"data"),
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed),
+        );
+        let problem = Problem::for_tile(StatusCode::BAD_GATEWAY, None, "src", xyz(2));
+        let tiles = [
+            Ok(Some((tile, TileWarnings::default()))),
+            Ok(None),
+            Err(problem),
+        ];
+        let (boundary, body) = multipart_tiles(&[xyz(0), xyz(1), xyz(2)], &tiles);
+        let body = String::from_utf8(body).unwrap();
+        let parts: Vec<_> = body.split(&format!("--{boundary}")).collect();
+        assert_eq!(parts.len(), 5);
+        assert!(parts[1].ends_with("\r\n\r\ndata\r\n"));
+        assert_eq!(
+            parts[2],
+            "\r\nContent-Location: 1/0/0\r\nContent-Length: 0\r\n\r\n\r\n"
+        );
+        assert!(parts[3].starts_with(
+            "\r\nContent-Location: 2/0/0\r\nContent-Type: application/problem+json\r\n"
+        ));
+        assert!(parts[3].contains(r#""status":502"#));
+
+        // the boundary is random, and never part of the data
+        let (other, _) = multipart_tiles(&[xyz(0), xyz(1), xyz(2)], &tiles);
+        assert_ne!(boundary, other);
+        let data = format!("--{boundary}");
+        let boundary = multipart_boundary(&[data.as_bytes()]);
+        assert!(!data.contains(&boundary));
+    }
+
+    /// The modification time of the sources in seconds since the epoch
+    async fn last_modified(
+        sources: &TileSources,
+        ids: &str,
+        cfg: Option<&SrvConfig>,
+    ) -> Option<u64> {
+        DynTileSource::new(sources, ids, None, "", None, cfg, None)
+            .unwrap()
+            .get_last_modified()
+            .await
+            .unwrap()
+            .map(|v| v.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[actix_rt::test]
+    async fn test_last_modified() {
+        let sources = TileSources::new(vec![vec![
+            Box::new(TestSource {
+                id: "a",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            }),
+            Box::new(TestSource {
+                id: "b",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::new(),
+            }),
+        ]]);
+        let srv_config: SrvConfig = serde_yaml::from_str(indoc! {"
+            source_options:
+              a:
+                last_modified: 2024-01-02T03:04:05.678Z
+              b:
+                last_modified: 2023-01-02T03:04:05Z
+        "})
+        .unwrap();
+
+        let get = |ids, cfg| last_modified(&sources, ids, cfg);
+        assert_eq!(get("a", None).await, None);
+        assert_eq!(get("a", Some(&srv_config)).await, Some(1_704_164_645));
+        assert_eq!(get("b", Some(&srv_config)).await, Some(1_672_628_645));
+        assert_eq!(get("a,b", Some(&srv_config)).await, Some(1_704_164_645));
+        let srv_config = SrvConfig::default();
+        assert_eq!(get("a,b", Some(&srv_config)).await, None);
+    }
+
     #[actix_rt::test]
     async fn test_tile_content() {
         let non_empty_source = TestSource {
             id: "non-empty",
             tj: tilejson! { tiles: vec![] },
-            data: vec![1_u8, 2, 3],
+            data: TileData::from_static(&[1, 2, 3]),
         };
         let empty_source = TestSource {
             id: "empty",
             tj: tilejson! { tiles: vec![] },
-            data: Vec::default(),
+            data: TileData::new(),
         };
         let sources = TileSources::new(vec![vec![
             Box::new(non_empty_source),
@@ -384,4 +1679,383 @@ mod tests {
             assert_eq!(expected, &src.get_tile_content(xyz).await.unwrap().data);
         }
     }
+
+    #[actix_rt::test]
+    async fn test_tile_bounds() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![], bounds: Bounds::new(10.0, 10.0, 20.0, 20.0) },
+            data: TileData::from_static(&[1, 2, 3]),
+        })]]);
+        let get = |xyz: TileCoord, srv_config: SrvConfig| {
+            let sources = &sources;
+            async move {
+                let src =
+                    DynTileSource::new(sources, "src", None, "", None, Some(&srv_config), None)
+                        .unwrap();
+                src.get_tile_content(xyz).await.unwrap().data
+            }
+        };
+        let srv_config = SrvConfig::default();
+        let xyz = TileCoord { z: 1, x: 1, y: 0 };
+        assert_eq!(get(xyz, srv_config.clone()).await, vec![1_u8, 2, 3]);
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        assert!(get(xyz, srv_config.clone()).await.is_empty());
+        let xyz = TileCoord { z: 1, x: 2, y: 0 };
+        assert!(get(xyz, srv_config).await.is_empty());
+
+        let srv_config = SrvConfig {
+            check_tile_bounds: Some(false),
+            ..Default::default()
+        };
+        let xyz = TileCoord { z: 1, x: 0, y: 0 };
+        assert_eq!(get(xyz, srv_config).await, vec![1_u8, 2, 3]);
+    }
+
+    #[rstest]
+    #[case(None, Format::Mvt, TileRepresentation::Native)]
+    #[case(Some("*/*"), Format::Mvt, TileRepresentation::Native)]
+    #[case(Some("application/geo+json"), Format::Mvt, TileRepresentation::GeoJson)]
+    #[case(Some("application/geo+json"), Format::Png, TileRepresentation::Native)]
+    #[case(
+        Some("application/geo+json;q=0.5, application/x-protobuf"),
+        Format::Mvt,
+        TileRepresentation::Native
+    )]
+    #[case(
+        Some("application/vnd.mapbox-vector-tile;q=0.5, application/geo+json"),
+        Format::Mvt,
+        TileRepresentation::GeoJson
+    )]
+    #[case(
+        Some("application/geo+json;q=0, */*"),
+        Format::Mvt,
+        TileRepresentation::Native
+    )]
+    #[case(Some("text/html"), Format::Mvt, TileRepresentation::Native)]
+    #[case(Some("image/*"), Format::Png, TileRepresentation::Native)]
+    // rendering is not enabled in these cases
+    #[case(Some("image/webp"), Format::Mvt, TileRepresentation::Native)]
+    fn test_negotiate(
+        #[case] accept: Option<&str>,
+        #[case] format: Format,
+        #[case] expected: TileRepresentation,
+    ) {
+        let accept = accept.map(parse_accept);
+        assert_eq!(negotiate(accept.as_ref(), format, false), expected);
+    }
+
+    fn parse_accept(value: &str) -> Accept {
+        TestRequest::default()
+            .insert_header((actix_web::http::header::ACCEPT, value))
+            .to_http_request()
+            .get_header()
+            .unwrap()
+    }
+
+    #[cfg(feature = "raster")]
+    #[test]
+    fn test_negotiate_rendered() {
+        let negotiate = |accept: &str| negotiate(Some(&parse_accept(accept)), Format::Mvt, true);
+        assert_eq!(
+            negotiate("image/webp,image/*;q=0.8"),
+            TileRepresentation::Rendered(RasterFormat::Webp)
+        );
+        assert_eq!(
+            negotiate("image/*"),
+            TileRepresentation::Rendered(RasterFormat::Png)
+        );
+        assert_eq!(
+            negotiate("image/avif, image/jpeg;q=0.9"),
+            TileRepresentation::Rendered(RasterFormat::Jpeg)
+        );
+        assert_eq!(
+            negotiate("image/webp;q=0.5, */*"),
+            TileRepresentation::Native
+        );
+    }
+
+    #[test]
+    fn test_strict_query_params() {
+        let sources = TileSources::new(vec![vec![
+            Box::new(TestSource {
+                id: "a",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }),
+            Box::new(TestSource {
+                id: "b",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }),
+        ]]);
+        let srv_config: SrvConfig = serde_yaml::from_str(indoc! {"
+            strict_query_params: true
+            source_options:
+              a:
+                query_params: [date]
+        "})
+        .unwrap();
+        let check = |ids: &str, query: &str, srv_config: &SrvConfig| {
+            DynTileSource::new(&sources, ids, None, query, None, Some(srv_config), None)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        assert!(check("a", "", &srv_config).is_ok());
+        assert!(check("a", "date=2024", &srv_config).is_ok());
+        assert!(check("a,b", "date=2024", &srv_config).is_ok());
+        assert_eq!(
+            check("b", "date=2024&x=1", &srv_config).unwrap_err(),
+            "Query parameters date, x are not declared for this source, see query_params of the source options"
+        );
+        assert!(check("a", "date=2024&cachebust=1", &srv_config).is_err());
+        assert!(check("a", "cachebust=1", &SrvConfig::default()).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_source_headers() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(&[1, 2, 3]),
+        })]]);
+        let srv_config: SrvConfig = serde_yaml::from_str(indoc! {"
+            source_options:
+              src:
+                headers:
+                  Cache-Control: public, max-age=3600
+                  X-Robots-Tag: noindex
+        "})
+        .unwrap();
+        let src =
+            DynTileSource::new(&sources, "src", None, "", None, Some(&srv_config), None).unwrap();
+        let req = TestRequest::default().to_http_request();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let resp = src.get_http_response(xyz, &req).await.unwrap();
+        let headers = resp.headers();
+        assert_eq!(
+            headers.get("cache-control").unwrap(),
+            "public, max-age=3600"
+        );
+        assert_eq!(headers.get("x-robots-tag").unwrap(), "noindex");
+        assert!(headers.get("surrogate-key").is_none());
+
+        let srv_config: SrvConfig = serde_yaml::from_str(indoc! {"
+            source_options:
+              src:
+                noindex: true
+        "})
+        .unwrap();
+        let src =
+            DynTileSource::new(&sources, "src", None, "", None, Some(&srv_config), None).unwrap();
+        let resp = src.get_http_response(xyz, &req).await.unwrap();
+        assert_eq!(resp.headers().get("x-robots-tag").unwrap(), "noindex");
+    }
+
+    /// The `Warning` header and the body of the response for a source returning the tile,
+    /// with the given `limits` source option
+    async fn get_with_limits(
+        tile: &'static [u8],
+        limits: &str,
+    ) -> Result<(Option<String>, Bytes), String> {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(tile),
+        })]]);
+        let srv_config: SrvConfig = serde_yaml::from_str(&format!(
+            "source_options: {{ src: {{ limits: {limits} }} }}"
+        ))
+        .unwrap();
+        let src =
+            DynTileSource::new(&sources, "src", None, "", None, Some(&srv_config), None).unwrap();
+        let req = TestRequest::default().to_http_request();
+        let resp = src
+            .get_http_response(TileCoord { z: 0, x: 0, y: 0 }, &req)
+            .await
+            .map_err(|e| e.to_string())?;
+        let warning = resp
+            .headers()
+            .get(WARNING)
+            .map(|v| v.to_str().unwrap().to_string());
+        Ok((
+            warning,
+            actix_web::body::to_bytes(resp.into_body()).await.unwrap(),
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_limits() {
+        // a layer "a" with three empty features
+        static TILE: &[u8] = &[
+            0x1a, 0x09, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00, 0x12, 0x00,
+        ];
+        let get = |limits| get_with_limits(TILE, limits);
+
+        let (warning, body) = get("{ max_size: 11, max_features: 3 }").await.unwrap();
+        assert_eq!((warning, body.as_ref()), (None, TILE));
+
+        assert_eq!(
+            get("{ max_features: 2 }").await.unwrap_err(),
+            "Tile 0/0/0 of source src has 3 features, more than the limit of 2"
+        );
+        assert_eq!(
+            get("{ max_size: 10 }").await.unwrap_err(),
+            "Tile 0/0/0 of source src has 11 bytes, more than the limit of 10"
+        );
+
+        let (warning, body) = get("{ max_features: 2, on_exceed: truncate }")
+            .await
+            .unwrap();
+        assert_eq!(warning.as_deref(), Some(TRUNCATED_WARNING));
+        assert_eq!(
+            body.as_ref(),
+            &[0x1a, 0x07, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00]
+        );
+
+        let (_, body) = get("{ max_size: 10, on_exceed: truncate }").await.unwrap();
+        assert_eq!(
+            body.as_ref(),
+            &[0x1a, 0x07, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_limits_reduce() {
+        // a layer "a" with two empty features, and a layer "b" with one
+        static TILE: &[u8] = &[
+            0x1a, 0x07, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00, //
+            0x1a, 0x05, 0x0a, 0x01, b'b', 0x12, 0x00,
+        ];
+        let reduce = "reduce: [{ drop_layers: [c] }, { drop_layers: [b] }]";
+
+        let (warning, body) = get_with_limits(TILE, &format!("{{ max_size: 9, {reduce} }}"))
+            .await
+            .unwrap();
+        assert_eq!((warning, body.as_ref()), (None, &TILE[..9]));
+
+        assert_eq!(
+            get_with_limits(TILE, &format!("{{ max_size: 8, {reduce} }}"))
+                .await
+                .unwrap_err(),
+            "Tile 0/0/0 of source src has 9 bytes, more than the limit of 8"
+        );
+
+        let limits = format!("{{ max_size: 8, {reduce}, on_exceed: truncate }}");
+        let (warning, body) = get_with_limits(TILE, &limits).await.unwrap();
+        assert_eq!(warning.as_deref(), Some(TRUNCATED_WARNING));
+        assert_eq!(body.as_ref(), &[0x1a, 0x05, 0x0a, 0x01, b'a', 0x12, 0x00]);
+    }
+
+    #[actix_rt::test]
+    async fn test_tile_limits_cached() {
+        // a layer "a" with three empty features
+        static TILE: &[u8] = &[
+            0x1a, 0x09, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00, 0x12, 0x00,
+        ];
+        static TRUNCATED: &[u8] = &[0x1a, 0x07, 0x0a, 0x01, b'a', 0x12, 0x00, 0x12, 0x00];
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(TILE),
+        })]]);
+        let srv_config: SrvConfig = serde_yaml::from_str(
+            "source_options: { src: { limits: { max_features: 2, on_exceed: truncate } } }",
+        )
+        .unwrap();
+        let cache = MainCache::builder().build();
+        let src = DynTileSource::new(
+            &sources,
+            "src",
+            None,
+            "",
+            None,
+            Some(&srv_config),
+            Some(&cache),
+        )
+        .unwrap();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        for _ in 0..2 {
+            let resp = src.get_tile_response(xyz, None, None).await.unwrap();
+            assert_eq!(resp.header("warning"), Some(TRUNCATED_WARNING));
+            assert_eq!(resp.body.as_ref(), TRUNCATED);
+        }
+        // the truncated tile is cached instead of the tile of the source
+        let Some(CacheValue::Tile(data, _, true)) =
+            cache.get(&CacheKey::Tile("src".to_string(), xyz)).await
+        else {
+            panic!("truncated tile is not cached");
+        };
+        assert_eq!(data.as_ref(), TRUNCATED);
+    }
+
+    #[actix_rt::test]
+    async fn test_data_version() {
+        let sources = TileSources::new(vec![vec![Box::new(TestSource {
+            id: "src",
+            tj: tilejson! { tiles: vec![] },
+            data: TileData::from_static(&[1, 2, 3]),
+        })]]);
+        let cache = MainCache::builder().build();
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        let mut etags = Vec::new();
+        for version in ["", "data_version: '1'", "data_version: '2'"] {
+            let srv_config: SrvConfig =
+                serde_yaml::from_str(&format!("source_options: {{src: {{{version}}}}}")).unwrap();
+            let src = DynTileSource::new(
+                &sources,
+                "src",
+                None,
+                "",
+                None,
+                Some(&srv_config),
+                Some(&cache),
+            )
+            .unwrap();
+            let resp = src.get_tile_response(xyz, None, None).await.unwrap();
+            etags.push(resp.header("etag").unwrap().to_string());
+        }
+        assert_ne!(etags[0], etags[1]);
+        assert_ne!(etags[1], etags[2]);
+        cache.run_pending_tasks().await;
+        // each version is cached separately
+        assert_eq!(cache.entry_count(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_cache_tags() {
+        let sources = TileSources::new(vec![vec![
+            Box::new(TestSource {
+                id: "roads",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }),
+            Box::new(TestSource {
+                id: "water",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[4, 5]),
+            }),
+        ]]);
+        let req = TestRequest::default().to_http_request();
+        let xyz = TileCoord { z: 12, x: 0, y: 0 };
+        for (header, name, value) in [
+            ("surrogate-key", "Surrogate-Key", "src:roads src:water z:12"),
+            ("cache-tag", "Cache-Tag", "src:roads,src:water,z:12"),
+        ] {
+            let srv_config: SrvConfig =
+                serde_yaml::from_str(&format!("cache_tags: {header}")).unwrap();
+            let src = DynTileSource::new(
+                &sources,
+                "roads,water",
+                None,
+                "",
+                None,
+                Some(&srv_config),
+                None,
+            )
+            .unwrap();
+            let resp = src.get_http_response(xyz, &req).await.unwrap();
+            assert_eq!(resp.headers().get(name).unwrap(), value);
+        }
+    }
 }
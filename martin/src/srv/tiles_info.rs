@@ -2,15 +2,25 @@ use std::string::ToString;
 use tokio::sync::RwLock;
 
 use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::{ContentType, HeaderValue};
 use actix_web::http::Uri;
 use actix_web::web::{Data, Path};
 use actix_web::{middleware, route, HttpRequest, HttpResponse, Result as ActixResult};
 use itertools::Itertools as _;
+use martin_tile_utils::{bbox_to_xyz, get_zoom_precision};
 use serde::Deserialize;
-use tilejson::{tilejson, TileJSON};
+use tilejson::{tilejson, Center, TileJSON};
 
 use crate::source::{Source, TileSources};
-use crate::srv::SrvConfig;
+use crate::srv::server::map_internal_error;
+use crate::srv::tiles::with_etag;
+use crate::srv::{SrvConfig, X_ROBOTS_TAG};
+
+/// Highest zoom of a computed center, so that sources with tiny bounds, e.g. a single point,
+/// are not opened at their maximum zoom
+const MAX_CENTER_ZOOM: u8 = 14;
+/// Number of tiles in each direction the bounds should fit in at the zoom of a computed center
+const CENTER_VIEW_TILES: u32 = 2;
 
 #[derive(Deserialize)]
 pub struct SourceIDsRequest {
@@ -61,14 +71,65 @@ async fn get_source_info(
         .map(|tiles_url| tiles_url.to_string())
         .map_err(|e| ErrorBadRequest(format!("Can't build tiles URL: {e}")))?;
 
-    Ok(HttpResponse::Ok().json(merge_tilejson(&sources, tiles_url)))
+    let tilejson = merge_tilejson(&sources, tiles_url);
+    let body = serde_json::to_vec(&tilejson).map_err(map_internal_error)?;
+    let mut response = with_etag(&req, ContentType::json(), body);
+    if sources
+        .iter()
+        .any(|src| srv_config_guard.is_noindex(src.get_id()))
+    {
+        response
+            .headers_mut()
+            .insert(X_ROBOTS_TAG, HeaderValue::from_static("noindex"));
+    }
+    Ok(response)
+}
+
+/// Public URL of the server, with the scheme and host from the request or the forwarding headers.
+/// The path is the one returned by [`public_base_path`].
+pub(crate) fn public_base_url(
+    req: &HttpRequest,
+    base_path: Option<&str>,
+    route_path: &str,
+) -> String {
+    let prefix = public_base_path(req, base_path, route_path);
+    let info = req.connection_info();
+    format!("{}://{}{prefix}", info.scheme(), info.host())
+}
+
+/// The configured `base_path`, or the path under which this request was received,
+/// i.e. the (rewritten) request path without the `route_path` suffix, e.g. `/t/{tenant}` or `/v1`.
+/// Empty if the server is not behind a path prefix.
+pub(crate) fn public_base_path(
+    req: &HttpRequest,
+    base_path: Option<&str>,
+    route_path: &str,
+) -> String {
+    if let Some(base_path) = base_path {
+        base_path.trim_end_matches('/').to_string()
+    } else {
+        let path = req
+            .headers()
+            .get("x-rewrite-url")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Uri>().ok())
+            .map_or_else(|| req.path().to_string(), |v| v.path().to_string());
+        let path = path.trim_end_matches('/');
+        path.strip_suffix(route_path).unwrap_or(path).to_string()
+    }
 }
 
 #[must_use]
 pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
     if sources.len() == 1 {
         let mut tj = sources[0].get_tilejson().clone();
+        if tj.grids.is_some() {
+            tj.grids = Some(vec![tiles_url.replacen("{y}", "{y}.grid.json", 1)]);
+        }
         tj.tiles = vec![tiles_url];
+        if tj.center.is_none() {
+            tj.center = auto_center(&tj);
+        }
         return tj;
     }
 
@@ -154,9 +215,43 @@ pub fn merge_tilejson(sources: &[&dyn Source], tiles_url: String) -> TileJSON {
         result.name = Some(names.into_iter().join(","));
     }
 
+    if result.center.is_none() {
+        result.center = auto_center(&result);
+    }
+
     result
 }
 
+/// A center for a `TileJSON` without one, so that viewers open the source where its data is.
+/// It is the middle of the bounds, at the highest zoom in which the bounds fit in a few tiles,
+/// but not below the `minzoom` of the source. Returns `None` if the bounds are not known.
+#[must_use]
+pub fn auto_center(tilejson: &TileJSON) -> Option<Center> {
+    let bounds = tilejson.bounds?;
+    let minzoom = tilejson.minzoom.unwrap_or(0);
+    let maxzoom = tilejson
+        .maxzoom
+        .unwrap_or(MAX_CENTER_ZOOM)
+        .min(MAX_CENTER_ZOOM)
+        .max(minzoom);
+    let zoom = (minzoom..=maxzoom)
+        .rev()
+        .find(|&zoom| {
+            let (min_x, min_y, max_x, max_y) =
+                bbox_to_xyz(bounds.left, bounds.bottom, bounds.right, bounds.top, zoom);
+            max_x - min_x < CENTER_VIEW_TILES && max_y - min_y < CENTER_VIEW_TILES
+        })
+        .unwrap_or(minzoom);
+    // A hundredth of the precision of the zoom is plenty to position the view, without meaningless digits
+    let precision = get_zoom_precision(zoom.min(MAX_CENTER_ZOOM)) + 2;
+    let round = |v: f64| format!("{v:.precision$}").parse().unwrap_or(v);
+    Some(Center::new(
+        round((bounds.left + bounds.right) / 2.0),
+        round((bounds.bottom + bounds.top) / 2.0),
+        zoom,
+    ))
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::collections::BTreeMap;
@@ -165,6 +260,7 @@ pub mod tests {
 
     use super::*;
     use crate::srv::server::tests::TestSource;
+    use crate::TileData;
 
     #[test]
     fn test_merge_tilejson() {
@@ -184,17 +280,28 @@ pub mod tests {
                     ]))
                 ],
             },
-            data: Vec::default(),
+            data: TileData::new(),
         };
         let tj = merge_tilejson(&[&src1], url.clone());
         assert_eq!(
             TileJSON {
                 tiles: vec![url.clone()],
+                center: Some(Center::new(0.0, 0.0, 5)),
                 ..src1.tj.clone()
             },
             tj
         );
 
+        let mut grid_src = src1.clone();
+        grid_src.tj.grids = Some(vec![]);
+        let tj = merge_tilejson(&[&grid_src], format!("{url}?key=1"));
+        assert_eq!(
+            tj.grids,
+            Some(vec![
+                "http://localhost:8888/foo/{z}/{x}/{y}.grid.json?key=1".to_string()
+            ])
+        );
+
         let src2 = TestSource {
             id: "id",
             tj: tilejson! {
@@ -210,7 +317,7 @@ pub mod tests {
                     ]))
                 ],
             },
-            data: Vec::default(),
+            data: TileData::new(),
         };
 
         let tj = merge_tilejson(&[&src1, &src2], url.clone());
@@ -219,6 +326,7 @@ pub mod tests {
         assert_eq!(tj.minzoom, Some(5));
         assert_eq!(tj.maxzoom, Some(12));
         assert_eq!(tj.bounds, Some(Bounds::new(-20.0, -20.0, 10.0, 50.0)));
+        assert_eq!(tj.center, Some(Center::new(-5.0, 15.0, 5)));
         assert_eq!(
             tj.vector_layers,
             Some(vec![
@@ -233,4 +341,28 @@ pub mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_auto_center() {
+        let mut tj = tilejson! { tiles: vec![] };
+        assert_eq!(auto_center(&tj), None);
+
+        tj.bounds = Some(Bounds::new(-180.0, -85.0, 180.0, 85.0));
+        assert_eq!(auto_center(&tj), Some(Center::new(0.0, 0.0, 1)));
+        tj.minzoom = Some(3);
+        assert_eq!(auto_center(&tj), Some(Center::new(0.0, 0.0, 3)));
+
+        tj.minzoom = None;
+        tj.bounds = Some(Bounds::new(8.5, 47.3, 8.6, 47.4));
+        assert_eq!(auto_center(&tj), Some(Center::new(8.55, 47.35, 11)));
+        tj.maxzoom = Some(8);
+        assert_eq!(auto_center(&tj), Some(Center::new(8.55, 47.35, 8)));
+
+        tj.bounds = Some(Bounds::new(8.5, 47.3, 8.5, 47.3));
+        tj.maxzoom = None;
+        assert_eq!(
+            auto_center(&tj),
+            Some(Center::new(8.5, 47.3, MAX_CENTER_ZOOM))
+        );
+    }
 }
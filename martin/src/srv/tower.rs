@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use tower_service::Service;
+
+use crate::srv::{ServiceRequest, TileService};
+
+/// Serve the [`TileService`] from a `tower` based framework like axum or hyper.
+/// The request body is ignored, and only `GET` and `HEAD` requests are allowed.
+impl<B> Service<Request<B>> for TileService {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let service = self.clone();
+        let method = req.method().clone();
+        let request = ServiceRequest {
+            path: req.uri().path().to_string(),
+            query: req.uri().query().unwrap_or_default().to_string(),
+            headers: req
+                .headers()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+                .collect(),
+        };
+
+        Box::pin(async move {
+            if method != Method::GET && method != Method::HEAD {
+                let mut response = Response::new(Full::default());
+                *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                return Ok(response);
+            }
+
+            let result = service.handle(&request).await;
+            let mut response = Response::builder().status(result.status);
+            for (name, value) in result.headers {
+                response = response.header(name, value);
+            }
+            let body = if method == Method::HEAD {
+                Bytes::new()
+            } else {
+                result.body
+            };
+            Ok(response.body(Full::new(body)).unwrap_or_else(|_| {
+                let mut response = Response::new(Full::default());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt as _;
+    use tilejson::tilejson;
+
+    use super::*;
+    use crate::srv::server::tests::TestSource;
+    use crate::srv::MartinApp;
+    use crate::TileData;
+
+    #[actix_rt::test]
+    async fn test_tower_service() {
+        let mut service = MartinApp::new()
+            .add_source(Box::new(TestSource {
+                id: "custom",
+                tj: tilejson! { tiles: vec![] },
+                data: TileData::from_static(&[1, 2, 3]),
+            }))
+            .service()
+            .unwrap();
+
+        let req = Request::get("/custom/0/0/0").body(()).unwrap();
+        let response = service.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.as_ref(), &[1_u8, 2, 3]);
+
+        let req = Request::head("/custom/0/0/0").body(()).unwrap();
+        let response = service.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("etag"));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+
+        let req = Request::post("/custom/0/0/0").body(()).unwrap();
+        let response = service.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,269 @@
+//! Alternative tile URLs configured per source with the `url_templates` source option,
+//! e.g. to keep serving the URLs of a previous tile server while its clients are migrated.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::uri::PathAndQuery;
+use actix_web::http::Uri;
+use actix_web::web::Data;
+use futures::future::LocalBoxFuture;
+use tokio::sync::RwLock;
+
+use crate::config::ServerState;
+use crate::TileCoord;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Z,
+    X,
+    Y,
+}
+
+/// A parsed URL template of a source, with the `{source}` placeholder already replaced by its ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+impl UrlTemplate {
+    /// Parse a template like `/tiles/{source}/{z}/{x}/{y}.pbf`. It must start with a `/`,
+    /// and contain each of `{z}`, `{x}`, and `{y}` once, separated by other characters.
+    pub fn parse(template: &str, source_id: &str) -> Result<Self, &'static str> {
+        if !template.starts_with('/') {
+            return Err("it must start with a '/'");
+        }
+        if template.contains('?') {
+            return Err("it must not contain a query string");
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or("it has an unclosed '{'")? + start;
+            let mut literal = rest[..start].to_string();
+            let part = match &rest[start + 1..end] {
+                "z" => Some(Part::Z),
+                "x" => Some(Part::X),
+                "y" => Some(Part::Y),
+                "source" => {
+                    literal.push_str(source_id);
+                    None
+                }
+                _ => return Err("only {source}, {z}, {x}, and {y} placeholders are supported"),
+            };
+            Self::push_literal(&mut parts, literal);
+            if let Some(part) = part {
+                if parts.contains(&part) {
+                    return Err("each of {z}, {x}, and {y} must only be used once");
+                }
+                if parts.last().is_some_and(|v| !matches!(v, Part::Literal(_))) {
+                    return Err("{z}, {x}, and {y} must be separated by other characters");
+                }
+                parts.push(part);
+            }
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err("it has an unopened '}'");
+        }
+        Self::push_literal(&mut parts, rest.to_string());
+        if [Part::Z, Part::X, Part::Y]
+            .iter()
+            .any(|part| !parts.contains(part))
+        {
+            return Err("it must contain {z}, {x}, and {y}");
+        }
+        Ok(Self { parts })
+    }
+
+    /// Append a literal, merging it with the previous one, e.g. around a `{source}` placeholder
+    fn push_literal(parts: &mut Vec<Part>, literal: String) {
+        if literal.is_empty() {
+            return;
+        }
+        if let Some(Part::Literal(last)) = parts.last_mut() {
+            last.push_str(&literal);
+        } else {
+            parts.push(Part::Literal(literal));
+        }
+    }
+
+    /// The tile of the path, if it matches the template
+    #[must_use]
+    pub fn matches(&self, path: &str) -> Option<TileCoord> {
+        let mut rest = path;
+        let (mut z, mut x, mut y) = (None, None, None);
+        for part in &self.parts {
+            let target = match part {
+                Part::Literal(literal) => {
+                    rest = rest.strip_prefix(literal.as_str())?;
+                    continue;
+                }
+                Part::Z => &mut z,
+                Part::X => &mut x,
+                Part::Y => &mut y,
+            };
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            *target = Some(rest[..len].parse::<u32>().ok()?);
+            rest = &rest[len..];
+        }
+        let xyz = TileCoord {
+            z: u8::try_from(z?).ok()?,
+            x: x?,
+            y: y?,
+        };
+        (rest.is_empty() && xyz.is_valid()).then_some(xyz)
+    }
+}
+
+/// The standard tile path of the first source with a URL template matching the path, if any
+fn find_tile_path(templates: &[(String, UrlTemplate)], path: &str) -> Option<String> {
+    templates.iter().find_map(|(id, template)| {
+        let xyz = template.matches(path)?;
+        Some(format!("/{id}/{}/{}/{}", xyz.z, xyz.x, xyz.y))
+    })
+}
+
+/// Middleware that rewrites the requests matching a URL template of a source to the standard
+/// `/{source}/{z}/{x}/{y}` tile path before they are routed, so they are served exactly like it.
+/// Only the templates of the main configuration are used, as parsed when it was loaded.
+#[derive(Clone, Default)]
+pub struct UrlTemplates;
+
+impl<S, B> Transform<S, ServiceRequest> for UrlTemplates
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = UrlTemplatesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UrlTemplatesMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct UrlTemplatesMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for UrlTemplatesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let state = req.app_data::<Data<RwLock<ServerState>>>().cloned();
+        Box::pin(async move {
+            let Some(state) = state else {
+                return service.call(req).await;
+            };
+            let tile_path = find_tile_path(&state.read().await.url_templates, req.path());
+            if let Some(tile_path) = tile_path {
+                let path_and_query = match req.query_string() {
+                    "" => tile_path,
+                    query => format!("{tile_path}?{query}"),
+                };
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = PathAndQuery::try_from(path_and_query).ok();
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    req.match_info_mut().get_mut().update(&uri);
+                    req.head_mut().uri = uri;
+                }
+            }
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpRequest};
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let template = UrlTemplate::parse("/{source}@{z}/{x}/{y}.mvt", "roads").unwrap();
+        assert_eq!(
+            template.matches("/roads@3/2/1.mvt"),
+            Some(TileCoord { z: 3, x: 2, y: 1 })
+        );
+        assert_eq!(template.matches("/roads@3/2/1.pbf"), None);
+        assert_eq!(template.matches("/water@3/2/1.mvt"), None);
+        assert_eq!(template.matches("/roads@3/8/1.mvt"), None);
+        assert_eq!(template.matches("/roads@3/2/.mvt"), None);
+
+        let template = UrlTemplate::parse("/tiles/{source}/{z}/{y}/{x}", "a").unwrap();
+        assert_eq!(
+            template.matches("/tiles/a/3/2/1"),
+            Some(TileCoord { z: 3, x: 1, y: 2 })
+        );
+        assert_eq!(template.matches("/tiles/a/3/2/1/0"), None);
+
+        for invalid in [
+            "tiles/{z}/{x}/{y}",
+            "/{z}/{x}/{y}?v=1",
+            "/{z}/{x}",
+            "/{z}/{x}/{y}/{z}",
+            "/{z}{x}/{y}",
+            "/{z}/{x}/{y}/{layer}",
+            "/{z}/{x}/{y",
+            "/{z}/{x}/y}",
+        ] {
+            assert!(UrlTemplate::parse(invalid, "a").is_err(), "{invalid}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_rewrite() {
+        let srv_config: crate::srv::SrvConfig = serde_yaml::from_str(indoc! {"
+            source_options:
+              roads:
+                url_templates:
+                  - /tiles/{source}/{z}/{x}/{y}
+                  - /{source}@{z}/{x}/{y}.mvt
+        "})
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(RwLock::new(ServerState {
+                    url_templates: srv_config.parse_url_templates().unwrap(),
+                    ..Default::default()
+                })))
+                .wrap(UrlTemplates)
+                .default_service(web::to(
+                    |req: HttpRequest| async move { req.uri().to_string() },
+                )),
+        )
+        .await;
+        for (path, expected) in [
+            ("/tiles/roads/1/0/1", "/roads/1/0/1"),
+            ("/roads@1/0/1.mvt?layers=a", "/roads/1/0/1?layers=a"),
+            ("/tiles/water/1/0/1", "/tiles/water/1/0/1"),
+            ("/roads/1/0/1", "/roads/1/0/1"),
+        ] {
+            let req = TestRequest::get().uri(path).to_request();
+            let body = read_body(call_service(&app, req).await).await;
+            assert_eq!(body, expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,614 @@
+//! Tiles and bytes served to each API key by source, e.g. to bill or monitor internal consumers.
+//! The usage is counted in memory, appended to the configured sinks periodically,
+//! and the totals since the start are served at `/admin/usage`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::future::{ready, Ready};
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use async_trait::async_trait;
+use futures::future::LocalBoxFuture;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::net::UdpSocket;
+
+use crate::srv::rate_limit::ServedTiles;
+use crate::srv::API_KEY_HEADER_DEFAULT;
+
+/// How often the usage is written to the sinks, in seconds
+pub const USAGE_FLUSH_INTERVAL_DEFAULT: u64 = 60;
+
+/// Without the list of `api_keys`, at most this many keys are counted separately,
+/// so that clients cannot grow the usage without limit by sending random keys
+const MAX_USAGE_KEYS: usize = 10_000;
+
+/// The key the usage of unknown API keys is counted under
+pub const OTHER_API_KEY: &str = "(other)";
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageConfig {
+    /// Name of the request header with the API key [default: `X-API-Key`]
+    pub key_header: Option<String>,
+    /// Only count these API keys separately, the usage of all other keys is counted under `(other)`.
+    /// Without the list, the first 10000 keys are counted separately.
+    pub api_keys: Option<Vec<String>>,
+    /// How often (in seconds) the usage is written to the sinks [default: 60]
+    pub flush_interval_sec: Option<u64>,
+    /// Where to write the usage of each flush interval to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<UsageSinkConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum UsageSinkConfig {
+    /// Append a `timestamp,api_key,source_id,tiles,bytes` line per API key and source to a CSV file
+    Csv { path: PathBuf },
+    /// Send the `{prefix}.tiles.{api_key}.{source_id}` and `{prefix}.bytes.…` counters to a `StatsD` server
+    Statsd {
+        address: String,
+        /// [default: martin]
+        prefix: Option<String>,
+    },
+    /// Insert a row per API key and source into a table, which is created if it does not exist
+    #[cfg(feature = "postgres")]
+    Postgres {
+        connection_string: String,
+        /// [default: `martin_usage`]
+        table: Option<String>,
+    },
+}
+
+impl UsageSinkConfig {
+    fn make_sink(&self) -> Arc<dyn UsageSink> {
+        match self {
+            Self::Csv { path } => Arc::new(CsvUsageSink(path.clone())),
+            Self::Statsd { address, prefix } => Arc::new(StatsdUsageSink {
+                address: address.clone(),
+                prefix: prefix.clone().unwrap_or_else(|| "martin".to_string()),
+            }),
+            #[cfg(feature = "postgres")]
+            Self::Postgres {
+                connection_string,
+                table,
+            } => Arc::new(PgUsageSink {
+                config: crate::pg::PgConfig {
+                    connection_string: Some(connection_string.clone()),
+                    ..Default::default()
+                },
+                table: table.clone().unwrap_or_else(|| "martin_usage".to_string()),
+                pool: tokio::sync::OnceCell::new(),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageCounter {
+    pub tiles: u64,
+    pub bytes: u64,
+}
+
+/// Usage of a single API key and source during a flush interval
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UsageRecord {
+    /// End of the flush interval
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub api_key: String,
+    pub source_id: String,
+    pub tiles: u64,
+    pub bytes: u64,
+}
+
+/// Destination of the usage records, e.g. to keep the usage in a billing system
+#[async_trait]
+pub trait UsageSink: Send + Sync {
+    async fn write(&self, records: &[UsageRecord]) -> io::Result<()>;
+}
+
+/// Usage by API key and source ID
+pub type UsageByKey = BTreeMap<String, BTreeMap<String, UsageCounter>>;
+
+/// The `/admin/usage` response
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UsageTotals {
+    /// When the usage started to be counted, i.e. when Martin was started
+    #[serde(with = "time::serde::rfc3339")]
+    pub since: OffsetDateTime,
+    pub keys: UsageByKey,
+}
+
+struct UsageState {
+    totals: UsageByKey,
+    /// Usage not written to the sinks yet
+    pending: UsageByKey,
+}
+
+pub struct UsageRecorder {
+    key_header: String,
+    api_keys: Option<HashSet<String>>,
+    flush_interval: Duration,
+    sinks: Vec<Arc<dyn UsageSink>>,
+    since: OffsetDateTime,
+    state: Mutex<UsageState>,
+}
+
+impl UsageRecorder {
+    #[must_use]
+    pub fn new(cfg: &UsageConfig) -> Self {
+        Self {
+            key_header: cfg
+                .key_header
+                .clone()
+                .unwrap_or_else(|| API_KEY_HEADER_DEFAULT.to_string()),
+            api_keys: cfg.api_keys.as_ref().map(|v| v.iter().cloned().collect()),
+            flush_interval: Duration::from_secs(
+                cfg.flush_interval_sec
+                    .unwrap_or(USAGE_FLUSH_INTERVAL_DEFAULT),
+            ),
+            sinks: cfg.sinks.iter().map(UsageSinkConfig::make_sink).collect(),
+            since: OffsetDateTime::now_utc(),
+            state: Mutex::new(UsageState {
+                totals: UsageByKey::new(),
+                pending: UsageByKey::new(),
+            }),
+        }
+    }
+
+    /// Also write the usage to a custom sink
+    #[must_use]
+    pub fn with_sink(mut self, sink: Arc<dyn UsageSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// The usage since the start of the server
+    #[must_use]
+    pub fn totals(&self) -> UsageTotals {
+        UsageTotals {
+            since: self.since,
+            keys: self.state.lock().unwrap().totals.clone(),
+        }
+    }
+
+    /// Count the tiles for each of the requested sources. The tiles of a composite source count for
+    /// each of its sources, and their bytes are split evenly between them.
+    fn record(&self, api_key: &str, served: &ServedTiles, bytes: u64) {
+        let source_ids: Vec<_> = served.source_ids.split(',').collect();
+        let count = source_ids.len() as u64;
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let api_key = self.counted_key(api_key, &state.totals);
+        for (idx, source_id) in source_ids.into_iter().enumerate() {
+            let bytes = bytes / count + if idx == 0 { bytes % count } else { 0 };
+            let mut usages = vec![&mut state.totals];
+            if !self.sinks.is_empty() {
+                usages.push(&mut state.pending);
+            }
+            for usage in usages {
+                let counter = usage
+                    .entry(api_key.to_string())
+                    .or_default()
+                    .entry(source_id.to_string())
+                    .or_default();
+                counter.tiles += served.count as u64;
+                counter.bytes += bytes;
+            }
+        }
+    }
+
+    /// The key to count the usage of an API key under
+    fn counted_key<'a>(&self, api_key: &'a str, totals: &UsageByKey) -> &'a str {
+        let is_known = match &self.api_keys {
+            Some(api_keys) => api_keys.contains(api_key),
+            None => totals.contains_key(api_key) || totals.len() < MAX_USAGE_KEYS,
+        };
+        if is_known {
+            api_key
+        } else {
+            OTHER_API_KEY
+        }
+    }
+
+    /// Write the usage counted since the last flush to all sinks. Failed writes are logged, and the records are lost.
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut self.state.lock().unwrap().pending);
+        let records = to_records(&pending);
+        if records.is_empty() {
+            return;
+        }
+        for sink in &self.sinks {
+            if let Err(e) = sink.write(&records).await {
+                warn!("Unable to write {} usage records: {e}", records.len());
+            }
+        }
+        debug!("Wrote {} usage records", records.len());
+    }
+
+    /// Flush the usage every `flush_interval` for as long as the server is running
+    pub async fn run_flusher(self: Arc<Self>) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let period = self.flush_interval.max(Duration::from_secs(1));
+        loop {
+            actix_rt::time::sleep(period).await;
+            self.flush().await;
+        }
+    }
+}
+
+fn to_records(usage: &UsageByKey) -> Vec<UsageRecord> {
+    let timestamp = OffsetDateTime::now_utc();
+    usage
+        .iter()
+        .flat_map(|(api_key, sources)| {
+            sources.iter().map(move |(source_id, counter)| UsageRecord {
+                timestamp,
+                api_key: api_key.clone(),
+                source_id: source_id.clone(),
+                tiles: counter.tiles,
+                bytes: counter.bytes,
+            })
+        })
+        .collect()
+}
+
+struct CsvUsageSink(PathBuf);
+
+#[async_trait]
+impl UsageSink for CsvUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> io::Result<()> {
+        let mut lines = String::new();
+        for rec in records {
+            let timestamp = rec.timestamp.format(&Rfc3339).map_err(io::Error::other)?;
+            let _ = writeln!(
+                lines,
+                "{timestamp},{},{},{},{}",
+                csv_field(&rec.api_key),
+                csv_field(&rec.source_id),
+                rec.tiles,
+                rec.bytes
+            );
+        }
+        let path = self.0.clone();
+        // the file is written on a blocking thread so that it does not stall the server
+        tokio::task::spawn_blocking(move || {
+            let is_new = !path.exists();
+            let mut file: File = OpenOptions::new().create(true).append(true).open(&path)?;
+            if is_new {
+                file.write_all(b"timestamp,api_key,source_id,tiles,bytes\n")?;
+            }
+            file.write_all(lines.as_bytes())
+        })
+        .await?
+    }
+}
+
+/// Quote a CSV field if needed, see RFC 4180
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+struct StatsdUsageSink {
+    address: String,
+    prefix: String,
+}
+
+#[async_trait]
+impl UsageSink for StatsdUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        // resolve the address once, and without blocking the server
+        socket.connect(&self.address).await?;
+        for line in statsd_lines(&self.prefix, records) {
+            socket.send(line.as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn statsd_lines(prefix: &str, records: &[UsageRecord]) -> Vec<String> {
+    // the metric name separators cannot be part of the names
+    let name = |v: &str| v.replace(['.', ':', '|', '@', ',', ' '], "_");
+    records
+        .iter()
+        .flat_map(|rec| {
+            let path = format!("{}.{}", name(&rec.api_key), name(&rec.source_id));
+            [
+                format!("{prefix}.tiles.{path}:{}|c", rec.tiles),
+                format!("{prefix}.bytes.{path}:{}|c", rec.bytes),
+            ]
+        })
+        .collect()
+}
+
+#[cfg(feature = "postgres")]
+struct PgUsageSink {
+    config: crate::pg::PgConfig,
+    table: String,
+    /// Connected, and the table created, on the first write
+    pool: tokio::sync::OnceCell<crate::pg::PgPool>,
+}
+
+#[cfg(feature = "postgres")]
+impl PgUsageSink {
+    fn table(&self) -> String {
+        self.table
+            .split('.')
+            .map(postgres_protocol::escape::escape_identifier)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    async fn pool(&self) -> crate::pg::PgResult<&crate::pg::PgPool> {
+        self.pool
+            .get_or_try_init(|| async {
+                let pool = crate::pg::PgPool::new(&self.config).await?;
+                let sql = format!(
+                    "CREATE TABLE IF NOT EXISTS {} (
+                        time timestamptz NOT NULL,
+                        api_key text NOT NULL,
+                        source_id text NOT NULL,
+                        tiles bigint NOT NULL,
+                        bytes bigint NOT NULL
+                    )",
+                    self.table()
+                );
+                pool.get().await?.execute(&sql, &[]).await.map_err(|e| {
+                    crate::pg::PgError::PostgresError(e, "creating the usage table")
+                })?;
+                Ok(pool)
+            })
+            .await
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl UsageSink for PgUsageSink {
+    async fn write(&self, records: &[UsageRecord]) -> io::Result<()> {
+        let client = self
+            .pool()
+            .await
+            .map_err(io::Error::other)?
+            .get()
+            .await
+            .map_err(io::Error::other)?;
+        let sql = format!(
+            "INSERT INTO {} (time, api_key, source_id, tiles, bytes) VALUES ($1::text::timestamptz, $2, $3, $4, $5)",
+            self.table()
+        );
+        for rec in records {
+            let timestamp = rec.timestamp.format(&Rfc3339).map_err(io::Error::other)?;
+            #[allow(clippy::cast_possible_wrap)]
+            client
+                .execute(
+                    &sql,
+                    &[
+                        &timestamp,
+                        &rec.api_key,
+                        &rec.source_id,
+                        &(rec.tiles as i64),
+                        &(rec.bytes as i64),
+                    ],
+                )
+                .await
+                .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Middleware that counts the tiles and bytes of successful tile responses for each API key.
+/// Requests without an API key are not counted. Does nothing if there is no recorder.
+#[derive(Clone, Default)]
+pub struct UsageTracking(pub Option<Arc<UsageRecorder>>);
+
+impl<S, B> Transform<S, ServiceRequest> for UsageTracking
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = UsageTrackingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UsageTrackingMiddleware {
+            service: Rc::new(service),
+            recorder: self.0.clone(),
+        }))
+    }
+}
+
+pub struct UsageTrackingMiddleware<S> {
+    service: Rc<S>,
+    recorder: Option<Arc<UsageRecorder>>,
+}
+
+impl<S, B> Service<ServiceRequest> for UsageTrackingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let recorder = self.recorder.clone();
+        let api_key = recorder.as_ref().and_then(|r| {
+            let value = req.headers().get(r.key_header.as_str())?;
+            value.to_str().ok().map(ToString::to_string)
+        });
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            if let (Some(recorder), Some(api_key)) = (recorder, api_key) {
+                let extensions = response.response().extensions();
+                let served = extensions.get::<ServedTiles>();
+                if let Some(served) = served.filter(|_| response.status().is_success()) {
+                    let bytes = match response.response().body().size() {
+                        BodySize::Sized(size) => size,
+                        BodySize::None | BodySize::Stream => 0,
+                    };
+                    recorder.record(&api_key, served, bytes);
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+    use crate::srv::rate_limit::ServedTiles;
+
+    struct MemorySink(Mutex<Vec<UsageRecord>>);
+
+    #[async_trait]
+    impl UsageSink for MemorySink {
+        async fn write(&self, records: &[UsageRecord]) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(records);
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_usage_tracking() {
+        let sink = Arc::new(MemorySink(Mutex::new(Vec::new())));
+        let cfg = UsageConfig {
+            api_keys: Some(vec!["a".to_string(), "b".to_string()]),
+            ..Default::default()
+        };
+        let recorder = Arc::new(UsageRecorder::new(&cfg).with_sink(sink.clone()));
+        let app = init_service(
+            App::new()
+                .wrap(UsageTracking(Some(recorder.clone())))
+                .route(
+                    "/{source_ids}/{z}/{x}/{y}",
+                    web::get().to(|path: web::Path<(String, u8, u32, u32)>| async move {
+                        let mut response = HttpResponse::Ok().body("tile");
+                        response
+                            .extensions_mut()
+                            .insert(ServedTiles::new(1, &path.0));
+                        response
+                    }),
+                )
+                .route("/catalog", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |uri: &'static str, key: Option<&'static str>| {
+            let req = TestRequest::get().uri(uri);
+            match key {
+                Some(key) => req.insert_header((API_KEY_HEADER_DEFAULT, key)),
+                None => req,
+            }
+            .to_request()
+        };
+
+        call_service(&app, get("/roads/0/0/0", Some("a"))).await;
+        call_service(&app, get("/roads/1/0/0", Some("a"))).await;
+        call_service(&app, get("/roads,water/1/0/0", Some("b"))).await;
+        call_service(&app, get("/roads/1/0/0", Some("unknown"))).await;
+        // not counted: no API key, not a tile
+        call_service(&app, get("/roads/0/0/0", None)).await;
+        call_service(&app, get("/catalog", Some("a"))).await;
+
+        let totals = recorder.totals();
+        let counter = |tiles, bytes| UsageCounter { tiles, bytes };
+        assert_eq!(
+            totals.keys,
+            BTreeMap::from([
+                (
+                    "a".to_string(),
+                    BTreeMap::from([("roads".to_string(), counter(2, 8))])
+                ),
+                (
+                    "b".to_string(),
+                    // the tile counts for both sources, and its bytes are split between them
+                    BTreeMap::from([
+                        ("roads".to_string(), counter(1, 2)),
+                        ("water".to_string(), counter(1, 2)),
+                    ])
+                ),
+                (
+                    OTHER_API_KEY.to_string(),
+                    BTreeMap::from([("roads".to_string(), counter(1, 4))])
+                ),
+            ])
+        );
+
+        recorder.flush().await;
+        let records = sink.0.lock().unwrap().clone();
+        let tiles: u64 = records.iter().map(|r| r.tiles).sum();
+        assert_eq!(tiles, 5);
+        // the flushed usage is not written again
+        recorder.flush().await;
+        assert_eq!(sink.0.lock().unwrap().len(), records.len());
+    }
+
+    #[test]
+    fn test_max_usage_keys() {
+        let recorder = UsageRecorder::new(&UsageConfig::default());
+        let served = ServedTiles::new(1, "roads");
+        for idx in 0..=MAX_USAGE_KEYS {
+            recorder.record(&format!("key{idx}"), &served, 0);
+        }
+        recorder.record("key0", &served, 0);
+        let keys = recorder.totals().keys;
+        assert_eq!(keys.len(), MAX_USAGE_KEYS + 1);
+        assert_eq!(keys["key0"]["roads"].tiles, 2);
+        assert_eq!(keys[OTHER_API_KEY]["roads"].tiles, 1);
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("key"), "key");
+        assert_eq!(csv_field("roads,water"), "\"roads,water\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        let records = [UsageRecord {
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            api_key: "team.a".to_string(),
+            source_id: "roads.water".to_string(),
+            tiles: 3,
+            bytes: 1024,
+        }];
+        assert_eq!(
+            statsd_lines("martin", &records),
+            vec![
+                "martin.tiles.team_a.roads_water:3|c",
+                "martin.bytes.team_a.roads_water:1024|c"
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! API versioning. All endpoints are served both without a prefix and under `/v1`,
+//! and clients can pin the version they were written for with the `Martin-Api-Version` header.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use futures::future::LocalBoxFuture;
+
+/// Current version of the HTTP API. It only changes when existing URLs or responses change incompatibly.
+pub const API_VERSION: &str = "1";
+
+/// Request header pinning a client to an API version, also returned with every response
+pub const API_VERSION_HEADER: &str = "Martin-Api-Version";
+
+/// Path prefix of the routes of the current API version
+pub const API_VERSION_PREFIX: &str = "/v1";
+
+/// Middleware that rejects requests pinned to an API version this server does not provide
+/// with `400 Bad Request`, and adds the `Martin-Api-Version` header to all responses.
+#[derive(Clone, Default)]
+pub struct ApiVersion;
+
+impl<S, B> Transform<S, ServiceRequest> for ApiVersion
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ApiVersionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiVersionMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ApiVersionMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiVersionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let requested = req
+            .headers()
+            .get(API_VERSION_HEADER)
+            .map(|v| v.to_str().unwrap_or_default().trim().to_string());
+        if let Some(requested) = requested.filter(|v| v != API_VERSION) {
+            let response = HttpResponse::BadRequest()
+                .insert_header((API_VERSION_HEADER, API_VERSION))
+                .body(format!(
+                    "API version {requested} is not supported, this server provides version {API_VERSION}"
+                ));
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut response = fut.await?;
+            response.headers_mut().insert(
+                HeaderName::from_static("martin-api-version"),
+                HeaderValue::from_static(API_VERSION),
+            );
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_api_version() {
+        let app = init_service(
+            App::new()
+                .wrap(ApiVersion)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::get().to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(API_VERSION_HEADER).unwrap(), "1");
+
+        let req = TestRequest::get()
+            .insert_header((API_VERSION_HEADER, "1"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = TestRequest::get()
+            .insert_header((API_VERSION_HEADER, "2"))
+            .to_request();
+        let response = call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(API_VERSION_HEADER).unwrap(), "1");
+    }
+}
@@ -0,0 +1,123 @@
+//! Webhooks notified when tile sources are added, removed, or changed by a refresh or a config file reload,
+//! so that downstream systems like style builders or CDNs can react to the changes.
+
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::srv::CatalogDiff;
+
+/// Header with the HMAC-SHA256 signature of the request body, sent if the webhook has a secret
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Martin-Signature";
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct WebhookConfig {
+    /// URL that receives the changes as a JSON `POST` request
+    pub url: String,
+    /// Sign the request body with HMAC-SHA256 using this secret. The signature is sent
+    /// in the `X-Martin-Signature` header as `sha256=<hex digest>`.
+    pub secret: Option<String>,
+}
+
+/// Body of the webhook requests
+#[derive(Debug, Serialize)]
+struct CatalogChanged<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    diff: &'a CatalogDiff,
+    version: &'static str,
+}
+
+/// Send the changes to all webhooks in the background. Nothing is sent if nothing has changed.
+/// Failed requests are logged, but not retried.
+pub fn notify_webhooks(webhooks: &[WebhookConfig], diff: &CatalogDiff) {
+    if webhooks.is_empty() || diff.is_empty() {
+        return;
+    }
+    let body = match webhook_body(diff) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Unable to serialize the catalog changes for the webhooks: {e}");
+            return;
+        }
+    };
+    let webhooks = webhooks.to_vec();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        for webhook in webhooks {
+            let mut request = client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(secret) = &webhook.secret {
+                request =
+                    request.header(WEBHOOK_SIGNATURE_HEADER, webhook_signature(secret, &body));
+            }
+            match request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                Ok(_) => debug!("Sent the catalog changes to {}", webhook.url),
+                Err(e) => warn!("Unable to send the catalog changes to {}: {e}", webhook.url),
+            }
+        }
+    });
+}
+
+fn webhook_body(diff: &CatalogDiff) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&CatalogChanged {
+        event: "catalog_changed",
+        diff,
+        version: VERSION,
+    })
+}
+
+/// The `X-Martin-Signature` header value of a request body, which receivers can compute the same way
+/// to verify that the request was sent by Martin
+#[must_use]
+pub fn webhook_signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            webhook_signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_webhook_body() {
+        let diff = CatalogDiff {
+            added: vec!["new".to_string()],
+            changed: vec!["roads".to_string()],
+            ..Default::default()
+        };
+        let body: serde_json::Value =
+            serde_json::from_slice(&webhook_body(&diff).unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "event": "catalog_changed",
+                "added": ["new"],
+                "removed": [],
+                "changed": ["roads"],
+                "version": VERSION,
+            })
+        );
+    }
+}
@@ -0,0 +1,368 @@
+//! Tile sources of another Martin server, so that this server can act as a caching edge for it.
+//! The sources are discovered from the `/catalog` of the upstream server, their `TileJSON` is copied,
+//! and their tiles are fetched on demand and kept in the main cache of this server.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use futures::stream;
+use log::info;
+use martin_tile_utils::{Encoding, Format, TileInfo};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tilejson::TileJSON;
+
+use crate::source::{CatalogSourceEntry, Source, TileData, TileInfoSources, TileStream, UrlQuery};
+use crate::upstream::UpstreamError::{InvalidResponse, RequestFailed, UnsupportedContentType};
+use crate::{IdResolver, MartinError, MartinResult, TileCoord};
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpstreamError {
+    #[error("Unable to fetch {1}: {0}")]
+    RequestFailed(#[source] reqwest::Error, String),
+
+    #[error("Invalid response from {1}: {0}")]
+    InvalidResponse(String, String),
+
+    #[error("Source {1} of the upstream server {2} has the unsupported content type {0}")]
+    UnsupportedContentType(String, String, String),
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamConfig {
+    /// Base URL of the upstream Martin server, e.g. `http://origin:3000`
+    pub url: String,
+    /// Only serve these sources of the upstream server. All sources of its catalog are served if not set.
+    pub sources: Option<Vec<String>>,
+}
+
+/// The part of the upstream catalog describing the tile sources
+#[derive(Deserialize)]
+struct UpstreamCatalog {
+    tiles: BTreeMap<String, CatalogSourceEntry>,
+}
+
+impl UpstreamConfig {
+    fn base_url(&self) -> &str {
+        self.url.trim_end_matches('/')
+    }
+
+    /// Get the sources listed in the catalog of the upstream server, with their `TileJSON`
+    pub async fn resolve(&self, idr: IdResolver) -> MartinResult<TileInfoSources> {
+        let client = Client::new();
+        let base_url = self.base_url();
+        let catalog: UpstreamCatalog = get_json(&client, &format!("{base_url}/catalog")).await?;
+
+        let mut sources = TileInfoSources::new();
+        for (upstream_id, entry) in catalog.tiles {
+            if let Some(ids) = &self.sources {
+                if !ids.contains(&upstream_id) {
+                    continue;
+                }
+            }
+            let info = tile_info(&entry).ok_or_else(|| {
+                UnsupportedContentType(
+                    entry.content_type.clone(),
+                    upstream_id.clone(),
+                    base_url.to_string(),
+                )
+            })?;
+            let mut tilejson: TileJSON =
+                get_json(&client, &format!("{base_url}/{upstream_id}")).await?;
+            // the tile URLs are set by this server
+            tilejson.tiles = vec![];
+            let id = idr.resolve(&upstream_id, format!("{base_url}/{upstream_id}"));
+            info!("Serving source {upstream_id} of the upstream server {base_url} as {id}");
+            sources.push(Box::new(UpstreamSource {
+                id,
+                tiles_url: format!("{base_url}/{upstream_id}"),
+                tilejson,
+                info,
+                client: client.clone(),
+            }));
+        }
+        Ok(sources)
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(client: &Client, url: &str) -> MartinResult<T> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .map_err(|e| RequestFailed(e, url.to_string()))?;
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| RequestFailed(e, url.to_string()))?;
+    Ok(serde_json::from_slice(&body)
+        .map_err(|e| InvalidResponse(e.to_string(), url.to_string()))?)
+}
+
+/// The format and the stored encoding of the tiles of a catalog entry
+fn tile_info(entry: &CatalogSourceEntry) -> Option<TileInfo> {
+    let format = [
+        Format::Mvt,
+        Format::Png,
+        Format::Jpeg,
+        Format::Webp,
+        Format::Gif,
+        Format::Json,
+    ]
+    .into_iter()
+    .find(|f| f.content_type() == entry.content_type)?;
+    let info = TileInfo::from(format);
+    Some(match entry.content_encoding.as_deref() {
+        None => info,
+        Some("gzip") => info.encoding(Encoding::Gzip),
+        Some("br") => info.encoding(Encoding::Brotli),
+        Some("zstd") => info.encoding(Encoding::Zstd),
+        Some("deflate") => info.encoding(Encoding::Zlib),
+        Some(_) => None?,
+    })
+}
+
+/// A tile source of an upstream Martin server
+#[derive(Clone, Debug)]
+pub struct UpstreamSource {
+    id: String,
+    /// URL of the source on the upstream server, without the `/{z}/{x}/{y}` suffix
+    tiles_url: String,
+    tilejson: TileJSON,
+    info: TileInfo,
+    client: Client,
+}
+
+#[async_trait]
+impl Source for UpstreamSource {
+    fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    fn get_tilejson(&self) -> &TileJSON {
+        &self.tilejson
+    }
+
+    fn get_tile_info(&self) -> TileInfo {
+        self.info
+    }
+
+    fn clone_source(&self) -> Box<dyn Source> {
+        Box::new(self.clone())
+    }
+
+    /// The query is passed on, e.g. to the function sources of the upstream server
+    fn support_url_query(&self) -> bool {
+        true
+    }
+
+    fn get_source_type(&self) -> &'static str {
+        "upstream"
+    }
+
+    async fn get_tile(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<TileData> {
+        let (request, url) = self.request(Method::GET, xyz, url_query);
+        let Some(response) = send(request, &url).await? else {
+            return Ok(TileData::new());
+        };
+        Ok(response
+            .bytes()
+            .await
+            .map_err(|e| RequestFailed(e, url.clone()))?)
+    }
+
+    /// The `Content-Length` of a `HEAD` request to the upstream server
+    async fn get_tile_size(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<usize>> {
+        let (request, url) = self.request(Method::HEAD, xyz, url_query);
+        let Some(response) = send(request, &url).await? else {
+            return Ok(Some(0));
+        };
+        // `Response::content_length` is the size of the (empty) body of a HEAD response, not the header
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok()))
+    }
+
+    async fn get_tile_stream(
+        &self,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> MartinResult<Option<TileStream>> {
+        let (request, url) = self.request(Method::GET, xyz, url_query);
+        let Some(response) = send(request, &url).await? else {
+            return Ok(Some(Box::pin(stream::empty())));
+        };
+        let chunks = stream::try_unfold(response, move |mut response| {
+            let url = url.clone();
+            async move {
+                let chunk = response.chunk().await.map_err(|e| RequestFailed(e, url))?;
+                Ok::<_, MartinError>(chunk.map(|chunk| (chunk, response)))
+            }
+        });
+        Ok(Some(Box::pin(chunks)))
+    }
+}
+
+impl UpstreamSource {
+    /// A request for a tile and its URL
+    fn request(
+        &self,
+        method: Method,
+        xyz: TileCoord,
+        url_query: Option<&UrlQuery>,
+    ) -> (RequestBuilder, String) {
+        let url = format!("{}/{xyz:#}", self.tiles_url);
+        let mut request = self
+            .client
+            .request(method, &url)
+            // get the tiles as stored, compressed or not, so that this server decides on the encoding
+            .header(
+                "Accept-Encoding",
+                self.info.encoding.content_encoding().unwrap_or("identity"),
+            );
+        if let Some(query) = url_query {
+            request = request.query(query);
+        }
+        (request, url)
+    }
+}
+
+/// Send a tile request, returning `None` if the upstream server has no such tile
+async fn send(request: RequestBuilder, url: &str) -> MartinResult<Option<Response>> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| RequestFailed(e, url.to_string()))?;
+    if matches!(
+        response.status(),
+        StatusCode::NO_CONTENT | StatusCode::NOT_FOUND
+    ) {
+        return Ok(None);
+    }
+    Ok(Some(
+        response
+            .error_for_status()
+            .map_err(|e| RequestFailed(e, url.to_string()))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use actix_web::body::to_bytes;
+    use actix_web::http::header::ETAG;
+    use actix_web::test::TestRequest;
+    use futures::TryStreamExt as _;
+
+    use super::*;
+    use crate::args::{Args, OsEnv};
+    use crate::debug::{DebugConfig, DebugFormat, DebugSource};
+    use crate::srv::{new_server, DynTileSource, SrvConfig, RESERVED_KEYWORDS};
+    use crate::Config;
+    use crate::TileSources;
+
+    #[test]
+    fn test_tile_info() {
+        let entry = |content_type: &str, content_encoding: Option<&str>| CatalogSourceEntry {
+            content_type: content_type.to_string(),
+            content_encoding: content_encoding.map(ToString::to_string),
+            ..Default::default()
+        };
+        assert_eq!(
+            tile_info(&entry("application/x-protobuf", Some("gzip"))),
+            Some(TileInfo::new(Format::Mvt, Encoding::Gzip))
+        );
+        assert_eq!(
+            tile_info(&entry("image/png", None)),
+            Some(TileInfo::new(Format::Png, Encoding::Internal))
+        );
+        assert_eq!(tile_info(&entry("text/plain", None)), None);
+        assert_eq!(tile_info(&entry("image/png", Some("compress"))), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_upstream_source() {
+        let mut config = Config {
+            debug: Some(BTreeMap::from([
+                ("vector".to_string(), DebugConfig::default()),
+                (
+                    "raster".to_string(),
+                    DebugConfig {
+                        format: Some(DebugFormat::Png),
+                        maxzoom: Some(5),
+                        ..Default::default()
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+        config.finalize().unwrap();
+        let state = config.resolve().await.unwrap();
+        let srv = SrvConfig {
+            listen_addresses: Some("127.0.0.1:0".to_string()),
+            worker_processes: Some(1),
+            ..Default::default()
+        };
+        let (server, address) = new_server(OsEnv::default(), Args::default(), srv, state).unwrap();
+        actix_rt::spawn(server);
+
+        let upstream = UpstreamConfig {
+            url: format!("http://{address}/"),
+            sources: Some(vec!["vector".to_string()]),
+        };
+        let sources = upstream
+            .resolve(IdResolver::new(RESERVED_KEYWORDS))
+            .await
+            .unwrap();
+        assert_eq!(sources.len(), 1);
+        let src = &sources[0];
+        assert_eq!(src.get_id(), "vector");
+        assert_eq!(src.get_source_type(), "upstream");
+        assert_eq!(
+            src.get_tile_info(),
+            TileInfo::new(Format::Mvt, Encoding::Uncompressed)
+        );
+        assert!(src.get_tilejson().tiles.is_empty());
+        assert!(src.get_tilejson().vector_layers.is_some());
+
+        let xyz = TileCoord { z: 3, x: 2, y: 5 };
+        let expected = DebugSource::new("vector".to_string(), &DebugConfig::default());
+        let tile = expected.get_tile(xyz, None).await.unwrap();
+        assert_eq!(src.get_tile(xyz, None).await.unwrap(), tile);
+        assert_eq!(
+            src.get_tile_size(xyz, None).await.unwrap(),
+            Some(tile.len())
+        );
+        let chunks = src.get_tile_stream(xyz, None).await.unwrap().unwrap();
+        let streamed: Vec<_> = chunks.try_collect().await.unwrap();
+        assert_eq!(streamed.concat(), tile);
+
+        // tiles of at least stream_min_size bytes are streamed, without an ETag
+        let sources = TileSources::new(vec![sources]);
+        let req = TestRequest::get().to_http_request();
+        for (stream_min_size, is_streamed) in [(tile.len(), true), (tile.len() + 1, false)] {
+            let srv = SrvConfig {
+                stream_min_size: Some(stream_min_size),
+                ..Default::default()
+            };
+            let dyn_src =
+                DynTileSource::new(&sources, "vector", None, "", None, Some(&srv), None).unwrap();
+            let resp = dyn_src.get_http_response(xyz, &req).await.unwrap();
+            assert_eq!(resp.headers().contains_key(ETAG), !is_streamed);
+            assert_eq!(to_bytes(resp.into_body()).await.unwrap(), tile);
+        }
+    }
+}
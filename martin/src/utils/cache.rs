@@ -1,3 +1,10 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use futures::TryFutureExt as _;
+use log::{trace, warn};
+use martin_tile_utils::Encoding;
 use moka::future::Cache;
 
 use crate::{TileCoord, TileData};
@@ -14,11 +21,14 @@ pub enum CacheKey {
     Tile(String, TileCoord),
     /// (`source_id`, `xyz`, `url_query`)
     TileWithQuery(String, TileCoord, String),
+    /// (`source_ids`, `xyz`, `url_query`, `encoding`) - a (possibly composite) tile re-encoded for the client
+    EncodedTile(String, TileCoord, Option<String>, Encoding),
 }
 
 #[derive(Debug, Clone)]
 pub enum CacheValue {
-    Tile(TileData),
+    /// Tile data, the time it was generated, and whether it was truncated to the limits of its source
+    Tile(TileData, Instant, bool),
     #[cfg(feature = "pmtiles")]
     PmtDirectory(pmtiles::Directory),
 }
@@ -36,6 +46,7 @@ macro_rules! trace_cache {
     };
 }
 
+#[cfg(feature = "pmtiles")]
 macro_rules! from_cache_value {
     ($value_type: path, $data: expr, $key: expr) => {
         #[allow(irrefutable_let_patterns)]
@@ -69,25 +80,160 @@ macro_rules! get_cached_value {
     };
 }
 
-macro_rules! get_or_insert_cached_value {
-    ($cache: expr, $value_type: path, $make_item:expr, $make_key: expr) => {{
-        if let Some(cache) = $cache {
-            let key = $make_key;
-            Ok(if let Some(data) = cache.get(&key).await {
-                $crate::utils::cache::trace_cache!("HIT", cache, key);
-                $crate::utils::cache::from_cache_value!($value_type, data, key)
-            } else {
-                $crate::utils::cache::trace_cache!("MISS", cache, key);
-                let data = $make_item.await?;
-                cache.insert(key, $value_type(data.clone())).await;
-                data
-            })
-        } else {
-            $make_item.await
+/// How long cached tiles stay valid, and what to do once they expire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TileCachePolicy {
+    /// Tiles older than this are generated again. Tiles never expire if not set.
+    pub ttl: Option<Duration>,
+    /// If generating a tile fails, use the expired cached tile instead of returning an error
+    pub serve_stale_on_error: bool,
+}
+
+/// A tile, and whether it is an expired copy served because the tile could not be generated
+pub struct CachedTile {
+    pub data: TileData,
+    pub is_stale: bool,
+    /// The tile was truncated to the limits of its source before it was cached
+    pub is_truncated: bool,
+}
+
+/// Get a tile from the cache, or generate it with `make_item` and cache it.
+pub async fn get_or_insert_tile<E: Display>(
+    cache: Option<&MainCache>,
+    policy: TileCachePolicy,
+    make_item: impl Future<Output = Result<TileData, E>>,
+    make_key: impl FnOnce() -> CacheKey,
+) -> Result<CachedTile, E> {
+    let make_item = make_item.map_ok(|data| (data, false));
+    get_or_insert_limited_tile(cache, policy, make_item, make_key).await
+}
+
+/// Same as [`get_or_insert_tile`], for the tiles that `make_item` may truncate to the limits of their source.
+/// The flag is cached together with the tile.
+pub async fn get_or_insert_limited_tile<E: Display>(
+    cache: Option<&MainCache>,
+    policy: TileCachePolicy,
+    make_item: impl Future<Output = Result<(TileData, bool), E>>,
+    make_key: impl FnOnce() -> CacheKey,
+) -> Result<CachedTile, E> {
+    let Some(cache) = cache else {
+        let (data, is_truncated) = make_item.await?;
+        return Ok(CachedTile {
+            data,
+            is_stale: false,
+            is_truncated,
+        });
+    };
+
+    let key = make_key();
+    let mut stale = None;
+    if let Some(CacheValue::Tile(data, created, is_truncated)) = cache.get(&key).await {
+        if policy.ttl.map_or(true, |ttl| created.elapsed() < ttl) {
+            trace_cache!("HIT", cache, key);
+            return Ok(CachedTile {
+                data,
+                is_stale: false,
+                is_truncated,
+            });
+        }
+        trace_cache!("EXPIRED", cache, key);
+        stale = Some((data, is_truncated));
+    } else {
+        trace_cache!("MISS", cache, key);
+    }
+
+    // The error must not be kept across the await point, it might not be `Send`
+    let (data, is_truncated) = match make_item.await {
+        Ok(item) => item,
+        Err(e) => {
+            return match stale {
+                Some((data, is_truncated)) if policy.serve_stale_on_error => {
+                    warn!("Serving expired tile {key:?} from cache because of an error: {e}");
+                    Ok(CachedTile {
+                        data,
+                        is_stale: true,
+                        is_truncated,
+                    })
+                }
+                _ => Err(e),
+            }
         }
-    }};
+    };
+    cache
+        .insert(
+            key,
+            CacheValue::Tile(data.clone(), Instant::now(), is_truncated),
+        )
+        .await;
+    Ok(CachedTile {
+        data,
+        is_stale: false,
+        is_truncated,
+    })
+}
+
+/// Remove the cached tiles of the sources, including the composite tiles that contain any of them.
+/// The cache must be built with `support_invalidation_closures`.
+pub fn invalidate_sources(
+    cache: &MainCache,
+    source_ids: &[String],
+) -> Result<(), moka::PredicateError> {
+    let ids = source_ids.to_vec();
+    cache
+        .invalidate_entries_if(move |key, _| match key {
+            CacheKey::Tile(id, _) | CacheKey::TileWithQuery(id, _, _) => ids.contains(id),
+            CacheKey::EncodedTile(composite, _, _, _) => {
+                composite.split(',').any(|id| ids.iter().any(|v| v == id))
+            }
+            CacheKey::PmtDirectory(..) => false,
+        })
+        .map(|_| ())
 }
 
 #[cfg(feature = "pmtiles")]
-pub(crate) use get_cached_value;
-pub(crate) use {from_cache_value, get_or_insert_cached_value, trace_cache};
+pub(crate) use {from_cache_value, get_cached_value, trace_cache};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(
+        policy: TileCachePolicy,
+        cache: &MainCache,
+        data: Option<u8>,
+    ) -> Result<CachedTile, String> {
+        let key = || CacheKey::Tile("src".to_string(), TileCoord { z: 0, x: 0, y: 0 });
+        let item = async move {
+            data.map(|v| TileData::from(vec![v]))
+                .ok_or_else(|| "failed".to_string())
+        };
+        get_or_insert_tile(Some(cache), policy, item, key).await
+    }
+
+    #[actix_rt::test]
+    async fn stale_on_error() {
+        let cache = MainCache::new(100);
+
+        let policy = TileCachePolicy::default();
+        let tile = get(policy, &cache, Some(1)).await.unwrap();
+        assert_eq!((tile.data.as_ref(), tile.is_stale), (&[1][..], false));
+        // tiles never expire by default
+        let tile = get(policy, &cache, None).await.unwrap();
+        assert_eq!((tile.data.as_ref(), tile.is_stale), (&[1][..], false));
+
+        let policy = TileCachePolicy {
+            ttl: Some(Duration::ZERO),
+            serve_stale_on_error: false,
+        };
+        assert!(get(policy, &cache, None).await.is_err());
+        let tile = get(policy, &cache, Some(2)).await.unwrap();
+        assert_eq!((tile.data.as_ref(), tile.is_stale), (&[2][..], false));
+
+        let policy = TileCachePolicy {
+            ttl: Some(Duration::ZERO),
+            serve_stale_on_error: true,
+        };
+        let tile = get(policy, &cache, None).await.unwrap();
+        assert_eq!((tile.data.as_ref(), tile.is_stale), (&[2][..], true));
+    }
+}
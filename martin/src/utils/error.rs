@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fmt::Write as _;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// A convenience [`Result`] for Martin crate.
 pub type MartinResult<T> = Result<T, MartinError>;
@@ -37,15 +38,62 @@ pub enum MartinError {
     #[error("Base path must be a valid URL path, and must begin with a '/' symbol, but is '{0}'")]
     BasePathError(String),
 
+    #[error("Invalid response header '{0}' configured for source {1}")]
+    InvalidSourceHeader(String, String),
+
+    #[error("Invalid URL template '{0}' configured for source {1}: {2}")]
+    InvalidUrlTemplate(String, String, &'static str),
+
+    #[error("Invalid CORS configuration: {0}")]
+    CorsConfigError(String),
+
+    #[error("Invalid value of the {0} security header")]
+    SecurityHeaderError(String),
+
+    #[error("Invalid fallback source {0}: {1}")]
+    FallbackSourceError(String, String),
+
+    #[error("Source {0} is temporarily unavailable")]
+    SourceUnavailable(String, Duration),
+
+    #[error("Source {0} did not return a tile within {1:?}")]
+    SourceTimeout(String, Duration),
+
+    #[error("{0}")]
+    TileLimitExceeded(String),
+
     #[error("Unable to load config file {}: {0}", .1.display())]
     ConfigLoadError(io::Error, PathBuf),
 
+    #[error("Unable to fetch config file {1}: {0}")]
+    ConfigFetchError(String, String),
+
     #[error("Unable to parse config file {}: {0}", .1.display())]
     ConfigParseError(subst::yaml::Error, PathBuf),
 
     #[error("Unable to write config file {}: {0}", .1.display())]
     ConfigWriteError(io::Error, PathBuf),
 
+    #[error("Invalid error reporting configuration, unable to parse {0}")]
+    InvalidErrorReporting(String),
+
+    #[error("Unable to open log file {}: {0}", .1.display())]
+    LogFileError(#[source] io::Error, PathBuf),
+
+    #[error("The file log output requires the log.file.path setting")]
+    MissingLogFilePath,
+
+    #[error(
+        "Invalid log level {0}, expected comma-separated directives like info,martin::pg=debug"
+    )]
+    InvalidLogLevel(String),
+
+    #[error("Duplicate source IDs: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    IdCollisions(Vec<crate::IdCollision>),
+
+    #[error("Invalid tenant {0}: {1}")]
+    InvalidTenant(String, &'static str),
+
     #[error("No tile sources found. Set sources by giving a database connection string on command line, env variable, or a config file.")]
     NoSources,
 
@@ -75,12 +123,27 @@ pub enum MartinError {
     #[error(transparent)]
     FontError(#[from] crate::fonts::FontError),
 
+    #[cfg(feature = "metrics")]
+    #[error("Invalid metrics configuration: {0}")]
+    MetricsError(#[from] prometheus::Error),
+
+    #[cfg(feature = "wasm")]
     #[error(transparent)]
-    WebError(#[from] actix_web::Error),
+    PluginError(#[from] crate::plugins::PluginError),
+
+    #[cfg(feature = "raster")]
+    #[error(transparent)]
+    RasterError(#[from] crate::raster::RasterError),
+
+    #[error(transparent)]
+    UpstreamError(#[from] crate::upstream::UpstreamError),
 
     #[error(transparent)]
     IoError(#[from] io::Error),
 
+    #[error("Unable to run the command: {0}")]
+    CommandError(String),
+
     #[error("Internal error: {0}")]
     InternalError(#[from] Box<dyn Error + Send + Sync>),
 }
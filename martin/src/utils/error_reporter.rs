@@ -0,0 +1,316 @@
+//! Reports panics and sources that fail repeatedly to Sentry or to a webhook,
+//! so that broken sources are noticed before the users of the maps notice them.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::panic;
+use std::sync::{Mutex, OnceLock};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::utils::TileCoord;
+use crate::MartinError::InvalidErrorReporting;
+use crate::MartinResult;
+
+const ERROR_REPORT_FAILURES_DEFAULT: u32 = 5;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+static REPORTER: OnceLock<ErrorReporter> = OnceLock::new();
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct ErrorReportingConfig {
+    /// Send the errors to the Sentry project with this DSN
+    pub sentry_dsn: Option<String>,
+    /// Send each error as a JSON `POST` request to this URL
+    pub webhook_url: Option<String>,
+    /// Number of consecutive failures of a source before it is reported [default: 5]
+    pub failures: Option<NonZeroU32>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Panic,
+    SourceError,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// ID of the failing source
+    pub source: Option<String>,
+    /// The `z/x/y` of the tile that failed last
+    pub tile: Option<String>,
+    /// Number of consecutive failures of the source
+    pub failures: Option<u32>,
+    pub version: &'static str,
+}
+
+/// Start reporting panics and failing sources in the background.
+/// Must be called from a Tokio runtime. Only the first call has an effect,
+/// so the reporting settings can only be changed with a restart.
+pub fn start_error_reporting(config: &ErrorReportingConfig) -> MartinResult<()> {
+    let sentry = config
+        .sentry_dsn
+        .as_deref()
+        .map(SentryDsn::parse)
+        .transpose()?;
+    let webhook = config
+        .webhook_url
+        .as_deref()
+        .map(|v| Url::parse(v).map_err(|e| InvalidErrorReporting(format!("webhook URL {v}: {e}"))))
+        .transpose()?;
+    if sentry.is_none() && webhook.is_none() {
+        return Ok(());
+    }
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let failures = config
+        .failures
+        .unwrap_or(NonZeroU32::new(ERROR_REPORT_FAILURES_DEFAULT).unwrap());
+    if REPORTER.set(ErrorReporter::new(failures, sender)).is_err() {
+        return Ok(());
+    }
+    tokio::spawn(send_reports(receiver, sentry, webhook));
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        default_hook(panic_info);
+        if let Some(reporter) = REPORTER.get() {
+            reporter.send(ErrorReport::new(ErrorKind::Panic, panic_info.to_string()));
+        }
+    }));
+    info!("Reporting panics and sources failing {failures} times in a row");
+    Ok(())
+}
+
+/// Count the consecutive failures of a source, and report it once it failed too often.
+/// Does nothing if error reporting is not enabled.
+pub(crate) fn record_source_result<T, E: Display>(
+    source_id: &str,
+    xyz: TileCoord,
+    result: &Result<T, E>,
+) {
+    if let Some(reporter) = REPORTER.get() {
+        let error = result.as_ref().err().map(ToString::to_string);
+        if let Some(report) = reporter.record(source_id, xyz, error) {
+            reporter.send(report);
+        }
+    }
+}
+
+struct ErrorReporter {
+    max_failures: NonZeroU32,
+    failures: Mutex<HashMap<String, u32>>,
+    sender: mpsc::UnboundedSender<ErrorReport>,
+}
+
+impl ErrorReporter {
+    fn new(max_failures: NonZeroU32, sender: mpsc::UnboundedSender<ErrorReport>) -> Self {
+        Self {
+            max_failures,
+            failures: Mutex::default(),
+            sender,
+        }
+    }
+
+    /// Returns a report when the source has just reached the maximum number of consecutive failures.
+    /// A source is reported again only after it succeeded in between.
+    fn record(
+        &self,
+        source_id: &str,
+        xyz: TileCoord,
+        error: Option<String>,
+    ) -> Option<ErrorReport> {
+        let mut failures = self.failures.lock().unwrap();
+        let Some(error) = error else {
+            failures.remove(source_id);
+            return None;
+        };
+        let count = failures.entry(source_id.to_string()).or_default();
+        *count = count.saturating_add(1);
+        if *count != self.max_failures.get() {
+            return None;
+        }
+        Some(ErrorReport {
+            source: Some(source_id.to_string()),
+            tile: Some(format!("{xyz:#}")),
+            failures: Some(*count),
+            ..ErrorReport::new(ErrorKind::SourceError, error)
+        })
+    }
+
+    fn send(&self, report: ErrorReport) {
+        // the receiver is only gone while the runtime shuts down
+        let _ = self.sender.send(report);
+    }
+}
+
+impl ErrorReport {
+    fn new(kind: ErrorKind, message: String) -> Self {
+        Self {
+            kind,
+            message,
+            source: None,
+            tile: None,
+            failures: None,
+            version: VERSION,
+        }
+    }
+
+    /// The report as a Sentry envelope with a single event
+    fn to_sentry_envelope(&self) -> String {
+        let mut tags = json!({ "kind": self.kind });
+        if let Some(source) = &self.source {
+            tags["source"] = json!(source);
+        }
+        if let Some(tile) = &self.tile {
+            tags["tile"] = json!(tile);
+        }
+        let event = json!({
+            "level": if self.kind == ErrorKind::Panic { "fatal" } else { "error" },
+            "logger": "martin",
+            "platform": "other",
+            "release": format!("martin@{}", self.version),
+            "message": { "formatted": self.message },
+            "tags": tags,
+            "extra": { "failures": self.failures },
+        });
+        format!("{{}}\n{{\"type\":\"event\"}}\n{event}\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SentryDsn {
+    envelope_url: Url,
+    public_key: String,
+}
+
+impl SentryDsn {
+    /// Parse a DSN in the `https://<public_key>@<host>/<project_id>` format
+    fn parse(dsn: &str) -> MartinResult<Self> {
+        let err = || InvalidErrorReporting(format!("Sentry DSN {dsn}"));
+        let url = Url::parse(dsn).map_err(|_| err())?;
+        let public_key = url.username().to_string();
+        let (path, project_id) = url.path().rsplit_once('/').ok_or_else(err)?;
+        if public_key.is_empty() || project_id.is_empty() || url.host_str().is_none() {
+            return Err(err());
+        }
+        let mut envelope_url = url.clone();
+        envelope_url.set_username("").map_err(|()| err())?;
+        envelope_url.set_password(None).map_err(|()| err())?;
+        envelope_url.set_path(&format!("{path}/api/{project_id}/envelope/"));
+        Ok(Self {
+            envelope_url,
+            public_key,
+        })
+    }
+
+    fn auth_header(&self) -> String {
+        format!(
+            "Sentry sentry_version=7, sentry_client=martin/{VERSION}, sentry_key={}",
+            self.public_key
+        )
+    }
+}
+
+async fn send_reports(
+    mut receiver: mpsc::UnboundedReceiver<ErrorReport>,
+    sentry: Option<SentryDsn>,
+    webhook: Option<Url>,
+) {
+    let client = reqwest::Client::new();
+    while let Some(report) = receiver.recv().await {
+        if let Some(dsn) = &sentry {
+            let request = client
+                .post(dsn.envelope_url.clone())
+                .header("X-Sentry-Auth", dsn.auth_header())
+                .header("Content-Type", "application/x-sentry-envelope")
+                .body(report.to_sentry_envelope());
+            if let Err(e) = request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                warn!("Unable to report an error to Sentry: {e}");
+            }
+        }
+        if let Some(url) = &webhook {
+            let request = client.post(url.clone()).json(&report);
+            if let Err(e) = request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                warn!("Unable to report an error to {url}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sentry_dsn() {
+        let dsn = SentryDsn::parse("https://abc123@o1.ingest.sentry.io/42").unwrap();
+        assert_eq!(
+            dsn.envelope_url.as_str(),
+            "https://o1.ingest.sentry.io/api/42/envelope/"
+        );
+        assert_eq!(dsn.public_key, "abc123");
+        let dsn = SentryDsn::parse("http://key@sentry.local:9000/prefix/7").unwrap();
+        assert_eq!(
+            dsn.envelope_url.as_str(),
+            "http://sentry.local:9000/prefix/api/7/envelope/"
+        );
+        for bad in ["", "https://o1.ingest.sentry.io/42", "https://key@host/"] {
+            assert!(SentryDsn::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_record_failures() {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let reporter = ErrorReporter::new(NonZeroU32::new(2).unwrap(), sender);
+        let xyz = TileCoord { z: 1, x: 0, y: 1 };
+        let fail = || reporter.record("src", xyz, Some("timeout".to_string()));
+
+        assert_eq!(fail(), None);
+        let report = fail().unwrap();
+        assert_eq!(report.kind, ErrorKind::SourceError);
+        assert_eq!(report.source.as_deref(), Some("src"));
+        assert_eq!(report.tile.as_deref(), Some("1/0/1"));
+        assert_eq!(report.failures, Some(2));
+        // reported once per series of failures
+        assert_eq!(fail(), None);
+        assert_eq!(reporter.record("src", xyz, None), None);
+        assert_eq!(fail(), None);
+        assert!(fail().is_some());
+    }
+
+    #[test]
+    fn test_sentry_envelope() {
+        let report = ErrorReport {
+            source: Some("src".to_string()),
+            ..ErrorReport::new(ErrorKind::SourceError, "boom".to_string())
+        };
+        let envelope = report.to_sentry_envelope();
+        let lines: Vec<_> = envelope.lines().collect();
+        assert_eq!(lines[..2], ["{}", r#"{"type":"event"}"#]);
+        let event: serde_json::Value = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(event["message"]["formatted"], "boom");
+        assert_eq!(event["tags"]["source"], "src");
+        assert_eq!(event["tags"]["kind"], "source_error");
+    }
+}
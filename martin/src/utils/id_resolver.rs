@@ -1,9 +1,51 @@
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::fmt::Write as _;
+use std::fmt::{Display, Formatter, Write as _};
 use std::sync::{Arc, Mutex};
 
+use clap::ValueEnum;
 use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// What to do with a source whose ID is already used by another source, or is reserved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IdCollisionPolicy {
+    /// Append `.1`, `.2`, etc. to the ID of the later source
+    #[default]
+    Suffix,
+    /// Fail to start the server, or to refresh the sources
+    Error,
+    /// Serve only the first source with the ID, skipping the later ones
+    Skip,
+    /// Use the qualified name of the later source as its ID, e.g. `schema.table.column` of a table.
+    /// A suffix is appended if the qualified name is taken as well.
+    Qualify,
+}
+
+/// A source that requested an ID which is already used by another source, or is reserved
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct IdCollision {
+    /// The requested ID, with the prohibited characters replaced
+    pub id: String,
+    /// Qualified name of the source, e.g. `schema.table.column`, a file path, or a URL
+    pub source: String,
+    /// Qualified name of the source that has the ID, or `None` if the ID is reserved
+    pub existing: Option<String>,
+    /// The ID given to the source instead
+    pub resolved: String,
+}
+
+impl Display for IdCollision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "source ID `{}` of {} ", self.id, self.source)?;
+        match &self.existing {
+            Some(existing) => write!(f, "is already used by {existing}"),
+            None => write!(f, "is reserved"),
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct IdResolver {
@@ -11,6 +53,10 @@ pub struct IdResolver {
     names: Arc<Mutex<HashMap<String, String>>>,
     /// reserved names
     reserved: HashSet<&'static str>,
+    /// items that failed to be discovered and were skipped, e.g. a file that cannot be opened
+    failures: Arc<Mutex<Vec<String>>>,
+    policy: IdCollisionPolicy,
+    collisions: Arc<Mutex<Vec<IdCollision>>>,
 }
 
 impl IdResolver {
@@ -19,57 +65,123 @@ impl IdResolver {
         Self {
             names: Arc::new(Mutex::new(HashMap::new())),
             reserved: reserved_keywords.iter().copied().collect(),
+            ..Default::default()
         }
     }
 
-    /// If source name already exists in the self.names structure,
-    /// try appending it with ".1", ".2", etc. until the name is unique.
-    /// Only alphanumeric characters plus dashes/dots/underscores are allowed.
     #[must_use]
-    pub fn resolve(&self, name: &str, unique_name: String) -> String {
-        let info = if name == unique_name {
-            None
+    pub fn with_collision_policy(mut self, policy: IdCollisionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Record an item that failed to be discovered, so that the discovery can be retried later
+    pub fn add_failure(&self, failure: String) {
+        self.failures
+            .lock()
+            .expect("IdResolver panicked")
+            .push(failure);
+    }
+
+    /// Items that failed to be discovered by all the users of this resolver
+    #[must_use]
+    pub fn failures(&self) -> Vec<String> {
+        self.failures.lock().expect("IdResolver panicked").clone()
+    }
+
+    /// All ID collisions so far, in the order they were resolved
+    #[must_use]
+    pub fn collisions(&self) -> Vec<IdCollision> {
+        self.collisions.lock().expect("IdResolver panicked").clone()
+    }
+
+    /// IDs of the sources that must not be served because of the `skip` collision policy
+    #[must_use]
+    pub fn skipped_ids(&self) -> HashSet<String> {
+        if self.policy == IdCollisionPolicy::Skip {
+            self.collisions().into_iter().map(|v| v.resolved).collect()
         } else {
-            Some(unique_name.clone())
-        };
-        let new_name = self.resolve_int(name, unique_name);
-        if name != new_name {
-            warn!("Source `{name}`{info} was renamed to `{new_name}`. Source IDs must be unique, cannot be reserved, and must contain alpha-numeric characters or `._-`",
-                 info = info.map_or(String::new(), |v| format!(" ({v})"))
-            );
+            HashSet::new()
         }
-        new_name
     }
 
+    /// Get a unique ID for the source with the given name.
+    /// Only alphanumeric characters plus dashes/dots/underscores are allowed.
+    /// If the name is already used by another source, or is reserved, the collision is recorded
+    /// and resolved according to the collision policy, e.g. by appending ".1", ".2", etc. to the name.
     #[must_use]
-    fn resolve_int(&self, name: &str, unique_name: String) -> String {
-        // Ensure name has no prohibited characters like spaces, commas, slashes, or non-unicode etc.
-        // Underscores, dashes, and dots are OK. All other characters will be replaced with dashes.
-        let mut name = name.replace(
-            |c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '-',
-            "-",
-        );
-
+    pub fn resolve(&self, name: &str, unique_name: String) -> String {
+        let id = sanitize_id(name);
         let mut names = self.names.lock().expect("IdResolver panicked");
-        if !self.reserved.contains(name.as_str()) {
-            match names.entry(name) {
-                Entry::Vacant(e) => {
-                    let id = e.key().clone();
-                    e.insert(unique_name);
-                    return id;
-                }
-                // Rust v1.78 - possibly due to bug fixed in https://github.com/rust-lang/rust-clippy/pull/12756
-                #[allow(unknown_lints, clippy::assigning_clones)]
-                Entry::Occupied(e) => {
-                    name = e.key().clone();
-                    if e.get() == &unique_name {
-                        return name;
-                    }
+        let existing = match self.claim(&mut names, &id, &unique_name) {
+            Ok(()) => {
+                if name != id {
+                    let info = if name == unique_name {
+                        String::new()
+                    } else {
+                        format!(" ({unique_name})")
+                    };
+                    warn!("Source `{name}`{info} was renamed to `{id}`. Source IDs must contain alpha-numeric characters or `._-`");
                 }
+                return id;
+            }
+            Err(existing) => existing,
+        };
+        let qualified = sanitize_id(&unique_name);
+        let resolved = if self.policy == IdCollisionPolicy::Qualify
+            && qualified != id
+            && self.claim(&mut names, &qualified, &unique_name).is_ok()
+        {
+            qualified
+        } else {
+            Self::add_suffix(&mut names, &id, &unique_name)
+        };
+        drop(names);
+
+        let collision = IdCollision {
+            id,
+            source: unique_name,
+            existing,
+            resolved: resolved.clone(),
+        };
+        let mut collisions = self.collisions.lock().expect("IdResolver panicked");
+        // the same source may be resolved more than once
+        if collisions.contains(&collision) {
+            return resolved;
+        }
+        match self.policy {
+            IdCollisionPolicy::Skip => warn!("Skipping the source because its {collision}"),
+            IdCollisionPolicy::Error => warn!("The {collision}"),
+            _ => warn!("The {collision}, using `{resolved}` instead"),
+        }
+        collisions.push(collision);
+        resolved
+    }
+
+    /// Use the name for the source, unless it is reserved, or used by another source,
+    /// whose unique name is returned then
+    fn claim(
+        &self,
+        names: &mut HashMap<String, String>,
+        name: &str,
+        unique_name: &str,
+    ) -> Result<(), Option<String>> {
+        if self.reserved.contains(name) {
+            return Err(None);
+        }
+        match names.entry(name.to_string()) {
+            Entry::Vacant(e) => {
+                e.insert(unique_name.to_string());
+                Ok(())
             }
+            Entry::Occupied(e) if e.get() == unique_name => Ok(()),
+            Entry::Occupied(e) => Err(Some(e.get().clone())),
         }
-        // name already exists, try it with ".1", ".2", etc. until the value matches
-        // assume that reserved keywords never end in a "dot number", so don't check
+    }
+
+    /// Try the name with ".1", ".2", etc. until the value matches.
+    /// Assume that reserved keywords never end in a "dot number", so don't check.
+    fn add_suffix(names: &mut HashMap<String, String>, name: &str, unique_name: &str) -> String {
         let mut index: i32 = 1;
         let mut new_name = String::new();
         loop {
@@ -78,11 +190,11 @@ impl IdResolver {
             index = index.checked_add(1).unwrap();
             match names.entry(new_name.clone()) {
                 Entry::Vacant(e) => {
-                    e.insert(unique_name);
+                    e.insert(unique_name.to_string());
                     return new_name;
                 }
                 Entry::Occupied(e) => {
-                    if e.get() == &unique_name {
+                    if e.get() == unique_name {
                         return new_name;
                     }
                 }
@@ -91,6 +203,15 @@ impl IdResolver {
     }
 }
 
+/// Ensure the ID has no prohibited characters like spaces, commas, slashes, or non-unicode etc.
+/// Underscores, dashes, and dots are OK. All other characters will be replaced with dashes.
+fn sanitize_id(name: &str) -> String {
+    name.replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '-',
+        "-",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,5 +230,38 @@ mod tests {
 
         assert_eq!(r.resolve("a b", "a b".to_string()), "a-b");
         assert_eq!(r.resolve("a b", "ab2".to_string()), "a-b.1");
+
+        let collisions = r.collisions();
+        assert_eq!(collisions.len(), 3);
+        assert_eq!(
+            collisions[0],
+            IdCollision {
+                id: "a".to_string(),
+                source: "b".to_string(),
+                existing: Some("a".to_string()),
+                resolved: "a.1".to_string(),
+            }
+        );
+        assert!(r.skipped_ids().is_empty());
+    }
+
+    #[test]
+    fn id_collision_policy() {
+        let r = IdResolver::new(&["catalog"]).with_collision_policy(IdCollisionPolicy::Qualify);
+        assert_eq!(
+            r.resolve("roads", "prod.public.roads.geom".to_string()),
+            "roads"
+        );
+        assert_eq!(
+            r.resolve("roads", "stage.public.roads.geom".to_string()),
+            "stage.public.roads.geom"
+        );
+        assert_eq!(r.resolve("catalog", "catalog".to_string()), "catalog.1");
+        assert_eq!(r.collisions()[1].existing, None);
+
+        let r = IdResolver::new(&[]).with_collision_policy(IdCollisionPolicy::Skip);
+        assert_eq!(r.resolve("roads", "a".to_string()), "roads");
+        assert_eq!(r.resolve("roads", "b".to_string()), "roads.1");
+        assert_eq!(r.skipped_ids(), HashSet::from(["roads.1".to_string()]));
     }
 }
@@ -0,0 +1,384 @@
+//! Logging to stderr, stdout, rotating files, or journald, with `RUST_LOG`-style per-module filters.
+//! The log outputs can be replaced at any time, e.g. when the config file is reloaded.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, RwLock};
+
+use env_logger::{Builder, Logger, Target};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::srv::current_request_id;
+use crate::utils::OptOneMany;
+use crate::MartinError::{InvalidLogLevel, LogFileError, MissingLogFilePath};
+use crate::MartinResult;
+
+pub const LOG_LEVEL_DEFAULT: &str = "martin=info";
+pub const LOG_FILE_MAX_SIZE_MB_DEFAULT: u64 = 100;
+pub const LOG_FILE_MAX_FILES_DEFAULT: usize = 5;
+
+static LOGGERS: RwLock<Vec<Logger>> = RwLock::new(Vec::new());
+static INSTALL: Once = Once::new();
+static LOG_STATE: Mutex<Option<LogState>> = Mutex::new(None);
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct LogConfig {
+    /// Log filter in the `RUST_LOG` format, e.g. `info,martin::pg=debug`. Ignored if `RUST_LOG` is set.
+    /// [default: martin=info]
+    pub level: Option<String>,
+    /// Where to write the log, one or more of `stderr`, `stdout`, `file`, and `journald` [default: stderr]
+    #[serde(default, skip_serializing_if = "OptOneMany::is_none")]
+    pub output: OptOneMany<LogOutput>,
+    /// Settings of the `file` output
+    pub file: Option<LogFileConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stderr,
+    Stdout,
+    /// Size-based rotating log files
+    File,
+    /// Stderr with syslog priority prefixes instead of timestamps, as expected by systemd-journald
+    Journald,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Size (in megabytes) at which the log file is rotated [default: 100]
+    pub max_size_mb: Option<u64>,
+    /// Number of rotated files to keep, as `<path>.1` to `<path>.<max_files>` [default: 5]
+    pub max_files: Option<usize>,
+}
+
+/// Log to stderr using the `RUST_LOG` environment variable, or the given filter if it is not set.
+/// This replaces any previously configured outputs.
+pub fn init_logging(default_level: &str) {
+    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| default_level.to_string());
+    // logging to stderr cannot fail
+    let _ = apply_config(LogConfig::default(), level);
+}
+
+/// Replace the log outputs and filters with the configured ones
+pub fn configure_logging(config: &LogConfig) -> MartinResult<()> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .or_else(|| config.level.clone())
+        .unwrap_or_else(|| LOG_LEVEL_DEFAULT.to_string());
+    apply_config(config.clone(), level)
+}
+
+/// The log filter currently in use
+#[must_use]
+pub fn get_log_level() -> String {
+    LOG_STATE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|v| v.level.clone())
+        .unwrap_or_default()
+}
+
+/// Change the log filter of all outputs at runtime, e.g. to `info,martin::pg=debug`,
+/// or restore the configured filter with `None`. Returns the filter now in use.
+pub fn set_log_level(level: Option<&str>) -> MartinResult<String> {
+    if let Some(level) = level {
+        validate_log_level(level)?;
+    }
+    let mut state = LOG_STATE.lock().unwrap();
+    let state = state.get_or_insert_with(|| LogState {
+        config: LogConfig::default(),
+        configured_level: LOG_LEVEL_DEFAULT.to_string(),
+        level: LOG_LEVEL_DEFAULT.to_string(),
+    });
+    let level = level.map_or_else(|| state.configured_level.clone(), ToString::to_string);
+    set_loggers(build_loggers(&state.config, &level)?);
+    state.level.clone_from(&level);
+    Ok(level)
+}
+
+struct LogState {
+    config: LogConfig,
+    /// The filter set with `RUST_LOG` or in the config
+    configured_level: String,
+    /// The filter in use, which may have been changed at runtime
+    level: String,
+}
+
+fn apply_config(config: LogConfig, level: String) -> MartinResult<()> {
+    let mut state = LOG_STATE.lock().unwrap();
+    set_loggers(build_loggers(&config, &level)?);
+    *state = Some(LogState {
+        config,
+        configured_level: level.clone(),
+        level,
+    });
+    Ok(())
+}
+
+/// Make sure each comma-separated directive is a level, a module path, or `module=level`,
+/// because `env_logger` only reports invalid directives on stderr.
+fn validate_log_level(level: &str) -> MartinResult<()> {
+    let is_level = |v: &str| v.parse::<LevelFilter>().is_ok();
+    let is_module = |v: &str| {
+        v.split("::").all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+    };
+    let is_valid =
+        level
+            .split(',')
+            .map(str::trim)
+            .all(|directive| match directive.split_once('=') {
+                Some((module, lvl)) => is_module(module) && is_level(lvl),
+                None => is_level(directive) || is_module(directive),
+            });
+    if is_valid {
+        Ok(())
+    } else {
+        Err(InvalidLogLevel(level.to_string()))
+    }
+}
+
+fn build_loggers(config: &LogConfig, level: &str) -> MartinResult<Vec<Logger>> {
+    let mut outputs = config.output.iter().copied().collect::<Vec<_>>();
+    if outputs.is_empty() {
+        outputs.push(LogOutput::Stderr);
+    }
+
+    let mut loggers = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let mut builder = Builder::new();
+        builder.parse_filters(level);
+        match output {
+            LogOutput::Stderr => builder.target(Target::Stderr),
+            LogOutput::Stdout => builder.target(Target::Stdout),
+            LogOutput::File => {
+                let cfg = config.file.as_ref().ok_or(MissingLogFilePath)?;
+                let file = RotatingFile::open(
+                    cfg.path.clone(),
+                    cfg.max_size_mb.unwrap_or(LOG_FILE_MAX_SIZE_MB_DEFAULT) * 1024 * 1024,
+                    cfg.max_files.unwrap_or(LOG_FILE_MAX_FILES_DEFAULT),
+                )
+                .map_err(|e| LogFileError(e, cfg.path.clone()))?;
+                builder.target(Target::Pipe(Box::new(file)))
+            }
+            LogOutput::Journald => builder.target(Target::Stderr).format(|buf, record| {
+                writeln!(
+                    buf,
+                    "<{}>{}: {}",
+                    syslog_priority(record.level()),
+                    record.target(),
+                    record.args()
+                )
+            }),
+        };
+        loggers.push(builder.build());
+    }
+    Ok(loggers)
+}
+
+fn set_loggers(loggers: Vec<Logger>) {
+    INSTALL.call_once(|| {
+        if log::set_logger(&MartinLogger).is_err() {
+            eprintln!("Unable to configure logging, another logger is already installed");
+        }
+    });
+    let max_level = loggers
+        .iter()
+        .map(Logger::filter)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    *LOGGERS.write().unwrap() = loggers;
+    log::set_max_level(max_level);
+}
+
+/// Severity as defined by syslog, and used by systemd-journald
+fn syslog_priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Sends every log record to all configured outputs,
+/// prefixing the records logged while handling a request with the request ID
+struct MartinLogger;
+
+impl Log for MartinLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        LOGGERS.read().unwrap().iter().any(|v| v.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        let loggers = LOGGERS.read().unwrap();
+        if let Some(id) = current_request_id() {
+            let args = format_args!("[{id}] {}", record.args());
+            let record = Record::builder()
+                .args(args)
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build();
+            for logger in loggers.iter() {
+                logger.log(&record);
+            }
+        } else {
+            for logger in loggers.iter() {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for logger in LOGGERS.read().unwrap().iter() {
+            logger.flush();
+        }
+    }
+}
+
+/// A log file that is renamed to `<path>.1` once it reaches the maximum size,
+/// shifting the older files up to `<path>.<max_files>`, which is removed.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{idx}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for idx in (1..self.max_files).rev() {
+                let from = Self::rotated_path(&self.path, idx);
+                if from.exists() {
+                    fs::rename(from, Self::rotated_path(&self.path, idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, Self::rotated_path(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn parse_log_config() {
+        let cfg: LogConfig = serde_yaml::from_str(indoc! {"
+            level: info,martin::pg=debug
+            output: [stdout, file]
+            file:
+              path: /var/log/martin.log
+              max_size_mb: 10
+        "})
+        .unwrap();
+        assert_eq!(
+            cfg,
+            LogConfig {
+                level: Some("info,martin::pg=debug".to_string()),
+                output: OptOneMany::Many(vec![LogOutput::Stdout, LogOutput::File]),
+                file: Some(LogFileConfig {
+                    path: PathBuf::from("/var/log/martin.log"),
+                    max_size_mb: Some(10),
+                    max_files: None,
+                }),
+            }
+        );
+        let cfg: LogConfig = serde_yaml::from_str("output: journald").unwrap();
+        assert_eq!(cfg.output, OptOneMany::One(LogOutput::Journald));
+    }
+
+    #[test]
+    fn test_validate_log_level() {
+        for valid in [
+            "debug",
+            "info,martin::pg=debug",
+            "martin",
+            "tokio-postgres=off, martin=trace",
+        ] {
+            assert!(validate_log_level(valid).is_ok(), "{valid}");
+        }
+        for invalid in ["", "martin=loud", "info,", "martin::=debug", "a b"] {
+            assert!(validate_log_level(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join("martin_test_rotating_file");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("martin.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |idx| fs::read_to_string(RotatingFile::rotated_path(&path, idx)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(read(1), "third\n");
+        assert_eq!(read(2), "second\n");
+        assert!(!RotatingFile::rotated_path(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 pub(crate) mod cache;
-pub use cache::{CacheKey, CacheValue, MainCache, OptMainCache, NO_MAIN_CACHE};
+pub use cache::{invalidate_sources, CacheKey, CacheValue, MainCache, OptMainCache, NO_MAIN_CACHE};
+
+mod aws;
 
 mod cfg_containers;
 pub use cfg_containers::{OptBoolObj, OptOneMany};
@@ -7,12 +9,54 @@ pub use cfg_containers::{OptBoolObj, OptOneMany};
 mod error;
 pub use error::*;
 
+mod error_reporter;
+pub(crate) use error_reporter::record_source_result;
+pub use error_reporter::{start_error_reporting, ErrorKind, ErrorReport, ErrorReportingConfig};
+
 mod id_resolver;
-pub use id_resolver::IdResolver;
+pub use id_resolver::{IdCollision, IdCollisionPolicy, IdResolver};
+
+mod logging;
+pub use logging::{
+    configure_logging, get_log_level, init_logging, set_log_level, LogConfig, LogFileConfig,
+    LogOutput, LOG_LEVEL_DEFAULT,
+};
+
+mod mvt;
+#[cfg(feature = "raster")]
+pub(crate) use mvt::WIRE_I64;
+pub(crate) use mvt::{
+    command, write_bytes, write_key, write_packed, write_uint, write_varint, zigzag, WIRE_LEN,
+};
+pub use mvt::{
+    count_mvt_features, filter_mvt_layers, mvt_layer_stats, truncate_mvt, AttributeType,
+    MvtLayerStats, MvtPipeline,
+};
+pub use mvt::{decode_mvt, mvt_to_geojson};
+#[cfg(feature = "raster")]
+pub use mvt::{GeomType, MvtFeature, MvtLayer};
+
+mod object_storage;
+pub use object_storage::{fetch_text, is_remote};
 
 mod rectangle;
 pub use rectangle::{append_rect, TileRect};
 
+#[cfg(feature = "postgres")]
+mod secrets;
+#[cfg(feature = "postgres")]
+pub use secrets::{read_secret, read_secret_file, SecretError};
+
+mod slow_tiles;
+pub(crate) use slow_tiles::log_if_slow;
+#[cfg(feature = "postgres")]
+pub(crate) use slow_tiles::record_phase;
+
+mod utfgrid;
+pub use utfgrid::UtfGrid;
+#[cfg(any(feature = "postgres", feature = "mbtiles"))]
+pub use utfgrid::UTFGRID_SIZE;
+
 mod utilities;
 pub use utilities::*;
 
@@ -0,0 +1,1192 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::TileCoord;
+
+/// Field number of the repeated `layers` field in the vector tile `Tile` message
+const TILE_LAYERS_FIELD: u64 = 3;
+/// Field numbers of the vector tile `Layer` message
+const LAYER_NAME_FIELD: u64 = 1;
+const LAYER_FEATURES_FIELD: u64 = 2;
+const LAYER_KEYS_FIELD: u64 = 3;
+const LAYER_VALUES_FIELD: u64 = 4;
+const LAYER_EXTENT_FIELD: u64 = 5;
+/// Field numbers of the vector tile `Feature` message
+const FEATURE_ID_FIELD: u64 = 1;
+const FEATURE_TAGS_FIELD: u64 = 2;
+const FEATURE_TYPE_FIELD: u64 = 3;
+const FEATURE_GEOMETRY_FIELD: u64 = 4;
+
+pub(crate) const WIRE_VARINT: u64 = 0;
+pub(crate) const WIRE_I64: u64 = 1;
+pub(crate) const WIRE_LEN: u64 = 2;
+const WIRE_I32: u64 = 5;
+
+/// Type to convert attribute values to
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+/// Presentation-level changes applied to every vector tile of a source before it is served.
+/// All attribute names refer to the attributes as they are stored in the source,
+/// and apply to all layers of the tile.
+#[serde_with::skip_serializing_none]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct MvtPipeline {
+    /// Layers to remove from the tiles
+    pub drop_layers: Option<Vec<String>>,
+    /// Attributes to remove from all features
+    pub drop_attributes: Option<Vec<String>>,
+    /// Attributes to rename, from the original to the new name
+    pub rename_attributes: Option<BTreeMap<String, String>>,
+    /// Attributes to convert to a different type.
+    /// Values that cannot be converted, e.g. a non-numeric string to `int`, are removed.
+    pub cast_attributes: Option<BTreeMap<String, AttributeType>>,
+}
+
+impl MvtPipeline {
+    /// Apply the pipeline to an uncompressed MVT tile
+    pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let drop_layers = self.drop_layers.as_deref().unwrap_or_default();
+        if self.drop_attributes.is_none()
+            && self.rename_attributes.is_none()
+            && self.cast_attributes.is_none()
+        {
+            return filter_mvt_layers(data, |name| !drop_layers.iter().any(|v| v == name));
+        }
+
+        let mut result = Vec::with_capacity(data.len());
+        let mut pos = 0;
+        while pos < data.len() {
+            let start = pos;
+            match read_field(data, &mut pos)? {
+                (TILE_LAYERS_FIELD, Field::Bytes(layer)) => {
+                    let name = get_layer_name(layer)?;
+                    if !drop_layers.iter().any(|v| v == name) {
+                        write_bytes(
+                            &mut result,
+                            TILE_LAYERS_FIELD,
+                            &self.transform_layer(layer)?,
+                        );
+                    }
+                }
+                _ => result.extend_from_slice(&data[start..pos]),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Map old key indexes to the new ones, merging keys renamed to the same name.
+    /// Returns the new keys, and the new index of each old key, or `None` if it is dropped.
+    fn map_keys<'a>(&'a self, keys: &[&'a str]) -> (Vec<&'a str>, Vec<Option<u64>>) {
+        let mut new_keys: Vec<&str> = Vec::new();
+        let mut key_map = Vec::with_capacity(keys.len());
+        for key in keys {
+            if self.drop_attributes.iter().flatten().any(|v| v == key) {
+                key_map.push(None);
+                continue;
+            }
+            let name = self
+                .rename_attributes
+                .as_ref()
+                .and_then(|v| v.get(*key))
+                .map_or(*key, String::as_str);
+            let idx = new_keys.iter().position(|v| *v == name).unwrap_or_else(|| {
+                new_keys.push(name);
+                new_keys.len() - 1
+            });
+            key_map.push(Some(idx as u64));
+        }
+        (new_keys, key_map)
+    }
+
+    fn transform_layer(&self, layer: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut result = Vec::with_capacity(layer.len());
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut features = Vec::new();
+        let mut pos = 0;
+        while pos < layer.len() {
+            let start = pos;
+            match read_field(layer, &mut pos)? {
+                (LAYER_KEYS_FIELD, Field::Bytes(key)) => keys.push(to_str(key)?),
+                (LAYER_VALUES_FIELD, Field::Bytes(value)) => values.push(Value::parse(value)?),
+                (LAYER_FEATURES_FIELD, Field::Bytes(feature)) => features.push(feature),
+                _ => result.extend_from_slice(&layer[start..pos]),
+            }
+        }
+
+        let (new_keys, key_map) = self.map_keys(&keys);
+
+        // Values are converted and added to the new table as they are used
+        let mut new_values = Vec::new();
+        let mut value_idx: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut value_map: HashMap<(Option<AttributeType>, u64), Option<u64>> = HashMap::new();
+        let mut get_value = |cast: Option<AttributeType>, old_idx: u64| -> Result<_, Error> {
+            if let Some(new_idx) = value_map.get(&(cast, old_idx)) {
+                return Ok(*new_idx);
+            }
+            let value = values
+                .get(usize::try_from(old_idx).unwrap_or(usize::MAX))
+                .ok_or_else(|| invalid_data("feature tag refers to a missing value"))?;
+            let new_idx = match cast {
+                Some(cast) => value.cast(cast),
+                None => Some(value.clone()),
+            }
+            .map(|value| {
+                let encoded = value.encode();
+                *value_idx.entry(encoded.clone()).or_insert_with(|| {
+                    new_values.push(encoded);
+                    new_values.len() as u64 - 1
+                })
+            });
+            value_map.insert((cast, old_idx), new_idx);
+            Ok(new_idx)
+        };
+
+        let mut new_features = Vec::with_capacity(features.len());
+        for feature in features {
+            let mut new_feature = Vec::with_capacity(feature.len());
+            let mut pos = 0;
+            while pos < feature.len() {
+                let start = pos;
+                let (FEATURE_TAGS_FIELD, Field::Bytes(tags)) = read_field(feature, &mut pos)?
+                else {
+                    new_feature.extend_from_slice(&feature[start..pos]);
+                    continue;
+                };
+                let mut new_tags: Vec<(u64, u64)> = Vec::new();
+                let mut tag_pos = 0;
+                while tag_pos < tags.len() {
+                    let key = read_varint(tags, &mut tag_pos)?;
+                    let value = read_varint(tags, &mut tag_pos)?;
+                    let key_idx = usize::try_from(key).unwrap_or(usize::MAX);
+                    let Some(new_key) = *key_map
+                        .get(key_idx)
+                        .ok_or_else(|| invalid_data("feature tag refers to a missing key"))?
+                    else {
+                        continue;
+                    };
+                    if new_tags.iter().any(|(k, _)| *k == new_key) {
+                        // another attribute was renamed to the same name, keep the first one
+                        continue;
+                    }
+                    let cast = self
+                        .cast_attributes
+                        .as_ref()
+                        .and_then(|v| v.get(keys[key_idx]))
+                        .copied();
+                    if let Some(new_value) = get_value(cast, value)? {
+                        new_tags.push((new_key, new_value));
+                    }
+                }
+                let mut packed = Vec::with_capacity(tags.len());
+                for (key, value) in new_tags {
+                    write_varint(&mut packed, key);
+                    write_varint(&mut packed, value);
+                }
+                write_bytes(&mut new_feature, FEATURE_TAGS_FIELD, &packed);
+            }
+            new_features.push(new_feature);
+        }
+
+        for key in new_keys {
+            write_bytes(&mut result, LAYER_KEYS_FIELD, key.as_bytes());
+        }
+        for value in new_values {
+            write_bytes(&mut result, LAYER_VALUES_FIELD, &value);
+        }
+        for feature in new_features {
+            write_bytes(&mut result, LAYER_FEATURES_FIELD, &feature);
+        }
+        Ok(result)
+    }
+}
+
+/// Value of a feature attribute, stored in the `Value` message of a vector tile layer
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    String(String),
+    Float(f32),
+    Double(f64),
+    Int(i64),
+    UInt(u64),
+    SInt(i64),
+    Bool(bool),
+}
+
+impl Value {
+    /// Name of the type in the `fields` of a `TileJSON` vector layer
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "String",
+            Self::Bool(_) => "Boolean",
+            _ => "Number",
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        let mut pos = 0;
+        let mut value = None;
+        while pos < data.len() {
+            value = Some(match read_field(data, &mut pos)? {
+                (1, Field::Bytes(v)) => Self::String(to_str(v)?.to_string()),
+                (2, Field::Fixed32(v)) => Self::Float(f32::from_bits(v)),
+                (3, Field::Fixed64(v)) => Self::Double(f64::from_bits(v)),
+                #[allow(clippy::cast_possible_wrap)]
+                (4, Field::Varint(v)) => Self::Int(v as i64),
+                (5, Field::Varint(v)) => Self::UInt(v),
+                (6, Field::Varint(v)) => Self::SInt(decode_zigzag(v)),
+                (7, Field::Varint(v)) => Self::Bool(v != 0),
+                _ => continue,
+            });
+        }
+        value.ok_or_else(|| invalid_data("attribute value has no known type"))
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        match self {
+            Self::String(v) => write_bytes(&mut result, 1, v.as_bytes()),
+            Self::Float(v) => {
+                write_key(&mut result, 2, WIRE_I32);
+                result.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+            Self::Double(v) => {
+                write_key(&mut result, 3, WIRE_I64);
+                result.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+            Self::Int(v) => {
+                write_key(&mut result, 4, WIRE_VARINT);
+                write_varint(&mut result, *v as u64);
+            }
+            Self::UInt(v) => {
+                write_key(&mut result, 5, WIRE_VARINT);
+                write_varint(&mut result, *v);
+            }
+            Self::SInt(v) => {
+                write_key(&mut result, 6, WIRE_VARINT);
+                write_varint(&mut result, ((v << 1) ^ (v >> 63)) as u64);
+            }
+            Self::Bool(v) => {
+                write_key(&mut result, 7, WIRE_VARINT);
+                write_varint(&mut result, u64::from(*v));
+            }
+        }
+        result
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::String(v) => v.clone().into(),
+            Self::Float(v) => (*v).into(),
+            Self::Double(v) => (*v).into(),
+            Self::Int(v) | Self::SInt(v) => (*v).into(),
+            Self::UInt(v) => (*v).into(),
+            Self::Bool(v) => (*v).into(),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+    fn cast(&self, to: AttributeType) -> Option<Self> {
+        let as_f64 = || match self {
+            Self::String(v) => v.trim().parse().ok(),
+            Self::Float(v) => Some(f64::from(*v)),
+            Self::Double(v) => Some(*v),
+            Self::Int(v) | Self::SInt(v) => Some(*v as f64),
+            Self::UInt(v) => Some(*v as f64),
+            Self::Bool(v) => Some(f64::from(u8::from(*v))),
+        };
+        Some(match to {
+            AttributeType::String => Self::String(match self {
+                Self::String(v) => v.clone(),
+                Self::Float(v) => v.to_string(),
+                Self::Double(v) => v.to_string(),
+                Self::Int(v) | Self::SInt(v) => v.to_string(),
+                Self::UInt(v) => v.to_string(),
+                Self::Bool(v) => v.to_string(),
+            }),
+            AttributeType::Int => Self::SInt(match self {
+                Self::Int(v) | Self::SInt(v) => *v,
+                Self::UInt(v) => i64::try_from(*v).ok()?,
+                Self::String(v) => match v.trim().parse() {
+                    Ok(v) => v,
+                    Err(_) => as_f64().filter(|v| v.is_finite())?.trunc() as i64,
+                },
+                _ => as_f64().filter(|v| v.is_finite())?.trunc() as i64,
+            }),
+            AttributeType::Float => Self::Double(as_f64()?),
+            AttributeType::Bool => Self::Bool(match self {
+                Self::Bool(v) => *v,
+                Self::String(v) => match v.trim().to_lowercase().as_str() {
+                    "true" | "yes" | "1" => true,
+                    "false" | "no" | "0" | "" => false,
+                    _ => None?,
+                },
+                _ => as_f64()? != 0.0,
+            }),
+        })
+    }
+}
+
+/// Remove layers from an uncompressed MVT tile unless `keep` returns true for their name.
+/// The tile is filtered at the protobuf wire level, so the remaining layers are copied as is
+/// without decoding their features.
+pub fn filter_mvt_layers(data: &[u8], keep: impl Fn(&str) -> bool) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        let keep_field = match read_field(data, &mut pos)? {
+            (TILE_LAYERS_FIELD, Field::Bytes(layer)) => keep(get_layer_name(layer)?),
+            _ => true,
+        };
+        if keep_field {
+            result.extend_from_slice(&data[start..pos]);
+        }
+    }
+    Ok(result)
+}
+
+/// Number of features of all layers of an uncompressed MVT tile, counted without decoding them
+pub fn count_mvt_features(data: &[u8]) -> Result<usize, Error> {
+    let mut count = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let (TILE_LAYERS_FIELD, Field::Bytes(layer)) = read_field(data, &mut pos)? else {
+            continue;
+        };
+        let mut layer_pos = 0;
+        while layer_pos < layer.len() {
+            if read_field(layer, &mut layer_pos)?.0 == LAYER_FEATURES_FIELD {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Keep the features of an uncompressed MVT tile in their order until there are `max_features` of them,
+/// or until the next one would make the tile larger than `max_size` bytes. Layers without any remaining
+/// features are removed. Like [`filter_mvt_layers`], the features are copied as is without decoding them.
+pub fn truncate_mvt(data: &[u8], max_features: usize, max_size: usize) -> Result<Vec<u8>, Error> {
+    let mut result = Vec::new();
+    let mut remaining = max_features;
+    let mut is_full = false;
+    let mut pos = 0;
+    while pos < data.len() && remaining > 0 {
+        let (TILE_LAYERS_FIELD, Field::Bytes(layer)) = read_field(data, &mut pos)? else {
+            continue;
+        };
+        let mut fields = Vec::new();
+        let mut layer_pos = 0;
+        while layer_pos < layer.len() {
+            let start = layer_pos;
+            let (field, _) = read_field(layer, &mut layer_pos)?;
+            fields.push((&layer[start..layer_pos], field == LAYER_FEATURES_FIELD));
+        }
+        // the other fields of the layer are kept, and the features while they fit
+        let mut len: usize = fields.iter().filter(|v| !v.1).map(|v| v.0.len()).sum();
+        let mut kept_features = 0;
+        fields.retain(|(field, is_feature)| {
+            if !is_feature {
+                return true;
+            }
+            let new_len = len + field.len();
+            // the layer is written with a single byte field key, and its length as a varint
+            let varint_len = (u64::BITS - (new_len as u64).leading_zeros())
+                .max(1)
+                .div_ceil(7);
+            is_full |=
+                remaining == 0 || result.len() + 1 + varint_len as usize + new_len > max_size;
+            if !is_full {
+                len = new_len;
+                remaining -= 1;
+                kept_features += 1;
+            }
+            !is_full
+        });
+        if kept_features > 0 {
+            let body: Vec<u8> = fields.iter().flat_map(|v| v.0).copied().collect();
+            write_bytes(&mut result, TILE_LAYERS_FIELD, &body);
+        }
+        if is_full {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Statistics of a vector tile layer
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MvtLayerStats {
+    pub name: String,
+    /// Size of the encoded layer in bytes
+    pub size: usize,
+    pub extent: u32,
+    pub features: usize,
+    pub points: usize,
+    pub lines: usize,
+    pub polygons: usize,
+    /// Attribute names of the features
+    pub keys: Vec<String>,
+    /// Types of the attributes used by the features: `String`, `Number`, `Boolean`,
+    /// or `Mixed` if the features have values of different types
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Decode the layers of an uncompressed MVT tile, without decoding the geometries
+pub fn mvt_layer_stats(data: &[u8]) -> Result<Vec<MvtLayerStats>, Error> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (TILE_LAYERS_FIELD, Field::Bytes(layer)) = read_field(data, &mut pos)? else {
+            continue;
+        };
+        let mut stats = MvtLayerStats {
+            size: layer.len(),
+            // the default extent of the vector tile spec
+            extent: 4096,
+            ..Default::default()
+        };
+        let mut values = Vec::new();
+        let mut tags = BTreeSet::new();
+        let mut layer_pos = 0;
+        while layer_pos < layer.len() {
+            match read_field(layer, &mut layer_pos)? {
+                (LAYER_NAME_FIELD, Field::Bytes(v)) => stats.name = to_str(v)?.to_string(),
+                (LAYER_KEYS_FIELD, Field::Bytes(v)) => stats.keys.push(to_str(v)?.to_string()),
+                (LAYER_VALUES_FIELD, Field::Bytes(v)) => values.push(Value::parse(v)?),
+                (LAYER_EXTENT_FIELD, Field::Varint(v)) => {
+                    stats.extent = u32::try_from(v).map_err(|_| invalid_data("invalid extent"))?;
+                }
+                (LAYER_FEATURES_FIELD, Field::Bytes(feature)) => {
+                    stats.features += 1;
+                    let mut feature_pos = 0;
+                    while feature_pos < feature.len() {
+                        match read_field(feature, &mut feature_pos)? {
+                            (FEATURE_TYPE_FIELD, Field::Varint(1)) => stats.points += 1,
+                            (FEATURE_TYPE_FIELD, Field::Varint(2)) => stats.lines += 1,
+                            (FEATURE_TYPE_FIELD, Field::Varint(3)) => stats.polygons += 1,
+                            (FEATURE_TAGS_FIELD, Field::Bytes(v)) => {
+                                let mut tag_pos = 0;
+                                while tag_pos < v.len() {
+                                    let key = read_varint(v, &mut tag_pos)?;
+                                    tags.insert((key, read_varint(v, &mut tag_pos)?));
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // keys and values may be stored after the features, so the tags are resolved at the end
+        for (key, value) in tags {
+            let get = |idx| usize::try_from(idx).unwrap_or(usize::MAX);
+            let (Some(key), Some(value)) = (stats.keys.get(get(key)), values.get(get(value)))
+            else {
+                return Err(invalid_data("feature tag refers to a missing key or value"));
+            };
+            stats
+                .fields
+                .entry(key.clone())
+                .and_modify(|v| {
+                    if v != value.type_name() {
+                        *v = "Mixed".to_string();
+                    }
+                })
+                .or_insert_with(|| value.type_name().to_string());
+        }
+        result.push(stats);
+    }
+    Ok(result)
+}
+
+/// Geometry type of a vector tile feature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeomType {
+    Point,
+    LineString,
+    Polygon,
+}
+
+/// A decoded vector tile layer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MvtLayer {
+    pub name: String,
+    pub extent: u32,
+    pub features: Vec<MvtFeature>,
+}
+
+/// A decoded vector tile feature, with the coordinates in the tile extent
+#[derive(Clone, Debug, PartialEq)]
+pub struct MvtFeature {
+    pub id: Option<u64>,
+    pub geom_type: GeomType,
+    /// Points of a multipoint, lines of a multiline, or all rings of the polygons.
+    /// The rings are in the winding order of the tile, so exterior rings and holes can be told apart.
+    pub geometry: Vec<Vec<[i32; 2]>>,
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Decode all layers of an uncompressed MVT tile, including the geometries.
+/// Features with an unknown geometry type are skipped.
+pub fn decode_mvt(data: &[u8]) -> Result<Vec<MvtLayer>, Error> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (TILE_LAYERS_FIELD, Field::Bytes(layer)) = read_field(data, &mut pos)? else {
+            continue;
+        };
+        let mut decoded = MvtLayer {
+            extent: 4096,
+            ..Default::default()
+        };
+        let (mut keys, mut values, mut features) = (Vec::new(), Vec::new(), Vec::new());
+        let mut layer_pos = 0;
+        while layer_pos < layer.len() {
+            match read_field(layer, &mut layer_pos)? {
+                (LAYER_NAME_FIELD, Field::Bytes(v)) => decoded.name = to_str(v)?.to_string(),
+                (LAYER_KEYS_FIELD, Field::Bytes(v)) => keys.push(to_str(v)?),
+                (LAYER_VALUES_FIELD, Field::Bytes(v)) => values.push(Value::parse(v)?),
+                (LAYER_EXTENT_FIELD, Field::Varint(v)) => {
+                    decoded.extent =
+                        u32::try_from(v).map_err(|_| invalid_data("invalid extent"))?;
+                }
+                (LAYER_FEATURES_FIELD, Field::Bytes(v)) => features.push(v),
+                _ => {}
+            }
+        }
+        for feature in features {
+            if let Some(feature) = decode_feature(feature, &keys, &values)? {
+                decoded.features.push(feature);
+            }
+        }
+        result.push(decoded);
+    }
+    Ok(result)
+}
+
+fn decode_feature(
+    data: &[u8],
+    keys: &[&str],
+    values: &[Value],
+) -> Result<Option<MvtFeature>, Error> {
+    let mut id = None;
+    let mut geom_type = None;
+    let mut geometry = &[][..];
+    let mut properties = serde_json::Map::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        match read_field(data, &mut pos)? {
+            (FEATURE_ID_FIELD, Field::Varint(v)) => id = Some(v),
+            (FEATURE_TYPE_FIELD, Field::Varint(v)) => {
+                geom_type = match v {
+                    1 => Some(GeomType::Point),
+                    2 => Some(GeomType::LineString),
+                    3 => Some(GeomType::Polygon),
+                    _ => None,
+                };
+            }
+            (FEATURE_GEOMETRY_FIELD, Field::Bytes(v)) => geometry = v,
+            (FEATURE_TAGS_FIELD, Field::Bytes(tags)) => {
+                let mut tag_pos = 0;
+                while tag_pos < tags.len() {
+                    let get = |idx| usize::try_from(idx).unwrap_or(usize::MAX);
+                    let key = get(read_varint(tags, &mut tag_pos)?);
+                    let value = get(read_varint(tags, &mut tag_pos)?);
+                    let (Some(key), Some(value)) = (keys.get(key), values.get(value)) else {
+                        return Err(invalid_data("feature tag refers to a missing key or value"));
+                    };
+                    properties.insert((*key).to_string(), value.to_json());
+                }
+            }
+            _ => {}
+        }
+    }
+    let Some(geom_type) = geom_type else {
+        return Ok(None);
+    };
+    Ok(Some(MvtFeature {
+        id,
+        geom_type,
+        geometry: decode_geometry(geometry, geom_type)?,
+        properties,
+    }))
+}
+
+/// Decode the `MoveTo`, `LineTo`, and `ClosePath` commands of a feature geometry.
+/// Each point of a multipoint becomes its own part.
+fn decode_geometry(data: &[u8], geom_type: GeomType) -> Result<Vec<Vec<[i32; 2]>>, Error> {
+    let mut parts: Vec<Vec<[i32; 2]>> = Vec::new();
+    let (mut x, mut y) = (0_i32, 0_i32);
+    let read_param = |pos: &mut usize| -> Result<i32, Error> {
+        i32::try_from(decode_zigzag(read_varint(data, pos)?))
+            .map_err(|_| invalid_data("geometry coordinate is out of range"))
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let command = read_varint(data, &mut pos)?;
+        match command & 0x7 {
+            id @ (1 | 2) => {
+                for _ in 0..command >> 3 {
+                    x = x.wrapping_add(read_param(&mut pos)?);
+                    y = y.wrapping_add(read_param(&mut pos)?);
+                    match parts.last_mut() {
+                        Some(part) if id == 2 && geom_type != GeomType::Point => part.push([x, y]),
+                        _ => parts.push(vec![[x, y]]),
+                    }
+                }
+            }
+            7 => {
+                if let Some(part) = parts.last_mut() {
+                    part.push(part[0]);
+                }
+            }
+            _ => return Err(invalid_data("unknown geometry command")),
+        }
+    }
+    Ok(parts)
+}
+
+/// Convert the decoded layers of a tile to a `GeoJSON` feature collection with WGS84 coordinates,
+/// e.g. to inspect a tile. The layer name of each feature is in its `layer` member.
+#[must_use]
+pub fn mvt_to_geojson(layers: &[MvtLayer], xyz: TileCoord) -> serde_json::Value {
+    let tiles = f64::from(1_u32 << xyz.z);
+    let mut features = Vec::new();
+    for layer in layers {
+        let extent = f64::from(layer.extent.max(1));
+        let to_lng_lat = |[px, py]: [i32; 2]| {
+            let x = (f64::from(xyz.x) + f64::from(px) / extent) / tiles;
+            let y = (f64::from(xyz.y) + f64::from(py) / extent) / tiles;
+            let lat = (PI * (1.0 - 2.0 * y)).sinh().atan().to_degrees();
+            // 7 decimals are about a centimeter
+            [x * 360.0 - 180.0, lat].map(|v| (v * 1e7).round() / 1e7)
+        };
+        let to_line =
+            |part: &Vec<[i32; 2]>| part.iter().copied().map(to_lng_lat).collect::<Vec<_>>();
+        for feature in &layer.features {
+            let parts = &feature.geometry;
+            let geometry = match feature.geom_type {
+                GeomType::Point => match parts.as_slice() {
+                    [] => continue,
+                    [part] => json!({"type": "Point", "coordinates": to_lng_lat(part[0])}),
+                    _ => {
+                        let points: Vec<_> = parts.iter().map(|p| to_lng_lat(p[0])).collect();
+                        json!({"type": "MultiPoint", "coordinates": points})
+                    }
+                },
+                GeomType::LineString => match parts.as_slice() {
+                    [] => continue,
+                    [part] => json!({"type": "LineString", "coordinates": to_line(part)}),
+                    _ => {
+                        let lines: Vec<_> = parts.iter().map(to_line).collect();
+                        json!({"type": "MultiLineString", "coordinates": lines})
+                    }
+                },
+                GeomType::Polygon => {
+                    // exterior rings have a positive area in tile coordinates, and are followed by their holes
+                    let mut polygons: Vec<Vec<_>> = Vec::new();
+                    for ring in parts {
+                        let area = ring_area(ring);
+                        if area > 0 {
+                            polygons.push(vec![to_line(ring)]);
+                        } else if let (true, Some(polygon)) = (area < 0, polygons.last_mut()) {
+                            polygon.push(to_line(ring));
+                        }
+                    }
+                    match polygons.len() {
+                        0 => continue,
+                        1 => json!({"type": "Polygon", "coordinates": polygons[0]}),
+                        _ => json!({"type": "MultiPolygon", "coordinates": polygons}),
+                    }
+                }
+            };
+            let mut value = json!({
+                "type": "Feature",
+                "geometry": geometry,
+                "properties": feature.properties,
+                "layer": layer.name,
+            });
+            if let Some(id) = feature.id {
+                value["id"] = id.into();
+            }
+            features.push(value);
+        }
+    }
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+/// Twice the signed area of a ring with the surveyor's formula
+fn ring_area(ring: &[[i32; 2]]) -> i64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| i64::from(a[0]) * i64::from(b[1]) - i64::from(b[0]) * i64::from(a[1]))
+        .sum()
+}
+
+fn get_layer_name(layer: &[u8]) -> Result<&str, Error> {
+    let mut pos = 0;
+    while pos < layer.len() {
+        if let (LAYER_NAME_FIELD, Field::Bytes(name)) = read_field(layer, &mut pos)? {
+            return to_str(name);
+        }
+    }
+    Err(invalid_data("vector tile layer has no name"))
+}
+
+/// Content of a protobuf field, depending on its wire type
+enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+/// Read a single field at `pos`, returning its field number and content
+fn read_field<'a>(data: &'a [u8], pos: &mut usize) -> Result<(u64, Field<'a>), Error> {
+    let key = read_varint(data, pos)?;
+    let value = match key & 0x7 {
+        WIRE_VARINT => Field::Varint(read_varint(data, pos)?),
+        WIRE_I64 => Field::Fixed64(u64::from_le_bytes(read_fixed(data, pos)?)),
+        WIRE_LEN => {
+            let len = usize::try_from(read_varint(data, pos)?)
+                .map_err(|_| invalid_data("field is too long"))?;
+            Field::Bytes(read_slice(data, pos, len)?)
+        }
+        WIRE_I32 => Field::Fixed32(u32::from_le_bytes(read_fixed(data, pos)?)),
+        wire_type => Err(invalid_data(&format!(
+            "unsupported protobuf wire type {wire_type}"
+        )))?,
+    };
+    Ok((key >> 3, value))
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| invalid_data("unexpected end of data"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint is too long"))
+}
+
+fn read_fixed<const N: usize>(data: &[u8], pos: &mut usize) -> Result<[u8; N], Error> {
+    Ok(read_slice(data, pos, N)?.try_into().unwrap())
+}
+
+fn read_slice<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+    match pos.checked_add(len) {
+        Some(end) if end <= data.len() => {
+            let start = *pos;
+            *pos = end;
+            Ok(&data[start..end])
+        }
+        _ => Err(invalid_data("unexpected end of data")),
+    }
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn decode_zigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn write_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, (field << 3) | wire_type);
+}
+
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    write_key(buf, field, WIRE_LEN);
+    write_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+pub(crate) fn write_packed(buf: &mut Vec<u8>, field: u64, values: &[u32]) {
+    let mut data = Vec::new();
+    for v in values {
+        write_varint(&mut data, u64::from(*v));
+    }
+    write_bytes(buf, field, &data);
+}
+
+pub(crate) fn write_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(buf, field, WIRE_VARINT);
+    write_varint(buf, value);
+}
+
+/// Encode a vector tile command, see the geometry encoding of the vector tile spec
+pub(crate) fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+#[allow(clippy::cast_sign_loss)]
+pub(crate) fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn to_str(data: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid vector tile: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A layer with a name, a version, and an extent
+    fn layer(name: &str) -> Vec<u8> {
+        let mut layer = vec![0x0a, u8::try_from(name.len()).unwrap()];
+        layer.extend_from_slice(name.as_bytes());
+        layer.extend_from_slice(&[0x78, 0x02, 0x28, 0x80, 0x20]);
+        let mut field = vec![0x1a, u8::try_from(layer.len()).unwrap()];
+        field.extend(layer);
+        field
+    }
+
+    /// A layer with one point feature for each list of attributes
+    fn layer_with_features(name: &str, features: &[&[(&str, Value)]]) -> Vec<u8> {
+        let mut layer = Vec::new();
+        write_bytes(&mut layer, LAYER_NAME_FIELD, name.as_bytes());
+        let mut keys: Vec<&str> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        for attrs in features {
+            let mut tags = Vec::new();
+            for (key, value) in *attrs {
+                let key_idx = keys.iter().position(|v| v == key).unwrap_or_else(|| {
+                    keys.push(key);
+                    keys.len() - 1
+                });
+                let value_idx = values.iter().position(|v| v == value).unwrap_or_else(|| {
+                    values.push(value.clone());
+                    values.len() - 1
+                });
+                write_varint(&mut tags, key_idx as u64);
+                write_varint(&mut tags, value_idx as u64);
+            }
+            let mut feature = Vec::new();
+            write_bytes(&mut feature, FEATURE_TAGS_FIELD, &tags);
+            // geometry type: point, and a single MoveTo command
+            feature.extend_from_slice(&[0x18, 0x01, 0x22, 0x03, 0x09, 0x02, 0x02]);
+            write_bytes(&mut layer, LAYER_FEATURES_FIELD, &feature);
+        }
+        for key in keys {
+            write_bytes(&mut layer, LAYER_KEYS_FIELD, key.as_bytes());
+        }
+        for value in values {
+            write_bytes(&mut layer, LAYER_VALUES_FIELD, &value.encode());
+        }
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, TILE_LAYERS_FIELD, &layer);
+        tile
+    }
+
+    /// Decode the attributes of all features of all layers
+    fn get_attributes(tile: &[u8]) -> Vec<Vec<(String, Value)>> {
+        let mut result = Vec::new();
+        let mut pos = 0;
+        while pos < tile.len() {
+            let (_, Field::Bytes(layer)) = read_field(tile, &mut pos).unwrap() else {
+                panic!("unexpected field");
+            };
+            let (mut keys, mut values, mut features) = (Vec::new(), Vec::new(), Vec::new());
+            let mut pos = 0;
+            while pos < layer.len() {
+                match read_field(layer, &mut pos).unwrap() {
+                    (LAYER_KEYS_FIELD, Field::Bytes(v)) => keys.push(to_str(v).unwrap()),
+                    (LAYER_VALUES_FIELD, Field::Bytes(v)) => values.push(Value::parse(v).unwrap()),
+                    (LAYER_FEATURES_FIELD, Field::Bytes(v)) => features.push(v),
+                    _ => {}
+                }
+            }
+            for feature in features {
+                let mut attrs = Vec::new();
+                let mut pos = 0;
+                while pos < feature.len() {
+                    if let (FEATURE_TAGS_FIELD, Field::Bytes(tags)) =
+                        read_field(feature, &mut pos).unwrap()
+                    {
+                        let mut pos = 0;
+                        while pos < tags.len() {
+                            let key =
+                                usize::try_from(read_varint(tags, &mut pos).unwrap()).unwrap();
+                            let value =
+                                usize::try_from(read_varint(tags, &mut pos).unwrap()).unwrap();
+                            attrs.push((keys[key].to_string(), values[value].clone()));
+                        }
+                    }
+                }
+                result.push(attrs);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_truncate() {
+        let a = || layer_with_features("a", &[&[("n", Value::UInt(1))], &[("n", Value::UInt(2))]]);
+        let tile = [a(), layer("empty"), layer_with_features("b", &[&[]])].concat();
+        assert_eq!(count_mvt_features(&tile).unwrap(), 3);
+        assert_eq!(
+            truncate_mvt(&tile, 3, usize::MAX).unwrap(),
+            [a(), layer_with_features("b", &[&[]])].concat()
+        );
+
+        let truncated = truncate_mvt(&tile, 1, usize::MAX).unwrap();
+        assert_eq!(count_mvt_features(&truncated).unwrap(), 1);
+        assert_eq!(
+            get_attributes(&truncated),
+            vec![vec![("n".to_string(), Value::UInt(1))]]
+        );
+
+        let truncated = truncate_mvt(&tile, usize::MAX, a().len() - 1).unwrap();
+        assert_eq!(count_mvt_features(&truncated).unwrap(), 1);
+        assert!(truncated.len() < a().len());
+        assert!(truncate_mvt(&tile, usize::MAX, 2).unwrap().is_empty());
+        assert!(truncate_mvt(&tile, 0, usize::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_filter_layers() {
+        let tile = [layer("roads"), layer("water"), layer("poi")].concat();
+        let filtered = filter_mvt_layers(&tile, |name| name != "water").unwrap();
+        assert_eq!(filtered, [layer("roads"), layer("poi")].concat());
+        assert!(filter_mvt_layers(&tile, |_| false).unwrap().is_empty());
+        assert_eq!(filter_mvt_layers(&tile, |_| true).unwrap(), tile);
+
+        assert!(filter_mvt_layers(&tile[..tile.len() - 1], |_| true).is_err());
+        assert!(filter_mvt_layers(&[0x1a, 0x02, 0x78, 0x02], |_| true).is_err());
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let roads = layer_with_features(
+            "roads",
+            &[
+                &[
+                    ("osm_id", Value::Int(1)),
+                    ("highway", Value::String("primary".into())),
+                    ("lanes", Value::String("2".into())),
+                ],
+                &[
+                    ("osm_id", Value::Int(2)),
+                    ("lanes", Value::String("many".into())),
+                    ("oneway", Value::Int(1)),
+                ],
+            ],
+        );
+        let tile = [roads.clone(), layer("debug")].concat();
+
+        let pipeline: MvtPipeline = serde_yaml::from_str(indoc::indoc! {"
+            drop_layers: [debug]
+            drop_attributes: [osm_id]
+            rename_attributes: { highway: class }
+            cast_attributes: { lanes: int, oneway: bool }
+        "})
+        .unwrap();
+        let result = pipeline.apply(&tile).unwrap();
+        assert_eq!(
+            get_attributes(&result),
+            vec![
+                vec![
+                    ("class".to_string(), Value::String("primary".into())),
+                    ("lanes".to_string(), Value::SInt(2)),
+                ],
+                vec![("oneway".to_string(), Value::Bool(true))],
+            ]
+        );
+        // only the roads layer is left
+        assert_eq!(
+            filter_mvt_layers(&result, |name| name == "roads").unwrap(),
+            result
+        );
+
+        let pipeline = MvtPipeline {
+            drop_layers: Some(vec!["debug".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(pipeline.apply(&tile).unwrap(), roads);
+    }
+
+    #[test]
+    fn test_decode_mvt() {
+        let tile = layer_with_features(
+            "poi",
+            &[&[
+                ("name", Value::String("a".into())),
+                ("rank", Value::UInt(2)),
+            ]],
+        );
+        let layers = decode_mvt(&[tile, layer("empty")].concat()).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].name, "poi");
+        assert_eq!(layers[0].extent, 4096);
+        let feature = &layers[0].features[0];
+        assert_eq!(feature.geom_type, GeomType::Point);
+        assert_eq!(feature.geometry, vec![vec![[1, 1]]]);
+        assert_eq!(
+            serde_json::Value::Object(feature.properties.clone()),
+            serde_json::json!({"name": "a", "rank": 2})
+        );
+        assert!(layers[1].features.is_empty());
+
+        // a closed square: MoveTo(0,0), LineTo(10,0)(10,10)(0,10), ClosePath
+        let geometry = [9, 0, 0, 26, 20, 0, 0, 20, 19, 0, 15];
+        assert_eq!(
+            decode_geometry(&geometry, GeomType::Polygon).unwrap(),
+            vec![vec![[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]]]
+        );
+        assert!(decode_geometry(&[3], GeomType::Point).is_err());
+    }
+
+    #[test]
+    fn test_mvt_to_geojson() {
+        let feature = |id, geom_type, geometry: Vec<Vec<[i32; 2]>>| MvtFeature {
+            id,
+            geom_type,
+            geometry,
+            properties: serde_json::Map::new(),
+        };
+        let square = |x0: i32, y0: i32, size: i32| {
+            vec![
+                [x0, y0],
+                [x0 + size, y0],
+                [x0 + size, y0 + size],
+                [x0, y0 + size],
+                [x0, y0],
+            ]
+        };
+        let mut hole = square(1, 1, 2);
+        hole.reverse();
+        let layers = [MvtLayer {
+            name: "test".to_string(),
+            extent: 4,
+            features: vec![
+                feature(Some(7), GeomType::Point, vec![vec![[2, 2]]]),
+                feature(
+                    None,
+                    GeomType::LineString,
+                    vec![vec![[0, 0], [4, 4]], vec![[0, 4], [4, 0]]],
+                ),
+                feature(None, GeomType::Polygon, vec![square(0, 0, 4), hole]),
+                feature(
+                    None,
+                    GeomType::Polygon,
+                    vec![square(0, 0, 1), square(2, 2, 1)],
+                ),
+                feature(None, GeomType::Point, vec![]),
+            ],
+        }];
+        let xyz = TileCoord { z: 1, x: 1, y: 0 };
+        let json = mvt_to_geojson(&layers, xyz);
+        let features = json["features"].as_array().unwrap();
+        assert_eq!(features.len(), 4);
+        assert_eq!(
+            features[0],
+            json!({
+                "type": "Feature",
+                "id": 7,
+                "layer": "test",
+                "properties": {},
+                "geometry": {"type": "Point", "coordinates": [90.0, 66.513_260_4]},
+            })
+        );
+        assert_eq!(features[1]["geometry"]["type"], "MultiLineString");
+        assert_eq!(
+            features[1]["geometry"]["coordinates"][0],
+            json!([[0.0, 85.051_128_8], [180.0, 0.0]])
+        );
+        assert_eq!(features[2]["geometry"]["type"], "Polygon");
+        assert_eq!(
+            features[2]["geometry"]["coordinates"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(features[3]["geometry"]["type"], "MultiPolygon");
+    }
+
+    #[test]
+    fn test_cast() {
+        use AttributeType as T;
+        let s = |v: &str| Value::String(v.to_string());
+        assert_eq!(Value::Double(2.7).cast(T::Int), Some(Value::SInt(2)));
+        assert_eq!(s(" 3.5 ").cast(T::Float), Some(Value::Double(3.5)));
+        assert_eq!(s("3.5").cast(T::Int), Some(Value::SInt(3)));
+        assert_eq!(s("abc").cast(T::Int), None);
+        assert_eq!(Value::UInt(7).cast(T::String), Some(s("7")));
+        assert_eq!(Value::Bool(true).cast(T::Int), Some(Value::SInt(1)));
+        assert_eq!(s("No").cast(T::Bool), Some(Value::Bool(false)));
+        assert_eq!(s("maybe").cast(T::Bool), None);
+        assert_eq!(Value::SInt(-5).cast(T::Bool), Some(Value::Bool(true)));
+        assert_eq!(
+            Value::parse(&Value::SInt(-5).encode()).unwrap(),
+            Value::SInt(-5)
+        );
+    }
+
+    #[test]
+    fn test_layer_stats() {
+        let mut tile = layer("water");
+        tile.extend(layer_with_features(
+            "pois",
+            &[
+                &[("name", Value::String("a".to_string()))],
+                &[("rank", Value::UInt(1)), ("name", Value::Bool(true))],
+            ],
+        ));
+        let stats = mvt_layer_stats(&tile).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "water");
+        assert_eq!(stats[0].extent, 4096);
+        assert_eq!(stats[0].features, 0);
+        assert_eq!(stats[1].name, "pois");
+        assert_eq!(stats[1].features, 2);
+        assert_eq!(stats[1].points, 2);
+        assert_eq!(stats[1].polygons, 0);
+        assert_eq!(stats[1].keys, vec!["name", "rank"]);
+        assert_eq!(
+            stats[1].fields,
+            BTreeMap::from([
+                ("name".to_string(), "Mixed".to_string()),
+                ("rank".to_string(), "Number".to_string()),
+            ])
+        );
+        assert!(stats[0].fields.is_empty());
+        assert!(mvt_layer_stats(&tile[..tile.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use url::Url;
+
+use crate::utils::aws::{aws_region, env_var, AwsCredentials};
+use crate::MartinError::ConfigFetchError;
+use crate::MartinResult;
+
+/// True if the location is a URL that should be downloaded rather than a local file path
+#[must_use]
+pub fn is_remote(location: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|prefix| location.starts_with(prefix))
+}
+
+/// Download a text file from an `http(s)://` or `s3://bucket/key` URL.
+///
+/// S3 objects are fetched from AWS, or from the S3-compatible service set with `AWS_ENDPOINT_URL`.
+/// Requests are signed if `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` are set,
+/// otherwise the object must be public.
+pub async fn fetch_text(location: &str) -> MartinResult<String> {
+    let err = |e: &dyn std::fmt::Display| ConfigFetchError(e.to_string(), location.to_string());
+
+    let mut url = Url::parse(location).map_err(|e| err(&e))?;
+    let mut headers = Vec::new();
+    if url.scheme() == "s3" {
+        let bucket = url.host_str().ok_or_else(|| err(&"missing bucket name"))?;
+        let region = aws_region();
+        url = s3_object_url(
+            bucket,
+            url.path(),
+            &region,
+            env_var("AWS_ENDPOINT_URL").as_deref(),
+        )
+        .map_err(|e| err(&e))?;
+        if let Some(credentials) = AwsCredentials::from_env() {
+            headers = credentials.sign("GET", &url, &region, "s3", b"", &[]);
+        }
+    }
+
+    let mut request = reqwest::Client::new().get(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let response = request.send().await.map_err(|e| err(&e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(err(&format!("server responded with {status}")));
+    }
+    response.text().await.map_err(|e| err(&e))
+}
+
+/// Virtual-hosted URL of an AWS S3 object, or a path-style URL for other S3-compatible services
+fn s3_object_url(
+    bucket: &str,
+    key: &str,
+    region: &str,
+    endpoint: Option<&str>,
+) -> Result<Url, url::ParseError> {
+    let key = key.trim_start_matches('/');
+    if let Some(endpoint) = endpoint {
+        Url::parse(&format!(
+            "{}/{bucket}/{key}",
+            endpoint.trim_end_matches('/')
+        ))
+    } else {
+        Url::parse(&format!("https://{bucket}.s3.{region}.amazonaws.com/{key}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_remote() {
+        assert!(is_remote("s3://bucket/martin.yaml"));
+        assert!(is_remote("https://example.org/martin.yaml"));
+        assert!(!is_remote("/etc/martin.yaml"));
+        assert!(!is_remote("config.yaml"));
+    }
+
+    #[test]
+    fn test_s3_object_url() {
+        assert_eq!(
+            s3_object_url("bucket", "/dir/martin.yaml", "eu-west-1", None)
+                .unwrap()
+                .as_str(),
+            "https://bucket.s3.eu-west-1.amazonaws.com/dir/martin.yaml"
+        );
+        assert_eq!(
+            s3_object_url(
+                "bucket",
+                "/martin.yaml",
+                "eu-west-1",
+                Some("http://minio:9000/")
+            )
+            .unwrap()
+            .as_str(),
+            "http://minio:9000/bucket/martin.yaml"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Secrets read from files or from external secret managers, so that credentials
+//! never need to appear in the config file or in the process environment.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use url::Url;
+
+use crate::utils::aws::{aws_region, env_var, AwsCredentials};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecretError {
+    #[error("Unable to read secret file {}: {0}", .1.display())]
+    SecretFileError(#[source] io::Error, PathBuf),
+
+    #[error("Invalid secret reference {0}, expected aws-sm://<secret-id>[#<key>] or vault://<path>#<key>")]
+    InvalidReference(String),
+
+    #[error("Environment variable {0} must be set to read secret {1}")]
+    MissingEnvVar(&'static str, String),
+
+    #[error("Unable to read secret {1}: {0}")]
+    FetchError(String, String),
+}
+
+/// Read a secret from a file, ignoring the surrounding whitespace such as a trailing newline
+pub fn read_secret_file(path: &Path) -> Result<String, SecretError> {
+    std::fs::read_to_string(path)
+        .map(|v| v.trim().to_string())
+        .map_err(|e| SecretError::SecretFileError(e, path.to_path_buf()))
+}
+
+/// Read a secret from a secret manager:
+/// * `aws-sm://<secret-id>[#<key>]` - AWS Secrets Manager, using the `AWS_*` environment variables.
+///   With a key, the secret is parsed as a JSON object, e.g. the ones created for RDS databases.
+/// * `vault://<path>#<key>` - `HashiCorp` Vault KV engine (v1 or v2) at `VAULT_ADDR`, using `VAULT_TOKEN`.
+pub async fn read_secret(reference: &str) -> Result<String, SecretError> {
+    let invalid = || SecretError::InvalidReference(reference.to_string());
+    let (scheme, path) = reference.split_once("://").ok_or_else(invalid)?;
+    let (path, key) = match path.split_once('#') {
+        Some((path, key)) => (path, Some(key)),
+        None => (path, None),
+    };
+    if path.is_empty() {
+        return Err(invalid());
+    }
+    let err = |e: &dyn std::fmt::Display| SecretError::FetchError(e.to_string(), reference.into());
+
+    match scheme {
+        "aws-sm" => {
+            let secret = get_aws_secret(path).await.map_err(|e| err(&e))?;
+            match key {
+                Some(key) => {
+                    let value = serde_json::from_str(&secret).map_err(|e| err(&e))?;
+                    get_field(&value, key).ok_or_else(|| err(&format!("no key {key}")))
+                }
+                None => Ok(secret),
+            }
+        }
+        "vault" => {
+            let key = key.ok_or_else(invalid)?;
+            let value = get_vault_secret(reference, path).await?;
+            // KV v2 nests the secret in another data object
+            let data = value.pointer("/data/data").or_else(|| value.get("data"));
+            data.and_then(|v| get_field(v, key))
+                .ok_or_else(|| err(&format!("no key {key}")))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn get_field(value: &Value, key: &str) -> Option<String> {
+    match value.get(key)? {
+        Value::String(v) => Some(v.clone()),
+        Value::Null => None,
+        v => Some(v.to_string()),
+    }
+}
+
+async fn get_aws_secret(
+    secret_id: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let region = aws_region();
+    let endpoint = env_var("AWS_ENDPOINT_URL_SECRETS_MANAGER")
+        .or_else(|| env_var("AWS_ENDPOINT_URL"))
+        .unwrap_or_else(|| format!("https://secretsmanager.{region}.amazonaws.com"));
+    let url = Url::parse(&endpoint)?;
+    let credentials = AwsCredentials::from_env()
+        .ok_or("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set")?;
+    let body = serde_json::json!({ "SecretId": secret_id }).to_string();
+    let headers = credentials.sign(
+        "POST",
+        &url,
+        &region,
+        "secretsmanager",
+        body.as_bytes(),
+        &[
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", "secretsmanager.GetSecretValue"),
+        ],
+    );
+
+    let mut request = reqwest::Client::new().post(url).body(body);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("Secrets Manager responded with {status}").into());
+    }
+    let value: Value = response.json().await?;
+    match value.get("SecretString") {
+        Some(Value::String(v)) => Ok(v.clone()),
+        _ => Err("the secret has no string value".into()),
+    }
+}
+
+async fn get_vault_secret(reference: &str, path: &str) -> Result<Value, SecretError> {
+    let missing = |var| SecretError::MissingEnvVar(var, reference.to_string());
+    let err = |e: &dyn std::fmt::Display| SecretError::FetchError(e.to_string(), reference.into());
+    let address = env_var("VAULT_ADDR").ok_or_else(|| missing("VAULT_ADDR"))?;
+    let token = env_var("VAULT_TOKEN").ok_or_else(|| missing("VAULT_TOKEN"))?;
+
+    let url = format!("{}/v1/{path}", address.trim_end_matches('/'));
+    let mut request = reqwest::Client::new()
+        .get(url)
+        .header("X-Vault-Token", token);
+    if let Some(namespace) = env_var("VAULT_NAMESPACE") {
+        request = request.header("X-Vault-Namespace", namespace);
+    }
+    let response = request.send().await.map_err(|e| err(&e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(err(&format!("Vault responded with {status}")));
+    }
+    response.json().await.map_err(|e| err(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_read_secret_file() {
+        let path = std::env::temp_dir().join("martin_test_read_secret_file");
+        std::fs::write(&path, "s3cr3t\n").unwrap();
+        assert_eq!(read_secret_file(&path).unwrap(), "s3cr3t");
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_secret_file(&path).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_reference() {
+        for reference in ["password", "aws-sm://", "vault://secret/martin", "ftp://a"] {
+            assert!(
+                matches!(
+                    read_secret(reference).await,
+                    Err(SecretError::InvalidReference(_))
+                ),
+                "{reference}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_field() {
+        let value = json!({"password": "pw", "port": 5432, "host": null});
+        assert_eq!(get_field(&value, "password"), Some("pw".to_string()));
+        assert_eq!(get_field(&value, "port"), Some("5432".to_string()));
+        assert_eq!(get_field(&value, "host"), None);
+        assert_eq!(get_field(&value, "user"), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Logging of tiles that take too long to generate, with the time spent in each phase
+//! of the backend, e.g. waiting for a database connection and running the query.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::TileCoord;
+
+tokio::task_local! {
+    static PHASES: RefCell<Vec<(&'static str, Duration)>>;
+}
+
+/// Record how long a phase of the tile generation took.
+/// Does nothing unless the tile is generated by [`log_if_slow`].
+#[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+pub(crate) fn record_phase(name: &'static str, duration: Duration) {
+    let _ = PHASES.try_with(|phases| phases.borrow_mut().push((name, duration)));
+}
+
+/// Run the tile generation, and log a warning if it took at least `threshold`
+pub(crate) async fn log_if_slow<T>(
+    get_tile: impl Future<Output = T>,
+    threshold: Option<Duration>,
+    source_id: &str,
+    xyz: TileCoord,
+    query: Option<&str>,
+) -> T {
+    let Some(threshold) = threshold else {
+        return get_tile.await;
+    };
+    let start = Instant::now();
+    let (result, phases) = PHASES
+        .scope(RefCell::default(), async {
+            let result = get_tile.await;
+            (result, PHASES.with(RefCell::take))
+        })
+        .await;
+    let elapsed = start.elapsed();
+    if elapsed >= threshold {
+        warn!(
+            "{}",
+            slow_tile_message(source_id, xyz, query, elapsed, &phases)
+        );
+        #[cfg(feature = "metrics")]
+        crate::srv::count_slow_tile(source_id);
+    }
+    result
+}
+
+fn slow_tile_message(
+    source_id: &str,
+    xyz: TileCoord,
+    query: Option<&str>,
+    elapsed: Duration,
+    phases: &[(&'static str, Duration)],
+) -> String {
+    let mut msg = format!(
+        "Slow tile {source_id}/{xyz:#} took {}ms",
+        elapsed.as_millis()
+    );
+    if let Some(query) = query {
+        let _ = write!(msg, " with query '{query}'");
+    }
+    if !phases.is_empty() {
+        let breakdown = phases
+            .iter()
+            .fold(String::new(), |mut acc, (name, duration)| {
+                let sep = if acc.is_empty() { "" } else { ", " };
+                let _ = write!(acc, "{sep}{name} {}ms", duration.as_millis());
+                acc
+            });
+        let _ = write!(msg, " ({breakdown})");
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slow_tile_message() {
+        let xyz = TileCoord { z: 3, x: 1, y: 2 };
+        let elapsed = Duration::from_millis(1520);
+        assert_eq!(
+            slow_tile_message("src", xyz, None, elapsed, &[]),
+            "Slow tile src/3/1/2 took 1520ms"
+        );
+        let phases = [
+            ("pool", Duration::from_millis(12)),
+            ("query", Duration::from_millis(1500)),
+        ];
+        assert_eq!(
+            slow_tile_message("src", xyz, Some("date=2024"), elapsed, &phases),
+            "Slow tile src/3/1/2 took 1520ms with query 'date=2024' (pool 12ms, query 1500ms)"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_record_phase() {
+        let xyz = TileCoord { z: 0, x: 0, y: 0 };
+        // phases recorded outside of log_if_slow are ignored
+        record_phase("ignored", Duration::ZERO);
+        let result = log_if_slow(
+            async {
+                record_phase("query", Duration::from_millis(5));
+                PHASES.with(|v| v.borrow().len())
+            },
+            Some(Duration::ZERO),
+            "src",
+            xyz,
+            None,
+        )
+        .await;
+        assert_eq!(result, 1);
+    }
+}
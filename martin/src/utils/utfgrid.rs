@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of rows and columns of a `UTFGrid`, i.e. one cell for every 4x4 pixels of a 256px tile
+#[cfg(any(feature = "postgres", feature = "mbtiles"))]
+pub const UTFGRID_SIZE: usize = 64;
+
+/// Interaction data of a tile in the `UTFGrid` 1.3 format, see
+/// <https://github.com/mapbox/utfgrid-spec/blob/master/1.3/utfgrid.md>
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct UtfGrid {
+    pub grid: Vec<String>,
+    pub keys: Vec<String>,
+    /// Grids of `MBTiles` files keep the data of their keys in a separate table
+    #[serde(default)]
+    pub data: BTreeMap<String, serde_json::Value>,
+}
+
+impl UtfGrid {
+    /// Build a grid of `size` by `size` cells from the `(row, column, key, data)` of the non-empty cells.
+    /// Cells outside of the grid are ignored, and if a key is repeated, the data of its first cell is used.
+    #[must_use]
+    pub fn new(
+        size: usize,
+        cells: impl IntoIterator<Item = (usize, usize, String, serde_json::Value)>,
+    ) -> Self {
+        let mut keys = vec![String::new()];
+        let mut key_ids = HashMap::new();
+        let mut data = BTreeMap::new();
+        let mut grid = vec![vec![encode_id(0); size]; size];
+
+        for (row, col, key, value) in cells {
+            if row >= size || col >= size {
+                continue;
+            }
+            let id = *key_ids.entry(key.clone()).or_insert_with(|| {
+                keys.push(key.clone());
+                data.insert(key, value);
+                keys.len() - 1
+            });
+            grid[row][col] = encode_id(id);
+        }
+
+        Self {
+            grid: grid.into_iter().map(String::from_iter).collect(),
+            keys,
+            data,
+        }
+    }
+}
+
+/// Encode a key index as a grid character, skipping `"` and `\` which would need escaping in JSON,
+/// and the UTF-16 surrogates which are not valid characters
+fn encode_id(id: usize) -> char {
+    let mut code = u32::try_from(id).unwrap_or(u32::MAX).saturating_add(32);
+    if code >= 34 {
+        code += 1;
+    }
+    if code >= 92 {
+        code += 1;
+    }
+    if code >= 0xD800 {
+        code = code.saturating_add(0x800);
+    }
+    char::from_u32(code).unwrap_or(' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_id() {
+        assert_eq!(encode_id(0), ' ');
+        assert_eq!(encode_id(1), '!');
+        assert_eq!(encode_id(2), '#');
+        assert_eq!(encode_id(58), '[');
+        assert_eq!(encode_id(59), ']');
+        assert_eq!(encode_id(93), '\u{7f}');
+        assert_eq!(encode_id(0xD800 - 35), '\u{d7ff}');
+        assert_eq!(encode_id(0xD800 - 34), '\u{e000}');
+    }
+
+    #[test]
+    fn test_utfgrid() {
+        let grid = UtfGrid::new(
+            3,
+            vec![
+                (0, 0, "a".to_string(), json!({"name": "A"})),
+                (0, 1, "a".to_string(), json!({"name": "ignored"})),
+                (2, 2, "b".to_string(), json!({"name": "B"})),
+                (3, 0, "c".to_string(), json!({})),
+            ],
+        );
+        assert_eq!(grid.grid, vec!["!! ", "   ", "  #"]);
+        assert_eq!(grid.keys, vec!["", "a", "b"]);
+        assert_eq!(
+            serde_json::to_value(&grid.data).unwrap(),
+            json!({"a": {"name": "A"}, "b": {"name": "B"}})
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::{Read as _, Write as _};
+use std::time::Duration;
 
 use actix_web::http::Uri;
 use flate2::read::GzDecoder;
@@ -33,6 +34,22 @@ pub fn encode_brotli(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     Ok(encoder.into_inner())
 }
 
+pub fn decode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::stream::decode_all(data)
+}
+
+pub fn encode_zstd(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    zstd::stream::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Format a wait time as a `Retry-After` header value.
+/// The header only supports whole seconds, so round up to avoid clients retrying too early.
+#[must_use]
+pub fn retry_after_value(wait: Duration) -> String {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    secs.max(1).to_string()
+}
+
 pub fn parse_base_path(path: &str) -> MartinResult<String> {
     if !path.starts_with('/') {
         return Err(BasePathError(path.to_string()));
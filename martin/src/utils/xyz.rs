@@ -1,4 +1,7 @@
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use martin_tile_utils::{xyz_to_bbox, MAX_ZOOM};
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct TileCoord {
@@ -7,6 +10,20 @@ pub struct TileCoord {
     pub y: u32,
 }
 
+impl TileCoord {
+    /// True if the tile exists in the tile matrix of its zoom level
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.z <= MAX_ZOOM && self.x < (1 << self.z) && self.y < (1 << self.z)
+    }
+
+    /// Bounding box of the tile as `[min_lng, min_lat, max_lng, max_lat]`
+    #[must_use]
+    pub fn bbox(&self) -> [f64; 4] {
+        xyz_to_bbox(self.z, self.x, self.y, self.x, self.y)
+    }
+}
+
 impl Display for TileCoord {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if f.alternate() {
@@ -16,3 +33,47 @@ impl Display for TileCoord {
         }
     }
 }
+
+/// Parse a `z/x/y` string, the same format as the alternate display.
+impl FromStr for TileCoord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid tile coordinate '{s}', expected z/x/y");
+        let mut parts = s.split('/');
+        let (Some(z), Some(x), Some(y), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(err());
+        };
+        Ok(Self {
+            z: z.parse().map_err(|_| err())?,
+            x: x.parse().map_err(|_| err())?,
+            y: y.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tile_coord() {
+        let xyz: TileCoord = "3/1/2".parse().unwrap();
+        assert_eq!(xyz, TileCoord { z: 3, x: 1, y: 2 });
+        assert_eq!(format!("{xyz:#}").parse::<TileCoord>().unwrap(), xyz);
+        for bad in ["", "1/2", "1/2/3/4", "a/1/2", "1/-1/2", "300/1/2"] {
+            assert!(bad.parse::<TileCoord>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn tile_coord_validity() {
+        assert!(TileCoord { z: 0, x: 0, y: 0 }.is_valid());
+        assert!(TileCoord { z: 3, x: 7, y: 7 }.is_valid());
+        assert!(!TileCoord { z: 3, x: 8, y: 0 }.is_valid());
+        assert!(!TileCoord { z: 3, x: 0, y: 8 }.is_valid());
+        assert!(!TileCoord { z: 31, x: 0, y: 0 }.is_valid());
+    }
+}
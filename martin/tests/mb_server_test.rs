@@ -1,10 +1,14 @@
-use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::http::header::{
+    ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use actix_web::http::{Method, StatusCode};
 use actix_web::test::{call_service, read_body, read_body_json, TestRequest};
 use ctor::ctor;
 use indoc::indoc;
 use insta::assert_yaml_snapshot;
 use martin::srv::SrvConfig;
-use martin::{decode_brotli, decode_gzip};
+use martin::{decode_brotli, decode_gzip, decode_zstd};
 use tilejson::TileJSON;
 pub mod utils;
 pub use utils::*;
@@ -66,18 +70,28 @@ async fn mbt_get_catalog() {
       m_json:
         content_type: application/json
         name: Dummy json data
+        tilejson: "http://localhost:8080/m_json"
+        tiles: "http://localhost:8080/m_json/{z}/{x}/{y}"
       m_mvt:
         content_encoding: gzip
         content_type: application/x-protobuf
         description: Major cities from Natural Earth data
         name: Major cities from Natural Earth data
+        style: "http://localhost:8080/style/m_mvt"
+        tilejson: "http://localhost:8080/m_mvt"
+        tiles: "http://localhost:8080/m_mvt/{z}/{x}/{y}"
       m_raw_mvt:
         content_type: application/x-protobuf
         description: Major cities from Natural Earth data
         name: Major cities from Natural Earth data
+        style: "http://localhost:8080/style/m_raw_mvt"
+        tilejson: "http://localhost:8080/m_raw_mvt"
+        tiles: "http://localhost:8080/m_raw_mvt/{z}/{x}/{y}"
       m_webp:
         content_type: image/webp
         name: ne2sr
+        tilejson: "http://localhost:8080/m_webp"
+        tiles: "http://localhost:8080/m_webp/{z}/{x}/{y}"
     "###);
 }
 
@@ -98,21 +112,60 @@ async fn mbt_get_catalog_gzip() {
       m_json:
         content_type: application/json
         name: Dummy json data
+        tilejson: "http://localhost:8080/m_json"
+        tiles: "http://localhost:8080/m_json/{z}/{x}/{y}"
       m_mvt:
         content_encoding: gzip
         content_type: application/x-protobuf
         description: Major cities from Natural Earth data
         name: Major cities from Natural Earth data
+        style: "http://localhost:8080/style/m_mvt"
+        tilejson: "http://localhost:8080/m_mvt"
+        tiles: "http://localhost:8080/m_mvt/{z}/{x}/{y}"
       m_raw_mvt:
         content_type: application/x-protobuf
         description: Major cities from Natural Earth data
         name: Major cities from Natural Earth data
+        style: "http://localhost:8080/style/m_raw_mvt"
+        tilejson: "http://localhost:8080/m_raw_mvt"
+        tiles: "http://localhost:8080/m_raw_mvt/{z}/{x}/{y}"
       m_webp:
         content_type: image/webp
         name: ne2sr
+        tilejson: "http://localhost:8080/m_webp"
+        tiles: "http://localhost:8080/m_webp/{z}/{x}/{y}"
     "###);
 }
 
+#[actix_rt::test]
+async fn mbt_get_catalog_html() {
+    let app = create_app! { CONFIG };
+    let accept = (ACCEPT, "text/html,application/xhtml+xml,*/*;q=0.8");
+    let req = test_get("/catalog").insert_header(accept).to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let content_type = response.headers().get(CONTENT_TYPE).unwrap();
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains(r#"<a href="./m_mvt">m_mvt</a>"#));
+    assert!(body.contains("Major cities from Natural Earth data"));
+}
+
+#[actix_rt::test]
+async fn mbt_get_openapi() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/api.json").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let body: serde_json::Value = read_body_json(response).await;
+    assert_eq!(body["openapi"], "3.0.3");
+    let paths = body["paths"].as_object().unwrap();
+    assert!(paths.contains_key("/catalog"));
+    assert!(paths.contains_key("/m_webp"));
+    let tile = &paths["/m_webp/{z}/{x}/{y}"]["get"]["responses"]["200"]["content"];
+    assert!(tile["image/webp"].is_object());
+}
+
 #[actix_rt::test]
 async fn mbt_get_tilejson() {
     let app = create_app! { CONFIG };
@@ -126,6 +179,23 @@ async fn mbt_get_tilejson() {
     assert_eq!(body.maxzoom, Some(6));
 }
 
+#[actix_rt::test]
+async fn mbt_get_tilejson_v1() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/v1/m_mvt").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let body: TileJSON = read_body_json(response).await;
+    assert_eq!(
+        body.tiles,
+        vec!["http://localhost:8080/v1/m_mvt/{z}/{x}/{y}"]
+    );
+
+    let req = test_get("/v1/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    assert_response(response).await;
+}
+
 #[actix_rt::test]
 async fn mbt_get_tilejson_gzip() {
     let app = create_app! { CONFIG };
@@ -182,6 +252,143 @@ async fn mbt_get_mvt() {
     assert_eq!(body.len(), 1828);
 }
 
+/// transform tiles with a per-source pipeline
+#[actix_rt::test]
+async fn mbt_get_mvt_pipeline() {
+    let app = create_app! { indoc! {"
+        mbtiles:
+            sources:
+                m_mvt: ../tests/fixtures/mbtiles/world_cities.mbtiles
+        source_options:
+            m_mvt:
+                pipeline:
+                    rename_attributes: { name: city_name }
+    "} };
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    let body = read_body(response).await;
+    assert!(body.windows(9).any(|v| v == b"city_name"));
+
+    let req = test_get("/catalog").to_request();
+    let response = call_service(&app, req).await;
+    let body: serde_json::Value = read_body_json(response).await;
+    assert!(body["tiles"]["m_mvt"]["content_encoding"].is_null());
+}
+
+/// select layers of an MVT tile with the `layers` query parameter
+#[actix_rt::test]
+async fn mbt_get_mvt_layers() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/0/0/0?layers=roads,cities").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    let body = read_body(response).await;
+    assert_eq!(body.len(), 1828);
+
+    let req = test_get("/m_mvt/0/0/0?layers=roads")
+        .insert_header((ACCEPT_ENCODING, "gzip"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let req = test_get("/m_json/0/0/0?layers=roads").to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+/// get several tiles at once, including one outside of the zoom range
+#[actix_rt::test]
+async fn mbt_get_tiles_batch() {
+    let app = create_app! { CONFIG };
+    let req = TestRequest::post()
+        .uri("/m_mvt/tiles")
+        .set_json(["0/0/0", "20/0/0"])
+        .to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let content_type = response.headers().get(CONTENT_TYPE).unwrap();
+    let boundary = content_type
+        .to_str()
+        .unwrap()
+        .strip_prefix("multipart/mixed; boundary=")
+        .unwrap()
+        .to_string();
+    let body = read_body(response).await;
+    let body = String::from_utf8_lossy(&body);
+    let parts: Vec<_> = body.split(&format!("--{boundary}")).collect();
+    assert_eq!(parts.len(), 4);
+    assert!(parts[1].starts_with(
+        "\r\nContent-Location: 0/0/0\r\nContent-Type: application/x-protobuf\r\nContent-Length: 1828\r\n\r\n"
+    ));
+    assert_eq!(
+        parts[2],
+        "\r\nContent-Location: 20/0/0\r\nContent-Length: 0\r\n\r\n\r\n"
+    );
+    assert_eq!(parts[3], "--\r\n");
+
+    let req = TestRequest::post()
+        .uri("/m_mvt/tiles")
+        .set_json(["0/0"])
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+/// get an MVT tile, and re-request it using the received `ETag`
+#[actix_rt::test]
+async fn mbt_get_mvt_etag() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_mvt/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let etag = response.headers().get(ETAG).unwrap().clone();
+
+    let req = test_get("/m_mvt/0/0/0")
+        .insert_header((IF_NONE_MATCH, etag.clone()))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get(ETAG).unwrap(), &etag);
+    assert!(read_body(response).await.is_empty());
+
+    let req = test_get("/m_mvt/0/0/0")
+        .insert_header((IF_NONE_MATCH, "\"other\""))
+        .to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert_eq!(read_body(response).await.len(), 1828);
+}
+
+/// get a raster tile, and re-request it using the received `Last-Modified` date
+#[actix_rt::test]
+async fn mbt_get_raster_last_modified() {
+    let app = create_app! { CONFIG };
+    let req = test_get("/m_webp/0/0/0").to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let last_modified = response.headers().get(LAST_MODIFIED).unwrap().clone();
+
+    let req = test_get("/m_webp/0/0/0")
+        .insert_header((IF_MODIFIED_SINCE, last_modified.clone()))
+        .to_request();
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(
+        response.headers().get(LAST_MODIFIED).unwrap(),
+        &last_modified
+    );
+
+    let req = test_get("/m_webp/0/0/0")
+        .insert_header((IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT"))
+        .to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert_eq!(read_body(response).await.len(), 11586);
+}
+
 /// get an MVT tile with accepted gzip enc
 #[actix_rt::test]
 async fn mbt_get_mvt_gzip() {
@@ -201,6 +408,35 @@ async fn mbt_get_mvt_gzip() {
     assert_eq!(body.len(), 1828);
 }
 
+/// HEAD of a stored gzip tile is answered from its size, without reading the data
+#[actix_rt::test]
+async fn mbt_head_mvt_gzip() {
+    let app = create_app! { CONFIG };
+    let accept = (ACCEPT_ENCODING, "gzip");
+    let req = TestRequest::default()
+        .method(Method::HEAD)
+        .uri("/m_mvt/0/0/0")
+        .insert_header(accept)
+        .to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    let headers = response.headers();
+    assert_eq!(headers.get(CONTENT_TYPE).unwrap(), "application/x-protobuf");
+    assert_eq!(headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+    assert_eq!(headers.get(CONTENT_LENGTH).unwrap(), "1107");
+    assert!(headers.get(ETAG).is_none());
+
+    // the tile must be decompressed, so the full response is built
+    let req = TestRequest::default()
+        .method(Method::HEAD)
+        .uri("/m_mvt/0/0/0")
+        .to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert!(response.headers().get(ETAG).is_some());
+    assert_eq!(read_body(response).await.len(), 1828);
+}
+
 /// get an MVT tile with accepted brotli enc
 #[actix_rt::test]
 async fn mbt_get_mvt_brotli() {
@@ -220,6 +456,23 @@ async fn mbt_get_mvt_brotli() {
     assert_eq!(body.len(), 1828);
 }
 
+/// get an MVT tile with accepted zstd enc
+#[actix_rt::test]
+async fn mbt_get_mvt_zstd() {
+    let app = create_app! { CONFIG };
+    let accept = (ACCEPT_ENCODING, "zstd");
+    let req = test_get("/m_mvt/0/0/0").insert_header(accept).to_request();
+    let response = call_service(&app, req).await;
+    let response = assert_response(response).await;
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-protobuf"
+    );
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "zstd");
+    let body = decode_zstd(&read_body(response).await).unwrap();
+    assert_eq!(body.len(), 1828);
+}
+
 /// get an uncompressed MVT tile
 #[actix_rt::test]
 async fn mbt_get_raw_mvt() {
@@ -66,73 +66,145 @@ postgres:
       "-function.withweired---_-characters":
         content_type: application/x-protobuf
         description: a function source with special characters
+        style: "http://localhost:8080/style/-function.withweired---_-characters"
+        tilejson: "http://localhost:8080/-function.withweired---_-characters"
+        tiles: "http://localhost:8080/-function.withweired---_-characters/{z}/{x}/{y}"
       ".-Points-----------quote":
         content_type: application/x-protobuf
         description: Escaping test table
+        style: "http://localhost:8080/style/.-Points-----------quote"
+        tilejson: "http://localhost:8080/.-Points-----------quote"
+        tiles: "http://localhost:8080/.-Points-----------quote/{z}/{x}/{y}"
       MixPoints:
         content_type: application/x-protobuf
         description: a description from comment on table
+        style: "http://localhost:8080/style/MixPoints"
+        tilejson: "http://localhost:8080/MixPoints"
+        tiles: "http://localhost:8080/MixPoints/{z}/{x}/{y}"
       auto_table:
         content_type: application/x-protobuf
         description: autodetect.auto_table.geom
+        style: "http://localhost:8080/style/auto_table"
+        tilejson: "http://localhost:8080/auto_table"
+        tiles: "http://localhost:8080/auto_table/{z}/{x}/{y}"
       bigint_table:
         content_type: application/x-protobuf
         description: autodetect.bigint_table.geom
+        style: "http://localhost:8080/style/bigint_table"
+        tilejson: "http://localhost:8080/bigint_table"
+        tiles: "http://localhost:8080/bigint_table/{z}/{x}/{y}"
       function_Mixed_Name:
         content_type: application/x-protobuf
         description: a function source with MixedCase name
+        style: "http://localhost:8080/style/function_Mixed_Name"
+        tilejson: "http://localhost:8080/function_Mixed_Name"
+        tiles: "http://localhost:8080/function_Mixed_Name/{z}/{x}/{y}"
       function_null:
         content_type: application/x-protobuf
         description: public.function_null
+        style: "http://localhost:8080/style/function_null"
+        tilejson: "http://localhost:8080/function_null"
+        tiles: "http://localhost:8080/function_null/{z}/{x}/{y}"
       function_null_row:
         content_type: application/x-protobuf
         description: public.function_null_row
+        style: "http://localhost:8080/style/function_null_row"
+        tilejson: "http://localhost:8080/function_null_row"
+        tiles: "http://localhost:8080/function_null_row/{z}/{x}/{y}"
       function_null_row2:
         content_type: application/x-protobuf
         description: public.function_null_row2
+        style: "http://localhost:8080/style/function_null_row2"
+        tilejson: "http://localhost:8080/function_null_row2"
+        tiles: "http://localhost:8080/function_null_row2/{z}/{x}/{y}"
       function_zoom_xy:
         content_type: application/x-protobuf
         description: public.function_zoom_xy
+        style: "http://localhost:8080/style/function_zoom_xy"
+        tilejson: "http://localhost:8080/function_zoom_xy"
+        tiles: "http://localhost:8080/function_zoom_xy/{z}/{x}/{y}"
       function_zxy:
         content_type: application/x-protobuf
         description: public.function_zxy
+        style: "http://localhost:8080/style/function_zxy"
+        tilejson: "http://localhost:8080/function_zxy"
+        tiles: "http://localhost:8080/function_zxy/{z}/{x}/{y}"
       function_zxy2:
         content_type: application/x-protobuf
         description: public.function_zxy2
+        style: "http://localhost:8080/style/function_zxy2"
+        tilejson: "http://localhost:8080/function_zxy2"
+        tiles: "http://localhost:8080/function_zxy2/{z}/{x}/{y}"
       function_zxy_query:
         content_type: application/x-protobuf
+        style: "http://localhost:8080/style/function_zxy_query"
+        tilejson: "http://localhost:8080/function_zxy_query"
+        tiles: "http://localhost:8080/function_zxy_query/{z}/{x}/{y}"
       function_zxy_query_jsonb:
         content_type: application/x-protobuf
         description: public.function_zxy_query_jsonb
+        style: "http://localhost:8080/style/function_zxy_query_jsonb"
+        tilejson: "http://localhost:8080/function_zxy_query_jsonb"
+        tiles: "http://localhost:8080/function_zxy_query_jsonb/{z}/{x}/{y}"
       function_zxy_query_test:
         content_type: application/x-protobuf
         description: public.function_zxy_query_test
+        style: "http://localhost:8080/style/function_zxy_query_test"
+        tilejson: "http://localhost:8080/function_zxy_query_test"
+        tiles: "http://localhost:8080/function_zxy_query_test/{z}/{x}/{y}"
       function_zxy_row:
         content_type: application/x-protobuf
         description: public.function_zxy_row
+        style: "http://localhost:8080/style/function_zxy_row"
+        tilejson: "http://localhost:8080/function_zxy_row"
+        tiles: "http://localhost:8080/function_zxy_row/{z}/{x}/{y}"
       function_zxy_row_key:
         content_type: application/x-protobuf
         description: public.function_zxy_row_key
+        style: "http://localhost:8080/style/function_zxy_row_key"
+        tilejson: "http://localhost:8080/function_zxy_row_key"
+        tiles: "http://localhost:8080/function_zxy_row_key/{z}/{x}/{y}"
       points1:
         content_type: application/x-protobuf
         description: public.points1.geom
+        style: "http://localhost:8080/style/points1"
+        tilejson: "http://localhost:8080/points1"
+        tiles: "http://localhost:8080/points1/{z}/{x}/{y}"
       points1_vw:
         content_type: application/x-protobuf
         description: public.points1_vw.geom
+        style: "http://localhost:8080/style/points1_vw"
+        tilejson: "http://localhost:8080/points1_vw"
+        tiles: "http://localhost:8080/points1_vw/{z}/{x}/{y}"
       points2:
         content_type: application/x-protobuf
         description: public.points2.geom
+        style: "http://localhost:8080/style/points2"
+        tilejson: "http://localhost:8080/points2"
+        tiles: "http://localhost:8080/points2/{z}/{x}/{y}"
       points3857:
         content_type: application/x-protobuf
         description: public.points3857.geom
+        style: "http://localhost:8080/style/points3857"
+        tilejson: "http://localhost:8080/points3857"
+        tiles: "http://localhost:8080/points3857/{z}/{x}/{y}"
       table_source:
         content_type: application/x-protobuf
+        style: "http://localhost:8080/style/table_source"
+        tilejson: "http://localhost:8080/table_source"
+        tiles: "http://localhost:8080/table_source/{z}/{x}/{y}"
       table_source_multiple_geom:
         content_type: application/x-protobuf
         description: public.table_source_multiple_geom.geom1
+        style: "http://localhost:8080/style/table_source_multiple_geom"
+        tilejson: "http://localhost:8080/table_source_multiple_geom"
+        tiles: "http://localhost:8080/table_source_multiple_geom/{z}/{x}/{y}"
       table_source_multiple_geom.1:
         content_type: application/x-protobuf
         description: public.table_source_multiple_geom.geom2
+        style: "http://localhost:8080/style/table_source_multiple_geom.1"
+        tilejson: "http://localhost:8080/table_source_multiple_geom.1"
+        tiles: "http://localhost:8080/table_source_multiple_geom.1/{z}/{x}/{y}"
     "###);
 }
 
@@ -303,6 +375,49 @@ postgres:
     assert_response(response).await;
 }
 
+#[actix_rt::test]
+async fn pg_get_table_source_utfgrid() {
+    let app = create_app! { "
+postgres:
+  connection_string: $DATABASE_URL
+  tables:
+    points1:
+      schema: public
+      table: points1
+      srid: 4326
+      geometry_column: geom
+      geometry_type: POINT
+      utfgrid_key: gid
+      properties:
+        gid: int4
+    points2:
+      schema: public
+      table: points2
+      srid: 4326
+      geometry_column: geom
+      geometry_type: POINT
+      properties:
+        gid: int4
+" };
+
+    let req = test_get("/points1");
+    let tj: TileJSON = call_and_read_body_json(&app, req).await;
+    assert!(tj.grids.unwrap()[0].ends_with("/points1/{z}/{x}/{y}.grid.json"));
+
+    let req = test_get("/points1/0/0/0.grid.json");
+    let grid: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(grid["grid"].as_array().unwrap().len(), 64);
+    let keys = grid["keys"].as_array().unwrap();
+    assert_eq!(keys[0], "");
+    assert!(keys.len() > 1);
+    let key = keys[1].as_str().unwrap();
+    assert_eq!(grid["data"][key]["gid"].to_string(), key);
+
+    let req = test_get("/points2/0/0/0.grid.json");
+    let response = call_service(&app, req).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
 #[actix_rt::test]
 async fn pg_get_table_source_multiple_geom_tile_ok() {
     let app = create_app! { "
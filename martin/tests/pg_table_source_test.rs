@@ -191,3 +191,41 @@ async fn table_source_schemas() {
       description: a description from comment on table
     "###);
 }
+
+#[actix_rt::test]
+async fn table_source_settings_apply_to_all_queries() {
+    let cfg = mock_pgcfg(indoc! {"
+        connection_string: $DATABASE_URL
+        tables:
+          points1:
+            schema: public
+            table: points1
+            srid: 4326
+            geometry_column: geom
+            geometry_type: POINT
+            utfgrid_key: gid
+            version_sequence: public.points1_gid_seq
+            last_modified_column: gid
+            role: martin_missing_role
+            properties:
+              gid: int4
+    "});
+    let mock = mock_sources(cfg).await;
+    let src = source(&mock, "points1");
+    let xyz = TileCoord { z: 0, x: 0, y: 0 };
+
+    // every query runs after switching to the role, so it fails because the role does not exist
+    let assert_role_error = |err: martin::MartinError| {
+        let mut chain = err.to_string();
+        let mut source = std::error::Error::source(&err);
+        while let Some(err) = source {
+            chain = format!("{chain}: {err}");
+            source = err.source();
+        }
+        assert!(chain.contains("martin_missing_role"), "{chain}");
+    };
+    assert_role_error(src.get_tile(xyz, None).await.unwrap_err());
+    assert_role_error(src.get_utfgrid(xyz).await.unwrap_err());
+    assert_role_error(src.get_data_version().await.unwrap_err());
+    assert_role_error(src.get_last_modified().await.unwrap_err());
+}
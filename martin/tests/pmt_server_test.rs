@@ -64,6 +64,8 @@ async fn pmt_get_catalog() {
     tiles:
       stamen_toner__raster_CC-BY-ODbL_z3:
         content_type: image/png
+        tilejson: "http://localhost:8080/stamen_toner__raster_CC-BY-ODbL_z3"
+        tiles: "http://localhost:8080/stamen_toner__raster_CC-BY-ODbL_z3/{z}/{x}/{y}"
     "###);
 }
 
@@ -83,6 +85,8 @@ async fn pmt_get_catalog_gzip() {
     tiles:
       p_png:
         content_type: image/png
+        tilejson: "http://localhost:8080/p_png"
+        tiles: "http://localhost:8080/p_png/{z}/{x}/{y}"
     "###);
 }
 
@@ -19,7 +19,7 @@ mod patcher;
 pub use patcher::apply_patch;
 
 mod pool;
-pub use pool::MbtilesPool;
+pub use pool::{MbtPoolOptions, MbtilesPool};
 
 mod queries;
 pub use queries::*;
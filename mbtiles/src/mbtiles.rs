@@ -7,7 +7,10 @@ use log::debug;
 use serde::{Deserialize, Serialize};
 use sqlite_hashes::register_md5_functions;
 use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{query, Connection as _, Executor, SqliteConnection, SqliteExecutor, Statement};
+use sqlx::{
+    query, query_as, query_scalar, Connection as _, Executor, SqliteConnection, SqliteExecutor,
+    Statement,
+};
 
 use crate::errors::{MbtError, MbtResult};
 use crate::{invert_y_value, CopyDuplicateMode, MbtType};
@@ -149,12 +152,81 @@ impl Mbtiles {
         Ok(None)
     }
 
+    /// Get the size of a tile in bytes without reading its data, or `None` if the tile does not exist
+    pub async fn get_tile_size<T>(
+        &self,
+        conn: &mut T,
+        z: u8,
+        x: u32,
+        y: u32,
+    ) -> MbtResult<Option<usize>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let y = invert_y_value(z, y);
+        let size: Option<Option<i64>> = query_scalar(
+            "SELECT length(tile_data) from tiles where zoom_level = ? AND tile_column = ? AND tile_row = ?",
+        )
+        .bind(z)
+        .bind(x)
+        .bind(y)
+        .fetch_optional(conn)
+        .await?;
+        Ok(size.flatten().and_then(|v| usize::try_from(v).ok()))
+    }
+
+    /// Get the zlib or gzip compressed `UTFGrid` JSON of a tile from the `grids` table,
+    /// or `None` if the tile has no grid. The grid keys have no data, see [`Mbtiles::get_grid_data`].
+    pub async fn get_grid<T>(
+        &self,
+        conn: &mut T,
+        z: u8,
+        x: u32,
+        y: u32,
+    ) -> MbtResult<Option<Vec<u8>>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let y = invert_y_value(z, y);
+        let grid: Option<Option<Vec<u8>>> = query_scalar(
+            "SELECT grid from grids where zoom_level = ? AND tile_column = ? AND tile_row = ?",
+        )
+        .bind(z)
+        .bind(x)
+        .bind(y)
+        .fetch_optional(conn)
+        .await?;
+        Ok(grid.flatten())
+    }
+
+    /// Get the `(key_name, key_json)` pairs of the `UTFGrid` keys of a tile from the `grid_data` table
+    pub async fn get_grid_data<T>(
+        &self,
+        conn: &mut T,
+        z: u8,
+        x: u32,
+        y: u32,
+    ) -> MbtResult<Vec<(String, String)>>
+    where
+        for<'e> &'e mut T: SqliteExecutor<'e>,
+    {
+        let y = invert_y_value(z, y);
+        Ok(query_as(
+            "SELECT key_name, key_json from grid_data where zoom_level = ? AND tile_column = ? AND tile_row = ?",
+        )
+        .bind(z)
+        .bind(x)
+        .bind(y)
+        .fetch_all(conn)
+        .await?)
+    }
+
     pub async fn insert_tiles(
         &self,
         conn: &mut SqliteConnection,
         mbt_type: MbtType,
         on_duplicate: CopyDuplicateMode,
-        batch: &[(u8, u32, u32, Vec<u8>)],
+        batch: &[(u8, u32, u32, impl AsRef<[u8]>)],
     ) -> MbtResult<()> {
         debug!(
             "Inserting a batch of {} tiles into {mbt_type} / {on_duplicate}",
@@ -165,7 +237,10 @@ impl Mbtiles {
         if let Some(sql2) = sql2 {
             let sql2 = tx.prepare(&sql2).await?;
             for (_, _, _, tile_data) in batch {
-                sql2.query().bind(tile_data).execute(&mut *tx).await?;
+                sql2.query()
+                    .bind(tile_data.as_ref())
+                    .execute(&mut *tx)
+                    .await?;
             }
         }
         let sql1 = tx.prepare(&sql1).await?;
@@ -175,7 +250,7 @@ impl Mbtiles {
                 .bind(z)
                 .bind(x)
                 .bind(y)
-                .bind(tile_data)
+                .bind(tile_data.as_ref())
                 .execute(&mut *tx)
                 .await?;
         }
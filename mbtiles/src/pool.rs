@@ -1,9 +1,26 @@
+use std::num::NonZeroU32;
 use std::path::Path;
+use std::time::Duration;
 
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::{Pool, Sqlite, SqlitePool};
 
 use crate::errors::MbtResult;
-use crate::{Mbtiles, Metadata};
+use crate::{has_grid_data, has_grids, Mbtiles, Metadata};
+
+/// How the connections of a [`MbtilesPool`] access the file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MbtPoolOptions {
+    /// Open the file as read-only and immutable, and memory-map up to this many bytes of it.
+    /// The file must not be modified while it is open, or the reads may return corrupted data.
+    pub mmap_size: Option<u64>,
+    /// Size of the page cache of each connection in bytes
+    pub cache_size: Option<u64>,
+    /// Maximum number of connections to the file
+    pub max_connections: Option<NonZeroU32>,
+    /// How long to wait for a lock on the file before failing
+    pub busy_timeout: Option<Duration>,
+}
 
 #[derive(Clone, Debug)]
 pub struct MbtilesPool {
@@ -18,6 +35,33 @@ impl MbtilesPool {
         Ok(Self { mbtiles, pool })
     }
 
+    pub async fn new_with_options<P: AsRef<Path>>(
+        filepath: P,
+        options: MbtPoolOptions,
+    ) -> MbtResult<Self> {
+        let mbtiles = Mbtiles::new(filepath)?;
+        let mut opts = SqliteConnectOptions::new().filename(mbtiles.filepath());
+        if let Some(mmap_size) = options.mmap_size {
+            opts = opts
+                .read_only(true)
+                .immutable(true)
+                .pragma("mmap_size", mmap_size.to_string());
+        }
+        if let Some(cache_size) = options.cache_size {
+            // negative values are in KiB instead of pages
+            opts = opts.pragma("cache_size", format!("-{}", cache_size / 1024));
+        }
+        if let Some(busy_timeout) = options.busy_timeout {
+            opts = opts.busy_timeout(busy_timeout);
+        }
+        let mut pool_opts = SqlitePoolOptions::new();
+        if let Some(max_connections) = options.max_connections {
+            pool_opts = pool_opts.max_connections(max_connections.get());
+        }
+        let pool = pool_opts.connect_with(opts).await?;
+        Ok(Self { mbtiles, pool })
+    }
+
     pub async fn get_metadata(&self) -> MbtResult<Metadata> {
         let mut conn = self.pool.acquire().await?;
         self.mbtiles.get_metadata(&mut *conn).await
@@ -27,4 +71,84 @@ impl MbtilesPool {
         let mut conn = self.pool.acquire().await?;
         self.mbtiles.get_tile(&mut *conn, z, x, y).await
     }
+
+    pub async fn get_tile_size(&self, z: u8, x: u32, y: u32) -> MbtResult<Option<usize>> {
+        let mut conn = self.pool.acquire().await?;
+        self.mbtiles.get_tile_size(&mut *conn, z, x, y).await
+    }
+
+    /// Check if the file has the `grids` and the `grid_data` tables of `UTFGrid` interaction data
+    pub async fn has_grids(&self) -> MbtResult<(bool, bool)> {
+        let mut conn = self.pool.acquire().await?;
+        Ok((
+            has_grids(&mut *conn).await?,
+            has_grid_data(&mut *conn).await?,
+        ))
+    }
+
+    pub async fn get_grid(&self, z: u8, x: u32, y: u32) -> MbtResult<Option<Vec<u8>>> {
+        let mut conn = self.pool.acquire().await?;
+        self.mbtiles.get_grid(&mut *conn, z, x, y).await
+    }
+
+    pub async fn get_grid_data(&self, z: u8, x: u32, y: u32) -> MbtResult<Vec<(String, String)>> {
+        let mut conn = self.pool.acquire().await?;
+        self.mbtiles.get_grid_data(&mut *conn, z, x, y).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn open_memory_mapped() {
+        let options = MbtPoolOptions {
+            mmap_size: Some(64 * 1024 * 1024),
+            cache_size: Some(8 * 1024 * 1024),
+            max_connections: NonZeroU32::new(2),
+            busy_timeout: None,
+        };
+        let pool = MbtilesPool::new_with_options(
+            "../tests/fixtures/mbtiles/world_cities.mbtiles",
+            options,
+        )
+        .await
+        .unwrap();
+        let mut conn = pool.pool.acquire().await.unwrap();
+        let mmap_size: i64 = sqlx::query_scalar("PRAGMA mmap_size")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(mmap_size, 64 * 1024 * 1024);
+        let cache_size: i64 = sqlx::query_scalar("PRAGMA cache_size")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assert_eq!(cache_size, -8 * 1024);
+        drop(conn);
+        assert_eq!(pool.pool.options().get_max_connections(), 2);
+        assert!(pool.get_tile(0, 0, 0).await.unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn grids() {
+        let pool = MbtilesPool::new("../tests/fixtures/mbtiles/geography-class-png.mbtiles")
+            .await
+            .unwrap();
+        assert_eq!(pool.has_grids().await.unwrap(), (true, true));
+        let grid = pool.get_grid(0, 0, 0).await.unwrap().unwrap();
+        // zlib header
+        assert_eq!(grid[0], 0x78);
+        let data = pool.get_grid_data(0, 0, 0).await.unwrap();
+        assert!(data
+            .iter()
+            .any(|(key, json)| key == "10" && json.contains("Argentina")));
+        assert_eq!(pool.get_grid(2, 1, 1).await.unwrap(), None);
+
+        let pool = MbtilesPool::new("../tests/fixtures/mbtiles/world_cities.mbtiles")
+            .await
+            .unwrap();
+        assert_eq!(pool.has_grids().await.unwrap(), (false, false));
+    }
 }
@@ -1,6 +1,6 @@
 use log::debug;
 use martin_tile_utils::MAX_ZOOM;
-use sqlx::{query, Executor as _, SqliteExecutor};
+use sqlx::{query, query_scalar, Executor as _, SqliteExecutor};
 
 use crate::errors::MbtResult;
 use crate::MbtError::InvalidZoomValue;
@@ -96,6 +96,36 @@ where
         == 1)
 }
 
+/// Check if `MBTiles` has a table or a view named `grids` with the `UTFGrid` interaction data of the tiles,
+/// as created by `TileMill`
+pub async fn has_grids<T>(conn: &mut T) -> MbtResult<bool>
+where
+    for<'e> &'e mut T: SqliteExecutor<'e>,
+{
+    has_table_or_view(conn, "grids").await
+}
+
+/// Check if `MBTiles` has a table or a view named `grid_data` with the data of the `UTFGrid` keys
+pub async fn has_grid_data<T>(conn: &mut T) -> MbtResult<bool>
+where
+    for<'e> &'e mut T: SqliteExecutor<'e>,
+{
+    has_table_or_view(conn, "grid_data").await
+}
+
+async fn has_table_or_view<T>(conn: &mut T, name: &str) -> MbtResult<bool>
+where
+    for<'e> &'e mut T: SqliteExecutor<'e>,
+{
+    let count: i64 = query_scalar(
+        "SELECT COUNT(*) FROM sqlite_master WHERE name = ? AND type IN ('table', 'view')",
+    )
+    .bind(name)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(count > 0)
+}
+
 pub async fn is_flat_with_hash_tables_type<T>(conn: &mut T) -> MbtResult<bool>
 where
     for<'e> &'e mut T: SqliteExecutor<'e>,